@@ -3,6 +3,7 @@
 //! This module provides cross-architecture support for libvirt domain creation
 //! and QEMU emulator selection, avoiding hardcoded architecture assumptions.
 
+use crate::qemu::{RtcClock, RtcConfig};
 use crate::xml_utils::XmlWriter;
 use color_eyre::Result;
 
@@ -46,21 +47,28 @@ impl ArchConfig {
     }
 
     /// Generate architecture-specific timer configuration
-    pub fn write_timers(&self, writer: &mut XmlWriter) -> Result<()> {
-        // RTC timer is common to all architectures
-        writer.write_empty_element("timer", &[("name", "rtc"), ("tickpolicy", "catchup")])?;
+    pub fn write_timers(&self, writer: &mut XmlWriter, rtc: &RtcConfig) -> Result<()> {
+        // RTC timer is common to all architectures; tracking the guest
+        // corresponds to QEMU's `-rtc clock=vm`
+        let mut rtc_attrs = vec![("name", "rtc"), ("tickpolicy", "catchup")];
+        if rtc.clock == RtcClock::Vm {
+            rtc_attrs.push(("track", "guest"));
+        }
+        writer.write_empty_element("timer", &rtc_attrs)?;
 
         // Add x86_64-specific timers
         if self.arch == "x86_64" {
             writer.write_empty_element("timer", &[("name", "pit"), ("tickpolicy", "delay")])?;
             writer.write_empty_element("timer", &[("name", "hpet"), ("present", "no")])?;
+            if rtc.is_fixed() {
+                writer.write_empty_element("timer", &[("name", "kvmclock"), ("present", "no")])?;
+            }
         }
 
         Ok(())
     }
 
     /// Check if this architecture supports VMport (x86_64 specific feature)
-    #[allow(dead_code)]
     pub fn supports_vmport(&self) -> bool {
         self.arch == "x86_64"
     }
@@ -76,19 +84,16 @@ impl ArchConfig {
 }
 
 /// Detect host architecture string (shorthand for ArchConfig::detect().arch)
-#[allow(dead_code)]
 pub fn host_arch() -> Result<&'static str> {
     Ok(ArchConfig::detect()?.arch)
 }
 
 /// Check if running on x86_64 architecture
-#[allow(dead_code)]
 pub fn is_x86_64() -> bool {
     std::env::consts::ARCH == "x86_64"
 }
 
-/// Check if running on ARM64/AArch64 architecture  
-#[allow(dead_code)]
+/// Check if running on ARM64/AArch64 architecture
 pub fn is_aarch64() -> bool {
     std::env::consts::ARCH == "aarch64"
 }
@@ -115,7 +120,9 @@ mod tests {
 
         // Test that we can generate timers XML without errors
         let mut writer = XmlWriter::new();
-        arch_config.write_timers(&mut writer).unwrap();
+        arch_config
+            .write_timers(&mut writer, &RtcConfig::default())
+            .unwrap();
         let timers_xml = writer.into_string().unwrap();
         assert!(timers_xml.contains("timer"));
         assert!(timers_xml.contains("rtc"));
@@ -0,0 +1,639 @@
+//! Systemd credential injection for bootc VMs
+//!
+//! Provides functions for injecting configuration into VMs via systemd credentials
+//! using SMBIOS firmware variables (preferred) or kernel command-line arguments.
+//! Supports SSH keys, mount units, environment configuration, and AF_VSOCK setup.
+
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// Convert a guest mount path to a systemd unit name
+///
+/// Systemd requires mount unit names to match the mount path with:
+/// - Leading slash removed
+/// - All slashes replaced with dashes
+/// - All dashes in path components escaped as `\x2d`
+/// - .mount suffix added
+///
+/// Examples:
+/// - `/mnt/data` → `mnt-data.mount`
+/// - `/var/lib/data` → `var-lib-data.mount`
+/// - `/data` → `data.mount`
+/// - `/mnt/test-rw` → `mnt-test\x2drw.mount`
+pub fn guest_path_to_unit_name(guest_path: &str) -> String {
+    let path = guest_path.strip_prefix('/').unwrap_or(guest_path);
+
+    // Escape dashes in path components, then replace slashes with dashes
+    let escaped = path
+        .split('/')
+        .map(|component| component.replace('-', "\\x2d"))
+        .collect::<Vec<_>>()
+        .join("-");
+
+    format!("{}.mount", escaped)
+}
+
+/// Generate a systemd mount unit for virtiofs
+///
+/// Creates a systemd mount unit that mounts a virtiofs filesystem at the specified
+/// guest path. The unit is configured to:
+/// - Mount type: virtiofs
+/// - Options: Include readonly flag if specified
+/// - TimeoutSec=10: Fail quickly if mount hangs instead of blocking boot
+/// - DefaultDependencies=no to avoid ordering cycles
+/// - Before=local-fs.target and After=systemd-remount-fs.service
+///
+/// Note: systemd automatically creates mount point directories, so DirectoryMode is not needed
+///
+/// Returns the complete unit file content as a string
+pub fn generate_virtiofs_mount_unit(
+    virtiofs_tag: &str,
+    guest_path: &str,
+    readonly: bool,
+) -> String {
+    let options = if readonly {
+        // Default readonly mounts to usr_t - this helps avoid SELinux
+        // issues when accessing them as container storage for example.
+        // TODO don't hardcode this, detect from the environment
+        "ro,context=system_u:object_r:usr_t:s0"
+    } else {
+        "rw"
+    };
+
+    format!(
+        "[Unit]\n\
+         Description=Mount virtiofs tag {tag} at {path}\n\
+         ConditionPathExists=!/etc/initrd-release\n\
+         DefaultDependencies=no\n\
+         Conflicts=umount.target\n\
+         Before=local-fs.target umount.target\n\
+         After=systemd-remount-fs.service\n\
+         \n\
+         [Mount]\n\
+         What={tag}\n\
+         Where={path}\n\
+         Type=virtiofs\n\
+         Options={options}\n",
+        tag = virtiofs_tag,
+        path = guest_path,
+        options = options
+    )
+}
+
+/// Generate SMBIOS credentials for a systemd mount unit
+///
+/// Creates systemd credentials for:
+/// 1. The mount unit itself (via systemd.extra-unit)
+/// 2. A dropin for local-fs.target that wants this mount unit
+///
+/// Returns a vector of SMBIOS credential strings
+pub fn smbios_creds_for_mount_unit(
+    virtiofs_tag: &str,
+    guest_path: &str,
+    readonly: bool,
+) -> Result<Vec<String>> {
+    let unit_name = guest_path_to_unit_name(guest_path);
+    let mount_unit_content = generate_virtiofs_mount_unit(virtiofs_tag, guest_path, readonly);
+    let encoded_mount = data_encoding::BASE64.encode(mount_unit_content.as_bytes());
+
+    let mount_cred =
+        format!("io.systemd.credential.binary:systemd.extra-unit.{unit_name}={encoded_mount}");
+
+    // Create a dropin for local-fs.target that wants this mount
+    let dropin_content = format!(
+        "[Unit]\n\
+         Wants={unit_name}\n"
+    );
+    let encoded_dropin = data_encoding::BASE64.encode(dropin_content.as_bytes());
+    let dropin_cred = format!(
+        "io.systemd.credential.binary:systemd.unit-dropin.local-fs.target~bcvk-mounts={encoded_dropin}"
+    );
+
+    Ok(vec![mount_cred, dropin_cred])
+}
+
+/// Generate SMBIOS credential string for AF_VSOCK systemd notification socket
+///
+/// Creates a systemd credential that configures systemd to send notifications
+/// via AF_VSOCK instead of the default Unix socket. This enables host-guest
+/// communication for debugging VM boot sequences.
+///
+/// Returns a string for use with `qemu -smbios type=11,value="..."`
+pub fn smbios_cred_for_vsock_notify(host_cid: u32, port: u32) -> String {
+    format!(
+        "io.systemd.credential:vmm.notify_socket=vsock-stream:{}:{}",
+        host_cid, port
+    )
+}
+
+/// Generate SMBIOS credentials using `store` as an additional image store
+///
+/// Creates a systemd unit which, once `store` is mounted, sets STORAGE_OPTS for
+/// PAM sessions including SSH (/etc/environment), the systemd user manager
+/// (/etc/environment.d/) and system services (/etc/systemd/system.conf.d/).
+/// This is done with a unit rather than tmpfiles.d lines, as the
+/// `tmpfiles.extra` credential may already be used for SSH keys.
+///
+/// Returns a vector with:
+/// 1. The unit itself (systemd.extra-unit)
+/// 2. A dropin for sysinit.target to pull in the unit
+pub fn smbios_creds_for_storage_opts(store: &str) -> Result<Vec<String>> {
+    let env = format!("STORAGE_OPTS=additionalimagestore={store}");
+    let unit_content = format!(
+        r#"[Unit]
+Description=Setup STORAGE_OPTS for bcvk
+DefaultDependencies=no
+RequiresMountsFor={store}
+Before=systemd-user-sessions.service
+
+[Service]
+Type=oneshot
+ExecStart=/bin/sh -c 'grep -q STORAGE_OPTS /etc/environment || echo {env} >> /etc/environment'
+ExecStart=/bin/mkdir -p /etc/environment.d /etc/systemd/system.conf.d
+ExecStart=/bin/sh -c 'echo {env} > /etc/environment.d/90-bcvk-storage.conf'
+ExecStart=/bin/sh -c 'echo "[Manager]" > /etc/systemd/system.conf.d/90-bcvk-storage.conf'
+ExecStart=/bin/sh -c 'echo DefaultEnvironment={env} >> /etc/systemd/system.conf.d/90-bcvk-storage.conf'
+RemainAfterExit=yes
+"#
+    );
+    let encoded_unit = data_encoding::BASE64.encode(unit_content.as_bytes());
+    let unit_cred = format!(
+        "io.systemd.credential.binary:systemd.extra-unit.bcvk-storage-opts.service={encoded_unit}"
+    );
+
+    // Create dropin for sysinit.target to pull in our unit
+    let dropin_content = "[Unit]\nWants=bcvk-storage-opts.service\n";
+    let encoded_dropin = data_encoding::BASE64.encode(dropin_content.as_bytes());
+    let dropin_cred = format!(
+        "io.systemd.credential.binary:systemd.unit-dropin.sysinit.target~bcvk-storage={encoded_dropin}"
+    );
+
+    Ok(vec![unit_cred, dropin_cred])
+}
+
+/// Credential holding the secret clients of the vsock exec agent must send
+pub const VSOCK_EXEC_SECRET_CREDENTIAL: &str = "bcvk.vsock-exec-secret";
+
+/// Generate SMBIOS credentials for an agent running commands sent over vsock
+///
+/// The agent is a socket-activated shell: for each connection to `port`, it
+/// reads a line with the secret from the [`VSOCK_EXEC_SECRET_CREDENTIAL`]
+/// credential, closing connections without it, then a nonce line and a line
+/// with a base64 encoded shell command. It runs the command as root with
+/// the rest of the connection as stdin and both stdout and stderr written
+/// back, and finally writes a line with the nonce and the exit status. The
+/// secret credential must be passed separately, and kept off the QEMU
+/// command line.
+///
+/// Returns a vector with:
+/// 1. The socket unit (systemd.extra-unit)
+/// 2. The per-connection service template (systemd.extra-unit)
+/// 3. A dropin for sockets.target to pull in the socket
+pub fn smbios_creds_for_vsock_exec(port: u32) -> Vec<String> {
+    let socket = format!(
+        r#"[Unit]
+Description=bcvk exec agent
+
+[Socket]
+ListenStream=vsock::{port}
+Accept=yes
+"#
+    );
+    // Written for systemd, which turns $$ into $, %% into % and \\ into \
+    let service = format!(
+        r#"[Unit]
+Description=bcvk exec agent connection
+
+[Service]
+ExecStart=/bin/sh -c 'read -r secret && test "$$secret" = "$$(cat "$$CREDENTIALS_DIRECTORY/{VSOCK_EXEC_SECRET_CREDENTIAL}")" || {{ echo "Rejecting a connection without the secret" >&2; exit 1; }}; read -r nonce && read -r cmd && cmd=$$(printf %%s "$$cmd" | base64 -d) || exit 1; sh -c "$$cmd" 2>&1; printf "\\n%%s %%s\\n" "$$nonce" "$$?"'
+ImportCredential={VSOCK_EXEC_SECRET_CREDENTIAL}
+StandardInput=socket
+StandardOutput=socket
+StandardError=journal
+Environment=HOME=/root
+WorkingDirectory=-/root
+"#
+    );
+    let dropin = "[Unit]\nWants=bcvk-exec.socket\n";
+    [
+        ("systemd.extra-unit.bcvk-exec.socket", socket.as_str()),
+        ("systemd.extra-unit.bcvk-exec@.service", service.as_str()),
+        ("systemd.unit-dropin.sockets.target~bcvk-exec", dropin),
+    ]
+    .into_iter()
+    .map(|(name, content)| {
+        let encoded = data_encoding::BASE64.encode(content.as_bytes());
+        format!("io.systemd.credential.binary:{name}={encoded}")
+    })
+    .collect()
+}
+
+/// What backs the swap space set up by [`smbios_creds_for_swap`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwapBackend {
+    /// A compressed RAM disk, so that the swapped out memory takes less space
+    #[default]
+    Zram,
+    /// The file `/var/swap/bcvk-swapfile`, created on the first boot
+    File,
+}
+
+impl FromStr for SwapBackend {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zram" => Ok(Self::Zram),
+            "file" => Ok(Self::File),
+            _ => Err(eyre!(
+                "Invalid swap backend '{s}'. Expected 'zram' or 'file'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SwapBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Zram => write!(f, "zram"),
+            Self::File => write!(f, "file"),
+        }
+    }
+}
+
+/// Generate SMBIOS credentials setting up `size` bytes of swap space at boot
+///
+/// Returns a vector with:
+/// 1. The service setting up the swap space (systemd.extra-unit)
+/// 2. A dropin for default.target to pull in the service
+pub fn smbios_creds_for_swap(size: u64, backend: SwapBackend) -> Vec<String> {
+    // Written for systemd, which turns $$ into $
+    let setup = match backend {
+        SwapBackend::Zram => format!(
+            "modprobe zram && dev=$$(zramctl --find --size {size}) && mkswap $$dev && swapon --priority 100 $$dev"
+        ),
+        // Copy-on-write is disabled for btrfs, which can't swap to such files
+        SwapBackend::File => format!(
+            "d=/var/swap; f=$$d/bcvk-swapfile; if [ ! -e $$f ]; then mkdir -p $$d && chattr +C $$d 2>/dev/null; fallocate -l {size} $$f.tmp && chmod 600 $$f.tmp && mkswap $$f.tmp && mv $$f.tmp $$f || exit 1; fi; swapon $$f"
+        ),
+    };
+    let service = format!(
+        r#"[Unit]
+Description=bcvk swap ({backend})
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/bin/sh -c '{setup}'
+"#
+    );
+    let dropin = "[Unit]\nWants=bcvk-swap.service\n";
+    [
+        ("systemd.extra-unit.bcvk-swap.service", service.as_str()),
+        (
+            "systemd.unit-dropin.default.target~bcvk-swap-service",
+            dropin,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, content)| {
+        let encoded = data_encoding::BASE64.encode(content.as_bytes());
+        format!("io.systemd.credential.binary:{name}={encoded}")
+    })
+    .collect()
+}
+
+/// Path of the file marking that [`smbios_creds_for_selinux_relabel`] ran
+pub const SELINUX_RELABELED_MARKER: &str = "/var/lib/bcvk/selinux-relabeled";
+
+/// Generate SMBIOS credentials relabeling the mutable parts of the guest
+/// (/etc and /var) for SELinux early during the first boot
+///
+/// The read-only /usr is labeled when the image is built. A marker file in
+/// /var keeps the relabel from running again on later boots.
+///
+/// Returns a vector with:
+/// 1. The relabel service (systemd.extra-unit)
+/// 2. A dropin for sysinit.target to pull in the service
+pub fn smbios_creds_for_selinux_relabel() -> Vec<String> {
+    let service = format!(
+        r#"[Unit]
+Description=bcvk SELinux relabel of /etc and /var
+DefaultDependencies=no
+After=local-fs.target
+Before=sysinit.target
+ConditionSecurity=selinux
+ConditionPathExists=!{SELINUX_RELABELED_MARKER}
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=restorecon -RF /etc /var
+ExecStart=/bin/sh -c 'mkdir -p /var/lib/bcvk && touch {SELINUX_RELABELED_MARKER}'
+"#
+    );
+    let dropin = "[Unit]\nWants=bcvk-selinux-relabel.service\n";
+    [
+        (
+            "systemd.extra-unit.bcvk-selinux-relabel.service",
+            service.as_str(),
+        ),
+        (
+            "systemd.unit-dropin.sysinit.target~bcvk-selinux-relabel",
+            dropin,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, content)| {
+        let encoded = data_encoding::BASE64.encode(content.as_bytes());
+        format!("io.systemd.credential.binary:{name}={encoded}")
+    })
+    .collect()
+}
+
+/// Generate SMBIOS credential string for root SSH access
+///
+/// Creates a systemd credential for QEMU's SMBIOS interface. Preferred method
+/// as it keeps credentials out of kernel command line and boot logs.
+///
+/// Returns a string for use with `qemu -smbios type=11,value="..."`
+pub fn smbios_cred_for_root_ssh(pubkey: &str) -> Result<String> {
+    let k = key_to_root_tmpfiles_d(pubkey);
+    let encoded = data_encoding::BASE64.encode(k.as_bytes());
+    let r = format!("io.systemd.credential.binary:tmpfiles.extra={encoded}");
+    Ok(r)
+}
+
+/// Generate kernel command-line argument for root SSH access
+///
+/// Creates a systemd credential for kernel command-line delivery. Less secure
+/// than SMBIOS method as credentials are visible in /proc/cmdline and boot logs.
+///
+/// Returns a string for use in kernel boot parameters.
+pub fn karg_for_root_ssh(pubkey: &str) -> Result<String> {
+    let k = key_to_root_tmpfiles_d(pubkey);
+    let encoded = data_encoding::BASE64.encode(k.as_bytes());
+    let r = format!("systemd.set_credential_binary=tmpfiles.extra:{encoded}");
+    Ok(r)
+}
+
+/// Convert SSH public key to systemd tmpfiles.d configuration
+///
+/// Generates configuration to create `/root/.ssh` directory (0750) and
+/// `/root/.ssh/authorized_keys` file (700) with the Base64-encoded SSH key.
+/// Uses `f+~` to append to existing authorized_keys files.
+pub fn key_to_root_tmpfiles_d(pubkey: &str) -> String {
+    let buf = data_encoding::BASE64.encode(pubkey.as_bytes());
+    format!("d /root/.ssh 0750 - - -\nf+~ /root/.ssh/authorized_keys 700 - - - {buf}\n")
+}
+
+/// A non-root account to create in the guest for SSH access, as `NAME[:UID]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SshUser {
+    /// Login name
+    pub name: String,
+    /// UID of the account; picked by systemd-sysusers if unset
+    pub uid: Option<u32>,
+}
+
+impl FromStr for SshUser {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, uid) = match s.split_once(':') {
+            Some((name, uid)) => {
+                let uid = uid
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&uid| uid > 0 && uid < 65534)
+                    .ok_or_else(|| eyre!("Invalid UID '{uid}'"))?;
+                (name, Some(uid))
+            }
+            None => (s, None),
+        };
+        // The portable subset of user names, see systemd's USER_NAMES.md
+        let valid = !name.is_empty()
+            && name.len() <= 32
+            && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !valid {
+            return Err(eyre!("Invalid user name '{name}'"));
+        }
+        if name == "root" {
+            return Err(eyre!("root always gets the SSH key, use another user name"));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            uid,
+        })
+    }
+}
+
+impl std::fmt::Display for SshUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(uid) = self.uid {
+            write!(f, ":{uid}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate the sysusers.d line creating `user`, with a login shell and a
+/// home directory under /home
+pub fn user_sysusers_d(user: &SshUser) -> String {
+    let name = &user.name;
+    let uid = user
+        .uid
+        .map_or_else(|| "-".to_owned(), |uid| uid.to_string());
+    format!("u {name} {uid} \"bcvk SSH user\" /home/{name} /bin/bash\n")
+}
+
+/// Convert SSH public key to systemd tmpfiles.d configuration for `user`
+///
+/// Like [`key_to_root_tmpfiles_d`], but also creates the home directory
+/// (systemd-sysusers does not) and lets the user run commands as root with
+/// sudo, as hardened images often disable root logins.
+pub fn key_to_user_tmpfiles_d(user: &SshUser, pubkey: &str) -> String {
+    let name = &user.name;
+    let key = data_encoding::BASE64.encode(pubkey.as_bytes());
+    let sudoers =
+        data_encoding::BASE64.encode(format!("{name} ALL=(ALL) NOPASSWD: ALL\n").as_bytes());
+    format!(
+        "d /home/{name} 0700 {name} {name} -\n\
+         d /home/{name}/.ssh 0700 {name} {name} -\n\
+         f+~ /home/{name}/.ssh/authorized_keys 0600 {name} {name} - {key}\n\
+         f~ /etc/sudoers.d/bcvk-{name} 0440 root root - {sudoers}\n"
+    )
+}
+
+/// Generate SMBIOS credential strings for SSH access as root and optionally
+/// as the non-root `user`, created with systemd-sysusers
+///
+/// There is a single `tmpfiles.extra` credential, so the configuration for
+/// both accounts is combined in it.
+pub fn smbios_creds_for_ssh(pubkey: &str, user: Option<&SshUser>) -> Result<Vec<String>> {
+    let Some(user) = user else {
+        return Ok(vec![smbios_cred_for_root_ssh(pubkey)?]);
+    };
+    let tmpfiles = key_to_root_tmpfiles_d(pubkey) + &key_to_user_tmpfiles_d(user, pubkey);
+    let tmpfiles = data_encoding::BASE64.encode(tmpfiles.as_bytes());
+    let sysusers = data_encoding::BASE64.encode(user_sysusers_d(user).as_bytes());
+    Ok(vec![
+        format!("io.systemd.credential.binary:sysusers.extra={sysusers}"),
+        format!("io.systemd.credential.binary:tmpfiles.extra={tmpfiles}"),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::BASE64;
+    use similar_asserts::assert_eq;
+
+    use super::*;
+
+    /// Test SSH public key for validation (truncated for brevity)
+    const STUBKEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC...";
+
+    /// Test tmpfiles.d configuration generation
+    #[test]
+    fn test_key_to_root_tmpfiles_d() {
+        let expected = "d /root/.ssh 0750 - - -\nf+~ /root/.ssh/authorized_keys 700 - - - c3NoLXJzYSBBQUFBQjNOemFDMXljMkVBQUFBREFRQUJBQUFCQVFDLi4u\n";
+        assert_eq!(key_to_root_tmpfiles_d(STUBKEY), expected);
+    }
+
+    /// Test SMBIOS credential generation and format validation
+    #[test]
+    fn test_credential_for_root_ssh() {
+        let b64_tmpfiles = BASE64.encode(key_to_root_tmpfiles_d(STUBKEY).as_bytes());
+        let expected = format!("io.systemd.credential.binary:tmpfiles.extra={b64_tmpfiles}");
+
+        // Verify credential format by reverse parsing
+        let v = expected
+            .strip_prefix("io.systemd.credential.binary:")
+            .unwrap();
+        let v = v.strip_prefix("tmpfiles.extra=").unwrap();
+        let v = String::from_utf8(BASE64.decode(v.as_bytes()).unwrap()).unwrap();
+        assert_eq!(v, "d /root/.ssh 0750 - - -\nf+~ /root/.ssh/authorized_keys 700 - - - c3NoLXJzYSBBQUFBQjNOemFDMXljMkVBQUFBREFRQUJBQUFCQVFDLi4u\n");
+
+        // Test the actual function output
+        assert_eq!(smbios_cred_for_root_ssh(STUBKEY).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_ssh_user() {
+        let user: SshUser = "core".parse().unwrap();
+        assert_eq!(user.uid, None);
+        assert_eq!(
+            user_sysusers_d(&user),
+            "u core - \"bcvk SSH user\" /home/core /bin/bash\n"
+        );
+        let user: SshUser = "ci-user:1001".parse().unwrap();
+        assert_eq!(user.to_string(), "ci-user:1001");
+        assert_eq!(
+            user_sysusers_d(&user),
+            "u ci-user 1001 \"bcvk SSH user\" /home/ci-user /bin/bash\n"
+        );
+        for invalid in [
+            "", "root", "Core", "1user", "a b", "core:", "core:0", "core:x",
+        ] {
+            assert!(invalid.parse::<SshUser>().is_err(), "{invalid}");
+        }
+
+        let creds = smbios_creds_for_ssh(STUBKEY, Some(&user)).unwrap();
+        assert_eq!(creds.len(), 2);
+        let tmpfiles = creds[1]
+            .strip_prefix("io.systemd.credential.binary:tmpfiles.extra=")
+            .unwrap();
+        let tmpfiles = String::from_utf8(BASE64.decode(tmpfiles.as_bytes()).unwrap()).unwrap();
+        assert!(tmpfiles.starts_with(&key_to_root_tmpfiles_d(STUBKEY)));
+        assert!(tmpfiles.contains("d /home/ci-user/.ssh 0700 ci-user ci-user -\n"));
+        assert_eq!(
+            smbios_creds_for_ssh(STUBKEY, None).unwrap(),
+            [smbios_cred_for_root_ssh(STUBKEY).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_vsock_exec() {
+        let creds = smbios_creds_for_vsock_exec(7022);
+        assert_eq!(creds.len(), 3);
+        let decode = |cred: &str, name: &str| {
+            let encoded = cred
+                .strip_prefix(&format!("io.systemd.credential.binary:{name}="))
+                .unwrap();
+            String::from_utf8(BASE64.decode(encoded.as_bytes()).unwrap()).unwrap()
+        };
+        let socket = decode(&creds[0], "systemd.extra-unit.bcvk-exec.socket");
+        assert!(socket.contains("ListenStream=vsock::7022\nAccept=yes\n"));
+        let service = decode(&creds[1], "systemd.extra-unit.bcvk-exec@.service");
+        assert!(service.contains(r#"printf "\\n%%s %%s\\n" "$$nonce" "$$?"'"#));
+        assert!(service.contains(
+            r#"test "$$secret" = "$$(cat "$$CREDENTIALS_DIRECTORY/bcvk.vsock-exec-secret")" || {"#
+        ));
+        assert!(service.contains("ImportCredential=bcvk.vsock-exec-secret\n"));
+        let dropin = decode(&creds[2], "systemd.unit-dropin.sockets.target~bcvk-exec");
+        assert_eq!(dropin, "[Unit]\nWants=bcvk-exec.socket\n");
+    }
+
+    #[test]
+    fn test_swap() {
+        let cases = [
+            (SwapBackend::Zram, "zramctl --find --size 4294967296"),
+            (SwapBackend::File, "fallocate -l 4294967296 $$f.tmp"),
+        ];
+        for (backend, expected) in cases {
+            assert_eq!(backend.to_string().parse::<SwapBackend>().unwrap(), backend);
+            let creds = smbios_creds_for_swap(4 << 30, backend);
+            assert_eq!(creds.len(), 2);
+            let service = creds[0]
+                .strip_prefix("io.systemd.credential.binary:systemd.extra-unit.bcvk-swap.service=")
+                .unwrap();
+            let service = String::from_utf8(BASE64.decode(service.as_bytes()).unwrap()).unwrap();
+            assert!(service.contains(expected), "{service}");
+            assert!(!service.contains('%'), "{service}");
+        }
+        assert!("disk".parse::<SwapBackend>().is_err());
+    }
+
+    #[test]
+    fn test_selinux_relabel() {
+        let creds = smbios_creds_for_selinux_relabel();
+        assert_eq!(creds.len(), 2);
+        let service = creds[0]
+            .strip_prefix(
+                "io.systemd.credential.binary:systemd.extra-unit.bcvk-selinux-relabel.service=",
+            )
+            .unwrap();
+        let service = String::from_utf8(BASE64.decode(service.as_bytes()).unwrap()).unwrap();
+        assert!(service.contains("ExecStart=restorecon -RF /etc /var\n"));
+        assert!(service.contains("ConditionPathExists=!/var/lib/bcvk/selinux-relabeled\n"));
+        let dropin = creds[1]
+            .strip_prefix(
+                "io.systemd.credential.binary:systemd.unit-dropin.sysinit.target~bcvk-selinux-relabel=",
+            )
+            .unwrap();
+        let dropin = String::from_utf8(BASE64.decode(dropin.as_bytes()).unwrap()).unwrap();
+        assert_eq!(dropin, "[Unit]\nWants=bcvk-selinux-relabel.service\n");
+    }
+
+    #[test]
+    fn test_storage_opts() {
+        let creds = smbios_creds_for_storage_opts("/run/host-container-storage").unwrap();
+        assert_eq!(creds.len(), 2);
+        let unit = creds[0]
+            .strip_prefix(
+                "io.systemd.credential.binary:systemd.extra-unit.bcvk-storage-opts.service=",
+            )
+            .unwrap();
+        let unit = String::from_utf8(BASE64.decode(unit.as_bytes()).unwrap()).unwrap();
+        assert!(unit.contains("RequiresMountsFor=/run/host-container-storage\n"));
+        assert!(unit.contains(
+            "echo STORAGE_OPTS=additionalimagestore=/run/host-container-storage > /etc/environment.d/"
+        ));
+    }
+}
@@ -4,14 +4,41 @@
 //! for bootc containers, inspired by the podman-bootc domain builder pattern.
 
 use crate::arch::ArchConfig;
-use crate::common_opts::DEFAULT_MEMORY_USER_STR;
-use crate::libvirt::run::FirmwareType;
-use crate::run_ephemeral::default_vcpus;
+use crate::domain_metadata::DomainMetadata;
+use crate::host_devices::HostDevice;
+use crate::qemu::{default_vcpus, DiskIoConfig, RtcBase, RtcConfig};
 use crate::xml_utils::XmlWriter;
 use color_eyre::{eyre::eyre, Result};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Scheduling period for CPU quotas, in microseconds (the kernel default)
+const CPU_QUOTA_PERIOD_US: u64 = 100_000;
+
+/// Memory of a domain if none is set, in MB
+const DEFAULT_MEMORY_MB: u64 = 4096;
+
+/// Firmware type for virtual machines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareType {
+    /// UEFI with secure boot enabled (default)
+    UefiSecure,
+    /// UEFI with secure boot explicitly disabled
+    UefiInsecure,
+    /// Legacy BIOS
+    Bios,
+}
+
+/// How the guest memory is backed on the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Shared memory, as required by virtiofs (default)
+    #[default]
+    Shared,
+    /// Shared memory that is also locked into host RAM, so it is never swapped out
+    Locked,
+}
+
 /// Configuration for a virtiofs filesystem mount
 #[derive(Debug, Clone)]
 pub struct VirtiofsFilesystem {
@@ -23,11 +50,41 @@ pub struct VirtiofsFilesystem {
     pub readonly: bool,
 }
 
+/// An additional block device attached after the root disk
+#[derive(Debug, Clone)]
+pub struct AdditionalDisk {
+    /// Path to the disk image on the host
+    pub path: String,
+    /// Disk image format (e.g. "raw", "qcow2")
+    pub format: String,
+    /// Serial number exposed to the guest (visible in /dev/disk/by-id)
+    pub serial: Option<String>,
+    /// Host cache and I/O settings
+    pub io: DiskIoConfig,
+}
+
+/// An ISO image attached as a read-only cdrom
+#[derive(Debug, Clone)]
+pub struct Cdrom {
+    /// Path to the ISO image on the host
+    pub path: String,
+    /// Boot from this cdrom before the root disk
+    pub boot: bool,
+}
+
+/// A network interface on a libvirt network, in addition to the primary network
+#[derive(Debug, Clone)]
+pub struct NetworkInterface {
+    /// Name of the libvirt network
+    pub network: String,
+    /// MAC address; assigned by libvirt if unset
+    pub mac: Option<String>,
+}
+
 /// Configuration for firmware debug log output
 #[derive(Debug, Clone)]
 pub enum FirmwareLogOutput {
     /// Write firmware log to a file on the host
-    #[allow(dead_code)]
     File(String),
     /// Make firmware log available via virsh console (pty)
     Console,
@@ -40,14 +97,25 @@ pub struct DomainBuilder {
     uuid: Option<String>,
     memory: Option<u64>, // in MB
     vcpus: Option<u32>,
+    cpu_quota: Option<u32>, // in percent of one host CPU
+    io_weight: Option<u16>,
+    hugepages: bool,
+    hugepage_size_kib: Option<u64>, // Host default huge page size if unset
+    memory_backing: MemoryBacking,
     disk_path: Option<String>,
+    disk_format: Option<String>,
+    disk_io: DiskIoConfig,
     transient_disk: bool, // Use transient disk with temporary overlay
     network: Option<String>,
     vnc_port: Option<u16>,
     kernel_args: Option<String>,
     metadata: HashMap<String, String>,
     qemu_args: Vec<String>,
+    fw_cfg_entries: Vec<(String, String)>, // QEMU firmware configuration items
     virtiofs_filesystems: Vec<VirtiofsFilesystem>,
+    additional_disks: Vec<AdditionalDisk>,
+    cdroms: Vec<Cdrom>,
+    interfaces: Vec<NetworkInterface>,
     firmware: Option<FirmwareType>,
     tpm: bool,
     ovmf_code_path: Option<String>, // Custom OVMF_CODE path for secure boot
@@ -55,6 +123,8 @@ pub struct DomainBuilder {
     nvram_template: Option<String>, // Custom NVRAM template with enrolled keys
     nvram_format: Option<String>,   // Format of NVRAM template (raw, qcow2)
     firmware_log: Option<FirmwareLogOutput>, // OVMF debug log output via isa-debugcon
+    rtc: RtcConfig,
+    host_devices: Vec<HostDevice>,
 }
 
 impl Default for DomainBuilder {
@@ -71,14 +141,25 @@ impl DomainBuilder {
             uuid: None,
             memory: None,
             vcpus: None,
+            cpu_quota: None,
+            io_weight: None,
+            hugepages: false,
+            hugepage_size_kib: None,
+            memory_backing: MemoryBacking::default(),
             disk_path: None,
+            disk_format: None,
+            disk_io: DiskIoConfig::default(),
             transient_disk: false,
             network: None,
             vnc_port: None,
             kernel_args: None,
             metadata: HashMap::new(),
             qemu_args: Vec::new(),
+            fw_cfg_entries: Vec::new(),
             virtiofs_filesystems: Vec::new(),
+            additional_disks: Vec::new(),
+            cdroms: Vec::new(),
+            interfaces: Vec::new(),
             firmware: None, // Defaults to UEFI
             tpm: true,      // Default to enabled
             ovmf_code_path: None,
@@ -86,6 +167,8 @@ impl DomainBuilder {
             nvram_template: None,
             nvram_format: None,
             firmware_log: Some(FirmwareLogOutput::Console), // Default to pty for virsh console access
+            rtc: RtcConfig::default(),
+            host_devices: Vec::new(),
         }
     }
 
@@ -107,12 +190,50 @@ impl DomainBuilder {
         self
     }
 
+    /// Limit the CPU time of the whole domain, in percent of one host CPU
+    pub fn with_cpu_quota(mut self, percent: u32) -> Self {
+        self.cpu_quota = Some(percent);
+        self
+    }
+
+    /// Set the relative block I/O weight (100-1000)
+    pub fn with_io_weight(mut self, weight: u16) -> Self {
+        self.io_weight = Some(weight);
+        self
+    }
+
+    /// Back the guest memory with huge pages, of the given size in KiB or
+    /// the host default size
+    pub fn with_hugepages(mut self, page_size_kib: Option<u64>) -> Self {
+        self.hugepages = true;
+        self.hugepage_size_kib = page_size_kib;
+        self
+    }
+
+    /// Set how the guest memory is backed on the host
+    pub fn with_memory_backing(mut self, backing: MemoryBacking) -> Self {
+        self.memory_backing = backing;
+        self
+    }
+
     /// Set disk path
     pub fn with_disk(mut self, disk_path: &str) -> Self {
         self.disk_path = Some(disk_path.to_string());
         self
     }
 
+    /// Set disk format (raw, qcow2); detected from the file extension if unset
+    pub fn with_disk_format(mut self, format: &str) -> Self {
+        self.disk_format = Some(format.to_string());
+        self
+    }
+
+    /// Set the host cache and I/O settings of the disk
+    pub fn with_disk_io(mut self, io: DiskIoConfig) -> Self {
+        self.disk_io = io;
+        self
+    }
+
     /// Enable transient disk (creates temporary overlay, base disk opened read-only)
     pub fn with_transient_disk(mut self, transient: bool) -> Self {
         self.transient_disk = transient;
@@ -126,14 +247,12 @@ impl DomainBuilder {
     }
 
     /// Enable VNC on specified port
-    #[allow(dead_code)]
     pub fn with_vnc(mut self, port: u16) -> Self {
         self.vnc_port = Some(port);
         self
     }
 
     /// Set kernel arguments for direct boot
-    #[allow(dead_code)]
     pub fn with_kernel_args(mut self, kernel_args: &str) -> Self {
         self.kernel_args = Some(kernel_args.to_string());
         self
@@ -145,18 +264,50 @@ impl DomainBuilder {
         self
     }
 
+    /// Add the elements of the bcvk metadata
+    pub fn with_domain_metadata(mut self, metadata: &DomainMetadata) -> Self {
+        self.metadata.extend(metadata.elements());
+        self
+    }
+
     /// Add QEMU command line arguments
     pub fn with_qemu_args(mut self, args: Vec<String>) -> Self {
         self.qemu_args = args;
         self
     }
 
+    /// Add a QEMU firmware configuration (fw_cfg) item, such as an Ignition
+    /// config under `opt/com.coreos/config`
+    pub fn with_fw_cfg_entry(mut self, name: &str, value: &str) -> Self {
+        self.fw_cfg_entries
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
     /// Add a virtiofs filesystem mount
     pub fn with_virtiofs_filesystem(mut self, filesystem: VirtiofsFilesystem) -> Self {
         self.virtiofs_filesystems.push(filesystem);
         self
     }
 
+    /// Attach an additional virtio-blk disk (vdb, vdc, ...)
+    pub fn with_additional_disk(mut self, disk: AdditionalDisk) -> Self {
+        self.additional_disks.push(disk);
+        self
+    }
+
+    /// Attach an ISO image as a cdrom (sda, sdb, ...)
+    pub fn with_cdrom(mut self, cdrom: Cdrom) -> Self {
+        self.cdroms.push(cdrom);
+        self
+    }
+
+    /// Attach a network interface on a libvirt network
+    pub fn with_interface(mut self, interface: NetworkInterface) -> Self {
+        self.interfaces.push(interface);
+        self
+    }
+
     /// Set firmware type (defaults to uefi-secure)
     pub fn with_firmware(mut self, firmware: FirmwareType) -> Self {
         self.firmware = Some(firmware);
@@ -169,6 +320,21 @@ impl DomainBuilder {
         self
     }
 
+    /// Configure the guest's real-time clock (defaults to the host's time in UTC)
+    pub fn with_rtc(mut self, rtc: RtcConfig) -> Self {
+        self.rtc = rtc;
+        self
+    }
+
+    /// Pass a host device through; see [`crate::host_devices`]
+    ///
+    /// A render node provides OpenGL (virgl) acceleration to a virtio-gpu
+    /// device, which libvirt renders through a headless EGL display.
+    pub fn with_host_device(mut self, device: HostDevice) -> Self {
+        self.host_devices.push(device);
+        self
+    }
+
     /// Set custom OVMF_CODE path and format for secure boot
     ///
     /// Format must be specified (either "raw" or "qcow2") and should come from
@@ -198,7 +364,6 @@ impl DomainBuilder {
     /// Options:
     /// - `FirmwareLogOutput::File(path)` - Write to a file on the host
     /// - `FirmwareLogOutput::Console` - Access via `virsh console <domain> serial1`
-    #[allow(dead_code)]
     pub fn with_firmware_log(mut self, output: FirmwareLogOutput) -> Self {
         self.firmware_log = Some(output);
         self
@@ -207,11 +372,7 @@ impl DomainBuilder {
     /// Build the domain XML
     pub fn build_xml(self) -> Result<String> {
         let name = self.name.ok_or_else(|| eyre!("Domain name is required"))?;
-        let memory = self.memory.unwrap_or_else(|| {
-            crate::utils::parse_memory_to_mb(DEFAULT_MEMORY_USER_STR)
-                .unwrap()
-                .into()
-        });
+        let memory = self.memory.unwrap_or(DEFAULT_MEMORY_MB);
         let vcpus = self.vcpus.unwrap_or_else(default_vcpus);
         let uuid = self.uuid.unwrap_or_else(|| Uuid::new_v4().to_string());
 
@@ -242,6 +403,30 @@ impl DomainBuilder {
         )?;
         writer.write_text_element("vcpu", &vcpus.to_string())?;
 
+        if let Some(percent) = self.cpu_quota {
+            // The global quota covers all vCPU threads together, unlike <quota>
+            writer.start_element("cputune", &[])?;
+            writer.write_text_element("global_period", &CPU_QUOTA_PERIOD_US.to_string())?;
+            writer.write_text_element(
+                "global_quota",
+                &(u64::from(percent) * CPU_QUOTA_PERIOD_US / 100).to_string(),
+            )?;
+            writer.end_element("cputune")?;
+        }
+        if let Some(weight) = self.io_weight {
+            writer.start_element("blkiotune", &[])?;
+            writer.write_text_element("weight", &weight.to_string())?;
+            writer.end_element("blkiotune")?;
+        }
+
+        if !self.fw_cfg_entries.is_empty() {
+            writer.start_element("sysinfo", &[("type", "fwcfg")])?;
+            for (name, value) in &self.fw_cfg_entries {
+                writer.write_text_element_with_attrs("entry", value, &[("name", name)])?;
+            }
+            writer.end_element("sysinfo")?;
+        }
+
         // OS section with firmware configuration
         let use_uefi = self.firmware != Some(FirmwareType::Bios);
         let secure_boot = use_uefi
@@ -312,7 +497,12 @@ impl DomainBuilder {
             }
         }
 
-        writer.write_empty_element("boot", &[("dev", "hd")])?;
+        // Booting from a cdrom needs a boot order on the devices instead,
+        // which libvirt doesn't allow together with <boot dev>
+        let boot_cdroms = self.cdroms.iter().filter(|c| c.boot).count();
+        if boot_cdroms == 0 {
+            writer.write_empty_element("boot", &[("dev", "hd")])?;
+        }
 
         // Add kernel arguments if specified (for direct boot)
         if let Some(ref kargs) = self.kernel_args {
@@ -323,6 +513,17 @@ impl DomainBuilder {
 
         // Add memory backing for shared memory support (required for virtiofs)
         writer.start_element("memoryBacking", &[])?;
+        if self.hugepages {
+            writer.start_element("hugepages", &[])?;
+            if let Some(size) = self.hugepage_size_kib {
+                writer
+                    .write_empty_element("page", &[("size", &size.to_string()), ("unit", "KiB")])?;
+            }
+            writer.end_element("hugepages")?;
+        }
+        if self.memory_backing == MemoryBacking::Locked {
+            writer.write_empty_element("locked", &[])?;
+        }
         writer.write_empty_element("source", &[("type", "memfd")])?;
         writer.write_empty_element("access", &[("mode", "shared")])?;
         writer.end_element("memoryBacking")?;
@@ -347,8 +548,17 @@ impl DomainBuilder {
         writer.write_empty_element("cpu", &[("mode", arch_config.cpu_mode())])?;
 
         // Clock and lifecycle configuration
-        writer.start_element("clock", &[("offset", "utc")])?;
-        arch_config.write_timers(&mut writer)?;
+        let start = match self.rtc.base {
+            RtcBase::At(secs) => Some(secs.to_string()),
+            RtcBase::Utc | RtcBase::Localtime => None,
+        };
+        let clock_attrs = match (&self.rtc.base, &start) {
+            (RtcBase::Localtime, _) => vec![("offset", "localtime")],
+            (_, Some(start)) => vec![("offset", "absolute"), ("start", start.as_str())],
+            _ => vec![("offset", "utc")],
+        };
+        writer.start_element("clock", &clock_attrs)?;
+        arch_config.write_timers(&mut writer, &self.rtc)?;
         writer.end_element("clock")?;
 
         writer.write_text_element("on_poweroff", "destroy")?;
@@ -360,17 +570,20 @@ impl DomainBuilder {
 
         // Disk
         if let Some(ref disk_path) = self.disk_path {
-            // Auto-detect disk format from file extension
-            let disk_type = if disk_path.ends_with(".qcow2") {
-                "qcow2"
-            } else {
-                "raw"
+            // Auto-detect disk format from file extension unless given
+            let disk_type = match self.disk_format.as_deref() {
+                Some(format) => format,
+                None if disk_path.ends_with(".qcow2") => "qcow2",
+                None => "raw",
             };
 
             writer.start_element("disk", &[("type", "file"), ("device", "disk")])?;
-            writer.write_empty_element("driver", &[("name", "qemu"), ("type", disk_type)])?;
+            write_disk_driver(&mut writer, disk_type, &self.disk_io)?;
             writer.write_empty_element("source", &[("file", disk_path)])?;
             writer.write_empty_element("target", &[("dev", "vda"), ("bus", "virtio")])?;
+            if boot_cdroms > 0 {
+                writer.write_empty_element("boot", &[("order", &(boot_cdroms + 1).to_string())])?;
+            }
             if self.transient_disk {
                 // shareBacking='yes' allows multiple VMs to share the backing image
                 // Libvirt creates a temporary QCOW2 overlay for writes
@@ -379,6 +592,49 @@ impl DomainBuilder {
             writer.end_element("disk")?;
         }
 
+        // Additional disks start at vdb, after the root disk
+        if self.additional_disks.len() > 25 {
+            return Err(eyre!(
+                "Too many additional disks: {}",
+                self.additional_disks.len()
+            ));
+        }
+        for (idx, disk) in self.additional_disks.iter().enumerate() {
+            let dev = format!("vd{}", (b'b' + idx as u8) as char);
+            writer.start_element("disk", &[("type", "file"), ("device", "disk")])?;
+            write_disk_driver(&mut writer, &disk.format, &disk.io)?;
+            writer.write_empty_element("source", &[("file", &disk.path)])?;
+            writer.write_empty_element("target", &[("dev", &dev), ("bus", "virtio")])?;
+            if let Some(ref serial) = disk.serial {
+                writer.write_text_element("serial", serial)?;
+            }
+            writer.end_element("disk")?;
+        }
+
+        if self.cdroms.len() > 26 {
+            return Err(eyre!("Too many cdroms: {}", self.cdroms.len()));
+        }
+        // There is no SATA controller on non-x86_64 machine types
+        let cdrom_bus = if arch_config.arch == "x86_64" {
+            "sata"
+        } else {
+            "scsi"
+        };
+        let mut boot_order = 0;
+        for (idx, cdrom) in self.cdroms.iter().enumerate() {
+            let dev = format!("sd{}", (b'a' + idx as u8) as char);
+            writer.start_element("disk", &[("type", "file"), ("device", "cdrom")])?;
+            writer.write_empty_element("driver", &[("name", "qemu"), ("type", "raw")])?;
+            writer.write_empty_element("source", &[("file", &cdrom.path)])?;
+            writer.write_empty_element("target", &[("dev", &dev), ("bus", cdrom_bus)])?;
+            writer.write_empty_element("readonly", &[])?;
+            if cdrom.boot {
+                boot_order += 1;
+                writer.write_empty_element("boot", &[("order", &boot_order.to_string())])?;
+            }
+            writer.end_element("disk")?;
+        }
+
         // Network
         let network_config = self.network.as_deref().unwrap_or("default");
         match network_config {
@@ -411,6 +667,16 @@ impl DomainBuilder {
             }
         }
 
+        for interface in &self.interfaces {
+            writer.start_element("interface", &[("type", "network")])?;
+            if let Some(ref mac) = interface.mac {
+                writer.write_empty_element("mac", &[("address", mac)])?;
+            }
+            writer.write_empty_element("source", &[("network", &interface.network)])?;
+            writer.write_empty_element("model", &[("type", "virtio")])?;
+            writer.end_element("interface")?;
+        }
+
         // Serial console, see https://libvirt.org/formatdomain.html#relationship-between-serial-ports-and-consoles
         // We allocate a platform-specific default for early console stuff like bootloaders,
         // and a platform-independent `hvc0` that can be referenced independently.
@@ -444,6 +710,39 @@ impl DomainBuilder {
             }
         }
 
+        // PCI devices assigned to the domain; bcvk does not rebind drivers,
+        // so they are not managed by libvirt
+        for device in &self.host_devices {
+            if let Some((domain, bus, slot, function)) = device.pci_address() {
+                writer.start_element(
+                    "hostdev",
+                    &[("mode", "subsystem"), ("type", "pci"), ("managed", "no")],
+                )?;
+                writer.start_element("source", &[])?;
+                writer.write_empty_element(
+                    "address",
+                    &[
+                        ("domain", &format!("0x{domain}")),
+                        ("bus", &format!("0x{bus}")),
+                        ("slot", &format!("0x{slot}")),
+                        ("function", &format!("0x{function}")),
+                    ],
+                )?;
+                writer.end_element("source")?;
+                writer.end_element("hostdev")?;
+            }
+        }
+
+        let render_node = self.host_devices.iter().find_map(|d| match d {
+            HostDevice::RenderNode(path) => Some(path.as_str()),
+            HostDevice::Vfio(_) => None,
+        });
+        if let Some(render_node) = render_node {
+            writer.start_element("graphics", &[("type", "egl-headless")])?;
+            writer.write_empty_element("gl", &[("rendernode", render_node)])?;
+            writer.end_element("graphics")?;
+        }
+
         // VNC graphics if enabled
         if let Some(vnc_port) = self.vnc_port {
             writer.write_empty_element(
@@ -454,6 +753,14 @@ impl DomainBuilder {
                     ("listen", "127.0.0.1"),
                 ],
             )?;
+        }
+        if render_node.is_some() {
+            writer.start_element("video", &[])?;
+            writer.start_element("model", &[("type", "virtio"), ("heads", "1")])?;
+            writer.write_empty_element("acceleration", &[("accel3d", "yes")])?;
+            writer.end_element("model")?;
+            writer.end_element("video")?;
+        } else if self.vnc_port.is_some() {
             writer.start_element("video", &[])?;
             writer.write_empty_element("model", &[("type", "vga")])?;
             writer.end_element("video")?;
@@ -497,7 +804,7 @@ impl DomainBuilder {
             writer.start_element("metadata", &[])?;
             writer.start_element(
                 "bootc:container",
-                &[("xmlns:bootc", "https://github.com/containers/bootc")],
+                &[("xmlns:bootc", crate::domain_metadata::NAMESPACE)],
             )?;
 
             for (key, value) in &self.metadata {
@@ -520,6 +827,18 @@ impl DomainBuilder {
     }
 }
 
+/// Write the `<driver>` element of a disk
+fn write_disk_driver(writer: &mut XmlWriter, format: &str, io: &DiskIoConfig) -> Result<()> {
+    let mut attrs = vec![("name", "qemu"), ("type", format)];
+    if let Some(cache) = io.cache {
+        attrs.push(("cache", cache.as_str()));
+    }
+    if let Some(aio) = io.aio {
+        attrs.push(("io", aio.as_str()));
+    }
+    writer.write_empty_element("driver", &attrs)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -546,6 +865,241 @@ mod tests {
         // Libvirt will automatically detect the appropriate emulator
     }
 
+    #[test]
+    fn test_resource_limits() {
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .build_xml()
+            .unwrap();
+        assert!(!xml.contains("cputune"));
+        assert!(!xml.contains("blkiotune"));
+
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_cpu_quota(150)
+            .with_io_weight(200)
+            .build_xml()
+            .unwrap();
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let cputune = dom.find("cputune").unwrap();
+        assert_eq!(
+            cputune.find("global_period").unwrap().text_content(),
+            "100000"
+        );
+        assert_eq!(
+            cputune.find("global_quota").unwrap().text_content(),
+            "150000"
+        );
+        let blkiotune = dom.find("blkiotune").unwrap();
+        assert_eq!(blkiotune.find("weight").unwrap().text_content(), "200");
+    }
+
+    #[test]
+    fn test_fw_cfg_entries() {
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .build_xml()
+            .unwrap();
+        assert!(!xml.contains("sysinfo"));
+
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_fw_cfg_entry(
+                "opt/com.coreos/config",
+                r#"{"ignition":{"version":"3.4.0"}}"#,
+            )
+            .build_xml()
+            .unwrap();
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let sysinfo = dom.find("sysinfo").unwrap();
+        assert_eq!(sysinfo.attributes.get("type").unwrap(), "fwcfg");
+        let entry = sysinfo.find("entry").unwrap();
+        assert_eq!(
+            entry.attributes.get("name").unwrap(),
+            "opt/com.coreos/config"
+        );
+        assert_eq!(entry.text_content(), r#"{"ignition":{"version":"3.4.0"}}"#);
+    }
+
+    #[test]
+    fn test_memory_backing() {
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .build_xml()
+            .unwrap();
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let backing = dom.find("memoryBacking").unwrap();
+        assert!(backing.find("hugepages").is_none());
+        assert!(backing.find("locked").is_none());
+        assert_eq!(backing.find("access").unwrap().attributes["mode"], "shared");
+
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_hugepages(None)
+            .build_xml()
+            .unwrap();
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let hugepages = dom.find("hugepages").unwrap();
+        assert!(hugepages.find("page").is_none());
+
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_hugepages(Some(1048576))
+            .with_memory_backing(MemoryBacking::Locked)
+            .build_xml()
+            .unwrap();
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let backing = dom.find("memoryBacking").unwrap();
+        let page = backing.find("hugepages").unwrap().find("page").unwrap();
+        assert_eq!(page.attributes["size"], "1048576");
+        assert_eq!(page.attributes["unit"], "KiB");
+        assert!(backing.find("locked").is_some());
+        assert_eq!(backing.find("source").unwrap().attributes["type"], "memfd");
+    }
+
+    #[test]
+    fn test_additional_disks() {
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_disk("/path/to/root.qcow2")
+            .with_additional_disk(AdditionalDisk {
+                path: "/path/to/data.qcow2".to_string(),
+                format: "qcow2".to_string(),
+                serial: Some("data".to_string()),
+                io: DiskIoConfig::default(),
+            })
+            .with_additional_disk(AdditionalDisk {
+                path: "/path/to/scratch.raw".to_string(),
+                format: "raw".to_string(),
+                serial: None,
+                io: "cache=unsafe,aio=io_uring".parse().unwrap(),
+            })
+            .build_xml()
+            .unwrap();
+
+        let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+        let devices = dom.find("devices").unwrap();
+        let summary: Vec<_> = devices
+            .children
+            .iter()
+            .filter(|c| c.name == "disk")
+            .map(|d| {
+                (
+                    d.find("source").unwrap().attributes["file"].as_str(),
+                    d.find("target").unwrap().attributes["dev"].as_str(),
+                    d.find("driver").unwrap().attributes["type"].as_str(),
+                    d.find("serial").map(|s| s.text_content()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/path/to/root.qcow2", "vda", "qcow2", None),
+                ("/path/to/data.qcow2", "vdb", "qcow2", Some("data")),
+                ("/path/to/scratch.raw", "vdc", "raw", None),
+            ]
+        );
+        assert!(xml.contains(r#"<driver name="qemu" type="qcow2"/>"#));
+        assert!(xml.contains(r#"<driver name="qemu" type="raw" cache="unsafe" io="io_uring"/>"#));
+    }
+
+    #[test]
+    fn test_cdroms() {
+        let build = |boot: bool| {
+            let xml = DomainBuilder::new()
+                .with_name("test-domain")
+                .with_disk("/path/to/root.qcow2")
+                .with_cdrom(Cdrom {
+                    path: "/path/to/drivers.iso".to_string(),
+                    boot: false,
+                })
+                .with_cdrom(Cdrom {
+                    path: "/path/to/installer.iso".to_string(),
+                    boot,
+                })
+                .build_xml()
+                .unwrap();
+            crate::xml_utils::parse_xml_dom(&xml).unwrap()
+        };
+
+        let dom = build(false);
+        assert_eq!(
+            dom.find("os").unwrap().find("boot").unwrap().attributes["dev"],
+            "hd"
+        );
+        let disks: Vec<_> = dom
+            .find("devices")
+            .unwrap()
+            .children
+            .iter()
+            .filter(|c| c.name == "disk")
+            .map(|d| {
+                (
+                    d.attributes["device"].as_str(),
+                    d.find("target").unwrap().attributes["dev"].as_str(),
+                    d.find("readonly").is_some(),
+                    d.find("boot").is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            disks,
+            [
+                ("disk", "vda", false, false),
+                ("cdrom", "sda", true, false),
+                ("cdrom", "sdb", true, false),
+            ]
+        );
+
+        // Booting from the cdrom orders the devices instead
+        let dom = build(true);
+        assert!(dom.find("os").unwrap().find("boot").is_none());
+        let boot_order: Vec<_> = dom
+            .find("devices")
+            .unwrap()
+            .children
+            .iter()
+            .filter(|c| c.name == "disk")
+            .map(|d| {
+                (
+                    d.find("source").unwrap().attributes["file"].as_str(),
+                    d.find("boot").map(|b| b.attributes["order"].as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            boot_order,
+            [
+                ("/path/to/root.qcow2", Some("2")),
+                ("/path/to/drivers.iso", None),
+                ("/path/to/installer.iso", Some("1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disk_format() {
+        let cases = [
+            ("/path/to/disk.qcow2", None, "qcow2"),
+            ("/path/to/disk.img", None, "raw"),
+            ("/path/to/disk.img", Some("qcow2"), "qcow2"),
+            ("/path/to/disk.qcow2", Some("raw"), "raw"),
+        ];
+        for (path, format, expected) in cases {
+            let mut builder = DomainBuilder::new()
+                .with_name("test-domain")
+                .with_disk(path);
+            if let Some(format) = format {
+                builder = builder.with_disk_format(format);
+            }
+            let xml = builder.build_xml().unwrap();
+            let dom = crate::xml_utils::parse_xml_dom(&xml).unwrap();
+            let driver = dom.find("disk").unwrap().find("driver").unwrap();
+            assert_eq!(driver.attributes["type"], expected, "{path} {format:?}");
+        }
+    }
+
     #[test]
     fn test_domain_with_metadata() {
         let xml = DomainBuilder::new()
@@ -560,6 +1114,19 @@ mod tests {
             xml.contains("<bootc:source-image>quay.io/fedora/fedora-bootc:42</bootc:source-image>")
         );
         assert!(xml.contains("<bootc:filesystem>xfs</bootc:filesystem>"));
+
+        let metadata = DomainMetadata {
+            source_image: Some("quay.io/fedora/fedora-bootc:42".into()),
+            ssh_port: Some(2222),
+            labels: vec!["a".into(), "b".into()],
+            ..DomainMetadata::new()
+        };
+        let xml = DomainBuilder::new()
+            .with_name("test-domain")
+            .with_domain_metadata(&metadata)
+            .build_xml()
+            .unwrap();
+        assert_eq!(DomainMetadata::from_xml(&xml).unwrap(), Some(metadata));
     }
 
     #[test]
@@ -587,6 +1154,25 @@ mod tests {
             .build_xml()
             .unwrap();
         assert!(!xml.contains("<interface"));
+
+        // Additional interfaces on libvirt networks
+        let xml = DomainBuilder::new()
+            .with_name("test")
+            .with_network("none")
+            .with_interface(NetworkInterface {
+                network: "bcvk-cluster".into(),
+                mac: Some("52:54:00:12:34:56".into()),
+            })
+            .with_interface(NetworkInterface {
+                network: "other".into(),
+                mac: None,
+            })
+            .build_xml()
+            .unwrap();
+        assert!(xml.contains("<mac address=\"52:54:00:12:34:56\"/>"));
+        assert!(xml.contains("<source network=\"bcvk-cluster\"/>"));
+        assert!(xml.contains("<source network=\"other\"/>"));
+        assert_eq!(xml.matches("<mac ").count(), 1);
     }
 
     #[test]
@@ -601,6 +1187,24 @@ mod tests {
         assert!(xml.contains("model type=\"vga\""));
     }
 
+    #[test]
+    fn test_host_devices() {
+        let xml = DomainBuilder::new()
+            .with_name("test")
+            .with_host_device(HostDevice::Vfio("0000:3b:00.1".into()))
+            .with_host_device(HostDevice::RenderNode("/dev/dri/renderD128".into()))
+            .build_xml()
+            .unwrap();
+
+        assert!(xml.contains("<hostdev mode=\"subsystem\" type=\"pci\" managed=\"no\">"));
+        assert!(xml
+            .contains("<address domain=\"0x0000\" bus=\"0x3b\" slot=\"0x00\" function=\"0x1\"/>"));
+        assert!(xml.contains("<graphics type=\"egl-headless\">"));
+        assert!(xml.contains("<gl rendernode=\"/dev/dri/renderD128\"/>"));
+        assert!(xml.contains("<acceleration accel3d=\"yes\"/>"));
+        assert!(!xml.contains("model type=\"vga\""));
+    }
+
     #[test]
     fn test_architecture_detection() {
         let xml = DomainBuilder::new()
@@ -708,6 +1312,36 @@ mod tests {
         assert!(!xml_disabled.contains("backend type=\"emulator\""));
     }
 
+    #[test]
+    fn test_rtc_configuration() {
+        let xml = DomainBuilder::new()
+            .with_name("test-rtc-default")
+            .build_xml()
+            .unwrap();
+        assert!(xml.contains("<clock offset=\"utc\">"));
+        assert!(!xml.contains("track="));
+        assert!(!xml.contains("kvmclock"));
+
+        let xml = DomainBuilder::new()
+            .with_name("test-rtc-localtime")
+            .with_rtc("base=localtime,clock=vm".parse().unwrap())
+            .build_xml()
+            .unwrap();
+        assert!(xml.contains("<clock offset=\"localtime\">"));
+        assert!(xml.contains("track=\"guest\""));
+
+        let xml = DomainBuilder::new()
+            .with_name("test-rtc-frozen")
+            .with_rtc(RtcConfig::frozen_at(1_893_456_000))
+            .build_xml()
+            .unwrap();
+        assert!(xml.contains("<clock offset=\"absolute\" start=\"1893456000\">"));
+        assert!(xml.contains("track=\"guest\""));
+        if std::env::consts::ARCH == "x86_64" {
+            assert!(xml.contains("<timer name=\"kvmclock\" present=\"no\"/>"));
+        }
+    }
+
     #[test]
     fn test_secure_boot_with_custom_firmware() {
         let xml = DomainBuilder::new()
@@ -0,0 +1,80 @@
+//! Classes of failures bcvk exits with a specific status for
+//!
+//! Errors are tagged by making a [`Failure`] their root cause where they are
+//! raised, e.g. `Report::new(Failure::ImageNotFound).wrap_err("...")`, so
+//! that the exit status of `bcvk` does not depend on the wording of error
+//! messages; see EXIT STATUS in bcvk(8).
+
+/// A class of failures scripts can branch on
+///
+/// Other errors make `bcvk` exit with 1, and invalid command lines with 2,
+/// as clap does. The statuses start at [`Failure::FIRST_EXIT_CODE`], out of
+/// the way of the small statuses commands in VMs commonly exit with, which
+/// commands like `ephemeral run-ssh` pass through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Failure {
+    /// The container image does not exist
+    #[error("image not found")]
+    ImageNotFound,
+    /// KVM, libvirt or another part of the virtualization stack is unusable
+    #[error("hypervisor unavailable")]
+    HypervisorUnavailable,
+    /// The VM did not boot, or SSH did not become available, in time
+    #[error("timed out waiting for the VM")]
+    SshTimeout,
+    /// Installing the image failed inside the installer VM
+    #[error("installation failed in the guest")]
+    InstallFailed,
+}
+
+impl Failure {
+    /// The lowest exit status reserved for failures
+    pub const FIRST_EXIT_CODE: i32 = 240;
+
+    /// The exit status for this failure
+    pub fn exit_code(self) -> i32 {
+        Self::FIRST_EXIT_CODE
+            + match self {
+                Self::ImageNotFound => 0,
+                Self::HypervisorUnavailable => 1,
+                Self::SshTimeout => 2,
+                Self::InstallFailed => 3,
+            }
+    }
+
+    /// The failure `report` is tagged with, if any
+    pub fn of(report: &color_eyre::Report) -> Option<Self> {
+        report.downcast_ref::<Self>().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Report;
+
+    use super::*;
+
+    #[test]
+    fn test_failure_exit_codes() {
+        let failures = [
+            Failure::ImageNotFound,
+            Failure::HypervisorUnavailable,
+            Failure::SshTimeout,
+            Failure::InstallFailed,
+        ];
+        let codes: Vec<i32> = failures.iter().map(|f| f.exit_code()).collect();
+        assert_eq!(codes, [240, 241, 242, 243]);
+        // ssh exits with 255 for its own errors
+        assert!(codes.iter().all(|c| (240..255).contains(c)));
+    }
+
+    #[test]
+    fn test_failure_of() {
+        let tagged = Report::new(Failure::ImageNotFound)
+            .wrap_err("Image foo is not in container storage")
+            .wrap_err("Creating VM");
+        assert_eq!(Failure::of(&tagged), Some(Failure::ImageNotFound));
+        let untagged = color_eyre::eyre::eyre!("image not known").wrap_err("Creating VM");
+        assert_eq!(Failure::of(&untagged), None);
+    }
+}
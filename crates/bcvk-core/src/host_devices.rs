@@ -0,0 +1,295 @@
+//! Passing host devices through to VMs
+//!
+//! Two kinds of devices are supported:
+//!
+//! - PCI devices (e.g. GPUs or other accelerators) bound to the `vfio-pci`
+//!   driver, which are assigned to the VM exclusively. This requires the
+//!   IOMMU to be enabled on the host, and all devices in the IOMMU group of
+//!   the device to be bound to `vfio-pci` as well.
+//! - DRM render nodes, which a virtio-gpu device with virgl (OpenGL) and
+//!   Venus (Vulkan) acceleration renders on, while the host keeps using the
+//!   GPU.
+//!
+//! [`HostDevice::check`] verifies on the host that a device can be passed
+//! through, failing with a description of what to fix otherwise.
+
+use std::str::FromStr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// Where PCI devices are listed in sysfs
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Where IOMMU groups are listed in sysfs; empty without an IOMMU
+const SYSFS_IOMMU_GROUPS: &str = "/sys/kernel/iommu_groups";
+
+/// The directory of DRM device nodes, which also means its first render node
+pub const DRI_DIR: &str = "/dev/dri";
+
+/// The VFIO container device, needed along with the group devices
+const VFIO_CONTAINER: &str = "/dev/vfio/vfio";
+
+/// The driver PCI devices must be bound to for passthrough
+const VFIO_DRIVER: &str = "vfio-pci";
+
+/// Drivers which may be bound to other devices in the IOMMU group of a
+/// passed through device; PCIe ports are not assigned themselves
+const IOMMU_GROUP_ALLOWED_DRIVERS: &[&str] = &[VFIO_DRIVER, "pcieport"];
+
+/// A host device to pass through to a VM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostDevice {
+    /// A PCI device, by its full address (`0000:01:00.0`)
+    Vfio(String),
+    /// A DRM render node, or [`DRI_DIR`] until [`HostDevice::check`]
+    /// resolved it
+    RenderNode(Utf8PathBuf),
+}
+
+/// Normalize a PCI address to the `DDDD:BB:SS.F` form used by sysfs
+fn parse_pci_address(s: &str) -> Result<String> {
+    let full = if s.matches(':').count() == 1 {
+        format!("0000:{s}")
+    } else {
+        s.to_owned()
+    };
+    let valid = full
+        .split_once('.')
+        .and_then(|(dbs, function)| {
+            let parts: Vec<&str> = dbs.split(':').collect();
+            let hex = |p: &str, len: usize| p.len() == len && p.chars().all(|c| c.is_ascii_hexdigit());
+            matches!(parts.as_slice(), [domain, bus, slot] if hex(domain, 4) && hex(bus, 2) && hex(slot, 2))
+                .then_some(function)
+        })
+        .is_some_and(|f| matches!(f, "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7"));
+    if !valid {
+        return Err(eyre!(
+            "Invalid PCI address '{s}'. Expected e.g. 0000:01:00.0 (see lspci -D)"
+        ));
+    }
+    Ok(full.to_ascii_lowercase())
+}
+
+impl FromStr for HostDevice {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(address) = s.strip_prefix("vfio:") {
+            return Ok(Self::Vfio(parse_pci_address(address)?));
+        }
+        let path = Utf8Path::new(s);
+        if path == DRI_DIR
+            || (path.parent() == Some(Utf8Path::new(DRI_DIR))
+                && path.file_name().is_some_and(|n| n.starts_with("renderD")))
+        {
+            return Ok(Self::RenderNode(path.to_owned()));
+        }
+        Err(eyre!(
+            "Invalid device '{s}'. Expected vfio:PCI_ADDRESS, {DRI_DIR} or {DRI_DIR}/renderDN"
+        ))
+    }
+}
+
+impl std::fmt::Display for HostDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vfio(address) => write!(f, "vfio:{address}"),
+            Self::RenderNode(path) => write!(f, "{path}"),
+        }
+    }
+}
+
+/// The name of the target of a sysfs symlink such as `driver`
+fn link_name(path: &Utf8Path) -> Option<String> {
+    let target = std::fs::read_link(path).ok()?;
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The IOMMU group of the PCI device at `address`
+fn iommu_group(address: &str) -> Result<String> {
+    let device = Utf8Path::new(SYSFS_PCI_DEVICES).join(address);
+    if !device.exists() {
+        return Err(eyre!("No PCI device {address} (see lspci -D)"));
+    }
+    let iommu_enabled = std::fs::read_dir(SYSFS_IOMMU_GROUPS)
+        .map(|mut groups| groups.next().is_some())
+        .unwrap_or(false);
+    if !iommu_enabled {
+        return Err(eyre!(
+            "The IOMMU is not enabled, which PCI passthrough requires. Enable VT-d or AMD-Vi in the firmware, and on Intel boot the host with intel_iommu=on"
+        ));
+    }
+    link_name(&device.join("iommu_group"))
+        .ok_or_else(|| eyre!("PCI device {address} is not in an IOMMU group"))
+}
+
+/// Whether the current user can read and write `path`
+fn check_node_access(path: &Utf8Path, hint: &str) -> Result<()> {
+    if !path.exists() {
+        return Err(eyre!("{path} does not exist"));
+    }
+    rustix::fs::access(
+        path.as_std_path(),
+        rustix::fs::Access::READ_OK | rustix::fs::Access::WRITE_OK,
+    )
+    .map_err(|_| eyre!("No permission to open {path}; {hint}"))
+}
+
+impl HostDevice {
+    /// Check that the device can be passed through, resolving [`DRI_DIR`]
+    /// to its first render node; see also [`HostDevice::check_access`]
+    pub fn check(&mut self) -> Result<()> {
+        match self {
+            Self::Vfio(address) => {
+                let group = iommu_group(address)?;
+                let device = Utf8Path::new(SYSFS_PCI_DEVICES).join(&*address);
+                match link_name(&device.join("driver")) {
+                    Some(driver) if driver == VFIO_DRIVER => {}
+                    driver => {
+                        let driver =
+                            driver.map_or("no driver".to_owned(), |d| format!("driver {d}"));
+                        return Err(eyre!(
+                            "PCI device {address} is bound to {driver}, not {VFIO_DRIVER}; bind it with e.g. `driverctl set-override {address} {VFIO_DRIVER}`"
+                        ));
+                    }
+                }
+                let members = Utf8Path::new(SYSFS_IOMMU_GROUPS)
+                    .join(&group)
+                    .join("devices");
+                let mut others = Vec::new();
+                for entry in members
+                    .read_dir_utf8()
+                    .with_context(|| format!("Reading {members}"))?
+                {
+                    let entry = entry?;
+                    let driver = link_name(&entry.path().join("driver"))
+                        .filter(|d| !IOMMU_GROUP_ALLOWED_DRIVERS.contains(&d.as_str()));
+                    if let Some(driver) = driver {
+                        others.push(format!("{} ({driver})", entry.file_name()));
+                    }
+                }
+                if !others.is_empty() {
+                    others.sort();
+                    return Err(eyre!(
+                        "IOMMU group {group} of PCI device {address} also contains devices bound to host drivers, which must be bound to {VFIO_DRIVER} too: {}",
+                        others.join(", ")
+                    ));
+                }
+                Ok(())
+            }
+            Self::RenderNode(path) => {
+                if path == DRI_DIR {
+                    let mut nodes: Vec<Utf8PathBuf> = Utf8Path::new(DRI_DIR)
+                        .read_dir_utf8()
+                        .map(|entries| {
+                            entries
+                                .filter_map(|e| e.ok())
+                                .filter(|e| e.file_name().starts_with("renderD"))
+                                .map(|e| e.into_path())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    nodes.sort();
+                    *path = nodes.into_iter().next().ok_or_else(|| {
+                        eyre!("No DRM render node in {DRI_DIR}; is a GPU driver loaded?")
+                    })?;
+                }
+                if !path.exists() {
+                    return Err(eyre!("{path} does not exist"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the current user can open the device nodes, for a VM
+    /// running as the current user
+    pub fn check_access(&self) -> Result<()> {
+        match self {
+            Self::Vfio(_) => {
+                for node in self.device_nodes()? {
+                    check_node_access(
+                        &node,
+                        "grant access to it, e.g. with a udev rule, or run as root",
+                    )?;
+                }
+                Ok(())
+            }
+            Self::RenderNode(path) => check_node_access(
+                path,
+                "add your user to the group owning it (usually render)",
+            ),
+        }
+    }
+
+    /// The device nodes a container running the VM needs access to
+    pub fn device_nodes(&self) -> Result<Vec<Utf8PathBuf>> {
+        match self {
+            Self::Vfio(address) => Ok(vec![
+                VFIO_CONTAINER.into(),
+                Utf8Path::new("/dev/vfio").join(iommu_group(address)?),
+            ]),
+            Self::RenderNode(path) => Ok(vec![path.clone()]),
+        }
+    }
+
+    /// The PCI address as `(domain, bus, slot, function)`, for a PCI device
+    pub fn pci_address(&self) -> Option<(&str, &str, &str, &str)> {
+        let Self::Vfio(address) = self else {
+            return None;
+        };
+        let (dbs, function) = address.split_once('.')?;
+        let mut parts = dbs.splitn(3, ':');
+        Some((parts.next()?, parts.next()?, parts.next()?, function))
+    }
+}
+
+/// Check that guest memory of `memory_mb` can be locked, as VFIO requires;
+/// unprivileged users can't raise their hard limit
+pub fn check_memlock(memory_mb: u64) -> Result<()> {
+    if rustix::process::geteuid().is_root() {
+        return Ok(());
+    }
+    let limit = rustix::process::getrlimit(rustix::process::Resource::Memlock);
+    match limit.maximum {
+        Some(max) if max < memory_mb * 1024 * 1024 => Err(eyre!(
+            "PCI passthrough locks all {memory_mb}M of guest memory, but the locked memory limit is {}K; raise it (e.g. in /etc/security/limits.conf), or run as root",
+            max / 1024
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_device() {
+        let cases = [
+            ("vfio:0000:01:00.0", Some("vfio:0000:01:00.0")),
+            ("vfio:01:00.0", Some("vfio:0000:01:00.0")),
+            ("vfio:0000:3B:00.1", Some("vfio:0000:3b:00.1")),
+            ("vfio:01:00.8", None),
+            ("vfio:1:00.0", None),
+            ("vfio:", None),
+            ("/dev/dri", Some("/dev/dri")),
+            ("/dev/dri/renderD128", Some("/dev/dri/renderD128")),
+            ("/dev/dri/card0", None),
+            ("/dev/kvm", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = input.parse::<HostDevice>().ok();
+            assert_eq!(
+                parsed.map(|d| d.to_string()).as_deref(),
+                expected,
+                "{input}"
+            );
+        }
+        let device: HostDevice = "vfio:0000:3b:00.1".parse().unwrap();
+        assert_eq!(device.pci_address(), Some(("0000", "3b", "00", "1")));
+    }
+}
@@ -0,0 +1,415 @@
+//! Execution of external commands that operate on the host
+//!
+//! Commands run against host state (virsh, qemu-img, podman) are built as
+//! [`HostCommand`]s, which mirror the parts of [`std::process::Command`] we
+//! use, but run through an executor. By default this just runs the command.
+//! Dry-run mode ([`set_dry_run`], the global `--dry-run` flag of bcvk)
+//! prints commands that would change something instead of running them;
+//! read-only queries (e.g. `virsh dumpxml`) still run so that the rest of
+//! the logic works as usual. Unit tests, also those of crates using this
+//! one with the `test-util` feature, can use `with_executor` and a
+//! `RecordingExecutor` to check which commands would be run.
+
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::de::DeserializeOwned;
+
+/// virsh subcommands which only read state
+const VIRSH_QUERIES: &[&str] = &[
+    "capabilities",
+    "domblkinfo",
+    "domblklist",
+    "domcapabilities",
+    "domid",
+    "domifaddr",
+    "domiflist",
+    "dominfo",
+    "domname",
+    "domstate",
+    "domstats",
+    "domuuid",
+    "dumpxml",
+    "hostname",
+    "list",
+    "net-dumpxml",
+    "net-info",
+    "net-list",
+    "nodeinfo",
+    "nodememstats",
+    "pool-dumpxml",
+    "pool-info",
+    "pool-list",
+    "snapshot-dumpxml",
+    "snapshot-info",
+    "snapshot-list",
+    "uri",
+    "version",
+    "vol-dumpxml",
+    "vol-info",
+    "vol-list",
+    "vol-path",
+];
+
+/// qemu-img subcommands which only read state
+const QEMU_IMG_QUERIES: &[&str] = &["check", "compare", "info", "map", "measure"];
+
+/// podman commands which only read state
+const PODMAN_QUERIES: &[&str] = &["images", "info", "inspect", "ps", "version"];
+
+/// Subcommands of podman object commands (e.g. `podman image`) which only
+/// read state
+const PODMAN_OBJECT_QUERIES: &[&str] = &["exists", "info", "inspect", "list", "ls"];
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static EXECUTOR: RefCell<Option<Arc<dyn Executor>>> = const { RefCell::new(None) };
+}
+
+/// Runs [`HostCommand`]s
+trait Executor: std::fmt::Debug + Send + Sync {
+    /// Run the command to completion, collecting its output
+    fn output(&self, cmd: &mut Command) -> io::Result<Output>;
+}
+
+/// Runs commands on the host
+#[derive(Debug, Default)]
+struct HostExecutor;
+
+impl Executor for HostExecutor {
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        cmd.output()
+    }
+}
+
+/// Prints commands which would change host state instead of running them
+///
+/// Read-only queries are still run; everything else succeeds without output.
+#[derive(Debug, Default)]
+struct DryRunExecutor;
+
+impl Executor for DryRunExecutor {
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        let argv = argv(cmd);
+        if is_query(&argv) {
+            tracing::debug!("Running query: {}", display_argv(&argv));
+            return cmd.output();
+        }
+        dry_run_note(display_argv(&argv));
+        Ok(success_output())
+    }
+}
+
+/// Records commands instead of running them, for tests
+///
+/// Every command succeeds with empty output.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+pub struct RecordingExecutor {
+    commands: std::sync::Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl RecordingExecutor {
+    /// The argument vectors (including the program) of all commands run so far
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Executor for RecordingExecutor {
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.commands.lock().unwrap().push(argv(cmd));
+        Ok(success_output())
+    }
+}
+
+/// Enable or disable dry-run mode for the whole process
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether dry-run mode is enabled
+///
+/// Code which changes host state other than via [`HostCommand`] (e.g.
+/// creating files, or replacing the process with `podman run`) should check
+/// this.
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Print an action skipped because of dry-run mode
+pub fn dry_run_note(action: impl std::fmt::Display) {
+    eprintln!("[dry-run] {action}");
+}
+
+/// Run `f` with all [`HostCommand`]s on this thread recorded by `recorder`
+/// instead of run
+#[cfg(any(test, feature = "test-util"))]
+pub fn with_executor<R>(recorder: Arc<RecordingExecutor>, f: impl FnOnce() -> R) -> R {
+    let prev = EXECUTOR.with(|e| e.replace(Some(recorder)));
+    // Restore the previous executor even if `f` panics
+    struct Restore(Option<Arc<dyn Executor>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            EXECUTOR.with(|e| e.replace(prev));
+        }
+    }
+    let _restore = Restore(prev);
+    f()
+}
+
+fn current_executor() -> Arc<dyn Executor> {
+    if let Some(executor) = EXECUTOR.with(|e| e.borrow().clone()) {
+        return executor;
+    }
+    if dry_run() {
+        Arc::new(DryRunExecutor)
+    } else {
+        Arc::new(HostExecutor)
+    }
+}
+
+fn success_output() -> Output {
+    Output {
+        status: ExitStatus::from_raw(0),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    }
+}
+
+fn argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Format an argument vector as a shell command line
+fn display_argv(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| match shlex::try_quote(arg) {
+            Ok(quoted) => quoted.into_owned(),
+            Err(_) => format!("{arg:?}"),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether the command only reads host state, and so is safe to run in
+/// dry-run mode
+fn is_query(argv: &[String]) -> bool {
+    let Some((program, args)) = argv.split_first() else {
+        return false;
+    };
+    let program = program.rsplit('/').next().unwrap_or(program);
+    match program {
+        "virsh" => {
+            let mut args = args.iter();
+            let subcommand = loop {
+                match args.next() {
+                    Some(arg) if arg == "-c" || arg == "--connect" => {
+                        args.next();
+                    }
+                    Some(arg) if arg.starts_with('-') => {}
+                    other => break other,
+                }
+            };
+            subcommand.is_some_and(|s| VIRSH_QUERIES.contains(&s.as_str()))
+        }
+        "qemu-img" => args
+            .first()
+            .is_some_and(|s| QEMU_IMG_QUERIES.contains(&s.as_str())),
+        "podman" => {
+            let mut words = args.iter().filter(|a| !a.starts_with('-'));
+            match words.next().map(|s| s.as_str()) {
+                Some(cmd) if PODMAN_QUERIES.contains(&cmd) => true,
+                Some("container" | "image" | "network" | "system" | "volume") => words
+                    .next()
+                    .is_some_and(|s| PODMAN_OBJECT_QUERIES.contains(&s.as_str())),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// A command operating on the host, run via the current executor
+pub struct HostCommand {
+    cmd: Command,
+}
+
+impl std::fmt::Debug for HostCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.cmd.fmt(f)
+    }
+}
+
+impl std::fmt::Display for HostCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&display_argv(&argv(&self.cmd)))
+    }
+}
+
+impl HostCommand {
+    /// Create a command for `program`
+    pub fn new(program: impl AsRef<OsStr>) -> Self {
+        Self {
+            cmd: Command::new(program),
+        }
+    }
+
+    /// Add an argument
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.cmd.arg(arg);
+        self
+    }
+
+    /// Add multiple arguments
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.cmd.args(args);
+        self
+    }
+
+    /// Configure stdout, as [`Command::stdout`]
+    pub fn stdout(&mut self, cfg: impl Into<Stdio>) -> &mut Self {
+        self.cmd.stdout(cfg);
+        self
+    }
+
+    /// Configure stderr, as [`Command::stderr`]
+    pub fn stderr(&mut self, cfg: impl Into<Stdio>) -> &mut Self {
+        self.cmd.stderr(cfg);
+        self
+    }
+
+    /// Access the underlying command, e.g. to set its working directory
+    pub fn as_std_mut(&mut self) -> &mut Command {
+        &mut self.cmd
+    }
+
+    /// Run the command to completion, collecting its output
+    pub fn output(&mut self) -> io::Result<Output> {
+        current_executor().output(&mut self.cmd)
+    }
+
+    /// Run the command, returning an error including its stderr on failure
+    pub fn run(&mut self) -> Result<()> {
+        self.run_get_stdout()?;
+        Ok(())
+    }
+
+    /// Run the command and parse its stdout as JSON
+    pub fn run_and_parse_json<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let stdout = self.run_get_stdout()?;
+        serde_json::from_slice(&stdout).with_context(|| format!("Parsing output of {self}"))
+    }
+
+    fn run_get_stdout(&mut self) -> Result<Vec<u8>> {
+        let output = self
+            .output()
+            .with_context(|| format!("Failed to run {self}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eyre!(
+                "{self} failed ({}): {}",
+                output.status,
+                stderr.trim()
+            ));
+        }
+        Ok(output.stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(s: &str) -> Vec<String> {
+        s.split_whitespace().map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn test_is_query() {
+        let cases = [
+            ("virsh list --all --name", true),
+            ("virsh -c qemu:///system dumpxml foo", true),
+            ("virsh --connect qemu:///system pool-info default", true),
+            (
+                "virsh -c qemu:///system vol-delete --pool default foo.qcow2",
+                false,
+            ),
+            ("virsh define /tmp/domain.xml", false),
+            ("virsh start foo", false),
+            ("/usr/bin/virsh domstate foo", true),
+            ("qemu-img info --force-share --output=json disk.qcow2", true),
+            ("qemu-img create -f qcow2 disk.qcow2 1024", false),
+            ("podman image inspect quay.io/fedora/fedora-bootc", true),
+            ("podman images --format json", true),
+            ("podman ps --all --format json", true),
+            ("podman system info --format=json", true),
+            ("podman rm -f abc", false),
+            ("podman image rm abc", false),
+            ("podman run --rm abc", false),
+            ("rm -rf /", false),
+        ];
+        for (cmd, expected) in cases {
+            assert_eq!(is_query(&words(cmd)), expected, "{cmd}");
+        }
+        assert!(!is_query(&[]));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut cmd = HostCommand::new("virsh");
+        cmd.args(["-c", "qemu:///session", "vol-delete", "my disk.qcow2"]);
+        assert_eq!(
+            cmd.to_string(),
+            "virsh -c qemu:///session vol-delete 'my disk.qcow2'"
+        );
+    }
+
+    #[test]
+    fn test_recording_executor() -> Result<()> {
+        let recorder = Arc::new(RecordingExecutor::default());
+        with_executor(recorder.clone(), || -> Result<()> {
+            HostCommand::new("virsh").args(["start", "foo"]).run()?;
+            let output = HostCommand::new("podman").arg("ps").output()?;
+            assert!(output.status.success());
+            assert!(output.stdout.is_empty());
+            Ok(())
+        })?;
+        assert_eq!(
+            recorder.commands(),
+            [words("virsh start foo"), words("podman ps")]
+        );
+
+        // The previous executor is restored afterwards
+        let output = HostCommand::new("true").output()?;
+        assert!(output.status.success());
+        assert_eq!(recorder.commands().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_executor() -> Result<()> {
+        // Mutating commands are not run
+        let output = DryRunExecutor.output(&mut Command::new("false"))?;
+        assert!(output.status.success());
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+//! Listing, inspecting and importing bootc container images through podman
+//!
+//! Images referenced with the `oci-archive:` or `dir:` transports (e.g. CI
+//! build artifacts) are not in container storage; they are imported into a
+//! temporary additional image store instead, see [`import`]. Podman commands
+//! created with [`podman`] see the images imported by this process.
+//!
+//! Images may be pinned to a digest (`IMAGE@sha256:...`); [`inspect`] then
+//! verifies that the image in container storage actually has that digest.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::{Mutex, MutexGuard};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::failure::Failure;
+use crate::hostexec::HostCommand;
+
+/// Label marking an image as bootc compatible
+const BOOTC_LABEL: &str = "containers.bootc";
+
+/// Label marking an image as bootable by ostree, set by older bootc images
+const OSTREE_BOOTABLE_LABEL: &str = "ostree.bootable";
+
+/// Transports of image references which are imported with [`import`]
+const IMPORT_TRANSPORTS: &[&str] = &["oci-archive:", "dir:"];
+
+/// Environment variable with options for container storage, honored by podman
+const STORAGE_OPTS_ENV: &str = "STORAGE_OPTS";
+
+/// The store of an image imported by this process
+#[derive(Debug)]
+struct ImportedStore {
+    /// Reference the image was imported from
+    reference: String,
+    id: String,
+    root: Utf8PathBuf,
+}
+
+/// The stores of the live [`ImportedImage`]s
+static IMPORTED: Mutex<Vec<ImportedStore>> = Mutex::new(Vec::new());
+
+fn imported() -> MutexGuard<'static, Vec<ImportedStore>> {
+    IMPORTED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Single bootc container image entry from podman images output.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageListEntry {
+    /// Repository names and tags, None for dangling images
+    pub names: Option<Vec<String>>,
+
+    /// SHA256 image identifier
+    pub id: String,
+
+    /// Image manifest digest
+    #[serde(default)]
+    pub digest: Option<String>,
+
+    /// Image size in bytes
+    pub size: u64,
+
+    /// Image creation timestamp
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Image labels
+    #[serde(default)]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+/// Whether image labels declare a bootc image
+fn has_bootc_labels(labels: Option<&HashMap<String, String>>) -> bool {
+    let Some(labels) = labels else {
+        return false;
+    };
+    labels.get(BOOTC_LABEL).map(String::as_str) == Some("1")
+        || matches!(
+            labels.get(OSTREE_BOOTABLE_LABEL).map(String::as_str),
+            Some("1" | "true")
+        )
+}
+
+impl ImageListEntry {
+    /// Whether the image is a bootc image, as declared by its labels
+    pub fn is_bootc(&self) -> bool {
+        has_bootc_labels(self.labels.as_ref())
+    }
+}
+
+/// Container image inspection data from podman image inspect.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageInspect {
+    /// SHA256 image identifier
+    pub id: String,
+
+    /// Image digest
+    pub digest: oci_spec::image::Digest,
+
+    /// Image size in bytes
+    pub size: u64,
+
+    /// Image creation timestamp
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Image labels
+    #[serde(default)]
+    pub labels: Option<HashMap<String, String>>,
+
+    /// Registry references of the image pinned to its manifest digest
+    #[serde(default)]
+    pub repo_digests: Vec<String>,
+}
+
+impl ImageInspect {
+    /// Whether the image is a bootc image, as declared by its labels
+    pub fn is_bootc(&self) -> bool {
+        has_bootc_labels(self.labels.as_ref())
+    }
+
+    /// Value of an image label
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels.as_ref()?.get(name).map(String::as_str)
+    }
+
+    /// Whether the image has the manifest digest `digest`, either as its
+    /// own or as that of the manifest list it was pulled with from the
+    /// repository of `image`
+    pub fn has_digest(&self, image: &str, digest: &str) -> bool {
+        self.digest.to_string() == digest
+            || self
+                .repo_digests
+                .iter()
+                .filter(|r| is_repo_digest_of(r, image))
+                .any(|r| r.rsplit_once('@').is_some_and(|(_, d)| d == digest))
+    }
+}
+
+/// The repository of an image reference, without transport, tag or digest
+pub fn repository(image: &str) -> &str {
+    let image = image.strip_prefix("docker://").unwrap_or(image);
+    let image = image.split_once('@').map_or(image, |(repo, _)| repo);
+    match image.rsplit_once(':') {
+        // A colon before the last slash separates a registry port
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => image,
+    }
+}
+
+/// Whether `repo_digest` (`REPOSITORY@DIGEST`) is for the repository of
+/// `image`, which may be a short name
+pub fn is_repo_digest_of(repo_digest: &str, image: &str) -> bool {
+    let repository = repository(image);
+    let repo = repo_digest
+        .split_once('@')
+        .map_or(repo_digest, |(repo, _)| repo);
+    repo == repository
+        || repo
+            .strip_suffix(repository)
+            .is_some_and(|prefix| prefix.ends_with('/'))
+}
+
+/// The digest an image reference is pinned to (`IMAGE@DIGEST`), if any
+pub fn pinned_digest(image: &str) -> Option<&str> {
+    let (_, digest) = image.rsplit_once('@')?;
+    // ALGORITHM:HEX, and not e.g. a user in a transport specific reference
+    digest
+        .split_once(':')
+        .is_some_and(|(algorithm, hex)| {
+            !algorithm.is_empty() && !hex.is_empty() && !hex.contains('/')
+        })
+        .then_some(digest)
+}
+
+/// An image reference without the digest it is pinned to, if any
+pub fn without_digest(image: &str) -> &str {
+    match pinned_digest(image) {
+        Some(digest) => &image[..image.len() - digest.len() - 1],
+        None => image,
+    }
+}
+
+/// The last path component of an image reference, without tag or digest
+/// (e.g. `fedora-bootc` for `quay.io/fedora/fedora-bootc:42`)
+pub fn short_name(image: &str) -> &str {
+    let image = without_digest(image);
+    let name = image.rsplit_once('/').map_or(image, |(_, name)| name);
+    name.split_once(':').map_or(name, |(name, _)| name)
+}
+
+/// List all bootc container images using podman.
+pub fn list() -> Result<Vec<ImageListEntry>> {
+    list_filtered(&[])
+}
+
+/// List bootc container images matching podman image filters.
+///
+/// Whether an image is a bootc image is determined from its labels after
+/// listing, as podman can only filter for one of the labels in use.
+pub fn list_filtered(filters: &[String]) -> Result<Vec<ImageListEntry>> {
+    let mut images: Vec<ImageListEntry> = HostCommand::new("podman")
+        .args(["images", "--format", "json"])
+        .args(filters.iter().map(|f| format!("--filter={f}")))
+        .run_and_parse_json()?;
+    images.retain(|i| i.is_bootc());
+    Ok(images)
+}
+
+/// Whether the image `name` is in container storage
+pub fn exists(name: &str) -> Result<bool> {
+    let output = podman()
+        .args(["image", "exists", name])
+        .output()
+        .context("Failed to run podman image exists")?;
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(eyre!(
+            "podman image exists {name} failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// The error for the image `name` missing from container storage
+fn not_found(name: &str) -> Report {
+    Report::new(Failure::ImageNotFound)
+        .wrap_err(format!("No such image {name} in container storage"))
+}
+
+/// A podman command which also sees the images imported by this process
+pub fn podman() -> HostCommand {
+    let roots: Vec<Utf8PathBuf> = imported().iter().map(|s| s.root.clone()).collect();
+    let mut cmd = HostCommand::new("podman");
+    cmd.args(storage_opts(
+        std::env::var_os(STORAGE_OPTS_ENV).as_ref(),
+        &roots,
+    ));
+    cmd
+}
+
+/// Inspect a container image and return metadata.
+///
+/// Images from OCI archives and directories are looked up among the images
+/// imported by this process, and otherwise imported for the duration of the
+/// call only.
+pub fn inspect(name: &str) -> Result<ImageInspect> {
+    if needs_import(name) {
+        let id = imported()
+            .iter()
+            .find(|s| s.reference == name)
+            .map(|s| s.id.clone());
+        if let Some(id) = id {
+            return inspect(&id);
+        }
+        let imported = import(name)?;
+        return inspect(imported.id());
+    }
+    let mut r: Vec<ImageInspect> = match podman()
+        .args(["image", "inspect", name])
+        .run_and_parse_json()
+    {
+        Ok(r) => r,
+        Err(e) if !exists(name)? => {
+            tracing::debug!("Inspecting {name}: {e:#}");
+            return Err(not_found(name));
+        }
+        Err(e) => return Err(e),
+    };
+    let inspect = r.pop().ok_or_else(|| not_found(name))?;
+    if let Some(pinned) = pinned_digest(name) {
+        if !inspect.has_digest(name, pinned) {
+            return Err(eyre!(
+                "Image {name} resolved to {} ({}) in container storage, which does not have the pinned digest",
+                inspect.id,
+                inspect.digest
+            ));
+        }
+    }
+    Ok(inspect)
+}
+
+/// Get container image size in bytes for disk space planning.
+pub fn get_image_size(name: &str) -> Result<u64> {
+    tracing::debug!("Getting size for image: {}", name);
+    let info = inspect(name)?;
+    tracing::debug!("Found image size: {} bytes", info.size);
+    Ok(info.size)
+}
+
+/// Whether an image reference uses a transport which needs [`import`]
+pub fn needs_import(image: &str) -> bool {
+    IMPORT_TRANSPORTS.iter().any(|t| image.starts_with(t))
+}
+
+/// An image imported into a temporary additional image store
+///
+/// While this exists, the commands created with [`podman`] see the image;
+/// the store is removed when it is dropped.
+#[derive(Debug)]
+pub struct ImportedImage {
+    id: String,
+    dir: Utf8PathBuf,
+    root: Utf8PathBuf,
+    // Declared last so it is removed after everything else
+    store: tempfile::TempDir,
+}
+
+impl ImportedImage {
+    /// The ID of the image
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The root directory of the image store
+    pub fn store(&self) -> &Utf8Path {
+        &self.root
+    }
+
+    /// The temporary directory containing the image store, removed on drop
+    pub fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+}
+
+impl Drop for ImportedImage {
+    fn drop(&mut self) {
+        imported().retain(|s| s.root != self.root);
+    }
+}
+
+/// Import an image from an OCI archive or directory (e.g.
+/// `oci-archive:/path/image.tar` or `dir:/path/image`) into a temporary
+/// additional image store, leaving the container storage untouched.
+pub fn import(image: &str) -> Result<ImportedImage> {
+    // Images can be large, so don't use a tmpfs
+    let store = tempfile::Builder::new()
+        .prefix("bcvk-imagestore-")
+        .tempdir_in("/var/tmp")
+        .context("Creating temporary image store")?;
+    let dir = Utf8PathBuf::try_from(store.path().to_owned())
+        .map_err(|_| eyre!("Invalid UTF-8 in temporary directory"))?;
+    let root = dir.join("root");
+    let runroot = dir.join("runroot");
+
+    tracing::info!("Importing {image}");
+    let mut cmd = HostCommand::new("podman");
+    cmd.args(["--root", root.as_str(), "--runroot", runroot.as_str()])
+        .args(["pull", "--quiet", image]);
+    // This only writes to the temporary store, so is also done in dry-run mode
+    let output = cmd
+        .as_std_mut()
+        .output()
+        .with_context(|| format!("Failed to run {cmd}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to import {image}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let id = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .last()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .ok_or_else(|| eyre!("No image ID in the output of {cmd}"))?;
+    tracing::debug!("Imported {image} as {id} into {root}");
+
+    imported().push(ImportedStore {
+        reference: image.to_owned(),
+        id: id.clone(),
+        root: root.clone(),
+    });
+    Ok(ImportedImage {
+        id,
+        dir,
+        root,
+        store,
+    })
+}
+
+/// The podman arguments adding the image stores `roots`
+///
+/// Storage options given on the command line replace those from
+/// `STORAGE_OPTS` (`env`), so these are passed along.
+fn storage_opts(env: Option<&OsString>, roots: &[Utf8PathBuf]) -> Vec<String> {
+    if roots.is_empty() {
+        return Vec::new();
+    }
+    let env = env.map(|e| e.to_string_lossy()).unwrap_or_default();
+    env.split(',')
+        .filter(|o| !o.is_empty())
+        .map(ToOwned::to_owned)
+        .chain(roots.iter().map(|r| format!("additionalimagestore={r}")))
+        .map(|o| format!("--storage-opt={o}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needs_import() {
+        let cases = [
+            ("oci-archive:/srv/ci/image.tar", true),
+            ("dir:/srv/ci/image", true),
+            ("quay.io/fedora/fedora-bootc:42", false),
+            ("localhost/dir:latest", false),
+            ("containers-storage:localhost/test", false),
+            ("docker://quay.io/fedora/fedora-bootc:42", false),
+        ];
+        for (image, expected) in cases {
+            assert_eq!(needs_import(image), expected, "{image}");
+        }
+    }
+
+    #[test]
+    fn test_pinned_digest() {
+        let cases = [
+            (
+                "quay.io/fedora/fedora-bootc@sha256:0123abcd",
+                Some("sha256:0123abcd"),
+            ),
+            (
+                "docker://registry.example.com:5000/os/base@sha256:0123abcd",
+                Some("sha256:0123abcd"),
+            ),
+            ("quay.io/fedora/fedora-bootc:42", None),
+            ("localhost:5000/test", None),
+            ("oci-archive:/srv/ci/image@v1.tar", None),
+        ];
+        for (image, expected) in cases {
+            assert_eq!(pinned_digest(image), expected, "{image}");
+        }
+    }
+
+    #[test]
+    fn test_short_name() {
+        let cases = [
+            ("quay.io/fedora/fedora-bootc:42", "fedora-bootc"),
+            (
+                "quay.io/fedora/fedora-bootc@sha256:0123abcd",
+                "fedora-bootc",
+            ),
+            ("registry.example.com:5000/os/base@sha256:0123abcd", "base"),
+            ("registry.example.com:5000/os/base", "base"),
+            ("localhost/test", "test"),
+            ("fedora-bootc", "fedora-bootc"),
+        ];
+        for (image, expected) in cases {
+            assert_eq!(short_name(image), expected, "{image}");
+        }
+    }
+
+    #[test]
+    fn test_is_repo_digest_of() {
+        let cases = [
+            (
+                "quay.io/fedora/fedora-bootc@sha256:aa",
+                "quay.io/fedora/fedora-bootc:42",
+                true,
+            ),
+            (
+                "quay.io/fedora/fedora-bootc@sha256:aa",
+                "fedora/fedora-bootc",
+                true,
+            ),
+            (
+                "quay.io/fedora/fedora-bootc@sha256:aa",
+                "fedora-bootc",
+                true,
+            ),
+            ("quay.io/fedora/fedora-bootc@sha256:aa", "bootc", false),
+            (
+                "quay.io/fedora/fedora-bootc@sha256:aa",
+                "quay.io/other",
+                false,
+            ),
+            (
+                "registry.example.com:5000/os/base@sha256:bb",
+                "docker://registry.example.com:5000/os/base:latest",
+                true,
+            ),
+        ];
+        for (repo_digest, image, expected) in cases {
+            assert_eq!(
+                is_repo_digest_of(repo_digest, image),
+                expected,
+                "{repo_digest} {image}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_has_digest() {
+        let inspect: ImageInspect = serde_json::from_value(serde_json::json!({
+            "Id": "abcd",
+            "Digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+            "Size": 1,
+            "RepoDigests": [
+                "quay.io/fedora/fedora-bootc@sha256:2222222222222222222222222222222222222222222222222222222222222222"
+            ]
+        }))
+        .unwrap();
+        let image = "quay.io/fedora/fedora-bootc";
+        let own = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+        let list = "sha256:2222222222222222222222222222222222222222222222222222222222222222";
+        let other = "sha256:3333333333333333333333333333333333333333333333333333333333333333";
+        assert!(inspect.has_digest(image, own));
+        assert!(inspect.has_digest(image, list));
+        assert!(inspect.has_digest("fedora/fedora-bootc", list));
+        assert!(!inspect.has_digest(image, other));
+        // The digest of a manifest list in another repository doesn't count
+        assert!(inspect.has_digest("quay.io/example/os", own));
+        assert!(!inspect.has_digest("quay.io/example/os", list));
+    }
+
+    #[test]
+    fn test_storage_opts() {
+        let roots = [
+            Utf8PathBuf::from("/var/tmp/bcvk-imagestore-x/root"),
+            Utf8PathBuf::from("/var/tmp/bcvk-imagestore-y/root"),
+        ];
+        assert!(storage_opts(None, &[]).is_empty());
+        assert!(storage_opts(Some(&"overlay.mountopt=nodev".into()), &[]).is_empty());
+        assert_eq!(
+            storage_opts(None, &roots[..1]),
+            ["--storage-opt=additionalimagestore=/var/tmp/bcvk-imagestore-x/root"]
+        );
+        assert_eq!(
+            storage_opts(Some(&OsString::new()), &roots),
+            [
+                "--storage-opt=additionalimagestore=/var/tmp/bcvk-imagestore-x/root",
+                "--storage-opt=additionalimagestore=/var/tmp/bcvk-imagestore-y/root"
+            ]
+        );
+        assert_eq!(
+            storage_opts(
+                Some(&"overlay.mount_program=/usr/bin/fuse-overlayfs".into()),
+                &roots[..1]
+            ),
+            [
+                "--storage-opt=overlay.mount_program=/usr/bin/fuse-overlayfs",
+                "--storage-opt=additionalimagestore=/var/tmp/bcvk-imagestore-x/root"
+            ]
+        );
+    }
+}
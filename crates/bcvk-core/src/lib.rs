@@ -0,0 +1,39 @@
+//! Library API of bcvk, for driving bootc virtual machines from other tools
+//!
+//! This contains the building blocks of the `bcvk` command line tool which
+//! are useful without it, so that e.g. IDE plugins and CI orchestrators can
+//! build and run virtual machines programmatically instead of running `bcvk`
+//! and parsing its output. None of the types here are tied to the command
+//! line (in particular they do not derive `clap` traits).
+//!
+//! - [`images`]: listing, inspecting and importing bootc container images
+//! - [`qemu`]: configuring and spawning QEMU ([`qemu::QemuConfig`])
+//! - [`domain`]: generating libvirt domain XML ([`domain::DomainBuilder`])
+//! - [`domain_metadata`]: reading and writing the bcvk metadata of libvirt
+//!   domains
+//! - [`ssh`]: connecting to VMs over SSH
+//! - [`qmp`]: controlling running VMs over the QEMU Machine Protocol
+//! - [`host_devices`]: passing PCI devices and GPUs through to VMs
+//! - [`failure`]: the classes of failures errors are tagged with
+//! - [`qemu_img`], [`credentials`], [`arch`], [`xml_utils`] and [`hostexec`]:
+//!   the helpers the above are built on
+//!
+//! The higher level workflows of `bcvk` (such as `to-disk` and ephemeral
+//! VMs, which run QEMU inside a container) are not part of the library yet.
+
+pub mod arch;
+pub mod credentials;
+pub mod domain;
+pub mod domain_metadata;
+pub mod failure;
+pub mod host_devices;
+pub mod hostexec;
+pub mod images;
+pub mod qemu;
+pub mod qemu_img;
+pub mod qmp;
+pub mod ssh;
+pub mod xml_utils;
+
+/// Default state directory for bcvk container data
+pub const CONTAINER_STATEDIR: &str = "/var/lib/bcvk";
@@ -0,0 +1,2412 @@
+//! QEMU virtualization integration and VM management.
+//!
+//! Supports direct kernel boot with VirtIO devices, automatic process cleanup,
+//! SMBIOS credential injection, and control via QMP (see [`crate::qmp`]).
+
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::os::fd::OwnedFd;
+use std::os::unix::process::CommandExt as _;
+use std::pin::Pin;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+use vsock::{VsockListener, VMADDR_CID_ANY};
+
+use crate::failure::Failure;
+use crate::host_devices::HostDevice;
+use crate::qemu_img::ImageFormat;
+
+/// Host memory window of a virtio-gpu device with a render node, which
+/// blob resources (e.g. Vulkan allocations) are mapped into
+const VIRTIO_GPU_HOSTMEM: &str = "4G";
+
+/// The device for vsock allocation
+pub const VHOST_VSOCK: &str = "/dev/vhost-vsock";
+
+/// Name of the fw_cfg item Ignition reads its config from on QEMU
+pub const IGNITION_FW_CFG_NAME: &str = "opt/com.coreos/config";
+
+/// How long to wait for QEMU to create its QMP socket
+const QMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Name of the swtpm control socket within the TPM state directory
+const SWTPM_SOCKET: &str = "swtpm.sock";
+
+/// QEMU options which are always set by [`spawn`], and so can't be passed
+/// as extra arguments
+const MANAGED_OPTIONS: &[&str] = &[
+    "m",
+    "smp",
+    "enable-kvm",
+    "accel",
+    "cpu",
+    "numa",
+    "kernel",
+    "initrd",
+    "append",
+    "serial",
+    "nographic",
+    "display",
+    "monitor",
+    "qmp",
+    "incoming",
+];
+
+/// IDs of objects, chardevs, devices and backends created by [`spawn`]
+const MANAGED_IDS: &[&str] = &[
+    "mem", "console0", "net0", "chrtpm", "tpm0", "balloon0", "bootdisk", "ahci0",
+];
+
+/// Prefixes of numbered IDs created by [`spawn`], e.g. `drive0`
+const MANAGED_ID_PREFIXES: &[&str] = &["char", "drive", "serial_char"];
+
+/// VirtIO-FS mount point configuration.
+#[derive(Debug, Clone)]
+pub struct VirtiofsMount {
+    /// Unix socket path for virtiofsd communication
+    pub socket_path: String,
+    /// Mount tag used by guest to identify this mount
+    pub tag: String,
+}
+
+/// VirtIO-Serial device for guest-to-host communication.
+/// Appears as /dev/virtio-ports/{name} in guest.
+#[derive(Debug)]
+pub struct VirtioSerialOut {
+    /// Device name (becomes /dev/virtio-ports/{name})
+    pub name: String,
+    /// Host file path for output
+    pub output_file: String,
+    /// Whether to append to the file (needed for fdsets)
+    pub append: bool,
+}
+
+/// VirtIO-Block storage device configuration.
+/// Appears as /dev/disk/by-id/virtio-{serial} in guest.
+#[derive(Debug)]
+pub struct VirtioBlkDevice {
+    /// Host disk image file path
+    pub disk_file: String,
+    /// Device serial for guest identification
+    pub serial: String,
+    /// Disk image format
+    pub format: ImageFormat,
+    /// Host cache and I/O settings
+    pub io: DiskIoConfig,
+}
+
+/// Host page cache mode of a disk (`cache=`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskCache {
+    /// Bypass the host page cache (O_DIRECT)
+    None,
+    /// Use the host page cache, flushing it when the guest asks to
+    Writeback,
+    /// Use the host page cache and ignore flushes; the image may be
+    /// corrupted if the host crashes, so only for throwaway writes
+    Unsafe,
+}
+
+impl DiskCache {
+    /// The value for QEMU `cache=` and the libvirt `cache` attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskCache::None => "none",
+            DiskCache::Writeback => "writeback",
+            DiskCache::Unsafe => "unsafe",
+        }
+    }
+}
+
+/// How QEMU submits disk I/O on the host (`aio=`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskAio {
+    /// Linux io_uring
+    IoUring,
+    /// A pool of worker threads
+    Threads,
+}
+
+impl DiskAio {
+    /// The value for QEMU `aio=` and the libvirt `io` attribute
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskAio::IoUring => "io_uring",
+            DiskAio::Threads => "threads",
+        }
+    }
+}
+
+/// Cache and I/O settings of a disk; QEMU's defaults apply to unset ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskIoConfig {
+    /// Host page cache mode
+    pub cache: Option<DiskCache>,
+    /// Host I/O submission
+    pub aio: Option<DiskAio>,
+}
+
+impl DiskIoConfig {
+    /// Settings for disks whose content is worthless if the host crashes
+    /// before they are complete, e.g. the target of an installation
+    pub fn throwaway() -> Self {
+        Self {
+            cache: Some(DiskCache::Unsafe),
+            aio: None,
+        }
+    }
+}
+
+impl std::str::FromStr for DiskIoConfig {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut r = Self::default();
+        for opt in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or_else(|| eyre!("Invalid disk I/O option '{opt}'. Expected KEY=VALUE"))?;
+            match (key, value) {
+                ("cache", "none") => r.cache = Some(DiskCache::None),
+                ("cache", "writeback") => r.cache = Some(DiskCache::Writeback),
+                ("cache", "unsafe") => r.cache = Some(DiskCache::Unsafe),
+                ("aio", "io_uring") => r.aio = Some(DiskAio::IoUring),
+                ("aio", "threads") => r.aio = Some(DiskAio::Threads),
+                ("cache", _) => {
+                    return Err(eyre!(
+                        "Invalid disk cache mode '{value}'. Expected none, writeback or unsafe"
+                    ))
+                }
+                ("aio", _) => {
+                    return Err(eyre!(
+                        "Invalid disk aio mode '{value}'. Expected io_uring or threads"
+                    ))
+                }
+                _ => {
+                    return Err(eyre!(
+                        "Unknown disk I/O option '{key}'. Expected cache or aio"
+                    ))
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl std::fmt::Display for DiskIoConfig {
+    /// The set options as `cache=..,aio=..`, as for a QEMU `-drive`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opts = [
+            self.cache.map(|c| format!("cache={}", c.as_str())),
+            self.aio.map(|a| format!("aio={}", a.as_str())),
+        ];
+        let opts: Vec<_> = opts.into_iter().flatten().collect();
+        f.write_str(&opts.join(","))
+    }
+}
+
+/// VM display and console configuration.
+#[derive(Debug, Clone)]
+pub enum DisplayMode {
+    /// Headless mode (-nographic)
+    None,
+    /// Console to stdio (-serial stdio -display none)
+    Console,
+}
+
+impl Default for DisplayMode {
+    fn default() -> Self {
+        DisplayMode::None
+    }
+}
+
+/// VM network configuration.
+#[derive(Debug, Clone)]
+pub enum NetworkMode {
+    /// User-mode networking with NAT and port forwarding
+    User {
+        /// Port forwarding rules: "tcp::2222-:22" format
+        hostfwd: Vec<String>,
+    },
+}
+
+impl Default for NetworkMode {
+    fn default() -> Self {
+        NetworkMode::User { hostfwd: vec![] }
+    }
+}
+
+/// CPU model used when none is configured
+const DEFAULT_CPU_MODEL: &str = "host";
+
+/// vCPU topology (`-smp sockets=,cores=,threads=`); unset values are 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmpTopology {
+    /// CPU sockets
+    pub sockets: u32,
+    /// Cores per socket
+    pub cores: u32,
+    /// Threads per core
+    pub threads: u32,
+}
+
+impl SmpTopology {
+    /// Total number of vCPUs
+    pub fn vcpus(&self) -> u32 {
+        self.sockets * self.cores * self.threads
+    }
+}
+
+impl std::str::FromStr for SmpTopology {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut r = Self {
+            sockets: 1,
+            cores: 1,
+            threads: 1,
+        };
+        for opt in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or_else(|| eyre!("Invalid topology option '{opt}'. Expected KEY=VALUE"))?;
+            let value = value
+                .parse::<u32>()
+                .ok()
+                .filter(|v| (1..=256).contains(v))
+                .ok_or_else(|| eyre!("Invalid {key} count '{value}'"))?;
+            match key {
+                "sockets" => r.sockets = value,
+                "cores" => r.cores = value,
+                "threads" => r.threads = value,
+                _ => {
+                    return Err(eyre!(
+                        "Unknown topology option '{key}'. Expected sockets, cores or threads"
+                    ))
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl std::fmt::Display for SmpTopology {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sockets={},cores={},threads={}",
+            self.sockets, self.cores, self.threads
+        )
+    }
+}
+
+/// Get default vCPU count (number of available processors, or 2 as fallback)
+pub fn default_vcpus() -> u32 {
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(2)
+}
+
+/// Accelerator running the guest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Accel {
+    /// Hardware virtualization via /dev/kvm
+    #[default]
+    Kvm,
+    /// Software emulation with QEMU's TCG; much slower, but works without /dev/kvm
+    Tcg,
+}
+
+/// CPU model for TCG, which cannot pass through the host CPU
+const TCG_CPU_MODEL: &str = "max";
+
+/// Where the guest's real-time clock starts at boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RtcBase {
+    /// The host's time, in UTC
+    #[default]
+    Utc,
+    /// The host's time, in its local time zone
+    Localtime,
+    /// A fixed time, in seconds since the epoch
+    At(i64),
+}
+
+/// What drives the guest's real-time clock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RtcClock {
+    /// The host's clock, which keeps running while the guest is paused
+    #[default]
+    Host,
+    /// The guest's virtual clock, which only runs while the guest does
+    Vm,
+}
+
+/// Guest real-time clock (`-rtc base=,clock=`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RtcConfig {
+    /// Time at boot
+    pub base: RtcBase,
+    /// What advances the clock
+    pub clock: RtcClock,
+}
+
+impl RtcConfig {
+    /// Start the clock at `secs` since the epoch, advancing only while the guest runs
+    pub fn frozen_at(secs: i64) -> Self {
+        Self {
+            base: RtcBase::At(secs),
+            clock: RtcClock::Vm,
+        }
+    }
+
+    /// Whether the guest time is decoupled from the host's, which requires
+    /// hiding the paravirtualized KVM clock: Linux guests take the wall
+    /// clock from it, rather than from the RTC
+    pub fn is_fixed(&self) -> bool {
+        matches!(self.base, RtcBase::At(_))
+    }
+}
+
+impl std::str::FromStr for RtcConfig {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut r = Self::default();
+        for opt in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or_else(|| eyre!("Invalid RTC option '{opt}'. Expected KEY=VALUE"))?;
+            match (key, value) {
+                ("base", "utc") => r.base = RtcBase::Utc,
+                ("base", "localtime") => r.base = RtcBase::Localtime,
+                ("clock", "host") => r.clock = RtcClock::Host,
+                ("clock", "vm") => r.clock = RtcClock::Vm,
+                ("base", _) => {
+                    return Err(eyre!(
+                        "Invalid RTC base '{value}'. Expected utc or localtime; use --freeze-time for a fixed time"
+                    ))
+                }
+                ("clock", _) => {
+                    return Err(eyre!("Invalid RTC clock '{value}'. Expected host or vm"))
+                }
+                _ => {
+                    return Err(eyre!(
+                        "Unknown RTC option '{key}'. Expected base or clock"
+                    ))
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl std::fmt::Display for RtcConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.base {
+            RtcBase::Utc => f.write_str("base=utc")?,
+            RtcBase::Localtime => f.write_str("base=localtime")?,
+            RtcBase::At(secs) => {
+                let time = chrono::DateTime::from_timestamp(secs, 0).ok_or(std::fmt::Error)?;
+                write!(f, "base={}", time.format("%Y-%m-%dT%H:%M:%S"))?
+            }
+        }
+        match self.clock {
+            RtcClock::Host => f.write_str(",clock=host"),
+            RtcClock::Vm => f.write_str(",clock=vm"),
+        }
+    }
+}
+
+/// Parse the time to freeze the guest clock at: `@SECONDS` since the
+/// epoch, an RFC 3339 time such as `2030-01-01T00:00:00Z`, or a date and
+/// optionally time in UTC, `2030-01-01` or `2030-01-01T12:00:00`
+pub fn parse_rtc_timestamp(s: &str) -> Result<i64> {
+    use chrono::Datelike as _;
+
+    let secs = if let Some(secs) = s.strip_prefix('@') {
+        secs.parse()
+            .map_err(|_| eyre!("Invalid timestamp '{s}': expected @SECONDS"))?
+    } else if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        time.timestamp()
+    } else if let Ok(time) = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+        time.and_utc().timestamp()
+    } else if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp()
+    } else {
+        return Err(eyre!(
+            "Invalid timestamp '{s}': expected e.g. 2030-01-01, 2030-01-01T12:00:00(Z) or @SECONDS"
+        ));
+    };
+    // QEMU takes the time as a date with a four digit year
+    let representable = chrono::DateTime::from_timestamp(secs, 0)
+        .is_some_and(|time| (0..=9999).contains(&time.year()));
+    if !representable {
+        return Err(eyre!("Invalid timestamp '{s}': out of range"));
+    }
+    Ok(secs)
+}
+
+/// UEFI firmware paths and formats from QEMU firmware interop descriptors
+#[derive(Debug, Clone)]
+pub struct FirmwareInfo {
+    /// Path to OVMF_CODE firmware file
+    pub code_path: Utf8PathBuf,
+    /// Format of the OVMF_CODE file (raw, qcow2)
+    pub code_format: String,
+    /// Path to OVMF_VARS template file
+    pub vars_path: Utf8PathBuf,
+    /// Format of the OVMF_VARS file (raw, qcow2)
+    pub vars_format: String,
+}
+
+/// VM boot configuration: direct kernel boot, or UEFI firmware booting a disk.
+#[derive(Debug)]
+pub enum BootMode {
+    /// Direct kernel boot (fast, testing-focused)
+    /// Also used for UKI boot after extracting kernel/initramfs from UKI PE sections
+    DirectBoot {
+        /// Kernel image
+        kernel_path: String,
+        /// Initramfs image
+        initramfs_path: String,
+        /// Kernel command line arguments
+        kernel_cmdline: Vec<String>,
+        /// VirtIO-FS socket for root filesystem
+        virtiofs_socket: Utf8PathBuf,
+    },
+    /// UEFI firmware boot from a disk image, which is opened with
+    /// `snapshot=on` so that guest writes are discarded
+    Firmware {
+        /// Firmware code (read-only pflash)
+        code_path: Utf8PathBuf,
+        /// Format of the firmware code
+        code_format: String,
+        /// Writable copy of the firmware variables template
+        vars_path: Utf8PathBuf,
+        /// Format of the firmware variables
+        vars_format: String,
+        /// Disk image to boot
+        disk_file: Utf8PathBuf,
+        /// Format of the disk image
+        disk_format: String,
+    },
+}
+
+/// Machine type for firmware boot
+fn firmware_machine_type() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" => "virt",
+        _ => "q35",
+    }
+}
+
+/// QEMU machine type for direct boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MachineType {
+    /// QEMU's default machine, with PCI devices
+    #[default]
+    Default,
+    /// The minimal x86_64 `microvm` machine, with virtio-mmio devices, no PCI
+    /// and no legacy firmware, which boots faster
+    Microvm,
+}
+
+/// The microvm machine without legacy PC devices; the guest finds the
+/// virtio-mmio devices via ACPI
+const MICROVM_MACHINE: &str =
+    "microvm,acpi=on,pit=off,pic=off,isa-serial=off,x-option-roms=off,rtc=on";
+
+/// fw_cfg directory systemd imports credentials from, as there is no SMBIOS
+/// on microvm
+const FW_CFG_CREDENTIALS_DIR: &str = "opt/io.systemd.credentials";
+
+impl std::str::FromStr for MachineType {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Self::Default),
+            "microvm" => Ok(Self::Microvm),
+            _ => Err(eyre!(
+                "Invalid machine type '{s}'. Expected 'default' or 'microvm'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for MachineType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Microvm => write!(f, "microvm"),
+        }
+    }
+}
+
+impl MachineType {
+    /// The name of the virtio device `base` (e.g. `virtio-blk`) on the bus of
+    /// this machine type
+    fn virtio_device(self, base: &str) -> String {
+        match self {
+            MachineType::Default => format!("{base}-pci"),
+            MachineType::Microvm => format!("{base}-device"),
+        }
+    }
+}
+
+/// A credential passed to the guest from files, see
+/// [`QemuConfig::add_secret_credential`]
+#[derive(Debug)]
+struct SecretCredential {
+    name: String,
+    /// File with the value, for fw_cfg
+    value_path: Utf8PathBuf,
+    /// File with the SMBIOS type 11 string
+    smbios_path: Utf8PathBuf,
+}
+
+/// Write `contents` to a new file at `path` only its owner can read
+fn write_private(path: &Utf8Path, contents: &str) -> Result<()> {
+    use std::io::Write as _;
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .with_context(|| format!("Writing {path}"))
+}
+
+/// Whether `qemu` has the microvm machine type, which e.g. qemu-kvm on RHEL lacks
+fn qemu_has_microvm(qemu: &str) -> bool {
+    Command::new(qemu)
+        .args(["-machine", "help"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .any(|l| l.split_whitespace().next() == Some("microvm"))
+        })
+        .unwrap_or(false)
+}
+
+/// Longest fw_cfg file name QEMU accepts, which is 56 bytes with the NUL
+const FW_CFG_MAX_NAME_LEN: usize = 55;
+
+/// Split a credential given for SMBIOS type 11, i.e.
+/// `io.systemd.credential:NAME=VALUE` or `io.systemd.credential.binary:NAME=BASE64`,
+/// into whether it is binary, its name and its value
+fn parse_credential(credential: &str) -> Result<(bool, &str, &str)> {
+    let (binary, rest) =
+        if let Some(rest) = credential.strip_prefix("io.systemd.credential.binary:") {
+            (true, rest)
+        } else if let Some(rest) = credential.strip_prefix("io.systemd.credential:") {
+            (false, rest)
+        } else {
+            return Err(eyre!("Invalid credential '{credential}'"));
+        };
+    let (name, value) = rest
+        .split_once('=')
+        .ok_or_else(|| eyre!("Invalid credential '{credential}'"))?;
+    Ok((binary, name, value))
+}
+
+/// Whether the credential `name` fits in a fw_cfg file name under
+/// [`FW_CFG_CREDENTIALS_DIR`]
+fn fits_fw_cfg(name: &str) -> bool {
+    FW_CFG_CREDENTIALS_DIR.len() + 1 + name.len() <= FW_CFG_MAX_NAME_LEN
+}
+
+/// The `-fw_cfg` item passing a credential given for SMBIOS type 11
+fn credential_fw_cfg(credential: &str) -> Result<String> {
+    let (binary, name, value) = parse_credential(credential)?;
+    if !fits_fw_cfg(name) {
+        return Err(eyre!("Credential name {name} is too long for fw_cfg"));
+    }
+    let value = if binary {
+        let decoded = data_encoding::BASE64
+            .decode(value.as_bytes())
+            .with_context(|| format!("Decoding credential {name}"))?;
+        String::from_utf8(decoded)
+            .map_err(|_| eyre!("Credential {name} is not text, which fw_cfg can't pass"))?
+    } else {
+        value.to_owned()
+    };
+    // QEMU option values escape commas by doubling them
+    Ok(format!(
+        "name={FW_CFG_CREDENTIALS_DIR}/{name},string={}",
+        value.replace(',', ",,")
+    ))
+}
+
+/// Complete QEMU VM configuration with builder pattern.
+#[derive(Default, Debug)]
+pub struct QemuConfig {
+    /// RAM in megabytes (128MB-1TB)
+    pub memory_mb: u32,
+    /// Number of vCPUs (1-256)
+    pub vcpus: u32,
+    /// CPU model (`-cpu`), by default `host`
+    cpu_model: Option<String>,
+    /// Accelerator, KVM by default
+    accel: Accel,
+    /// Machine type requested for direct boot; see [`QemuConfig::machine_type`]
+    machine: MachineType,
+    /// Guest real-time clock, following the host in UTC by default
+    rtc: Option<RtcConfig>,
+    /// vCPU topology; must add up to `vcpus`
+    smp_topology: Option<SmpTopology>,
+    boot_mode: Option<BootMode>,
+    /// Main VirtioFS configuration for root filesystem (handled separately from additional mounts)
+    pub main_virtiofs_config: Option<VirtiofsConfig>,
+    /// VirtioFS configurations to spawn as daemons
+    pub virtiofs_configs: Vec<VirtiofsConfig>,
+    /// File descriptors to pass
+    fdset: Vec<Arc<OwnedFd>>,
+    /// Additional VirtIO-FS mounts
+    pub additional_mounts: Vec<VirtiofsMount>,
+    /// VirtIO-Serial output devices
+    pub virtio_serial_devices: Vec<VirtioSerialOut>,
+    /// VirtIO-Block storage devices
+    pub virtio_blk_devices: Vec<VirtioBlkDevice>,
+    /// Display and console configuration
+    pub display_mode: DisplayMode,
+    /// Network configuration
+    pub network_mode: NetworkMode,
+    /// Deprecated: use display_mode
+    pub enable_console: bool,
+    /// Write the guest console (`hvc0`) to this file in headless mode
+    console_log: Option<String>,
+    /// SMBIOS credentials for systemd
+    smbios_credentials: Vec<String>,
+    /// Firmware configuration (fw_cfg) items, as names and file paths
+    fw_cfg_files: Vec<(String, String)>,
+    /// Credentials for systemd kept off the command line
+    secret_credentials: Vec<SecretCredential>,
+
+    /// Write systemd notifications to this file
+    pub systemd_notify: Option<File>,
+
+    /// Unix socket to serve QMP on
+    qmp_socket: Option<Utf8PathBuf>,
+    /// Load the VM state from this file instead of booting
+    incoming_migration: Option<Utf8PathBuf>,
+    /// State directory of the swtpm-emulated TPM 2.0, if enabled
+    tpm_state_dir: Option<Utf8PathBuf>,
+    /// With a memory balloon, the guest RAM in megabytes; `memory_mb` is
+    /// then only the initial balloon target
+    memory_max_mb: Option<u32>,
+    /// Raw arguments appended to the QEMU command line
+    extra_args: Vec<String>,
+    /// Attach the boot disk via AHCI and use an e1000e NIC, for guests
+    /// without virtio drivers (only for firmware boot)
+    emulated_devices: bool,
+    /// Host devices passed through; see [`crate::host_devices`]
+    host_devices: Vec<HostDevice>,
+
+    vhost_fd: Option<File>,
+}
+
+impl QemuConfig {
+    /// Create a new config with direct boot (kernel + initramfs)
+    pub fn new_direct_boot(
+        memory_mb: u32,
+        vcpus: u32,
+        kernel_path: String,
+        initramfs_path: String,
+        virtiofs_socket: Utf8PathBuf,
+    ) -> Self {
+        Self {
+            memory_mb,
+            vcpus,
+            boot_mode: Some(BootMode::DirectBoot {
+                kernel_path,
+                initramfs_path,
+                kernel_cmdline: vec![],
+                virtiofs_socket,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Create a new config booting `disk_file` with UEFI firmware
+    ///
+    /// `vars_path` is written by the guest, so it should be a copy of the
+    /// firmware's variables template.
+    pub fn new_firmware_boot(
+        memory_mb: u32,
+        vcpus: u32,
+        firmware: &FirmwareInfo,
+        vars_path: Utf8PathBuf,
+        disk_file: Utf8PathBuf,
+        disk_format: String,
+    ) -> Self {
+        Self {
+            memory_mb,
+            vcpus,
+            boot_mode: Some(BootMode::Firmware {
+                code_path: firmware.code_path.clone(),
+                code_format: firmware.code_format.clone(),
+                vars_path,
+                vars_format: firmware.vars_format.clone(),
+                disk_file,
+                disk_format,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Enable vsock, allocating a guest CID when QEMU is spawned
+    pub fn enable_vsock(&mut self) -> Result<()> {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(VHOST_VSOCK)
+            .with_context(|| format!("Failed to open {VHOST_VSOCK} for CID allocation"))?;
+        self.vhost_fd = Some(fd);
+        Ok(())
+    }
+
+    /// Set kernel command line arguments (only for direct boot)
+    pub fn set_kernel_cmdline(&mut self, cmdline: Vec<String>) -> &mut Self {
+        if let Some(BootMode::DirectBoot { kernel_cmdline, .. }) = self.boot_mode.as_mut() {
+            *kernel_cmdline = cmdline;
+        }
+        self
+    }
+
+    /// Enable console output
+    pub fn set_console(&mut self, enable: bool) -> &mut Self {
+        self.enable_console = enable;
+        if enable {
+            self.display_mode = DisplayMode::Console;
+        }
+        self
+    }
+
+    /// Validate configuration before VM creation
+    pub fn validate(&self) -> Result<()> {
+        // Memory validation
+        if self.memory_mb < 128 {
+            return Err(eyre!(
+                "Memory too low: {}MB (minimum 128MB)",
+                self.memory_mb
+            ));
+        }
+        if self.memory_mb > 1024 * 1024 {
+            return Err(eyre!("Memory too high: {}MB (maximum 1TB)", self.memory_mb));
+        }
+        if let Some(max) = self.memory_max_mb {
+            if max < self.memory_mb {
+                return Err(eyre!(
+                    "Maximum memory {max}MB is less than memory {}MB",
+                    self.memory_mb
+                ));
+            }
+            if max > 1024 * 1024 {
+                return Err(eyre!("Maximum memory too high: {max}MB (maximum 1TB)"));
+            }
+        }
+
+        // CPU validation
+        if self.vcpus == 0 {
+            return Err(eyre!("vCPU count must be at least 1"));
+        }
+        if self.vcpus > 256 {
+            return Err(eyre!("vCPU count too high: {} (maximum 256)", self.vcpus));
+        }
+        if let Some(topology) = self.smp_topology {
+            if topology.vcpus() != self.vcpus {
+                return Err(eyre!(
+                    "vCPU topology {topology} has {} vCPUs, but {} are configured",
+                    topology.vcpus(),
+                    self.vcpus
+                ));
+            }
+        }
+        if self.cpu_model.as_deref().is_some_and(str::is_empty) {
+            return Err(eyre!("CPU model cannot be empty"));
+        }
+
+        validate_extra_args(&self.extra_args)?;
+
+        if self.emulated_devices && !matches!(self.boot_mode, Some(BootMode::Firmware { .. })) {
+            return Err(eyre!("Emulated devices require firmware boot"));
+        }
+
+        let render_nodes = self
+            .host_devices
+            .iter()
+            .filter(|d| matches!(d, HostDevice::RenderNode(_)))
+            .count();
+        if render_nodes > 1 {
+            return Err(eyre!("Only one render node can be passed through"));
+        }
+
+        if self.console_log.is_some() && matches!(self.display_mode, DisplayMode::Console) {
+            return Err(eyre!(
+                "A console log cannot be used with an interactive console"
+            ));
+        }
+
+        // Validate virtiofs mounts
+        for mount in &self.additional_mounts {
+            if mount.tag.is_empty() {
+                return Err(eyre!("Virtiofs mount tag cannot be empty"));
+            }
+            let socket_dir = std::path::Path::new(&mount.socket_path)
+                .parent()
+                .ok_or_else(|| eyre!("Invalid virtiofs socket path: {}", mount.socket_path))?;
+            if !socket_dir.exists() {
+                return Err(eyre!(
+                    "Virtiofs socket directory does not exist: {}",
+                    socket_dir.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a virtio-blk device with specified format
+    pub fn add_virtio_blk_device_with_format(
+        &mut self,
+        disk_file: String,
+        serial: String,
+        format: ImageFormat,
+    ) -> &mut Self {
+        self.add_virtio_blk_device(VirtioBlkDevice {
+            disk_file,
+            serial,
+            format,
+            io: DiskIoConfig::default(),
+        })
+    }
+
+    /// Add a virtio-blk device
+    pub fn add_virtio_blk_device(&mut self, device: VirtioBlkDevice) -> &mut Self {
+        self.virtio_blk_devices.push(device);
+        self
+    }
+
+    /// Set the main virtiofs configuration for the root filesystem
+    pub fn set_main_virtiofs(&mut self, config: VirtiofsConfig) -> &mut Self {
+        self.main_virtiofs_config = Some(config);
+        self
+    }
+
+    /// Add a virtiofs configuration that will be spawned as a daemon
+    pub fn add_virtiofs(&mut self, config: VirtiofsConfig, tag: &str) -> &mut Self {
+        // Also add a corresponding mount so QEMU knows about it
+        self.additional_mounts.push(VirtiofsMount {
+            socket_path: config.socket_path.clone().into(),
+            tag: tag.to_owned(),
+        });
+        self.virtiofs_configs.push(config);
+        self
+    }
+
+    /// Add a virtio-serial output device
+    pub fn add_virtio_serial_out(
+        &mut self,
+        name: &str,
+        output_file: String,
+        append: bool,
+    ) -> &mut Self {
+        self.virtio_serial_devices.push(VirtioSerialOut {
+            name: name.to_owned(),
+            output_file,
+            append,
+        });
+        self
+    }
+
+    /// Pass `fd` to QEMU, returning the `/dev/fdset/N` path to refer to it
+    pub fn add_fd(&mut self, fd: Arc<OwnedFd>) -> String {
+        self.fdset.push(fd);
+        format!("/dev/fdset/{}", self.fdset.len())
+    }
+
+    /// Create a virtio-serial device with pipe-based output
+    pub fn add_virtio_serial_pipe(&mut self, name: &str) -> Result<OwnedFd> {
+        // Create a pipe for QEMU chardev communication
+        use rustix::pipe::pipe;
+        let (read_fd, write_fd) = pipe().context("Failed to create pipe")?;
+
+        let fdset = self.add_fd(Arc::new(write_fd));
+
+        // Use append=true for fdsets to avoid truncation issues
+        self.add_virtio_serial_out(name, fdset, true);
+        Ok(read_fd)
+    }
+
+    /// Create a guest console (`hvc0`) with pipe-based output
+    ///
+    /// This is only supported in headless mode; with [`DisplayMode::Console`]
+    /// the console is connected to stdio instead.
+    pub fn add_console_log_pipe(&mut self) -> Result<OwnedFd> {
+        use rustix::pipe::pipe;
+        let (read_fd, write_fd) = pipe().context("Failed to create pipe")?;
+
+        self.console_log = Some(self.add_fd(Arc::new(write_fd)));
+        Ok(read_fd)
+    }
+
+    /// Add SMBIOS credential for systemd credential passing
+    pub fn add_smbios_credential(&mut self, credential: String) -> &mut Self {
+        self.smbios_credentials.push(credential);
+        self
+    }
+
+    /// Add a credential for systemd whose value must not appear on the QEMU
+    /// command line, which any process on the host can read; QEMU reads it
+    /// from files in `dir`, readable only by their owner
+    pub fn add_secret_credential(
+        &mut self,
+        name: &str,
+        value: &str,
+        dir: &Utf8Path,
+    ) -> Result<&mut Self> {
+        let credential = SecretCredential {
+            name: name.to_owned(),
+            value_path: dir.join(name),
+            smbios_path: dir.join(format!("{name}.smbios")),
+        };
+        write_private(&credential.value_path, value)?;
+        write_private(
+            &credential.smbios_path,
+            &format!("io.systemd.credential:{name}={value}"),
+        )?;
+        self.secret_credentials.push(credential);
+        Ok(self)
+    }
+
+    /// Add a firmware configuration (fw_cfg) item with the contents of a file,
+    /// e.g. an Ignition config under `opt/com.coreos/config`
+    pub fn add_fw_cfg_file(&mut self, name: &str, path: &str) -> &mut Self {
+        self.fw_cfg_files.push((name.to_string(), path.to_string()));
+        self
+    }
+
+    /// Serve the QEMU Machine Protocol on a unix socket at `path`
+    pub fn enable_qmp(&mut self, path: Utf8PathBuf) -> &mut Self {
+        self.qmp_socket = Some(path);
+        self
+    }
+
+    /// Attach a TPM 2.0 device emulated by swtpm, keeping its state in `state_dir`
+    pub fn enable_tpm(&mut self, state_dir: Utf8PathBuf) -> &mut Self {
+        self.tpm_state_dir = Some(state_dir);
+        self
+    }
+
+    /// Give the guest `memory_max_mb` of RAM, with a virtio-balloon device
+    /// which can hold back all but `memory_mb` of it. The balloon target must
+    /// be set via QMP (see [`crate::qmp::QmpClient::balloon`]) once QEMU runs.
+    pub fn enable_balloon(&mut self, memory_max_mb: u32) -> &mut Self {
+        self.memory_max_mb = Some(memory_max_mb);
+        self
+    }
+
+    /// Emulate the CPU model `model` (e.g. `max` or `Skylake-Server,-hle`)
+    /// instead of passing through the host CPU
+    pub fn set_cpu_model(&mut self, model: String) -> &mut Self {
+        self.cpu_model = Some(model);
+        self
+    }
+
+    /// Run the guest with the given accelerator
+    pub fn set_accel(&mut self, accel: Accel) -> &mut Self {
+        self.accel = accel;
+        self
+    }
+
+    /// Use the machine type `machine` for direct boot, falling back to the
+    /// default one where it isn't supported
+    pub fn set_machine(&mut self, machine: MachineType) -> &mut Self {
+        self.machine = machine;
+        self
+    }
+
+    /// Why the microvm machine type can't run this configuration with
+    /// `extra_credentials`, if it can't
+    fn microvm_unsupported(&self, extra_credentials: &[String]) -> Option<String> {
+        if std::env::consts::ARCH != "x86_64" {
+            return Some("it is only available on x86_64".into());
+        } else if !matches!(self.boot_mode, Some(BootMode::DirectBoot { .. })) {
+            return Some("it requires direct boot".into());
+        } else if !self.host_devices.is_empty() {
+            return Some("host devices need PCI".into());
+        } else if self.tpm_state_dir.is_some() {
+            return Some("it has no TPM".into());
+        }
+        // Invalid credentials are left for spawning QEMU to report
+        let names = self
+            .smbios_credentials
+            .iter()
+            .chain(extra_credentials)
+            .filter_map(|c| parse_credential(c).ok().map(|(_, name, _)| name))
+            .chain(self.secret_credentials.iter().map(|c| c.name.as_str()));
+        let mut too_long = names.filter(|name| !fits_fw_cfg(name)).peekable();
+        too_long.peek()?;
+        let too_long = too_long.collect::<Vec<_>>().join(", ");
+        Some(format!(
+            "fw_cfg limits credential names to {} characters, and these are longer: {too_long}",
+            FW_CFG_MAX_NAME_LEN - FW_CFG_CREDENTIALS_DIR.len() - 1
+        ))
+    }
+
+    /// The machine type to run `qemu` with `extra_credentials`: the requested
+    /// one, or the default one with a warning if the requested one can't be used
+    fn machine_type(&self, qemu: &str, extra_credentials: &[String]) -> MachineType {
+        if self.machine != MachineType::Microvm {
+            return self.machine;
+        }
+        let reason = self
+            .microvm_unsupported(extra_credentials)
+            .or_else(|| (!qemu_has_microvm(qemu)).then(|| "QEMU was built without it".to_owned()));
+        match reason {
+            Some(reason) => {
+                warn!("Not using the microvm machine type, as {reason}");
+                MachineType::Default
+            }
+            None => MachineType::Microvm,
+        }
+    }
+
+    /// Configure the guest's real-time clock
+    pub fn set_rtc(&mut self, rtc: RtcConfig) -> &mut Self {
+        self.rtc = Some(rtc);
+        self
+    }
+
+    /// Arrange the vCPUs in sockets, cores and threads
+    pub fn set_smp_topology(&mut self, topology: SmpTopology) -> &mut Self {
+        self.smp_topology = Some(topology);
+        self
+    }
+
+    /// Append raw arguments to the QEMU command line; see [`validate_extra_args`]
+    pub fn add_extra_args(&mut self, args: impl IntoIterator<Item = String>) -> &mut Self {
+        self.extra_args.extend(args);
+        self
+    }
+
+    /// Use emulated AHCI and e1000e devices for the boot disk and network
+    /// instead of virtio, e.g. for Windows guests
+    pub fn set_emulated_devices(&mut self, enable: bool) -> &mut Self {
+        self.emulated_devices = enable;
+        self
+    }
+
+    /// Pass a host device through to the VM; it should have passed
+    /// [`HostDevice::check`]
+    pub fn add_host_device(&mut self, device: HostDevice) -> &mut Self {
+        self.host_devices.push(device);
+        self
+    }
+
+    /// Restore the VM from a state file written by [`crate::qmp::QmpClient::save_state`].
+    /// The rest of the configuration must match that of the saved VM.
+    pub fn set_incoming_migration(&mut self, state_file: Utf8PathBuf) -> &mut Self {
+        self.incoming_migration = Some(state_file);
+        self
+    }
+
+    /// Enable SSH access by configuring port forwarding
+    pub fn enable_ssh_access(&mut self, host_port: Option<u16>) -> &mut Self {
+        let port = host_port.unwrap_or(2222); // Default to port 2222 on host
+        let hostfwd = format!("tcp::{}-:22", port); // Forward host port to guest port 22
+        self.network_mode = NetworkMode::User {
+            hostfwd: vec![hostfwd],
+        };
+        self
+    }
+}
+
+/// Allocate a unique VSOCK CID
+fn allocate_vsock_cid(vhost_fd: File) -> Result<(OwnedFd, u32)> {
+    use rustix::io::Errno;
+    use rustix::ioctl::{ioctl, opcode, Opcode, Setter};
+
+    // VHOST_VSOCK_SET_GUEST_CID = _IOW(VHOST_VIRTIO, 0x60, __u64)
+    const VHOST_VSOCK_SET_GUEST_CID: Opcode = opcode::write::<u64>(0xaf, 0x60);
+
+    for candidate_cid in 3..10001u32 {
+        // Test if this CID is available
+        // SAFETY: VHOST_VSOCK_SET_GUEST_CID takes a pointer to a u64, which
+        // the Setter passes
+        #[allow(unsafe_code)]
+        let result = unsafe {
+            ioctl(
+                &vhost_fd,
+                Setter::<VHOST_VSOCK_SET_GUEST_CID, u64>::new(candidate_cid.into()),
+            )
+        };
+        match result {
+            Ok(()) => {
+                // Success! This CID is available
+                debug!("Successfully allocated VSOCK CID: {}", candidate_cid);
+                return Ok((vhost_fd.into(), candidate_cid));
+            }
+            Err(Errno::ADDRINUSE) => {
+                debug!("VSOCK CID {} is in use, trying next", candidate_cid);
+                continue;
+            }
+            Err(e) => return Err(std::io::Error::from(e).into()),
+        }
+    }
+
+    Err(eyre!("Could not find available VSOCK CID (tried 3-10000)"))
+}
+
+/// Spawn QEMU VM process with given configuration and optional extra credential.
+/// Uses KVM acceleration unless configured for TCG, memory-backend-memfd for VirtIO-FS compatibility.
+fn spawn(
+    config: &QemuConfig,
+    extra_credentials: &[String],
+    vsock: Option<(OwnedFd, u32)>,
+) -> Result<Child> {
+    // Validate configuration first
+    config.validate()?;
+    // With a balloon, the guest gets the maximum and the balloon holds back the rest
+    let ram_mb = config.memory_max_mb.unwrap_or(config.memory_mb);
+    let memory_arg = format!("{ram_mb}M");
+    let memory_obj_arg = format!("memory-backend-memfd,id=mem,share=on,size={ram_mb}M");
+
+    let qemu = std::env::var("QEMU_BIN")
+        .ok()
+        .map(Ok)
+        .unwrap_or_else(|| -> Result<_> {
+            // RHEL only supports non-emulated, and qemu is an implementation detail
+            // of higher level virt.
+            let libexec_qemu = Utf8Path::new("/usr/libexec/qemu-kvm");
+            if libexec_qemu.try_exists()? {
+                Ok(libexec_qemu.to_string())
+            } else {
+                let arch = std::env::consts::ARCH;
+                Ok(format!("qemu-system-{arch}"))
+            }
+        })
+        .context("Checking for qemu")?;
+
+    let machine = config.machine_type(&qemu, extra_credentials);
+    let mut cmd = Command::new(qemu);
+    // SAFETY: This API is safe to call in a forked child.
+    #[allow(unsafe_code)]
+    unsafe {
+        cmd.pre_exec(|| {
+            rustix::process::set_parent_process_death_signal(Some(rustix::process::Signal::TERM))
+                .map_err(Into::into)
+        });
+    }
+    let smp_arg = match config.smp_topology {
+        Some(topology) => format!("{},{topology}", config.vcpus),
+        None => config.vcpus.to_string(),
+    };
+    let (accel_args, default_cpu_model): (&[&str], _) = match config.accel {
+        Accel::Kvm => (&["-enable-kvm"], DEFAULT_CPU_MODEL),
+        Accel::Tcg => (&["-accel", "tcg,thread=multi"], TCG_CPU_MODEL),
+    };
+    let mut cpu_model = config
+        .cpu_model
+        .as_deref()
+        .unwrap_or(default_cpu_model)
+        .to_owned();
+    let fixed_time = config.rtc.is_some_and(|rtc| rtc.is_fixed());
+    if fixed_time && config.accel == Accel::Kvm && std::env::consts::ARCH == "x86_64" {
+        cpu_model.push_str(",kvmclock=off");
+    }
+    cmd.args(["-m", &memory_arg, "-smp", &smp_arg]);
+    cmd.args(accel_args);
+    if let Some(rtc) = config.rtc {
+        cmd.args(["-rtc", &rtc.to_string()]);
+    }
+    cmd.args([
+        "-cpu",
+        &cpu_model,
+        "-audio",
+        "none",
+        "-object",
+        &memory_obj_arg,
+    ]);
+    match machine {
+        MachineType::Default => {
+            cmd.args(["-numa", "node,memdev=mem"]);
+        }
+        MachineType::Microvm => {
+            cmd.args(["-machine", &format!("{MICROVM_MACHINE},memory-backend=mem")]);
+        }
+    }
+
+    for (idx, fd) in config.fdset.iter().enumerate() {
+        let fd_id = 100 + idx as u32; // Start at 100 to avoid conflicts
+        let set_id = idx + 1; // fdset starts at 1
+
+        // Pass the write FD to QEMU
+        cmd.take_fd_n(Arc::clone(fd), fd_id as i32);
+
+        cmd.args(["-add-fd", &format!("fd={},set={}", fd_id, set_id)]);
+    }
+
+    // Add virtio-blk block devices
+    for (idx, blk_device) in config.virtio_blk_devices.iter().enumerate() {
+        let drive_id = format!("drive{}", idx);
+        let mut drive = format!(
+            "file={},format={},if=none,id={}",
+            blk_device.disk_file,
+            blk_device.format.as_str(),
+            drive_id
+        );
+        let io = blk_device.io.to_string();
+        if !io.is_empty() {
+            drive.push(',');
+            drive.push_str(&io);
+        }
+        cmd.args([
+            "-drive",
+            &drive,
+            "-device",
+            &format!(
+                "{},drive={},serial={}",
+                machine.virtio_device("virtio-blk"),
+                drive_id,
+                blk_device.serial
+            ),
+        ]);
+    }
+
+    // Configure boot mode
+    match config.boot_mode.as_ref() {
+        Some(BootMode::DirectBoot {
+            kernel_path,
+            initramfs_path,
+            kernel_cmdline,
+            virtiofs_socket,
+        }) => {
+            // Direct kernel boot
+            cmd.args(["-kernel", kernel_path, "-initrd", initramfs_path]);
+
+            // Add virtiofs root mount for direct boot
+            cmd.args([
+                "-chardev",
+                &format!("socket,id=char0,path={}", virtiofs_socket),
+                "-device",
+                &format!(
+                    "{},queue-size=1024,chardev=char0,tag=rootfs",
+                    machine.virtio_device("vhost-user-fs")
+                ),
+            ]);
+
+            // Add kernel command line
+            let append_str = kernel_cmdline.join(" ");
+            cmd.args(["-append", &append_str]);
+        }
+        Some(BootMode::Firmware {
+            code_path,
+            code_format,
+            vars_path,
+            vars_format,
+            disk_file,
+            disk_format,
+        }) => {
+            cmd.args([
+                "-machine",
+                firmware_machine_type(),
+                "-drive",
+                &format!("if=pflash,unit=0,format={code_format},readonly=on,file={code_path}"),
+                "-drive",
+                &format!("if=pflash,unit=1,format={vars_format},file={vars_path}"),
+                "-drive",
+                &format!("file={disk_file},format={disk_format},if=none,id=bootdisk,snapshot=on"),
+            ]);
+            if config.emulated_devices {
+                cmd.args([
+                    "-device",
+                    "ahci,id=ahci0",
+                    "-device",
+                    "ide-hd,drive=bootdisk,bus=ahci0.0,serial=bootdisk,bootindex=1",
+                ]);
+            } else {
+                cmd.args([
+                    "-device",
+                    "virtio-blk-pci,drive=bootdisk,serial=bootdisk,bootindex=1",
+                ]);
+            }
+        }
+        None => {}
+    }
+
+    // Add additional virtiofs mounts
+    for (idx, mount) in config.additional_mounts.iter().enumerate() {
+        let char_id = format!("char{}", idx + 1);
+        cmd.args([
+            "-chardev",
+            &format!("socket,id={},path={}", char_id, mount.socket_path),
+            "-device",
+            &format!(
+                "{},queue-size=1024,chardev={},tag={}",
+                machine.virtio_device("vhost-user-fs"),
+                char_id,
+                mount.tag
+            ),
+        ]);
+    }
+
+    // Add virtio-serial controller - always needed for console
+    cmd.args(["-device", &machine.virtio_device("virtio-serial")]);
+
+    // Add virtio-serial devices
+    for (idx, serial_device) in config.virtio_serial_devices.iter().enumerate() {
+        let char_id = format!("serial_char{}", idx);
+        // Build chardev args with optional append
+        let chardev_args = if serial_device.append {
+            format!(
+                "file,id={},path={},append=on",
+                char_id, serial_device.output_file
+            )
+        } else {
+            format!("file,id={},path={}", char_id, serial_device.output_file)
+        };
+
+        cmd.args([
+            "-chardev",
+            &chardev_args,
+            "-device",
+            &format!(
+                "virtserialport,chardev={},name={}",
+                char_id, serial_device.name
+            ),
+        ]);
+    }
+
+    // Configure network (only User mode supported now)
+    match &config.network_mode {
+        NetworkMode::User { hostfwd } => {
+            let mut netdev_parts = vec!["user".to_string(), "id=net0".to_string()];
+
+            // Add port forwarding rules
+            for fwd in hostfwd {
+                netdev_parts.push(format!("hostfwd={}", fwd));
+            }
+
+            let netdev_arg = netdev_parts.join(",");
+            let nic = if config.emulated_devices {
+                "e1000e".to_owned()
+            } else {
+                machine.virtio_device("virtio-net")
+            };
+            cmd.args([
+                "-netdev",
+                &netdev_arg,
+                "-device",
+                &format!("{nic},netdev=net0"),
+            ]);
+        }
+    }
+
+    // No GUI, and no emulated serial ports by default. A render node is
+    // used through a headless EGL display.
+    let display = config
+        .host_devices
+        .iter()
+        .find_map(|d| match d {
+            HostDevice::RenderNode(path) => Some(format!("egl-headless,rendernode={path}")),
+            HostDevice::Vfio(_) => None,
+        })
+        .unwrap_or_else(|| "none".to_owned());
+    cmd.args(["-serial", "none", "-nographic", "-display", &display]);
+
+    for device in &config.host_devices {
+        match device {
+            HostDevice::Vfio(address) => {
+                cmd.args(["-device", &format!("vfio-pci,host={address}")]);
+            }
+            HostDevice::RenderNode(_) => {
+                // virgl for OpenGL, Venus for Vulkan; the latter needs blob
+                // resources mapped into the host memory window
+                cmd.args([
+                    "-device",
+                    &format!("virtio-gpu-gl-pci,blob=true,venus=true,hostmem={VIRTIO_GPU_HOSTMEM}"),
+                ]);
+            }
+        }
+    }
+
+    match &config.display_mode {
+        DisplayMode::None => {
+            if let Some(console_log) = &config.console_log {
+                cmd.args(["-device", "virtconsole,chardev=console0"]);
+                cmd.args([
+                    "-chardev",
+                    &format!("file,id=console0,path={console_log},append=on"),
+                ]);
+            }
+            // Disable monitor in non-console mode
+            cmd.args(["-monitor", "none"]);
+        }
+        DisplayMode::Console => {
+            cmd.args(["-device", "virtconsole,chardev=console0"]);
+            cmd.args(["-chardev", "stdio,id=console0,mux=on"]);
+            // Use monitor on the same muxed chardev
+            cmd.args(["-monitor", "chardev:console0"]);
+        }
+    }
+
+    if let Some(qmp_socket) = &config.qmp_socket {
+        cmd.args(["-qmp", &format!("unix:{qmp_socket},server=on,wait=off")]);
+    }
+
+    if config.memory_max_mb.is_some() {
+        // Free page reporting returns memory freed by the guest to the host
+        cmd.args([
+            "-device",
+            &format!(
+                "{},id=balloon0,free-page-reporting=on",
+                machine.virtio_device("virtio-balloon")
+            ),
+        ]);
+    }
+
+    if let Some(state_file) = &config.incoming_migration {
+        cmd.args(["-incoming", &format!("file:{state_file}")]);
+    }
+
+    if let Some(state_dir) = &config.tpm_state_dir {
+        // The MMIO variant is what is available on non-PC machine types
+        let tpm_device = match std::env::consts::ARCH {
+            "x86_64" => "tpm-tis",
+            _ => "tpm-tis-device",
+        };
+        cmd.args([
+            "-chardev",
+            &format!("socket,id=chrtpm,path={state_dir}/{SWTPM_SOCKET}"),
+            "-tpmdev",
+            "emulator,id=tpm0,chardev=chrtpm",
+            "-device",
+            &format!("{tpm_device},tpmdev=tpm0"),
+        ]);
+    }
+
+    // Add AF_VSOCK device if enabled
+    if let Some((vhostfd, guest_cid)) = vsock {
+        debug!("Adding AF_VSOCK device with guest CID: {}", guest_cid);
+        cmd.take_fd_n(Arc::new(vhostfd), 42);
+        cmd.args([
+            "-device",
+            &format!(
+                "{},guest-cid={},vhostfd=42",
+                machine.virtio_device("vhost-vsock"),
+                guest_cid
+            ),
+        ]);
+    }
+
+    // Add SMBIOS credentials for systemd credential passing, and extra
+    // credentials passed to this function
+    for credential in config.smbios_credentials.iter().chain(extra_credentials) {
+        match machine {
+            MachineType::Default => {
+                cmd.args(["-smbios", &format!("type=11,value={}", credential)]);
+            }
+            MachineType::Microvm => {
+                cmd.args(["-fw_cfg", &credential_fw_cfg(credential)?]);
+            }
+        }
+    }
+
+    for credential in &config.secret_credentials {
+        match machine {
+            MachineType::Default => {
+                cmd.args([
+                    "-smbios",
+                    &format!("type=11,path={}", credential.smbios_path),
+                ]);
+            }
+            MachineType::Microvm => {
+                cmd.args([
+                    "-fw_cfg",
+                    &format!(
+                        "name={FW_CFG_CREDENTIALS_DIR}/{},file={}",
+                        credential.name, credential.value_path
+                    ),
+                ]);
+            }
+        }
+    }
+
+    for (name, path) in &config.fw_cfg_files {
+        cmd.args(["-fw_cfg", &format!("name={name},file={path}")]);
+    }
+
+    if !config.extra_args.is_empty() {
+        debug!("Adding extra QEMU arguments: {:?}", config.extra_args);
+        cmd.args(&config.extra_args);
+    }
+
+    // Configure stdio based on display mode
+    match &config.display_mode {
+        DisplayMode::Console => {
+            // Keep stdio for console interaction
+        }
+        _ => {
+            // Redirect stdout/stderr for non-console modes
+            if !config.enable_console {
+                // In non-console mode, redirect stderr to inherited (so we can see QEMU errors)
+                // but redirect stdout to null (to avoid noise)
+                cmd.stdout(Stdio::null()).stderr(Stdio::inherit());
+            }
+        }
+    }
+
+    tracing::debug!("{cmd:?}");
+
+    cmd.spawn().context("Failed to spawn QEMU")
+}
+
+/// A spawned QEMU process and the helper processes it needs
+pub struct RunningQemu {
+    /// The QEMU process
+    pub qemu_process: Child,
+    /// QMP socket, if enabled via [`QemuConfig::enable_qmp`]
+    qmp_socket: Option<Utf8PathBuf>,
+    /// Pending exits of the virtiofsd (and swtpm) processes
+    pub virtiofsd_processes: Vec<Pin<Box<dyn Future<Output = std::io::Result<Output>>>>>,
+    /// vsock CID of the guest, if vsock is enabled
+    guest_cid: Option<u32>,
+}
+
+impl std::fmt::Debug for RunningQemu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RunningQemu")
+            .field("qemu_process", &self.qemu_process)
+            .field("qmp_socket", &self.qmp_socket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RunningQemu {
+    /// Spawn QEMU
+    pub async fn spawn(mut config: QemuConfig) -> Result<Self> {
+        // Spawn all virtiofsd processes (and swtpm) first
+        let mut awaiting_virtiofsd = Vec::new();
+        let virtiofsd_configs = config
+            .main_virtiofs_config
+            .iter()
+            .chain(config.virtiofs_configs.iter());
+        for config in virtiofsd_configs {
+            let process = spawn_virtiofsd_async(config).await?;
+            awaiting_virtiofsd.push(("virtiofsd", process, config.socket_path.clone()));
+        }
+        if let Some(state_dir) = config.tpm_state_dir.as_deref() {
+            let process = spawn_swtpm_async(state_dir)?;
+            awaiting_virtiofsd.push(("swtpm", process, state_dir.join(SWTPM_SOCKET)));
+        }
+
+        // Wait for all of them to be ready
+        let mut virtiofsd_processes = Vec::new();
+        while let Some((name, proc, socket_path)) = awaiting_virtiofsd.pop() {
+            let socket_path = &socket_path;
+            let query_exists = async move {
+                loop {
+                    if socket_path.exists() {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            };
+            tokio::pin!(query_exists);
+            let timeout_val = Duration::from_secs(60);
+            let timeout = tokio::time::sleep(timeout_val);
+            tokio::pin!(timeout);
+            debug!("Waiting for socket at {socket_path}");
+            let mut output: Pin<Box<dyn Future<Output = std::io::Result<Output>>>> =
+                Box::pin(proc.wait_with_output());
+            tokio::select! {
+                output = &mut output => {
+                    tracing::trace!("{name} exited");
+                    let output = output?;
+                    let status = output.status;
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    return Err(eyre!(
+                        "{name} failed to start for socket {socket_path}\nExit status: {status:?}\nOutput: {stderr}"
+                    ));
+                }
+                _ = timeout => {
+                    return Err(eyre!("timed out waiting for {name} socket {} to be created (waited {timeout_val:?})", socket_path));
+                }
+                _ = query_exists => {
+                }
+            }
+            virtiofsd_processes.push(output);
+            tracing::debug!("{name} socket created: {socket_path}");
+        }
+
+        let vsockdata = if let Some(vhost_fd) = config.vhost_fd.take() {
+            // Get a unique guest CID using dynamic allocation
+            // If /dev/vhost-vsock is not available, fall back to disabled vsock
+            match allocate_vsock_cid(vhost_fd) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    debug!("Failed to allocate vsock CID, disabling vsock: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // The port systemd notifications are forwarded to a file from
+        let sd_notify_port = if let Some(target) = config.systemd_notify.take() {
+            color_eyre::eyre::ensure!(vsockdata.is_some());
+            // Bind to host address with ANY port - let kernel allocate a free port
+            let listener = VsockListener::bind_with_cid(VMADDR_CID_ANY)
+                .context("Failed to bind AF_VSOCK stream socket")?;
+            let port = listener.local_addr()?;
+            debug!("Listening on AF_VSOCK {port}");
+
+            // Runs as long as the process
+            std::thread::spawn(move || {
+                use std::io::{Read, Write};
+
+                debug!("AF_VSOCK listener thread started, waiting for systemd notifications");
+                let mut target = target;
+
+                // Accept connections and copy data to target file
+                for client in listener.incoming() {
+                    match client {
+                        Ok(mut client) => {
+                            debug!("Accepted systemd notification connection");
+
+                            // Read from socket and write to file
+                            let mut buffer = [0u8; 4096];
+                            match client.read(&mut buffer) {
+                                Ok(bytes_read) if bytes_read > 0 => {
+                                    let data = &buffer[..bytes_read];
+                                    trace!("Received systemd notification ({} bytes)", bytes_read);
+
+                                    // Write raw data directly to target file, with a
+                                    // newline to separate notifications
+                                    if let Err(e) = target
+                                        .write_all(data)
+                                        .and_then(|()| target.write_all(b"\n"))
+                                        .and_then(|()| target.flush())
+                                    {
+                                        warn!("Failed to forward systemd notification: {e}");
+                                        return;
+                                    }
+                                }
+                                Ok(_) => {
+                                    debug!("Connection closed");
+                                }
+                                Err(e) => {
+                                    warn!("Failed to receive data: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                    }
+                }
+            });
+
+            Some(port.port())
+        } else {
+            None
+        };
+
+        let creds = sd_notify_port
+            .map(|port| vec![crate::credentials::smbios_cred_for_vsock_notify(2, port)])
+            .unwrap_or_default();
+
+        // Spawn QEMU process with additional VSOCK credential if needed
+        let guest_cid = vsockdata.as_ref().map(|(_, cid)| *cid);
+        let qemu_process = spawn(&config, &creds, vsockdata)?;
+
+        Ok(Self {
+            qemu_process,
+            qmp_socket: config.qmp_socket.clone(),
+            virtiofsd_processes,
+            guest_cid,
+        })
+    }
+
+    /// The vsock CID allocated to the guest, if vsock is enabled
+    pub fn guest_cid(&self) -> Option<u32> {
+        self.guest_cid
+    }
+
+    /// Connect to the QMP socket of this VM. This blocks (briefly) if QEMU
+    /// has not created the socket yet.
+    pub fn qmp(&self) -> Result<crate::qmp::QmpClient> {
+        let socket = self
+            .qmp_socket
+            .as_deref()
+            .ok_or_else(|| eyre!("QMP is not enabled for this VM"))?;
+        crate::qmp::QmpClient::connect_with_timeout(socket, QMP_CONNECT_TIMEOUT)
+    }
+
+    /// Wait for QEMU process to exit
+    pub async fn wait(&mut self) -> Result<std::process::ExitStatus> {
+        let r = self.qemu_process.wait()?;
+        Ok(r)
+    }
+}
+
+/// Check that extra QEMU arguments don't collide with the options and
+/// devices managed by bcvk.
+pub fn validate_extra_args(args: &[String]) -> Result<()> {
+    for arg in args {
+        if let Some(opt) = arg.strip_prefix('-') {
+            // QEMU accepts both -opt and --opt
+            let opt = opt.strip_prefix('-').unwrap_or(opt);
+            if MANAGED_OPTIONS.contains(&opt) {
+                return Err(eyre!(
+                    "QEMU option '{arg}' is managed by bcvk and can't be passed as an extra argument"
+                ));
+            }
+        } else if let Some(id) = arg.split(',').find_map(|v| v.strip_prefix("id=")) {
+            if is_managed_id(id) {
+                return Err(eyre!(
+                    "QEMU id '{id}' (in '{arg}') is used by a device managed by bcvk; choose a different id"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_managed_id(id: &str) -> bool {
+    MANAGED_IDS.contains(&id)
+        || MANAGED_ID_PREFIXES.iter().any(|prefix| {
+            id.strip_prefix(prefix)
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+}
+
+/// Spawn QEMU with automatic process cleanup via guard.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtio_serial_device_creation() {
+        let mut config = QemuConfig::new_direct_boot(
+            1024,
+            1,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        config
+            .add_virtio_serial_out("serial0", "/tmp/output.txt".to_string(), false)
+            .set_kernel_cmdline(vec!["console=ttyS0".to_string()])
+            .set_console(true);
+
+        // Test that the config is created correctly
+        assert_eq!(config.virtio_serial_devices.len(), 1);
+        assert_eq!(config.virtio_serial_devices[0].name, "serial0");
+        assert_eq!(
+            config.virtio_serial_devices[0].output_file,
+            "/tmp/output.txt"
+        );
+    }
+
+    #[test]
+    fn test_balloon_validation() {
+        let mut config = QemuConfig::new_direct_boot(
+            2048,
+            1,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        config.validate().unwrap();
+        config.enable_balloon(8192);
+        config.validate().unwrap();
+        config.enable_balloon(1024);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_console_log_validation() {
+        let mut config = QemuConfig::new_direct_boot(
+            2048,
+            1,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        let _pipe = config.add_console_log_pipe().unwrap();
+        config.validate().unwrap();
+        config.set_console(true);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_host_devices_validation() {
+        let mut config = QemuConfig::new_direct_boot(
+            2048,
+            1,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        config
+            .add_host_device(HostDevice::Vfio("0000:01:00.0".into()))
+            .add_host_device(HostDevice::Vfio("0000:01:00.1".into()))
+            .add_host_device(HostDevice::RenderNode("/dev/dri/renderD128".into()));
+        config.validate().unwrap();
+        config.add_host_device(HostDevice::RenderNode("/dev/dri/renderD129".into()));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_emulated_devices_validation() {
+        let mut config = QemuConfig::new_direct_boot(
+            2048,
+            1,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        config.set_emulated_devices(true);
+        assert!(config.validate().is_err());
+
+        let firmware = FirmwareInfo {
+            code_path: "/test/OVMF_CODE.fd".into(),
+            code_format: "raw".into(),
+            vars_path: "/test/OVMF_VARS.fd".into(),
+            vars_format: "raw".into(),
+        };
+        let mut config = QemuConfig::new_firmware_boot(
+            2048,
+            1,
+            &firmware,
+            "/test/vars.fd".into(),
+            "/test/disk.qcow2".into(),
+            "qcow2".into(),
+        );
+        config.set_emulated_devices(true);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_smp_topology() {
+        let t: SmpTopology = "sockets=2,cores=4,threads=2".parse().unwrap();
+        assert_eq!(t.vcpus(), 16);
+        assert_eq!(t.to_string(), "sockets=2,cores=4,threads=2");
+        let t: SmpTopology = "cores=3".parse().unwrap();
+        assert_eq!(t.to_string(), "sockets=1,cores=3,threads=1");
+
+        for invalid in [
+            "cores",
+            "cores=0",
+            "cores=x",
+            "dies=2",
+            "sockets=1,cores=300",
+        ] {
+            assert!(invalid.parse::<SmpTopology>().is_err(), "{invalid}");
+        }
+
+        let mut config = QemuConfig::new_direct_boot(
+            2048,
+            4,
+            "/test/kernel".to_string(),
+            "/test/initramfs".to_string(),
+            "/test/socket".into(),
+        );
+        config.set_smp_topology("sockets=2,cores=2".parse().unwrap());
+        config.validate().unwrap();
+        config.set_smp_topology("sockets=2".parse().unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_rtc_config() {
+        let rtc: RtcConfig = "base=localtime,clock=vm".parse().unwrap();
+        assert_eq!(rtc.base, RtcBase::Localtime);
+        assert_eq!(rtc.clock, RtcClock::Vm);
+        assert_eq!(rtc.to_string(), "base=localtime,clock=vm");
+        let rtc: RtcConfig = "clock=vm".parse().unwrap();
+        assert_eq!(rtc.to_string(), "base=utc,clock=vm");
+        assert!(!rtc.is_fixed());
+
+        let rtc = RtcConfig::frozen_at(1_893_456_000);
+        assert!(rtc.is_fixed());
+        assert_eq!(rtc.to_string(), "base=2030-01-01T00:00:00,clock=vm");
+
+        for invalid in ["base", "base=2030-01-01", "clock=rt", "driftfix=slew"] {
+            assert!(invalid.parse::<RtcConfig>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_disk_io_config() {
+        let io: DiskIoConfig = "cache=none,aio=io_uring".parse().unwrap();
+        assert_eq!(io.cache, Some(DiskCache::None));
+        assert_eq!(io.aio, Some(DiskAio::IoUring));
+        assert_eq!(io.to_string(), "cache=none,aio=io_uring");
+        assert_eq!(
+            "aio=threads".parse::<DiskIoConfig>().unwrap().to_string(),
+            "aio=threads"
+        );
+        assert_eq!(DiskIoConfig::default().to_string(), "");
+        assert_eq!(DiskIoConfig::throwaway().to_string(), "cache=unsafe");
+
+        for invalid in ["cache", "cache=directsync", "aio=native", "discard=unmap"] {
+            assert!(invalid.parse::<DiskIoConfig>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_rtc_timestamp() {
+        for s in [
+            "2030-01-01",
+            "2030-01-01T00:00:00",
+            "2030-01-01T00:00:00Z",
+            "2030-01-01T01:00:00+01:00",
+            "@1893456000",
+        ] {
+            assert_eq!(parse_rtc_timestamp(s).unwrap(), 1_893_456_000, "{s}");
+        }
+        for invalid in [
+            "",
+            "tomorrow",
+            "2030-13-01",
+            "@soon",
+            "@9223372036854775807",
+            "@-9223372036854775808",
+            "@253402300800",
+        ] {
+            assert!(parse_rtc_timestamp(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_validate_extra_args() {
+        let valid: &[&[&str]] = &[
+            &[],
+            &["-device", "virtio-rng-pci,id=rng0"],
+            &["-machine", "q35,smm=on"],
+            &["-chardev", "socket,id=charx,path=/tmp/s"],
+            &["--global", "driver=cfi.pflash01,property=secure,value=on"],
+            &["-device", "virtio-scsi-pci,id=drive"],
+        ];
+        for args in valid {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            validate_extra_args(&args).unwrap();
+        }
+
+        let invalid: &[&[&str]] = &[
+            &["-m", "8G"],
+            &["--smp", "4"],
+            &["-kernel", "/boot/vmlinuz"],
+            &["-qmp", "unix:/tmp/qmp.sock,server=on"],
+            &["-netdev", "user,id=net0"],
+            &["-object", "memory-backend-ram,id=mem,size=1G"],
+            &["-chardev", "socket,id=char1,path=/tmp/s"],
+            &["-drive", "file=/tmp/disk.img,if=none,id=drive0"],
+        ];
+        for args in invalid {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            assert!(validate_extra_args(&args).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_credential_fw_cfg() {
+        let encoded = data_encoding::BASE64.encode(b"[Unit]\nWants=a.service,b.service\n");
+        let cases = [
+            (
+                "io.systemd.credential:vmm.notify_socket=vsock-stream:2:1234".to_owned(),
+                Some("name=opt/io.systemd.credentials/vmm.notify_socket,string=vsock-stream:2:1234"),
+            ),
+            (
+                format!("io.systemd.credential.binary:systemd.extra-unit.x.mount={encoded}"),
+                Some("name=opt/io.systemd.credentials/systemd.extra-unit.x.mount,string=[Unit]\nWants=a.service,,b.service\n"),
+            ),
+            // Longer than fw_cfg file names can be
+            (
+                format!("io.systemd.credential.binary:systemd.unit-dropin.sysinit.target~x={encoded}"),
+                None,
+            ),
+            ("io.systemd.credential.binary:x=not base64".to_owned(), None),
+            ("io.systemd.credential:novalue".to_owned(), None),
+            ("something=else".to_owned(), None),
+        ];
+        for (credential, expected) in cases {
+            assert_eq!(
+                credential_fw_cfg(&credential).ok().as_deref(),
+                expected,
+                "{credential}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_microvm_unsupported() {
+        let direct_boot = || {
+            QemuConfig::new_direct_boot(
+                1024,
+                1,
+                "/test/kernel".to_string(),
+                "/test/initramfs".to_string(),
+                "/test/socket".into(),
+            )
+        };
+        if std::env::consts::ARCH != "x86_64" {
+            assert!(direct_boot().microvm_unsupported(&[]).is_some());
+            return;
+        }
+        assert_eq!(direct_boot().microvm_unsupported(&[]), None);
+        let mut tpm = direct_boot();
+        tpm.enable_tpm("/run/swtpm".into());
+        assert!(tpm.microvm_unsupported(&[]).is_some());
+        let mut gpu = direct_boot();
+        gpu.add_host_device(HostDevice::RenderNode("/dev/dri/renderD128".into()));
+        assert!(gpu.microvm_unsupported(&[]).is_some());
+        let no_boot = QemuConfig {
+            boot_mode: None,
+            ..direct_boot()
+        };
+        assert!(no_boot.microvm_unsupported(&[]).is_some());
+        let notify = crate::credentials::smbios_cred_for_vsock_notify(2, 1234);
+        assert_eq!(direct_boot().microvm_unsupported(&[notify]), None);
+        let mut swap = direct_boot();
+        for credential in crate::credentials::smbios_creds_for_swap(1 << 30, Default::default()) {
+            swap.add_smbios_credential(credential);
+        }
+        let reason = swap.microvm_unsupported(&[]).unwrap();
+        assert!(
+            reason.contains("systemd.unit-dropin.default.target~bcvk-swap-service"),
+            "{reason}"
+        );
+    }
+
+    /// The credentials bcvk passes, checked against the fw_cfg name limit
+    #[test]
+    fn test_credential_names_fw_cfg() {
+        let mut credentials = vec![crate::credentials::smbios_cred_for_vsock_notify(2, 1234)];
+        credentials.extend(
+            crate::credentials::smbios_creds_for_mount_unit(
+                "mount_virtiofs_src",
+                "/run/virtiofs-mnt-src",
+                true,
+            )
+            .unwrap(),
+        );
+        credentials.extend(
+            crate::credentials::smbios_creds_for_storage_opts("/run/host-storage").unwrap(),
+        );
+        credentials.extend(crate::credentials::smbios_creds_for_vsock_exec(5000));
+        credentials.extend(crate::credentials::smbios_creds_for_swap(
+            1 << 30,
+            Default::default(),
+        ));
+        credentials.extend(
+            crate::credentials::smbios_creds_for_ssh("ssh-ed25519 AAAA test", None).unwrap(),
+        );
+        let mut too_long = credentials
+            .iter()
+            .map(|c| parse_credential(c).unwrap().1)
+            .filter(|name| !fits_fw_cfg(name))
+            .collect::<Vec<_>>();
+        too_long.sort_unstable();
+        assert_eq!(
+            too_long,
+            [
+                "systemd.extra-unit.bcvk-exec.socket",
+                "systemd.extra-unit.bcvk-exec@.service",
+                "systemd.extra-unit.bcvk-storage-opts.service",
+                "systemd.extra-unit.bcvk-swap.service",
+                "systemd.extra-unit.run-virtiofs\\x2dmnt\\x2dsrc.mount",
+                "systemd.unit-dropin.default.target~bcvk-swap-service",
+                "systemd.unit-dropin.local-fs.target~bcvk-mounts",
+                "systemd.unit-dropin.sockets.target~bcvk-exec",
+                "systemd.unit-dropin.sysinit.target~bcvk-storage",
+            ]
+        );
+        for credential in &credentials {
+            let (_, name, _) = parse_credential(credential).unwrap();
+            assert_eq!(
+                credential_fw_cfg(credential).is_ok(),
+                fits_fw_cfg(name),
+                "{name}"
+            );
+        }
+        assert!(fits_fw_cfg(
+            crate::credentials::VSOCK_EXEC_SECRET_CREDENTIAL
+        ));
+    }
+}
+
+/// VirtiofsD daemon configuration.
+#[derive(Debug, Clone)]
+pub struct VirtiofsConfig {
+    /// Unix socket for QEMU communication
+    pub socket_path: Utf8PathBuf,
+    /// Host directory to share
+    pub shared_dir: Utf8PathBuf,
+    pub debug: bool,
+    /// Mount as read-only
+    pub readonly: bool,
+    /// Optional log file path for virtiofsd output
+    pub log_file: Option<Utf8PathBuf>,
+}
+
+impl Default for VirtiofsConfig {
+    fn default() -> Self {
+        Self {
+            socket_path: "/run/inner-shared/virtiofs.sock".into(),
+            shared_dir: "/run/source-image".into(),
+            debug: false,
+            // We don't need to write to this, there's a transient overlay
+            readonly: true,
+            log_file: None,
+        }
+    }
+}
+
+/// Common virtiofsd binary locations, in order of preference.
+pub const VIRTIOFSD_PATHS: &[&str] = &[
+    "/usr/libexec/virtiofsd",
+    "/usr/bin/virtiofsd",
+    "/usr/local/bin/virtiofsd",
+    "/usr/lib/virtiofsd",
+];
+
+/// Find the virtiofsd binary in one of [`VIRTIOFSD_PATHS`].
+pub fn find_virtiofsd() -> Option<&'static str> {
+    VIRTIOFSD_PATHS
+        .iter()
+        .copied()
+        .find(|path| std::path::Path::new(path).exists())
+}
+
+/// Check if virtiofsd supports the --readonly flag.
+async fn virtiofsd_supports_readonly(virtiofsd_binary: &str) -> bool {
+    let output = tokio::process::Command::new(virtiofsd_binary)
+        .arg("--help")
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            stdout.contains("--readonly") || stderr.contains("--readonly")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Spawn virtiofsd daemon process as tokio::process::Child.
+/// Searches for binary in /usr/libexec, /usr/bin, /usr/local/bin.
+/// Creates socket directory if needed, redirects output unless debug=true.
+pub async fn spawn_virtiofsd_async(config: &VirtiofsConfig) -> Result<tokio::process::Child> {
+    // Validate configuration
+    validate_virtiofsd_config(config)?;
+
+    let virtiofsd_binary = find_virtiofsd().ok_or_else(|| {
+        Report::new(Failure::HypervisorUnavailable).wrap_err(format!(
+            "virtiofsd binary not found. Searched paths: {}. Please install virtiofsd.",
+            VIRTIOFSD_PATHS.join(", ")
+        ))
+    })?;
+
+    // Check if virtiofsd supports --readonly flag
+    let supports_readonly = virtiofsd_supports_readonly(virtiofsd_binary).await;
+    debug!(
+        "virtiofsd at {} supports --readonly: {}",
+        virtiofsd_binary, supports_readonly
+    );
+
+    let mut cmd = tokio::process::Command::new(virtiofsd_binary);
+    // SAFETY: This API is safe to call in a forked child.
+    #[allow(unsafe_code)]
+    unsafe {
+        cmd.pre_exec(|| {
+            rustix::process::set_parent_process_death_signal(Some(rustix::process::Signal::TERM))
+                .map_err(Into::into)
+        });
+    }
+    cmd.args([
+        "--socket-path",
+        config.socket_path.as_str(),
+        "--shared-dir",
+        config.shared_dir.as_str(),
+        // Ensure we don't hit fd exhaustion
+        "--cache=never",
+        // We always run in a container
+        "--sandbox=none",
+    ]);
+
+    // Only add --readonly if requested and supported
+    if config.readonly && supports_readonly {
+        cmd.arg("--readonly");
+    }
+
+    // https://gitlab.com/virtio-fs/virtiofsd/-/issues/17 - this is the new default,
+    // but we want to be compatible with older virtiofsd too.
+    cmd.arg("--inode-file-handles=fallback");
+
+    // Configure output redirection
+    if let Some(log_file) = &config.log_file {
+        // Create/open log file for both stdout and stderr
+        let tokio_file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .await
+            .with_context(|| format!("Failed to open virtiofsd log file: {}", log_file))?;
+
+        let log_file_handle = tokio_file.into_std().await;
+
+        // Clone for stderr
+        let stderr_handle = log_file_handle
+            .try_clone()
+            .with_context(|| "Failed to clone log file handle for stderr")?;
+
+        cmd.stdout(std::process::Stdio::from(log_file_handle));
+        cmd.stderr(std::process::Stdio::from(stderr_handle));
+
+        debug!("virtiofsd output will be logged to: {}", log_file);
+    } else {
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+    }
+
+    let child = cmd.spawn().with_context(|| {
+        format!(
+            "Failed to spawn virtiofsd. Binary: {}, Socket: {}, Shared dir: {}",
+            virtiofsd_binary, config.socket_path, config.shared_dir
+        )
+    })?;
+
+    debug!(
+        "Spawned virtiofsd: binary={}, socket={}, shared_dir={}, debug={}, log_file={:?}",
+        virtiofsd_binary, config.socket_path, config.shared_dir, config.debug, config.log_file
+    );
+
+    Ok(child)
+}
+
+/// Spawn swtpm emulating a TPM 2.0, with its state and control socket in `state_dir`.
+/// The process exits when QEMU disconnects from the control socket.
+fn spawn_swtpm_async(state_dir: &Utf8Path) -> Result<tokio::process::Child> {
+    let swtpm_paths = ["/usr/bin/swtpm", "/usr/local/bin/swtpm"];
+    let swtpm_binary = swtpm_paths
+        .iter()
+        .find(|path| std::path::Path::new(path).exists())
+        .ok_or_else(|| {
+            eyre!(
+                "swtpm binary not found. Searched paths: {}. Please install swtpm on the host.",
+                swtpm_paths.join(", ")
+            )
+        })?;
+
+    std::fs::create_dir_all(state_dir)
+        .with_context(|| format!("Creating TPM state directory {state_dir}"))?;
+
+    let mut cmd = tokio::process::Command::new(swtpm_binary);
+    // SAFETY: This API is safe to call in a forked child.
+    #[allow(unsafe_code)]
+    unsafe {
+        cmd.pre_exec(|| {
+            rustix::process::set_parent_process_death_signal(Some(rustix::process::Signal::TERM))
+                .map_err(Into::into)
+        });
+    }
+    cmd.args([
+        "socket",
+        "--tpm2",
+        "--tpmstate",
+        &format!("dir={state_dir}"),
+        "--ctrl",
+        &format!("type=unixio,path={state_dir}/{SWTPM_SOCKET}"),
+        "--terminate",
+    ]);
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    let child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to spawn {swtpm_binary}"))?;
+    debug!("Spawned swtpm with state in {state_dir}");
+    Ok(child)
+}
+
+/// Validate virtiofsd configuration.
+/// Checks shared directory exists/readable, socket path valid,
+/// and cache/sandbox modes are valid values.
+pub fn validate_virtiofsd_config(config: &VirtiofsConfig) -> Result<()> {
+    // Validate shared directory
+    let shared_path = std::path::Path::new(&config.shared_dir);
+    if !shared_path.exists() {
+        return Err(eyre!(
+            "Virtiofsd shared directory does not exist: {}",
+            config.shared_dir
+        ));
+    }
+
+    if !shared_path.is_dir() {
+        return Err(eyre!(
+            "Virtiofsd shared directory is not a directory: {}",
+            config.shared_dir
+        ));
+    }
+
+    // Check if directory is readable
+    match std::fs::read_dir(shared_path) {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(eyre!(
+                "Cannot read virtiofsd shared directory {}: {}",
+                config.shared_dir,
+                e
+            ));
+        }
+    }
+
+    // Validate socket path
+    if config.socket_path.as_str().is_empty() {
+        return Err(eyre!("Virtiofsd socket path cannot be empty"));
+    }
+
+    let socket_path = std::path::Path::new(&config.socket_path);
+    if let Some(socket_dir) = socket_path.parent() {
+        if !socket_dir.exists() {
+            std::fs::create_dir_all(socket_dir).with_context(|| {
+                format!(
+                    "Failed to create socket directory: {}",
+                    socket_dir.display()
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
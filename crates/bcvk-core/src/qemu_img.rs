@@ -0,0 +1,440 @@
+//! Helper functions for interacting with qemu-img
+//!
+//! All operations which take a [`Dir`] run `qemu-img` with that directory as
+//! its working directory and refer to images by file name relative to it.
+//! This keeps path handling for e.g. the libvirt storage pool in one place,
+//! and avoids accidentally operating on files outside of it.
+
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use color_eyre::eyre::eyre;
+use color_eyre::{eyre::Context, Result};
+use serde::Deserialize;
+
+use crate::hostexec::HostCommand;
+
+/// Disk image formats understood by this wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Raw disk image
+    Raw,
+    /// QEMU Copy On Write 2
+    Qcow2,
+}
+
+impl ImageFormat {
+    /// Get the string representation for qemu-img
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Compression algorithm for qcow2 output in [`convert`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    /// zlib (the qemu-img default)
+    Zlib,
+    /// zstd
+    Zstd,
+}
+
+impl CompressionType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CompressionType::Zlib => "zlib",
+            CompressionType::Zstd => "zstd",
+        }
+    }
+}
+
+/// Information returned by `qemu-img info --output=json`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QemuImgInfo {
+    /// Virtual size of the disk image in bytes
+    pub virtual_size: u64,
+    /// Path to the disk image file
+    pub filename: String,
+    /// Image format (e.g., "qcow2", "raw")
+    pub format: String,
+    /// Actual size on disk in bytes (if available)
+    pub actual_size: Option<u64>,
+    /// Cluster size in bytes (for formats like qcow2)
+    pub cluster_size: Option<u64>,
+    /// Backing file name (if this is a snapshot)
+    pub backing_filename: Option<String>,
+    /// Full path to backing file (if this is a snapshot)
+    pub full_backing_filename: Option<String>,
+    /// Whether the image is marked as dirty
+    pub dirty_flag: Option<bool>,
+    /// Internal snapshots stored in the image (qcow2 only)
+    #[serde(default)]
+    pub snapshots: Vec<QemuImgSnapshot>,
+}
+
+/// An internal snapshot as reported by `qemu-img info --output=json`
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct QemuImgSnapshot {
+    /// Numeric snapshot identifier
+    pub id: String,
+    /// Snapshot name
+    pub name: String,
+    /// Size of the saved VM state in bytes (0 for disk-only snapshots)
+    pub vm_state_size: u64,
+    /// Creation time, seconds since the epoch
+    pub date_sec: u64,
+}
+
+/// Options for [`convert`]
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOpts {
+    /// Output format; defaults to the qemu-img default (raw)
+    pub format: Option<ImageFormat>,
+    /// Compress the output (qcow2 only)
+    pub compress: bool,
+    /// Compression algorithm to use when `compress` is set
+    pub compression_type: Option<CompressionType>,
+}
+
+impl ConvertOpts {
+    /// Build the `qemu-img convert` arguments for the given source and target
+    fn to_args(&self, src: &str, dest: &str) -> Result<Vec<String>> {
+        let mut args = vec!["convert".to_string()];
+        if let Some(format) = self.format {
+            args.extend(["-O".to_string(), format.to_string()]);
+        }
+        if self.compress {
+            if self.format != Some(ImageFormat::Qcow2) {
+                return Err(eyre!("Compression is only supported for qcow2 output"));
+            }
+            args.push("-c".to_string());
+            if let Some(compression_type) = self.compression_type {
+                args.extend([
+                    "-o".to_string(),
+                    format!("compression_type={}", compression_type.as_str()),
+                ]);
+            }
+        }
+        args.extend([src.to_string(), dest.to_string()]);
+        Ok(args)
+    }
+}
+
+/// Create a qemu-img command operating relative to `dir`
+fn qemu_img_in(dir: &Dir) -> Result<HostCommand> {
+    let mut cmd = HostCommand::new("qemu-img");
+    cmd.as_std_mut()
+        .cwd_dir(dir.try_clone().context("Cloning directory fd")?);
+    Ok(cmd)
+}
+
+/// Run a qemu-img command, returning its stdout on success
+///
+/// `desc` is used in error messages, e.g. "qemu-img create for disk.qcow2".
+fn run(mut cmd: HostCommand, desc: &str) -> Result<Vec<u8>> {
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run {desc}"))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{desc} failed: {}", stderr.trim()));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Open the parent directory of `path` and return it along with the file name
+pub fn open_parent(path: &Utf8Path) -> Result<(Dir, &str)> {
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Path has no file name: {path}"))?;
+    let dir = Dir::open_ambient_dir(parent, cap_std::ambient_authority())
+        .with_context(|| format!("Failed to open directory {parent}"))?;
+    Ok((dir, name))
+}
+
+/// Run `qemu-img info --force-share --output=json` on a disk image
+///
+/// The `--force-share` flag allows reading disk info even when the image
+/// is locked by a running VM.
+pub fn info(path: &Utf8Path) -> Result<QemuImgInfo> {
+    let (dir, name) = open_parent(path)?;
+    info_at(&dir, name).with_context(|| format!("Querying {path}"))
+}
+
+/// Like [`info`], but for an image named `name` in `dir`
+pub fn info_at(dir: &Dir, name: &str) -> Result<QemuImgInfo> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["info", "--force-share", "--output=json", name]);
+    let stdout = run(cmd, &format!("qemu-img info for {name}"))?;
+
+    serde_json::from_slice(&stdout)
+        .with_context(|| format!("Failed to parse qemu-img info JSON for {name}"))
+}
+
+/// Create a new disk image `name` in `dir` with the given virtual size in bytes
+pub fn create(dir: &Dir, name: &str, format: ImageFormat, size: u64) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["create", "-f", format.as_str(), name, &size.to_string()]);
+    run(cmd, &format!("qemu-img create for {name}"))?;
+    Ok(())
+}
+
+/// Create a new qcow2 overlay `name` in `dir` backed by `backing`
+///
+/// The backing file is recorded relative to `dir`; if `size` is `None`
+/// the virtual size of the backing file is used.
+pub fn create_with_backing(
+    dir: &Dir,
+    name: &str,
+    backing: &str,
+    backing_format: ImageFormat,
+    size: Option<u64>,
+) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args([
+        "create",
+        "-f",
+        "qcow2",
+        "-b",
+        backing,
+        "-F",
+        backing_format.as_str(),
+        name,
+    ]);
+    if let Some(size) = size {
+        cmd.arg(size.to_string());
+    }
+    run(cmd, &format!("qemu-img create for {name}"))?;
+    Ok(())
+}
+
+/// Convert the image `src` into a new image `dest`, both in `dir`
+pub fn convert(dir: &Dir, src: &str, dest: &str, opts: &ConvertOpts) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(opts.to_args(src, dest)?);
+    run(cmd, &format!("qemu-img convert of {src} to {dest}"))?;
+    Ok(())
+}
+
+/// Resize the image `name` in `dir` to `size` bytes
+///
+/// Shrinking is refused unless `shrink` is set, since it discards data.
+pub fn resize(dir: &Dir, name: &str, size: u64, shrink: bool) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.arg("resize");
+    if shrink {
+        cmd.arg("--shrink");
+    }
+    cmd.args([name, &size.to_string()]);
+    run(cmd, &format!("qemu-img resize of {name}"))?;
+    Ok(())
+}
+
+/// List internal snapshots of the image `name` in `dir`
+pub fn snapshot_list(dir: &Dir, name: &str) -> Result<Vec<QemuImgSnapshot>> {
+    Ok(info_at(dir, name)?.snapshots)
+}
+
+/// Create an internal snapshot named `snapshot` in the image `name`
+pub fn snapshot_create(dir: &Dir, name: &str, snapshot: &str) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["snapshot", "-c", snapshot, name]);
+    run(cmd, &format!("qemu-img snapshot create of {name}"))?;
+    Ok(())
+}
+
+/// Revert the image `name` to the internal snapshot `snapshot`
+pub fn snapshot_apply(dir: &Dir, name: &str, snapshot: &str) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["snapshot", "-a", snapshot, name]);
+    run(cmd, &format!("qemu-img snapshot apply of {name}"))?;
+    Ok(())
+}
+
+/// Delete the internal snapshot `snapshot` from the image `name`
+pub fn snapshot_delete(dir: &Dir, name: &str, snapshot: &str) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["snapshot", "-d", snapshot, name]);
+    run(cmd, &format!("qemu-img snapshot delete of {name}"))?;
+    Ok(())
+}
+
+/// Change the backing file of the qcow2 image `name` to `backing`
+///
+/// With `unsafe_rebase` only the header is rewritten; this is correct only
+/// when the new backing file has identical content to the old one (e.g.
+/// it was moved or renamed).
+pub fn rebase(
+    dir: &Dir,
+    name: &str,
+    backing: &str,
+    backing_format: ImageFormat,
+    unsafe_rebase: bool,
+) -> Result<()> {
+    let mut cmd = qemu_img_in(dir)?;
+    cmd.args(["rebase", "-f", "qcow2"]);
+    if unsafe_rebase {
+        cmd.arg("-u");
+    }
+    cmd.args(["-b", backing, "-F", backing_format.as_str(), name]);
+    run(cmd, &format!("qemu-img rebase of {name}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_info_with_snapshots() {
+        let json = r#"{
+            "virtual-size": 21474836480,
+            "filename": "vm.qcow2",
+            "format": "qcow2",
+            "actual-size": 200704,
+            "cluster-size": 65536,
+            "backing-filename": "bootc-base-0123456789abcdef.qcow2",
+            "dirty-flag": false,
+            "snapshots": [
+                {
+                    "icount": 0,
+                    "vm-state-size": 0,
+                    "date-sec": 1700000000,
+                    "date-nsec": 0,
+                    "vm-clock-sec": 0,
+                    "vm-clock-nsec": 0,
+                    "name": "clean",
+                    "id": "1"
+                }
+            ]
+        }"#;
+        let info: QemuImgInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(info.virtual_size, 21474836480);
+        assert_eq!(
+            info.backing_filename.as_deref(),
+            Some("bootc-base-0123456789abcdef.qcow2")
+        );
+        assert_eq!(
+            info.snapshots,
+            vec![QemuImgSnapshot {
+                id: "1".into(),
+                name: "clean".into(),
+                vm_state_size: 0,
+                date_sec: 1700000000,
+            }]
+        );
+
+        // Raw images have no snapshots key at all
+        let json = r#"{"virtual-size": 1024, "filename": "a.raw", "format": "raw"}"#;
+        let info: QemuImgInfo = serde_json::from_str(json).unwrap();
+        assert!(info.snapshots.is_empty());
+    }
+
+    #[test]
+    fn test_convert_args() {
+        let cases: &[(ConvertOpts, &[&str])] = &[
+            (ConvertOpts::default(), &["convert", "a", "b"]),
+            (
+                ConvertOpts {
+                    format: Some(ImageFormat::Raw),
+                    ..Default::default()
+                },
+                &["convert", "-O", "raw", "a", "b"],
+            ),
+            (
+                ConvertOpts {
+                    format: Some(ImageFormat::Qcow2),
+                    compress: true,
+                    compression_type: None,
+                },
+                &["convert", "-O", "qcow2", "-c", "a", "b"],
+            ),
+            (
+                ConvertOpts {
+                    format: Some(ImageFormat::Qcow2),
+                    compress: true,
+                    compression_type: Some(CompressionType::Zstd),
+                },
+                &[
+                    "convert",
+                    "-O",
+                    "qcow2",
+                    "-c",
+                    "-o",
+                    "compression_type=zstd",
+                    "a",
+                    "b",
+                ],
+            ),
+        ];
+        for (opts, expected) in cases {
+            assert_eq!(opts.to_args("a", "b").unwrap(), *expected, "{opts:?}");
+        }
+
+        let opts = ConvertOpts {
+            format: Some(ImageFormat::Raw),
+            compress: true,
+            compression_type: None,
+        };
+        assert!(opts.to_args("a", "b").is_err());
+    }
+
+    #[test]
+    fn test_create_with_backing_command() -> Result<()> {
+        use crate::hostexec::{with_executor, RecordingExecutor};
+        use std::sync::Arc;
+
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let recorder = Arc::new(RecordingExecutor::default());
+        with_executor(recorder.clone(), || {
+            create_with_backing(&td, "vm.qcow2", "base.qcow2", ImageFormat::Qcow2, None)?;
+            create_with_backing(&td, "big.qcow2", "base.raw", ImageFormat::Raw, Some(4096))
+        })?;
+        assert_eq!(
+            recorder.commands(),
+            [
+                vec![
+                    "qemu-img",
+                    "create",
+                    "-f",
+                    "qcow2",
+                    "-b",
+                    "base.qcow2",
+                    "-F",
+                    "qcow2",
+                    "vm.qcow2",
+                ],
+                vec![
+                    "qemu-img",
+                    "create",
+                    "-f",
+                    "qcow2",
+                    "-b",
+                    "base.raw",
+                    "-F",
+                    "raw",
+                    "big.qcow2",
+                    "4096",
+                ],
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! Minimal client for the QEMU Machine Protocol (QMP)
+//!
+//! QEMU is started with a QMP unix socket (see [`crate::qemu::QemuConfig::enable_qmp`]),
+//! which lets bcvk control a running VM, e.g. to save its state to a file.
+//!
+//! The protocol is line-delimited JSON: after a greeting and capability
+//! negotiation, each command gets exactly one `return` or `error` reply,
+//! possibly interleaved with asynchronous events. Besides the generic
+//! [`QmpClient::execute`], typed helpers are provided for the commands
+//! bcvk uses.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use camino::Utf8Path;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::debug;
+
+/// How long to wait for a reply to a single command
+const QMP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to retry connecting while QEMU is starting up
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often to poll for migration progress
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Result of `query-status`
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct VmStatus {
+    /// Whether the guest CPUs are running
+    pub running: bool,
+    /// QEMU run state, e.g. `running`, `paused`, `shutdown` or `postmigrate`
+    pub status: String,
+}
+
+/// The `qemu` part of the result of `query-version`
+#[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct QemuVersion {
+    /// Major version
+    pub major: u32,
+    /// Minor version
+    pub minor: u32,
+    /// Micro (patch) version
+    pub micro: u32,
+}
+
+impl std::fmt::Display for QemuVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+/// A connection to a QMP socket
+#[derive(Debug)]
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl QmpClient {
+    /// Connect to the QMP socket at `path` and negotiate capabilities
+    pub fn connect(path: &Utf8Path) -> Result<Self> {
+        let stream =
+            UnixStream::connect(path).with_context(|| format!("Connecting to QMP at {path}"))?;
+        Self::handshake(stream)
+    }
+
+    fn handshake(stream: UnixStream) -> Result<Self> {
+        stream.set_read_timeout(Some(QMP_TIMEOUT))?;
+        let writer = stream.try_clone()?;
+        let mut client = Self {
+            reader: BufReader::new(stream),
+            writer,
+        };
+
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(eyre!("Unexpected QMP greeting: {greeting}"));
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    /// Like [`Self::connect`], but wait up to `timeout` for the socket to
+    /// appear, as QEMU creates it asynchronously at startup.
+    pub fn connect_with_timeout(path: &Utf8Path, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => return Self::handshake(stream),
+                Err(e)
+                    if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused)
+                        && Instant::now() < deadline =>
+                {
+                    std::thread::sleep(CONNECT_RETRY_INTERVAL);
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Connecting to QMP at {path}"));
+                }
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        let n = self
+            .reader
+            .read_line(&mut line)
+            .context("Reading from QMP")?;
+        if n == 0 {
+            return Err(eyre!("QMP connection closed"));
+        }
+        serde_json::from_str(&line).with_context(|| format!("Parsing QMP message: {line}"))
+    }
+
+    /// Execute a command, returning the contents of its `return` reply
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .with_context(|| format!("Sending QMP command {command}"))?;
+
+        loop {
+            let msg = self.read_message()?;
+            if let Some(r) = parse_reply(&msg) {
+                return r.with_context(|| format!("QMP command {command}"));
+            }
+        }
+    }
+
+    /// Execute a command, deserializing its reply
+    pub fn execute_as<T: DeserializeOwned>(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<T> {
+        let ret = self.execute(command, arguments)?;
+        serde_json::from_value(ret).with_context(|| format!("Parsing reply to {command}"))
+    }
+
+    /// Query the run state of the VM
+    pub fn query_status(&mut self) -> Result<VmStatus> {
+        self.execute_as("query-status", None)
+    }
+
+    /// Query the version of QEMU
+    pub fn query_version(&mut self) -> Result<QemuVersion> {
+        #[derive(Deserialize)]
+        struct Reply {
+            qemu: QemuVersion,
+        }
+        Ok(self.execute_as::<Reply>("query-version", None)?.qemu)
+    }
+
+    /// Request an ACPI shutdown of the guest. This returns immediately; the
+    /// guest may take a while to shut down, or ignore the request.
+    pub fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None).map(drop)
+    }
+
+    /// Wait up to `timeout` for the asynchronous event `name`
+    pub fn wait_for_event(&mut self, name: &str, timeout: Duration) -> Result<Value> {
+        let deadline = Instant::now() + timeout;
+        let r = loop {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break Err(eyre!("Timed out waiting for QMP event {name}"));
+            };
+            self.reader
+                .get_ref()
+                .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+            match self.read_message() {
+                Ok(msg) if msg.get("event").and_then(Value::as_str) == Some(name) => {
+                    break Ok(msg);
+                }
+                Ok(msg) => debug!("Ignoring QMP message: {msg}"),
+                Err(e) if is_timeout(&e) => {
+                    break Err(eyre!("Timed out waiting for QMP event {name}"));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.reader.get_ref().set_read_timeout(Some(QMP_TIMEOUT))?;
+        r
+    }
+
+    /// Ask the guest to shut down, and wait up to `timeout` for it to do so
+    pub fn powerdown_and_wait(&mut self, timeout: Duration) -> Result<()> {
+        self.system_powerdown()?;
+        self.wait_for_event("SHUTDOWN", timeout)
+            .context("Waiting for guest to shut down")?;
+        Ok(())
+    }
+
+    /// Save the complete VM state (devices and memory) to `path`.
+    ///
+    /// The VM is left paused; use [`Self::cont`] to resume it.
+    pub fn save_state(&mut self, path: &Utf8Path) -> Result<()> {
+        self.execute("migrate", Some(json!({ "uri": format!("file:{path}") })))?;
+        loop {
+            let info = self.execute("query-migrate", None)?;
+            match info.get("status").and_then(Value::as_str) {
+                Some("completed") => return Ok(()),
+                Some(status @ ("failed" | "cancelled")) => {
+                    let desc = info
+                        .get("error-desc")
+                        .and_then(Value::as_str)
+                        .unwrap_or("no details");
+                    return Err(eyre!("Saving VM state {status}: {desc}"));
+                }
+                status => debug!("Migration status: {status:?}"),
+            }
+            std::thread::sleep(MIGRATION_POLL_INTERVAL);
+        }
+    }
+
+    /// Set the memory balloon so that the guest has `target_mb` of RAM. The
+    /// guest adjusts asynchronously.
+    pub fn balloon(&mut self, target_mb: u32) -> Result<()> {
+        let value = u64::from(target_mb) * 1024 * 1024;
+        self.execute("balloon", Some(json!({ "value": value })))
+            .map(drop)
+    }
+
+    /// Resume a paused VM
+    pub fn cont(&mut self) -> Result<()> {
+        self.execute("cont", None).map(drop)
+    }
+
+    /// Terminate QEMU immediately
+    pub fn quit(&mut self) -> Result<()> {
+        self.execute("quit", None).map(drop)
+    }
+}
+
+/// Whether reading failed because the socket read timeout expired
+fn is_timeout(e: &color_eyre::Report) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// Interpret a message received from QMP: `Some` for a command reply,
+/// `None` for anything else (i.e. asynchronous events).
+fn parse_reply(msg: &Value) -> Option<Result<Value>> {
+    if let Some(ret) = msg.get("return") {
+        return Some(Ok(ret.clone()));
+    }
+    if let Some(err) = msg.get("error") {
+        let class = err.get("class").and_then(Value::as_str).unwrap_or("Error");
+        let desc = err.get("desc").and_then(Value::as_str).unwrap_or_default();
+        return Some(Err(eyre!("{class}: {desc}")));
+    }
+    if let Some(event) = msg.get("event") {
+        debug!("QMP event: {event}");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        let cases = [
+            (r#"{"return": {}}"#, Some(Ok(json!({})))),
+            (
+                r#"{"return": {"status": "running", "running": true}}"#,
+                Some(Ok(json!({"status": "running", "running": true}))),
+            ),
+            (
+                r#"{"error": {"class": "GenericError", "desc": "migration blocked"}}"#,
+                Some(Err("GenericError: migration blocked")),
+            ),
+            (
+                r#"{"event": "STOP", "timestamp": {"seconds": 1, "microseconds": 2}}"#,
+                None,
+            ),
+        ];
+        for (msg, expected) in cases {
+            let msg: Value = serde_json::from_str(msg).unwrap();
+            let r = parse_reply(&msg).map(|r| r.map_err(|e| e.to_string()));
+            let expected = expected.map(|r| r.map_err(ToOwned::to_owned));
+            assert_eq!(r, expected, "{msg}");
+        }
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let status: VmStatus = serde_json::from_value(
+            json!({"status": "paused", "singlestep": false, "running": false}),
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            VmStatus {
+                running: false,
+                status: "paused".into()
+            }
+        );
+    }
+}
@@ -121,6 +121,16 @@ pub struct XmlNode {
 }
 
 impl XmlNode {
+    /// Create an element without attributes, text or children
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            attributes: HashMap::new(),
+            text: String::new(),
+            children: Vec::new(),
+        }
+    }
+
     /// Find first element by name (recursive search)
     pub fn find(&self, element_name: &str) -> Option<&XmlNode> {
         if self.name == element_name {
@@ -136,6 +146,16 @@ impl XmlNode {
         None
     }
 
+    /// Find first element by name (recursive search), for modifying it
+    pub fn find_mut(&mut self, element_name: &str) -> Option<&mut XmlNode> {
+        if self.name == element_name {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(element_name))
+    }
+
     /// Find first element by name with namespace fallback
     pub fn find_with_namespace(&self, element_name: &str) -> Option<&XmlNode> {
         // Try namespaced version first
@@ -150,6 +170,35 @@ impl XmlNode {
     pub fn text_content(&self) -> &str {
         &self.text
     }
+
+    /// Serialize this element and its children
+    ///
+    /// As [`parse_xml_dom`] keeps neither comments nor the order of text
+    /// and child elements, the text of an element is written before its
+    /// children. Attributes are written sorted by name.
+    pub fn to_xml(&self) -> Result<String> {
+        let mut writer = XmlWriter::new();
+        self.write(&mut writer)?;
+        writer.into_string()
+    }
+
+    fn write(&self, writer: &mut XmlWriter) -> Result<()> {
+        let mut attributes: Vec<(&str, &str)> = self
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        attributes.sort_unstable();
+        if self.text.is_empty() && self.children.is_empty() {
+            return writer.write_empty_element(&self.name, &attributes);
+        }
+        writer.start_element(&self.name, &attributes)?;
+        writer.write_text(&self.text)?;
+        for child in &self.children {
+            child.write(writer)?;
+        }
+        writer.end_element(&self.name)
+    }
 }
 
 /// Parse XML string into a simple DOM structure
@@ -368,6 +417,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dom_round_trip() {
+        let xml = r#"<domain type="kvm"><name>vm &amp; co</name><metadata><bootc:container xmlns:bootc="https://github.com/containers/bootc"><bootc:ssh-port>2222</bootc:ssh-port></bootc:container></metadata><devices><disk device="disk" type="file"><source file="/a.qcow2"/></disk></devices></domain>"#;
+        let mut dom = parse_xml_dom(xml).unwrap();
+        assert_eq!(dom.to_xml().unwrap(), xml);
+
+        dom.find_mut("source")
+            .unwrap()
+            .attributes
+            .insert("file".into(), "/b.qcow2".into());
+        dom.find_mut("bootc:ssh-port").unwrap().text = "2223".into();
+        let edited = parse_xml_dom(&dom.to_xml().unwrap()).unwrap();
+        assert_eq!(
+            edited.find("source").unwrap().attributes["file"],
+            "/b.qcow2"
+        );
+        assert_eq!(
+            edited.find("bootc:ssh-port").unwrap().text_content(),
+            "2223"
+        );
+        assert_eq!(edited.find("name").unwrap().text_content(), "vm & co");
+    }
+
     #[test]
     fn test_xml_writer_nested_elements() {
         let mut writer = XmlWriter::new();
@@ -1,125 +1,350 @@
 //! Cleanup utility for integration test resources
 //!
-//! This binary removes integration test containers and libvirt VMs that were created during testing.
+//! This binary removes resources left behind by integration tests:
+//!
+//! - containers with [`INTEGRATION_TEST_LABEL`]
+//! - libvirt VMs with [`LIBVIRT_INTEGRATION_TEST_LABEL`], including their
+//!   storage and NVRAM
+//! - temporary domain XML files of bcvk for such VMs
+//!
+//! Storage volumes and NVRAM files of test VMs (named with
+//! [`LIBVIRT_INTEGRATION_TEST_PREFIX`]) that no longer exist are reported,
+//! but not removed: without the VM, nothing tells whether they belong to the
+//! integration tests.
+//!
+//! Everything found is printed as a leak report. Since tests are expected to
+//! clean up after themselves, the exit code is 1 if anything was found, which
+//! makes this usable as a CI post-job step. With `--pre-run`, to clean up
+//! before tests, the exit code is 1 only if something could not be removed.
 
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 // Import shared constants from the library
-use integration_tests::{INTEGRATION_TEST_LABEL, LIBVIRT_INTEGRATION_TEST_LABEL};
+use bcvk_core::domain_metadata::DomainMetadata;
+use integration_tests::{
+    INTEGRATION_TEST_LABEL, LIBVIRT_INTEGRATION_TEST_LABEL, LIBVIRT_INTEGRATION_TEST_PREFIX,
+};
 
-fn cleanup_integration_test_containers() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Cleaning up integration test containers...");
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-    // List all containers with our integration test label
-    let list_output = Command::new("podman")
-        .args([
-            "ps",
-            "-a",
-            "--filter",
-            &format!("label={}", INTEGRATION_TEST_LABEL),
-            "-q",
-        ])
-        .output()?;
+/// The libvirt storage pool bcvk creates VM disks in
+const STORAGE_POOL: &str = "default";
 
-    if !list_output.status.success() {
-        eprintln!("Warning: Failed to list containers");
-        return Ok(());
+/// Prefix of the temporary domain XML files written by bcvk
+const TEMP_XML_PREFIX: &str = "bcvk-libvirt";
+
+/// What became of a leaked resource
+#[derive(Debug)]
+enum Outcome {
+    Removed,
+    /// Removing the resource failed with this error
+    Failed(String),
+    /// Not removed, as it isn't known to belong to the integration tests
+    Kept,
+}
+
+/// A leaked resource
+#[derive(Debug)]
+struct Leak {
+    kind: &'static str,
+    name: String,
+    outcome: Outcome,
+}
+
+/// All leaked resources found
+#[derive(Debug, Default)]
+struct Report {
+    leaks: Vec<Leak>,
+}
+
+impl Report {
+    /// Record a leaked resource and the result of removing it
+    fn record(&mut self, kind: &'static str, name: &str, removed: Result<()>) {
+        let outcome = match removed {
+            Ok(()) => Outcome::Removed,
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        self.leaks.push(Leak {
+            kind,
+            name: name.to_owned(),
+            outcome,
+        });
     }
 
-    let container_ids = String::from_utf8_lossy(&list_output.stdout);
-    let containers: Vec<&str> = container_ids.lines().filter(|l| !l.is_empty()).collect();
+    /// Record a leaked resource which is left alone
+    fn record_kept(&mut self, kind: &'static str, name: &str) {
+        self.leaks.push(Leak {
+            kind,
+            name: name.to_owned(),
+            outcome: Outcome::Kept,
+        });
+    }
 
-    if containers.is_empty() {
-        println!("No integration test containers found to clean up");
-        return Ok(());
+    /// Whether removing any resource failed
+    fn has_failures(&self) -> bool {
+        self.leaks
+            .iter()
+            .any(|l| matches!(l.outcome, Outcome::Failed(_)))
     }
 
-    println!(
-        "Found {} integration test container(s) to clean up",
-        containers.len()
-    );
-
-    // Force remove each container
-    let mut cleaned = 0;
-    for container_id in containers {
-        print!(
-            "  Removing container {}... ",
-            &container_id[..12.min(container_id.len())]
-        );
-        let rm_output = Command::new("podman")
-            .args(["rm", "-f", container_id])
-            .output()?;
-
-        if rm_output.status.success() {
-            println!("✓");
-            cleaned += 1;
-        } else {
-            println!("✗ (failed)");
-            eprintln!("    Error: {}", String::from_utf8_lossy(&rm_output.stderr));
+    fn print(&self) {
+        if self.leaks.is_empty() {
+            println!("No leaked integration test resources found");
+            return;
+        }
+        println!("Leaked integration test resources:");
+        for leak in &self.leaks {
+            match &leak.outcome {
+                Outcome::Removed => println!("  {} {} (removed)", leak.kind, leak.name),
+                Outcome::Failed(e) => {
+                    println!("  {} {} (FAILED to remove: {})", leak.kind, leak.name, e)
+                }
+                Outcome::Kept => {
+                    println!("  {} {} (unknown owner, not removed)", leak.kind, leak.name)
+                }
+            }
         }
     }
+}
 
-    println!("Cleanup completed: {} container(s) removed", cleaned);
+/// Run a command, returning its stdout, or its stderr as error
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{program} {}: {}", args.join(" "), stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn non_empty_lines(s: &str) -> impl Iterator<Item = &str> {
+    s.lines().map(str::trim).filter(|l| !l.is_empty())
+}
+
+/// Whether the bcvk metadata in domain XML carries the integration test label
+fn has_test_label(domain_xml: &str) -> Result<bool> {
+    let metadata = DomainMetadata::from_xml(domain_xml).map_err(|e| e.to_string())?;
+    Ok(metadata.is_some_and(|m| m.labels.iter().any(|l| l == LIBVIRT_INTEGRATION_TEST_LABEL)))
+}
+
+/// Whether a volume or NVRAM file named `name` may belong to a test VM that
+/// no longer exists
+///
+/// The files of a VM are named after it, e.g. `<vm>.qcow2` or
+/// `<vm>_VARS.fd`.
+fn is_stray(name: &str, domains: &[String]) -> bool {
+    name.starts_with(LIBVIRT_INTEGRATION_TEST_PREFIX)
+        && !domains.iter().any(|d| {
+            name.strip_prefix(d.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '-', '_']))
+        })
+}
+
+fn cleanup_containers(report: &mut Report) -> Result<()> {
+    let label_filter = format!("label={INTEGRATION_TEST_LABEL}");
+    let names = run(
+        "podman",
+        &[
+            "ps",
+            "-a",
+            "--filter",
+            &label_filter,
+            "--format",
+            "{{.Names}}",
+        ],
+    )?;
+    for name in non_empty_lines(&names) {
+        let removed = run("podman", &["rm", "-f", name]).map(drop);
+        report.record("container", name, removed);
+    }
     Ok(())
 }
 
-fn cleanup_libvirt_integration_test_vms() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Cleaning up integration test libvirt VMs...");
+fn list_domains() -> Result<Vec<String>> {
+    let names = run("virsh", &["list", "--all", "--name"])?;
+    Ok(non_empty_lines(&names).map(ToOwned::to_owned).collect())
+}
+
+fn remove_domain(name: &str) -> Result<()> {
+    // Transient domains are gone once destroyed; this fails for stopped ones
+    if let Err(e) = run("virsh", &["destroy", name]) {
+        eprintln!("Not destroying {name}: {e}");
+    }
+    if run("virsh", &["domstate", name]).is_ok() {
+        run(
+            "virsh",
+            &["undefine", name, "--nvram", "--remove-all-storage"],
+        )?;
+    }
+    Ok(())
+}
 
-    // Get path to bcvk binary (should be in the same directory as this cleanup binary)
-    let current_exe = std::env::current_exe()?;
-    let bcvk_path = current_exe
-        .parent()
-        .ok_or("Failed to get parent directory")?
-        .join("bcvk");
+/// Remove labeled domains, returning the names of the remaining domains
+fn cleanup_domains(report: &mut Report) -> Result<Vec<String>> {
+    for name in list_domains()? {
+        let Ok(xml) = run("virsh", &["dumpxml", &name]) else {
+            // Removed concurrently
+            continue;
+        };
+        match has_test_label(&xml) {
+            Ok(true) => report.record("domain", &name, remove_domain(&name)),
+            Ok(false) => {}
+            Err(e) => eprintln!("Skipping domain {name} with invalid metadata: {e}"),
+        }
+    }
+    list_domains()
+}
 
-    if !bcvk_path.exists() {
-        println!(
-            "bcvk binary not found at {:?}, skipping libvirt cleanup",
-            bcvk_path
-        );
+fn cleanup_volumes(report: &mut Report, domains: &[String]) -> Result<()> {
+    // Without the pool there are no volumes to leak
+    let Ok(volumes) = run("virsh", &["vol-list", STORAGE_POOL]) else {
         return Ok(());
+    };
+    // Skip the header and separator lines
+    for line in non_empty_lines(&volumes).skip(2) {
+        let Some(name) = line.split_whitespace().next() else {
+            continue;
+        };
+        if is_stray(name, domains) {
+            report.record_kept("volume", name);
+        }
     }
+    Ok(())
+}
 
-    // Use bcvk libvirt rm-all with label filter
-    let rm_output = Command::new(&bcvk_path)
-        .args([
-            "libvirt",
-            "rm-all",
-            "--label",
-            LIBVIRT_INTEGRATION_TEST_LABEL,
-            "--force",
-            "--stop",
-        ])
-        .output()?;
-
-    if !rm_output.status.success() {
-        let stderr = String::from_utf8_lossy(&rm_output.stderr);
-        eprintln!("Warning: Failed to clean up libvirt VMs: {}", stderr);
-        return Ok(());
+/// Directories libvirt keeps NVRAM files in, for the system and session instances
+fn nvram_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/var/lib/libvirt/qemu/nvram")];
+    if let Some(config) = dirs::config_dir() {
+        dirs.push(config.join("libvirt/qemu/nvram"));
+    }
+    dirs
+}
+
+/// The files in `dir` whose name `matches`
+fn find_files(dir: &Path, matches: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Reading {}: {e}", dir.display()).into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(&matches) {
+            files.push(entry.path());
+        }
     }
+    Ok(files)
+}
 
-    let stdout = String::from_utf8_lossy(&rm_output.stdout);
-    println!("{}", stdout);
+fn cleanup_nvram(report: &mut Report, domains: &[String]) -> Result<()> {
+    for dir in nvram_dirs() {
+        for path in find_files(&dir, |name| is_stray(name, domains))? {
+            report.record_kept("nvram", &path.display().to_string());
+        }
+    }
+    Ok(())
+}
 
+/// Remove the temporary domain XML files of labeled domains
+fn cleanup_temp_xml(report: &mut Report) -> Result<()> {
+    let files = find_files(&std::env::temp_dir(), |name| {
+        name.starts_with(TEMP_XML_PREFIX)
+    })?;
+    for path in files {
+        // Other files with the prefix, e.g. SSH keys, are not domain XML
+        let is_test_domain = std::fs::read_to_string(&path)
+            .map_err(Into::into)
+            .and_then(|xml| has_test_label(&xml));
+        if let Ok(true) = is_test_domain {
+            let removed = std::fs::remove_file(&path).map_err(Into::into);
+            report.record("temp-xml", &path.display().to_string(), removed);
+        }
+    }
     Ok(())
 }
 
 fn main() {
+    let pre_run = match std::env::args().nth(1).as_deref() {
+        None => false,
+        Some("--pre-run") => true,
+        Some(arg) => {
+            eprintln!("Unknown argument {arg}; usage: test-cleanup [--pre-run]");
+            std::process::exit(2);
+        }
+    };
+    let mut report = Report::default();
     let mut errors = Vec::new();
 
-    if let Err(e) = cleanup_integration_test_containers() {
-        eprintln!("Error during container cleanup: {}", e);
+    println!("Cleaning up integration test resources...");
+
+    if let Err(e) = cleanup_containers(&mut report) {
         errors.push(format!("containers: {}", e));
     }
 
-    if let Err(e) = cleanup_libvirt_integration_test_vms() {
-        eprintln!("Error during libvirt VM cleanup: {}", e);
-        errors.push(format!("libvirt: {}", e));
+    match cleanup_domains(&mut report) {
+        Ok(domains) => {
+            if let Err(e) = cleanup_volumes(&mut report, &domains) {
+                errors.push(format!("volumes: {}", e));
+            }
+            if let Err(e) = cleanup_nvram(&mut report, &domains) {
+                errors.push(format!("nvram: {}", e));
+            }
+        }
+        Err(e) => errors.push(format!("libvirt: {}", e)),
+    }
+
+    if let Err(e) = cleanup_temp_xml(&mut report) {
+        errors.push(format!("temp files: {}", e));
     }
 
+    report.print();
+
     if !errors.is_empty() {
         eprintln!("Cleanup completed with errors: {}", errors.join(", "));
+    }
+    let leaked = if pre_run {
+        report.has_failures()
+    } else {
+        !report.leaks.is_empty()
+    };
+    if !errors.is_empty() || leaked {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_test_label() {
+        let xml = |labels: &str| {
+            format!(
+                "<domain><metadata><bootc:container><bootc:label>{labels}</bootc:label></bootc:container></metadata></domain>"
+            )
+        };
+        assert!(has_test_label(&xml("bcvk-integration")).unwrap());
+        assert!(has_test_label(&xml("test-workflow,bcvk-integration")).unwrap());
+        assert!(!has_test_label(&xml("bcvk-integration-other")).unwrap());
+        assert!(!has_test_label("<domain><name>test</name></domain>").unwrap());
+        // A label element outside the bcvk metadata
+        assert!(!has_test_label(
+            "<domain><seclabel><label>bcvk-integration</label></seclabel></domain>"
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn test_is_stray() {
+        let domains = vec!["test-rm-abc".to_string(), "my-vm".to_string()];
+        assert!(is_stray("test-rm-xyz.qcow2", &domains));
+        assert!(is_stray("test-transient-xyz_VARS.fd", &domains));
+        assert!(!is_stray("test-rm-abc.qcow2", &domains));
+        assert!(!is_stray("test-rm-abc_VARS.fd", &domains));
+        assert!(!is_stray("my-vm.qcow2", &domains));
+        assert!(!is_stray("bootc-base-1234.qcow2", &domains));
+    }
+}
@@ -0,0 +1,63 @@
+//! Fixtures shared by integration tests
+//!
+//! See [`integration_tests::fixture!`]; tests take these as arguments with
+//! `integration_test!(name, fixture = ...)`.
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use integration_tests::{fixture, Fixture};
+
+use crate::{get_test_image, run_bcvk, run_command, CapturedOutput, INTEGRATION_TEST_LABEL};
+
+/// A detached ephemeral VM of the primary test image, with SSH access
+///
+/// Tests must not change the state of the VM in ways affecting other tests,
+/// e.g. by stopping it.
+#[derive(Debug)]
+pub(crate) struct SharedVm {
+    /// Name of the container running the VM
+    pub name: String,
+}
+
+impl SharedVm {
+    /// Run a command in the VM via `bcvk ephemeral ssh`, capturing output
+    pub fn ssh(&self, args: &[&str]) -> Result<CapturedOutput> {
+        let mut bcvk_args = vec!["ephemeral", "ssh", self.name.as_str()];
+        bcvk_args.extend_from_slice(args);
+        Ok(run_bcvk(&bcvk_args)?)
+    }
+}
+
+impl Fixture for SharedVm {
+    fn setup() -> Result<Self> {
+        let name = format!("shared-vm-{}", uuid::Uuid::new_v4().simple());
+        let output = run_bcvk(&[
+            "ephemeral",
+            "run",
+            "--ssh-keygen",
+            "--label",
+            INTEGRATION_TEST_LABEL,
+            "--detach",
+            "--name",
+            &name,
+            &get_test_image(),
+        ])?;
+        if !output.success() {
+            return Err(eyre!("Failed to start shared VM: {}", output.stderr));
+        }
+        Ok(Self { name })
+    }
+
+    fn teardown(&self) -> Result<()> {
+        let output = run_command("podman", &["rm", "-f", &self.name])?;
+        if !output.success() {
+            return Err(eyre!(
+                "Failed to remove shared VM {}: {}",
+                self.name,
+                output.stderr
+            ));
+        }
+        Ok(())
+    }
+}
+fixture!(pub(crate) shared_vm: SharedVm);
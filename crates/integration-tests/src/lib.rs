@@ -6,12 +6,20 @@
 // Unfortunately needed here to work with linkme
 #![allow(unsafe_code)]
 
+pub mod report;
+
 /// Label used to identify containers created by integration tests
 pub const INTEGRATION_TEST_LABEL: &str = "bcvk.integration-test=1";
 
 /// Label used to identify libvirt VMs created by integration tests
 pub const LIBVIRT_INTEGRATION_TEST_LABEL: &str = "bcvk-integration";
 
+/// Prefix of the names of libvirt VMs created by integration tests
+///
+/// Storage volumes and NVRAM files named after such VMs are reported as leaks
+/// by the cleanup helper once the VM is gone.
+pub const LIBVIRT_INTEGRATION_TEST_PREFIX: &str = "test-";
+
 /// A test function that returns a Result
 pub type TestFn = fn() -> color_eyre::Result<()>;
 
@@ -34,6 +42,37 @@ impl IntegrationTest {
     }
 }
 
+/// A condition under which a parameterized test does not apply to an image,
+/// e.g. because it relies on behavior of another distribution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSkip {
+    /// The image reference contains the string
+    Contains(&'static str),
+}
+
+impl ImageSkip {
+    /// Whether the test is skipped for `image`
+    pub fn matches(&self, image: &str) -> bool {
+        match self {
+            ImageSkip::Contains(pattern) => image.contains(pattern),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageSkip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSkip::Contains(pattern) => write!(f, "image contains \"{pattern}\""),
+        }
+    }
+}
+
+/// Skip a parameterized test for images whose reference contains `pattern`,
+/// for use with [`parameterized_integration_test!`]
+pub const fn skip_if_image_contains(pattern: &'static str) -> ImageSkip {
+    ImageSkip::Contains(pattern)
+}
+
 /// Metadata for a parameterized integration test that runs once per image
 #[derive(Debug)]
 pub struct ParameterizedIntegrationTest {
@@ -41,12 +80,28 @@ pub struct ParameterizedIntegrationTest {
     pub name: &'static str,
     /// Parameterized test function to execute
     pub f: ParameterizedTestFn,
+    /// Images the test does not apply to
+    pub skips: &'static [ImageSkip],
 }
 
 impl ParameterizedIntegrationTest {
     /// Create a new parameterized integration test with the given name and function
     pub const fn new(name: &'static str, f: ParameterizedTestFn) -> Self {
-        Self { name, f }
+        Self {
+            name,
+            f,
+            skips: &[],
+        }
+    }
+
+    /// Skip the test for the images matching any of `skips`
+    pub const fn with_skips(self, skips: &'static [ImageSkip]) -> Self {
+        Self { skips, ..self }
+    }
+
+    /// Why the test is skipped for `image`, if it is
+    pub fn skip_reason(&self, image: &str) -> Option<ImageSkip> {
+        self.skips.iter().copied().find(|s| s.matches(image))
     }
 }
 
@@ -72,6 +127,19 @@ pub static PARAMETERIZED_INTEGRATION_TESTS: [ParameterizedIntegrationTest];
 /// }
 /// integration_test!(test_basic_functionality);
 /// ```
+///
+/// Tests needing resources shared with other tests, like a running VM, take
+/// them as arguments; the fixtures (see [`fixture!`]) are set up on first use
+/// and torn down after all tests ran:
+///
+/// ```ignore
+/// fn test_ssh_echo(vm: &SharedVm) -> Result<()> {
+///     let output = vm.ssh(&["echo", "hello"])?;
+///     output.assert_success("ssh");
+///     Ok(())
+/// }
+/// integration_test!(test_ssh_echo, fixture = shared_vm);
+/// ```
 #[macro_export]
 macro_rules! integration_test {
     ($fn_name:ident) => {
@@ -81,6 +149,13 @@ macro_rules! integration_test {
                 $crate::IntegrationTest::new(stringify!($fn_name), $fn_name);
         }
     };
+    ($fn_name:ident, $(fixture = $fixture:ident),+ $(,)?) => {
+        ::paste::paste! {
+            #[::linkme::distributed_slice($crate::INTEGRATION_TESTS)]
+            static [<$fn_name:upper>]: $crate::IntegrationTest =
+                $crate::IntegrationTest::new(stringify!($fn_name), || $fn_name($($fixture()?),+));
+        }
+    };
 }
 
 /// Register a parameterized integration test with less boilerplate.
@@ -97,6 +172,13 @@ macro_rules! integration_test {
 /// }
 /// parameterized_integration_test!(test_with_image);
 /// ```
+///
+/// Images the test does not apply to are given after the function; the test
+/// is reported as ignored for them:
+///
+/// ```ignore
+/// parameterized_integration_test!(test_dnf_install, skip_if_image_contains("centos"));
+/// ```
 #[macro_export]
 macro_rules! parameterized_integration_test {
     ($fn_name:ident) => {
@@ -106,6 +188,130 @@ macro_rules! parameterized_integration_test {
                 $crate::ParameterizedIntegrationTest::new(stringify!($fn_name), $fn_name);
         }
     };
+    ($fn_name:ident, $($skip:expr),+ $(,)?) => {
+        ::paste::paste! {
+            #[::linkme::distributed_slice($crate::PARAMETERIZED_INTEGRATION_TESTS)]
+            static [<$fn_name:upper>]: $crate::ParameterizedIntegrationTest =
+                $crate::ParameterizedIntegrationTest::new(stringify!($fn_name), $fn_name)
+                    .with_skips(&[$($skip),+]);
+        }
+    };
+}
+
+/// A resource shared by the tests using it, e.g. a running VM
+///
+/// Declare fixtures with [`fixture!`].
+pub trait Fixture: Sized + Send + Sync + 'static {
+    /// Set up the fixture; called by the first test using it
+    fn setup() -> color_eyre::Result<Self>;
+
+    /// Tear down the fixture after all tests ran
+    fn teardown(&self) -> color_eyre::Result<()>;
+}
+
+/// A fixture which is set up at most once per process
+///
+/// A setup failure is remembered, failing all tests using the fixture.
+#[derive(Debug)]
+pub struct FixtureCell<T> {
+    name: &'static str,
+    value: std::sync::OnceLock<Result<T, String>>,
+}
+
+impl<T: Fixture> FixtureCell<T> {
+    /// Create a fixture named `name` which has not been set up
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Get the fixture, setting it up on first use
+    pub fn get(&self) -> color_eyre::Result<&T> {
+        self.value
+            .get_or_init(|| T::setup().map_err(|e| format!("{e:?}")))
+            .as_ref()
+            .map_err(|e| color_eyre::eyre::eyre!("Setting up fixture {}: {e}", self.name))
+    }
+}
+
+/// A fixture declared with [`fixture!`], as seen by the test runner
+pub trait RegisteredFixture: Sync {
+    /// Name of the fixture
+    fn name(&self) -> &'static str;
+
+    /// Tear down the fixture if it was set up
+    fn teardown(&self) -> color_eyre::Result<()>;
+}
+
+impl<T: Fixture> RegisteredFixture for FixtureCell<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn teardown(&self) -> color_eyre::Result<()> {
+        match self.value.get() {
+            Some(Ok(value)) => value.teardown(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Distributed slice holding all declared fixtures
+#[linkme::distributed_slice]
+pub static FIXTURES: [&'static dyn RegisteredFixture];
+
+/// Tear down the fixtures which were set up, printing errors
+///
+/// Returns whether all of them were torn down.
+pub fn teardown_fixtures() -> bool {
+    let mut ok = true;
+    for fixture in FIXTURES.iter() {
+        if let Err(e) = fixture.teardown() {
+            eprintln!("error: tearing down fixture {}: {e:?}", fixture.name());
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Declare a fixture, for use with `integration_test!(name, fixture = ...)`
+///
+/// This generates a function with the name of the fixture returning it,
+/// setting it up on first use, and registers it to be torn down after all
+/// tests ran.
+///
+/// # Examples
+///
+/// ```ignore
+/// struct SharedVm { name: String }
+///
+/// impl Fixture for SharedVm {
+///     fn setup() -> Result<Self> { ... }
+///     fn teardown(&self) -> Result<()> { ... }
+/// }
+/// fixture!(pub(crate) shared_vm: SharedVm);
+/// ```
+#[macro_export]
+macro_rules! fixture {
+    ($vis:vis $name:ident: $ty:ty) => {
+        ::paste::paste! {
+            static [<$name:upper>]: $crate::FixtureCell<$ty> =
+                $crate::FixtureCell::new(stringify!($name));
+
+            #[::linkme::distributed_slice($crate::FIXTURES)]
+            static [<$name:upper _REGISTRATION>]: &'static dyn $crate::RegisteredFixture =
+                &[<$name:upper>];
+
+            #[doc = concat!(
+                "Get the `", stringify!($name), "` fixture, setting it up on first use"
+            )]
+            $vis fn $name() -> ::color_eyre::Result<&'static $ty> {
+                [<$name:upper>].get()
+            }
+        }
+    };
 }
 
 /// Create a test suffix from an image name by replacing invalid characters with underscores
@@ -121,10 +327,167 @@ pub fn image_to_test_suffix(image: &str) -> String {
     image.replace(|c: char| !c.is_alphanumeric(), "_")
 }
 
+/// Parse a comma-separated list of container images, as in `BCVK_TEST_IMAGES`
+///
+/// Whitespace around entries and empty entries are ignored.
+pub fn parse_image_list(images: &str) -> Vec<String> {
+    images
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Prefix of `--filter` values selecting images for parameterized tests
+const IMAGE_FILTER_PREFIX: &str = "image=";
+
+/// Split `--filter image=PATTERN` (or `--filter=image=PATTERN`) out of the
+/// test harness arguments
+///
+/// Returns the remaining arguments, to be parsed by libtest-mimic, and the
+/// patterns.
+pub fn extract_image_filters(
+    args: impl IntoIterator<Item = String>,
+) -> color_eyre::Result<(Vec<String>, Vec<String>)> {
+    let mut args = args.into_iter();
+    let mut remaining = Vec::new();
+    let mut patterns = Vec::new();
+    while let Some(arg) = args.next() {
+        let value = if arg == "--filter" {
+            args.next()
+                .ok_or_else(|| color_eyre::eyre::eyre!("--filter requires a value"))?
+        } else if let Some(value) = arg.strip_prefix("--filter=") {
+            value.to_owned()
+        } else {
+            remaining.push(arg);
+            continue;
+        };
+        let pattern = value.strip_prefix(IMAGE_FILTER_PREFIX).ok_or_else(|| {
+            color_eyre::eyre::eyre!("Unsupported filter '{value}', expected image=PATTERN")
+        })?;
+        patterns.push(pattern.to_owned());
+    }
+    Ok((remaining, patterns))
+}
+
+/// Whether an image is selected by `--filter image=` patterns: it contains
+/// one of them, or there are none
+pub fn image_matches(image: &str, patterns: &[String]) -> bool {
+    patterns.is_empty() || patterns.iter().any(|p| image.contains(p.as_str()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_image_list() {
+        assert_eq!(
+            parse_image_list(
+                "quay.io/fedora/fedora-bootc:42, quay.io/centos-bootc/centos-bootc:stream10,"
+            ),
+            [
+                "quay.io/fedora/fedora-bootc:42",
+                "quay.io/centos-bootc/centos-bootc:stream10"
+            ]
+        );
+        assert!(parse_image_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_extract_image_filters() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let (remaining, patterns) = extract_image_filters(args(&[
+            "integration-tests",
+            "--filter",
+            "image=fedora",
+            "--nocapture",
+            "--filter=image=stream10",
+            "ssh",
+        ]))
+        .unwrap();
+        assert_eq!(remaining, ["integration-tests", "--nocapture", "ssh"]);
+        assert_eq!(patterns, ["fedora", "stream10"]);
+
+        for invalid in [&["x", "--filter"][..], &["x", "--filter", "fedora"]] {
+            assert!(extract_image_filters(args(invalid)).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_image_matches() {
+        let image = "quay.io/fedora/fedora-bootc:42";
+        assert!(image_matches(image, &[]));
+        assert!(image_matches(image, &["centos".into(), "fedora".into()]));
+        assert!(!image_matches(image, &["centos".into()]));
+    }
+
+    #[test]
+    fn test_skip_reason() {
+        fn test(_: &str) -> color_eyre::Result<()> {
+            Ok(())
+        }
+        const SKIPS: &[ImageSkip] = &[
+            skip_if_image_contains("centos"),
+            skip_if_image_contains("stream9"),
+        ];
+        let t = ParameterizedIntegrationTest::new("test", test).with_skips(SKIPS);
+        assert_eq!(t.skip_reason("quay.io/fedora/fedora-bootc:42"), None);
+        let reason = t
+            .skip_reason("quay.io/centos-bootc/centos-bootc:stream9")
+            .unwrap();
+        assert_eq!(reason, ImageSkip::Contains("centos"));
+        assert_eq!(reason.to_string(), "image contains \"centos\"");
+        assert_eq!(
+            ParameterizedIntegrationTest::new("test", test).skip_reason("centos"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fixture_cell() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static SETUPS: AtomicUsize = AtomicUsize::new(0);
+        static TEARDOWNS: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Debug)]
+        struct Counted(usize);
+        impl Fixture for Counted {
+            fn setup() -> color_eyre::Result<Self> {
+                Ok(Self(SETUPS.fetch_add(1, Ordering::SeqCst)))
+            }
+            fn teardown(&self) -> color_eyre::Result<()> {
+                TEARDOWNS.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let cell = FixtureCell::<Counted>::new("counted");
+        cell.teardown().unwrap();
+        assert_eq!(TEARDOWNS.load(Ordering::SeqCst), 0);
+        assert_eq!(cell.get().unwrap().0, 0);
+        assert_eq!(cell.get().unwrap().0, 0);
+        assert_eq!(SETUPS.load(Ordering::SeqCst), 1);
+        cell.teardown().unwrap();
+        assert_eq!(TEARDOWNS.load(Ordering::SeqCst), 1);
+
+        struct Broken;
+        impl Fixture for Broken {
+            fn setup() -> color_eyre::Result<Self> {
+                Err(color_eyre::eyre::eyre!("no VM"))
+            }
+            fn teardown(&self) -> color_eyre::Result<()> {
+                unreachable!()
+            }
+        }
+        let cell = FixtureCell::<Broken>::new("broken");
+        let err = cell.get().err().unwrap().to_string();
+        assert!(err.contains("broken") && err.contains("no VM"), "{err}");
+        assert!(cell.get().is_err());
+        cell.teardown().unwrap();
+    }
+
     #[test]
     fn test_image_to_test_suffix_basic() {
         assert_eq!(
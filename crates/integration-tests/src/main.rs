@@ -1,7 +1,10 @@
 //! Integration tests for bcvk
 
 use camino::Utf8Path;
-use std::process::Output;
+use std::collections::BTreeMap;
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
@@ -9,12 +12,15 @@ use libtest_mimic::{Arguments, Trial};
 use serde_json::Value;
 use xshell::{cmd, Shell};
 
+use integration_tests::report::{self, TestReport, REPORT_CHILD_ENV};
 // Re-export constants from lib for internal use
 pub(crate) use integration_tests::{
-    image_to_test_suffix, integration_test, INTEGRATION_TESTS, INTEGRATION_TEST_LABEL,
+    extract_image_filters, image_matches, image_to_test_suffix, integration_test, parse_image_list,
+    teardown_fixtures, ImageSkip, INTEGRATION_TESTS, INTEGRATION_TEST_LABEL,
     LIBVIRT_INTEGRATION_TEST_LABEL, PARAMETERIZED_INTEGRATION_TESTS,
 };
 
+mod fixtures;
 mod tests {
     pub mod libvirt_base_disks;
     pub mod libvirt_port_forward;
@@ -55,26 +61,67 @@ pub(crate) fn get_test_image() -> String {
 
 /// Get all test images for matrix testing
 ///
-/// Parses BCVK_ALL_IMAGES environment variable, which should be a whitespace-separated
-/// list of container images (spaces, tabs, and newlines are all acceptable separators).
-/// Falls back to a single-element vec containing the primary image if not set or empty.
+/// Parses the BCVK_TEST_IMAGES environment variable, a comma-separated list of
+/// container images. For backwards compatibility BCVK_ALL_IMAGES, a
+/// whitespace-separated list, is used if it is not set. Falls back to a
+/// single-element vec containing the primary image if neither is set or both are empty.
 ///
-/// Example: `export BCVK_ALL_IMAGES="quay.io/fedora/fedora-bootc:42 quay.io/centos-bootc/centos-bootc:stream9"`
+/// Example: `export BCVK_TEST_IMAGES="quay.io/fedora/fedora-bootc:42,quay.io/centos-bootc/centos-bootc:stream9"`
 pub(crate) fn get_all_test_images() -> Vec<String> {
-    if let Ok(all_images) = std::env::var("BCVK_ALL_IMAGES") {
-        let images: Vec<String> = all_images
+    let images = if let Ok(images) = std::env::var("BCVK_TEST_IMAGES") {
+        Some(("BCVK_TEST_IMAGES", parse_image_list(&images)))
+    } else if let Ok(all_images) = std::env::var("BCVK_ALL_IMAGES") {
+        let images = all_images
             .split_whitespace()
             .map(|s| s.to_string())
             .collect();
+        Some(("BCVK_ALL_IMAGES", images))
+    } else {
+        None
+    };
 
-        if images.is_empty() {
-            eprintln!("Warning: BCVK_ALL_IMAGES is set but empty, falling back to primary image");
+    match images {
+        Some((var, images)) if images.is_empty() => {
+            eprintln!("Warning: {var} is set but empty, falling back to primary image");
             vec![get_test_image()]
-        } else {
-            images
         }
-    } else {
-        vec![get_test_image()]
+        Some((_, images)) => images,
+        None => vec![get_test_image()],
+    }
+}
+
+/// Results of the parameterized tests that ran for one image
+#[derive(Debug, Default)]
+struct ImageResults {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+/// Print the results of parameterized tests per image
+fn print_image_results(results: &BTreeMap<String, ImageResults>) {
+    if results.is_empty() {
+        return;
+    }
+    println!("\nparameterized test results per image:");
+    for (image, r) in results {
+        let status = if r.failed == 0 { "ok" } else { "FAILED" };
+        println!(
+            "    {image}: {status}. {} passed; {} failed; {} skipped",
+            r.passed, r.failed, r.skipped
+        );
+    }
+}
+
+/// Print the combinations of parameterized tests and images which were
+/// skipped because the test does not apply to the image
+fn print_skipped(skipped: &[(String, String, ImageSkip)]) {
+    if skipped.is_empty() {
+        return;
+    }
+    println!("\nskipped image combinations:");
+    for (name, image, reason) in skipped {
+        println!("    {name} on {image}: {reason}");
     }
 }
 
@@ -186,33 +233,202 @@ fn test_images_list() -> Result<()> {
 }
 integration_test!(test_images_list);
 
-fn main() {
-    let args = Arguments::from_args();
+/// A test to run, before it is wrapped in a libtest-mimic `Trial`
+struct TestCase {
+    name: String,
+    /// Image of a parameterized test
+    image: Option<String>,
+    /// Why a parameterized test does not apply to its image
+    skip: Option<ImageSkip>,
+    run: Box<dyn FnOnce() -> Result<()> + Send>,
+}
 
-    let mut tests: Vec<Trial> = Vec::new();
+/// Collect the registered tests, generating a variant of each parameterized
+/// test for every test image selected by `image_filters`
+fn collect_tests(image_filters: &[String]) -> Vec<TestCase> {
+    let mut tests = Vec::new();
 
-    // Collect regular tests from the distributed slice
-    tests.extend(INTEGRATION_TESTS.iter().map(|test| {
-        let name = test.name;
-        let f = test.f;
-        Trial::test(name, move || f().map_err(|e| format!("{:?}", e).into()))
-    }));
+    // Selecting images only makes sense for parameterized tests
+    if image_filters.is_empty() {
+        tests.extend(INTEGRATION_TESTS.iter().map(|test| TestCase {
+            name: test.name.to_owned(),
+            image: None,
+            skip: None,
+            run: Box::new(test.f),
+        }));
+    }
 
-    // Collect parameterized tests and generate variants for each image
-    let all_images = get_all_test_images();
+    let all_images: Vec<String> = get_all_test_images()
+        .into_iter()
+        .filter(|image| image_matches(image, image_filters))
+        .collect();
+    if all_images.is_empty() {
+        eprintln!("Warning: no test images match the image filters");
+    }
     for param_test in PARAMETERIZED_INTEGRATION_TESTS.iter() {
         for image in &all_images {
-            let image = image.clone();
-            let test_suffix = image_to_test_suffix(&image);
-            let test_name = format!("{}_{}", param_test.name, test_suffix);
             let f = param_test.f;
+            let image_arg = image.clone();
+            tests.push(TestCase {
+                name: format!("{}_{}", param_test.name, image_to_test_suffix(image)),
+                image: Some(image.clone()),
+                skip: param_test.skip_reason(image),
+                run: Box::new(move || f(&image_arg)),
+            });
+        }
+    }
+    tests
+}
+
+/// Number of trailing stderr lines of a failed test included in its failure message
+const FAILURE_STDERR_LINES: usize = 50;
+
+/// Run a single test in a child process of this binary, capturing its output
+fn run_in_child(name: &str, image: Option<String>) -> TestReport {
+    let start = Instant::now();
+    let output = std::env::current_exe().and_then(|exe| {
+        std::process::Command::new(exe)
+            .env(REPORT_CHILD_ENV, name)
+            .stdin(Stdio::null())
+            .output()
+    });
+    let duration = start.elapsed();
+    let (failure, stdout, stderr) = match output {
+        Ok(output) => {
+            let output = CapturedOutput::new(output);
+            let failure = (!output.success()).then(|| {
+                let lines: Vec<&str> = output.stderr.lines().collect();
+                let tail = &lines[lines.len().saturating_sub(FAILURE_STDERR_LINES)..];
+                format!("{}\n{}", output.output.status, tail.join("\n"))
+            });
+            (failure, output.stdout, output.stderr)
+        }
+        Err(e) => (
+            Some(format!("Failed to run test process: {e}")),
+            String::new(),
+            String::new(),
+        ),
+    };
+    TestReport {
+        name: name.to_owned(),
+        image,
+        duration,
+        failure,
+        stdout,
+        stderr,
+    }
+}
+
+/// Run the single test named by `REPORT_CHILD_ENV`, in a child process of
+/// a run with `--report-path`
+fn run_child(name: &str) -> ! {
+    let Some(test) = collect_tests(&[]).into_iter().find(|t| t.name == name) else {
+        eprintln!("error: unknown test {name}");
+        std::process::exit(1);
+    };
+    let r = (test.run)();
+    let torn_down = teardown_fixtures();
+    if let Err(e) = r {
+        eprintln!("{e:?}");
+        std::process::exit(1);
+    }
+    std::process::exit(if torn_down { 0 } else { 1 });
+}
+
+fn main() {
+    if let Ok(name) = std::env::var(REPORT_CHILD_ENV) {
+        run_child(&name);
+    }
 
-            tests.push(Trial::test(test_name, move || {
-                f(&image).map_err(|e| format!("{:?}", e).into())
-            }));
+    let parsed = extract_image_filters(std::env::args()).and_then(|(args, image_filters)| {
+        let (args, report_paths) = report::extract_report_paths(args)?;
+        Ok((args, image_filters, report_paths))
+    });
+    let (args, image_filters, report_paths) = match parsed {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
+    let args = Arguments::from_iter(args);
+
+    let results: Arc<Mutex<BTreeMap<String, ImageResults>>> = Default::default();
+    let reports: Option<Arc<Mutex<Vec<TestReport>>>> =
+        (!report_paths.is_empty()).then(Default::default);
+    let tests = collect_tests(&image_filters);
+    // Skipped combinations are ignored tests, which still run with --ignored
+    let skipped: Vec<(String, String, ImageSkip)> = if args.ignored || args.include_ignored {
+        Vec::new()
+    } else {
+        tests
+            .iter()
+            .filter_map(|t| Some((t.name.clone(), t.image.clone()?, t.skip?)))
+            .collect()
+    };
+    {
+        let mut results = results.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, image, _) in &skipped {
+            results.entry(image.clone()).or_default().skipped += 1;
         }
     }
+    let tests: Vec<Trial> = tests
+        .into_iter()
+        .map(|test| {
+            let results = Arc::clone(&results);
+            let reports = reports.clone();
+            let ignored = test.skip.is_some();
+            Trial::test(test.name.clone(), move || {
+                let r = match reports {
+                    Some(reports) => {
+                        let report = run_in_child(&test.name, test.image.clone());
+                        let r = report.failure.clone().map_or(Ok(()), Err);
+                        reports
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .push(report);
+                        Ok(r)
+                    }
+                    // Many tests fail by panicking; count that before libtest-mimic reports it
+                    None => std::panic::catch_unwind(std::panic::AssertUnwindSafe(test.run))
+                        .map(|r| r.map_err(|e| format!("{:?}", e))),
+                };
+                if let Some(image) = test.image {
+                    let passed = matches!(r, Ok(Ok(())));
+                    let mut results = results.lock().unwrap_or_else(|e| e.into_inner());
+                    let entry = results.entry(image).or_default();
+                    if passed {
+                        entry.passed += 1;
+                    } else {
+                        entry.failed += 1;
+                    }
+                }
+                match r {
+                    Ok(r) => r.map_err(Into::into),
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            })
+            .with_ignored_flag(ignored)
+        })
+        .collect();
 
     // Run the tests and exit with the result
-    libtest_mimic::run(&args, tests).exit();
+    let conclusion = libtest_mimic::run(&args, tests);
+    let torn_down = teardown_fixtures();
+    print_image_results(&results.lock().unwrap_or_else(|e| e.into_inner()));
+    print_skipped(&skipped);
+    if let Some(reports) = reports {
+        let reports = reports.lock().unwrap_or_else(|e| e.into_inner());
+        for path in &report_paths {
+            if let Err(e) = report::write_report(path, &reports) {
+                eprintln!("error: {e:?}");
+                std::process::exit(1);
+            }
+            println!("wrote report to {path}");
+        }
+    }
+    if !torn_down {
+        std::process::exit(101);
+    }
+    conclusion.exit();
 }
@@ -0,0 +1,356 @@
+//! JUnit XML and HTML reports of integration test runs
+//!
+//! With `--report-path`, every test runs in a child process of the test
+//! binary so that its stdout and stderr (including that of the commands it
+//! spawns) can be captured separately and attached to the report, instead
+//! of being interleaved on the console.
+
+use std::time::Duration;
+
+use bcvk_core::xml_utils::XmlWriter;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+
+/// Environment variable naming the single test a child process should run
+pub const REPORT_CHILD_ENV: &str = "BCVK_INTEGRATION_TEST_RUN";
+
+/// Name of the test suite in reports
+const SUITE_NAME: &str = "integration-tests";
+
+/// Format of a report, determined by the extension of its path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// JUnit XML, for CI systems
+    JUnit,
+    /// A standalone HTML page
+    Html,
+}
+
+impl ReportFormat {
+    /// Determine the format from the extension of `path`
+    pub fn from_path(path: &Utf8Path) -> Result<Self> {
+        match path.extension() {
+            Some("xml") => Ok(Self::JUnit),
+            Some("html" | "htm") => Ok(Self::Html),
+            _ => Err(eyre!(
+                "Unsupported report path {path}: expected a .xml (JUnit) or .html extension"
+            )),
+        }
+    }
+}
+
+/// Outcome and captured output of a single test
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    /// Full test name
+    pub name: String,
+    /// Image of a parameterized test
+    pub image: Option<String>,
+    /// Wall-clock duration
+    pub duration: Duration,
+    /// Failure message, if the test failed
+    pub failure: Option<String>,
+    /// Captured stdout
+    pub stdout: String,
+    /// Captured stderr
+    pub stderr: String,
+}
+
+/// Split `--report-path PATH` (or `--report-path=PATH`) out of the test
+/// harness arguments
+///
+/// Returns the remaining arguments and the report paths, whose formats have
+/// been validated.
+pub fn extract_report_paths(
+    args: impl IntoIterator<Item = String>,
+) -> Result<(Vec<String>, Vec<Utf8PathBuf>)> {
+    let mut args = args.into_iter();
+    let mut remaining = Vec::new();
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        let path = if arg == "--report-path" {
+            args.next()
+                .ok_or_else(|| eyre!("--report-path requires a value"))?
+        } else if let Some(value) = arg.strip_prefix("--report-path=") {
+            value.to_owned()
+        } else {
+            remaining.push(arg);
+            continue;
+        };
+        let path = Utf8PathBuf::from(path);
+        ReportFormat::from_path(&path)?;
+        paths.push(path);
+    }
+    Ok((remaining, paths))
+}
+
+/// Write a report of `reports` to `path`, in the format given by its extension
+pub fn write_report(path: &Utf8Path, reports: &[TestReport]) -> Result<()> {
+    let mut reports = reports.to_vec();
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+    let contents = match ReportFormat::from_path(path)? {
+        ReportFormat::JUnit => junit_xml(&reports)?,
+        ReportFormat::Html => html(&reports)?,
+    };
+    std::fs::write(path, contents).with_context(|| format!("Writing report {path}"))
+}
+
+/// Drop control characters that XML 1.0 does not allow (such as the
+/// escape sequences of progress bars)
+fn strip_control(s: &str) -> String {
+    s.chars()
+        .filter(|&c| matches!(c, '\t' | '\n' | '\r') || !c.is_control())
+        .collect()
+}
+
+/// Total duration and number of failures
+fn totals(reports: &[TestReport]) -> (Duration, usize) {
+    let duration = reports.iter().map(|r| r.duration).sum();
+    let failures = reports.iter().filter(|r| r.failure.is_some()).count();
+    (duration, failures)
+}
+
+/// Render a JUnit XML report
+fn junit_xml(reports: &[TestReport]) -> Result<String> {
+    let (duration, failures) = totals(reports);
+    let tests = reports.len().to_string();
+    let failures = failures.to_string();
+    let time = format!("{:.3}", duration.as_secs_f64());
+    let mut w = XmlWriter::new();
+    w.start_element(
+        "testsuites",
+        &[
+            ("name", SUITE_NAME),
+            ("tests", &tests),
+            ("failures", &failures),
+            ("time", &time),
+        ],
+    )?;
+    w.start_element(
+        "testsuite",
+        &[
+            ("name", SUITE_NAME),
+            ("tests", &tests),
+            ("failures", &failures),
+            ("errors", "0"),
+            ("skipped", "0"),
+            ("time", &time),
+        ],
+    )?;
+    for r in reports {
+        let time = format!("{:.3}", r.duration.as_secs_f64());
+        w.start_element(
+            "testcase",
+            &[
+                ("name", &strip_control(&r.name)),
+                ("classname", SUITE_NAME),
+                ("time", &time),
+            ],
+        )?;
+        if let Some(image) = &r.image {
+            w.start_element("properties", &[])?;
+            w.write_empty_element(
+                "property",
+                &[("name", "image"), ("value", &strip_control(image))],
+            )?;
+            w.end_element("properties")?;
+        }
+        if let Some(failure) = &r.failure {
+            let message = failure.lines().next().unwrap_or_default();
+            w.write_text_element_with_attrs(
+                "failure",
+                &strip_control(failure),
+                &[("message", &strip_control(message))],
+            )?;
+        }
+        w.write_text_element("system-out", &strip_control(&r.stdout))?;
+        w.write_text_element("system-err", &strip_control(&r.stderr))?;
+        w.end_element("testcase")?;
+    }
+    w.end_element("testsuite")?;
+    w.end_element("testsuites")?;
+    Ok(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}\n",
+        w.into_string()?
+    ))
+}
+
+/// Render a standalone HTML report; the output of failed tests is expanded
+fn html(reports: &[TestReport]) -> Result<String> {
+    let (duration, failures) = totals(reports);
+    let mut w = XmlWriter::new();
+    w.start_element("html", &[])?;
+    w.start_element("head", &[])?;
+    w.write_empty_element("meta", &[("charset", "utf-8")])?;
+    w.write_text_element("title", &format!("bcvk {SUITE_NAME}"))?;
+    w.write_text_element(
+        "style",
+        "body{font-family:sans-serif}td,th{padding:2px 8px;text-align:left;vertical-align:top}\
+         .ok{color:green}.failed{color:red}pre{white-space:pre-wrap;background:#f4f4f4}",
+    )?;
+    w.end_element("head")?;
+    w.start_element("body", &[])?;
+    w.write_text_element("h1", &format!("bcvk {SUITE_NAME}"))?;
+    w.write_text_element(
+        "p",
+        &format!(
+            "{} passed; {failures} failed; {:.1}s",
+            reports.len() - failures,
+            duration.as_secs_f64()
+        ),
+    )?;
+    w.start_element("table", &[])?;
+    w.start_element("tr", &[])?;
+    for heading in ["Test", "Image", "Status", "Duration"] {
+        w.write_text_element("th", heading)?;
+    }
+    w.end_element("tr")?;
+    for r in reports {
+        let (class, status) = if r.failure.is_some() {
+            ("failed", "FAILED")
+        } else {
+            ("ok", "ok")
+        };
+        w.start_element("tr", &[])?;
+        w.start_element("td", &[])?;
+        if r.failure.is_some() {
+            w.start_element("details", &[("open", "")])?;
+        } else {
+            w.start_element("details", &[])?;
+        }
+        w.write_text_element("summary", &strip_control(&r.name))?;
+        let failure = r.failure.as_deref().unwrap_or_default();
+        for (label, output) in [
+            ("failure", failure),
+            ("stdout", r.stdout.as_str()),
+            ("stderr", r.stderr.as_str()),
+        ] {
+            if !output.is_empty() {
+                w.write_text_element("h4", label)?;
+                w.write_text_element("pre", &strip_control(output))?;
+            }
+        }
+        w.end_element("details")?;
+        w.end_element("td")?;
+        w.write_text_element("td", &strip_control(r.image.as_deref().unwrap_or_default()))?;
+        w.write_text_element_with_attrs("td", status, &[("class", class)])?;
+        w.write_text_element("td", &format!("{:.1}s", r.duration.as_secs_f64()))?;
+        w.end_element("tr")?;
+    }
+    w.end_element("table")?;
+    w.end_element("body")?;
+    w.end_element("html")?;
+    Ok(format!("<!DOCTYPE html>\n{}\n", w.into_string()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reports() -> Vec<TestReport> {
+        vec![
+            TestReport {
+                name: "test_images_list".into(),
+                image: None,
+                duration: Duration::from_millis(1500),
+                failure: None,
+                stdout: "found 2 images\n".into(),
+                stderr: String::new(),
+            },
+            TestReport {
+                name: "test_to_disk_quay_io_fedora_fedora_bootc_42".into(),
+                image: Some("quay.io/fedora/fedora-bootc:42".into()),
+                duration: Duration::from_secs(90),
+                failure: Some("exit status: 1\nError: <boom> & \"more\"".into()),
+                stdout: "\u{1b}[2Kprogress\n".into(),
+                stderr: "bcvk failed\n".into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_report_format() {
+        for (path, expected) in [
+            ("report.xml", Some(ReportFormat::JUnit)),
+            ("out/report.html", Some(ReportFormat::Html)),
+            ("report.htm", Some(ReportFormat::Html)),
+            ("report.json", None),
+            ("report", None),
+        ] {
+            assert_eq!(
+                ReportFormat::from_path(Utf8Path::new(path)).ok(),
+                expected,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_report_paths() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let (remaining, paths) = extract_report_paths(args(&[
+            "integration-tests",
+            "--report-path",
+            "junit.xml",
+            "--nocapture",
+            "--report-path=report.html",
+        ]))
+        .unwrap();
+        assert_eq!(remaining, ["integration-tests", "--nocapture"]);
+        assert_eq!(paths, ["junit.xml", "report.html"]);
+
+        for invalid in [&["x", "--report-path"][..], &["x", "--report-path=r.txt"]] {
+            assert!(extract_report_paths(args(invalid)).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_strip_control() {
+        assert_eq!(
+            strip_control("a<b> & \"c\"\t'd'\n\u{1b}[0m"),
+            "a<b> & \"c\"\t'd'\n[0m"
+        );
+    }
+
+    #[test]
+    fn test_junit_xml() -> Result<()> {
+        let xml = junit_xml(&reports())?;
+        assert!(xml.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?>"#));
+        assert!(xml.contains(
+            r#"<testsuites name="integration-tests" tests="2" failures="1" time="91.500">"#
+        ));
+        assert!(xml.contains(
+            r#"<testcase name="test_images_list" classname="integration-tests" time="1.500">"#
+        ));
+        assert!(xml.contains(r#"<property name="image" value="quay.io/fedora/fedora-bootc:42"/>"#));
+        assert!(xml.contains(r#"<failure message="exit status: 1">exit status: 1"#));
+        assert!(xml.contains("Error: &lt;boom&gt; &amp; &quot;more&quot;</failure>"));
+        assert!(xml.contains("<system-out>[2Kprogress\n</system-out>"));
+        assert_eq!(xml.matches("<testcase ").count(), 2);
+        assert_eq!(xml.matches("<failure ").count(), 1);
+        // The report is well-formed
+        let dom = bcvk_core::xml_utils::parse_xml_dom(&xml)?;
+        assert_eq!(
+            dom.find("failure").unwrap().attributes["message"],
+            "exit status: 1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_html() -> Result<()> {
+        let html = html(&reports())?;
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<p>1 passed; 1 failed; 91.5s</p>"));
+        assert!(html.contains(r#"<td class="failed">FAILED</td><td>90.0s</td>"#));
+        assert!(html.contains(
+            r#"<details open=""><summary>test_to_disk_quay_io_fedora_fedora_bootc_42</summary>"#
+        ));
+        assert!(html.contains("<details><summary>test_images_list</summary>"));
+        assert!(html.contains("<td></td>"));
+        // Empty output is omitted
+        assert_eq!(html.matches("<h4>stderr</h4>").count(), 1);
+        Ok(())
+    }
+}
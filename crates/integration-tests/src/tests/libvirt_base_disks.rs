@@ -254,7 +254,7 @@ fn test_vm_disk_references_base() -> Result<()> {
     let domain_xml = String::from_utf8_lossy(&dumpxml_output.stdout);
 
     // Parse XML using bcvk's xml_utils to extract disk path
-    let dom = bcvk::xml_utils::parse_xml_dom(&domain_xml).expect("Failed to parse domain XML");
+    let dom = bcvk_core::xml_utils::parse_xml_dom(&domain_xml).expect("Failed to parse domain XML");
 
     let disk_path = dom
         .find("disk")
@@ -15,7 +15,7 @@ use std::process::Command;
 use crate::{
     get_bck_command, get_test_image, run_bcvk, run_bcvk_nocapture, LIBVIRT_INTEGRATION_TEST_LABEL,
 };
-use bcvk::xml_utils::parse_xml_dom;
+use bcvk_core::xml_utils::parse_xml_dom;
 
 /// Generate a random alphanumeric suffix for VM names to avoid collisions
 fn random_suffix() -> String {
@@ -920,7 +920,7 @@ fn test_libvirt_run_no_storage_opts_without_bind_storage() -> Result<()> {
     let domain_xml = String::from_utf8_lossy(&dumpxml_output.stdout);
 
     // Verify that the domain XML does NOT contain STORAGE_OPTS related credentials
-    // The bugfix ensures storage_opts_tmpfiles_d_lines() is only added when --bind-storage-ro is true
+    // The bugfix ensures smbios_creds_for_storage_opts() is only added when --bind-storage-ro is true
     // These credentials appear as SMBIOS entries in the domain XML
 
     // Check that bcvk-storage-opts is NOT present (this is the systemd unit name)
@@ -1310,15 +1310,12 @@ fn test_libvirt_run_bind_mounts() -> Result<()> {
 
     println!("✓ Domain XML contains virtiofs and SMBIOS credentials");
 
-    // Wait for VM to boot and mounts to be ready
-    println!("Waiting for VM to boot and mounts to be ready...");
-    std::thread::sleep(std::time::Duration::from_secs(15));
-
-    // Debug: Check systemd credentials
+    // Debug: Check systemd credentials, once the VM has booted
     println!("Debugging: Checking systemd credentials...");
     let _creds_check = run_bcvk(&[
         "libvirt",
         "ssh",
+        "--wait",
         &domain_name,
         "--",
         "ls",
@@ -1473,3 +1470,79 @@ fn test_libvirt_run_bind_mounts() -> Result<()> {
     Ok(())
 }
 integration_test!(test_libvirt_run_bind_mounts);
+
+/// Test `libvirt run --wait` and the readiness line printed once the wait is over
+fn test_libvirt_run_wait_modes() -> Result<()> {
+    let test_image = get_test_image();
+
+    for wait in ["none", "ssh", "boot"] {
+        let domain_name = format!("test-wait-{wait}-{}", random_suffix());
+        println!("Testing --wait {wait} with domain: {domain_name}");
+        cleanup_domain(&domain_name);
+
+        let output = run_bcvk(&[
+            "libvirt",
+            "run",
+            "--name",
+            &domain_name,
+            "--label",
+            LIBVIRT_INTEGRATION_TEST_LABEL,
+            "--filesystem",
+            "ext4",
+            "--wait",
+            wait,
+            &test_image,
+        ])
+        .expect("Failed to run libvirt run");
+        if !output.success() {
+            cleanup_domain(&domain_name);
+            panic!("libvirt run --wait {wait} failed: {}", output.stderr);
+        }
+
+        let ready: Vec<&str> = output
+            .stdout
+            .lines()
+            .filter(|l| l.starts_with("ready: "))
+            .collect();
+        if wait == "none" {
+            assert!(ready.is_empty(), "Unexpected readiness line: {ready:?}");
+            cleanup_domain(&domain_name);
+            continue;
+        }
+        let prefix = format!("ready: name={domain_name} wait={wait} ssh-port=");
+        assert!(
+            matches!(ready[..], [line] if line.starts_with(&prefix) && line.contains(" elapsed=")),
+            "Expected one readiness line starting with {prefix:?}, got: {ready:?}"
+        );
+
+        // No further waiting is needed for SSH
+        let ssh = run_bcvk(&[
+            "libvirt",
+            "ssh",
+            &domain_name,
+            "--",
+            "systemctl",
+            "is-system-running",
+        ])
+        .expect("Failed to run libvirt ssh");
+        if wait == "boot" {
+            // is-system-running exits nonzero for degraded, which is fine here
+            let state = ssh.stdout.trim();
+            assert!(
+                state == "running" || state == "degraded",
+                "Expected the boot to be finished, got state {state:?}"
+            );
+        } else {
+            assert!(
+                !ssh.stdout.trim().is_empty(),
+                "SSH should work right after --wait ssh. stderr: {}",
+                ssh.stderr
+            );
+        }
+
+        cleanup_domain(&domain_name);
+        println!("✓ --wait {wait} works");
+    }
+    Ok(())
+}
+integration_test!(test_libvirt_run_wait_modes);
@@ -20,7 +20,8 @@ use integration_tests::integration_test;
 use std::process::Command;
 use tracing::debug;
 
-use crate::{get_test_image, run_bcvk, INTEGRATION_TEST_LABEL};
+use crate::fixtures::{shared_vm, SharedVm};
+use crate::{get_test_image, run_bcvk, run_command, INTEGRATION_TEST_LABEL};
 
 pub fn get_container_kernel_version(image: &str) -> String {
     // Run container to get its kernel version
@@ -159,52 +160,16 @@ fn test_run_ephemeral_execute() -> Result<()> {
 }
 integration_test!(test_run_ephemeral_execute);
 
-fn test_run_ephemeral_container_ssh_access() -> Result<()> {
-    let image = get_test_image();
-    let container_name = format!(
-        "ssh-test-{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    );
-
-    let output = run_bcvk(&[
-        "ephemeral",
-        "run",
-        "--ssh-keygen",
-        "--label",
-        INTEGRATION_TEST_LABEL,
-        "--detach",
-        "--name",
-        &container_name,
-        &image,
-    ])?;
-
-    if !output.success() {
-        panic!("Failed to start detached VM: {}", output.stderr);
-    }
-
-    let ssh_output = run_bcvk(&[
-        "ephemeral",
-        "ssh",
-        &container_name,
-        "echo",
-        "SSH_TEST_SUCCESS",
-    ])?;
+fn test_run_ephemeral_container_ssh_access(vm: &SharedVm) -> Result<()> {
+    let ssh_output = vm.ssh(&["echo", "SSH_TEST_SUCCESS"])?;
 
     debug!("SSH exit status: {:?}", ssh_output.exit_code());
 
-    // Cleanup: stop the container
-    let _ = Command::new("podman")
-        .args(["stop", &container_name])
-        .output();
-
     assert!(ssh_output.success());
     assert!(ssh_output.stdout.contains("SSH_TEST_SUCCESS"));
     Ok(())
 }
-integration_test!(test_run_ephemeral_container_ssh_access);
+integration_test!(test_run_ephemeral_container_ssh_access, fixture = shared_vm);
 
 fn test_run_ephemeral_with_instancetype() -> Result<()> {
     // Test u1.nano: 1 vCPU, 512 MiB memory
@@ -299,3 +264,68 @@ fn test_run_ephemeral_instancetype_invalid() -> Result<()> {
     Ok(())
 }
 integration_test!(test_run_ephemeral_instancetype_invalid);
+
+/// Checkpoint a VM with state only in its memory, and check that the
+/// restored VM still has it
+fn test_run_ephemeral_checkpoint_restore() -> Result<()> {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    let name = format!("test-checkpoint-{suffix}");
+    let restored = format!("test-restored-{suffix}");
+    let backing = tempfile::tempdir_in("/var/tmp")?;
+    let backing = backing.path().to_str().unwrap();
+
+    let result = (|| -> Result<()> {
+        let output = run_bcvk(&[
+            "ephemeral",
+            "run",
+            "--ssh-keygen",
+            "--label",
+            INTEGRATION_TEST_LABEL,
+            "--detach",
+            "--name",
+            &name,
+            "--overlay-backing",
+            &format!("disk:{backing}"),
+            &get_test_image(),
+        ])?;
+        output.assert_success("ephemeral run");
+
+        // /run is a tmpfs, so this only survives in the saved memory
+        let marker = format!("echo {suffix} > /run/checkpoint-marker");
+        let output = run_bcvk(&["ephemeral", "ssh", &name, "sh", "-c", &marker])?;
+        output.assert_success("writing the marker");
+
+        let output = run_bcvk(&["ephemeral", "checkpoint", &name, "--name", "checkpoint"])?;
+        output.assert_success("ephemeral checkpoint");
+
+        let output = run_bcvk(&[
+            "ephemeral",
+            "restore",
+            "--label",
+            INTEGRATION_TEST_LABEL,
+            "--detach",
+            "--name",
+            &restored,
+            &format!("{backing}/checkpoint"),
+        ])?;
+        output.assert_success("ephemeral restore");
+
+        let output = run_bcvk(&[
+            "ephemeral",
+            "ssh",
+            &restored,
+            "cat",
+            "/run/checkpoint-marker",
+        ])?;
+        output.assert_success("reading the marker");
+        assert_eq!(output.stdout.trim(), suffix);
+        Ok(())
+    })();
+
+    for container in [&name, &restored] {
+        let output = run_command("podman", &["rm", "-f", "--ignore", container])?;
+        output.assert_success("removing the VM");
+    }
+    result
+}
+integration_test!(test_run_ephemeral_checkpoint_restore);
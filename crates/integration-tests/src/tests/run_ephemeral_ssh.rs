@@ -176,7 +176,7 @@ fn test_run_ephemeral_ssh_exit_code() -> Result<()> {
 integration_test!(test_run_ephemeral_ssh_exit_code);
 
 /// Test SSH functionality across different bootc images
-/// This parameterized test runs once per image in BCVK_ALL_IMAGES and verifies
+/// This parameterized test runs once per image in BCVK_TEST_IMAGES and verifies
 /// that our systemd version compatibility fix works correctly with both newer
 /// systemd (Fedora) and older systemd (CentOS Stream 9)
 fn test_run_ephemeral_ssh_cross_distro_compatibility(image: &str) -> Result<()> {
@@ -320,6 +320,79 @@ fn test_to_disk_different_imgref_same_digest() -> Result<()> {
 }
 integration_test!(test_to_disk_different_imgref_same_digest);
 
+/// Test booting the produced disk with --verify-boot, including a failing
+/// verification command
+fn test_to_disk_verify_boot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("Failed to create temp directory");
+    let disk_path = Utf8PathBuf::try_from(temp_dir.path().join("test-disk.img"))
+        .expect("temp path is not UTF-8");
+
+    let output = run_bcvk(&[
+        "to-disk",
+        "--label",
+        INTEGRATION_TEST_LABEL,
+        "--verify-boot",
+        "--verify-command",
+        "bootc status",
+        &get_test_image(),
+        disk_path.as_str(),
+    ])?;
+    assert!(
+        output.success(),
+        "to-disk --verify-boot failed with exit code: {:?}. stdout: {}, stderr: {}",
+        output.exit_code(),
+        output.stdout,
+        output.stderr
+    );
+    validate_disk_image(&disk_path, &output, "test_to_disk_verify_boot")?;
+    assert!(
+        output.stdout.contains("Booted successfully"),
+        "No boot verification in output: {}",
+        output.stdout
+    );
+
+    // A failed verification fails the command, but keeps the disk without
+    // cache metadata, so it is reinstalled by the next run
+    std::fs::remove_file(&disk_path)?;
+    let output = run_bcvk(&[
+        "to-disk",
+        "--label",
+        INTEGRATION_TEST_LABEL,
+        "--verify-boot",
+        "--verify-command",
+        "exit 3",
+        &get_test_image(),
+        disk_path.as_str(),
+    ])?;
+    assert!(!output.success(), "to-disk with a failing check succeeded");
+    assert!(
+        output.stderr.contains("Boot verification of"),
+        "Unexpected error: {}",
+        output.stderr
+    );
+    assert!(
+        disk_path.exists(),
+        "Disk was removed after failed verification"
+    );
+
+    let output = run_bcvk(&[
+        "to-disk",
+        "--dry-run",
+        "--label",
+        INTEGRATION_TEST_LABEL,
+        &get_test_image(),
+        disk_path.as_str(),
+    ])?;
+    assert!(
+        output.stdout.contains("would-regenerate"),
+        "Disk which failed verification would be reused. stdout: {}, stderr: {}",
+        output.stdout,
+        output.stderr
+    );
+    Ok(())
+}
+integration_test!(test_to_disk_verify_boot);
+
 /// Test to-disk with various bootc images to ensure compatibility
 ///
 /// This parameterized test runs to-disk with multiple container images,
@@ -37,7 +37,6 @@ init_tmproot() {
 
 BWRAP_ARGS=(
     --bind /run/tmproot /
-    --proc /proc
     --dev-bind /dev /dev
     --bind /var/tmp /var/tmp
     --tmpfs /run
@@ -45,6 +44,14 @@ BWRAP_ARGS=(
     --bind /run/inner-shared /run/inner-shared
 )
 
+# Unprivileged containers mask parts of /proc, so that a new /proc (and
+# with it a new PID namespace) cannot be mounted; share ours instead
+if [[ "${BCVK_UNPRIVILEGED:-}" == 1 ]]; then
+    BWRAP_ARGS+=(--bind /proc /proc)
+else
+    BWRAP_ARGS+=(--as-pid-1 --unshare-pid --proc /proc)
+fi
+
 # Pass ALL arguments to container-entrypoint
 # Default to "run-ephemeral" if no args
 if [[ $# -eq 0 ]]; then
@@ -60,6 +67,12 @@ fi
 # Check systemd version from the container image (not host)
 export SYSTEMD_VERSION=$(systemctl --version 2>/dev/null)
 
+# Commands forwarding stdin run in the foreground, as the stdin of
+# background jobs is /dev/null
+if [[ "${1:-}" == vsock-exec ]]; then
+    exec bwrap "${BWRAP_ARGS[@]}" --bind /run /run -- ${SELFEXE} container-entrypoint "$@"
+fi
+
 # Execute with proper environment passing
 # Set up signal handlers that will cleanly exit on INT or TERM
 trap 'kill -TERM $BWRAP_PID 2>/dev/null; exit 0' INT TERM
@@ -67,7 +80,7 @@ trap 'kill -TERM $BWRAP_PID 2>/dev/null; exit 0' INT TERM
 # Run bwrap in background so we can handle signals; xref
 # https://github.com/containers/bubblewrap/pull/586
 # But probably really we should switch to systemd
-bwrap --as-pid-1 --unshare-pid "${BWRAP_ARGS[@]}" --bind /run /run -- ${SELFEXE} container-entrypoint "$@" &
+bwrap "${BWRAP_ARGS[@]}" --bind /run /run -- ${SELFEXE} container-entrypoint "$@" &
 BWRAP_PID=$!
 
 # Wait for bwrap to complete
@@ -39,11 +39,17 @@ pub async fn monitor_boot_progress(piper: File, status_writer: StatusWriter) ->
         tracing::debug!("Got systemd notification: {k}={v}");
         match k {
             "READY" => {
-                let state = SupervisorState::ReachedTarget(v.to_owned());
+                // Sent by systemd once it finished starting up
+                let state = if v == "1" {
+                    SupervisorState::Ready
+                } else {
+                    SupervisorState::ReachedTarget(v.to_owned())
+                };
                 status_writer.update(SupervisorStatus {
                     state: Some(state),
                     ssh_access,
                     running: true,
+                    ..Default::default()
                 })?;
             }
             "X_SYSTEMD_UNIT_ACTIVE" => {
@@ -55,6 +61,7 @@ pub async fn monitor_boot_progress(piper: File, status_writer: StatusWriter) ->
                     state: Some(state),
                     ssh_access,
                     running: true,
+                    ..Default::default()
                 })?;
             }
             _ => {
@@ -49,9 +49,21 @@ struct CacheInputs {
     /// Whether to use composefs-native storage
     composefs_backend: bool,
 
+    /// Root filesystem encryption if specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encrypt_root: Option<String>,
+
     /// Kernel arguments used during installation
     kernel_args: Vec<String>,
 
+    /// SELinux mode if specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selinux: Option<String>,
+
+    /// SHA256 digest of the bootc install configuration if specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    install_config: Option<String>,
+
     /// Version of the cache format for future compatibility
     version: u32,
 }
@@ -78,9 +90,18 @@ pub struct DiskImageMetadata {
     /// Whether to use composefs-native storage
     pub composefs_backend: bool,
 
+    /// Root filesystem encryption if specified
+    pub encrypt_root: Option<String>,
+
     /// Kernel arguments used during installation
     pub kernel_args: Vec<String>,
 
+    /// SELinux mode if specified
+    pub selinux: Option<String>,
+
+    /// SHA256 digest of the bootc install configuration if specified
+    pub install_config: Option<String>,
+
     /// Version of the metadata format for future compatibility
     pub version: u32,
 }
@@ -95,7 +116,10 @@ impl DiskImageMetadata {
             filesystem: self.filesystem.clone(),
             root_size: self.root_size.clone(),
             composefs_backend: self.composefs_backend,
+            encrypt_root: self.encrypt_root.clone(),
             kernel_args: self.kernel_args.clone(),
+            selinux: self.selinux.clone(),
+            install_config: self.install_config.clone(),
             version: self.version,
         };
 
@@ -183,7 +207,13 @@ impl DiskImageMetadata {
             filesystem: options.filesystem.clone(),
             root_size: options.root_size.clone(),
             kernel_args: options.karg.clone(),
+            selinux: options.selinux.map(|m| m.to_string()),
             composefs_backend: options.composefs_backend,
+            encrypt_root: options.encrypt_root.as_ref().map(|e| e.to_string()),
+            install_config: options
+                .install_config
+                .as_ref()
+                .map(|c| format!("sha256:{:x}", Sha256::digest(c.contents.as_bytes()))),
         }
     }
 }
@@ -257,6 +287,27 @@ pub fn check_cached_disk(
     }
 }
 
+/// Check if a disk image was built with the given source and options from
+/// any digest of the image, returning that digest
+///
+/// Such a disk can be updated to a newer digest in place.
+pub fn check_previous_install(
+    path: &Path,
+    source_imgref: &str,
+    install_options: &InstallOptions,
+) -> Result<Option<String>> {
+    let Some(previous_digest) = DiskImageMetadata::read_image_digest_from_path(path)? else {
+        return Ok(None);
+    };
+    match check_cached_disk(path, &previous_digest, source_imgref, install_options)? {
+        Ok(()) => Ok(Some(previous_digest)),
+        Err(e) => {
+            tracing::debug!("Disk at {path:?} was not installed with the same options: {e}");
+            Ok(None)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -330,6 +381,54 @@ mod tests {
             metadata5.compute_cache_hash(),
             "Different source imgrefs with same digest should generate different cache hashes"
         );
+
+        // Encryption should generate different hash
+        let install_options6 = InstallOptions {
+            filesystem: Some("ext4".to_string()),
+            root_size: Some("20G".to_string()),
+            encrypt_root: Some(crate::install_options::EncryptRoot::Passphrase(
+                "/tmp/pw".into(),
+            )),
+            ..Default::default()
+        };
+        let metadata6 =
+            DiskImageMetadata::from(&install_options6, "sha256:abc123", "quay.io/test/image:v1");
+
+        assert_ne!(
+            metadata1.compute_cache_hash(),
+            metadata6.compute_cache_hash()
+        );
+
+        // So should the contents of the install configuration
+        let config = |contents: &str| crate::install_options::InstallConfig {
+            path: "install.toml".into(),
+            contents: contents.into(),
+        };
+        let metadata7 = |contents| {
+            let options = InstallOptions {
+                install_config: Some(config(contents)),
+                ..install_options1.clone()
+            };
+            DiskImageMetadata::from(&options, "sha256:abc123", "quay.io/test/image:v1")
+                .compute_cache_hash()
+        };
+        assert_ne!(metadata1.compute_cache_hash(), metadata7("[install]\n"));
+
+        // And the SELinux mode
+        let options8 = InstallOptions {
+            selinux: Some(crate::install_options::SelinuxMode::Permissive),
+            ..install_options1.clone()
+        };
+        let metadata8 =
+            DiskImageMetadata::from(&options8, "sha256:abc123", "quay.io/test/image:v1");
+        assert_ne!(
+            metadata1.compute_cache_hash(),
+            metadata8.compute_cache_hash()
+        );
+        assert_ne!(
+            metadata7("[install]\n"),
+            metadata7("[install]\nroot-fs-type = \"xfs\"\n")
+        );
     }
 
     #[test]
@@ -341,7 +440,10 @@ mod tests {
             filesystem: Some("ext4".to_string()),
             root_size: Some("20G".to_string()),
             kernel_args: vec!["console=ttyS0".to_string()],
+            selinux: None,
             composefs_backend: false,
+            encrypt_root: None,
+            install_config: None,
             version: 1,
         };
 
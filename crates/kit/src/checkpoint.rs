@@ -0,0 +1,248 @@
+//! Checkpoint and restore of ephemeral VMs
+//!
+//! A checkpoint is a directory holding everything needed to resume an
+//! ephemeral VM where it left off:
+//!
+//! - `memory.state`: the VM state (devices and memory) saved via QMP
+//! - `overlay.img`: a copy of the disk-backed root overlay
+//! - `config.json`: the options the VM was started with
+//! - `ssh`, `ssh.pub`: the SSH keypair, if one was generated
+//!
+//! Because the root overlay must survive the VM, checkpointing requires
+//! `--overlay-backing=disk:PATH`, and checkpoints are stored in PATH
+//! alongside the overlay disks. As the root filesystem is shared via
+//! virtiofs, it also requires a QEMU and virtiofsd which can save the state
+//! of virtiofs devices; this is checked before pausing the VM.
+//!
+//! Checkpointing runs inside the container hosting the VM (via
+//! `podman exec`), as that is where the QMP socket lives. Restoring starts a
+//! new container with the saved options, which boots QEMU with the saved
+//! state via `-incoming` rather than from scratch.
+
+use std::process::Command;
+
+use bootc_utils::CommandRunExt;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Report, Result};
+use tracing::debug;
+
+use crate::qmp::{QemuVersion, QmpClient};
+use crate::run_ephemeral::{self, CommonPodmanOptions, OverlayBacking, RunEphemeralOpts};
+use crate::CONTAINER_STATEDIR;
+
+/// Where a checkpoint being restored is mounted in the container
+pub(crate) const CONTAINER_CHECKPOINT_DIR: &str = "/run/checkpoint";
+
+/// File name of the saved VM state
+pub(crate) const STATE_FILE: &str = "memory.state";
+/// File name of the saved root overlay
+pub(crate) const OVERLAY_FILE: &str = "overlay.img";
+/// File name of the saved options
+const CONFIG_FILE: &str = "config.json";
+/// File names of the SSH keypair, both in the checkpoint and the container state directory
+pub(crate) const SSH_KEY_FILES: [&str; 2] = ["ssh", "ssh.pub"];
+
+/// First QEMU version which can save vhost-user-fs devices (and migrate to files)
+const MIN_QEMU_VERSION: QemuVersion = QemuVersion {
+    major: 8,
+    minor: 2,
+    micro: 0,
+};
+
+/// Save the state of a running ephemeral VM
+#[derive(Parser, Debug)]
+pub struct CheckpointOpts {
+    /// Name or ID of the ephemeral VM container
+    pub container_name: String,
+
+    /// Name of the checkpoint directory, created in the overlay backing directory
+    /// (defaults to bcvk-checkpoint-<container>)
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// Resume the VM after saving its state instead of stopping it
+    #[clap(long)]
+    pub leave_running: bool,
+}
+
+/// Resume an ephemeral VM from a checkpoint
+#[derive(Parser, Debug)]
+pub struct RestoreOpts {
+    /// Checkpoint directory created by `bcvk ephemeral checkpoint`
+    pub checkpoint: Utf8PathBuf,
+
+    #[clap(flatten)]
+    pub podman: CommonPodmanOptions,
+}
+
+/// Options for the checkpoint operation inside the container
+#[derive(Parser, Debug)]
+pub struct ContainerCheckpointOpts {
+    /// Name of the checkpoint directory
+    #[clap(long)]
+    pub name: String,
+
+    /// Resume the VM after saving its state
+    #[clap(long)]
+    pub leave_running: bool,
+}
+
+/// Execute the checkpoint command
+pub fn checkpoint(opts: CheckpointOpts) -> Result<()> {
+    let name = opts
+        .name
+        .unwrap_or_else(|| format!("bcvk-checkpoint-{}", opts.container_name));
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(eyre!("Invalid checkpoint name: {name}"));
+    }
+
+    let mut cmd = Command::new("podman");
+    cmd.args([
+        "exec",
+        opts.container_name.as_str(),
+        "/var/lib/bcvk/entrypoint",
+        "checkpoint",
+        "--name",
+        name.as_str(),
+    ]);
+    if opts.leave_running {
+        cmd.arg("--leave-running");
+    }
+    // Saving the state stops the VM unless --leave-running is given
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!(
+            "checkpoint the VM in {} to {name}",
+            opts.container_name
+        ));
+        return Ok(());
+    }
+    cmd.run()
+        .map_err(|e| eyre!("Checkpointing {}: {e}", opts.container_name))?;
+    Ok(())
+}
+
+/// Execute the restore command
+pub fn restore(opts: RestoreOpts) -> Result<()> {
+    let dir = opts
+        .checkpoint
+        .canonicalize_utf8()
+        .with_context(|| format!("Checkpoint {}", opts.checkpoint))?;
+    for f in [STATE_FILE, OVERLAY_FILE, CONFIG_FILE] {
+        if !dir.join(f).try_exists()? {
+            return Err(eyre!("{dir} is not a complete checkpoint: missing {f}"));
+        }
+    }
+
+    let config = std::fs::read_to_string(dir.join(CONFIG_FILE))
+        .with_context(|| format!("Reading {dir}/{CONFIG_FILE}"))?;
+    let mut run_opts: RunEphemeralOpts =
+        serde_json::from_str(&config).with_context(|| format!("Parsing {dir}/{CONFIG_FILE}"))?;
+    if !matches!(run_opts.overlay.overlay_backing, OverlayBacking::Disk(_)) {
+        return Err(eyre!("Checkpoint {dir} has no disk-backed overlay"));
+    }
+    run_opts.podman = opts.podman;
+    run_opts.host_dns_servers = None;
+    // A --timeout counts from the restore
+    run_opts.deadline = None;
+    run_opts.restore_from = Some(dir);
+    run_ephemeral::run(run_opts)
+}
+
+/// Save the VM state; runs inside the container hosting the VM.
+pub fn checkpoint_in_container(opts: ContainerCheckpointOpts) -> Result<()> {
+    let config = std::env::var("BCK_CONFIG").context("BCK_CONFIG")?;
+    let run_opts: RunEphemeralOpts = serde_json::from_str(&config)?;
+    let OverlayBacking::Disk(ref host_dir) = run_opts.overlay.overlay_backing else {
+        return Err(eyre!(
+            "Checkpointing requires the VM to be started with --overlay-backing=disk:<path>"
+        ));
+    };
+
+    let dir = Utf8Path::new(run_ephemeral::OVERLAY_BACKING_DIR).join(&opts.name);
+    std::fs::create_dir(&dir).with_context(|| format!("Creating {host_dir}/{}", opts.name))?;
+    let r = write_checkpoint(&dir, &config, opts.leave_running);
+    if r.is_err() {
+        // Don't leave behind a checkpoint which can't be restored
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    r?;
+
+    println!("Checkpoint saved to {host_dir}/{}", opts.name);
+    Ok(())
+}
+
+/// Fail unless QEMU and virtiofsd can save the state of the virtiofs
+/// devices, which every ephemeral VM has for its root filesystem; QEMU
+/// considers the VM unmigratable otherwise
+fn check_migratable(qmp: &mut QmpClient) -> Result<()> {
+    let version = qmp.query_version()?;
+    if version < MIN_QEMU_VERSION {
+        return Err(eyre!(
+            "Checkpointing needs QEMU {MIN_QEMU_VERSION} or newer to save virtiofs devices, but the VM runs QEMU {version}"
+        ));
+    }
+    let virtiofsd = crate::qemu::find_virtiofsd().ok_or_else(|| {
+        Report::new(crate::Failure::HypervisorUnavailable).wrap_err("virtiofsd not found")
+    })?;
+    let output = Command::new(virtiofsd)
+        .arg("--help")
+        .output()
+        .with_context(|| format!("Running {virtiofsd} --help"))?;
+    let supported = [&output.stdout, &output.stderr]
+        .iter()
+        .any(|o| String::from_utf8_lossy(o).contains("--migration-mode"));
+    if !supported {
+        return Err(eyre!(
+            "Checkpointing needs virtiofsd 1.10 or newer to save virtiofs devices, but {virtiofsd} can't"
+        ));
+    }
+    Ok(())
+}
+
+fn write_checkpoint(dir: &Utf8Path, config: &str, leave_running: bool) -> Result<()> {
+    let mut qmp = QmpClient::connect(Utf8Path::new(run_ephemeral::QMP_SOCKET))?;
+    check_migratable(&mut qmp)?;
+
+    debug!("Saving VM state to {dir}");
+    qmp.save_state(&dir.join(STATE_FILE))?;
+
+    // The VM is paused now, so the overlay is consistent with the saved state
+    let r = copy_vm_files(dir, config);
+    // Don't leave the VM paused if we failed
+    if leave_running || r.is_err() {
+        qmp.cont()?;
+    } else {
+        qmp.quit()?;
+    }
+    r
+}
+
+/// Copy the overlay disk, options and SSH keys into the checkpoint
+fn copy_vm_files(dir: &Utf8Path, config: &str) -> Result<()> {
+    let overlay =
+        std::fs::read_link(run_ephemeral::OVERLAY_DISK_LINK).context("Finding overlay disk")?;
+    copy_sparse(&overlay, dir.join(OVERLAY_FILE).as_std_path()).context("Copying overlay disk")?;
+
+    std::fs::write(dir.join(CONFIG_FILE), config)?;
+
+    let statedir = Utf8Path::new(CONTAINER_STATEDIR);
+    for f in SSH_KEY_FILES {
+        let src = statedir.join(f);
+        if src.try_exists()? {
+            std::fs::copy(&src, dir.join(f)).with_context(|| format!("Copying {src}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a (possibly large, sparse) disk image, reflinking if possible
+pub(crate) fn copy_sparse(src: &std::path::Path, dest: &std::path::Path) -> Result<()> {
+    Command::new("cp")
+        .args(["--reflink=auto", "--sparse=always"])
+        .arg(src)
+        .arg(dest)
+        .run()
+        .map_err(|e| eyre!("{e}"))
+}
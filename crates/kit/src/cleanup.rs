@@ -0,0 +1,187 @@
+//! Rollback of partially created resources on errors and interruption
+//!
+//! Long operations such as `to-disk` or `libvirt run` create resources step
+//! by step: temporary files, disk images, containers and domains. Each such
+//! resource is covered by a [`CleanupGuard`] until the operation completes
+//! and the guard is disarmed. Dropping an armed guard, e.g. when returning
+//! an error, runs its cleanup action.
+//!
+//! As the process exits on SIGINT and SIGTERM without unwinding, the actions
+//! of all armed guards are additionally kept in a global registry; the
+//! handler installed by [`install_signal_handler`] runs them, newest first,
+//! before exiting.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use camino::Utf8PathBuf;
+use color_eyre::Result;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::debug;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// A registered cleanup action
+struct Entry {
+    id: u64,
+    description: String,
+    action: Action,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static ACTIONS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn lock(registry: &Mutex<Vec<Entry>>) -> MutexGuard<'_, Vec<Entry>> {
+    // Actions are run outside of the lock, so it can't be poisoned by them
+    registry.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn actions() -> MutexGuard<'static, Vec<Entry>> {
+    lock(&ACTIONS)
+}
+
+fn take(id: u64) -> Option<Entry> {
+    let mut actions = actions();
+    let idx = actions.iter().position(|e| e.id == id)?;
+    Some(actions.remove(idx))
+}
+
+fn run_entry(entry: Entry) {
+    debug!("Cleaning up {}", entry.description);
+    (entry.action)();
+}
+
+/// Runs a cleanup action when dropped or on SIGINT/SIGTERM, unless disarmed
+#[derive(Debug)]
+#[must_use = "the cleanup action runs immediately if the guard is not kept"]
+pub(crate) struct CleanupGuard {
+    id: u64,
+}
+
+impl CleanupGuard {
+    /// Register `action`; `description` is used for logging
+    pub(crate) fn new(
+        description: impl Into<String>,
+        action: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        actions().push(Entry {
+            id,
+            description: description.into(),
+            action: Box::new(action),
+        });
+        Self { id }
+    }
+
+    /// Remove the file or directory at `path` if it (still) exists
+    pub(crate) fn remove_path(path: impl Into<Utf8PathBuf>) -> Self {
+        let path = path.into();
+        Self::new(path.to_string(), move || {
+            let r = if path.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            match r {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => tracing::warn!("Failed to remove {path}: {e}"),
+            }
+        })
+    }
+
+    /// The resource is complete; don't clean it up
+    pub(crate) fn disarm(self) {
+        if let Some(entry) = take(self.id) {
+            debug!("Keeping {}", entry.description);
+        }
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        if let Some(entry) = take(self.id) {
+            run_entry(entry);
+        }
+    }
+}
+
+/// Run the actions of all armed guards, newest first
+fn run_all() {
+    run_all_in(&ACTIONS)
+}
+
+/// Run the actions of all entries of `registry`, newest first
+fn run_all_in(registry: &Mutex<Vec<Entry>>) {
+    loop {
+        let Some(entry) = lock(registry).pop() else {
+            break;
+        };
+        run_entry(entry);
+    }
+}
+
+/// Run all cleanup actions and exit on SIGINT or SIGTERM
+pub(crate) fn install_signal_handler(rt: &tokio::runtime::Runtime) -> Result<()> {
+    let _guard = rt.enter();
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    rt.spawn(async move {
+        let signo = tokio::select! {
+            _ = sigint.recv() => libc::SIGINT,
+            _ = sigterm.recv() => libc::SIGTERM,
+        };
+        debug!("Caught signal {signo}, cleaning up");
+        if let Err(e) = tokio::task::spawn_blocking(run_all).await {
+            tracing::warn!("Cleanup failed: {e}");
+        }
+        // Like the default action, but for the exit code
+        std::process::exit(128 + signo);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_cleanup_guard() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let log = Arc::clone(&log);
+            move || log.lock().unwrap().push(name)
+        };
+
+        // Dropped guards run their action, disarmed ones don't
+        drop(CleanupGuard::new("dropped", record("dropped")));
+        CleanupGuard::new("disarmed", record("disarmed")).disarm();
+        assert_eq!(*log.lock().unwrap(), ["dropped"]);
+        log.lock().unwrap().clear();
+
+        // The global registry is shared with tests running in parallel
+        let entry = |id, name| Entry {
+            id,
+            description: name.to_owned(),
+            action: Box::new(record(name)),
+        };
+        let registry = Mutex::new(vec![entry(0, "first"), entry(1, "second")]);
+        run_all_in(&registry);
+        assert_eq!(*log.lock().unwrap(), ["second", "first"]);
+        assert!(lock(&registry).is_empty());
+    }
+
+    #[test]
+    fn test_remove_path() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(td.path().to_owned())?;
+        let file = dir.join("disk.img");
+        std::fs::write(&file, "partial")?;
+        drop(CleanupGuard::remove_path(&file));
+        assert!(!file.exists());
+        // Missing paths are fine
+        drop(CleanupGuard::remove_path(&file));
+        Ok(())
+    }
+}
@@ -1,24 +1,184 @@
 //! Common CLI options shared across commands
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::Context;
+use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+use crate::install_options::SelinuxMode;
+
 pub const DEFAULT_MEMORY_USER_STR: &str = "4G";
 
 /// Memory size options
 #[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MemoryOpts {
+    // Optional so commands can tell when to pick another default
     #[clap(
         long,
-        default_value = DEFAULT_MEMORY_USER_STR,
-        help = "Memory size (e.g. 4G, 2048M, or plain number for MB)"
+        help = const_format::concatcp!(
+            "Memory size (e.g. 4G, 2048M, or plain number for MB; default ",
+            DEFAULT_MEMORY_USER_STR,
+            ")"
+        )
     )]
-    pub memory: String,
+    pub memory: Option<String>,
+}
+
+impl MemoryOpts {
+    /// The memory size, [`DEFAULT_MEMORY_USER_STR`] unless given
+    pub fn memory(&self) -> &str {
+        self.memory.as_deref().unwrap_or(DEFAULT_MEMORY_USER_STR)
+    }
 }
 
 impl fmt::Display for MemoryOpts {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.memory)
+        write!(f, "{}", self.memory())
+    }
+}
+
+/// Limits on the host resources a VM may use
+///
+/// These are enforced by the cgroup of the VM: for ephemeral VMs via the
+/// podman container, for libvirt domains via `<cputune>` and `<blkiotune>`.
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    #[clap(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Limit CPU time in percent of one host CPU (e.g. 150 for 1.5 CPUs)"
+    )]
+    pub cpu_quota: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "WEIGHT",
+        value_parser = clap::value_parser!(u16).range(100..=1000),
+        help = "Relative block I/O weight of the VM (100-1000)"
+    )]
+    pub io_weight: Option<u16>,
+}
+
+impl ResourceLimits {
+    /// Arguments for `podman run` applying these limits
+    pub fn podman_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(quota) = self.cpu_quota {
+            args.push(format!("--cpus={}.{:02}", quota / 100, quota % 100));
+        }
+        if let Some(weight) = self.io_weight {
+            args.push(format!("--blkio-weight={weight}"));
+        }
+        args
+    }
+}
+
+/// Swap space set up in the guest at boot, via systemd credentials
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SwapOpts {
+    #[clap(
+        long,
+        value_name = "SIZE",
+        help = "Set up swap space of this size in the guest (e.g. 4G)"
+    )]
+    pub swap: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "zram|file",
+        default_value = "zram",
+        requires = "swap",
+        help = "Back --swap by compressed guest memory, or by a file in /var"
+    )]
+    #[serde(default)]
+    pub swap_backend: crate::credentials::SwapBackend,
+}
+
+impl SwapOpts {
+    /// Parse the swap size to bytes, if one was given
+    pub fn size(&self) -> Result<Option<u64>> {
+        self.swap
+            .as_deref()
+            .map(crate::utils::parse_size)
+            .transpose()
+            .context("Parsing --swap")
+    }
+}
+
+/// A named set of kernel arguments, for `--karg-profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KargProfile {
+    /// Verbose kernel and systemd logging to the console
+    Debug,
+    /// SELinux in permissive mode, as `--selinux permissive` where available
+    Permissive,
+    /// Only errors on the console
+    Quiet,
+}
+
+impl KargProfile {
+    /// The kernel arguments of this profile
+    pub fn kargs(&self) -> &'static [&'static str] {
+        match self {
+            KargProfile::Debug => &[
+                "loglevel=7",
+                "systemd.log_level=debug",
+                "systemd.log_target=console",
+                "systemd.show_status=true",
+            ],
+            KargProfile::Permissive => SelinuxMode::Permissive.kargs(),
+            KargProfile::Quiet => &["quiet", "loglevel=3", "systemd.show_status=error"],
+        }
+    }
+}
+
+/// The kernel arguments of `profiles` followed by `kargs`, so that explicit
+/// arguments take precedence over those of a profile
+pub fn expand_karg_profiles(profiles: &[KargProfile], kargs: &[String]) -> Vec<String> {
+    profiles
+        .iter()
+        .flat_map(|p| p.kargs().iter().map(|k| k.to_string()))
+        .chain(kargs.iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_karg_profiles() {
+        assert!(expand_karg_profiles(&[], &[]).is_empty());
+        assert_eq!(
+            expand_karg_profiles(
+                &[KargProfile::Permissive, KargProfile::Quiet],
+                &["loglevel=4".to_owned()]
+            ),
+            [
+                "enforcing=0",
+                "quiet",
+                "loglevel=3",
+                "systemd.show_status=error",
+                "loglevel=4"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resource_limits_podman_args() {
+        assert!(ResourceLimits::default().podman_args().is_empty());
+        let limits = ResourceLimits {
+            cpu_quota: Some(150),
+            io_weight: Some(200),
+        };
+        assert_eq!(limits.podman_args(), ["--cpus=1.50", "--blkio-weight=200"]);
+        let limits = ResourceLimits {
+            cpu_quota: Some(5),
+            io_weight: None,
+        };
+        assert_eq!(limits.podman_args(), ["--cpus=0.05"]);
     }
 }
@@ -0,0 +1,164 @@
+//! Shell completion
+//!
+//! Completion is done by bcvk itself: the script printed by `bcvk completion
+//! <shell>` calls back into bcvk with the `COMPLETE` environment variable set,
+//! which is handled by [`clap_complete::CompleteEnv`] before the command line
+//! is parsed. This allows completing the names of existing libvirt domains
+//! and local bootc images, see [`domain_names`] and [`image_names`], which
+//! static completion scripts can't do.
+
+use std::ffi::OsString;
+
+use clap::builder::PossibleValuesParser;
+use clap::{CommandFactory, Parser};
+use clap_complete::engine::CompletionCandidate;
+use clap_complete::env::Shells;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::domain_list::DomainLister;
+
+/// Environment variable through which the shell requests completions
+pub(crate) const COMPLETE_ENV: &str = "COMPLETE";
+
+/// Options for generating a shell completion script
+#[derive(Debug, Parser)]
+pub struct CompletionOpts {
+    /// Shell to generate the completion script for
+    #[clap(value_parser = PossibleValuesParser::new(Shells::builtins().names()))]
+    pub shell: String,
+}
+
+/// Print the completion script for a shell
+pub fn run(opts: CompletionOpts) -> Result<()> {
+    let shells = Shells::builtins();
+    let completer = shells
+        .completer(&opts.shell)
+        .ok_or_else(|| eyre!("Unsupported shell: {}", opts.shell))?;
+    let mut stdout = std::io::stdout().lock();
+    completer
+        .write_registration(COMPLETE_ENV, "bcvk", "bcvk", "bcvk", &mut stdout)
+        .context("Writing completion script")?;
+    Ok(())
+}
+
+/// The `--connect` URI on the command line being completed, if any
+///
+/// The completion script passes the words being completed after `--`.
+fn connect_uri(args: impl Iterator<Item = OsString>) -> Option<String> {
+    let words: Vec<OsString> = args.skip_while(|a| a != "--").skip(1).collect();
+    let matches = crate::config::lenient_matches(crate::Cli::command(), &words)?;
+    let (_, sub) = matches.subcommand()?;
+    sub.try_get_one::<String>("connect").ok().flatten().cloned()
+}
+
+/// Complete the names of libvirt domains created by bcvk
+///
+/// Errors are only logged at debug level, as there is no good way to report
+/// them while completing; there are just no candidates then.
+pub(crate) fn domain_names() -> Vec<CompletionCandidate> {
+    let lister = match connect_uri(std::env::args_os()) {
+        Some(uri) => DomainLister::with_connection(uri),
+        None => DomainLister::new(),
+    };
+    let domains = match lister.list_bootc_domains() {
+        Ok(domains) => domains,
+        Err(e) => {
+            debug!("Listing domains to complete: {e:#}");
+            return Vec::new();
+        }
+    };
+    domains
+        .into_iter()
+        .map(|d| CompletionCandidate::new(d.name).help(Some(d.state.into())))
+        .collect()
+}
+
+/// Complete the names of local bootc container images
+///
+/// Errors are only logged at debug level, see [`domain_names`].
+pub(crate) fn image_names() -> Vec<CompletionCandidate> {
+    let images = match crate::images::list() {
+        Ok(images) => images,
+        Err(e) => {
+            debug!("Listing images to complete: {e:#}");
+            return Vec::new();
+        }
+    };
+    images
+        .into_iter()
+        .flat_map(|i| i.names.unwrap_or_default())
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shells() {
+        let cmd = crate::Cli::command();
+        for shell in ["bash", "zsh", "fish"] {
+            let r = cmd
+                .clone()
+                .try_get_matches_from(["bcvk", "completion", shell]);
+            assert!(r.is_ok(), "{shell}");
+        }
+        assert!(cmd
+            .try_get_matches_from(["bcvk", "completion", "cmd.exe"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_connect_uri() {
+        let cases: &[(&[&str], Option<&str>)] = &[
+            (&["bcvk", "--", "bcvk", "libvirt", "ssh", ""], None),
+            (
+                &[
+                    "bcvk",
+                    "--",
+                    "bcvk",
+                    "libvirt",
+                    "-c",
+                    "qemu:///system",
+                    "ssh",
+                    "",
+                ],
+                Some("qemu:///system"),
+            ),
+            (
+                &[
+                    "bcvk",
+                    "--",
+                    "bcvk",
+                    "libvirt",
+                    "rm",
+                    "--connect=test:///default",
+                    "v",
+                ],
+                Some("test:///default"),
+            ),
+            (&["bcvk", "--", "bcvk", "images", "inspect", ""], None),
+        ];
+        for (args, expected) in cases {
+            let uri = connect_uri(args.iter().map(OsString::from));
+            assert_eq!(uri.as_deref(), *expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_registration() {
+        for shell in Shells::builtins().names() {
+            let mut buf = Vec::new();
+            Shells::builtins()
+                .completer(shell)
+                .unwrap()
+                .write_registration(COMPLETE_ENV, "bcvk", "bcvk", "bcvk", &mut buf)
+                .unwrap();
+            let script = String::from_utf8(buf).unwrap();
+            assert!(script.contains(COMPLETE_ENV), "{shell}");
+        }
+    }
+}
@@ -0,0 +1,630 @@
+//! Multi-VM compose files
+//!
+//! A compose file (`bcvk-compose.yaml` by default) declares a group of
+//! named VMs which are brought up and down together:
+//!
+//! ```yaml
+//! name: cluster
+//! network:
+//!   subnet: 10.0.5.0/24
+//! vms:
+//!   node1:
+//!     image: quay.io/fedora/fedora-bootc:42
+//!     memory: 4G
+//!     ports: ["8080:80"]
+//!     volumes: ["./shared:/mnt/shared"]
+//!   node2:
+//!     image: quay.io/fedora/fedora-bootc:42
+//! ```
+//!
+//! Each VM is created like `bcvk libvirt run` would, named
+//! `<project>-<vm>` and labelled `compose=<project>`. Besides the user-mode
+//! network used for SSH and port forwards, the VMs of a project share a
+//! private (isolated) libvirt network `bcvk-<project>`, on which each VM
+//! has a fixed address and can reach the others as `<vm>.<project>`.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::Ipv4Addr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+
+use crate::domain_list::DomainLister;
+use crate::libvirt::domain::NetworkInterface;
+use crate::libvirt::run::{run_virsh_cmd, LibvirtRunOpts};
+use crate::libvirt::LibvirtOptions;
+use crate::xml_utils::XmlWriter;
+
+/// Compose file used when none is given
+pub const DEFAULT_FILE: &str = "bcvk-compose.yaml";
+
+/// Last octet of the private network address of the first VM
+const FIRST_VM_HOST: u8 = 10;
+
+/// Last octet of the first address handed out to other guests by DHCP
+const DHCP_RANGE_START: u8 = 100;
+
+/// Compose subcommands
+#[derive(Debug, Subcommand)]
+pub enum ComposeCommands {
+    /// Create and start the network and VMs of a compose file
+    Up(ComposeUpOpts),
+
+    /// Remove the VMs and the network of a compose file
+    Down,
+}
+
+/// Options for bringing up the VMs of a compose file
+#[derive(Debug, Parser)]
+pub struct ComposeUpOpts {
+    /// Wait until SSH is available in each created VM
+    #[clap(long)]
+    pub wait: bool,
+
+    /// Make each VM resolvable on the host as PROJECT-VM.bcvk.local, by its private network address
+    #[clap(long)]
+    pub register_dns: bool,
+}
+
+/// The contents of a compose file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ComposeFile {
+    /// Project name, used to name VMs and the network
+    name: Option<String>,
+    /// The private network between the VMs
+    #[serde(default)]
+    network: NetworkConfig,
+    /// The VMs, by name
+    vms: BTreeMap<String, VmConfig>,
+}
+
+/// The private network of a compose file
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct NetworkConfig {
+    /// Subnet as ADDR/24
+    subnet: Option<String>,
+}
+
+/// A VM of a compose file, see `bcvk libvirt run` for the meaning of the fields
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct VmConfig {
+    image: String,
+    memory: Option<String>,
+    cpus: Option<u32>,
+    disk_size: Option<String>,
+    #[serde(default)]
+    ports: Vec<String>,
+    #[serde(default)]
+    volumes: Vec<String>,
+}
+
+/// A loaded compose file
+#[derive(Debug)]
+struct Project {
+    name: String,
+    /// Directory of the compose file, which relative volume paths are relative to
+    dir: Utf8PathBuf,
+    /// Network address of the private network (a /24)
+    subnet: Ipv4Addr,
+    vms: BTreeMap<String, VmConfig>,
+}
+
+/// Names are used in domain, network and host names
+fn validate_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('-')
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(eyre!(
+            "Invalid {kind} name '{name}': only letters, digits and '-' are allowed"
+        ));
+    }
+    Ok(())
+}
+
+/// Parse a subnet given as ADDR/24 into its network address
+fn parse_subnet(subnet: &str) -> Result<Ipv4Addr> {
+    let (addr, prefix) = subnet
+        .split_once('/')
+        .ok_or_else(|| eyre!("Invalid subnet '{subnet}'. Expected ADDR/24, e.g. 10.0.5.0/24"))?;
+    if prefix != "24" {
+        return Err(eyre!(
+            "Invalid subnet '{subnet}': only /24 subnets are supported"
+        ));
+    }
+    let addr: Ipv4Addr = addr
+        .parse()
+        .map_err(|_| eyre!("Invalid subnet address '{addr}'"))?;
+    let [a, b, c, _] = addr.octets();
+    Ok(Ipv4Addr::new(a, b, c, 0))
+}
+
+/// A subnet for projects which don't specify one, stable for a project name
+fn default_subnet(project: &str) -> Ipv4Addr {
+    let hash = project
+        .bytes()
+        .fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(b.into()));
+    Ipv4Addr::new(192, 168, 200 + (hash % 50) as u8, 0)
+}
+
+/// MAC address of the private network interface of the VM with the given host number
+fn mac_address(host: u8) -> String {
+    format!("52:54:00:bc:00:{host:02x}")
+}
+
+/// Whether the output of `virsh net-info` shows an active network
+fn network_active(net_info: &str) -> bool {
+    net_info
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .any(|(key, value)| key.trim() == "Active" && value.trim() == "yes")
+}
+
+impl Project {
+    fn load(path: &Utf8Path) -> Result<Self> {
+        let path = path
+            .canonicalize_utf8()
+            .with_context(|| format!("Failed to find compose file {path}"))?;
+        let content =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read {path}"))?;
+        // A canonical file path always has a parent
+        let dir = path.parent().unwrap_or(Utf8Path::new("/"));
+        Self::parse(&content, dir).with_context(|| format!("Invalid compose file {path}"))
+    }
+
+    fn parse(content: &str, dir: &Utf8Path) -> Result<Self> {
+        let file: ComposeFile = serde_yaml::from_str(content)?;
+        let name = match file.name {
+            Some(name) => name,
+            // Like other compose tools, default to the (sanitized) directory name
+            None => dir
+                .file_name()
+                .unwrap_or("default")
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_lowercase()
+                    } else {
+                        '-'
+                    }
+                })
+                .collect::<String>()
+                .trim_matches('-')
+                .to_owned(),
+        };
+        validate_name("project", &name)?;
+        if file.vms.is_empty() {
+            return Err(eyre!("No VMs defined"));
+        }
+        let max_vms = usize::from(DHCP_RANGE_START - FIRST_VM_HOST);
+        if file.vms.len() > max_vms {
+            return Err(eyre!("At most {max_vms} VMs are supported"));
+        }
+        for vm in file.vms.keys() {
+            validate_name("VM", vm)?;
+        }
+        let subnet = match file.network.subnet.as_deref() {
+            Some(subnet) => parse_subnet(subnet)?,
+            None => default_subnet(&name),
+        };
+        Ok(Self {
+            name,
+            dir: dir.to_owned(),
+            subnet,
+            vms: file.vms,
+        })
+    }
+
+    fn network_name(&self) -> String {
+        format!("bcvk-{}", self.name)
+    }
+
+    fn label(&self) -> String {
+        format!("compose={}", self.name)
+    }
+
+    fn domain_name(&self, vm: &str) -> String {
+        format!("{}-{}", self.name, vm)
+    }
+
+    fn address(&self, host: u8) -> Ipv4Addr {
+        let [a, b, c, _] = self.subnet.octets();
+        Ipv4Addr::new(a, b, c, host)
+    }
+
+    /// The VMs with the host number of their private network address
+    fn hosts(&self) -> impl Iterator<Item = (&str, &VmConfig, u8)> {
+        // Checked in parse() to fit below DHCP_RANGE_START
+        self.vms
+            .iter()
+            .zip(FIRST_VM_HOST..)
+            .map(|((vm, config), host)| (vm.as_str(), config, host))
+    }
+
+    /// The libvirt network XML of the private network
+    fn network_xml(&self) -> Result<String> {
+        let mut writer = XmlWriter::new();
+        writer.start_element("network", &[])?;
+        writer.write_text_element("name", &self.network_name())?;
+        // Resolve the VM names inside the network only
+        writer.write_empty_element("domain", &[("name", &self.name), ("localOnly", "yes")])?;
+        writer.start_element(
+            "ip",
+            &[
+                ("address", &self.address(1).to_string()),
+                ("netmask", "255.255.255.0"),
+            ],
+        )?;
+        writer.start_element("dhcp", &[])?;
+        writer.write_empty_element(
+            "range",
+            &[
+                ("start", &self.address(DHCP_RANGE_START).to_string()),
+                ("end", &self.address(254).to_string()),
+            ],
+        )?;
+        for (vm, _, host) in self.hosts() {
+            writer.write_empty_element(
+                "host",
+                &[
+                    ("mac", &mac_address(host)),
+                    ("name", vm),
+                    ("ip", &self.address(host).to_string()),
+                ],
+            )?;
+        }
+        writer.end_element("dhcp")?;
+        writer.end_element("ip")?;
+        writer.end_element("network")?;
+        writer.into_string()
+    }
+
+    /// Relative host paths of volumes are relative to the compose file
+    fn resolve_volume(&self, volume: &str) -> String {
+        match volume.split_once(':') {
+            Some((host, rest)) if Utf8Path::new(host).is_relative() => {
+                format!("{}:{rest}", self.dir.join(host.trim_start_matches("./")))
+            }
+            _ => volume.to_owned(),
+        }
+    }
+
+    /// The options to create a VM with, as if given to `bcvk libvirt run`
+    fn run_opts(
+        &self,
+        vm: &str,
+        config: &VmConfig,
+        host: u8,
+        up: &ComposeUpOpts,
+    ) -> Result<LibvirtRunOpts> {
+        let mut args = vec![
+            "run".to_owned(),
+            "--name".to_owned(),
+            self.domain_name(vm),
+            "--label".to_owned(),
+            self.label(),
+        ];
+        if let Some(memory) = &config.memory {
+            args.extend(["--memory".to_owned(), memory.clone()]);
+        }
+        if let Some(cpus) = config.cpus {
+            args.extend(["--cpus".to_owned(), cpus.to_string()]);
+        }
+        if let Some(disk_size) = &config.disk_size {
+            args.extend(["--disk-size".to_owned(), disk_size.clone()]);
+        }
+        for port in &config.ports {
+            args.extend(["--port".to_owned(), port.clone()]);
+        }
+        for volume in &config.volumes {
+            args.extend(["--volume".to_owned(), self.resolve_volume(volume)]);
+        }
+        if up.register_dns {
+            args.push("--register-dns".to_owned());
+        }
+        let wait = if up.wait { "ssh" } else { "none" };
+        args.extend(["--wait".to_owned(), wait.to_owned()]);
+        args.push(config.image.clone());
+
+        let mut opts = LibvirtRunOpts::try_parse_from(&args)
+            .with_context(|| format!("Invalid configuration of VM '{vm}'"))?;
+        opts.interfaces.push(NetworkInterface {
+            network: self.network_name(),
+            mac: Some(mac_address(host)),
+        });
+        // The private network is reachable from the host, and unlike the
+        // loopback address distinguishes the VMs
+        opts.dns_address = Some(self.address(host).into());
+        Ok(opts)
+    }
+}
+
+fn lister(global_opts: &LibvirtOptions) -> DomainLister {
+    match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    }
+}
+
+/// The output of `virsh net-info`, or `None` if the network doesn't exist
+fn network_info(global_opts: &LibvirtOptions, name: &str) -> Result<Option<String>> {
+    let output = global_opts
+        .virsh_command()
+        .args(["net-info", name])
+        .output()
+        .with_context(|| format!("Failed to query network {name}"))?;
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Define and start the private network, unless it is already running
+fn ensure_network(global_opts: &LibvirtOptions, project: &Project) -> Result<()> {
+    let connect_uri = global_opts.connect.as_deref();
+    let name = project.network_name();
+    match network_info(global_opts, &name)? {
+        Some(info) if network_active(&info) => return Ok(()),
+        Some(_) => {}
+        None => {
+            println!("Creating network '{name}' ({}/24)...", project.subnet);
+            let mut xml_file = tempfile::NamedTempFile::with_prefix("bcvk-libvirt")?;
+            xml_file
+                .as_file_mut()
+                .write_all(project.network_xml()?.as_bytes())
+                .context("Failed to write network XML")?;
+            let xml_path = xml_file
+                .path()
+                .to_str()
+                .ok_or_else(|| eyre!("Invalid UTF-8 in tempfile"))?;
+            run_virsh_cmd(
+                connect_uri,
+                &["net-define", xml_path],
+                "Failed to define network",
+            )?;
+        }
+    }
+    run_virsh_cmd(
+        connect_uri,
+        &["net-start", &name],
+        "Failed to start network",
+    )
+}
+
+fn up(global_opts: &LibvirtOptions, project: &Project, opts: ComposeUpOpts) -> Result<()> {
+    ensure_network(global_opts, project)?;
+
+    let lister = lister(global_opts);
+    let existing = lister.list_all_domains()?;
+    for (vm, config, host) in project.hosts() {
+        let name = project.domain_name(vm);
+        if existing.contains(&name) {
+            if lister.get_domain_state(&name)? == "running" {
+                println!("VM '{name}' is already running");
+            } else {
+                println!("Starting VM '{name}'...");
+                run_virsh_cmd(
+                    global_opts.connect.as_deref(),
+                    &["start", &name],
+                    &format!("Failed to start VM '{name}'"),
+                )?;
+            }
+            continue;
+        }
+        println!("Creating VM '{name}'...");
+        let run_opts = project.run_opts(vm, config, host, &opts)?;
+        crate::libvirt::run::run(global_opts, run_opts)
+            .with_context(|| format!("Failed to create VM '{name}'"))?;
+    }
+
+    println!("\nProject '{}' is up:", project.name);
+    for (vm, _, host) in project.hosts() {
+        println!(
+            "  {} ({} on {})",
+            project.domain_name(vm),
+            project.address(host),
+            project.network_name()
+        );
+    }
+    Ok(())
+}
+
+fn down(global_opts: &LibvirtOptions, project: &Project) -> Result<()> {
+    // Go by the label, so VMs removed from the file since are removed too
+    let label = project.label();
+    let domains = lister(global_opts)
+        .list_bootc_domains()
+        .context("Failed to list bootc domains from libvirt")?;
+    for domain in domains.iter().filter(|d| d.labels.contains(&label)) {
+        println!("Removing VM '{}'...", domain.name);
+        crate::libvirt::rm::remove_vm_forced(global_opts, &domain.name, true)?;
+    }
+
+    let connect_uri = global_opts.connect.as_deref();
+    let name = project.network_name();
+    if let Some(info) = network_info(global_opts, &name)? {
+        println!("Removing network '{name}'...");
+        if network_active(&info) {
+            run_virsh_cmd(
+                connect_uri,
+                &["net-destroy", &name],
+                "Failed to stop network",
+            )?;
+        }
+        run_virsh_cmd(
+            connect_uri,
+            &["net-undefine", &name],
+            "Failed to remove network",
+        )?;
+    }
+
+    println!("Project '{}' is down", project.name);
+    Ok(())
+}
+
+/// Execute a compose command
+pub fn run(file: &Utf8Path, global_opts: &LibvirtOptions, command: ComposeCommands) -> Result<()> {
+    let project = Project::load(file)?;
+    match command {
+        ComposeCommands::Up(opts) => up(global_opts, &project, opts),
+        ComposeCommands::Down => down(global_opts, &project),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libvirt::run::{PortMapping, VolumeMount, WaitMode};
+
+    const EXAMPLE: &str = r#"
+name: cluster
+network:
+  subnet: 10.0.5.0/24
+vms:
+  node2:
+    image: quay.io/fedora/fedora-bootc:42
+  node1:
+    image: quay.io/fedora/fedora-bootc:42
+    memory: 4G
+    cpus: 4
+    disk-size: 30G
+    ports: ["8080:80"]
+    volumes: ["./shared:/mnt/shared", "/srv/data:/mnt/data:ro"]
+"#;
+
+    #[test]
+    fn test_parse() {
+        let project = Project::parse(EXAMPLE, Utf8Path::new("/home/user/proj")).unwrap();
+        assert_eq!(project.name, "cluster");
+        assert_eq!(project.subnet, Ipv4Addr::new(10, 0, 5, 0));
+        let hosts: Vec<_> = project.hosts().map(|(vm, _, host)| (vm, host)).collect();
+        assert_eq!(hosts, [("node1", 10), ("node2", 11)]);
+
+        // The project name defaults to the directory name
+        let content = "vms:\n  a:\n    image: example\n";
+        let project = Project::parse(content, Utf8Path::new("/src/My_Cluster")).unwrap();
+        assert_eq!(project.name, "my-cluster");
+        assert_eq!(project.subnet, default_subnet("my-cluster"));
+
+        let errors = [
+            ("vms: {}\n", "No VMs defined"),
+            (
+                "name: a_b\nvms:\n  a:\n    image: x\n",
+                "Invalid project name",
+            ),
+            ("vms:\n  a.b:\n    image: x\n", "Invalid VM name"),
+            (
+                "vms:\n  a:\n    image: x\n    memroy: 4G\n",
+                "unknown field",
+            ),
+            ("vms:\n  a:\n    memory: 4G\n", "missing field"),
+            (
+                "network:\n  subnet: 10.0.0.0/16\nvms:\n  a:\n    image: x\n",
+                "only /24",
+            ),
+        ];
+        for (content, msg) in errors {
+            let err = Project::parse(content, Utf8Path::new("/src/proj"))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(msg), "{content}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_parse_subnet() {
+        assert_eq!(
+            parse_subnet("10.0.5.0/24").unwrap(),
+            Ipv4Addr::new(10, 0, 5, 0)
+        );
+        assert_eq!(
+            parse_subnet("10.0.5.7/24").unwrap(),
+            Ipv4Addr::new(10, 0, 5, 0)
+        );
+        assert!(parse_subnet("10.0.5.0").is_err());
+        assert!(parse_subnet("10.0.5/24").is_err());
+        let subnet = default_subnet("cluster");
+        assert_eq!(subnet, default_subnet("cluster"));
+        assert!((200..250).contains(&subnet.octets()[2]));
+    }
+
+    #[test]
+    fn test_network_xml() {
+        let project = Project::parse(EXAMPLE, Utf8Path::new("/home/user/proj")).unwrap();
+        let xml = project.network_xml().unwrap();
+        assert!(xml.contains("<name>bcvk-cluster</name>"));
+        assert!(xml.contains("<ip address=\"10.0.5.1\" netmask=\"255.255.255.0\">"));
+        assert!(xml.contains("<range start=\"10.0.5.100\" end=\"10.0.5.254\"/>"));
+        assert!(xml.contains("<host mac=\"52:54:00:bc:00:0a\" name=\"node1\" ip=\"10.0.5.10\"/>"));
+        assert!(xml.contains("<host mac=\"52:54:00:bc:00:0b\" name=\"node2\" ip=\"10.0.5.11\"/>"));
+        // The network is isolated
+        assert!(!xml.contains("<forward"));
+    }
+
+    #[test]
+    fn test_run_opts() {
+        let project = Project::parse(EXAMPLE, Utf8Path::new("/home/user/proj")).unwrap();
+        let (vm, config, host) = project.hosts().next().unwrap();
+        let up = ComposeUpOpts {
+            wait: true,
+            register_dns: true,
+        };
+        let opts = project.run_opts(vm, config, host, &up).unwrap();
+        assert_eq!(opts.name.as_deref(), Some("cluster-node1"));
+        assert_eq!(
+            opts.image.as_deref(),
+            Some("quay.io/fedora/fedora-bootc:42")
+        );
+        assert_eq!(opts.label, ["compose=cluster"]);
+        assert_eq!(opts.memory.memory(), "4G");
+        assert_eq!(opts.cpus, 4);
+        assert_eq!(opts.disk_size, "30G");
+        assert_eq!(opts.wait, WaitMode::Ssh);
+        assert!(opts.register_dns);
+        assert_eq!(opts.dns_address, Some("10.0.5.10".parse().unwrap()));
+        assert_eq!(
+            opts.port_mappings,
+            ["8080:80".parse::<PortMapping>().unwrap()]
+        );
+        assert_eq!(
+            opts.volumes,
+            [
+                "/home/user/proj/shared:/mnt/shared"
+                    .parse::<VolumeMount>()
+                    .unwrap(),
+                "/srv/data:/mnt/data:ro".parse::<VolumeMount>().unwrap(),
+            ]
+        );
+        assert_eq!(opts.interfaces.len(), 1);
+        assert_eq!(opts.interfaces[0].network, "bcvk-cluster");
+        assert_eq!(opts.interfaces[0].mac.as_deref(), Some("52:54:00:bc:00:0a"));
+
+        let (vm, config, host) = project.hosts().nth(1).unwrap();
+        let up = ComposeUpOpts {
+            wait: false,
+            register_dns: false,
+        };
+        let opts = project.run_opts(vm, config, host, &up).unwrap();
+        assert_eq!(opts.name.as_deref(), Some("cluster-node2"));
+        assert_eq!(opts.disk_size, "20G");
+        assert_eq!(opts.wait, WaitMode::None);
+        assert!(!opts.register_dns);
+    }
+
+    #[test]
+    fn test_network_active() {
+        let info = "Name:           bcvk-cluster\nUUID:           0e2b\nActive:         yes\nPersistent:     yes\nAutostart:      no\nBridge:         virbr1\n";
+        assert!(network_active(info));
+        assert!(!network_active(
+            &info.replace("Active:         yes", "Active:         no")
+        ));
+        assert!(!network_active(""));
+    }
+}
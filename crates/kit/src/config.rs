@@ -0,0 +1,410 @@
+//! User configuration file with profiles of command line defaults
+//!
+//! `~/.config/bcvk/config.toml` contains named profiles:
+//!
+//! ```toml
+//! default-profile = "team"
+//!
+//! [profiles.team]
+//! memory = "8G"
+//! cpus = 4
+//! firmware = "uefi-insecure"
+//! network = "bridge=virbr0"
+//! labels = ["team=storage"]
+//! image = "quay.io/fedora/fedora-bootc:42"
+//! ```
+//!
+//! The profile to use is selected with `--profile`, the `BCVK_PROFILE`
+//! environment variable, or `default-profile`, in that order. Its values
+//! become the *defaults* of the corresponding options before the command
+//! line is parsed, so options given explicitly still take precedence, and
+//! `--help` shows the effective defaults.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use camino::Utf8PathBuf;
+use clap::builder::ArgPredicate;
+use clap::{ArgMatches, Command};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+
+/// Environment variable selecting a profile
+const PROFILE_ENV: &str = "BCVK_PROFILE";
+
+/// Commands whose only positional argument is the container image, which
+/// therefore can default to the profile's image
+const IMAGE_COMMANDS: &[&[&str]] = &[
+    &["ephemeral", "run"],
+    &["images", "verify"],
+    &["libvirt", "run"],
+];
+
+/// Commands creating VMs, to which the profile's labels are added
+const LABEL_COMMANDS: &[&[&str]] = &[
+    &["ephemeral", "run"],
+    &["ephemeral", "run-ssh"],
+    &["libvirt", "run"],
+];
+
+/// The configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Profile to use if none is selected otherwise
+    pub(crate) default_profile: Option<String>,
+    /// Profiles by name
+    #[serde(default)]
+    pub(crate) profiles: BTreeMap<String, Profile>,
+}
+
+/// Defaults for command line options
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Profile {
+    /// Default for `--memory`
+    pub(crate) memory: Option<String>,
+    /// Default for `--cpus` (libvirt) and `--vcpus` (ephemeral VMs)
+    pub(crate) cpus: Option<u32>,
+    /// Default for `--firmware`
+    pub(crate) firmware: Option<String>,
+    /// Default for `--network` of libvirt commands
+    pub(crate) network: Option<String>,
+    /// Default for `--label` of commands creating VMs
+    #[serde(default)]
+    pub(crate) labels: Vec<String>,
+    /// Default container image of commands taking just an image
+    pub(crate) image: Option<String>,
+}
+
+/// Path to the per-user configuration file, unless there is no user config
+/// directory (e.g. `$HOME` is unset)
+fn config_path() -> Result<Option<Utf8PathBuf>> {
+    let Some(config_dir) = dirs::config_dir() else {
+        return Ok(None);
+    };
+    let config_dir = Utf8PathBuf::try_from(config_dir)?;
+    Ok(Some(config_dir.join("bcvk").join("config.toml")))
+}
+
+impl Config {
+    /// Parse a configuration file
+    fn parse(s: &str) -> Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Remove and return the selected profile: `explicit`, the one named by
+    /// `BCVK_PROFILE`, or the default profile, if any.
+    fn take_profile(&mut self, explicit: Option<String>) -> Result<Option<Profile>> {
+        let name = explicit
+            .or_else(|| std::env::var(PROFILE_ENV).ok().filter(|v| !v.is_empty()))
+            .or_else(|| self.default_profile.clone());
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        self.profiles
+            .remove(&name)
+            .map(Some)
+            .ok_or_else(|| eyre!("Profile '{name}' is not defined"))
+    }
+}
+
+/// Load the selected profile from the user configuration file, if any
+///
+/// A missing configuration file or user config directory means there is no
+/// configuration.
+pub(crate) fn load_profile(explicit: Option<String>) -> Result<Option<Profile>> {
+    let Some(path) = config_path()? else {
+        return Config::default().take_profile(explicit);
+    };
+    let mut config = match std::fs::read_to_string(&path) {
+        Ok(s) => Config::parse(&s).with_context(|| format!("Parsing {path}"))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {path}")),
+    };
+    config
+        .take_profile(explicit)
+        .with_context(|| format!("Loading profile from {path}"))
+}
+
+/// Make `cmd` and its subcommands ignore parse errors and requests for help
+fn lenient(cmd: Command) -> Command {
+    let subcommands: Vec<String> = cmd
+        .get_subcommands()
+        .map(|s| s.get_name().to_owned())
+        .collect();
+    let mut cmd = cmd
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_help_subcommand(true)
+        .disable_version_flag(true);
+    for name in subcommands {
+        cmd = cmd.mut_subcommand(name.as_str(), lenient);
+    }
+    cmd
+}
+
+/// Parse `args` leniently with `cmd`, as far as they can be parsed
+///
+/// A missing positional argument or `--help` does not get in the way, but
+/// parsing stops at the first error.
+pub(crate) fn lenient_matches(cmd: Command, args: &[OsString]) -> Option<ArgMatches> {
+    lenient(cmd).try_get_matches_from(args).ok()
+}
+
+/// Find the value of the global `--profile` option of `cmd` in `args`
+///
+/// This has to happen before the actual parsing, as the profile changes the
+/// defaults of options.
+pub(crate) fn profile_arg(cmd: Command, args: &[OsString]) -> Option<String> {
+    let matches = lenient_matches(cmd, args)?;
+    matches.get_one::<String>("profile").cloned()
+}
+
+impl Profile {
+    /// Make the values of this profile the defaults of the options of `cmd`
+    /// and all of its subcommands.
+    pub(crate) fn apply(&self, cmd: Command) -> Command {
+        self.apply_at(cmd, &[])
+    }
+
+    fn apply_at(&self, mut cmd: Command, path: &[&str]) -> Command {
+        let has_arg = |cmd: &Command, id: &str| cmd.get_arguments().any(|a| a.get_id() == id);
+
+        if let Some(memory) = self.memory.as_deref() {
+            if has_arg(&cmd, "memory") {
+                cmd = cmd.mut_arg("memory", |a| a.default_value(memory.to_owned()));
+            }
+        }
+        if let Some(cpus) = self.cpus {
+            for id in ["cpus", "vcpus"] {
+                if has_arg(&cmd, id) {
+                    cmd = cmd.mut_arg(id, |a| a.default_value(cpus.to_string()));
+                }
+            }
+        }
+        if let Some(firmware) = self.firmware.as_deref() {
+            if has_arg(&cmd, "firmware") {
+                cmd = cmd.mut_arg("firmware", |a| a.default_value(firmware.to_owned()));
+            }
+        }
+        // For ephemeral VMs, --network is the podman network
+        if let Some(network) = self.network.as_deref() {
+            if path.first() == Some(&"libvirt") && has_arg(&cmd, "network") {
+                cmd = cmd.mut_arg("network", |a| a.default_value(network.to_owned()));
+            }
+        }
+        if !self.labels.is_empty()
+            && LABEL_COMMANDS.iter().any(|c| *c == path)
+            && has_arg(&cmd, "label")
+        {
+            let labels = self.labels.clone();
+            cmd = cmd.mut_arg("label", |a| a.default_values(labels));
+        }
+        if let Some(image) = self.image.as_deref() {
+            if IMAGE_COMMANDS.iter().any(|c| *c == path) && has_arg(&cmd, "image") {
+                let disk_image = has_arg(&cmd, "disk_image");
+                cmd = cmd.mut_arg("image", |a| {
+                    let a = a.required(false).default_value(image.to_owned());
+                    // A disk image replaces the container image
+                    if disk_image {
+                        a.default_value_if("disk_image", ArgPredicate::IsPresent, None::<&str>)
+                    } else {
+                        a
+                    }
+                });
+            }
+        }
+
+        let subcommands: Vec<String> = cmd
+            .get_subcommands()
+            .map(|s| s.get_name().to_owned())
+            .collect();
+        for name in subcommands {
+            let mut subpath = path.to_vec();
+            subpath.push(name.as_str());
+            cmd = cmd.mut_subcommand(name.as_str(), |sub| self.apply_at(sub, &subpath));
+        }
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    use crate::{Cli, Commands};
+
+    fn parse(profile: &Profile, args: &[&str]) -> Cli {
+        let cmd = profile.apply(Cli::command());
+        let matches = cmd.try_get_matches_from(args).unwrap();
+        Cli::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config = Config::parse(indoc::indoc! {r#"
+            default-profile = "team"
+
+            [profiles.team]
+            memory = "8G"
+            cpus = 4
+            labels = ["team=storage"]
+
+            [profiles.empty]
+        "#})?;
+        assert_eq!(config.default_profile.as_deref(), Some("team"));
+        assert_eq!(config.profiles.len(), 2);
+        let team = &config.profiles["team"];
+        assert_eq!(team.memory.as_deref(), Some("8G"));
+        assert_eq!(team.cpus, Some(4));
+        assert_eq!(team.labels, ["team=storage"]);
+
+        assert!(Config::parse("[profiles.x]\nmemroy = \"8G\"\n").is_err());
+        assert!(Config::parse("[profiles.x]\ncpus = \"four\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_profile() -> Result<()> {
+        let mut config = Config::parse(indoc::indoc! {r#"
+            default-profile = "a"
+            [profiles.a]
+            cpus = 1
+            [profiles.b]
+            cpus = 2
+        "#})?;
+        let b = config.take_profile(Some("b".into()))?.unwrap();
+        assert_eq!(b.cpus, Some(2));
+        assert!(config.take_profile(Some("missing".into())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_arg() {
+        let profile = |v: &[&str]| {
+            let args = v.iter().map(OsString::from).collect::<Vec<_>>();
+            profile_arg(Cli::command(), &args)
+        };
+        let cases: &[(&[&str], Option<&str>)] = &[
+            (&["bcvk", "libvirt", "list"], None),
+            (
+                &["bcvk", "--profile", "team", "libvirt", "list"],
+                Some("team"),
+            ),
+            (
+                &["bcvk", "libvirt", "run", "--profile=team", "img"],
+                Some("team"),
+            ),
+            // The image is missing
+            (
+                &["bcvk", "libvirt", "run", "--profile", "team"],
+                Some("team"),
+            ),
+            (
+                &["bcvk", "--profile", "team", "libvirt", "run", "--help"],
+                Some("team"),
+            ),
+            (
+                &["bcvk", "--profile", "team", "help", "libvirt"],
+                Some("team"),
+            ),
+            // Part of the command run over SSH
+            (
+                &[
+                    "bcvk",
+                    "ephemeral",
+                    "run-ssh",
+                    "img",
+                    "--",
+                    "--profile",
+                    "x",
+                ],
+                None,
+            ),
+            (
+                &[
+                    "bcvk",
+                    "ephemeral",
+                    "run-ssh",
+                    "img",
+                    "ls",
+                    "--profile",
+                    "x",
+                ],
+                None,
+            ),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(profile(args).as_deref(), *expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let profile = Profile {
+            memory: Some("8G".into()),
+            cpus: Some(6),
+            network: Some("bridge=virbr0".into()),
+            labels: vec!["team=storage".into()],
+            image: Some("localhost/default".into()),
+            ..Default::default()
+        };
+
+        let Commands::Libvirt { command, .. } =
+            parse(&profile, &["bcvk", "libvirt", "run"]).command
+        else {
+            panic!("expected libvirt command");
+        };
+        let crate::libvirt::LibvirtSubcommands::Run(opts) = command else {
+            panic!("expected libvirt run");
+        };
+        assert_eq!(opts.memory.memory(), "8G");
+        assert_eq!(opts.cpus, 6);
+        assert_eq!(opts.network, "bridge=virbr0");
+        assert_eq!(opts.label, ["team=storage"]);
+        assert_eq!(opts.image.as_deref(), Some("localhost/default"));
+
+        // Explicit options win
+        let Commands::Libvirt { command, .. } = parse(
+            &profile,
+            &[
+                "bcvk",
+                "libvirt",
+                "run",
+                "--memory",
+                "2G",
+                "--cpus",
+                "1",
+                "--label",
+                "a=b",
+                "localhost/other",
+            ],
+        )
+        .command
+        else {
+            panic!("expected libvirt command");
+        };
+        let crate::libvirt::LibvirtSubcommands::Run(opts) = command else {
+            panic!("expected libvirt run");
+        };
+        assert_eq!(opts.memory.memory(), "2G");
+        assert_eq!(opts.cpus, 1);
+        assert_eq!(opts.label, ["a=b"]);
+        assert_eq!(opts.image.as_deref(), Some("localhost/other"));
+
+        // The podman network of ephemeral VMs is not affected
+        let Commands::Ephemeral(crate::ephemeral::EphemeralCommands::Run(opts)) =
+            parse(&profile, &["bcvk", "ephemeral", "run"]).command
+        else {
+            panic!("expected ephemeral run");
+        };
+        assert_eq!(opts.image, "localhost/default");
+        assert_eq!(opts.common.memory.memory(), "8G");
+        assert_eq!(opts.common.vcpus, Some(6));
+        assert_eq!(opts.podman.network, None);
+        assert_eq!(opts.podman.label, ["team=storage"]);
+    }
+}
@@ -1,10 +1,14 @@
 use clap::{Parser, Subcommand};
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use tokio::signal::unix::SignalKind;
 use tracing::debug;
 
 use crate::run_ephemeral::RunEphemeralOpts;
 
+/// Smallest memory a VM can be shrunk to, the same as the minimum for booting
+const MIN_MEMORY_MB: u32 = 128;
+
 #[derive(Parser)]
 pub struct ContainerEntrypointOpts {
     #[command(subcommand)]
@@ -21,6 +25,21 @@ pub enum ContainerCommands {
 
     /// Monitor VM status file using inotify
     MonitorStatus(MonitorStatusOpts),
+
+    /// Save the VM state to a checkpoint
+    Checkpoint(crate::checkpoint::ContainerCheckpointOpts),
+
+    /// Shut down the VM gracefully
+    Shutdown(ShutdownOpts),
+
+    /// Set the memory balloon target of the VM
+    SetMemory(SetMemoryOpts),
+
+    /// Run a command in the VM via its vsock agent
+    VsockExec(crate::vsock_exec::ContainerVsockExecOpts),
+
+    /// Check the health of the VM, for the podman health check
+    Health,
 }
 
 #[derive(Parser)]
@@ -33,6 +52,19 @@ pub struct SshOpts {
 #[derive(Parser)]
 pub struct MonitorStatusOpts {}
 
+#[derive(Parser)]
+pub struct ShutdownOpts {
+    /// Seconds to wait for the guest to shut down
+    #[clap(long, default_value = "60")]
+    pub timeout: u64,
+}
+
+#[derive(Parser)]
+pub struct SetMemoryOpts {
+    /// Memory of the guest in MB
+    pub memory_mb: u32,
+}
+
 pub async fn run_ephemeral_in_container() -> Result<()> {
     // Parse BCK_CONFIG from environment
     let config_json = std::env::var("BCK_CONFIG")?;
@@ -77,6 +109,31 @@ pub fn monitor_status(_opts: MonitorStatusOpts) -> Result<()> {
     crate::status_monitor::monitor_and_stream_status()
 }
 
+pub fn shutdown_vm(opts: ShutdownOpts) -> Result<()> {
+    let socket = camino::Utf8Path::new(crate::run_ephemeral::QMP_SOCKET);
+    let mut qmp = crate::qmp::QmpClient::connect(socket)?;
+    debug!("Requesting guest shutdown");
+    qmp.powerdown_and_wait(std::time::Duration::from_secs(opts.timeout))
+}
+
+pub fn set_memory(opts: SetMemoryOpts) -> Result<()> {
+    let config_json = std::env::var("BCK_CONFIG")?;
+    let run_opts: RunEphemeralOpts = serde_json::from_str(&config_json)?;
+    let Some(max) = run_opts.common.memory_max_mb()? else {
+        return Err(eyre!("The VM was not started with --memory-max"));
+    };
+    if !(MIN_MEMORY_MB..=max).contains(&opts.memory_mb) {
+        return Err(eyre!(
+            "Memory must be between {MIN_MEMORY_MB}MB and the VM's maximum of {max}MB, not {}MB",
+            opts.memory_mb
+        ));
+    }
+    let socket = camino::Utf8Path::new(crate::run_ephemeral::QMP_SOCKET);
+    let mut qmp = crate::qmp::QmpClient::connect(socket)?;
+    debug!("Setting balloon target to {}MB", opts.memory_mb);
+    qmp.balloon(opts.memory_mb)
+}
+
 pub async fn run(opts: ContainerEntrypointOpts) -> Result<()> {
     let signals = [libc::SIGTERM, libc::SIGINT, libc::SIGRTMIN() + 3];
     let mut signal_joinset = tokio::task::JoinSet::new();
@@ -102,6 +159,27 @@ pub async fn run(opts: ContainerEntrypointOpts) -> Result<()> {
                 ContainerCommands::MonitorStatus(monitor_opts) => {
                     tokio::task::spawn_blocking(move || monitor_status(monitor_opts)).await?
                 }
+                ContainerCommands::Shutdown(shutdown_opts) => {
+                    tokio::task::spawn_blocking(move || shutdown_vm(shutdown_opts)).await?
+                }
+                ContainerCommands::SetMemory(set_memory_opts) => {
+                    tokio::task::spawn_blocking(move || set_memory(set_memory_opts)).await?
+                }
+                ContainerCommands::Health => {
+                    tokio::task::spawn_blocking(crate::qemu_watchdog::health).await?
+                }
+                ContainerCommands::VsockExec(exec_opts) => {
+                    tokio::task::spawn_blocking(move || {
+                        crate::vsock_exec::run_in_container(exec_opts)
+                    })
+                    .await?
+                }
+                ContainerCommands::Checkpoint(checkpoint_opts) => {
+                    tokio::task::spawn_blocking(move || {
+                        crate::checkpoint::checkpoint_in_container(checkpoint_opts)
+                    })
+                    .await?
+                }
             }
         } => r
     }
@@ -3,11 +3,11 @@
 //! This module provides functionality to list libvirt domains created by bcvk libvirt,
 //! using libvirt as the source of truth instead of the VmRegistry cache.
 
+use crate::hostexec::HostCommand;
+use crate::libvirt::domain_metadata::DomainMetadata;
 use crate::xml_utils;
-use base64::Engine;
 use color_eyre::{eyre::Context, Result};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
 use std::time::SystemTime;
 
 /// Information about a podman-bootc domain from libvirt
@@ -19,6 +19,8 @@ pub struct PodmanBootcDomain {
     pub state: String,
     /// Container image used to create the domain
     pub image: Option<String>,
+    /// Digest of the container image the domain's disk was installed from
+    pub image_digest: Option<String>,
     /// Domain creation timestamp (if available)
     pub created: Option<SystemTime>,
     /// Memory allocation in MB
@@ -35,6 +37,9 @@ pub struct PodmanBootcDomain {
     pub has_ssh_key: bool,
     /// SSH private key (available only when outputting JSON)
     pub ssh_private_key: Option<String>,
+    /// File containing the SSH private key, unless it is embedded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_private_key_file: Option<String>,
 }
 
 impl PodmanBootcDomain {
@@ -87,8 +92,8 @@ impl DomainLister {
     }
 
     /// Build a virsh command with optional connection URI
-    fn virsh_command(&self) -> Command {
-        let mut cmd = Command::new("virsh");
+    fn virsh_command(&self) -> HostCommand {
+        let mut cmd = HostCommand::new("virsh");
         if let Some(ref uri) = self.connect_uri {
             cmd.arg("-c").arg(uri);
         }
@@ -146,82 +151,15 @@ impl DomainLister {
             .context(format!("Failed to get XML for domain '{}'", domain_name))
     }
 
-    /// Extract podman-bootc metadata from parsed domain XML
-    fn extract_podman_bootc_metadata(
+    /// Check if a domain was created by bcvk libvirt
+    pub(crate) fn is_podman_bootc_domain(
         &self,
+        _domain_name: &str,
         dom: &xml_utils::XmlNode,
-    ) -> Result<Option<PodmanBootcDomainMetadata>> {
-        // Look for bootc metadata in the XML
-        // This could be in various forms:
-        // 1. <bootc:source-image> in metadata section
-        // 2. Domain name pattern (created by bcvk libvirt)
-        // 3. Domain description containing bcvk signature
-
-        // Try to extract source image from bootc metadata
-        let source_image = dom
-            .find("bootc:source-image")
-            .or_else(|| dom.find("source-image"))
-            .map(|node| node.text_content().to_string());
-
-        // Extract other metadata
-        let created = dom
-            .find("bootc:created")
-            .or_else(|| dom.find("created"))
-            .map(|node| node.text_content().to_string());
-
-        // Extract labels (comma-separated)
-        let labels = dom
-            .find("bootc:label")
-            .or_else(|| dom.find("label"))
-            .map(|node| {
-                node.text_content()
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        // Extract memory and vcpu from domain XML
-        let memory_mb = dom
-            .find("memory")
-            .and_then(|node| crate::libvirt::parse_memory_mb(node));
-
-        let vcpus = dom
-            .find("vcpu")
-            .and_then(|node| node.text_content().parse::<u32>().ok());
-
-        // Extract disk path from first disk device
-        let disk_path = extract_disk_path(&dom);
-
-        // Extract SSH port
-        let ssh_port = dom
-            .find_with_namespace("ssh-port")
-            .and_then(|node| node.text_content().parse::<u16>().ok());
-
-        // Extract SSH private key (either base64 or legacy format)
-        let ssh_private_key = extract_ssh_private_key(dom);
-        let has_ssh_key = ssh_private_key.is_some();
-
-        Ok(Some(PodmanBootcDomainMetadata {
-            source_image,
-            created,
-            memory_mb,
-            vcpus,
-            disk_path,
-            labels,
-            ssh_port,
-            has_ssh_key,
-            ssh_private_key,
-        }))
-    }
-
-    /// Check if a domain was created by bcvk libvirt
-    fn is_podman_bootc_domain(&self, _domain_name: &str, dom: &xml_utils::XmlNode) -> bool {
-        // Only use XML metadata - domains created by bcvk libvirt should have bootc metadata
-        dom.find("bootc:source-image").is_some()
-            || dom.find("source-image").is_some()
-            || dom.find("bootc:container").is_some()
+    ) -> bool {
+        // Only use XML metadata - domains created by bcvk libvirt should have bootc metadata;
+        // invalid metadata is reported when getting the domain info
+        !matches!(DomainMetadata::from_dom(dom), Ok(None))
     }
 
     /// Get detailed information about a domain with pre-parsed XML
@@ -231,23 +169,32 @@ impl DomainLister {
         dom: &xml_utils::XmlNode,
     ) -> Result<PodmanBootcDomain> {
         let state = self.get_domain_state(domain_name)?;
-        let metadata = self.extract_podman_bootc_metadata(dom)?;
+        let metadata = DomainMetadata::from_dom(dom)
+            .with_context(|| format!("Invalid bcvk metadata of domain '{domain_name}'"))?
+            .unwrap_or_default();
 
         Ok(PodmanBootcDomain {
             name: domain_name.to_string(),
             state,
-            image: metadata.as_ref().and_then(|m| m.source_image.clone()),
+            image: metadata.source_image,
+            image_digest: metadata.image_digest,
             created: None, // TODO: Parse created timestamp
-            memory_mb: metadata.as_ref().and_then(|m| m.memory_mb),
-            vcpus: metadata.as_ref().and_then(|m| m.vcpus),
-            disk_path: metadata.as_ref().and_then(|m| m.disk_path.clone()),
-            labels: metadata
-                .as_ref()
-                .map(|m| m.labels.clone())
+            // The current values, which may have been changed since creation
+            memory_mb: dom
+                .find("memory")
+                .and_then(|node| crate::libvirt::parse_memory_mb(node)),
+            vcpus: dom
+                .find("vcpu")
+                .and_then(|node| node.text_content().parse::<u32>().ok()),
+            disk_path: extract_disk_path(dom),
+            labels: metadata.labels,
+            ssh_port: metadata.ssh_port,
+            has_ssh_key: metadata.ssh_private_key.is_some()
+                || metadata.ssh_private_key_file.is_some(),
+            // A key file of another user is not readable, which is not an error here
+            ssh_private_key: crate::libvirt::ssh_keys::load(&metadata, domain_name)
                 .unwrap_or_default(),
-            ssh_port: metadata.as_ref().and_then(|m| m.ssh_port),
-            has_ssh_key: metadata.as_ref().map(|m| m.has_ssh_key).unwrap_or(false),
-            ssh_private_key: metadata.as_ref().and_then(|m| m.ssh_private_key.clone()),
+            ssh_private_key_file: metadata.ssh_private_key_file,
         })
     }
 
@@ -300,21 +247,6 @@ impl DomainLister {
     }
 }
 
-/// Internal structure for extracting metadata
-#[derive(Debug)]
-struct PodmanBootcDomainMetadata {
-    source_image: Option<String>,
-    #[allow(dead_code)]
-    created: Option<String>,
-    memory_mb: Option<u32>,
-    vcpus: Option<u32>,
-    disk_path: Option<String>,
-    labels: Vec<String>,
-    ssh_port: Option<u16>,
-    has_ssh_key: bool,
-    ssh_private_key: Option<String>,
-}
-
 /// Extract disk path from domain XML using DOM parser
 fn extract_disk_path(dom: &xml_utils::XmlNode) -> Option<String> {
     // Look for first disk device with type="file"
@@ -344,24 +276,6 @@ fn find_disk_with_file_type(node: &xml_utils::XmlNode) -> Option<&xml_utils::Xml
     None
 }
 
-/// Extract SSH private key from domain XML, handling both base64 and legacy formats
-fn extract_ssh_private_key(dom: &xml_utils::XmlNode) -> Option<String> {
-    if let Some(encoded_key_node) = dom.find_with_namespace("ssh-private-key-base64") {
-        let encoded_key = encoded_key_node.text_content();
-        // Strip whitespace/newlines from base64 before decoding
-        let encoded_key_clean: String =
-            encoded_key.chars().filter(|c| !c.is_whitespace()).collect();
-        // Decode base64 encoded private key
-        base64::engine::general_purpose::STANDARD
-            .decode(encoded_key_clean.as_bytes())
-            .ok()
-            .and_then(|decoded_bytes| String::from_utf8(decoded_bytes).ok())
-    } else {
-        dom.find_with_namespace("ssh-private-key")
-            .map(|node| node.text_content().to_string())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -427,6 +341,7 @@ mod tests {
             name: "test".to_string(),
             state: "running".to_string(),
             image: None,
+            image_digest: None,
             created: None,
             memory_mb: None,
             vcpus: None,
@@ -435,6 +350,7 @@ mod tests {
             ssh_port: None,
             has_ssh_key: false,
             ssh_private_key: None,
+            ssh_private_key_file: None,
         };
 
         assert!(domain.is_running());
@@ -445,6 +361,7 @@ mod tests {
             name: "test".to_string(),
             state: "shut off".to_string(),
             image: None,
+            image_digest: None,
             created: None,
             memory_mb: None,
             vcpus: None,
@@ -453,6 +370,7 @@ mod tests {
             ssh_port: None,
             has_ssh_key: false,
             ssh_private_key: None,
+            ssh_private_key_file: None,
         };
 
         assert!(!stopped_domain.is_running());
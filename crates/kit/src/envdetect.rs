@@ -0,0 +1,544 @@
+//! Detection of the host environment
+//!
+//! QEMU needs read-write access to /dev/kvm for hardware virtualization,
+//! which nested or unprivileged CI environments often lack. This is checked
+//! on the host before the container is started, so that `--accel auto` can
+//! fall back to TCG software emulation, and `--accel kvm` fails early with
+//! a clear error instead of somewhere in the container.
+//!
+//! Running bcvk itself inside an unprivileged container (e.g. a CI job
+//! without `--privileged`) takes away more: such containers mask parts of
+//! /proc, which prevents mounting a new /proc for the PID namespace of the
+//! VM container, and their overlay root filesystem cannot hold native
+//! overlay mounts of container storage. [`HostCapabilities`] detects this,
+//! so that ephemeral VMs can run in unprivileged mode, sharing the PID
+//! namespace and /proc of their container and mounting storage with
+//! fuse-overlayfs, and `bcvk ephemeral check-host` reports precisely what is
+//! missing.
+
+use std::fs::OpenOptions;
+
+use camino::Utf8PathBuf;
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::eyre;
+use color_eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::qemu::Accel;
+
+/// Path of the KVM device
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Path of the FUSE device, needed by fuse-overlayfs
+const FUSE_DEVICE: &str = "/dev/fuse";
+
+/// Files container engines create in their containers
+const CONTAINER_MARKERS: &[&str] = &["/run/.containerenv", "/.dockerenv"];
+
+/// Maximum number of user namespaces; 0 if they are disabled
+const MAX_USER_NAMESPACES: &str = "/proc/sys/user/max_user_namespaces";
+
+/// Mounts under /proc which do not prevent mounting a new /proc, as they
+/// are on empty directories
+const PROC_ALLOWED_SUBMOUNTS: &[&str] = &["/proc/sys/fs/binfmt_misc"];
+
+/// Storage option making podman mount overlay storage with a FUSE program
+const MOUNT_PROGRAM_OPTION: &str = "overlay.mount_program";
+
+/// Environment variable telling the entrypoint script of the VM container
+/// to run in unprivileged mode
+pub(crate) const UNPRIVILEGED_ENV: &str = "BCVK_UNPRIVILEGED";
+
+/// Memory for VMs using TCG unless configured otherwise; emulation is
+/// slow enough without making the guest touch more memory
+pub(crate) const TCG_DEFAULT_MEMORY: &str = "2G";
+
+/// Accelerator selection on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccelMode {
+    /// KVM if /dev/kvm is accessible, TCG otherwise
+    #[default]
+    Auto,
+    /// KVM, failing if /dev/kvm is not accessible
+    Kvm,
+    /// TCG software emulation; very slow, but works anywhere
+    Tcg,
+}
+
+impl AccelMode {
+    /// The accelerator to run QEMU with, `auto` meaning KVM
+    pub(crate) fn accel(self) -> Accel {
+        match self {
+            AccelMode::Auto | AccelMode::Kvm => Accel::Kvm,
+            AccelMode::Tcg => Accel::Tcg,
+        }
+    }
+}
+
+/// Whether to run the VM container in unprivileged mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnprivilegedMode {
+    /// Unprivileged mode if bcvk runs in a container which masks /proc
+    #[default]
+    Auto,
+    /// Always unprivileged mode
+    Always,
+    /// Never unprivileged mode
+    Never,
+}
+
+/// Whether `device` can be opened read-write
+fn device_accessible(device: &str) -> bool {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .is_ok()
+}
+
+/// Whether /dev/kvm can be opened read-write
+pub(crate) fn kvm_accessible() -> bool {
+    device_accessible(KVM_DEVICE)
+}
+
+/// Whether a new /proc can be mounted, given /proc/self/mountinfo
+///
+/// The kernel only allows mounting /proc in a user namespace if an existing
+/// /proc mount is fully visible, i.e. has nothing mounted over its files,
+/// which container engines do to mask e.g. /proc/kcore.
+fn proc_fully_visible(mountinfo: &str) -> bool {
+    !mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mountpoint| {
+            mountpoint.starts_with("/proc/") && !PROC_ALLOWED_SUBMOUNTS.contains(&mountpoint)
+        })
+}
+
+/// What the environment bcvk runs in allows
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HostCapabilities {
+    /// Whether bcvk runs inside a container
+    pub in_container: bool,
+    /// Whether /dev/kvm is accessible
+    pub kvm: bool,
+    /// Whether /dev/vhost-vsock is accessible
+    pub vhost_vsock: bool,
+    /// Whether user namespaces, needed for the nested containers, are enabled
+    pub user_namespaces: bool,
+    /// Whether a new /proc can be mounted; `None` if /proc/self/mountinfo
+    /// is unreadable
+    pub proc_mount: Option<bool>,
+    /// Whether /dev/fuse is accessible
+    pub fuse: bool,
+    /// Path of fuse-overlayfs, if installed
+    pub fuse_overlayfs: Option<Utf8PathBuf>,
+    /// Storage driver of podman; `None` if podman is unusable, or was not
+    /// queried
+    pub storage_driver: Option<String>,
+    /// Whether podman storage is already mounted with a FUSE program
+    pub storage_mount_program: bool,
+}
+
+impl HostCapabilities {
+    /// Detect the capabilities of this environment
+    pub(crate) fn detect() -> Self {
+        let caps = Self::detect_local().with_storage();
+        debug!("Host capabilities: {caps:?}");
+        caps
+    }
+
+    /// Detect the capabilities of this environment if the VM container is
+    /// to run in unprivileged `mode`; podman is only queried then
+    pub(crate) fn detect_unprivileged(mode: UnprivilegedMode) -> Option<Self> {
+        if mode == UnprivilegedMode::Never {
+            return None;
+        }
+        let caps = Self::detect_local();
+        if !caps.unprivileged(mode) {
+            debug!("Not running unprivileged: {caps:?}");
+            return None;
+        }
+        let caps = caps.with_storage();
+        debug!("Host capabilities: {caps:?}");
+        Some(caps)
+    }
+
+    /// Detect what can be seen without running podman, leaving the storage
+    /// fields unset
+    fn detect_local() -> Self {
+        let proc_mount = match std::fs::read_to_string("/proc/self/mountinfo") {
+            Ok(mountinfo) => Some(proc_fully_visible(&mountinfo)),
+            Err(e) => {
+                warn!(
+                    "Failed to read /proc/self/mountinfo, cannot tell whether /proc is masked: {e}"
+                );
+                None
+            }
+        };
+        let user_namespaces = std::fs::read_to_string(MAX_USER_NAMESPACES)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .is_some_and(|max| max > 0);
+        Self {
+            in_container: CONTAINER_MARKERS
+                .iter()
+                .any(|p| std::path::Path::new(p).exists()),
+            kvm: kvm_accessible(),
+            vhost_vsock: device_accessible(crate::qemu::VHOST_VSOCK),
+            user_namespaces,
+            proc_mount,
+            fuse: device_accessible(FUSE_DEVICE),
+            fuse_overlayfs: which::which("fuse-overlayfs")
+                .ok()
+                .and_then(|p| Utf8PathBuf::from_path_buf(p).ok()),
+            storage_driver: None,
+            storage_mount_program: false,
+        }
+    }
+
+    /// Fill in the storage fields from `podman system info`
+    fn with_storage(self) -> Self {
+        let store = crate::podman::get_system_info()
+            .inspect_err(|e| debug!("Querying podman storage: {e}"))
+            .ok()
+            .map(|info| info.store);
+        Self {
+            storage_mount_program: store
+                .as_ref()
+                .is_some_and(|s| s.graph_options.contains_key(MOUNT_PROGRAM_OPTION)),
+            storage_driver: store.map(|s| s.graph_driver_name),
+            ..self
+        }
+    }
+
+    /// Whether to run the VM container in unprivileged mode
+    ///
+    /// In a container whose /proc could not be inspected, unprivileged mode
+    /// is picked, as it works whether /proc is masked or not.
+    pub(crate) fn unprivileged(&self, mode: UnprivilegedMode) -> bool {
+        match mode {
+            UnprivilegedMode::Auto => self.in_container && self.proc_mount != Some(true),
+            UnprivilegedMode::Always => true,
+            UnprivilegedMode::Never => false,
+        }
+    }
+
+    /// The podman storage option mounting overlay storage with
+    /// fuse-overlayfs in unprivileged mode, if needed and possible
+    pub(crate) fn storage_option(&self) -> Option<String> {
+        if self.storage_driver.as_deref() != Some("overlay")
+            || self.storage_mount_program
+            || !self.fuse
+        {
+            return None;
+        }
+        let program = self.fuse_overlayfs.as_ref()?;
+        Some(format!("--storage-opt={MOUNT_PROGRAM_OPTION}={program}"))
+    }
+
+    /// What prevents running ephemeral VMs at all
+    pub(crate) fn blockers(&self) -> Vec<String> {
+        let mut blockers = Vec::new();
+        if self.storage_driver.is_none() {
+            blockers.push("podman is not usable (podman system info failed)".to_owned());
+        }
+        if !self.user_namespaces {
+            blockers.push(format!(
+                "user namespaces are disabled ({MAX_USER_NAMESPACES} is 0), so the nested containers of the VM cannot be created"
+            ));
+        }
+        blockers
+    }
+
+    /// The checks making up the report of `bcvk ephemeral check-host`, for
+    /// running in unprivileged mode or not
+    fn checks(&self, unprivileged: bool) -> Vec<Check> {
+        let check = |name, ok, detail: String| Check { name, ok, detail };
+        let storage = match &self.storage_driver {
+            Some(driver) => check("podman", true, format!("storage driver {driver}")),
+            None => check(
+                "podman",
+                false,
+                "podman system info failed; ephemeral VMs cannot run".to_owned(),
+            ),
+        };
+        let fuse_storage = if !unprivileged || self.storage_driver.as_deref() != Some("overlay") {
+            check("fuse-overlayfs", true, "not needed".to_owned())
+        } else if self.storage_mount_program {
+            check(
+                "fuse-overlayfs",
+                true,
+                "storage is already mounted with a FUSE program".to_owned(),
+            )
+        } else if let Some(option) = self.storage_option() {
+            check(
+                "fuse-overlayfs",
+                true,
+                format!("storage is mounted with {option}"),
+            )
+        } else {
+            let missing = if self.fuse_overlayfs.is_none() {
+                "fuse-overlayfs is not installed"
+            } else {
+                "it needs /dev/fuse, which is not accessible"
+            };
+            check(
+                "fuse-overlayfs",
+                false,
+                format!(
+                    "overlay storage is mounted natively, which fails on an overlay root filesystem, as {missing}; pass --device {FUSE_DEVICE} to the container"
+                ),
+            )
+        };
+        vec![
+            storage,
+            check(
+                "user-namespaces",
+                self.user_namespaces,
+                if self.user_namespaces {
+                    "enabled".to_owned()
+                } else {
+                    format!("disabled; set {MAX_USER_NAMESPACES} above 0, as ephemeral VMs cannot run without")
+                },
+            ),
+            check(
+                "kvm",
+                self.kvm,
+                if self.kvm {
+                    format!("{KVM_DEVICE} is accessible")
+                } else {
+                    format!("{KVM_DEVICE} is not accessible, so VMs use TCG software emulation, which is much slower; pass --device {KVM_DEVICE} to the container")
+                },
+            ),
+            check(
+                "vhost-vsock",
+                self.vhost_vsock,
+                if self.vhost_vsock {
+                    format!("{} is accessible", crate::qemu::VHOST_VSOCK)
+                } else {
+                    format!(
+                        "{0} is not accessible, so --vsock-exec cannot be used; pass --device {0} to the container",
+                        crate::qemu::VHOST_VSOCK
+                    )
+                },
+            ),
+            check(
+                "proc-mount",
+                self.proc_mount == Some(true) || unprivileged,
+                match (self.proc_mount, unprivileged) {
+                    (Some(true), _) => "a new /proc can be mounted".to_owned(),
+                    (Some(false), true) => "/proc is partially masked, so VM containers share their PID namespace and /proc with their container".to_owned(),
+                    (Some(false), false) => "/proc is partially masked, so VM containers fail to mount a new /proc; use --unprivileged always".to_owned(),
+                    (None, true) => "/proc/self/mountinfo is unreadable, so VM containers share their PID namespace and /proc with their container".to_owned(),
+                    (None, false) => "/proc/self/mountinfo is unreadable, so VM containers may fail to mount a new /proc; use --unprivileged always if they do".to_owned(),
+                },
+            ),
+            fuse_storage,
+        ]
+    }
+}
+
+/// The result of checking one capability
+#[derive(Debug, Serialize)]
+struct Check {
+    /// What was checked
+    name: &'static str,
+    /// Whether it is available, or not needed
+    ok: bool,
+    /// What was found, and what it means for ephemeral VMs
+    detail: String,
+}
+
+/// Report which capabilities for ephemeral VMs this environment has
+#[derive(Debug, Parser)]
+pub struct CheckHostOpts {
+    /// Which mode to check for; auto as ephemeral VMs would pick
+    #[clap(long, value_enum, default_value_t = UnprivilegedMode::Auto)]
+    pub unprivileged: UnprivilegedMode,
+
+    /// Output the report as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// The report of `bcvk ephemeral check-host`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CheckHostReport {
+    in_container: bool,
+    unprivileged: bool,
+    checks: Vec<Check>,
+}
+
+/// Print the capabilities of this environment, failing if ephemeral VMs
+/// cannot run at all
+pub(crate) fn check_host(opts: CheckHostOpts) -> Result<()> {
+    let caps = HostCapabilities::detect();
+    let unprivileged = caps.unprivileged(opts.unprivileged);
+    let report = CheckHostReport {
+        in_container: caps.in_container,
+        unprivileged,
+        checks: caps.checks(unprivileged),
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        let environment = if caps.in_container {
+            "container"
+        } else {
+            "host"
+        };
+        let mode = if unprivileged {
+            "unprivileged"
+        } else {
+            "default"
+        };
+        println!("Environment: {environment}, {mode} mode");
+        for check in &report.checks {
+            let status = if check.ok { "ok" } else { "MISSING" };
+            println!("  {:<16} {status:<8} {}", check.name, check.detail);
+        }
+    }
+    let blockers = caps.blockers();
+    if !blockers.is_empty() {
+        return Err(eyre!(
+            "Ephemeral VMs cannot run here: {}",
+            blockers.join("; ")
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve `mode` to `kvm` or `tcg`, given whether KVM is accessible
+fn resolve(mode: AccelMode, kvm_ok: bool) -> Result<AccelMode> {
+    match mode {
+        AccelMode::Kvm if !kvm_ok => Err(Report::new(crate::Failure::HypervisorUnavailable)
+            .wrap_err(format!(
+                "{KVM_DEVICE} is not accessible, but --accel kvm was given"
+            ))),
+        AccelMode::Auto if kvm_ok => Ok(AccelMode::Kvm),
+        AccelMode::Auto => Ok(AccelMode::Tcg),
+        mode => Ok(mode),
+    }
+}
+
+/// Resolve `mode` for this host, warning loudly when falling back to TCG
+pub(crate) fn resolve_accel(mode: AccelMode) -> Result<AccelMode> {
+    let resolved = resolve(mode, kvm_accessible())?;
+    if mode == AccelMode::Auto && resolved == AccelMode::Tcg {
+        eprintln!(
+            "WARNING: {KVM_DEVICE} is not accessible, falling back to TCG software emulation."
+        );
+        eprintln!(
+            "WARNING: The VM will be much slower; use --accel kvm to fail instead, or --accel tcg to silence this."
+        );
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(AccelMode::Auto, true).unwrap(), AccelMode::Kvm);
+        assert_eq!(resolve(AccelMode::Auto, false).unwrap(), AccelMode::Tcg);
+        assert_eq!(resolve(AccelMode::Kvm, true).unwrap(), AccelMode::Kvm);
+        let err = resolve(AccelMode::Kvm, false).unwrap_err();
+        assert_eq!(
+            crate::Failure::of(&err),
+            Some(crate::Failure::HypervisorUnavailable)
+        );
+        assert_eq!(resolve(AccelMode::Tcg, true).unwrap(), AccelMode::Tcg);
+        assert_eq!(resolve(AccelMode::Tcg, false).unwrap(), AccelMode::Tcg);
+    }
+
+    #[test]
+    fn test_proc_fully_visible() {
+        let host = "22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+35 22 0:32 / /proc/sys/fs/binfmt_misc rw,relatime shared:13 - autofs systemd-1 rw
+";
+        let container = "612 590 0:52 / /proc rw,nosuid,nodev,noexec,relatime - proc proc rw
+615 612 0:5 /null /proc/kcore rw,nosuid - devtmpfs devtmpfs rw
+616 612 0:53 / /proc/acpi ro,relatime - tmpfs tmpfs ro
+";
+        assert!(proc_fully_visible(host));
+        assert!(!proc_fully_visible(container));
+        assert!(proc_fully_visible(""));
+    }
+
+    #[test]
+    fn test_unprivileged() {
+        let caps = HostCapabilities {
+            in_container: true,
+            kvm: false,
+            vhost_vsock: false,
+            user_namespaces: true,
+            proc_mount: Some(false),
+            fuse: true,
+            fuse_overlayfs: Some("/usr/bin/fuse-overlayfs".into()),
+            storage_driver: Some("overlay".to_owned()),
+            storage_mount_program: false,
+        };
+        assert!(caps.unprivileged(UnprivilegedMode::Auto));
+        assert!(!caps.unprivileged(UnprivilegedMode::Never));
+        assert_eq!(
+            caps.storage_option().as_deref(),
+            Some("--storage-opt=overlay.mount_program=/usr/bin/fuse-overlayfs")
+        );
+        assert!(caps.blockers().is_empty());
+        let failed: Vec<_> = caps
+            .checks(true)
+            .into_iter()
+            .filter(|c| !c.ok)
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(failed, ["kvm", "vhost-vsock"]);
+
+        let privileged = HostCapabilities {
+            proc_mount: Some(true),
+            ..caps.clone()
+        };
+        assert!(!privileged.unprivileged(UnprivilegedMode::Auto));
+        assert!(privileged.unprivileged(UnprivilegedMode::Always));
+
+        // Unreadable mountinfo: unprivileged mode works either way
+        let unknown = HostCapabilities {
+            proc_mount: None,
+            ..caps.clone()
+        };
+        assert!(unknown.unprivileged(UnprivilegedMode::Auto));
+        assert!(!unknown
+            .checks(false)
+            .iter()
+            .any(|c| c.name == "proc-mount" && c.ok));
+        let host = HostCapabilities {
+            in_container: false,
+            ..unknown
+        };
+        assert!(!host.unprivileged(UnprivilegedMode::Auto));
+
+        let no_fuse = HostCapabilities {
+            fuse: false,
+            ..caps.clone()
+        };
+        assert_eq!(no_fuse.storage_option(), None);
+        assert!(no_fuse
+            .checks(true)
+            .iter()
+            .any(|c| c.name == "fuse-overlayfs" && !c.ok));
+
+        let blocked = HostCapabilities {
+            user_namespaces: false,
+            storage_driver: None,
+            ..caps
+        };
+        assert_eq!(blocked.blockers().len(), 2);
+    }
+}
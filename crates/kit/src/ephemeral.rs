@@ -4,7 +4,9 @@
 //! Ephemeral VMs are temporary, non-persistent VMs that are useful for testing, development,
 //! and CI/CD workflows.
 
-use std::process::Command;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
 
 use clap::Subcommand;
 use color_eyre::{eyre::eyre, Result};
@@ -12,13 +14,56 @@ use comfy_table::{presets::UTF8_FULL, Table};
 use serde::{Deserialize, Serialize};
 
 // Re-export the existing implementations
+use crate::checkpoint;
+use crate::ephemeral_boot_disk;
+use crate::ephemeral_commit;
+use crate::ephemeral_cp;
+use crate::ephemeral_list;
+use crate::hostexec::HostCommand;
 use crate::run_ephemeral;
 use crate::run_ephemeral_ssh;
 use crate::ssh;
+use crate::vsock_exec;
 
 /// Label used to identify bcvk ephemeral containers
 const EPHEMERAL_LABEL: &str = "bcvk.ephemeral=1";
 
+/// How long to wait for a VM to shut down before forcibly stopping it
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Options for stopping an ephemeral VM
+#[derive(clap::Parser, Debug)]
+pub struct StopOpts {
+    /// Name or ID of the container running the VM, or an unambiguous prefix
+    /// of its name as shown by `bcvk ephemeral list`
+    pub container_name: String,
+
+    /// Seconds to wait for the VM to shut down before forcibly stopping it
+    #[clap(long, default_value = "60")]
+    pub timeout: u64,
+}
+
+/// Options for changing the memory of an ephemeral VM
+#[derive(clap::Parser, Debug)]
+pub struct SetMemoryOpts {
+    /// Name or ID of the container running the VM
+    pub container_name: String,
+
+    /// New memory size (e.g. 4G, 2048M, or plain number for MB), at most the VM's --memory-max
+    pub memory: String,
+}
+
+/// Options for showing the console output of an ephemeral VM
+#[derive(clap::Parser, Debug)]
+pub struct ConsoleLogOpts {
+    /// Name or ID of the container running the VM
+    pub container_name: String,
+
+    /// Keep printing the output as the VM writes it
+    #[clap(long, short = 'f')]
+    pub follow: bool,
+}
+
 /// SSH connection options for accessing running VMs.
 ///
 /// Provides secure shell access to VMs running within containers,
@@ -28,9 +73,15 @@ pub struct SshOpts {
     /// Name or ID of the container running the target VM
     ///
     /// This should match the container name from podman or the VM ID
-    /// used when starting the ephemeral VM.
+    /// used when starting the ephemeral VM; an unambiguous prefix of the
+    /// name as shown by `bcvk ephemeral list` works too.
     pub container_name: String,
 
+    /// User to connect as; defaults to the one given with `--ssh-user`
+    /// when starting the VM, or root
+    #[clap(long)]
+    pub user: Option<String>,
+
     /// Additional SSH client arguments to pass through
     ///
     /// Standard ssh arguments like -v for verbose output, -L for
@@ -60,6 +111,14 @@ pub struct ContainerListEntry {
 
     /// Container command
     pub command: Vec<String>,
+
+    /// Start time, in seconds since the epoch
+    #[serde(default)]
+    pub started_at: i64,
+
+    /// Container labels
+    #[serde(default)]
+    pub labels: Option<HashMap<String, String>>,
 }
 
 /// Ephemeral VM operations
@@ -73,10 +132,54 @@ pub enum EphemeralCommands {
     #[clap(name = "run-ssh")]
     RunSsh(run_ephemeral_ssh::RunEphemeralSshOpts),
 
+    /// Boot an existing disk image as an ephemeral VM, discarding all changes
+    #[clap(name = "boot-disk")]
+    BootDisk(ephemeral_boot_disk::BootDiskOpts),
+
     /// Connect to running VMs via SSH
     #[clap(name = "ssh")]
     Ssh(SshOpts),
 
+    /// Run a command in a running VM, via SSH or the vsock agent
+    #[clap(name = "exec")]
+    Exec(vsock_exec::ExecOpts),
+
+    /// Copy files between the host and a running VM
+    #[clap(name = "cp")]
+    Cp(ephemeral_cp::CpOpts),
+
+    /// Commit the changes made in a running VM to a new container image
+    #[clap(name = "commit")]
+    Commit(ephemeral_commit::CommitOpts),
+
+    /// Shut down an ephemeral VM gracefully
+    #[clap(name = "stop")]
+    Stop(StopOpts),
+
+    /// Change the memory of an ephemeral VM started with --memory-max
+    #[clap(name = "set-memory")]
+    SetMemory(SetMemoryOpts),
+
+    /// Show the timestamped console output of a running ephemeral VM
+    #[clap(name = "console-log")]
+    ConsoleLog(ConsoleLogOpts),
+
+    /// Save the state of a running ephemeral VM to a checkpoint
+    #[clap(name = "checkpoint")]
+    Checkpoint(checkpoint::CheckpointOpts),
+
+    /// Resume an ephemeral VM from a checkpoint
+    #[clap(name = "restore")]
+    Restore(checkpoint::RestoreOpts),
+
+    /// List running ephemeral VMs with their image, uptime, SSH status and memory
+    #[clap(name = "list")]
+    List(ephemeral_list::ListOpts),
+
+    /// Report whether this environment can run ephemeral VMs, and what is missing
+    #[clap(name = "check-host")]
+    CheckHost(crate::envdetect::CheckHostOpts),
+
     /// List ephemeral VM containers
     #[clap(name = "ps")]
     Ps {
@@ -98,16 +201,48 @@ impl EphemeralCommands {
     /// Execute the ephemeral subcommand
     pub fn run(self) -> Result<()> {
         match self {
-            EphemeralCommands::Run(opts) => run_ephemeral::run(opts),
-            EphemeralCommands::RunSsh(opts) => run_ephemeral_ssh::run_ephemeral_ssh(opts),
+            EphemeralCommands::Run(mut opts) => {
+                opts.add_qemu_args_from_env()?;
+                run_ephemeral::run(opts)
+            }
+            EphemeralCommands::RunSsh(mut opts) => {
+                opts.run_opts.add_qemu_args_from_env()?;
+                run_ephemeral_ssh::run_ephemeral_ssh(opts)
+            }
+            EphemeralCommands::BootDisk(opts) => ephemeral_boot_disk::boot_disk(opts),
             EphemeralCommands::Ssh(opts) => {
                 // Create progress bar if stderr is a terminal
                 let progress_bar = crate::boot_progress::create_boot_progress_bar();
 
-                run_ephemeral_ssh::wait_for_ssh_ready(&opts.container_name, None, progress_bar)?;
-
-                ssh::connect_via_container(&opts.container_name, opts.args)
+                let container = ephemeral_list::resolve_container(&opts.container_name)?;
+                run_ephemeral_ssh::wait_for_ssh_ready(&container, None, progress_bar)?;
+
+                let options = ssh::SshConnectionOptions {
+                    user: opts.user,
+                    ..ssh::SshConnectionOptions::default()
+                };
+                let status = ssh::connect(&container, opts.args, &options)?;
+                if !status.success() {
+                    return Err(eyre!(
+                        "SSH connection failed with exit code: {:?}",
+                        status.code()
+                    ));
+                }
+                Ok(())
+            }
+            EphemeralCommands::Exec(opts) => vsock_exec::run(opts),
+            EphemeralCommands::Cp(opts) => ephemeral_cp::cp(opts),
+            EphemeralCommands::Commit(opts) => ephemeral_commit::commit(opts),
+            EphemeralCommands::Stop(opts) => {
+                let container = ephemeral_list::resolve_container(&opts.container_name)?;
+                stop_container(&container, Duration::from_secs(opts.timeout))
             }
+            EphemeralCommands::SetMemory(opts) => set_memory(opts),
+            EphemeralCommands::ConsoleLog(opts) => console_log(opts),
+            EphemeralCommands::Checkpoint(opts) => checkpoint::checkpoint(opts),
+            EphemeralCommands::Restore(opts) => checkpoint::restore(opts),
+            EphemeralCommands::List(opts) => ephemeral_list::list(opts),
+            EphemeralCommands::CheckHost(opts) => crate::envdetect::check_host(opts),
             EphemeralCommands::Ps { json } => {
                 let containers = list_ephemeral_containers()?;
 
@@ -157,11 +292,75 @@ impl EphemeralCommands {
     }
 }
 
-/// List ephemeral VM containers with bcvk.ephemeral=1 label
-fn list_ephemeral_containers() -> Result<Vec<ContainerListEntry>> {
-    use bootc_utils::CommandRunExt;
+/// Stop an ephemeral VM container, first asking the guest to shut down
+/// cleanly (so that e.g. pending disk writes are flushed) and only forcibly
+/// stopping the container if that fails or takes longer than `timeout`.
+pub(crate) fn stop_container(container: &str, timeout: Duration) -> Result<()> {
+    let output = HostCommand::new("podman")
+        .args(["exec", container, "/var/lib/bcvk/entrypoint", "shutdown"])
+        .arg(format!("--timeout={}", timeout.as_secs()))
+        .stdout(Stdio::null())
+        .output()
+        .map_err(|e| eyre!("Failed to run podman exec: {e}"))?;
+    if output.status.success() {
+        tracing::debug!("VM in {container} shut down");
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprintln!(
+            "Warning: Graceful shutdown of {container} failed, stopping forcibly: {}",
+            stderr.trim()
+        );
+    }
 
-    let containers: Vec<ContainerListEntry> = Command::new("podman")
+    // With a clean shutdown the container exits by itself; this waits for
+    // that, or kills it otherwise.
+    HostCommand::new("podman")
+        .args(["stop", "--ignore", container])
+        .stdout(Stdio::null())
+        .run()
+        .map_err(|e| eyre!("Failed to stop {container}: {e}"))?;
+    Ok(())
+}
+
+/// Set the memory balloon of the VM in `opts.container_name`
+fn set_memory(opts: SetMemoryOpts) -> Result<()> {
+    let memory_mb = crate::utils::parse_memory_to_mb(&opts.memory)?;
+    HostCommand::new("podman")
+        .args([
+            "exec",
+            opts.container_name.as_str(),
+            "/var/lib/bcvk/entrypoint",
+            "set-memory",
+        ])
+        .arg(memory_mb.to_string())
+        .run()
+        .map_err(|e| eyre!("Setting memory of {}: {e}", opts.container_name))?;
+    println!("Memory of {} set to {}", opts.container_name, opts.memory);
+    Ok(())
+}
+
+/// Print the console log of the VM in `opts.container_name`
+fn console_log(opts: ConsoleLogOpts) -> Result<()> {
+    let log = run_ephemeral::CONSOLE_LOG;
+    let mut cmd = HostCommand::new("podman");
+    cmd.args(["exec", opts.container_name.as_str()]);
+    if opts.follow {
+        cmd.args(["tail", "-n", "+1", "-f", log]);
+    } else {
+        cmd.args(["cat", log]);
+    }
+    cmd.stdout(Stdio::inherit()).run().map_err(|e| {
+        eyre!(
+            "Reading console log of {}: {e}\n\
+             The log is only kept while the VM runs; use --console-log to keep it on the host",
+            opts.container_name
+        )
+    })
+}
+
+/// List ephemeral VM containers with bcvk.ephemeral=1 label
+pub(crate) fn list_ephemeral_containers() -> Result<Vec<ContainerListEntry>> {
+    let containers: Vec<ContainerListEntry> = HostCommand::new("podman")
         .args([
             "ps",
             "--all",
@@ -176,8 +375,6 @@ fn list_ephemeral_containers() -> Result<Vec<ContainerListEntry>> {
 
 /// Remove all ephemeral VM containers
 fn remove_all_ephemeral_containers(force: bool) -> Result<()> {
-    use bootc_utils::CommandRunExt;
-
     let containers = list_ephemeral_containers()?;
 
     if containers.is_empty() {
@@ -215,7 +412,7 @@ fn remove_all_ephemeral_containers(force: bool) -> Result<()> {
             "Removing container {}",
             &container.id[..12.min(container.id.len())]
         );
-        let result = Command::new("podman")
+        let result = HostCommand::new("podman")
             .args(["rm", "-f", &container.id])
             .run();
 
@@ -0,0 +1,162 @@
+//! Booting an existing disk image as an ephemeral VM
+//!
+//! Unlike `bcvk ephemeral run`, which boots the kernel of a container image
+//! directly with the image as the root filesystem, this boots a disk image
+//! (e.g. created by `bcvk to-disk`) with UEFI firmware and its own
+//! bootloader. QEMU still runs in a podman container, which needs a bootc
+//! image to provide its userspace; by default the image the disk was
+//! installed from is used. The disk is opened read-only with QEMU's
+//! `snapshot=on`, so all changes made by the VM are discarded.
+//!
+//! The disk does not need to be a bootc one: any image UEFI can boot works,
+//! given `--image`. Guests without virtio drivers such as Windows can use
+//! `--emulated-devices`.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::cache_metadata::DiskImageMetadata;
+use crate::images::ImageListEntry;
+use crate::run_ephemeral::{self, CommonPodmanOptions, CommonVmOpts, RunEphemeralOpts};
+
+/// Options for booting a disk image as an ephemeral VM
+#[derive(Parser, Debug)]
+pub struct BootDiskOpts {
+    /// Disk image to boot (raw or qcow2)
+    pub disk: Utf8PathBuf,
+
+    /// Container image to run QEMU in; defaults to the image the disk was installed from
+    #[clap(long)]
+    pub image: Option<String>,
+
+    #[clap(flatten)]
+    pub common: CommonVmOpts,
+
+    #[clap(flatten)]
+    pub podman: CommonPodmanOptions,
+
+    #[clap(
+        long = "bind",
+        value_name = "HOST_PATH[:NAME]",
+        help = "Bind mount host directory (RW) at /run/virtiofs-mnt-<name>"
+    )]
+    pub bind_mounts: Vec<String>,
+
+    #[clap(
+        long = "ro-bind",
+        value_name = "HOST_PATH[:NAME]",
+        help = "Bind mount host directory (RO) at /run/virtiofs-mnt-<name>"
+    )]
+    pub ro_bind_mounts: Vec<String>,
+
+    #[clap(
+        long,
+        help = "Mount host container storage (RO) at /run/virtiofs-mnt-hoststorage and use it as an additional image store"
+    )]
+    pub share_host_images: bool,
+
+    #[clap(
+        long = "qemu-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Append a raw argument to the QEMU command line (repeatable)"
+    )]
+    pub qemu_args: Vec<String>,
+
+    #[clap(
+        long,
+        help = "Attach the disk via AHCI and use an e1000e NIC, for guests without virtio drivers (e.g. Windows)"
+    )]
+    pub emulated_devices: bool,
+
+    #[clap(
+        long,
+        help = "Boot even if the VM requests more memory than the host has, and without warning about overcommitting it"
+    )]
+    pub force: bool,
+}
+
+/// Find the local image with the given manifest digest
+fn find_image_by_digest<'a>(images: &'a [ImageListEntry], digest: &str) -> Option<&'a str> {
+    images
+        .iter()
+        .find(|i| i.digest.as_deref() == Some(digest))
+        .map(|i| i.id.as_str())
+}
+
+/// Check that `disk` can be booted on its own, returning its absolute path
+fn validate_disk(disk: &Utf8Path) -> Result<Utf8PathBuf> {
+    let disk = disk
+        .canonicalize_utf8()
+        .with_context(|| format!("Disk image {disk}"))?;
+    if !disk.is_file() {
+        return Err(eyre!("Disk image {disk} is not a regular file"));
+    }
+    // Only the disk itself is mounted into the container
+    let info = crate::qemu_img::info(&disk)?;
+    if let Some(backing) = info.backing_filename {
+        return Err(eyre!(
+            "Disk image {disk} has a backing file ({backing}); flatten it first with `qemu-img convert`"
+        ));
+    }
+    Ok(disk)
+}
+
+/// Execute the boot-disk command
+pub fn boot_disk(opts: BootDiskOpts) -> Result<()> {
+    let disk = validate_disk(&opts.disk)?;
+
+    let image = match opts.image {
+        Some(image) => image,
+        None => {
+            let digest = DiskImageMetadata::read_image_digest_from_path(disk.as_std_path())?
+                .ok_or_else(|| {
+                    eyre!("Disk image {disk} has no recorded source image; use --image")
+                })?;
+            let images = crate::images::list()?;
+            let id = find_image_by_digest(&images, &digest).ok_or_else(|| {
+                eyre!("Source image {digest} of {disk} not found in local storage; use --image")
+            })?;
+            debug!("Using image {id} with digest {digest}");
+            id.to_owned()
+        }
+    };
+
+    let opts = RunEphemeralOpts {
+        image,
+        common: opts.common,
+        podman: opts.podman,
+        bind_mounts: opts.bind_mounts,
+        ro_bind_mounts: opts.ro_bind_mounts,
+        share_host_images: opts.share_host_images,
+        force: opts.force,
+        qemu_args: opts.qemu_args,
+        boot_disk: Some(disk),
+        emulated_devices: opts.emulated_devices,
+        ..Default::default()
+    };
+    run_ephemeral::run(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_image_by_digest() {
+        let images: Vec<ImageListEntry> = serde_json::from_str(
+            r#"[
+                {"Id": "aaaa", "Digest": "sha256:1111", "Size": 1, "Labels": {"containers.bootc": "1"}},
+                {"Id": "bbbb", "Size": 1, "Labels": {"containers.bootc": "1"}},
+                {"Id": "cccc", "Digest": "sha256:3333", "Size": 1, "Labels": {"containers.bootc": "1"}}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(find_image_by_digest(&images, "sha256:3333"), Some("cccc"));
+        assert_eq!(find_image_by_digest(&images, "sha256:1111"), Some("aaaa"));
+        assert_eq!(find_image_by_digest(&images, "sha256:2222"), None);
+    }
+}
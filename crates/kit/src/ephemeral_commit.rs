@@ -0,0 +1,262 @@
+//! Committing the changes made in an ephemeral VM to a container image
+//!
+//! The root filesystem of an ephemeral VM is its container image, read-only,
+//! with a writable overlayfs on top whose upper directory holds all changes:
+//! `/run/systemd/overlay-sysroot/upper` for the default tmpfs backing, or
+//! `/run/bcvk-overlay/upper` for a disk-backed overlay. The upper directory
+//! is streamed out of the VM as a tar archive over SSH (like `bcvk ephemeral
+//! cp`) and added as a new layer on top of the VM's image with `podman build`.
+//!
+//! overlayfs records deleted files as whiteouts (0:0 character devices) and
+//! replaced directories as opaque (an xattr), neither of which survives
+//! `ADD`. They are listed separately and removed with `RUN rm` before the
+//! archive is added.
+
+use std::process::Stdio;
+
+use camino::Utf8Path;
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::hostexec::HostCommand;
+use crate::ssh::{self, SshConnectionOptions};
+
+/// Paths (relative to `/`, as `find -path` patterns) left out of the image
+/// by default: state that is specific to the VM instance
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "etc/machine-id",
+    "etc/ssh/ssh_host_*",
+    "root/.ssh",
+    "tmp",
+    "var/cache",
+    "var/lib/systemd",
+    "var/log",
+    "var/tmp",
+];
+
+/// Runs in the VM: `changes` lists whiteouts and opaque directories,
+/// `archive` writes a tar archive of everything else to stdout. The
+/// remaining arguments are paths to exclude.
+///
+/// Opaque directories can only be found with getfattr; without it,
+/// replaced directories end up merged with the original.
+const OVERLAY_SCRIPT: &str = r#"set -e
+mode=$1; shift
+for upper in /run/bcvk-overlay/upper /run/systemd/overlay-sysroot/upper; do
+    [ -d "$upper" ] && break
+done
+if ! [ -d "$upper" ]; then
+    echo "No writable root overlay found in the VM" >&2
+    exit 1
+fi
+cd "$upper"
+n=$#
+for p in "$@"; do set -- "$@" -path "./$p" -o; done
+shift $n
+case $mode in
+changes)
+    find . -mindepth 1 \( "$@" -false \) -prune -o -type c -exec stat --printf 'whiteout-%t:%T %n\0' {} +
+    if command -v getfattr >/dev/null; then
+        find . -mindepth 1 \( "$@" -false \) -prune -o -type d -exec sh -c '
+            for d; do
+                v=$(getfattr --only-values -n trusted.overlay.opaque "$d" 2>/dev/null) || continue
+                if [ "$v" = y ]; then printf "opaque %s\0" "$d"; fi
+            done' sh {} +
+    else
+        echo "warning: getfattr not found in the VM; replaced directories will be merged with the original" >&2
+    fi
+    ;;
+archive)
+    find . -mindepth 1 \( "$@" -type c \) -prune -o -print0 |
+        tar --null --no-recursion -T - --xattrs --xattrs-exclude='trusted.*' -cf -
+    ;;
+esac
+"#;
+
+/// File name of the archive of changes in the build context
+const ARCHIVE_NAME: &str = "changes.tar";
+
+/// Commit the changes made in an ephemeral VM to a new container image
+#[derive(Parser, Debug)]
+pub struct CommitOpts {
+    /// Name or ID of the container running the VM
+    pub container_name: String,
+
+    /// Name of the new image, e.g. localhost/myimage:latest
+    pub image: String,
+
+    /// Path in the VM to leave out of the image (may be repeated; shell patterns allowed)
+    #[clap(long, value_name = "PATH")]
+    pub exclude: Vec<String>,
+
+    /// Include the instance-specific paths left out by default (machine ID, SSH host keys, logs, caches, ...)
+    #[clap(long)]
+    pub no_default_excludes: bool,
+}
+
+/// The subset of `podman container inspect` output we need
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerInspect {
+    /// ID of the image the container runs
+    image: String,
+}
+
+fn overlay_command(
+    container: &str,
+    mode: &str,
+    excludes: &[String],
+) -> Result<std::process::Command> {
+    let args: Vec<String> = ["sh", "-c", OVERLAY_SCRIPT, "sh", mode]
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .chain(excludes.iter().cloned())
+        .collect();
+    // Reading the whole overlay needs root, whatever user the VM has
+    let options = SshConnectionOptions {
+        allocate_tty: false,
+        user: Some("root".to_owned()),
+        ..SshConnectionOptions::default()
+    };
+    ssh::ssh_command(container, &args, &options)
+}
+
+/// Parse the output of the `changes` mode of [`OVERLAY_SCRIPT`] into the
+/// absolute paths to remove from the image
+fn parse_changes(output: &[u8]) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for entry in output.split(|&b| b == 0).filter(|e| !e.is_empty()) {
+        let entry = std::str::from_utf8(entry).context("Invalid UTF-8 in path")?;
+        let path = if let Some(path) = entry.strip_prefix("whiteout-0:0 ") {
+            path
+        } else if let Some(path) = entry.strip_prefix("opaque ") {
+            path
+        } else if let Some(device) = entry.strip_prefix("whiteout-") {
+            debug!("Skipping character device {device}");
+            continue;
+        } else {
+            return Err(eyre!("Unexpected change entry: {entry}"));
+        };
+        let path = path
+            .strip_prefix("./")
+            .ok_or_else(|| eyre!("Unexpected path: {path}"))?;
+        removed.push(format!("/{path}"));
+    }
+    removed.sort();
+    removed.dedup();
+    Ok(removed)
+}
+
+/// Build the Containerfile applying the changes to `base`
+fn containerfile(base: &str, removed: &[String]) -> Result<String> {
+    let mut r = format!("FROM {base}\n");
+    if !removed.is_empty() {
+        let args: Vec<&str> = ["rm", "-rf", "--"]
+            .into_iter()
+            .chain(removed.iter().map(String::as_str))
+            .collect();
+        r.push_str(&format!("RUN {}\n", serde_json::to_string(&args)?));
+    }
+    r.push_str(&format!("ADD {ARCHIVE_NAME} /\n"));
+    Ok(r)
+}
+
+/// Execute the commit command
+pub fn commit(opts: CommitOpts) -> Result<()> {
+    let container = opts.container_name.as_str();
+    let inspect: Vec<ContainerInspect> = HostCommand::new("podman")
+        .args(["container", "inspect", container])
+        .run_and_parse_json()?;
+    let base = inspect
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("No such container: {container}"))?
+        .image;
+
+    let mut excludes: Vec<String> = opts
+        .exclude
+        .iter()
+        .map(|p| p.trim_start_matches('/').to_owned())
+        .collect();
+    if !opts.no_default_excludes {
+        excludes.extend(DEFAULT_EXCLUDES.iter().map(|p| p.to_string()));
+    }
+
+    let changes = overlay_command(container, "changes", &excludes)?
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Listing changes in the VM")?;
+    if !changes.status.success() {
+        return Err(eyre!(
+            "Listing changes in the VM failed: {}",
+            changes.status
+        ));
+    }
+    let removed = parse_changes(&changes.stdout)?;
+    debug!("Removed paths: {removed:?}");
+
+    let context = tempfile::Builder::new()
+        .prefix("bcvk-commit-")
+        .tempdir_in("/var/tmp")
+        .context("Creating build context")?;
+    let context_dir = Utf8Path::from_path(context.path())
+        .ok_or_else(|| eyre!("Invalid UTF-8 in temporary directory"))?;
+
+    let archive = std::fs::File::create(context_dir.join(ARCHIVE_NAME))
+        .context("Creating archive of changes")?;
+    let status = overlay_command(container, "archive", &excludes)?
+        .stdout(archive)
+        .status()
+        .context("Archiving changes in the VM")?;
+    if !status.success() {
+        return Err(eyre!("Archiving changes in the VM failed: {status}"));
+    }
+
+    let containerfile_path = context_dir.join("Containerfile");
+    std::fs::write(&containerfile_path, containerfile(&base, &removed)?)
+        .context("Writing Containerfile")?;
+
+    HostCommand::new("podman")
+        .args(["build", "--pull=never", "-t", opts.image.as_str(), "-f"])
+        .arg(containerfile_path.as_str())
+        .arg(context_dir.as_str())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .run()
+        .with_context(|| format!("Building {}", opts.image))?;
+
+    println!("Committed the changes in {container} to {}", opts.image);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_changes() {
+        let output = b"whiteout-0:0 ./etc/motd\0whiteout-1:3 ./dev-null-copy\0opaque ./opt/app\0whiteout-0:0 ./usr/lib/old file\0";
+        assert_eq!(
+            parse_changes(output).unwrap(),
+            ["/etc/motd", "/opt/app", "/usr/lib/old file"]
+        );
+        assert!(parse_changes(b"").unwrap().is_empty());
+        assert!(parse_changes(b"garbage\0").is_err());
+        assert!(parse_changes(b"opaque /abs\0").is_err());
+    }
+
+    #[test]
+    fn test_containerfile() {
+        assert_eq!(
+            containerfile("sha256:abc", &[]).unwrap(),
+            "FROM sha256:abc\nADD changes.tar /\n"
+        );
+        assert_eq!(
+            containerfile("sha256:abc", &["/etc/motd".into(), "/opt/a \"b\"".into()]).unwrap(),
+            "FROM sha256:abc\nRUN [\"rm\",\"-rf\",\"--\",\"/etc/motd\",\"/opt/a \\\"b\\\"\"]\nADD changes.tar /\n"
+        );
+    }
+}
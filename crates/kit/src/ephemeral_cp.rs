@@ -0,0 +1,275 @@
+//! Copying files to and from ephemeral VMs
+//!
+//! Files are transferred as a tar stream over the same SSH connection used by
+//! `bcvk ephemeral ssh` (i.e. `podman exec -i <container> ssh ...`), so
+//! nothing needs to be copied into the container itself. Using tar means
+//! directories are copied recursively and permissions are preserved.
+//!
+//! The destination follows `scp` semantics: if it is an existing directory
+//! the source is copied into it, otherwise the source is copied to that path.
+
+use std::process::{Command, Stdio};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::ssh::{self, SshConnectionOptions};
+
+/// Extract a tar stream containing a single top-level entry `$2` on stdin
+/// to the path `$1`, with `scp` semantics for the destination.
+///
+/// This runs both locally and in the VM, so it only relies on POSIX sh and
+/// GNU coreutils/tar.
+const EXTRACT_SCRIPT: &str = r#"set -e
+dest=$1 name=$2
+if [ -d "$dest" ]; then
+    exec tar -C "$dest" --no-same-owner -xpf -
+fi
+tmp=$(mktemp -d -p "$(dirname -- "$dest")" .bcvk-cp.XXXXXX)
+trap 'rm -rf -- "$tmp"' EXIT
+tar -C "$tmp" --no-same-owner -xpf -
+mv -T -- "$tmp/$name" "$dest"
+"#;
+
+/// Copy files between the host and an ephemeral VM
+#[derive(Parser, Debug)]
+pub struct CpOpts {
+    /// Source: a host path, or CONTAINER:PATH for a path in the VM
+    pub source: CopyLocation,
+
+    /// Destination: a host path, or CONTAINER:PATH for a path in the VM
+    pub dest: CopyLocation,
+}
+
+/// One side of a copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyLocation {
+    /// A path on the host
+    Host(Utf8PathBuf),
+    /// A path in a VM; relative paths are relative to the user's home directory
+    Vm {
+        /// The VM: the name or ID of its container for ephemeral VMs, or
+        /// the domain name for libvirt VMs
+        vm: String,
+        /// Path in the VM
+        path: Utf8PathBuf,
+    },
+}
+
+impl std::str::FromStr for CopyLocation {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Like `podman cp`, host paths containing a colon can be given
+        // unambiguously as absolute or ./relative paths
+        match s.split_once(':') {
+            Some((container, path))
+                if !container.is_empty() && !container.contains('/') && !s.starts_with('.') =>
+            {
+                if path.is_empty() {
+                    return Err(eyre!("Missing path in the VM in '{s}'"));
+                }
+                Ok(Self::Vm {
+                    vm: container.to_owned(),
+                    path: path.into(),
+                })
+            }
+            _ => Ok(Self::Host(s.into())),
+        }
+    }
+}
+
+/// Split a path into the directory to run tar in and the entry to archive
+fn split_path(path: &Utf8Path) -> Result<(&Utf8Path, &str)> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Cannot copy {path}: no file name"))?;
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    Ok((parent, name))
+}
+
+fn tar_create_args(path: &Utf8Path) -> Result<Vec<String>> {
+    let (parent, name) = split_path(path)?;
+    Ok(["tar", "-C", parent.as_str(), "-cf", "-", "--", name]
+        .map(ToOwned::to_owned)
+        .to_vec())
+}
+
+fn extract_args(dest: &Utf8Path, name: &str) -> Vec<String> {
+    ["sh", "-c", EXTRACT_SCRIPT, "sh", dest.as_str(), name]
+        .map(ToOwned::to_owned)
+        .to_vec()
+}
+
+fn vm_command(container: &str, args: &[String]) -> Result<Command> {
+    let options = SshConnectionOptions {
+        allocate_tty: false,
+        forward_stdin: true,
+        ..SshConnectionOptions::default()
+    };
+    ssh::ssh_command(container, args, &options)
+}
+
+fn host_command(args: &[String]) -> Command {
+    let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    cmd
+}
+
+/// Run `producer | consumer`, failing if either fails
+fn run_pipeline(mut producer: Command, mut consumer: Command) -> Result<()> {
+    debug!("Running {producer:?} | {consumer:?}");
+    let mut child = producer
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn copy source")?;
+    let stdout = child.stdout.take().expect("piped stdout");
+    let consumer_status = consumer
+        .stdin(stdout)
+        .status()
+        .context("Failed to run copy destination");
+    let producer_status = child.wait().context("Failed to wait for copy source")?;
+    let consumer_status = consumer_status?;
+    if !producer_status.success() {
+        return Err(eyre!("Reading source failed: {producer_status}"));
+    }
+    if !consumer_status.success() {
+        return Err(eyre!("Writing destination failed: {consumer_status}"));
+    }
+    Ok(())
+}
+
+/// Execute the cp command
+pub fn cp(opts: CpOpts) -> Result<()> {
+    match (opts.source, opts.dest) {
+        (CopyLocation::Host(src), CopyLocation::Vm { vm, path }) => {
+            if !src.try_exists()? {
+                return Err(eyre!("{src} does not exist"));
+            }
+            let (_, name) = split_path(&src)?;
+            let producer = host_command(&tar_create_args(&src)?);
+            let consumer = vm_command(&vm, &extract_args(&path, name))?;
+            run_pipeline(producer, consumer)
+                .with_context(|| format!("Copying {src} to {vm}:{path}"))
+        }
+        (CopyLocation::Vm { vm, path }, CopyLocation::Host(dest)) => {
+            let (_, name) = split_path(&path)?;
+            let producer = vm_command(&vm, &tar_create_args(&path)?)?;
+            let consumer = host_command(&extract_args(&dest, name));
+            run_pipeline(producer, consumer)
+                .with_context(|| format!("Copying {vm}:{path} to {dest}"))
+        }
+        (CopyLocation::Host(_), CopyLocation::Host(_)) => Err(eyre!(
+            "One of source or destination must be a path in a VM (CONTAINER:PATH)"
+        )),
+        (CopyLocation::Vm { .. }, CopyLocation::Vm { .. }) => {
+            Err(eyre!("Copying directly between VMs is not supported"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_copy_location() {
+        let vm = |name: &str, path: &str| CopyLocation::Vm {
+            vm: name.into(),
+            path: path.into(),
+        };
+        let host = |path: &str| CopyLocation::Host(path.into());
+        let cases = [
+            ("myvm:/etc/os-release", vm("myvm", "/etc/os-release")),
+            ("myvm:file.txt", vm("myvm", "file.txt")),
+            ("/tmp/out", host("/tmp/out")),
+            ("relative/path", host("relative/path")),
+            ("./a:b", host("./a:b")),
+            ("/tmp/a:b", host("/tmp/a:b")),
+            ("dir/a:b", host("dir/a:b")),
+            (":foo", host(":foo")),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<CopyLocation>().unwrap(), expected, "{input}");
+        }
+        assert!("myvm:".parse::<CopyLocation>().is_err());
+    }
+
+    #[test]
+    fn test_split_path() {
+        let cases = [
+            ("/etc/os-release", "/etc", "os-release"),
+            ("file.txt", ".", "file.txt"),
+            ("dir/sub/", "dir", "sub"),
+            ("/var", "/", "var"),
+        ];
+        for (path, parent, name) in cases {
+            let (p, n) = split_path(Utf8Path::new(path)).unwrap();
+            assert_eq!((p.as_str(), n), (parent, name), "{path}");
+        }
+        assert!(split_path(Utf8Path::new("/")).is_err());
+        assert!(split_path(Utf8Path::new("..")).is_err());
+    }
+
+    /// Copy `src` to `dest` on the host using the same commands used for VMs
+    fn copy_local(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+        let (_, name) = split_path(src)?;
+        run_pipeline(
+            host_command(&tar_create_args(src)?),
+            host_command(&extract_args(dest, name)),
+        )
+    }
+
+    #[test]
+    fn test_extract_semantics() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).unwrap();
+        let src = td.join("src");
+        std::fs::create_dir_all(src.join("sub"))?;
+        std::fs::write(src.join("sub/file"), "hello")?;
+        std::fs::set_permissions(src.join("sub/file"), std::fs::Permissions::from_mode(0o750))?;
+
+        // Copy a directory to a new path
+        copy_local(&src, &td.join("renamed"))?;
+        assert_eq!(
+            std::fs::read_to_string(td.join("renamed/sub/file"))?,
+            "hello"
+        );
+        let mode = std::fs::metadata(td.join("renamed/sub/file"))?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        // Copy a directory into an existing directory
+        std::fs::create_dir(td.join("existing"))?;
+        copy_local(&src, &td.join("existing"))?;
+        assert_eq!(
+            std::fs::read_to_string(td.join("existing/src/sub/file"))?,
+            "hello"
+        );
+
+        // Copy a file over an existing file
+        std::fs::write(td.join("target"), "old")?;
+        copy_local(&src.join("sub/file"), &td.join("target"))?;
+        assert_eq!(std::fs::read_to_string(td.join("target"))?, "hello");
+
+        // No temporary directories are left behind
+        let leftovers = std::fs::read_dir(td)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with(".bcvk-cp"))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // The parent of the destination must exist
+        assert!(copy_local(&src, &td.join("missing/dest")).is_err());
+        Ok(())
+    }
+}
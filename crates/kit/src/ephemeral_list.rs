@@ -0,0 +1,292 @@
+//! Listing ephemeral VMs
+//!
+//! Unlike `bcvk ephemeral ps`, which shows the podman view of the containers,
+//! this shows the VMs in them: their image, how long they have been up,
+//! whether SSH is ready (read from the supervisor status file in the
+//! container) and their memory. The names it prints are the container names,
+//! which `bcvk ephemeral ssh` and `stop` also accept as unambiguous prefixes.
+
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use comfy_table::{presets::UTF8_FULL, Table};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::ephemeral::{list_ephemeral_containers, ContainerListEntry};
+use crate::hostexec::HostCommand;
+use crate::libvirt::OutputFormat;
+use crate::run_ephemeral::MEMORY_LABEL;
+use crate::supervisor_status::{SupervisorState, SupervisorStatus};
+
+/// Options for listing ephemeral VMs
+#[derive(Parser, Debug)]
+pub struct ListOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+
+    /// Also list VMs whose container has exited
+    #[clap(long, short = 'a')]
+    pub all: bool,
+}
+
+/// SSH status of an ephemeral VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SshStatus {
+    /// The VM accepts SSH connections
+    Ready,
+    /// The VM is still booting
+    Booting,
+    /// The VM booted without SSH access, or is not running
+    Unavailable,
+}
+
+impl SshStatus {
+    fn from_supervisor(status: &SupervisorStatus) -> Self {
+        if status.ssh_access {
+            Self::Ready
+        } else if status.running && status.state != Some(SupervisorState::Ready) {
+            Self::Booting
+        } else {
+            Self::Unavailable
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ready => "ready",
+            Self::Booting => "booting",
+            Self::Unavailable => "-",
+        }
+    }
+}
+
+/// An ephemeral VM as shown by `bcvk ephemeral list`
+#[derive(Debug, Serialize)]
+pub struct EphemeralVm {
+    /// Name of the container running the VM
+    pub name: String,
+    /// Container ID
+    pub id: String,
+    /// The bootc image
+    pub image: String,
+    /// Container state
+    pub state: String,
+    /// Seconds since the container started, if it is running
+    pub uptime_secs: Option<u64>,
+    /// SSH status of the VM
+    pub ssh: SshStatus,
+    /// Memory of the VM in megabytes, if recorded
+    pub memory_mb: Option<u32>,
+}
+
+/// The name shown for a container: its first name, or its short ID
+fn short_name(container: &ContainerListEntry) -> &str {
+    container
+        .names
+        .first()
+        .map(String::as_str)
+        .unwrap_or(&container.id[..12.min(container.id.len())])
+}
+
+/// Format a duration in seconds compactly, with at most two units
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{days}d{hours}h")
+    } else if hours > 0 {
+        format!("{hours}h{mins}m")
+    } else if mins > 0 {
+        format!("{mins}m{}s", secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// Read the supervisor status of the VM in a running container
+fn supervisor_status(container: &str) -> Option<SupervisorStatus> {
+    let output = HostCommand::new("podman")
+        .args([
+            "exec",
+            container,
+            "cat",
+            crate::run_ephemeral::SUPERVISOR_STATUS,
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        debug!("No supervisor status in {container}");
+        return None;
+    }
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+fn to_vm(container: &ContainerListEntry, now: u64) -> EphemeralVm {
+    let name = short_name(container).to_owned();
+    let running = container.state == "running";
+    let ssh = running
+        .then(|| supervisor_status(&name))
+        .flatten()
+        .map(|s| SshStatus::from_supervisor(&s))
+        .unwrap_or(SshStatus::Unavailable);
+    let memory_mb = container
+        .labels
+        .as_ref()
+        .and_then(|l| l.get(MEMORY_LABEL))
+        .and_then(|v| v.parse().ok());
+    EphemeralVm {
+        name,
+        id: container.id.clone(),
+        image: container.image.clone(),
+        state: container.state.clone(),
+        uptime_secs: running.then(|| now.saturating_sub(container.started_at.max(0) as u64)),
+        ssh,
+        memory_mb,
+    }
+}
+
+/// Find the container `name` refers to: a container name or ID (prefix),
+/// which is passed on as is, or else an unambiguous prefix of the name of
+/// an ephemeral VM container.
+fn match_container<'a>(containers: &'a [ContainerListEntry], name: &'a str) -> Result<&'a str> {
+    let exact = containers
+        .iter()
+        .any(|c| c.names.iter().any(|n| n == name) || c.id.starts_with(name));
+    if exact {
+        return Ok(name);
+    }
+    let candidates: Vec<&str> = containers
+        .iter()
+        .flat_map(|c| &c.names)
+        .filter(|n| n.starts_with(name))
+        .map(String::as_str)
+        .collect();
+    match candidates.as_slice() {
+        [] => Ok(name),
+        [one] => Ok(*one),
+        _ => Err(eyre!(
+            "{name} is ambiguous, it matches: {}",
+            candidates.join(", ")
+        )),
+    }
+}
+
+/// Resolve `name`, which may be a short name as printed by
+/// `bcvk ephemeral list`, to the container it refers to
+pub(crate) fn resolve_container(name: &str) -> Result<String> {
+    let containers = list_ephemeral_containers()?;
+    match_container(&containers, name).map(ToOwned::to_owned)
+}
+
+/// Execute the list command
+pub fn list(opts: ListOpts) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let vms: Vec<EphemeralVm> = list_ephemeral_containers()?
+        .iter()
+        .filter(|c| opts.all || c.state == "running")
+        .map(|c| to_vm(c, now))
+        .collect();
+
+    match opts.format {
+        OutputFormat::Table => {
+            if vms.is_empty() {
+                println!("No ephemeral VMs found");
+                println!("Tip: Create one with 'bcvk ephemeral run -d -K <image>'");
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .set_header(vec!["NAME", "IMAGE", "STATUS", "UPTIME", "SSH", "MEMORY"]);
+            for vm in &vms {
+                // By characters, as a byte index may split one
+                let image = if vm.image.chars().count() > 38 {
+                    format!("{}...", vm.image.chars().take(35).collect::<String>())
+                } else {
+                    vm.image.clone()
+                };
+                let uptime = vm
+                    .uptime_secs
+                    .map(format_uptime)
+                    .unwrap_or_else(|| "-".to_owned());
+                let memory = vm
+                    .memory_mb
+                    .map(|m| format!("{m}MB"))
+                    .unwrap_or_else(|| "unknown".to_owned());
+                table.add_row(vec![
+                    vm.name.as_str(),
+                    &image,
+                    &vm.state,
+                    &uptime,
+                    vm.ssh.as_str(),
+                    &memory,
+                ]);
+            }
+            println!("{table}");
+        }
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&vms).context("Serializing VMs as JSON")?
+            );
+        }
+        OutputFormat::Yaml | OutputFormat::Xml => {
+            return Err(eyre!(
+                "Only table and JSON formats are supported for list command"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(id: &str, name: &str) -> ContainerListEntry {
+        ContainerListEntry {
+            id: id.to_owned(),
+            names: vec![name.to_owned()],
+            state: "running".to_owned(),
+            created_at: String::new(),
+            image: "quay.io/fedora/fedora-bootc:42".to_owned(),
+            command: Vec::new(),
+            started_at: 0,
+            labels: None,
+        }
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(125), "2m5s");
+        assert_eq!(format_uptime(3 * 3600 + 12 * 60 + 7), "3h12m");
+        assert_eq!(format_uptime(2 * 86400 + 5 * 3600), "2d5h");
+    }
+
+    #[test]
+    fn test_match_container() {
+        let containers = [
+            container("abc123", "webserver"),
+            container("def456", "webtest"),
+            container("0789ab", "db"),
+        ];
+        assert_eq!(
+            match_container(&containers, "webserver").unwrap(),
+            "webserver"
+        );
+        assert_eq!(match_container(&containers, "def4").unwrap(), "def4");
+        assert_eq!(match_container(&containers, "webs").unwrap(), "webserver");
+        assert_eq!(match_container(&containers, "other").unwrap(), "other");
+        assert!(match_container(&containers, "web").is_err());
+    }
+}
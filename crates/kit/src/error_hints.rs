@@ -0,0 +1,186 @@
+//! User-facing rendering of errors
+//!
+//! By default a failed command prints just the chain of error messages,
+//! without the locations and span traces color-eyre adds. Errors of a few
+//! common classes, mostly problems with the host setup, are summarized in
+//! one line with a `hint:` on how to fix them. `bcvk --verbose` prints the
+//! full color-eyre report instead.
+//!
+//! The classes are recognized by the messages, and only select the hint;
+//! the exit status comes from the [`crate::Failure`] an error is tagged with.
+
+use std::io::IsTerminal;
+
+use color_eyre::Report;
+
+/// Common classes of errors which get a summary and a hint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    /// /dev/kvm is missing or not accessible
+    NoKvm,
+    /// virtiofsd is not installed
+    VirtiofsdMissing,
+    /// The libvirt daemon socket cannot be opened
+    LibvirtPermission,
+    /// The container image is not in local storage
+    ImageNotFound,
+}
+
+impl ErrorClass {
+    /// Find the class of the error with the given chain of messages
+    fn classify<'a>(messages: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        messages.into_iter().find_map(|msg| {
+            let msg = msg.to_lowercase();
+            if msg.contains("/dev/kvm")
+                || msg.contains("kvm device not accessible")
+                || msg.contains("could not access kvm kernel module")
+            {
+                Some(Self::NoKvm)
+            } else if msg.contains("virtiofsd binary not found") {
+                Some(Self::VirtiofsdMissing)
+            } else if msg.contains("libvirt-sock")
+                && (msg.contains("permission denied") || msg.contains("authentication"))
+            {
+                Some(Self::LibvirtPermission)
+            } else if msg.contains("image not known")
+                || msg.contains("failed to find image")
+                || msg.contains("no such image")
+            {
+                Some(Self::ImageNotFound)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn summary(self) -> &'static str {
+        match self {
+            Self::NoKvm => "KVM is not available",
+            Self::VirtiofsdMissing => "virtiofsd is not installed",
+            Self::LibvirtPermission => "Permission denied connecting to libvirt",
+            Self::ImageNotFound => "Image not found in local container storage",
+        }
+    }
+
+    fn hint(self) -> &'static str {
+        match self {
+            Self::NoKvm => {
+                "enable virtualization in the firmware settings and make sure you can \
+                 access /dev/kvm, e.g. as a member of the kvm group; or use `--accel tcg` \
+                 for (slow) software emulation"
+            }
+            Self::VirtiofsdMissing => "install virtiofsd, e.g. with `dnf install virtiofsd`",
+            Self::LibvirtPermission => {
+                "add your user to the libvirt group, or use `--connect qemu:///session`; \
+                 `bcvk libvirt status` checks the setup"
+            }
+            Self::ImageNotFound => {
+                "pull it first with `bcvk images pull IMAGE`, or pass `--pull missing`"
+            }
+        }
+    }
+}
+
+/// Wrap `s` in the ANSI escape sequence `code` if `color` is set
+fn paint(s: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{code}m{s}\x1b[0m")
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Render an error with the chain of messages `messages`, outermost first
+fn render(messages: &[&str], color: bool) -> String {
+    let error = paint("error:", "1;31", color);
+    let mut out = String::new();
+    match ErrorClass::classify(messages.iter().copied()) {
+        Some(class) => {
+            out.push_str(&format!("{error} {}\n", class.summary()));
+            if let Some(cause) = messages.last() {
+                out.push_str(&format!("  caused by: {cause}\n"));
+            }
+            out.push_str(&format!(
+                "{} {}\n",
+                paint("hint:", "1;36", color),
+                class.hint()
+            ));
+        }
+        None => {
+            let first = messages.first().copied().unwrap_or("unknown error");
+            out.push_str(&format!("{error} {first}\n"));
+            for cause in messages.iter().skip(1) {
+                out.push_str(&format!("  caused by: {cause}\n"));
+            }
+        }
+    }
+    out.push_str(&paint(
+        "Run with --verbose for the full error report.",
+        "2",
+        color,
+    ));
+    out
+}
+
+/// Print `report` to stderr in the short form
+pub(crate) fn print(report: &Report) {
+    let messages: Vec<String> = report.chain().map(|e| e.to_string()).collect();
+    let messages: Vec<&str> = messages.iter().map(String::as_str).collect();
+    let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    eprintln!("{}", render(&messages, color));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cases: &[(&[&str], Option<ErrorClass>)] = &[
+            (
+                &["Running VM", "KVM device not accessible"],
+                Some(ErrorClass::NoKvm),
+            ),
+            (
+                &["virtiofsd binary not found. Searched paths: /usr/libexec/virtiofsd."],
+                Some(ErrorClass::VirtiofsdMissing),
+            ),
+            (
+                &[
+                    "Failed to list domains",
+                    "error: failed to connect to the hypervisor\nerror: Failed to connect socket to '/var/run/libvirt/libvirt-sock': Permission denied",
+                ],
+                Some(ErrorClass::LibvirtPermission),
+            ),
+            (
+                &["podman run failed", "Error: quay.io/example/foo: image not known"],
+                Some(ErrorClass::ImageNotFound),
+            ),
+            (&["Disk image foo.qcow2 is not a regular file"], None),
+        ];
+        for (messages, class) in cases {
+            assert_eq!(
+                ErrorClass::classify(messages.iter().copied()),
+                *class,
+                "{messages:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(&["Running VM", "KVM device not accessible"], false),
+            "error: KVM is not available\n  caused by: KVM device not accessible\n\
+             hint: enable virtualization in the firmware settings and make sure you can \
+             access /dev/kvm, e.g. as a member of the kvm group; or use `--accel tcg` \
+             for (slow) software emulation\n\
+             Run with --verbose for the full error report."
+        );
+        assert_eq!(
+            render(&["Creating VM", "Disk foo is in use"], false),
+            "error: Creating VM\n  caused by: Disk foo is in use\n\
+             Run with --verbose for the full error report."
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Structured event log
+//!
+//! bcvk appends one JSON object per line to a per-user state file
+//! (`$XDG_STATE_HOME/bcvk/events.jsonl`) for notable operations such as
+//! creating a VM or building a disk. This makes it possible to untangle
+//! interleaved concurrent runs after the fact, and gives CI a
+//! machine-readable record of what bcvk did.
+//!
+//! Recording is best-effort: failures to write the log are logged at debug
+//! level and never fail the operation itself.
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use camino::Utf8PathBuf;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// File name of the event log within the bcvk state directory
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// How often to check for new events with `--follow`
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The kind of event, with event-specific fields
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EventKind {
+    /// A libvirt domain was created
+    VmCreated { name: String, image: String },
+    /// A disk image was installed from a container image
+    DiskBuilt {
+        path: String,
+        image: String,
+        /// How long the installation took
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seconds: Option<u64>,
+    },
+    /// An existing base disk was reused instead of being rebuilt
+    BaseDiskReused { path: String, image: String },
+    /// An SSH keypair was generated
+    SshKeyGenerated { path: String },
+    /// A libvirt domain was removed
+    DomainRemoved { name: String },
+    /// A libvirt domain was updated to a new image
+    VmUpdated { name: String, image: String },
+}
+
+/// A single recorded event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// When the event happened
+    pub timestamp: DateTime<Utc>,
+    /// Process ID of the bcvk invocation which recorded the event
+    pub pid: u32,
+    /// Event details
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{}] ",
+            self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            self.pid
+        )?;
+        match &self.kind {
+            EventKind::VmCreated { name, image } => write!(f, "vm-created {name} ({image})"),
+            EventKind::DiskBuilt {
+                path,
+                image,
+                seconds,
+            } => {
+                write!(f, "disk-built {path} ({image})")?;
+                match seconds {
+                    Some(secs) => write!(f, " in {secs}s"),
+                    None => Ok(()),
+                }
+            }
+            EventKind::BaseDiskReused { path, image } => {
+                write!(f, "base-disk-reused {path} ({image})")
+            }
+            EventKind::SshKeyGenerated { path } => write!(f, "ssh-key-generated {path}"),
+            EventKind::DomainRemoved { name } => write!(f, "domain-removed {name}"),
+            EventKind::VmUpdated { name, image } => write!(f, "vm-updated {name} ({image})"),
+        }
+    }
+}
+
+/// Path to the per-user event log
+fn events_path() -> Result<Utf8PathBuf> {
+    let state_dir = dirs::state_dir().ok_or_else(|| eyre!("No user state directory"))?;
+    let state_dir = Utf8PathBuf::try_from(state_dir)?;
+    Ok(state_dir.join("bcvk").join(EVENTS_FILE))
+}
+
+/// Append an event to the log
+fn append(kind: EventKind) -> Result<()> {
+    let path = events_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {parent}"))?;
+    }
+
+    let event = Event {
+        timestamp: Utc::now(),
+        pid: std::process::id(),
+        kind,
+    };
+    let mut line = serde_json::to_string(&event)?;
+    line.push('\n');
+
+    // A single write to an O_APPEND file keeps lines from concurrent
+    // processes from being interleaved.
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Opening {path}"))?;
+    f.write_all(line.as_bytes())
+        .with_context(|| format!("Writing {path}"))?;
+    Ok(())
+}
+
+/// Record an event in the per-user event log
+///
+/// Errors are logged and otherwise ignored.
+pub fn record(kind: EventKind) {
+    if let Err(e) = append(kind) {
+        debug!("Failed to record event: {e:#}");
+    }
+}
+
+/// How long the last installation of `image` to a disk took, if recorded
+pub fn last_build_duration(image: &str) -> Option<Duration> {
+    let path = events_path().ok()?;
+    let f = std::fs::File::open(&path).ok()?;
+    last_build_seconds(
+        BufReader::new(f).lines().map_while(std::result::Result::ok),
+        image,
+    )
+    .map(Duration::from_secs)
+}
+
+fn last_build_seconds(lines: impl Iterator<Item = String>, image: &str) -> Option<u64> {
+    lines
+        .filter_map(|line| parse_line(&line))
+        .filter_map(|event| match event.kind {
+            EventKind::DiskBuilt {
+                image: i,
+                seconds: Some(secs),
+                ..
+            } if i == image => Some(secs),
+            _ => None,
+        })
+        .last()
+}
+
+/// Parse a single line of the event log, skipping anything unrecognized
+fn parse_line(line: &str) -> Option<Event> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    match serde_json::from_str(line) {
+        Ok(event) => Some(event),
+        Err(e) => {
+            debug!("Skipping unparseable event: {e}");
+            None
+        }
+    }
+}
+
+/// Show the bcvk event log
+#[derive(Debug, Parser)]
+pub struct EventsOpts {
+    /// Keep waiting for and printing new events
+    #[clap(long, short = 'f')]
+    pub follow: bool,
+
+    /// Output events as JSON lines
+    #[clap(long)]
+    pub json: bool,
+}
+
+impl EventsOpts {
+    fn print(&self, event: &Event) -> Result<()> {
+        if self.json {
+            println!("{}", serde_json::to_string(event)?);
+        } else {
+            println!("{event}");
+        }
+        Ok(())
+    }
+}
+
+/// Execute the events command
+pub fn run(opts: EventsOpts) -> Result<()> {
+    let path = events_path()?;
+    let f = match std::fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !opts.follow => {
+            debug!("No event log at {path}");
+            return Ok(());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // Create it so we have something to follow
+            ensure_events_file(&path)?;
+            std::fs::File::open(&path).with_context(|| format!("Opening {path}"))?
+        }
+        Err(e) => return Err(e).with_context(|| format!("Opening {path}")),
+    };
+    let mut reader = BufReader::new(f);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let n = reader
+            .read_line(&mut line)
+            .with_context(|| format!("Reading {path}"))?;
+        if n == 0 {
+            if !opts.follow {
+                break;
+            }
+            std::thread::sleep(FOLLOW_POLL_INTERVAL);
+            // Clear the EOF condition so newly appended data is picked up
+            let pos = reader.stream_position()?;
+            reader.seek(SeekFrom::Start(pos))?;
+            continue;
+        }
+        // A partially written line; wait for the rest of it
+        if !line.ends_with('\n') && opts.follow {
+            let pos = reader.stream_position()? - n as u64;
+            reader.seek(SeekFrom::Start(pos))?;
+            std::thread::sleep(FOLLOW_POLL_INTERVAL);
+            continue;
+        }
+        if let Some(event) = parse_line(&line) {
+            opts.print(&event)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create an empty event log at `path`
+fn ensure_events_file(path: &camino::Utf8Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {parent}"))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Creating {path}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_roundtrip() {
+        let timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let cases = [
+            (
+                EventKind::VmCreated {
+                    name: "fedora-bootc".into(),
+                    image: "quay.io/fedora/fedora-bootc:42".into(),
+                },
+                r#"{"timestamp":"2023-11-14T22:13:20Z","pid":42,"event":"vm-created","name":"fedora-bootc","image":"quay.io/fedora/fedora-bootc:42"}"#,
+                "2023-11-14 22:13:20 [42] vm-created fedora-bootc (quay.io/fedora/fedora-bootc:42)",
+            ),
+            (
+                EventKind::DomainRemoved {
+                    name: "fedora-bootc".into(),
+                },
+                r#"{"timestamp":"2023-11-14T22:13:20Z","pid":42,"event":"domain-removed","name":"fedora-bootc"}"#,
+                "2023-11-14 22:13:20 [42] domain-removed fedora-bootc",
+            ),
+        ];
+        for (kind, json, display) in cases {
+            let event = Event {
+                timestamp,
+                pid: 42,
+                kind,
+            };
+            assert_eq!(serde_json::to_string(&event).unwrap(), json);
+            assert_eq!(parse_line(json).unwrap(), event);
+            assert_eq!(event.to_string(), display);
+        }
+    }
+
+    #[test]
+    fn test_last_build_seconds() {
+        let lines = [
+            r#"{"timestamp":"2023-11-14T22:13:20Z","pid":1,"event":"disk-built","path":"/a.img","image":"foo","seconds":200}"#,
+            r#"{"timestamp":"2023-11-14T22:20:00Z","pid":2,"event":"disk-built","path":"/b.img","image":"bar","seconds":90}"#,
+            r#"{"timestamp":"2023-11-14T22:30:00Z","pid":3,"event":"disk-built","path":"/c.img","image":"foo","seconds":180}"#,
+            r#"{"timestamp":"2023-11-14T22:40:00Z","pid":4,"event":"disk-built","path":"/d.img","image":"foo"}"#,
+        ];
+        let lines = || lines.iter().map(|l| l.to_string());
+        assert_eq!(last_build_seconds(lines(), "foo"), Some(180));
+        assert_eq!(last_build_seconds(lines(), "bar"), Some(90));
+        assert_eq!(last_build_seconds(lines(), "baz"), None);
+    }
+
+    #[test]
+    fn test_parse_line_skips_invalid() {
+        for line in ["", "  \n", "not json", r#"{"event":"unknown-event"}"#] {
+            assert!(parse_line(line).is_none(), "{line:?}");
+        }
+    }
+}
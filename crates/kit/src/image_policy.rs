@@ -0,0 +1,239 @@
+//! Checks an image must pass before a disk is installed from it
+//!
+//! `--require-label` refuses images lacking a label (or with a different
+//! value), e.g. to only boot images built by a given pipeline.
+//!
+//! `--verify-signature` refuses images whose signature is not accepted by
+//! the containers signature policy (containers-policy.json(5)), which covers
+//! GPG as well as sigstore (cosign) signatures. The signatures live in the
+//! registry, so the image is pulled again by its digest, which verifies
+//! them without downloading layers that are present already. Images only
+//! present locally, and images from registries for which the policy accepts
+//! anything, count as unsigned.
+//!
+//! All failed checks are reported together.
+
+use clap::Parser;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::hostexec::HostCommand;
+use crate::images::ImageInspect;
+
+/// Checks an image must pass before a disk is installed from it
+#[derive(Debug, Clone, Default, Parser)]
+pub struct ImagePolicyOpts {
+    /// Refuse the image unless its signature is accepted by the containers
+    /// signature policy (containers-policy.json); contacts the registry
+    #[clap(long)]
+    pub verify_signature: bool,
+
+    /// Refuse the image unless it has this label, with this value if given
+    /// (can be specified multiple times)
+    #[clap(long = "require-label", value_name = "KEY[=VALUE]")]
+    pub require_label: Vec<LabelRequirement>,
+}
+
+/// A label an image must have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelRequirement {
+    key: String,
+    value: Option<String>,
+}
+
+impl std::str::FromStr for LabelRequirement {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = match s.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_owned())),
+            None => (s, None),
+        };
+        if key.is_empty() {
+            return Err(eyre!("Invalid label requirement '{s}': empty key"));
+        }
+        Ok(Self {
+            key: key.to_owned(),
+            value,
+        })
+    }
+}
+
+/// An entry of `podman image trust show --json`
+#[derive(Debug, Deserialize)]
+struct TrustEntry {
+    #[serde(default)]
+    transport: String,
+    repo_name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// How specific `scope` of the signature policy is for `repository`, if it
+/// applies to it at all
+fn scope_specificity(scope: &str, repository: &str) -> Option<usize> {
+    if matches!(scope, "" | "*" | "default") {
+        return Some(0);
+    }
+    if let Some(domain) = scope.strip_prefix('*') {
+        let host = repository.split('/').next().unwrap_or_default();
+        // Wildcards are less specific than any explicit scope
+        return host.ends_with(domain).then_some(1);
+    }
+    let matches = repository == scope
+        || repository
+            .strip_prefix(scope)
+            .is_some_and(|rest| rest.starts_with('/'));
+    matches.then_some(scope.len() + 1)
+}
+
+/// The entry of the signature policy applying to `repository`
+fn trust_requirement<'a>(entries: &'a [TrustEntry], repository: &str) -> Option<&'a TrustEntry> {
+    entries
+        .iter()
+        .filter(|e| matches!(e.transport.as_str(), "" | "repository" | "docker"))
+        .filter_map(|e| scope_specificity(&e.repo_name, repository).map(|s| (s, e)))
+        .max_by_key(|(s, _)| *s)
+        .map(|(_, e)| e)
+}
+
+/// The labels in `requirements` which `image` lacks
+fn missing_labels(requirements: &[LabelRequirement], image: &ImageInspect) -> Vec<String> {
+    requirements
+        .iter()
+        .filter_map(|req| match (image.label(&req.key), req.value.as_deref()) {
+            (None, None) => Some(format!("missing label {}", req.key)),
+            (None, Some(expected)) => Some(format!("missing label {}={expected}", req.key)),
+            (Some(actual), Some(expected)) if actual != expected => Some(format!(
+                "label {} is '{actual}', expected '{expected}'",
+                req.key
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Verify the signature of `image` in the registry it was pulled from
+fn verify_signature(image: &ImageInspect) -> Result<()> {
+    let pinned = image
+        .repo_digests
+        .iter()
+        .find(|r| !r.starts_with("localhost/"))
+        .ok_or_else(|| eyre!("the image was not pulled from a registry, so it is not signed"))?;
+    let repository = pinned.split_once('@').map_or(pinned.as_str(), |(r, _)| r);
+
+    let entries: Vec<TrustEntry> = HostCommand::new("podman")
+        .args(["image", "trust", "show", "--json"])
+        .run_and_parse_json()?;
+    match trust_requirement(&entries, repository).map(|e| e.kind.as_str()) {
+        None | Some("accept") => {
+            return Err(eyre!(
+                "the signature policy accepts unsigned images from {repository}"
+            ))
+        }
+        Some("reject") => {
+            return Err(eyre!(
+                "the signature policy rejects all images from {repository}"
+            ))
+        }
+        Some(kind) => debug!("Signature policy for {repository}: {kind}"),
+    }
+
+    HostCommand::new("podman")
+        .args(["pull", "--quiet", pinned])
+        .run()
+        .map_err(|e| eyre!("verifying {pinned}: {e:#}"))
+}
+
+impl ImagePolicyOpts {
+    /// Whether any check is enabled
+    pub fn is_active(&self) -> bool {
+        self.verify_signature || !self.require_label.is_empty()
+    }
+
+    /// Check `inspect`, the inspection of `image`, against the policy,
+    /// failing with everything it lacks
+    pub fn check(&self, image: &str, inspect: &ImageInspect) -> Result<()> {
+        if !self.is_active() {
+            return Ok(());
+        }
+        let mut problems = missing_labels(&self.require_label, inspect);
+        if self.verify_signature {
+            if let Err(e) = verify_signature(inspect) {
+                problems.push(format!("signature: {e:#}"));
+            }
+        }
+        if problems.is_empty() {
+            info!("Image {image} satisfies the image policy");
+            return Ok(());
+        }
+        let problems: Vec<String> = problems.iter().map(|p| format!("  - {p}")).collect();
+        Err(eyre!(
+            "Refusing image {image}, it does not satisfy the image policy:\n{}",
+            problems.join("\n")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(labels: &[(&str, &str)]) -> ImageInspect {
+        serde_json::from_value(serde_json::json!({
+            "Id": "1234",
+            "Digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+            "Size": 1,
+            "Labels": labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<std::collections::HashMap<_, _>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_missing_labels() {
+        let img = image(&[("containers.bootc", "1"), ("vendor", "Example")]);
+        let cases: &[(&str, Option<&str>)] = &[
+            ("containers.bootc", None),
+            ("containers.bootc=1", None),
+            ("vendor=Example", None),
+            (
+                "vendor=Other",
+                Some("label vendor is 'Example', expected 'Other'"),
+            ),
+            ("team", Some("missing label team")),
+            ("team=infra", Some("missing label team=infra")),
+        ];
+        for (input, expected) in cases {
+            let req: LabelRequirement = input.parse().unwrap();
+            let missing = missing_labels(&[req], &img);
+            assert_eq!(missing.first().map(String::as_str), *expected, "{input}");
+        }
+        assert!("=x".parse::<LabelRequirement>().is_err());
+    }
+
+    #[test]
+    fn test_trust_requirement() {
+        let entries: Vec<TrustEntry> = serde_json::from_str(
+            r#"[
+                {"transport": "repository", "name": "* (default)", "repo_name": "default", "type": "accept"},
+                {"transport": "repository", "name": "registry.example.com", "repo_name": "registry.example.com", "type": "signed"},
+                {"transport": "repository", "name": "registry.example.com/private", "repo_name": "registry.example.com/private", "type": "reject"},
+                {"transport": "repository", "name": "*.mirror.example.com", "repo_name": "*.mirror.example.com", "type": "sigstoreSigned"}
+            ]"#,
+        )
+        .unwrap();
+        let cases = [
+            ("quay.io/fedora/fedora-bootc", "accept"),
+            ("registry.example.com/os/base", "signed"),
+            ("registry.example.com/private/base", "reject"),
+            ("registry.example.com/privateer", "signed"),
+            ("eu.mirror.example.com/os/base", "sigstoreSigned"),
+        ];
+        for (repository, expected) in cases {
+            let entry = trust_requirement(&entries, repository).unwrap();
+            assert_eq!(entry.kind, expected, "{repository}");
+        }
+    }
+}
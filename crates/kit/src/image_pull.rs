@@ -0,0 +1,277 @@
+//! Pulling images, with retries
+//!
+//! bcvk otherwise expects images to be in the host container storage
+//! already. `bcvk images pull`, and `--pull` of the commands creating VMs and
+//! disks, pull them first, retrying with an exponential backoff, so that a
+//! flaky registry fails early rather than deep inside a VM creation flow.
+//! Errors which retrying can't fix (e.g. an unknown tag, or missing
+//! credentials) are not retried.
+//!
+//! Pulls are reported with the digest the image was pinned to, so that the
+//! exact image can be referenced later.
+
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::hostexec::HostCommand;
+use crate::images;
+
+/// How often a failed pull is retried by default
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Delay before the first retry by default, in seconds
+const DEFAULT_RETRY_DELAY_SECS: u64 = 2;
+
+/// Upper bound for the delay between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Fragments of podman errors which retrying won't fix
+const PERMANENT_ERRORS: &[&str] = &[
+    "manifest unknown",
+    "name unknown",
+    "unauthorized",
+    "authentication required",
+    "requested access to the resource is denied",
+    "invalid reference format",
+    "short-name",
+];
+
+/// When an image is pulled before it is used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullPolicy {
+    /// Always pull, updating the image if the registry has a newer one
+    Always,
+    /// Pull only if the image is not in the host container storage
+    Missing,
+    /// Never pull; the image must be in the host container storage
+    #[default]
+    Never,
+}
+
+/// How failed pulls are retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubling for each further one
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            delay: Duration::from_secs(DEFAULT_RETRY_DELAY_SECS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (counting from 1)
+    fn delay(&self, retry: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Options for pulling an image
+#[derive(Debug, Parser)]
+pub struct PullOpts {
+    /// Container image to pull
+    pub image: String,
+
+    /// How often to retry a failed pull
+    #[clap(long, default_value_t = DEFAULT_RETRIES)]
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry; the delay doubles for each further retry
+    #[clap(long, value_name = "SECONDS", default_value_t = DEFAULT_RETRY_DELAY_SECS)]
+    pub retry_delay: u64,
+}
+
+/// Whether a pull which failed with `stderr` is worth retrying
+fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_ascii_lowercase();
+    !PERMANENT_ERRORS.iter().any(|e| stderr.contains(e))
+}
+
+/// The entry of `repo_digests` (`REPOSITORY@DIGEST`) for `image`, which
+/// may be a short name
+///
+/// The digests of other repositories the image was also pulled from are not
+/// necessarily valid for `image`, so there is none if no entry matches.
+fn pinned_reference<'a>(image: &str, repo_digests: &'a [String]) -> Option<&'a str> {
+    repo_digests
+        .iter()
+        .find(|r| images::is_repo_digest_of(r, image))
+        .map(String::as_str)
+}
+
+/// Create a spinner for a pull
+fn create_progress_bar() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_draw_target(ProgressDrawTarget::stderr());
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} {msg} [{elapsed}]")
+            .unwrap(),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// Pull `image`, retrying transient failures according to `retry`
+pub fn pull(image: &str, retry: &RetryPolicy) -> Result<()> {
+    let pb = create_progress_bar();
+    let attempts = retry.retries + 1;
+    let mut attempt = 1;
+    loop {
+        if attempt == 1 {
+            pb.set_message(format!("Pulling {image}"));
+        } else {
+            pb.set_message(format!("Pulling {image} (attempt {attempt}/{attempts})"));
+        }
+        let output = HostCommand::new("podman")
+            .args(["pull", "--quiet", image])
+            .output()
+            .context("Failed to run podman pull")?;
+        if output.status.success() {
+            pb.finish_and_clear();
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        if attempt == attempts || !is_transient(stderr) {
+            pb.finish_and_clear();
+            return Err(eyre!("Failed to pull {image}: {stderr}"));
+        }
+        let delay = retry.delay(attempt);
+        pb.suspend(|| {
+            warn!(
+                "Pulling {image} failed, retrying in {}s: {stderr}",
+                delay.as_secs()
+            )
+        });
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Pull `image` and return the `REPOSITORY@DIGEST` reference it is pinned to,
+/// if podman recorded a digest for its repository
+fn pull_pinned(image: &str, retry: &RetryPolicy) -> Result<Option<String>> {
+    pull(image, retry)?;
+    if crate::hostexec::dry_run() {
+        return Ok(None);
+    }
+    let inspect = images::inspect(image)?;
+    let pinned = pinned_reference(image, &inspect.repo_digests);
+    if pinned.is_none() {
+        debug!("No digest of {image} among {:?}", inspect.repo_digests);
+    }
+    Ok(pinned.map(ToOwned::to_owned))
+}
+
+/// Make `image` available in the host container storage according to `policy`
+///
+/// Images from OCI archives and directories, and images explicitly from the
+/// container storage, are never pulled.
+pub fn ensure(image: &str, policy: PullPolicy) -> Result<()> {
+    if images::needs_import(image) || image.starts_with("containers-storage:") {
+        return Ok(());
+    }
+    match policy {
+        PullPolicy::Never => return Ok(()),
+        PullPolicy::Missing if images::exists(image)? => {
+            debug!("{image} is present, not pulling it");
+            return Ok(());
+        }
+        PullPolicy::Missing | PullPolicy::Always => {}
+    }
+    if let Some(pinned) = pull_pinned(image, &RetryPolicy::default())? {
+        info!("Pulled {image} as {pinned}");
+    }
+    Ok(())
+}
+
+/// Execute the images pull command
+pub fn run(opts: PullOpts) -> Result<()> {
+    let retry = RetryPolicy {
+        retries: opts.retries,
+        delay: Duration::from_secs(opts.retry_delay),
+    };
+    if let Some(pinned) = pull_pinned(&opts.image, &retry)? {
+        println!("{pinned}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryPolicy::default();
+        let delays: Vec<u64> = (1..=7).map(|n| retry.delay(n).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let cases = [
+            ("Error: initializing source docker://quay.io/fedora/fedora-bootc:42: pinging container registry quay.io: Get \"https://quay.io/v2/\": dial tcp: lookup quay.io: no such host", true),
+            ("Error: copying system image from manifest list: reading blob sha256:1234: unexpected EOF", true),
+            ("Error: initializing source docker://quay.io/fedora/fedora-bootc:nope: reading manifest nope in quay.io/fedora/fedora-bootc: manifest unknown", false),
+            ("Error: initializing source docker://registry.example.com/private:latest: reading manifest latest in registry.example.com/private: unauthorized: access to the requested resource is not authorized", false),
+        ];
+        for (stderr, expected) in cases {
+            assert_eq!(is_transient(stderr), expected, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn test_pinned_reference() {
+        let digests = vec![
+            "quay.io/fedora/fedora-bootc@sha256:aaaa".to_owned(),
+            "registry.example.com:5000/os/base@sha256:bbbb".to_owned(),
+        ];
+        let cases = [
+            (
+                "quay.io/fedora/fedora-bootc:42",
+                Some("quay.io/fedora/fedora-bootc@sha256:aaaa"),
+            ),
+            (
+                "fedora/fedora-bootc",
+                Some("quay.io/fedora/fedora-bootc@sha256:aaaa"),
+            ),
+            (
+                "docker://quay.io/fedora/fedora-bootc@sha256:aaaa",
+                Some("quay.io/fedora/fedora-bootc@sha256:aaaa"),
+            ),
+            (
+                "registry.example.com:5000/os/base:latest",
+                Some("registry.example.com:5000/os/base@sha256:bbbb"),
+            ),
+            (
+                "registry.example.com:5000/os/base",
+                Some("registry.example.com:5000/os/base@sha256:bbbb"),
+            ),
+        ];
+        for (image, expected) in cases {
+            assert_eq!(pinned_reference(image, &digests), expected, "{image}");
+        }
+        assert_eq!(pinned_reference("quay.io/other", &[]), None);
+        // Only digests of the pulled repository pin it
+        assert_eq!(pinned_reference("quay.io/other", &digests), None);
+        assert_eq!(pinned_reference("bootc", &digests), None);
+    }
+}
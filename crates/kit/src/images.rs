@@ -1,118 +1,169 @@
 //! Image management and inspection utilities for bootc containers.
 //!
 //! Provides functionality for listing and inspecting bootc container images through
-//! podman integration with both table and JSON output formats.
+//! podman integration with both table and JSON output formats. The podman
+//! queries themselves live in [`bcvk_core::images`].
 
 use std::collections::HashMap;
-use std::process::Command;
 
-use bootc_utils::CommandRunExt;
-use color_eyre::{eyre::eyre, Result};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
 use comfy_table::{presets::UTF8_FULL, Table};
-use serde::{Deserialize, Serialize};
+
+use crate::libvirt::OutputFormat;
+
+use crate::cleanup::CleanupGuard;
+
+pub use bcvk_core::images::{
+    exists, get_image_size, inspect, is_repo_digest_of, list, list_filtered, needs_import,
+    pinned_digest, podman, short_name, without_digest, ImageInspect, ImageListEntry,
+};
 
 /// Command-line options for image management operations.
 #[derive(clap::Subcommand, Debug)]
 pub(crate) enum ImagesOpts {
     /// List all available bootc container images on the system
-    List {
-        /// Output as structured JSON instead of table format
-        #[clap(long)]
-        json: bool,
-    },
+    List(ListOpts),
+
+    /// Pull an image, retrying on failure, and print the digest it is pinned to
+    Pull(crate::image_pull::PullOpts),
+
+    /// Show a bootc-focused report on the contents of an image
+    Inspect(crate::images_inspect::InspectOpts),
+
+    /// Boot an image in an ephemeral VM and check that it comes up healthy
+    Verify(crate::images_verify::VerifyOpts),
+
+    /// Compare the packages and files of two images
+    Diff(crate::images_diff::DiffOpts),
 }
 
-impl ImagesOpts {
-    pub(crate) fn run(self) -> Result<()> {
-        match self {
-            ImagesOpts::List { json } => {
-                let images = list()?;
+/// Options for listing bootc container images
+#[derive(clap::Parser, Debug)]
+pub(crate) struct ListOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
 
-                if json {
-                    let json_output = serde_json::to_string_pretty(&images)?;
-                    println!("{}", json_output);
-                } else {
-                    // Create a table using comfy_table
-                    let mut table = Table::new();
-                    table.load_preset(UTF8_FULL).set_header(vec![
-                        "REPOSITORY",
-                        "TAG",
-                        "IMAGE ID",
-                        "CREATED",
-                        "SIZE",
-                    ]);
-
-                    for image in images {
-                        let (repository, tag) = if let Some(names) = &image.names {
-                            if let Some(name) = names.first() {
-                                if let Some((repo, tag)) = name.rsplit_once(':') {
-                                    (repo.to_string(), tag.to_string())
-                                } else {
-                                    (name.to_string(), "latest".to_string())
-                                }
-                            } else {
-                                ("<none>".to_string(), "<none>".to_string())
-                            }
-                        } else {
-                            ("<none>".to_string(), "<none>".to_string())
-                        };
+    /// Output as structured JSON instead of table format (same as --format=json)
+    #[clap(long, conflicts_with = "format")]
+    json: bool,
 
-                        let id = if image.id.len() > 12 {
-                            &image.id[..12]
-                        } else {
-                            &image.id
-                        };
+    /// Only list images matching a podman image filter (e.g. label=KEY=VALUE); repeatable
+    #[clap(long, value_name = "FILTER")]
+    filter: Vec<String>,
 
-                        let created = image
-                            .created_at
-                            .map(|dt| format_relative_time(dt))
-                            .unwrap_or_else(|| "N/A".to_string());
+    /// Sort images, largest or newest first
+    #[clap(long, value_enum)]
+    sort: Option<ImageSort>,
 
-                        let size = indicatif::BinaryBytes(image.size).to_string();
+    /// Show image digests
+    #[clap(long)]
+    digests: bool,
+}
 
-                        table.add_row(vec![repository, tag, id.to_string(), created, size]);
-                    }
+/// Sort order for listed images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum ImageSort {
+    /// Largest first
+    Size,
+    /// Newest first
+    Created,
+}
 
-                    println!("{}", table);
-                }
-                Ok(())
-            }
+impl ImagesOpts {
+    pub(crate) fn run(self) -> Result<()> {
+        match self {
+            ImagesOpts::List(opts) => run_list(opts),
+            ImagesOpts::Pull(opts) => crate::image_pull::run(opts),
+            ImagesOpts::Inspect(opts) => crate::images_inspect::run(opts),
+            ImagesOpts::Verify(opts) => crate::images_verify::run(opts),
+            ImagesOpts::Diff(opts) => crate::images_diff::run(opts),
         }
     }
 }
 
-/// Single bootc container image entry from podman images output.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct ImageListEntry {
-    /// Repository names and tags, None for dangling images
-    pub names: Option<Vec<String>>,
+/// Sort images in place
+fn sort_images(images: &mut [ImageListEntry], sort: ImageSort) {
+    match sort {
+        ImageSort::Size => images.sort_by(|a, b| b.size.cmp(&a.size)),
+        // Images without a creation time sort last
+        ImageSort::Created => images.sort_by(|a, b| b.created_at.cmp(&a.created_at)),
+    }
+}
 
-    /// SHA256 image identifier
-    pub id: String,
+fn run_list(opts: ListOpts) -> Result<()> {
+    let mut images = list_filtered(&opts.filter)?;
+    if let Some(sort) = opts.sort {
+        sort_images(&mut images, sort);
+    }
 
-    /// Image size in bytes
-    pub size: u64,
+    let format = if opts.json {
+        OutputFormat::Json
+    } else {
+        opts.format
+    };
+    match format {
+        OutputFormat::Table => {
+            // Create a table using comfy_table
+            let mut table = Table::new();
+            let mut header = vec!["REPOSITORY", "TAG"];
+            if opts.digests {
+                header.push("DIGEST");
+            }
+            header.extend(["IMAGE ID", "CREATED", "SIZE"]);
+            table.load_preset(UTF8_FULL).set_header(header);
 
-    /// Image creation timestamp
-    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
-}
+            for image in images {
+                let (repository, tag) = if let Some(names) = &image.names {
+                    if let Some(name) = names.first() {
+                        if let Some((repo, tag)) = name.rsplit_once(':') {
+                            (repo.to_string(), tag.to_string())
+                        } else {
+                            (name.to_string(), "latest".to_string())
+                        }
+                    } else {
+                        ("<none>".to_string(), "<none>".to_string())
+                    }
+                } else {
+                    ("<none>".to_string(), "<none>".to_string())
+                };
 
-/// Container image inspection data from podman image inspect.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-pub struct ImageInspect {
-    /// SHA256 image identifier
-    pub id: String,
+                let id = if image.id.len() > 12 {
+                    &image.id[..12]
+                } else {
+                    &image.id
+                };
+
+                let created = image
+                    .created_at
+                    .map(|dt| format_relative_time(dt))
+                    .unwrap_or_else(|| "N/A".to_string());
 
-    /// Image digest
-    pub digest: oci_spec::image::Digest,
+                let size = indicatif::BinaryBytes(image.size).to_string();
 
-    /// Image size in bytes
-    pub size: u64,
+                let mut row = vec![repository, tag];
+                if opts.digests {
+                    row.push(image.digest.clone().unwrap_or_else(|| "<none>".to_string()));
+                }
+                row.extend([id.to_string(), created, size]);
+                table.add_row(row);
+            }
 
-    /// Image creation timestamp
-    pub created: Option<chrono::DateTime<chrono::Utc>>,
+            println!("{}", table);
+        }
+        OutputFormat::Json => {
+            let json_output = serde_json::to_string_pretty(&images)?;
+            println!("{}", json_output);
+        }
+        OutputFormat::Yaml => {
+            print!("{}", serde_yaml::to_string(&images)?);
+        }
+        OutputFormat::Xml => {
+            return Err(eyre!("XML format is not supported for images list"));
+        }
+    }
+    Ok(())
 }
 
 /// Format a datetime as relative time (e.g., "2 hours ago", "3 days ago").
@@ -160,6 +211,38 @@ fn format_relative_time(dt: chrono::DateTime<chrono::Utc>) -> String {
     }
 }
 
+/// An image imported with [`import`]
+#[derive(Debug)]
+pub(crate) struct ImportedImage {
+    image: bcvk_core::images::ImportedImage,
+    // Dropped last; the store is gone by then unless interrupted
+    _guard: CleanupGuard,
+}
+
+impl ImportedImage {
+    /// The ID of the image
+    pub(crate) fn id(&self) -> &str {
+        self.image.id()
+    }
+
+    /// The root directory of the image store
+    pub(crate) fn store(&self) -> &camino::Utf8Path {
+        self.image.store()
+    }
+}
+
+/// Import an image from an OCI archive or directory into a temporary image
+/// store, see [`bcvk_core::images::import`]; the store is also removed on
+/// SIGINT and SIGTERM.
+pub(crate) fn import(image: &str) -> Result<ImportedImage> {
+    let image = bcvk_core::images::import(image)?;
+    let guard = CleanupGuard::remove_path(image.dir());
+    Ok(ImportedImage {
+        image,
+        _guard: guard,
+    })
+}
+
 /// Parse os-release file format into key-value pairs.
 #[allow(dead_code)]
 fn parse_osrelease(s: &str) -> Result<HashMap<String, String>> {
@@ -184,38 +267,6 @@ fn parse_osrelease(s: &str) -> Result<HashMap<String, String>> {
     Ok(r)
 }
 
-/// List all bootc container images using podman.
-#[allow(dead_code)]
-pub fn list() -> Result<Vec<ImageListEntry>> {
-    let images: Vec<ImageListEntry> = Command::new("podman")
-        .args([
-            "images",
-            "--format",
-            "json",
-            "--filter=label=containers.bootc=1",
-        ])
-        .run_and_parse_json()
-        .map_err(|e| eyre!("{e}"))?;
-    Ok(images)
-}
-
-/// Inspect a container image and return metadata.
-pub fn inspect(name: &str) -> Result<ImageInspect> {
-    let mut r: Vec<ImageInspect> = Command::new("podman")
-        .args(["image", "inspect", name])
-        .run_and_parse_json()
-        .map_err(|e| eyre!("{e}"))?;
-    r.pop().ok_or_else(|| eyre!("No such image"))
-}
-
-/// Get container image size in bytes for disk space planning.
-pub fn get_image_size(name: &str) -> Result<u64> {
-    tracing::debug!("Getting size for image: {}", name);
-    let info = inspect(name)?;
-    tracing::debug!("Found image size: {} bytes", info.size);
-    Ok(info.size)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -249,6 +300,31 @@ LOGO="fedora-logo-icon"
         }
     }
 
+    #[test]
+    fn test_list_entries() {
+        let json = r#"[
+            {"Id": "aaaa", "Names": ["localhost/a:latest"], "Size": 10,
+             "CreatedAt": "2024-01-02T00:00:00Z", "Digest": "sha256:1111",
+             "Labels": {"containers.bootc": "1"}},
+            {"Id": "bbbb", "Names": ["localhost/b:latest"], "Size": 30,
+             "CreatedAt": "2024-01-01T00:00:00Z",
+             "Labels": {"ostree.bootable": "true"}},
+            {"Id": "cccc", "Names": null, "Size": 20, "Labels": null},
+            {"Id": "dddd", "Size": 40, "Labels": {"containers.bootc": "0"}}
+        ]"#;
+        let mut images: Vec<ImageListEntry> = serde_json::from_str(json).unwrap();
+        let bootc: Vec<bool> = images.iter().map(|i| i.is_bootc()).collect();
+        assert_eq!(bootc, [true, true, false, false]);
+        assert_eq!(images[0].digest.as_deref(), Some("sha256:1111"));
+
+        let ids =
+            |images: &[ImageListEntry]| images.iter().map(|i| i.id.clone()).collect::<Vec<_>>();
+        sort_images(&mut images, ImageSort::Size);
+        assert_eq!(ids(&images), ["dddd", "bbbb", "cccc", "aaaa"]);
+        sort_images(&mut images, ImageSort::Created);
+        assert_eq!(ids(&images), ["aaaa", "bbbb", "dddd", "cccc"]);
+    }
+
     #[test]
     fn test_disk_size_calculation_logic() {
         // Test the logic used in calculate_disk_size
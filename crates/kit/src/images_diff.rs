@@ -0,0 +1,622 @@
+//! Comparing two images at the filesystem level
+//!
+//! `bcvk images diff` lists the packages and files of both images with a
+//! shell script run in a throwaway container of each (see
+//! [`crate::images_inspect::run_script`]), and reports what changed between
+//! them, so that the effect of a rebuild can be checked before installing it.
+//! Files are compared by type, mode, symlink target and SHA-256 digest, or
+//! with `--no-checksums` by size instead of the digest.
+
+use std::collections::BTreeMap;
+
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::images;
+use crate::images_inspect::{parse_sections, run_script};
+
+/// Script listing the packages and files of the image; with `checksums` as
+/// its argument, also the SHA-256 digests of all regular files
+///
+/// File names may contain any character but NUL, so the file listing and
+/// checksums are NUL-terminated records: `TYPE MODE SIZE`, path and symlink
+/// target for each file, and `DIGEST  PATH` for each checksum.
+const LIST_SCRIPT: &str = r#"section() { echo "@@bcvk-inspect@@ $*"; }
+if command -v rpm >/dev/null 2>&1; then
+    section packages
+    rpm -qa --qf '%{NAME}.%{ARCH}\t%|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\n'
+fi
+section files
+find / -xdev -printf '%y %m %s\0%p\0%l\0' 2>/dev/null
+echo
+if test "$1" = checksums; then
+    section checksums
+    find / -xdev -type f -print0 2>/dev/null | xargs -0 -r sha256sum -z 2>/dev/null
+    echo
+fi
+"#;
+
+/// Files podman mounts into every container, which are not part of the image
+const CONTAINER_FILES: &[&str] = &[
+    "/",
+    "/etc/hostname",
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/run/.containerenv",
+];
+
+/// Compare the packages and files of two container images
+#[derive(Debug, Parser)]
+pub struct DiffOpts {
+    /// The image to compare from, e.g. the previous build
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub from: String,
+
+    /// The image to compare to
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub to: String,
+
+    /// Compare regular files by size instead of by SHA-256 digest, which is faster but misses changes keeping the size
+    #[clap(long)]
+    pub no_checksums: bool,
+
+    /// Only show the number of changed packages and files, not the changes themselves
+    #[clap(long)]
+    pub summary: bool,
+
+    /// Output the report as JSON
+    #[clap(long, conflicts_with = "summary")]
+    pub json: bool,
+}
+
+/// How an entry differs between the images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    /// Only in the second image
+    Added,
+    /// Only in the first image
+    Removed,
+    /// In both images, but different
+    Changed,
+}
+
+impl ChangeKind {
+    /// Marker of the change in the text report
+    fn marker(self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Changed => '~',
+        }
+    }
+}
+
+/// A package added, removed or updated
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PackageChange {
+    /// Name and architecture, e.g. `kernel.x86_64`
+    pub name: String,
+    /// What changed
+    pub change: ChangeKind,
+    /// Versions in the first image, as `[EPOCH:]VERSION-RELEASE`; several
+    /// if more than one is installed, like for `kernel` or `gpg-pubkey`
+    pub from: Vec<String>,
+    /// Versions in the second image
+    pub to: Vec<String>,
+}
+
+/// A file added, removed or modified
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    /// Absolute path in the image
+    pub path: String,
+    /// What changed
+    pub change: ChangeKind,
+    /// For changed files, which properties differ: type, mode, target,
+    /// content or size
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<&'static str>,
+}
+
+/// An entry of the file listing of an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileEntry {
+    /// Type as printed by `find -printf %y`, e.g. `f`, `d` or `l`
+    kind: char,
+    /// Permission bits, in octal
+    mode: String,
+    /// Size in bytes
+    size: u64,
+    /// Target of a symlink
+    target: String,
+    /// SHA-256 digest of a regular file, if checksums were computed
+    digest: Option<String>,
+}
+
+/// Packages and files of an image, as listed by [`LIST_SCRIPT`]
+#[derive(Debug, Default)]
+struct ImageListing {
+    /// Sorted versions by package name and architecture; `None` without an
+    /// rpm database
+    packages: Option<BTreeMap<String, Vec<String>>>,
+    files: BTreeMap<String, FileEntry>,
+}
+
+/// The NUL-terminated records of a section of [`LIST_SCRIPT`] output
+fn nul_records(content: &str) -> impl Iterator<Item = &str> {
+    let content = content.strip_suffix('\n').unwrap_or(content);
+    let content = content.strip_suffix('\0').unwrap_or(content);
+    content.split('\0').filter(|_| !content.is_empty())
+}
+
+impl ImageListing {
+    /// Parse the output of [`LIST_SCRIPT`]
+    fn parse(output: &str) -> Result<Self> {
+        let mut listing = Self::default();
+        let mut digests = Vec::new();
+        for (header, content) in parse_sections(output) {
+            match header {
+                "packages" => {
+                    let mut packages: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                    for (name, version) in content.lines().filter_map(|l| l.split_once('\t')) {
+                        packages
+                            .entry(name.to_owned())
+                            .or_default()
+                            .push(version.to_owned());
+                    }
+                    packages.values_mut().for_each(|v| v.sort());
+                    listing.packages = Some(packages);
+                }
+                "files" => {
+                    let mut records = nul_records(&content);
+                    while let Some(meta) = records.next() {
+                        let mut fields = meta.split(' ');
+                        let (Some(kind), Some(mode), Some(size), None, Some(path), Some(target)) = (
+                            fields.next(),
+                            fields.next(),
+                            fields.next(),
+                            fields.next(),
+                            records.next(),
+                            records.next(),
+                        ) else {
+                            return Err(eyre!("Invalid file listing record '{meta}'"));
+                        };
+                        if CONTAINER_FILES.contains(&path) {
+                            continue;
+                        }
+                        let entry = FileEntry {
+                            kind: kind.chars().next().unwrap_or('?'),
+                            mode: mode.to_owned(),
+                            size: size
+                                .parse()
+                                .with_context(|| format!("Invalid size of {path}"))?,
+                            target: target.to_owned(),
+                            digest: None,
+                        };
+                        listing.files.insert(path.to_owned(), entry);
+                    }
+                }
+                "checksums" => {
+                    digests.extend(nul_records(&content).filter_map(|r| {
+                        let (digest, path) = r.split_once("  ")?;
+                        Some((path.to_owned(), digest.to_owned()))
+                    }));
+                }
+                _ => debug!("Ignoring unknown section {header}"),
+            }
+        }
+        for (path, digest) in digests {
+            if let Some(entry) = listing.files.get_mut(&path) {
+                entry.digest = Some(digest);
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Total size of the regular files, in bytes
+    fn files_size(&self) -> u64 {
+        self.files
+            .values()
+            .filter(|e| e.kind == 'f')
+            .map(|e| e.size)
+            .sum()
+    }
+}
+
+/// The entries of `from` and `to` which differ, with `changed` telling the
+/// differences of an entry in both
+fn diff_maps<'a, V, T>(
+    from: &'a BTreeMap<String, V>,
+    to: &'a BTreeMap<String, V>,
+    mut changed: impl FnMut(&'a V, &'a V) -> Option<T>,
+) -> Vec<(&'a str, ChangeKind, Option<T>)> {
+    let mut changes = Vec::new();
+    for (key, old) in from {
+        match to.get(key) {
+            None => changes.push((key.as_str(), ChangeKind::Removed, None)),
+            Some(new) => {
+                if let Some(details) = changed(old, new) {
+                    changes.push((key.as_str(), ChangeKind::Changed, Some(details)));
+                }
+            }
+        }
+    }
+    changes.extend(
+        to.keys()
+            .filter(|key| !from.contains_key(*key))
+            .map(|key| (key.as_str(), ChangeKind::Added, None)),
+    );
+    changes.sort_by(|a, b| a.0.cmp(b.0));
+    changes
+}
+
+/// The packages which differ between two package lists
+fn diff_packages(
+    from: &BTreeMap<String, Vec<String>>,
+    to: &BTreeMap<String, Vec<String>>,
+) -> Vec<PackageChange> {
+    diff_maps(from, to, |old, new| (old != new).then_some(()))
+        .into_iter()
+        .map(|(name, change, _)| PackageChange {
+            name: name.to_owned(),
+            change,
+            from: from.get(name).cloned().unwrap_or_default(),
+            to: to.get(name).cloned().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// How two entries of the same path differ, if they do
+fn file_differences(old: &FileEntry, new: &FileEntry) -> Option<Vec<&'static str>> {
+    let mut details = Vec::new();
+    if old.kind != new.kind {
+        details.push("type");
+    } else {
+        if old.mode != new.mode {
+            details.push("mode");
+        }
+        match old.kind {
+            'l' if old.target != new.target => details.push("target"),
+            'f' => match (&old.digest, &new.digest) {
+                (Some(a), Some(b)) if a != b => details.push("content"),
+                (Some(_), Some(_)) => {}
+                _ if old.size != new.size => details.push("size"),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    (!details.is_empty()).then_some(details)
+}
+
+/// The files which differ between two listings
+fn diff_files(
+    from: &BTreeMap<String, FileEntry>,
+    to: &BTreeMap<String, FileEntry>,
+) -> Vec<FileChange> {
+    diff_maps(from, to, file_differences)
+        .into_iter()
+        .map(|(path, change, details)| FileChange {
+            path: path.to_owned(),
+            change,
+            details: details.unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Result of comparing two images
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    /// The image compared from
+    pub from: String,
+    /// The image compared to
+    pub to: String,
+    /// Changed packages; `None` if an image has no rpm database
+    pub packages: Option<Vec<PackageChange>>,
+    /// Changed files
+    pub files: Vec<FileChange>,
+    /// Size of the first image in container storage, in bytes
+    pub from_image_size: u64,
+    /// Size of the second image in container storage, in bytes
+    pub to_image_size: u64,
+    /// Total size of the regular files of the first image, in bytes
+    pub from_files_size: u64,
+    /// Total size of the regular files of the second image, in bytes
+    pub to_files_size: u64,
+}
+
+/// Count the changes of each kind
+fn count_changes(changes: impl Iterator<Item = ChangeKind>) -> String {
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for change in changes {
+        match change {
+            ChangeKind::Added => added += 1,
+            ChangeKind::Removed => removed += 1,
+            ChangeKind::Changed => changed += 1,
+        }
+    }
+    format!("{added} added, {removed} removed, {changed} changed")
+}
+
+/// A size change, e.g. `1.20 GiB -> 1.25 GiB (+51.20 MiB)`
+fn size_change(from: u64, to: u64) -> String {
+    let sign = if to >= from { '+' } else { '-' };
+    format!(
+        "{} -> {} ({sign}{})",
+        HumanBytes(from),
+        HumanBytes(to),
+        HumanBytes(from.abs_diff(to))
+    )
+}
+
+impl DiffReport {
+    fn print(&self, summary: bool) {
+        println!("Comparing {} to {}", self.from, self.to);
+        match &self.packages {
+            Some(packages) => {
+                println!(
+                    "Packages: {}",
+                    count_changes(packages.iter().map(|p| p.change))
+                );
+                if !summary {
+                    for p in packages {
+                        let (from, to) = (p.from.join(", "), p.to.join(", "));
+                        let version = match p.change {
+                            ChangeKind::Changed => format!("{from} -> {to}"),
+                            ChangeKind::Removed => from,
+                            ChangeKind::Added => to,
+                        };
+                        println!("  {} {} {version}", p.change.marker(), p.name);
+                    }
+                }
+            }
+            None => println!("Packages: unknown (no rpm database)"),
+        }
+        println!(
+            "Files: {}",
+            count_changes(self.files.iter().map(|f| f.change))
+        );
+        if !summary {
+            for f in &self.files {
+                if f.details.is_empty() {
+                    println!("  {} {}", f.change.marker(), f.path);
+                } else {
+                    println!(
+                        "  {} {} ({})",
+                        f.change.marker(),
+                        f.path,
+                        f.details.join(", ")
+                    );
+                }
+            }
+        }
+        println!(
+            "Image size: {}",
+            size_change(self.from_image_size, self.to_image_size)
+        );
+        println!(
+            "Files size: {}",
+            size_change(self.from_files_size, self.to_files_size)
+        );
+    }
+}
+
+/// Compare two images, printing a report
+pub fn run(opts: DiffOpts) -> Result<()> {
+    let args: &[&str] = if opts.no_checksums {
+        &[]
+    } else {
+        &["checksums"]
+    };
+    // Keep imported images around for the container runs as well
+    let mut listings = Vec::new();
+    for image in [&opts.from, &opts.to] {
+        let imported = if images::needs_import(image) {
+            Some(images::import(image)?)
+        } else {
+            None
+        };
+        let name = imported.as_ref().map_or(image.as_str(), |i| i.id());
+        let info = images::inspect(name)?;
+        let output = run_script(&info.id, LIST_SCRIPT, args)?;
+        let listing = ImageListing::parse(&output)
+            .with_context(|| format!("Listing the files of {image}"))?;
+        listings.push((info, listing));
+    }
+    let [(from_info, from), (to_info, to)] =
+        <[_; 2]>::try_from(listings).map_err(|_| eyre!("Expected the listings of two images"))?;
+
+    let packages = match (&from.packages, &to.packages) {
+        (Some(a), Some(b)) => Some(diff_packages(a, b)),
+        _ => None,
+    };
+    let report = DiffReport {
+        from: opts.from,
+        to: opts.to,
+        packages,
+        files: diff_files(&from.files, &to.files),
+        from_image_size: from_info.size,
+        to_image_size: to_info.size,
+        from_files_size: from.files_size(),
+        to_files_size: to.files_size(),
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print(opts.summary);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(output: &str) -> ImageListing {
+        ImageListing::parse(output).unwrap()
+    }
+
+    #[test]
+    fn test_parse_listing() {
+        let output = "@@bcvk-inspect@@ packages
+bash.x86_64\t5.2.37-1.fc42
+kernel.x86_64\t6.14.2-100.fc42
+kernel.x86_64\t6.14.0-63.fc42
+@@bcvk-inspect@@ files
+d 555 4096\0/\0\0\
+d 755 4096\0/usr\0\0\
+f 755 1234\0/usr/bin/bash\0\0\
+l 777 4\0/usr/bin/sh\0bash\0\
+l 777 3\0/usr/lib/odd\tname\0a\tb\0\
+f 644 10\0/etc/hosts\0\0
+@@bcvk-inspect@@ checksums
+aaaa  /usr/bin/bash\0\
+bbbb  /etc/hosts\0
+";
+        let parsed = listing(output);
+        let packages = parsed.packages.as_ref().unwrap();
+        assert_eq!(packages["bash.x86_64"], ["5.2.37-1.fc42"]);
+        assert_eq!(
+            packages["kernel.x86_64"],
+            ["6.14.0-63.fc42", "6.14.2-100.fc42"]
+        );
+        let paths: Vec<_> = parsed.files.keys().map(String::as_str).collect();
+        assert_eq!(
+            paths,
+            ["/usr", "/usr/bin/bash", "/usr/bin/sh", "/usr/lib/odd\tname"]
+        );
+        assert_eq!(parsed.files["/usr/lib/odd\tname"].target, "a\tb");
+        let bash = &parsed.files["/usr/bin/bash"];
+        assert_eq!((bash.kind, bash.size), ('f', 1234));
+        assert_eq!(bash.digest.as_deref(), Some("aaaa"));
+        assert_eq!(parsed.files["/usr/bin/sh"].target, "bash");
+        assert_eq!(parsed.files_size(), 1234);
+
+        let no_rpm = listing("@@bcvk-inspect@@ files\n");
+        assert!(no_rpm.packages.is_none());
+        assert!(ImageListing::parse("@@bcvk-inspect@@ files\nf 644\0/x\0\0\n").is_err());
+        assert!(ImageListing::parse("@@bcvk-inspect@@ files\nf 644 1\0/x\n").is_err());
+    }
+
+    #[test]
+    fn test_diff_packages() {
+        let from = listing(
+            "@@bcvk-inspect@@ packages\nbash.x86_64\t5.2.37-1.fc42\nkernel.x86_64\t6.14.0-63.fc42\nnano.x86_64\t8.3-2.fc42\n",
+        );
+        let to = listing(
+            "@@bcvk-inspect@@ packages\nbash.x86_64\t5.2.37-1.fc42\nhtop.x86_64\t3.3.0-5.fc42\nkernel.x86_64\t6.14.2-100.fc42\nkernel.x86_64\t6.14.0-63.fc42\n",
+        );
+        let changes = diff_packages(
+            from.packages.as_ref().unwrap(),
+            to.packages.as_ref().unwrap(),
+        );
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.change, c.from.join(","), c.to.join(",")))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "htop.x86_64",
+                    ChangeKind::Added,
+                    String::new(),
+                    "3.3.0-5.fc42".to_owned()
+                ),
+                // A second kernel was installed
+                (
+                    "kernel.x86_64",
+                    ChangeKind::Changed,
+                    "6.14.0-63.fc42".to_owned(),
+                    "6.14.0-63.fc42,6.14.2-100.fc42".to_owned()
+                ),
+                (
+                    "nano.x86_64",
+                    ChangeKind::Removed,
+                    "8.3-2.fc42".to_owned(),
+                    String::new()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let from = listing(
+            "@@bcvk-inspect@@ files
+d 755 4096\0/usr/lib\0\0\
+f 644 10\0/usr/lib/os-release\0\0\
+f 755 20\0/usr/bin/tool\0\0\
+l 777 4\0/usr/bin/sh\0bash\0\
+f 644 30\0/usr/share/old\0\0\
+f 644 5\0/usr/share/same\0\0
+@@bcvk-inspect@@ checksums
+aaaa  /usr/lib/os-release\0\
+bbbb  /usr/bin/tool\0\
+cccc  /usr/share/old\0\
+dddd  /usr/share/same\0
+",
+        );
+        let to = listing(
+            "@@bcvk-inspect@@ files
+d 755 8192\0/usr/lib\0\0\
+f 644 10\0/usr/lib/os-release\0\0\
+f 644 20\0/usr/bin/tool\0\0\
+f 755 100\0/usr/bin/sh\0\0\
+f 644 40\0/usr/share/new\0\0\
+f 644 5\0/usr/share/same\0\0
+@@bcvk-inspect@@ checksums
+ffff  /usr/lib/os-release\0\
+bbbb  /usr/bin/tool\0\
+eeee  /usr/bin/sh\0\
+gggg  /usr/share/new\0\
+dddd  /usr/share/same\0
+",
+        );
+        let changes = diff_files(&from.files, &to.files);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.change, c.details.join(",")))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/usr/bin/sh", ChangeKind::Changed, "type".to_owned()),
+                ("/usr/bin/tool", ChangeKind::Changed, "mode".to_owned()),
+                (
+                    "/usr/lib/os-release",
+                    ChangeKind::Changed,
+                    "content".to_owned()
+                ),
+                ("/usr/share/new", ChangeKind::Added, String::new()),
+                ("/usr/share/old", ChangeKind::Removed, String::new()),
+            ]
+        );
+
+        // Without checksums, only the size tells a content change
+        let old = FileEntry {
+            kind: 'f',
+            mode: "644".into(),
+            size: 10,
+            target: String::new(),
+            digest: None,
+        };
+        let new = FileEntry {
+            size: 11,
+            ..old.clone()
+        };
+        assert_eq!(file_differences(&old, &new), Some(vec!["size"]));
+        assert_eq!(file_differences(&old, &old), None);
+    }
+
+    #[test]
+    fn test_size_change() {
+        assert_eq!(size_change(1024, 3072), "1.00 KiB -> 3.00 KiB (+2.00 KiB)");
+        assert_eq!(size_change(3072, 1024), "3.00 KiB -> 1.00 KiB (-2.00 KiB)");
+    }
+}
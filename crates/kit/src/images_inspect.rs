@@ -0,0 +1,371 @@
+//! Bootc-specific inspection of container images
+//!
+//! `bcvk images inspect` goes beyond `podman image inspect` by looking into
+//! the image contents: a shell script run in a throwaway container (without
+//! network, and never booting anything) prints the kernels, provisioning
+//! agents, kernel arguments from `/usr/lib/bootc/kargs.d`, the SBOM if the
+//! image ships one and the disk usage of the root filesystem. Its output is
+//! split into sections by lines starting with [`SECTION_MARKER`].
+
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::images;
+
+/// Label with the ostree commit an image was built from
+const OSTREE_COMMIT_LABEL: &str = "ostree.commit";
+
+/// Prefix of the lines separating the sections of the script output; files
+/// are followed by an empty line in case they lack a final newline
+const SECTION_MARKER: &str = "@@bcvk-inspect@@ ";
+
+/// Script printing the contents of the image to report on
+const INSPECT_SCRIPT: &str = r#"section() { echo "@@bcvk-inspect@@ $*"; }
+section kernels
+for d in /usr/lib/modules/*/; do
+    if test -f "${d}vmlinuz"; then basename "$d"; fi
+done
+section provisioning
+if test -x /usr/bin/cloud-init; then echo cloud-init; fi
+if test -x /usr/bin/ignition || ls -d /usr/lib/dracut/modules.d/*ignition >/dev/null 2>&1; then
+    echo ignition
+fi
+for f in /usr/lib/bootc/kargs.d/*.toml; do
+    if test -f "$f"; then section kargs "$f"; cat "$f"; echo; fi
+done
+sbom=$(find /usr/share/buildinfo /usr/share/sbom /root/buildinfo -type f \
+    \( -name '*.spdx.json' -o -name '*.cdx.json' \) 2>/dev/null | sort | head -n 1)
+if test -n "$sbom"; then section sbom "$sbom"; cat "$sbom"; echo; fi
+section installed-size
+du -skx / 2>/dev/null | cut -f1
+"#;
+
+/// Show a bootc-focused report on the contents of a container image
+#[derive(Debug, Parser)]
+pub struct InspectOpts {
+    /// Container image to inspect
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub image: String,
+
+    /// Output the report as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// A kernel arguments file from `/usr/lib/bootc/kargs.d`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KargsToml {
+    kargs: Vec<String>,
+    #[serde(default)]
+    match_architectures: Vec<String>,
+}
+
+/// Kernel arguments added by bootc at install time
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct KargsFile {
+    /// Path of the file in the image
+    pub path: String,
+    /// The kernel arguments
+    pub kargs: Vec<String>,
+    /// Architectures the arguments are restricted to; empty for all
+    pub architectures: Vec<String>,
+}
+
+/// Summary of the software bill of materials shipped in the image
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SbomSummary {
+    /// Path of the SBOM in the image
+    pub path: String,
+    /// Format and version, e.g. `SPDX-2.3`
+    pub format: String,
+    /// Number of packages or components listed
+    pub packages: Option<usize>,
+}
+
+/// Result of inspecting an image
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    /// The inspected image
+    pub image: String,
+    /// Image ID
+    pub id: String,
+    /// Image manifest digest
+    pub digest: String,
+    /// Image creation timestamp
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether the image is labeled as a bootc image
+    pub bootc: bool,
+    /// The ostree commit the image was built from, if any
+    pub ostree_commit: Option<String>,
+    /// Versions of the kernels in `/usr/lib/modules`
+    pub kernels: Vec<String>,
+    /// Whether cloud-init is installed
+    pub cloud_init: bool,
+    /// Whether Ignition is installed
+    pub ignition: bool,
+    /// Default kernel arguments
+    pub kargs: Vec<KargsFile>,
+    /// SBOM summary, if the image ships one
+    pub sbom: Option<SbomSummary>,
+    /// Size of the image in container storage, in bytes
+    pub image_size: u64,
+    /// Estimated size of the root filesystem once installed, in bytes
+    pub installed_size: Option<u64>,
+}
+
+/// Split the script output into `(header, content)` sections
+pub(crate) fn parse_sections(output: &str) -> Vec<(&str, String)> {
+    let mut sections: Vec<(&str, String)> = Vec::new();
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix(SECTION_MARKER) {
+            sections.push((header, String::new()));
+        } else if let Some((_, content)) = sections.last_mut() {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+    sections
+}
+
+/// Summarize an SPDX or CycloneDX JSON document
+fn summarize_sbom(path: &str, content: &str) -> Result<SbomSummary> {
+    let doc: serde_json::Value =
+        serde_json::from_str(content).with_context(|| format!("Parsing SBOM {path}"))?;
+    let count = |key: &str| doc.get(key).and_then(|v| v.as_array()).map(Vec::len);
+    let (format, packages) = if let Some(version) = doc.get("spdxVersion").and_then(|v| v.as_str())
+    {
+        (version.to_owned(), count("packages"))
+    } else if doc.get("bomFormat").and_then(|v| v.as_str()) == Some("CycloneDX") {
+        let version = doc
+            .get("specVersion")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+        (format!("CycloneDX-{version}"), count("components"))
+    } else {
+        ("unknown".to_owned(), None)
+    };
+    Ok(SbomSummary {
+        path: path.to_owned(),
+        format,
+        packages,
+    })
+}
+
+/// Contents of the image, as reported by [`INSPECT_SCRIPT`]
+#[derive(Debug, Default)]
+struct ImageContents {
+    kernels: Vec<String>,
+    cloud_init: bool,
+    ignition: bool,
+    kargs: Vec<KargsFile>,
+    sbom: Option<SbomSummary>,
+    installed_size: Option<u64>,
+}
+
+impl ImageContents {
+    /// Parse the output of [`INSPECT_SCRIPT`]
+    fn parse(output: &str) -> Result<Self> {
+        let mut contents = Self::default();
+        for (header, content) in parse_sections(output) {
+            let (name, path) = header.split_once(' ').unwrap_or((header, ""));
+            match name {
+                "kernels" => contents.kernels = content.lines().map(str::to_owned).collect(),
+                "provisioning" => {
+                    contents.cloud_init = content.lines().any(|l| l == "cloud-init");
+                    contents.ignition = content.lines().any(|l| l == "ignition");
+                }
+                "kargs" => {
+                    let kargs: KargsToml =
+                        toml::from_str(&content).with_context(|| format!("Parsing {path}"))?;
+                    contents.kargs.push(KargsFile {
+                        path: path.to_owned(),
+                        kargs: kargs.kargs,
+                        architectures: kargs.match_architectures,
+                    });
+                }
+                "sbom" => contents.sbom = Some(summarize_sbom(path, &content)?),
+                "installed-size" => {
+                    contents.installed_size =
+                        content.trim().parse::<u64>().ok().map(|kib| kib * 1024);
+                }
+                _ => debug!("Ignoring unknown section {header}"),
+            }
+        }
+        Ok(contents)
+    }
+}
+
+/// Run the shell script `script` with `args` in a throwaway container of
+/// the image, returning its output
+pub(crate) fn run_script(image_id: &str, script: &str, args: &[&str]) -> Result<String> {
+    let output = images::podman()
+        .args(["run", "--rm", "--net=none", "--pull=never", "--user=0"])
+        .args(["--entrypoint", "/bin/sh", image_id, "-c", script, "sh"])
+        .args(args)
+        .output()
+        .with_context(|| "Failed to run podman")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "Failed to inspect the contents of {image_id}: {}",
+            stderr.trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run [`INSPECT_SCRIPT`] in a container of the image
+fn inspect_contents(image_id: &str) -> Result<ImageContents> {
+    ImageContents::parse(&run_script(image_id, INSPECT_SCRIPT, &[])?)
+}
+
+impl InspectReport {
+    fn print(&self) {
+        let yes_no = |b| if b { "yes" } else { "no" };
+        let or_none = |s: Option<String>| s.unwrap_or_else(|| "none".to_owned());
+        println!("Image:           {}", self.image);
+        println!("ID:              {}", self.id);
+        println!("Digest:          {}", self.digest);
+        if let Some(created) = self.created {
+            println!(
+                "Created:         {}",
+                created.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+        println!("Bootc:           {}", yes_no(self.bootc));
+        println!("OSTree commit:   {}", or_none(self.ostree_commit.clone()));
+        let kernels = (!self.kernels.is_empty()).then(|| self.kernels.join(", "));
+        println!("Kernel:          {}", or_none(kernels));
+        println!("cloud-init:      {}", yes_no(self.cloud_init));
+        println!("Ignition:        {}", yes_no(self.ignition));
+        if self.kargs.is_empty() {
+            println!("Kernel args:     none");
+        } else {
+            println!("Kernel args:");
+            for file in &self.kargs {
+                let arches = if file.architectures.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", file.architectures.join(", "))
+                };
+                println!("  {}{arches}: {}", file.path, file.kargs.join(" "));
+            }
+        }
+        let sbom = self.sbom.as_ref().map(|s| match s.packages {
+            Some(n) => format!("{}, {n} packages ({})", s.format, s.path),
+            None => format!("{} ({})", s.format, s.path),
+        });
+        println!("SBOM:            {}", or_none(sbom));
+        println!("Image size:      {}", HumanBytes(self.image_size));
+        let installed = self
+            .installed_size
+            .map(|s| format!("~{} (estimated)", HumanBytes(s)));
+        println!(
+            "Installed size:  {}",
+            installed.unwrap_or_else(|| "unknown".to_owned())
+        );
+    }
+}
+
+/// Inspect an image, printing a report
+pub fn run(opts: InspectOpts) -> Result<()> {
+    // Keep an imported image around for the container run as well
+    let imported = if images::needs_import(&opts.image) {
+        Some(images::import(&opts.image)?)
+    } else {
+        None
+    };
+    let name = imported.as_ref().map_or(opts.image.as_str(), |i| i.id());
+    let info = images::inspect(name)?;
+    if !info.is_bootc() {
+        tracing::warn!("{} is not labeled as a bootc image", opts.image);
+    }
+    let contents = inspect_contents(&info.id)?;
+
+    let report = InspectReport {
+        image: opts.image,
+        id: info.id.clone(),
+        digest: info.digest.to_string(),
+        created: info.created,
+        bootc: info.is_bootc(),
+        ostree_commit: info.label(OSTREE_COMMIT_LABEL).map(str::to_owned),
+        kernels: contents.kernels,
+        cloud_init: contents.cloud_init,
+        ignition: contents.ignition,
+        kargs: contents.kargs,
+        sbom: contents.sbom,
+        image_size: info.size,
+        installed_size: contents.installed_size,
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_contents() -> Result<()> {
+        let output = r#"@@bcvk-inspect@@ kernels
+6.14.0-63.fc42.x86_64
+@@bcvk-inspect@@ provisioning
+cloud-init
+@@bcvk-inspect@@ kargs /usr/lib/bootc/kargs.d/10-console.toml
+### Serial console
+kargs = ["console=ttyS0,115200n8"]
+match-architectures = ["x86_64"]
+@@bcvk-inspect@@ sbom /usr/share/buildinfo/image.spdx.json
+{"spdxVersion": "SPDX-2.3", "packages": [{"name": "bash"}, {"name": "kernel"}]}
+@@bcvk-inspect@@ installed-size
+2048
+"#;
+        let contents = ImageContents::parse(output)?;
+        assert_eq!(contents.kernels, ["6.14.0-63.fc42.x86_64"]);
+        assert!(contents.cloud_init);
+        assert!(!contents.ignition);
+        assert_eq!(
+            contents.kargs,
+            [KargsFile {
+                path: "/usr/lib/bootc/kargs.d/10-console.toml".into(),
+                kargs: vec!["console=ttyS0,115200n8".into()],
+                architectures: vec!["x86_64".into()],
+            }]
+        );
+        assert_eq!(
+            contents.sbom,
+            Some(SbomSummary {
+                path: "/usr/share/buildinfo/image.spdx.json".into(),
+                format: "SPDX-2.3".into(),
+                packages: Some(2),
+            })
+        );
+        assert_eq!(contents.installed_size, Some(2 * 1024 * 1024));
+
+        let empty = ImageContents::parse("@@bcvk-inspect@@ kernels\n")?;
+        assert!(empty.kernels.is_empty());
+        assert_eq!(empty.installed_size, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarize_sbom() -> Result<()> {
+        let cdx = r#"{"bomFormat": "CycloneDX", "specVersion": "1.5", "components": []}"#;
+        let summary = summarize_sbom("/sbom.cdx.json", cdx)?;
+        assert_eq!(summary.format, "CycloneDX-1.5");
+        assert_eq!(summary.packages, Some(0));
+
+        assert_eq!(summarize_sbom("/x.json", "{}")?.format, "unknown");
+        assert!(summarize_sbom("/x.json", "not json").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+//! Boot smoke test for bootc container images
+//!
+//! `bcvk images verify` boots an image in an ephemeral VM and runs a fixed set
+//! of checks in it over SSH: that systemd reaches a running state, that
+//! `bootc status` works, and that composefs and fs-verity are in use if the
+//! image asks for them. Each check is a small shell script whose exit code is
+//! its result: 0 passes, [`SKIP_EXIT_CODE`] means the check does not apply to
+//! the image, and anything else fails.
+
+use std::process::Stdio;
+
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanDuration;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::run_ephemeral::{run_detached, CommonPodmanOptions, CommonVmOpts, RunEphemeralOpts};
+use crate::run_ephemeral_ssh::{wait_for_ssh_ready, ContainerCleanup};
+use crate::ssh::{self, SshConnectionOptions};
+
+/// Exit code of a check script that does not apply to the image (as in automake)
+const SKIP_EXIT_CODE: i32 = 77;
+
+/// Shell function printing the `[composefs] enabled` value of the image's
+/// ostree prepare-root configuration, if any.
+const COMPOSEFS_ENABLED_FN: &str = r#"composefs_enabled() {
+    local f
+    for f in /etc/ostree/prepare-root.conf /usr/lib/ostree/prepare-root.conf; do
+        if test -f "$f"; then
+            awk -F= '/^\[/ { s = $0 } s == "[composefs]" && $1 ~ /^ *enabled *$/ { gsub(/ /, "", $2); print $2 }' "$f"
+            return 0
+        fi
+    done
+}
+"#;
+
+/// A check run in the booted VM
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    script: &'static str,
+    /// Further verification run from the host if the script passed
+    followup: Option<fn(&str, &mut CheckResult) -> Result<()>>,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "systemd",
+        script: r#"state=$(systemctl is-system-running --wait)
+echo "system state: ${state}"
+if test "${state}" != running; then
+    systemctl --failed --no-legend
+    exit 1
+fi
+"#,
+        followup: None,
+    },
+    Check {
+        name: "bootc-status",
+        script: "bootc status",
+        followup: None,
+    },
+    Check {
+        name: "composefs",
+        script: r#"enabled=$(composefs_enabled)
+case "${enabled}" in
+    yes|true|verity|signed) echo "composefs enabled (${enabled})" ;;
+    "") echo "composefs is not configured"; exit 77 ;;
+    *) echo "composefs is not enabled (${enabled})"; exit 77 ;;
+esac
+# composefs mounts an EROFS image overlaid onto the object store
+if ! grep -qw erofs /proc/filesystems && ! modinfo erofs &>/dev/null; then
+    echo "kernel $(uname -r) does not support EROFS, which composefs requires"
+    exit 1
+fi
+"#,
+        followup: Some(check_composefs_root),
+    },
+    Check {
+        name: "fs-verity",
+        script: r#"enabled=$(composefs_enabled)
+case "${enabled}" in
+    verity|signed) ;;
+    *) echo "fs-verity is not required by the composefs configuration"; exit 77 ;;
+esac
+kver=$(uname -r)
+if ! grep -qs '^CONFIG_FS_VERITY=y' "/usr/lib/modules/${kver}/config"; then
+    echo "kernel ${kver} is not built with CONFIG_FS_VERITY"
+    exit 1
+fi
+echo "kernel ${kver} supports fs-verity"
+if ! test -e /run/ostree-booted; then
+    echo "not booted from an ostree deployment, no file measured"
+    exit 0
+fi
+if ! command -v fsverity &>/dev/null; then
+    echo "fsverity is not installed in the image, cannot measure deployed files"
+    exit 77
+fi
+# Deployed files are hardlinks to the content objects of the ostree repository
+obj=$(find /sysroot/ostree/repo/objects -type f -name '*.file' -size +0 -print -quit)
+if test -z "${obj}"; then
+    echo "no content objects found in /sysroot/ostree/repo"
+    exit 1
+fi
+fsverity measure "${obj}"
+"#,
+        followup: None,
+    },
+];
+
+/// Boot an image in an ephemeral VM and check that it comes up healthy
+#[derive(Debug, Parser)]
+pub struct VerifyOpts {
+    /// Container image to verify
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub image: String,
+
+    /// Output the report as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    /// Common VM configuration options
+    #[clap(flatten)]
+    pub common: CommonVmOpts,
+}
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// The check succeeded
+    Pass,
+    /// The check failed
+    Fail,
+    /// The check does not apply to this image
+    Skip,
+}
+
+impl CheckStatus {
+    /// Map the exit code of a check script to its outcome
+    fn from_exit_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => Self::Pass,
+            Some(SKIP_EXIT_CODE) => Self::Skip,
+            _ => Self::Fail,
+        }
+    }
+}
+
+impl std::fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Pass => "PASS",
+            Self::Fail => "FAIL",
+            Self::Skip => "SKIP",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Result of a single check
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    /// Name of the check
+    pub name: String,
+    /// Outcome
+    pub status: CheckStatus,
+    /// Output of the check
+    pub detail: String,
+}
+
+/// Result of verifying an image
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    /// The verified image
+    pub image: String,
+    /// Whether no check failed
+    pub passed: bool,
+    /// Results of the individual checks, in the order they ran
+    pub checks: Vec<CheckResult>,
+}
+
+impl VerifyReport {
+    fn new(image: String, checks: Vec<CheckResult>) -> Self {
+        let passed = checks.iter().all(|c| c.status != CheckStatus::Fail);
+        Self {
+            image,
+            passed,
+            checks,
+        }
+    }
+
+    fn count(&self, status: CheckStatus) -> usize {
+        self.checks.iter().filter(|c| c.status == status).count()
+    }
+
+    fn print(&self) {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &self.checks {
+            let mut lines = check.detail.lines();
+            let summary = lines.next().unwrap_or_default();
+            println!("{}  {:width$}  {summary}", check.status, check.name);
+            if check.status == CheckStatus::Fail {
+                for line in lines {
+                    println!("      {line}");
+                }
+            }
+        }
+        println!(
+            "\n{}: {} ({} passed, {} failed, {} skipped)",
+            self.image,
+            if self.passed { "PASS" } else { "FAIL" },
+            self.count(CheckStatus::Pass),
+            self.count(CheckStatus::Fail),
+            self.count(CheckStatus::Skip),
+        );
+    }
+}
+
+/// A filesystem as reported by `findmnt -J`
+#[derive(Debug, Deserialize)]
+struct Filesystem {
+    source: String,
+    fstype: String,
+}
+
+/// Output of `findmnt -J`
+#[derive(Debug, Deserialize)]
+struct Findmnt {
+    filesystems: Vec<Filesystem>,
+}
+
+impl Findmnt {
+    /// Parse `findmnt -J -o SOURCE,FSTYPE /`, returning the root filesystem
+    fn parse_root(json: &str) -> Result<Filesystem> {
+        let findmnt: Self = serde_json::from_str(json).context("Parsing findmnt output")?;
+        findmnt
+            .filesystems
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("findmnt reported no filesystem for /"))
+    }
+}
+
+impl Filesystem {
+    /// Whether this is the overlay ostree mounts for a composefs image
+    fn is_composefs(&self) -> bool {
+        self.fstype == "overlay" && self.source == "composefs"
+    }
+}
+
+/// Run a shell script in the VM via SSH
+fn run_script(container_id: &str, script: String) -> Result<std::process::Output> {
+    let args = vec!["/bin/bash".to_string(), "-c".to_string(), script];
+    let options = SshConnectionOptions {
+        allocate_tty: false,
+        ..SshConnectionOptions::default()
+    };
+    let mut cmd = ssh::ssh_command(container_id, &args, &options)?;
+    cmd.stdin(Stdio::null());
+    Ok(cmd.output()?)
+}
+
+/// Check that `/` is mounted from the composefs image of an ostree deployment
+fn check_composefs_root(container_id: &str, result: &mut CheckResult) -> Result<()> {
+    let script = "test -e /run/ostree-booted || exit 0; findmnt -J -o SOURCE,FSTYPE /";
+    let output = run_script(container_id, script.to_string()).context("Running findmnt")?;
+    if !output.status.success() {
+        result.status = CheckStatus::Fail;
+        result.detail.push_str(&format!(
+            "\nfindmnt failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+        return Ok(());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        result
+            .detail
+            .push_str("\nnot booted from an ostree deployment, root mount not checked");
+        return Ok(());
+    }
+    let root = Findmnt::parse_root(&stdout)?;
+    if root.is_composefs() {
+        result.detail.push_str("\n/ is a composefs overlay");
+    } else {
+        result.status = CheckStatus::Fail;
+        result.detail.push_str(&format!(
+            "\n/ is not a composefs overlay (source {}, type {})",
+            root.source, root.fstype
+        ));
+    }
+    Ok(())
+}
+
+/// Run a check in the VM via SSH
+fn run_check(container_id: &str, check: &Check) -> Result<CheckResult> {
+    debug!("Running check {}", check.name);
+    let script = format!("{COMPOSEFS_ENABLED_FN}{}", check.script);
+    let output = run_script(container_id, script)
+        .with_context(|| format!("Running check {}", check.name))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = [stdout.trim(), stderr.trim()]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut result = CheckResult {
+        name: check.name.to_string(),
+        status: CheckStatus::from_exit_code(output.status.code()),
+        detail,
+    };
+    if let (CheckStatus::Pass, Some(followup)) = (result.status, check.followup) {
+        followup(container_id, &mut result)
+            .with_context(|| format!("Running check {}", check.name))?;
+    }
+    Ok(result)
+}
+
+/// Verify an image, printing a report; fails if any check failed
+pub fn run(opts: VerifyOpts) -> Result<()> {
+    let mut common = opts.common.clone();
+    common.ssh_keygen = true;
+
+    let ephemeral_opts = RunEphemeralOpts {
+        image: opts.image.clone(),
+        common,
+        podman: CommonPodmanOptions {
+            rm: true,
+            detach: true,
+            ..Default::default()
+        },
+        force: true,
+        ..Default::default()
+    };
+
+    let container_id = run_detached(ephemeral_opts)?;
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
+    let _cleanup = ContainerCleanup::new(container_id.clone());
+
+    let mut checks = Vec::new();
+    let progress_bar = crate::boot_progress::create_boot_progress_bar();
+    match wait_for_ssh_ready(&container_id, None, progress_bar) {
+        Ok((duration, progress_bar)) => {
+            progress_bar.finish_and_clear();
+            checks.push(CheckResult {
+                name: "boot".to_string(),
+                status: CheckStatus::Pass,
+                detail: format!("reachable via SSH after {}", HumanDuration(duration)),
+            });
+            for check in CHECKS {
+                checks.push(run_check(&container_id, check)?);
+            }
+        }
+        Err(e) => {
+            checks.push(CheckResult {
+                name: "boot".to_string(),
+                status: CheckStatus::Fail,
+                detail: format!("{e:#}"),
+            });
+            checks.extend(CHECKS.iter().map(|check| CheckResult {
+                name: check.name.to_string(),
+                status: CheckStatus::Skip,
+                detail: "VM did not boot".to_string(),
+            }));
+        }
+    }
+
+    let report = VerifyReport::new(opts.image, checks);
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print();
+    }
+
+    if !report.passed {
+        return Err(eyre!(
+            "Verification of {} failed: {} check(s) failed",
+            report.image,
+            report.count(CheckStatus::Fail)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_from_exit_code() {
+        assert_eq!(CheckStatus::from_exit_code(Some(0)), CheckStatus::Pass);
+        assert_eq!(CheckStatus::from_exit_code(Some(77)), CheckStatus::Skip);
+        assert_eq!(CheckStatus::from_exit_code(Some(1)), CheckStatus::Fail);
+        // Killed by a signal
+        assert_eq!(CheckStatus::from_exit_code(None), CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_findmnt_root() {
+        let cases = [
+            (
+                r#"{"filesystems": [{"source": "composefs", "fstype": "overlay"}]}"#,
+                true,
+            ),
+            (
+                r#"{"filesystems": [{"source": "overlay", "fstype": "overlay"}]}"#,
+                false,
+            ),
+            (
+                r#"{"filesystems": [{"source": "/dev/vda4", "fstype": "xfs"}]}"#,
+                false,
+            ),
+        ];
+        for (json, expected) in cases {
+            let root = Findmnt::parse_root(json).unwrap();
+            assert_eq!(root.is_composefs(), expected, "{json}");
+        }
+        assert!(Findmnt::parse_root(r#"{"filesystems": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let check = |name: &str, status| CheckResult {
+            name: name.to_string(),
+            status,
+            detail: String::new(),
+        };
+
+        let report = VerifyReport::new(
+            "localhost/test".to_string(),
+            vec![
+                check("boot", CheckStatus::Pass),
+                check("fs-verity", CheckStatus::Skip),
+            ],
+        );
+        assert!(report.passed);
+
+        let report = VerifyReport::new(
+            "localhost/test".to_string(),
+            vec![
+                check("boot", CheckStatus::Pass),
+                check("systemd", CheckStatus::Fail),
+                check("fs-verity", CheckStatus::Skip),
+            ],
+        );
+        assert!(!report.passed);
+        assert_eq!(report.count(CheckStatus::Fail), 1);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["passed"], false);
+        assert_eq!(json["checks"][1]["name"], "systemd");
+        assert_eq!(json["checks"][1]["status"], "fail");
+    }
+}
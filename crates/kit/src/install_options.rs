@@ -5,7 +5,149 @@
 //! and other installation-related commands.
 
 use camino::Utf8PathBuf;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+
+use crate::common_opts::KargProfile;
+
+/// Path at which the `--install-config` file is made available to `bootc install`
+///
+/// bootc merges the files in this directory in lexical order, so this takes
+/// precedence over the configuration shipped in the image.
+pub const INSTALL_CONFIG_PATH: &str = "/usr/lib/bootc/install/99-bcvk.toml";
+
+/// How to encrypt the root filesystem
+///
+/// bootc seals the key to the emulated TPM of the installation VM, whose
+/// state is discarded afterwards, so only a passphrase can unlock the disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptRoot {
+    /// LUKS, unlocked by the passphrase read from the given host file
+    Passphrase(Utf8PathBuf),
+}
+
+impl std::str::FromStr for EncryptRoot {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("passphrase", path)) if !path.is_empty() => Ok(Self::Passphrase(path.into())),
+            None if s == "tpm2" => Err(eyre!(
+                "Root encryption 'tpm2' is not supported: the key would be sealed to the TPM of the installation VM, which is discarded, so the disk could never be unlocked; use 'passphrase:<file>'"
+            )),
+            _ => Err(eyre!(
+                "Invalid root encryption '{s}'. Expected 'passphrase:<file>'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for EncryptRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Passphrase(path) => write!(f, "passphrase:{path}"),
+        }
+    }
+}
+
+/// SELinux mode of the installed system, for `--selinux`
+///
+/// The mode is set with kernel arguments, which take precedence over
+/// `/etc/selinux/config` in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "kebab-case")]
+pub enum SelinuxMode {
+    /// Enforce the policy
+    Enforcing,
+    /// Load the policy, but only log denials
+    Permissive,
+    /// Do not load a policy at all
+    Disabled,
+}
+
+impl SelinuxMode {
+    /// The kernel arguments selecting this mode
+    pub fn kargs(&self) -> &'static [&'static str] {
+        match self {
+            Self::Enforcing => &["enforcing=1"],
+            Self::Permissive => &["enforcing=0"],
+            Self::Disabled => &["selinux=0"],
+        }
+    }
+
+    /// The mode given with `--selinux`, or permissive with the permissive
+    /// `--karg-profile`; both may not disagree
+    pub fn with_karg_profiles(
+        mode: Option<Self>,
+        profiles: &[KargProfile],
+    ) -> Result<Option<Self>> {
+        if !profiles.contains(&KargProfile::Permissive) {
+            return Ok(mode);
+        }
+        match mode {
+            None | Some(Self::Permissive) => Ok(Some(Self::Permissive)),
+            Some(mode) => Err(eyre!(
+                "--karg-profile permissive conflicts with --selinux {mode}"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SelinuxMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Enforcing => "enforcing",
+            Self::Permissive => "permissive",
+            Self::Disabled => "disabled",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for SelinuxMode {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        <Self as ValueEnum>::from_str(s, false).map_err(|_| {
+            eyre!("Invalid SELinux mode '{s}'. Expected enforcing, permissive or disabled")
+        })
+    }
+}
+
+/// A bootc install configuration file, read when the options are parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallConfig {
+    /// Path of the file on the host
+    pub path: Utf8PathBuf,
+    /// Contents of the file
+    pub contents: String,
+}
+
+impl InstallConfig {
+    /// Parse and validate `contents`, read from `path`
+    fn new(path: Utf8PathBuf, contents: String) -> Result<Self> {
+        let table: toml::Table =
+            toml::from_str(&contents).with_context(|| format!("Parsing {path}"))?;
+        // bootc ignores everything else
+        if !table.get("install").is_some_and(|v| v.is_table()) {
+            return Err(eyre!(
+                "{path} has no [install] table; see bootc-install-config(5)"
+            ));
+        }
+        Ok(Self { path, contents })
+    }
+}
+
+impl std::str::FromStr for InstallConfig {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let path = Utf8PathBuf::from(s);
+        let contents = std::fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+        Self::new(path, contents)
+    }
+}
 
 /// Common installation options for bootc disk operations
 ///
@@ -37,9 +179,27 @@ pub struct InstallOptions {
     /// Set a kernel argument
     pub karg: Vec<String>,
 
+    /// SELinux mode of the installed system, set with kernel arguments; --karg arguments come after them
+    #[clap(long, value_enum, value_name = "MODE")]
+    pub selinux: Option<SelinuxMode>,
+
     /// Default to composefs-native storage
     #[clap(long)]
     pub composefs_backend: bool,
+
+    /// Encrypt the root filesystem with LUKS, unlocked by the passphrase
+    /// read from the host file
+    #[clap(long, value_name = "passphrase:FILE")]
+    pub encrypt_root: Option<EncryptRoot>,
+
+    /// bootc install configuration to apply on top of the one in the image
+    ///
+    /// The TOML file is installed as /usr/lib/bootc/install/99-bcvk.toml in
+    /// the installation environment, so it can set anything bootc supports
+    /// there, such as kernel arguments, the root filesystem type and the
+    /// block setup; see bootc-install-config(5).
+    #[clap(long, value_name = "TOML")]
+    pub install_config: Option<InstallConfig>,
 }
 
 impl InstallOptions {
@@ -57,7 +217,8 @@ impl InstallOptions {
             args.push(root_size.clone());
         }
 
-        for k in self.karg.iter() {
+        let selinux_kargs = self.selinux.iter().flat_map(|m| m.kargs().iter().copied());
+        for k in selinux_kargs.chain(self.karg.iter().map(String::as_str)) {
             args.push(format!("--karg={k}"));
         }
 
@@ -70,6 +231,121 @@ impl InstallOptions {
             args.push("--composefs-backend".to_owned());
         }
 
+        // Set up via the TPM; the passphrase replaces it afterwards
+        if self.encrypt_root.is_some() {
+            args.push("--block-setup".to_owned());
+            args.push("tpm2-luks".to_owned());
+        }
+
         args
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_encrypt_root() {
+        assert_eq!(
+            "passphrase:/tmp/pw".parse::<EncryptRoot>().unwrap(),
+            EncryptRoot::Passphrase("/tmp/pw".into())
+        );
+        for invalid in ["", "tpm2", "tpm1", "passphrase:", "passphrase", "tpm2:foo"] {
+            assert!(invalid.parse::<EncryptRoot>().is_err(), "{invalid}");
+        }
+        let v = EncryptRoot::Passphrase("/tmp/pw".into());
+        assert_eq!(v.to_string().parse::<EncryptRoot>().unwrap(), v);
+    }
+
+    #[test]
+    fn test_install_config() {
+        let config = InstallConfig::new(
+            "install.toml".into(),
+            "[install]\nkargs = [\"console=ttyS0\"]\n".into(),
+        )
+        .unwrap();
+        assert_eq!(config.path, "install.toml");
+        for invalid in ["", "kargs = []", "install = 1", "[install"] {
+            assert!(
+                InstallConfig::new("install.toml".into(), invalid.into()).is_err(),
+                "{invalid}"
+            );
+        }
+        assert!("/nonexistent/install.toml"
+            .parse::<InstallConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_selinux_args() {
+        let cases = [
+            (None, vec!["--karg=console=ttyS0"]),
+            (
+                Some(SelinuxMode::Enforcing),
+                vec!["--karg=enforcing=1", "--karg=console=ttyS0"],
+            ),
+            (
+                Some(SelinuxMode::Permissive),
+                vec!["--karg=enforcing=0", "--karg=console=ttyS0"],
+            ),
+            (
+                Some(SelinuxMode::Disabled),
+                vec!["--karg=selinux=0", "--karg=console=ttyS0"],
+            ),
+        ];
+        for (selinux, expected) in cases {
+            let opts = InstallOptions {
+                karg: vec!["console=ttyS0".into()],
+                selinux,
+                ..Default::default()
+            };
+            assert_eq!(opts.to_bootc_args(), expected, "{selinux:?}");
+            if let Some(mode) = selinux {
+                assert_eq!(mode.to_string().parse::<SelinuxMode>().unwrap(), mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_selinux_with_karg_profiles() {
+        use KargProfile::{Debug, Permissive};
+        use SelinuxMode::{Disabled, Enforcing};
+        let cases = [
+            (None, vec![], Some(None)),
+            (Some(Enforcing), vec![Debug], Some(Some(Enforcing))),
+            (
+                None,
+                vec![Debug, Permissive],
+                Some(Some(SelinuxMode::Permissive)),
+            ),
+            (
+                Some(SelinuxMode::Permissive),
+                vec![Permissive],
+                Some(Some(SelinuxMode::Permissive)),
+            ),
+            (Some(Enforcing), vec![Permissive], None),
+            (Some(Disabled), vec![Permissive], None),
+        ];
+        for (mode, profiles, expected) in cases {
+            let r = SelinuxMode::with_karg_profiles(mode, &profiles).ok();
+            assert_eq!(r, expected, "{mode:?} {profiles:?}");
+        }
+    }
+
+    #[test]
+    fn test_encrypt_root_args() {
+        let opts = InstallOptions::default();
+        assert!(!opts.to_bootc_args().iter().any(|a| a == "--block-setup"));
+
+        let opts = InstallOptions {
+            filesystem: Some("xfs".into()),
+            encrypt_root: Some(EncryptRoot::Passphrase("pw".into())),
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.to_bootc_args(),
+            ["--filesystem", "xfs", "--block-setup", "tpm2-luks"]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Cache of kernels and initramfs images extracted for ephemeral VMs
+//!
+//! For images shipping a UKI, the kernel and initramfs have to be extracted
+//! with `objcopy` before every ephemeral boot. The extracted files are kept
+//! in a per-user cache directory on the host, which is mounted into the
+//! container at [`CONTAINER_KERNEL_CACHE_DIR`].
+//!
+//! Entries are keyed by the image ID, i.e. the digest of the image
+//! configuration; an updated image has a different ID so an entry can never
+//! be stale. Entries for images which are no longer in container storage are
+//! removed on the host before each boot. Populating an entry happens under an
+//! exclusive lock, so concurrent boots of the same image extract only once.
+
+use std::collections::HashSet;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use rustix::fs::{flock, FlockOperation};
+use tracing::debug;
+
+/// Mount point of the host cache directory in the container
+pub(crate) const CONTAINER_KERNEL_CACHE_DIR: &str = "/run/kernel-cache";
+
+/// File name of the kernel in a cache entry
+pub(crate) const KERNEL: &str = "vmlinuz";
+
+/// File name of the initramfs in a cache entry
+pub(crate) const INITRAMFS: &str = "initramfs.img";
+
+/// Suffix of the lock file next to each entry
+const LOCK_SUFFIX: &str = ".lock";
+
+/// Infix of temporary directories an entry is extracted to
+const TMP_INFIX: &str = ".tmp-";
+
+/// Path of the cache directory on the host
+pub(crate) fn host_cache_dir() -> Result<Utf8PathBuf> {
+    let cache_dir = dirs::cache_dir().ok_or_else(|| eyre!("No user cache directory"))?;
+    let cache_dir = Utf8PathBuf::try_from(cache_dir)?;
+    Ok(cache_dir.join("bcvk").join("kernels"))
+}
+
+/// Cache key for an image ID such as `sha256:abcd...`
+pub(crate) fn cache_key(image_id: &str) -> String {
+    let id = image_id.rsplit_once(':').map_or(image_id, |(_, id)| id);
+    id.to_string()
+}
+
+/// The key an entry, lock file or temporary directory in the cache belongs to
+fn entry_key(name: &str) -> &str {
+    let name = name.strip_prefix('.').unwrap_or(name);
+    let name = name.strip_suffix(LOCK_SUFFIX).unwrap_or(name);
+    name.split_once(TMP_INFIX).map_or(name, |(key, _)| key)
+}
+
+/// Names of cache directory entries not belonging to any of `live_keys`
+fn stale_entries<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    live_keys: &HashSet<String>,
+) -> Vec<&'a str> {
+    names
+        .into_iter()
+        .filter(|name| !live_keys.contains(entry_key(name)))
+        .collect()
+}
+
+/// Remove entries for images which are no longer in container storage
+pub(crate) fn prune(cache_dir: &Utf8Path) -> Result<()> {
+    if !cache_dir.try_exists()? {
+        return Ok(());
+    }
+    // Including imported images, whose kernels may be in use
+    let output = crate::images::podman()
+        .args(["images", "--all", "--no-trunc", "--quiet"])
+        .output()
+        .context("Failed to list images")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to list images: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let live_keys: HashSet<String> = String::from_utf8(output.stdout)
+        .context("Invalid UTF-8 in podman images output")?
+        .lines()
+        .map(cache_key)
+        .collect();
+
+    let mut names = Vec::new();
+    for entry in cache_dir.read_dir_utf8()? {
+        names.push(entry?.file_name().to_owned());
+    }
+    for name in stale_entries(names.iter().map(String::as_str), &live_keys) {
+        let path = cache_dir.join(name);
+        debug!("Removing stale kernel cache entry {path}");
+        let r = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        r.with_context(|| format!("Removing {path}"))?;
+    }
+    Ok(())
+}
+
+/// Return the cache entry for `key`, calling `extract` to write [`KERNEL`]
+/// and [`INITRAMFS`] into a directory if there is none yet.
+pub(crate) fn get_or_insert(
+    cache_dir: &Utf8Path,
+    key: &str,
+    extract: impl FnOnce(&Utf8Path) -> Result<()>,
+) -> Result<Utf8PathBuf> {
+    let entry = cache_dir.join(key);
+    let lock_path = cache_dir.join(format!("{key}{LOCK_SUFFIX}"));
+    let lock =
+        std::fs::File::create(&lock_path).with_context(|| format!("Creating {lock_path}"))?;
+    flock(&lock, FlockOperation::LockExclusive).with_context(|| format!("Locking {lock_path}"))?;
+
+    if entry.try_exists()? {
+        debug!("Using cached kernel and initramfs from {entry}");
+        return Ok(entry);
+    }
+
+    let tmp = tempfile::Builder::new()
+        .prefix(&format!(".{key}{TMP_INFIX}"))
+        .tempdir_in(cache_dir)
+        .with_context(|| format!("Creating temporary directory in {cache_dir}"))?;
+    let tmp_path = Utf8Path::from_path(tmp.path())
+        .ok_or_else(|| eyre!("Invalid UTF-8 in {}", tmp.path().display()))?;
+    extract(tmp_path)?;
+    for name in [KERNEL, INITRAMFS] {
+        if !tmp_path.join(name).exists() {
+            return Err(eyre!("Extraction did not produce {name}"));
+        }
+    }
+    // Renaming makes the complete entry visible at once
+    std::fs::rename(tmp.keep(), &entry).with_context(|| format!("Creating {entry}"))?;
+    debug!("Cached kernel and initramfs in {entry}");
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_entries() {
+        let live: HashSet<String> = ["aaaa".to_string()].into();
+        let names = [
+            "aaaa",
+            "aaaa.lock",
+            ".aaaa.tmp-x1y2",
+            "bbbb",
+            "bbbb.lock",
+            ".bbbb.tmp-z3",
+        ];
+        assert_eq!(
+            stale_entries(names, &live),
+            ["bbbb", "bbbb.lock", ".bbbb.tmp-z3"]
+        );
+        assert_eq!(cache_key("sha256:aaaa"), "aaaa");
+        assert_eq!(cache_key("aaaa"), "aaaa");
+    }
+
+    #[test]
+    fn test_get_or_insert() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let mut calls = 0;
+        for _ in 0..2 {
+            let entry = get_or_insert(dir, "aaaa", |d| {
+                calls += 1;
+                std::fs::write(d.join(KERNEL), "kernel")?;
+                std::fs::write(d.join(INITRAMFS), "initramfs")?;
+                Ok(())
+            })?;
+            assert_eq!(entry, dir.join("aaaa"));
+            assert_eq!(std::fs::read_to_string(entry.join(KERNEL))?, "kernel");
+        }
+        assert_eq!(calls, 1);
+
+        // A failed extraction leaves no entry behind
+        let r = get_or_insert(dir, "bbbb", |d| {
+            std::fs::write(d.join(KERNEL), "kernel")?;
+            Ok(())
+        });
+        assert!(r.is_err());
+        assert!(!dir.join("bbbb").exists());
+        Ok(())
+    }
+}
@@ -1,4 +0,0 @@
-//! bcvk library - exposes internal modules for testing
-
-pub mod qemu_img;
-pub mod xml_utils;
@@ -2,15 +2,131 @@
 //!
 //! This module manages base disk images that serve as CoW sources for VM disks.
 //! Base disks are cached by their DiskImageMetadata hash (image digest + install options).
-//! Each VM gets a disk with a backing file using `virsh vol-create-as --backing-vol` for efficient CoW storage.
+//! Each VM gets a disk cloned from its base disk according to [`CloneMode`]: by
+//! default a disk with a backing file using `virsh vol-create-as --backing-vol`
+//! for efficient CoW storage, or on request a reflink or full copy.
 
 use crate::cache_metadata::DiskImageMetadata;
 use crate::install_options::InstallOptions;
 use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
 use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
 use std::fs;
-use tracing::{debug, info};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Extended attribute recording when a base disk was last cloned (seconds since the epoch)
+const BASE_DISK_LAST_USED_XATTR: &str = "user.bootc.last_used";
+
+/// How the disk of a VM is cloned from its base disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "kebab-case")]
+pub enum CloneMode {
+    /// reflink if the filesystem of the storage pool supports it, otherwise backing
+    Auto,
+    /// An instant full copy sharing its data with the base disk until either
+    /// is modified; requires btrfs, or XFS with reflink support
+    Reflink,
+    /// A qcow2 overlay with the base disk as its backing file (default)
+    #[default]
+    Backing,
+    /// A full copy of the base disk
+    Full,
+}
+
+impl std::fmt::Display for CloneMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Auto => "auto",
+            Self::Reflink => "reflink",
+            Self::Backing => "backing",
+            Self::Full => "full",
+        };
+        f.write_str(s)
+    }
+}
+
+impl CloneMode {
+    /// The mode to clone into the storage pool at `pool_path` with, resolving [`CloneMode::Auto`]
+    pub fn resolve(self, pool_path: &Utf8Path) -> Self {
+        match self {
+            Self::Auto if supports_reflink(pool_path) => Self::Reflink,
+            Self::Auto => Self::Backing,
+            mode => mode,
+        }
+    }
+}
+
+/// Whether files in `dir` can be reflinked, tried on two temporary files
+fn supports_reflink(dir: &Utf8Path) -> bool {
+    let probe = || -> std::io::Result<()> {
+        let mut src = tempfile::tempfile_in(dir)?;
+        src.write_all(b"bcvk")?;
+        let dst = tempfile::tempfile_in(dir)?;
+        rustix::fs::ioctl_ficlone(&dst, &src)?;
+        Ok(())
+    };
+    match probe() {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("No reflink support in {dir}: {e}");
+            false
+        }
+    }
+}
+
+/// Create `dst` as a reflink copy of `src`
+fn reflink(src: &Utf8Path, dst: &Utf8Path) -> Result<()> {
+    let src_file = fs::File::open(src).with_context(|| format!("Opening {src}"))?;
+    let dst_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)
+        .with_context(|| format!("Creating {dst}"))?;
+    if let Err(e) = rustix::fs::ioctl_ficlone(&dst_file, &src_file) {
+        if let Err(e) = fs::remove_file(dst) {
+            warn!("Failed to remove {dst}: {e}");
+        }
+        return Err(e).with_context(|| format!("Failed to reflink {src} to {dst}"));
+    }
+    Ok(())
+}
+
+/// Whether a file name in the storage pool is that of a base disk
+pub(crate) fn is_base_disk_name(file_name: &str) -> bool {
+    file_name.starts_with("bootc-base-") && file_name.ends_with(".qcow2")
+}
+
+/// Record that a base disk was just used to create a VM disk
+fn record_base_disk_use(base_disk_path: &Utf8Path) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context("Invalid system time")?
+        .as_secs();
+    rustix::fs::setxattr(
+        base_disk_path.as_std_path(),
+        BASE_DISK_LAST_USED_XATTR,
+        now.to_string().as_bytes(),
+        rustix::fs::XattrFlags::empty(),
+    )
+    .with_context(|| format!("Failed to set last-used xattr on {base_disk_path}"))?;
+    Ok(())
+}
+
+/// Read when a base disk was last used, if recorded
+fn read_base_disk_last_used(base_disk_path: &Utf8Path) -> Option<SystemTime> {
+    let mut buf = [0u8; 32];
+    let n = rustix::fs::getxattr(
+        base_disk_path.as_std_path(),
+        BASE_DISK_LAST_USED_XATTR,
+        &mut buf,
+    )
+    .ok()?;
+    let secs = std::str::from_utf8(&buf[..n]).ok()?.parse::<u64>().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
 
 /// Find or create a base disk for the given parameters
 pub fn find_or_create_base_disk(
@@ -18,6 +134,7 @@ pub fn find_or_create_base_disk(
     image_digest: &str,
     install_options: &InstallOptions,
     connect_uri: Option<&str>,
+    pool: &str,
 ) -> Result<Utf8PathBuf> {
     let metadata = DiskImageMetadata::from(install_options, image_digest, source_image);
     let cache_hash = metadata.compute_cache_hash();
@@ -33,7 +150,7 @@ pub fn find_or_create_base_disk(
     let base_disk_name = format!("bootc-base-{}.qcow2", short_hash);
 
     // Get storage pool path
-    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri)?;
+    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri, pool)?;
     let base_disk_path = pool_path.join(&base_disk_name);
 
     // Check if base disk already exists with valid metadata
@@ -48,6 +165,10 @@ pub fn find_or_create_base_disk(
         )?
         .is_ok()
         {
+            crate::events::record(crate::events::EventKind::BaseDiskReused {
+                path: base_disk_path.to_string(),
+                image: source_image.to_string(),
+            });
             return Ok(base_disk_path);
         } else {
             info!("Base disk exists but metadata doesn't match, will recreate");
@@ -61,12 +182,22 @@ pub fn find_or_create_base_disk(
     // Multiple concurrent processes may race to create this, but each uses
     // a unique temp file, so they won't conflict
     info!("Creating base disk: {:?}", base_disk_path);
+    match crate::events::last_build_duration(source_image) {
+        Some(took) => println!(
+            "Installing {source_image} to a new base disk; this took {} last time",
+            indicatif::HumanDuration(took)
+        ),
+        None => {
+            println!("Installing {source_image} to a new base disk; this may take a few minutes")
+        }
+    }
     create_base_disk(
         &base_disk_path,
         source_image,
         image_digest,
         install_options,
         connect_uri,
+        pool,
     )?;
 
     Ok(base_disk_path)
@@ -79,6 +210,7 @@ fn create_base_disk(
     image_digest: &str,
     install_options: &InstallOptions,
     connect_uri: Option<&str>,
+    pool: &str,
 ) -> Result<()> {
     use crate::run_ephemeral::CommonVmOpts;
     use crate::to_disk::{Format, ToDiskAdditionalOpts, ToDiskOpts};
@@ -99,7 +231,8 @@ fn create_base_disk(
     let temp_disk_path = Utf8PathBuf::from(temp_file.path().to_str().unwrap());
 
     // Keep the temp file open so it gets cleaned up automatically if we error out
-    // We'll persist it manually on success
+    // We'll persist it manually on success. The guard covers interruption.
+    let temp_guard = crate::cleanup::CleanupGuard::remove_path(&temp_disk_path);
 
     // Create the disk using to_disk at temporary location
     let to_disk_opts = ToDiskOpts {
@@ -114,7 +247,7 @@ fn create_base_disk(
             format: Format::Qcow2, // Use qcow2 for CoW cloning
             common: CommonVmOpts {
                 memory: crate::common_opts::MemoryOpts {
-                    memory: super::LIBVIRT_DEFAULT_MEMORY.to_string(),
+                    memory: Some(super::LIBVIRT_DEFAULT_MEMORY.to_string()),
                 },
                 ..Default::default()
             },
@@ -126,6 +259,9 @@ fn create_base_disk(
     // On error, temp_file is automatically cleaned up when dropped
     crate::to_disk::run(to_disk_opts)
         .with_context(|| format!("Failed to install bootc to base disk: {:?}", temp_disk_path))?;
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
 
     // If we got here, bootc install succeeded - verify metadata was written
     let metadata_valid = crate::cache_metadata::check_cached_disk(
@@ -142,6 +278,7 @@ fn create_base_disk(
             // If another concurrent process already created the file, that's fine
             match temp_file.persist(base_disk_path) {
                 Ok(_) => {
+                    temp_guard.disarm();
                     debug!("Successfully created base disk: {:?}", base_disk_path);
                 }
                 Err(e) if e.error.kind() == std::io::ErrorKind::AlreadyExists => {
@@ -161,13 +298,13 @@ fn create_base_disk(
 
             // Refresh libvirt storage pool so the new disk is visible to virsh
             let mut cmd = super::run::virsh_command(connect_uri)?;
-            cmd.args(&["pool-refresh", "default"]);
+            cmd.args(&["pool-refresh", pool]);
 
             if let Err(e) = cmd
                 .output()
                 .with_context(|| "Failed to run virsh pool-refresh")
             {
-                debug!("Warning: Failed to refresh libvirt storage pool: {}", e);
+                warn!("Failed to refresh libvirt storage pool: {e:#}");
                 // Don't fail if pool refresh fails, the disk was created successfully
             }
 
@@ -184,16 +321,65 @@ fn create_base_disk(
     }
 }
 
+/// Create the volume `vm_disk_name` in `pool` as a qcow2 overlay of `base_disk_path`
+fn create_backed_volume(
+    base_disk_path: &Utf8Path,
+    vm_disk_name: &str,
+    connect_uri: Option<&str>,
+    pool: &str,
+) -> Result<()> {
+    // Get the virtual size of the base disk to use for the new volume
+    let info = crate::qemu_img::info(base_disk_path)?;
+    let virtual_size = info.virtual_size;
+
+    // Create volume with backing file using vol-create-as
+    // This creates a qcow2 image with the base disk as backing file (proper CoW)
+    let base_disk_filename = base_disk_path.file_name().ok_or_else(|| {
+        color_eyre::eyre::eyre!("Base disk path has no filename: {:?}", base_disk_path)
+    })?;
+
+    let mut cmd = super::run::virsh_command(connect_uri)?;
+    cmd.args(&[
+        "vol-create-as",
+        pool,
+        vm_disk_name,
+        &virtual_size.to_string(),
+        "--format",
+        "qcow2",
+        "--backing-vol",
+        base_disk_filename,
+        "--backing-vol-format",
+        "qcow2",
+    ]);
+
+    let output = cmd
+        .output()
+        .with_context(|| "Failed to run virsh vol-create-as")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(color_eyre::eyre::eyre!(
+            "Failed to create VM disk with backing file: {}",
+            stderr
+        ));
+    }
+
+    Ok(())
+}
+
 /// Clone a base disk to create a VM-specific disk
 ///
 /// Uses predictable disk name: `{vm_name}.qcow2`
 /// If the disk already exists, it will be deleted using `virsh vol-delete` first.
+/// Disks cloned other than with [`CloneMode::Backing`] don't depend on the base disk.
 pub fn clone_from_base(
     base_disk_path: &Utf8Path,
     vm_name: &str,
     connect_uri: Option<&str>,
+    pool: &str,
+    mode: CloneMode,
 ) -> Result<Utf8PathBuf> {
-    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri)?;
+    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri, pool)?;
 
     // Use predictable disk name
     let vm_disk_name = format!("{}.qcow2", vm_name);
@@ -201,13 +387,13 @@ pub fn clone_from_base(
 
     // Refresh the storage pool so libvirt knows about all files
     let mut refresh_cmd = super::run::virsh_command(connect_uri)?;
-    refresh_cmd.args(&["pool-refresh", "default"]);
+    refresh_cmd.args(&["pool-refresh", pool]);
     let _ = refresh_cmd.output(); // Ignore errors, pool might not exist yet
 
     // Try to delete the volume if it exists (either as a file or in libvirt's view)
     // This handles both cases: file exists but not tracked, or tracked by libvirt
     let mut cmd = super::run::virsh_command(connect_uri)?;
-    cmd.args(&["vol-delete", "--pool", "default", &vm_disk_name]);
+    cmd.args(&["vol-delete", "--pool", pool, &vm_disk_name]);
 
     let output = cmd
         .output()
@@ -239,79 +425,80 @@ pub fn clone_from_base(
             .with_context(|| format!("Failed to remove disk file: {:?}", vm_disk_path))?;
     }
 
+    let mode = mode.resolve(&pool_path);
     debug!(
-        "Creating VM disk with backing file: {:?} -> {:?}",
-        base_disk_path, vm_disk_path
+        "Creating VM disk as {} clone: {:?} -> {:?}",
+        mode, base_disk_path, vm_disk_path
     );
+    match mode {
+        CloneMode::Reflink => {
+            reflink(base_disk_path, &vm_disk_path).with_context(|| {
+                format!(
+                    "Failed to create a reflink clone in storage pool '{pool}'; reflinks require btrfs, or XFS with reflink=1 (otherwise use --clone-mode backing or full)"
+                )
+            })?;
+            // Make the new disk known to libvirt
+            let mut cmd = super::run::virsh_command(connect_uri)?;
+            cmd.args(&["pool-refresh", pool]);
+            if let Err(e) = cmd
+                .output()
+                .with_context(|| "Failed to run virsh pool-refresh")
+            {
+                warn!("Failed to refresh libvirt storage pool: {e:#}");
+            }
+        }
+        CloneMode::Full => {
+            let base_disk_filename = base_disk_path.file_name().ok_or_else(|| {
+                color_eyre::eyre::eyre!("Base disk path has no filename: {:?}", base_disk_path)
+            })?;
+            let mut cmd = super::run::virsh_command(connect_uri)?;
+            cmd.args(&[
+                "vol-clone",
+                "--pool",
+                pool,
+                base_disk_filename,
+                &vm_disk_name,
+            ]);
+            let output = cmd
+                .output()
+                .with_context(|| "Failed to run virsh vol-clone")?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(color_eyre::eyre::eyre!(
+                    "Failed to copy base disk: {}",
+                    stderr
+                ));
+            }
+        }
+        CloneMode::Backing | CloneMode::Auto => {
+            create_backed_volume(base_disk_path, &vm_disk_name, connect_uri, pool)?
+        }
+    }
 
-    // Get the virtual size of the base disk to use for the new volume
-    let info = crate::qemu_img::info(base_disk_path)?;
-    let virtual_size = info.virtual_size;
-
-    // Create volume with backing file using vol-create-as
-    // This creates a qcow2 image with the base disk as backing file (proper CoW)
-    let base_disk_filename = base_disk_path.file_name().ok_or_else(|| {
-        color_eyre::eyre::eyre!("Base disk path has no filename: {:?}", base_disk_path)
-    })?;
-
-    let mut cmd = super::run::virsh_command(connect_uri)?;
-    cmd.args(&[
-        "vol-create-as",
-        "default",
-        &vm_disk_name,
-        &virtual_size.to_string(),
-        "--format",
-        "qcow2",
-        "--backing-vol",
-        base_disk_filename,
-        "--backing-vol-format",
-        "qcow2",
-    ]);
-
-    let output = cmd
-        .output()
-        .with_context(|| "Failed to run virsh vol-create-as")?;
+    debug!("Successfully created VM disk: {:?}", vm_disk_path);
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(color_eyre::eyre::eyre!(
-            "Failed to create VM disk with backing file: {}",
-            stderr
-        ));
+    // Track usage for `base-disks gc`; not fatal since the clone succeeded
+    if let Err(e) = record_base_disk_use(base_disk_path) {
+        debug!("Failed to record base disk usage: {e:#}");
     }
 
-    debug!(
-        "Successfully created VM disk with backing file: {:?}",
-        vm_disk_path
-    );
     Ok(vm_disk_path)
 }
 
-/// List all base disks in the storage pool with reference counts
-pub fn list_base_disks(connect_uri: Option<&str>) -> Result<Vec<BaseDiskInfo>> {
-    use super::run::list_storage_pool_volumes;
-
-    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri)?;
+/// List all base disks in the storage pool `pool` with reference counts
+pub fn list_base_disks(connect_uri: Option<&str>, pool: &str) -> Result<Vec<BaseDiskInfo>> {
+    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri, pool)?;
     let mut base_disks = Vec::new();
 
-    // Get all volumes to count references
-    let all_volumes = list_storage_pool_volumes(connect_uri)?;
-    let vm_disks: Vec<_> = all_volumes
-        .iter()
-        .filter(|p| {
-            if let Some(name) = p.file_name() {
-                !name.starts_with("bootc-base-")
-            } else {
-                false
-            }
-        })
-        .collect();
+    // Get all VM disks to count references
+    let all_vm_disks = list_vm_disks(connect_uri, pool)?;
+    let vm_disks: Vec<_> = all_vm_disks.iter().collect();
 
     if let Ok(entries) = fs::read_dir(&pool_path) {
         for entry in entries.flatten() {
             if let Ok(file_name) = entry.file_name().into_string() {
                 // Check if this is a base disk
-                if file_name.starts_with("bootc-base-") && file_name.ends_with(".qcow2") {
+                if is_base_disk_name(&file_name) {
                     let path = pool_path.join(&file_name);
 
                     // Try to read metadata
@@ -325,6 +512,7 @@ pub fn list_base_disks(connect_uri: Option<&str>) -> Result<Vec<BaseDiskInfo>> {
                     let metadata = entry.metadata().ok();
                     let size = metadata.as_ref().map(|m| m.len());
                     let created = metadata.and_then(|m| m.created().ok());
+                    let last_used = read_base_disk_last_used(&path);
 
                     // Count references
                     let ref_count = count_base_disk_references(&path, &vm_disks)?;
@@ -335,6 +523,7 @@ pub fn list_base_disks(connect_uri: Option<&str>) -> Result<Vec<BaseDiskInfo>> {
                         size,
                         ref_count,
                         created,
+                        last_used,
                     });
                 }
             }
@@ -352,26 +541,65 @@ pub struct BaseDiskInfo {
     pub size: Option<u64>,
     pub ref_count: usize,
     pub created: Option<std::time::SystemTime>,
+    /// When a VM disk was last cloned from this base disk
+    pub last_used: Option<std::time::SystemTime>,
 }
 
-/// Prune unreferenced base disks
-pub fn prune_base_disks(connect_uri: Option<&str>, dry_run: bool) -> Result<Vec<Utf8PathBuf>> {
-    use super::run::list_storage_pool_volumes;
-
-    let base_disks = list_base_disks(connect_uri)?;
-    let all_volumes = list_storage_pool_volumes(connect_uri)?;
+impl BaseDiskInfo {
+    /// Most recent known use of this base disk, falling back to its creation time
+    fn last_activity(&self) -> Option<SystemTime> {
+        self.last_used.or(self.created)
+    }
+}
 
-    // Collect all non-base volumes (VM disks)
-    let vm_disks: Vec<_> = all_volumes
-        .iter()
+/// List all VM disks (non-base volumes) in the storage pool
+fn list_vm_disks(connect_uri: Option<&str>, pool: &str) -> Result<Vec<Utf8PathBuf>> {
+    let all_volumes = super::run::list_storage_pool_volumes(connect_uri, pool)?;
+    Ok(all_volumes
+        .into_iter()
         .filter(|p| {
-            if let Some(name) = p.file_name() {
-                !name.starts_with("bootc-base-")
-            } else {
-                false
-            }
+            p.file_name()
+                .map(|name| !name.starts_with("bootc-base-"))
+                .unwrap_or(false)
         })
-        .collect();
+        .collect())
+}
+
+/// Remove a base disk, unregistering it from the libvirt storage pool
+fn delete_base_disk(connect_uri: Option<&str>, pool: &str, path: &Utf8Path) -> Result<()> {
+    // Use virsh vol-delete to properly unregister from libvirt storage pool
+    let base_disk_name = path
+        .file_name()
+        .ok_or_else(|| color_eyre::eyre::eyre!("Base disk path has no filename: {:?}", path))?;
+
+    let mut cmd = super::run::virsh_command(connect_uri)?;
+    cmd.args(&["vol-delete", "--pool", pool, base_disk_name]);
+
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run virsh vol-delete for {}", base_disk_name))?;
+
+    if !output.status.success() {
+        let stderr =
+            String::from_utf8(output.stderr).with_context(|| "Invalid UTF-8 in virsh stderr")?;
+        return Err(color_eyre::eyre::eyre!(
+            "Failed to delete base disk volume '{}': {}",
+            base_disk_name,
+            stderr
+        ));
+    }
+    Ok(())
+}
+
+/// Prune unreferenced base disks
+pub fn prune_base_disks(
+    connect_uri: Option<&str>,
+    pool: &str,
+    dry_run: bool,
+) -> Result<Vec<Utf8PathBuf>> {
+    let base_disks = list_base_disks(connect_uri, pool)?;
+    let all_vm_disks = list_vm_disks(connect_uri, pool)?;
+    let vm_disks: Vec<_> = all_vm_disks.iter().collect();
 
     let mut pruned = Vec::new();
 
@@ -385,27 +613,7 @@ pub fn prune_base_disks(connect_uri: Option<&str>, dry_run: bool) -> Result<Vec<
             if dry_run {
                 println!("Would remove: {}", base_disk.path);
             } else {
-                // Use virsh vol-delete to properly unregister from libvirt storage pool
-                let base_disk_name = base_disk.path.file_name().ok_or_else(|| {
-                    color_eyre::eyre::eyre!("Base disk path has no filename: {:?}", base_disk.path)
-                })?;
-
-                let mut cmd = super::run::virsh_command(connect_uri)?;
-                cmd.args(&["vol-delete", "--pool", "default", base_disk_name]);
-
-                let output = cmd.output().with_context(|| {
-                    format!("Failed to run virsh vol-delete for {}", base_disk_name)
-                })?;
-
-                if !output.status.success() {
-                    let stderr = String::from_utf8(output.stderr)
-                        .with_context(|| "Invalid UTF-8 in virsh stderr")?;
-                    return Err(color_eyre::eyre::eyre!(
-                        "Failed to delete base disk volume '{}': {}",
-                        base_disk_name,
-                        stderr
-                    ));
-                }
+                delete_base_disk(connect_uri, pool, &base_disk.path)?;
                 println!("Removed: {}", base_disk.path);
             }
 
@@ -416,6 +624,86 @@ pub fn prune_base_disks(connect_uri: Option<&str>, dry_run: bool) -> Result<Vec<
     Ok(pruned)
 }
 
+/// Limits applied by [`gc_base_disks`]
+#[derive(Debug, Clone, Default)]
+pub struct GcPolicy {
+    /// Maximum total size in bytes of all base disks
+    pub max_size: Option<u64>,
+    /// Unreferenced base disks not used for longer than this are removed
+    pub max_age: Option<Duration>,
+}
+
+/// Select which base disks to remove under `policy`
+///
+/// Only unreferenced disks (`referenced[i] == false`) are candidates. Disks
+/// exceeding the maximum age are always selected; then the least recently
+/// used remaining candidates are selected until the total size of all base
+/// disks (including referenced ones) fits within the maximum size.
+///
+/// Returns indices into `disks`, least recently used first.
+fn select_gc_victims(
+    disks: &[BaseDiskInfo],
+    referenced: &[bool],
+    policy: &GcPolicy,
+    now: SystemTime,
+) -> Vec<usize> {
+    let mut candidates: Vec<usize> = (0..disks.len()).filter(|&i| !referenced[i]).collect();
+    // Disks with no known activity time sort first, i.e. are treated as oldest
+    candidates.sort_by_key(|&i| disks[i].last_activity());
+
+    let mut total_size: u64 = disks.iter().filter_map(|d| d.size).sum();
+    let mut victims = Vec::new();
+
+    for i in candidates {
+        let disk = &disks[i];
+        let too_old = match (policy.max_age, disk.last_activity()) {
+            (Some(max_age), Some(t)) => now.duration_since(t).unwrap_or_default() > max_age,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let over_size = policy.max_size.is_some_and(|max| total_size > max);
+        if too_old || over_size {
+            total_size = total_size.saturating_sub(disk.size.unwrap_or(0));
+            victims.push(i);
+        }
+    }
+
+    victims
+}
+
+/// Remove least recently used unreferenced base disks according to `policy`
+pub fn gc_base_disks(
+    connect_uri: Option<&str>,
+    pool: &str,
+    policy: &GcPolicy,
+    dry_run: bool,
+) -> Result<Vec<Utf8PathBuf>> {
+    let base_disks = list_base_disks(connect_uri, pool)?;
+    let all_vm_disks = list_vm_disks(connect_uri, pool)?;
+    let vm_disks: Vec<_> = all_vm_disks.iter().collect();
+
+    let referenced = base_disks
+        .iter()
+        .map(|d| check_base_disk_referenced(&d.path, &vm_disks))
+        .collect::<Result<Vec<_>>>()?;
+
+    let victims = select_gc_victims(&base_disks, &referenced, policy, SystemTime::now());
+
+    let mut removed = Vec::new();
+    for i in victims {
+        let path = &base_disks[i].path;
+        if dry_run {
+            println!("Would remove: {}", path);
+        } else {
+            delete_base_disk(connect_uri, pool, path)?;
+            println!("Removed: {}", path);
+        }
+        removed.push(path.clone());
+    }
+
+    Ok(removed)
+}
+
 /// Count how many VM disks reference a specific base disk
 fn count_base_disk_references(base_disk: &Utf8Path, vm_disks: &[&Utf8PathBuf]) -> Result<usize> {
     let base_disk_name = base_disk.file_name().unwrap();
@@ -495,3 +783,78 @@ fn check_base_disk_referenced(base_disk: &Utf8Path, vm_disks: &[&Utf8PathBuf]) -
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn disk(name: &str, size_gib: u64, last_used_days_ago: Option<u64>) -> BaseDiskInfo {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        BaseDiskInfo {
+            path: Utf8PathBuf::from(name),
+            image_digest: None,
+            size: Some(size_gib * GIB),
+            ref_count: 0,
+            created: Some(SystemTime::UNIX_EPOCH),
+            last_used: last_used_days_ago.map(|d| now - Duration::from_secs(d * DAY)),
+        }
+    }
+
+    #[test]
+    fn test_select_gc_victims() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let disks = [
+            disk("a", 10, Some(1)),
+            disk("b", 10, Some(40)),
+            disk("c", 10, Some(5)),
+            disk("d", 10, None),
+            disk("e", 10, Some(100)),
+        ];
+        // "e" is the oldest but still referenced
+        let referenced = [false, false, false, false, true];
+
+        let cases: &[(GcPolicy, &[&str])] = &[
+            (GcPolicy::default(), &[]),
+            (
+                GcPolicy {
+                    max_age: Some(Duration::from_secs(30 * DAY)),
+                    max_size: None,
+                },
+                // "d" was never cloned; its creation time is ancient
+                &["d", "b"],
+            ),
+            (
+                GcPolicy {
+                    max_age: None,
+                    max_size: Some(30 * GIB),
+                },
+                &["d", "b"],
+            ),
+            (
+                GcPolicy {
+                    max_age: None,
+                    max_size: Some(15 * GIB),
+                },
+                // Can't get below 15G without removing the referenced disk
+                &["d", "b", "c", "a"],
+            ),
+            (
+                GcPolicy {
+                    max_age: Some(Duration::from_secs(3 * DAY)),
+                    max_size: Some(100 * GIB),
+                },
+                &["d", "b", "c"],
+            ),
+        ];
+        for (policy, expected) in cases {
+            let victims: Vec<_> = select_gc_victims(&disks, &referenced, policy, now)
+                .into_iter()
+                .map(|i| disks[i].path.as_str())
+                .collect();
+            assert_eq!(victims, *expected, "{policy:?}");
+        }
+    }
+}
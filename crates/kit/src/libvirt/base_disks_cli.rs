@@ -8,12 +8,16 @@ use color_eyre::Result;
 use comfy_table::{presets::UTF8_FULL, Table};
 use serde_json;
 
-use super::base_disks::{list_base_disks, prune_base_disks};
+use super::base_disks::{gc_base_disks, list_base_disks, prune_base_disks, GcPolicy};
 use super::OutputFormat;
 
 /// Options for base-disks command
 #[derive(Debug, Parser)]
 pub struct LibvirtBaseDisksOpts {
+    /// Libvirt storage pool of the base disks
+    #[clap(long, global = true, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+
     #[command(subcommand)]
     pub command: BaseDisksSubcommand,
 }
@@ -25,6 +29,8 @@ pub enum BaseDisksSubcommand {
     List(ListOpts),
     /// Prune unreferenced base disk images
     Prune(PruneOpts),
+    /// Remove least recently used unreferenced base disks to meet size/age limits
+    Gc(GcOpts),
 }
 
 /// Options for list command
@@ -43,19 +49,45 @@ pub struct PruneOpts {
     pub dry_run: bool,
 }
 
+/// Options for gc command
+#[derive(Debug, Parser)]
+#[clap(group = clap::ArgGroup::new("limits").required(true).multiple(true))]
+pub struct GcOpts {
+    /// Maximum total size of all base disks (e.g. 100G)
+    #[clap(long, group = "limits")]
+    pub max_size: Option<String>,
+
+    /// Remove unreferenced base disks not used within this duration (e.g. 30d, 12h)
+    #[clap(long, group = "limits")]
+    pub max_age: Option<String>,
+
+    /// Show what would be removed without actually removing
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
 /// Execute the base-disks command
 pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtBaseDisksOpts) -> Result<()> {
     let connect_uri = global_opts.connect.as_deref();
+    let pool = opts.pool.as_str();
 
     match opts.command {
-        BaseDisksSubcommand::List(list_opts) => run_list(connect_uri, list_opts),
-        BaseDisksSubcommand::Prune(prune_opts) => run_prune(connect_uri, prune_opts),
+        BaseDisksSubcommand::List(list_opts) => run_list(connect_uri, pool, list_opts),
+        BaseDisksSubcommand::Prune(prune_opts) => run_prune(connect_uri, pool, prune_opts),
+        BaseDisksSubcommand::Gc(gc_opts) => run_gc(connect_uri, pool, gc_opts),
     }
 }
 
+/// Format a timestamp for table output
+fn format_time(t: Option<std::time::SystemTime>) -> Option<String> {
+    t.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
+        .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+}
+
 /// Execute the list subcommand
-fn run_list(connect_uri: Option<&str>, opts: ListOpts) -> Result<()> {
-    let base_disks = list_base_disks(connect_uri)?;
+fn run_list(connect_uri: Option<&str>, pool: &str, opts: ListOpts) -> Result<()> {
+    let base_disks = list_base_disks(connect_uri, pool)?;
 
     match opts.format {
         OutputFormat::Table => {
@@ -66,7 +98,14 @@ fn run_list(connect_uri: Option<&str>, opts: ListOpts) -> Result<()> {
 
             let mut table = Table::new();
             table.load_preset(UTF8_FULL);
-            table.set_header(vec!["NAME", "SIZE", "REFS", "CREATED", "IMAGE DIGEST"]);
+            table.set_header(vec![
+                "NAME",
+                "SIZE",
+                "REFS",
+                "CREATED",
+                "LAST USED",
+                "IMAGE DIGEST",
+            ]);
 
             for disk in &base_disks {
                 let name = disk.path.file_name().unwrap_or("unknown");
@@ -78,12 +117,8 @@ fn run_list(connect_uri: Option<&str>, opts: ListOpts) -> Result<()> {
 
                 let refs = disk.ref_count.to_string();
 
-                let created = disk
-                    .created
-                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                    .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
-                    .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
-                    .unwrap_or_else(|| "unknown".to_string());
+                let created = format_time(disk.created).unwrap_or_else(|| "unknown".to_string());
+                let last_used = format_time(disk.last_used).unwrap_or_else(|| "never".to_string());
 
                 let digest = disk
                     .image_digest
@@ -98,7 +133,7 @@ fn run_list(connect_uri: Option<&str>, opts: ListOpts) -> Result<()> {
                     })
                     .unwrap_or_else(|| "<no metadata>".to_string());
 
-                table.add_row(vec![name, &size, &refs, &created, &digest]);
+                table.add_row(vec![name, &size, &refs, &created, &last_used, &digest]);
             }
 
             println!("{}", table);
@@ -127,12 +162,12 @@ fn run_list(connect_uri: Option<&str>, opts: ListOpts) -> Result<()> {
 }
 
 /// Execute the prune subcommand
-fn run_prune(connect_uri: Option<&str>, opts: PruneOpts) -> Result<()> {
+fn run_prune(connect_uri: Option<&str>, pool: &str, opts: PruneOpts) -> Result<()> {
     if opts.dry_run {
         println!("Dry run: showing base disks that would be removed");
     }
 
-    let pruned = prune_base_disks(connect_uri, opts.dry_run)?;
+    let pruned = prune_base_disks(connect_uri, pool, opts.dry_run)?;
 
     if pruned.is_empty() {
         println!("No unreferenced base disks found to remove");
@@ -151,3 +186,42 @@ fn run_prune(connect_uri: Option<&str>, opts: PruneOpts) -> Result<()> {
 
     Ok(())
 }
+
+/// Execute the gc subcommand
+fn run_gc(connect_uri: Option<&str>, pool: &str, opts: GcOpts) -> Result<()> {
+    let policy = GcPolicy {
+        max_size: opts
+            .max_size
+            .as_deref()
+            .map(crate::utils::parse_size)
+            .transpose()?,
+        max_age: opts
+            .max_age
+            .as_deref()
+            .map(crate::utils::parse_duration)
+            .transpose()?,
+    };
+
+    if opts.dry_run {
+        println!("Dry run: showing base disks that would be removed");
+    }
+
+    let removed = gc_base_disks(connect_uri, pool, &policy, opts.dry_run)?;
+
+    if removed.is_empty() {
+        println!("Base disks are within limits, nothing to remove");
+    } else {
+        println!(
+            "\n{} {} base disk{}",
+            if opts.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            },
+            removed.len(),
+            if removed.len() == 1 { "" } else { "s" }
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,906 @@
+//! libvirt export and import commands - move a bootc domain between hosts
+//!
+//! `bcvk libvirt export` writes a stopped domain into a zstd compressed
+//! tarball (a bundle), and `bcvk libvirt import` recreates the domain from
+//! it, usually on another host. A bundle contains:
+//!
+//! - `manifest.json`: a [`BundleManifest`]
+//! - `domain.xml`: the persistent definition of the domain, including the
+//!   bcvk metadata (source image, digest, SSH key)
+//! - `disks/<N>.<format>`: the file backed disks of the domain, in order
+//! - `base/<file>`: with `--include-base`, the backing file of the first
+//!   disk, which otherwise is flattened into a standalone image
+//! - `nvram`: the UEFI variable store, if any
+//! - `tpm/`: the swtpm state, if the domain has an emulated TPM
+//!
+//! On import, the parts of the definition which are specific to a host are
+//! regenerated: the UUID, the disk and NVRAM paths, the firmware paths (when
+//! libvirt selects the firmware) and the SSH port forward.
+//!
+//! Both commands access the storage pool, NVRAM and swtpm directories
+//! directly, so they only work with a local hypervisor.
+
+use std::io::Write;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::status::ConnectionKind;
+use crate::cleanup::CleanupGuard;
+use crate::domain_list::DomainLister;
+use crate::hostexec::HostCommand;
+use crate::libvirt::domain_metadata::{self, DomainMetadata};
+use crate::qemu_img::{self, ImageFormat};
+use crate::xml_utils::{self, XmlNode};
+
+/// Version of the bundle layout written by this version of bcvk
+const BUNDLE_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const DOMAIN_XML: &str = "domain.xml";
+const DISKS_DIR: &str = "disks";
+const BASE_DIR: &str = "base";
+const NVRAM: &str = "nvram";
+const TPM_DIR: &str = "tpm";
+
+/// Options for exporting a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtExportOpts {
+    /// Name of the domain to export
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub name: String,
+
+    /// Path of the bundle to write (e.g. vm.tar.zst)
+    pub bundle: Utf8PathBuf,
+
+    /// Include the base disk the VM disk is backed by, instead of flattening the VM disk
+    #[clap(long)]
+    pub include_base: bool,
+
+    /// Overwrite an existing bundle
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Options for importing a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtImportOpts {
+    /// Path of the bundle to import
+    pub bundle: Utf8PathBuf,
+
+    /// Name for the imported domain (defaults to the name it was exported with)
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// Start the domain after importing it
+    #[clap(long)]
+    pub start: bool,
+
+    /// Libvirt storage pool to import the disks into
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+}
+
+/// Description of the contents of a bundle
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BundleManifest {
+    /// Layout version, see [`BUNDLE_VERSION`]
+    pub version: u32,
+    /// Name of the exported domain
+    pub name: String,
+    /// Container image the domain was created from
+    pub image: Option<String>,
+    /// Digest of that image
+    pub image_digest: Option<String>,
+    /// Disks in `disks/`, in the order of the domain definition
+    pub disks: Vec<BundleDisk>,
+    /// File name of the base disk in `base/`, backing the first disk
+    pub base: Option<String>,
+    /// Whether the bundle contains the UEFI variable store
+    pub nvram: bool,
+    /// Whether the bundle contains swtpm state
+    pub tpm: bool,
+}
+
+/// A disk in a bundle
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BundleDisk {
+    /// Path of the disk in the bundle
+    pub file: String,
+    /// Image format, `qcow2` or `raw`
+    pub format: String,
+    /// Path of the disk on the exporting host, as used in the domain XML
+    pub source: String,
+}
+
+/// A file backed disk of a domain
+#[derive(Debug, PartialEq, Eq)]
+struct DomainDisk {
+    source: String,
+    format: String,
+}
+
+fn parse_format(format: &str) -> Result<ImageFormat> {
+    match format {
+        "qcow2" => Ok(ImageFormat::Qcow2),
+        "raw" => Ok(ImageFormat::Raw),
+        o => Err(eyre!(
+            "Unsupported disk format '{o}' (expected qcow2 or raw)"
+        )),
+    }
+}
+
+/// `value` from a bundle manifest, which must be a plain file name, as it is
+/// joined to host paths
+fn plain_file_name<'a>(what: &str, value: &'a str) -> Result<&'a str> {
+    match Utf8Path::new(value).file_name() {
+        Some(name) if name == value => Ok(name),
+        _ => Err(eyre!("Invalid {what} '{value}' in bundle manifest")),
+    }
+}
+
+impl BundleManifest {
+    /// Check the values joined to paths, as bundles may come from anywhere
+    fn validate(&self) -> Result<()> {
+        plain_file_name("name", &self.name)?;
+        if let Some(base) = &self.base {
+            plain_file_name("base disk", base)?;
+        }
+        for disk in &self.disks {
+            parse_format(&disk.format)?;
+            let name = Utf8Path::new(&disk.file)
+                .strip_prefix(DISKS_DIR)
+                .map_err(|_| eyre!("Invalid disk '{}' in bundle manifest", disk.file))?;
+            plain_file_name("disk", name.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// The file backed disks of a domain; other devices such as CD-ROMs are
+/// ignored, but disks backed by anything but a file can't be exported
+fn domain_disks(dom: &XmlNode) -> Result<Vec<DomainDisk>> {
+    let Some(devices) = dom.find("devices") else {
+        return Ok(Vec::new());
+    };
+    let mut disks = Vec::new();
+    for disk in devices.children.iter().filter(|n| n.name == "disk") {
+        if disk.attributes.get("device").map(String::as_str) != Some("disk") {
+            continue;
+        }
+        let source = match disk.attributes.get("type").map(String::as_str) {
+            Some("file") => disk.find("source").and_then(|s| s.attributes.get("file")),
+            _ => None,
+        }
+        .ok_or_else(|| eyre!("Only disks backed by files can be exported"))?;
+        let format = disk
+            .find("driver")
+            .and_then(|d| d.attributes.get("type"))
+            .map_or("raw", String::as_str);
+        disks.push(DomainDisk {
+            source: source.clone(),
+            format: format.to_owned(),
+        });
+    }
+    Ok(disks)
+}
+
+/// Set the text of the first element `tag` in `dom`
+fn set_element_text(dom: &mut XmlNode, tag: &str, text: &str) -> Result<()> {
+    let element = dom
+        .find_mut(tag)
+        .ok_or_else(|| eyre!("No {tag} element in domain XML"))?;
+    element.text = text.to_owned();
+    Ok(())
+}
+
+/// Point every disk `source` element below `node` with file `old` to `new`;
+/// returns whether any was found
+fn replace_disk_source(node: &mut XmlNode, old: &str, new: &str) -> bool {
+    let mut found = false;
+    if node.name == "source" && node.attributes.get("file").is_some_and(|f| f == old) {
+        node.attributes.insert("file".to_owned(), new.to_owned());
+        found = true;
+    }
+    for child in &mut node.children {
+        found |= replace_disk_source(child, old, new);
+    }
+    found
+}
+
+/// Host specific parts of a domain definition to change on import
+#[derive(Debug)]
+struct XmlRewrite<'a> {
+    name: &'a str,
+    /// Old and new path of each disk
+    disks: &'a [(String, String)],
+    nvram: Option<&'a str>,
+    /// Old and new SSH port
+    ssh_port: Option<(u16, u16)>,
+    /// Storage pool the disks were moved to
+    pool: &'a str,
+}
+
+/// Apply `rewrite` to the domain definition `xml` from a bundle
+fn rewrite_domain_xml(xml: &str, rewrite: &XmlRewrite) -> Result<String> {
+    let mut dom = xml_utils::parse_xml_dom(xml).context("Failed to parse domain XML")?;
+    set_element_text(&mut dom, "name", rewrite.name)?;
+
+    // libvirt generates a new UUID, so importing a bundle twice works
+    dom.children.retain(|n| n.name != "uuid");
+
+    for (old, new) in rewrite.disks {
+        if !replace_disk_source(&mut dom, old, new) {
+            return Err(eyre!("Failed to find disk {old} in domain XML"));
+        }
+    }
+
+    // When libvirt selects the firmware, let it select it again on this
+    // host; the variable store from the bundle works with any build
+    if dom
+        .find("os")
+        .is_some_and(|os| os.attributes.contains_key("firmware"))
+    {
+        if let Some(loader) = dom.find_mut("loader") {
+            loader.text.clear();
+        }
+        if let Some(nvram) = dom.find_mut("nvram") {
+            nvram.attributes.remove("template");
+            nvram.attributes.remove("templateFormat");
+        }
+    }
+    if let Some(nvram) = rewrite.nvram {
+        set_element_text(&mut dom, "nvram", nvram)?;
+    }
+
+    if let Some((old, new)) = rewrite.ssh_port {
+        super::start::replace_ssh_port(&mut dom, old, new)?;
+    }
+
+    if let Some(mut metadata) = DomainMetadata::from_dom(&dom)? {
+        metadata.pool = Some(rewrite.pool.to_owned());
+        // Exported keys are embedded; a key file would refer to this host
+        metadata.ssh_private_key_file = None;
+        metadata.replace_in_dom(&mut dom)?;
+    }
+    dom.to_xml()
+}
+
+/// Directories libvirt keeps per domain state in
+#[derive(Debug)]
+struct StateDirs {
+    nvram: Utf8PathBuf,
+    swtpm: Utf8PathBuf,
+}
+
+impl StateDirs {
+    fn for_connection(global_opts: &super::LibvirtOptions) -> Result<Self> {
+        let uri = super::status::query_uri(global_opts)
+            .ok_or_else(|| eyre!("Failed to determine the libvirt connection URI"))?;
+        if super::ssh::is_remote_uri(&uri) {
+            return Err(eyre!(
+                "Export and import need a local hypervisor, but {uri} is remote"
+            ));
+        }
+        Ok(match ConnectionKind::from_uri(&uri) {
+            ConnectionKind::System => Self {
+                nvram: "/var/lib/libvirt/qemu/nvram".into(),
+                swtpm: "/var/lib/libvirt/swtpm".into(),
+            },
+            ConnectionKind::Session => {
+                let config =
+                    dirs::config_dir().ok_or_else(|| eyre!("No user configuration directory"))?;
+                let libvirt = Utf8PathBuf::try_from(config)?.join("libvirt");
+                Self {
+                    nvram: libvirt.join("qemu/nvram"),
+                    swtpm: libvirt.join("qemu/swtpm"),
+                }
+            }
+        })
+    }
+}
+
+/// Copy a directory tree, preserving ownership, modes and SELinux labels
+fn copy_tree(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    let output = HostCommand::new("cp")
+        .args(["-a", "--", src.as_str(), dest.as_str()])
+        .output()
+        .with_context(|| "Failed to run cp")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to copy {src} to {dest}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn run_tar(args: &[&str]) -> Result<()> {
+    let output = HostCommand::new("tar")
+        .args(args)
+        .output()
+        .with_context(|| "Failed to run tar")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn utf8_path(path: &std::path::Path) -> Result<&Utf8Path> {
+    Utf8Path::from_path(path).ok_or_else(|| eyre!("Invalid UTF-8 in {}", path.display()))
+}
+
+/// Execute the libvirt export command
+pub fn run_export(global_opts: &super::LibvirtOptions, opts: LibvirtExportOpts) -> Result<()> {
+    let name = &opts.name;
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let domain = lister
+        .get_domain_info(name)
+        .map_err(|_| eyre!("VM '{name}' not found"))?;
+    if domain.is_running() {
+        return Err(eyre!(
+            "VM '{name}' is running; stop it with 'bcvk libvirt stop {name}' before exporting"
+        ));
+    }
+    if opts.bundle.exists() && !opts.force {
+        return Err(eyre!(
+            "{} already exists; use --force to overwrite it",
+            opts.bundle
+        ));
+    }
+    let state_dirs = StateDirs::for_connection(global_opts)?;
+
+    let xml = super::start::inactive_domain_xml(global_opts, name)?;
+    let dom = xml_utils::parse_xml_dom(&xml)?;
+    let disks = domain_disks(&dom)?;
+    if disks.is_empty() {
+        return Err(eyre!("VM '{name}' has no disk to export"));
+    }
+    if dom.find("filesystem").is_some() {
+        eprintln!("Warning: VM '{name}' has bind mounts, which refer to paths on this host");
+    }
+    let nvram = dom
+        .find("nvram")
+        .map(|n| n.text_content().trim())
+        .filter(|p| !p.is_empty())
+        .map(Utf8Path::new)
+        .filter(|p| p.exists());
+    let tpm_state = dom
+        .find("tpm")
+        .and_then(|tpm| tpm.find("backend"))
+        .filter(|b| b.attributes.get("type").map(String::as_str) == Some("emulator"))
+        .and_then(|_| dom.find("uuid"))
+        .map(|uuid| state_dirs.swtpm.join(uuid.text_content().trim()))
+        .filter(|p| p.exists());
+
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!("export VM '{name}' to {}", opts.bundle));
+        return Ok(());
+    }
+
+    // Flattened disks can be large, so stage next to the bundle rather
+    // than in a possibly memory backed /tmp
+    let parent = opts
+        .bundle
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    let staging = tempfile::Builder::new()
+        .prefix(".bcvk-export")
+        .tempdir_in(parent)
+        .with_context(|| format!("Creating staging directory in {parent}"))?;
+    let staging_path = utf8_path(staging.path())?;
+    let staging_dir = Dir::open_ambient_dir(staging_path, cap_std::ambient_authority())?;
+    staging_dir.create_dir(DISKS_DIR)?;
+
+    let mut manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        name: name.clone(),
+        image: domain.image.clone(),
+        image_digest: DomainMetadata::from_dom(&dom)?.and_then(|m| m.image_digest),
+        disks: Vec::new(),
+        base: None,
+        nvram: nvram.is_some(),
+        tpm: tpm_state.is_some(),
+    };
+
+    for (idx, disk) in disks.iter().enumerate() {
+        let format = parse_format(&disk.format)?;
+        let file = format!("{DISKS_DIR}/{idx}.{format}");
+        let source = Utf8Path::new(&disk.source);
+        let backing = if idx == 0 && opts.include_base {
+            qemu_img::info(source)?.full_backing_filename
+        } else {
+            None
+        };
+        if let Some(backing) = backing {
+            let backing = Utf8PathBuf::from(backing);
+            let base_name = backing
+                .file_name()
+                .ok_or_else(|| eyre!("Invalid backing file {backing}"))?;
+            println!("Copying base disk {backing}...");
+            staging_dir.create_dir(BASE_DIR)?;
+            std::fs::copy(&backing, staging_path.join(BASE_DIR).join(base_name))
+                .with_context(|| format!("Copying {backing}"))?;
+            println!("Copying disk {source}...");
+            std::fs::copy(source, staging_path.join(&file))
+                .with_context(|| format!("Copying {source}"))?;
+            // Relative to the disk, so it's found wherever the bundle is unpacked
+            qemu_img::rebase(
+                &staging_dir,
+                &file,
+                &format!("../{BASE_DIR}/{base_name}"),
+                ImageFormat::Qcow2,
+                true,
+            )?;
+            manifest.base = Some(base_name.to_owned());
+        } else {
+            println!("Copying disk {source}...");
+            let convert_opts = qemu_img::ConvertOpts {
+                format: Some(format),
+                ..Default::default()
+            };
+            qemu_img::convert(&staging_dir, source.as_str(), &file, &convert_opts)?;
+        }
+        manifest.disks.push(BundleDisk {
+            file,
+            format: format.to_string(),
+            source: disk.source.clone(),
+        });
+    }
+
+    if let Some(nvram) = nvram {
+        std::fs::copy(nvram, staging_path.join(NVRAM))
+            .with_context(|| format!("Copying {nvram}"))?;
+    }
+    if let Some(tpm_state) = tpm_state {
+        copy_tree(&tpm_state, &staging_path.join(TPM_DIR))?;
+    }
+    // The key file only exists on this host
+    let xml = super::ssh_keys::embed_in_xml(&xml, name)?;
+    staging_dir.write(DOMAIN_XML, &xml)?;
+    staging_dir.write(MANIFEST, serde_json::to_vec_pretty(&manifest)?)?;
+
+    println!("Writing bundle {}...", opts.bundle);
+    let bundle_guard = CleanupGuard::remove_path(&opts.bundle);
+    run_tar(&[
+        "--zstd",
+        "--sparse",
+        "--xattrs",
+        "-cf",
+        opts.bundle.as_str(),
+        "-C",
+        staging_path.as_str(),
+        ".",
+    ])?;
+    bundle_guard.disarm();
+
+    println!("VM '{name}' exported to {}", opts.bundle);
+    Ok(())
+}
+
+/// Execute the libvirt import command
+pub fn run_import(global_opts: &super::LibvirtOptions, opts: LibvirtImportOpts) -> Result<()> {
+    let connect_uri = global_opts.connect.as_deref();
+    if !opts.bundle.is_file() {
+        return Err(eyre!("Bundle {} not found", opts.bundle));
+    }
+    let state_dirs = StateDirs::for_connection(global_opts)?;
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!("import VM from {}", opts.bundle));
+        return Ok(());
+    }
+
+    // Unpack into the pool, so disks can be moved into place
+    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri, &opts.pool)?;
+    let staging = tempfile::Builder::new()
+        .prefix(".bcvk-import")
+        .tempdir_in(&pool_path)
+        .with_context(|| format!("Creating staging directory in {pool_path}"))?;
+    let staging_path = utf8_path(staging.path())?;
+    println!("Unpacking bundle {}...", opts.bundle);
+    run_tar(&[
+        "--zstd",
+        "--xattrs",
+        "-xf",
+        opts.bundle.as_str(),
+        "-C",
+        staging_path.as_str(),
+    ])?;
+
+    let manifest: BundleManifest = serde_json::from_slice(
+        &std::fs::read(staging_path.join(MANIFEST)).context("Reading bundle manifest")?,
+    )
+    .context("Parsing bundle manifest")?;
+    if manifest.version != BUNDLE_VERSION {
+        return Err(eyre!(
+            "Unsupported bundle version {} (expected {BUNDLE_VERSION})",
+            manifest.version
+        ));
+    }
+    manifest.validate()?;
+    let xml = std::fs::read_to_string(staging_path.join(DOMAIN_XML))
+        .context("Reading domain XML from bundle")?;
+    // The bundle may have been exported by an older version of bcvk
+    let xml = domain_metadata::migrate(&xml)?.unwrap_or(xml);
+    let dom = xml_utils::parse_xml_dom(&xml)?;
+
+    let name = opts.name.as_deref().unwrap_or(&manifest.name);
+    plain_file_name("name", name)?;
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    if lister.list_all_domains()?.iter().any(|d| d == name) {
+        return Err(eyre!(
+            "VM '{name}' already exists; use --name to import under another name"
+        ));
+    }
+
+    // Everything moved into place is removed again unless the import completes
+    let mut guards = Vec::new();
+
+    // Base disks are named by content, so an existing one is reused
+    if let Some(base) = &manifest.base {
+        let dest = pool_path.join(base);
+        if dest.exists() {
+            println!("Using existing base disk {dest}");
+        } else {
+            std::fs::rename(staging_path.join(BASE_DIR).join(base), &dest)
+                .with_context(|| format!("Moving base disk to {dest}"))?;
+            guards.push(CleanupGuard::remove_path(&dest));
+        }
+    }
+
+    // Disks are named like those created by `bcvk libvirt run`
+    let mut disk_paths = Vec::new();
+    for (idx, disk) in manifest.disks.iter().enumerate() {
+        let file_name = match idx {
+            0 => format!("{name}.{}", disk.format),
+            n => format!("{name}-disk{n}.{}", disk.format),
+        };
+        let dest = pool_path.join(&file_name);
+        if dest.exists() {
+            return Err(eyre!(
+                "{dest} already exists; remove it or use --name to import under another name"
+            ));
+        }
+        std::fs::rename(staging_path.join(&disk.file), &dest)
+            .with_context(|| format!("Moving disk to {dest}"))?;
+        guards.push(CleanupGuard::remove_path(&dest));
+        if idx == 0 {
+            if let Some(base) = &manifest.base {
+                let pool_dir = Dir::open_ambient_dir(&pool_path, cap_std::ambient_authority())?;
+                qemu_img::rebase(&pool_dir, &file_name, base, ImageFormat::Qcow2, true)?;
+            }
+        }
+        disk_paths.push((disk.source.clone(), dest.to_string()));
+    }
+    // Make libvirt aware of the new volumes
+    match global_opts
+        .virsh_command()
+        .args(["pool-refresh", opts.pool.as_str()])
+        .output()
+    {
+        Ok(output) if !output.status.success() => warn!(
+            "Failed to refresh libvirt storage pool {}: {}",
+            opts.pool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to run virsh pool-refresh: {e}"),
+    }
+
+    let nvram = if manifest.nvram {
+        let old = dom.find("nvram").map(|n| n.text_content().trim());
+        let ext = old
+            .and_then(|p| Utf8Path::new(p).extension())
+            .unwrap_or("fd");
+        let dest = state_dirs.nvram.join(format!("{name}_VARS.{ext}"));
+        std::fs::create_dir_all(&state_dirs.nvram)
+            .with_context(|| format!("Creating {}", state_dirs.nvram))?;
+        std::fs::copy(staging_path.join(NVRAM), &dest)
+            .with_context(|| format!("Copying NVRAM to {dest}"))?;
+        guards.push(CleanupGuard::remove_path(&dest));
+        Some(dest.into_string())
+    } else {
+        // The domain was never started; don't refer to the exporting
+        // host's path, libvirt picks one when the store is created
+        dom.find("nvram").map(|_| String::new())
+    };
+
+    let ssh_port = DomainMetadata::from_dom(&dom)?
+        .and_then(|m| m.ssh_port)
+        .map(|old| (old, super::run::find_available_ssh_port()));
+    let xml = rewrite_domain_xml(
+        &xml,
+        &XmlRewrite {
+            name,
+            disks: &disk_paths,
+            nvram: nvram.as_deref(),
+            ssh_port,
+            pool: &opts.pool,
+        },
+    )?;
+    let (xml, key_file) = super::ssh_keys::externalize_in_xml(&xml, name)?;
+    if let Some(key_file) = key_file {
+        guards.push(CleanupGuard::remove_path(key_file));
+    }
+    let dom = xml_utils::parse_xml_dom(&xml)?;
+    if !dom
+        .find("os")
+        .is_some_and(|os| os.attributes.contains_key("firmware"))
+    {
+        if let Some(loader) = dom.find("loader").map(|l| l.text_content().trim()) {
+            if !loader.is_empty() && !Utf8Path::new(loader).exists() {
+                return Err(eyre!(
+                    "Firmware {loader} used by VM '{}' does not exist on this host",
+                    manifest.name
+                ));
+            }
+        }
+    }
+
+    let mut tmpf = tempfile::NamedTempFile::with_prefix("bcvk-libvirt")?;
+    tmpf.write_all(xml.as_bytes())
+        .with_context(|| "Failed to write domain XML")?;
+    let xml_path = tmpf
+        .path()
+        .to_str()
+        .ok_or_else(|| eyre!("Invalid UTF-8 in tempfile"))?;
+    super::run::run_virsh_cmd(
+        connect_uri,
+        &["define", xml_path],
+        "Failed to define libvirt domain",
+    )?;
+    let undefine_guard = {
+        let connect_uri = connect_uri.map(ToOwned::to_owned);
+        let name = name.to_owned();
+        CleanupGuard::new(format!("domain {name}"), move || {
+            if let Err(e) = super::run::run_virsh_cmd(
+                connect_uri.as_deref(),
+                &["undefine", "--nvram", &name],
+                "Failed to undefine libvirt domain",
+            ) {
+                tracing::warn!("{e}");
+            }
+        })
+    };
+
+    // swtpm state is keyed by the UUID libvirt generated
+    if manifest.tpm {
+        let uuid = lister.get_domain_xml(name)?;
+        let uuid = uuid
+            .find("uuid")
+            .map(|n| n.text_content().trim().to_owned())
+            .ok_or_else(|| eyre!("VM '{name}' has no UUID"))?;
+        let dest = state_dirs.swtpm.join(&uuid);
+        debug!("Restoring TPM state to {dest}");
+        std::fs::create_dir_all(&state_dirs.swtpm)
+            .with_context(|| format!("Creating {}", state_dirs.swtpm))?;
+        copy_tree(&staging_path.join(TPM_DIR), &dest)?;
+        guards.push(CleanupGuard::remove_path(&dest));
+    }
+
+    if opts.start {
+        super::run::run_virsh_cmd(connect_uri, &["start", name], "Failed to start VM")?;
+    }
+    undefine_guard.disarm();
+    guards.into_iter().for_each(CleanupGuard::disarm);
+
+    crate::events::record(crate::events::EventKind::VmCreated {
+        name: name.to_owned(),
+        image: manifest
+            .image
+            .clone()
+            .unwrap_or_else(|| opts.bundle.to_string()),
+    });
+    println!("VM '{name}' imported from {}", opts.bundle);
+    if let Some((_, port)) = ssh_port {
+        println!("  SSH port: {port}");
+    }
+    if !opts.start {
+        println!("Start it with: bcvk libvirt start {name}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN_XML: &str = r#"<domain type='kvm' xmlns:qemu='http://libvirt.org/schemas/domain/qemu/1.0'>
+  <name>old-vm</name>
+  <uuid>5d2d1cbb-5b8e-4f1e-9d8f-0a4b0b7c1d2e</uuid>
+  <metadata>
+    <bootc:container xmlns:bootc='https://github.com/containers/bootc'>
+      <bootc:source-image>quay.io/fedora/fedora-bootc:42</bootc:source-image>
+      <bootc:ssh-port>2345</bootc:ssh-port>
+      <bootc:ssh-private-key-file>/home/user/.ssh/id_ed25519</bootc:ssh-private-key-file>
+    </bootc:container>
+  </metadata>
+  <os firmware='efi'>
+    <type arch='x86_64' machine='pc-q35-9.2'>hvm</type>
+    <loader readonly='yes' type='pflash' format='raw'>/usr/share/edk2/ovmf/OVMF_CODE.fd</loader>
+    <nvram template='/usr/share/edk2/ovmf/OVMF_VARS.fd' templateFormat='raw' format='raw'>/var/lib/libvirt/qemu/nvram/old-vm_VARS.fd</nvram>
+  </os>
+  <devices>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='qcow2'/>
+      <source file='/var/lib/libvirt/images/old-vm.qcow2'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='raw'/>
+      <source file='/var/lib/libvirt/images/old-vm-disk1.raw'/>
+      <target dev='vdb' bus='virtio'/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <source file='/tmp/cidata.iso'/>
+    </disk>
+  </devices>
+  <qemu:commandline>
+    <qemu:arg value='-netdev'/>
+    <qemu:arg value='user,id=ssh0,hostfwd=tcp::2345-:22'/>
+  </qemu:commandline>
+</domain>"#;
+
+    #[test]
+    fn test_domain_disks() -> Result<()> {
+        let dom = xml_utils::parse_xml_dom(DOMAIN_XML)?;
+        assert_eq!(
+            domain_disks(&dom)?,
+            [
+                DomainDisk {
+                    source: "/var/lib/libvirt/images/old-vm.qcow2".into(),
+                    format: "qcow2".into(),
+                },
+                DomainDisk {
+                    source: "/var/lib/libvirt/images/old-vm-disk1.raw".into(),
+                    format: "raw".into(),
+                },
+            ]
+        );
+
+        let dom = xml_utils::parse_xml_dom(
+            "<domain><devices><disk type='volume' device='disk'><source pool='default' volume='x'/></disk></devices></domain>",
+        )?;
+        assert!(domain_disks(&dom).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_element_text() -> Result<()> {
+        let mut dom = xml_utils::parse_xml_dom("<os><nvram format='raw'/><name>a</name></os>")?;
+        set_element_text(&mut dom, "nvram", "/x_VARS.fd")?;
+        set_element_text(&mut dom, "name", "b&c")?;
+        assert_eq!(
+            dom.to_xml()?,
+            r#"<os><nvram format="raw">/x_VARS.fd</nvram><name>b&amp;c</name></os>"#
+        );
+        assert!(set_element_text(&mut dom, "loader", "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_domain_xml() -> Result<()> {
+        let disks = [
+            (
+                "/var/lib/libvirt/images/old-vm.qcow2".to_string(),
+                "/srv/pool/new-vm.qcow2".to_string(),
+            ),
+            (
+                "/var/lib/libvirt/images/old-vm-disk1.raw".to_string(),
+                "/srv/pool/new-vm-disk1.raw".to_string(),
+            ),
+        ];
+        let xml = rewrite_domain_xml(
+            DOMAIN_XML,
+            &XmlRewrite {
+                name: "new-vm",
+                disks: &disks,
+                nvram: Some("/var/lib/libvirt/qemu/nvram/new-vm_VARS.fd"),
+                ssh_port: Some((2345, 2400)),
+                pool: "fast",
+            },
+        )?;
+        let dom = xml_utils::parse_xml_dom(&xml)?;
+        assert_eq!(dom.find("name").unwrap().text_content(), "new-vm");
+        assert!(dom.find("uuid").is_none());
+        assert_eq!(
+            domain_disks(&dom)?
+                .into_iter()
+                .map(|d| d.source)
+                .collect::<Vec<_>>(),
+            ["/srv/pool/new-vm.qcow2", "/srv/pool/new-vm-disk1.raw"]
+        );
+        assert_eq!(dom.find("loader").unwrap().text_content(), "");
+        let nvram = dom.find("nvram").unwrap();
+        assert_eq!(
+            nvram.text_content(),
+            "/var/lib/libvirt/qemu/nvram/new-vm_VARS.fd"
+        );
+        assert!(!nvram.attributes.contains_key("template"));
+        assert!(!nvram.attributes.contains_key("templateFormat"));
+        assert_eq!(nvram.attributes["format"], "raw");
+        assert_eq!(
+            dom.find_with_namespace("ssh-port").unwrap().text_content(),
+            "2400"
+        );
+        assert!(xml.contains("hostfwd=tcp::2400-:22"));
+        let metadata = DomainMetadata::from_dom(&dom)?.unwrap();
+        assert_eq!(metadata.pool.as_deref(), Some("fast"));
+        assert_eq!(metadata.ssh_private_key_file, None);
+
+        // Disks must match the bundle
+        let missing = [("/nonexistent.qcow2".to_string(), "/x.qcow2".to_string())];
+        assert!(rewrite_domain_xml(
+            DOMAIN_XML,
+            &XmlRewrite {
+                name: "new-vm",
+                disks: &missing,
+                nvram: None,
+                ssh_port: None,
+                pool: "default",
+            },
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_manifest() {
+        let manifest = |base: Option<&str>, file: &str, format: &str| BundleManifest {
+            version: BUNDLE_VERSION,
+            name: "vm".into(),
+            image: None,
+            image_digest: None,
+            disks: vec![BundleDisk {
+                file: file.into(),
+                format: format.into(),
+                source: "/var/lib/libvirt/images/vm.qcow2".into(),
+            }],
+            base: base.map(Into::into),
+            nvram: false,
+            tpm: false,
+        };
+        assert!(
+            manifest(Some("bcvk-base-0123.qcow2"), "disks/0.qcow2", "qcow2")
+                .validate()
+                .is_ok()
+        );
+        let invalid = [
+            (Some("../x.qcow2"), "disks/0.qcow2", "qcow2"),
+            (Some("/home/u/secret.qcow2"), "disks/0.qcow2", "qcow2"),
+            (None, "../0.qcow2", "qcow2"),
+            (None, "/home/u/secret.qcow2", "qcow2"),
+            (None, "disks/../../0.qcow2", "qcow2"),
+            (None, "disks/a/0.qcow2", "qcow2"),
+            (None, "disks/0.qcow2", "qcow2/../../x"),
+        ];
+        for (base, file, format) in invalid {
+            assert!(
+                manifest(base, file, format).validate().is_err(),
+                "{base:?} {file} {format}"
+            );
+        }
+        let name = BundleManifest {
+            name: "../vm".into(),
+            ..manifest(None, "disks/0.qcow2", "qcow2")
+        };
+        assert!(name.validate().is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Host names for libvirt domains
+//!
+//! `libvirt run --register-dns` makes a VM resolvable on the host as
+//! `<name>.bcvk.local`, so that tests talking to several VMs can refer to
+//! them by name. The names are kept in a block of /etc/hosts which bcvk
+//! manages; systemd-resolved has no interface for adding static records, but
+//! answers from /etc/hosts as well. A VM only on user-mode networking is
+//! reached through the ports forwarded on the loopback address, so its name
+//! resolves to 127.0.0.1; a VM on a libvirt network to its address there.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, Write};
+use std::net::IpAddr;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use rustix::fs::{Access, FlockOperation};
+use tracing::debug;
+
+/// Domain the names of VMs are registered in
+pub const DNS_DOMAIN: &str = "bcvk.local";
+
+/// The hosts file holding the names
+const HOSTS_FILE: &str = "/etc/hosts";
+
+/// First line of the block of the hosts file managed by bcvk
+const BEGIN_MARKER: &str = "# BEGIN bcvk managed hosts";
+
+/// Last line of the block of the hosts file managed by bcvk
+const END_MARKER: &str = "# END bcvk managed hosts";
+
+/// The host name of a domain, which must be a valid DNS label
+pub(crate) fn host_name(domain_name: &str) -> Result<String> {
+    let valid = !domain_name.is_empty()
+        && domain_name.len() <= 63
+        && !domain_name.starts_with('-')
+        && !domain_name.ends_with('-')
+        && domain_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(eyre!(
+            "VM name '{domain_name}' is not a valid DNS label \
+             (letters, digits and '-', at most 63 characters)"
+        ));
+    }
+    Ok(format!("{}.{DNS_DOMAIN}", domain_name.to_ascii_lowercase()))
+}
+
+/// Set the address of `host` in the managed block of a hosts file, or
+/// remove it if `address` is `None`
+///
+/// Returns `None` if nothing changes. The block is appended if missing, and
+/// dropped once empty.
+fn update_hosts(content: &str, host: &str, address: Option<IpAddr>) -> Option<String> {
+    let mut before = Vec::new();
+    let mut block = Vec::new();
+    let mut lines = content.lines();
+    for line in lines.by_ref() {
+        if line == BEGIN_MARKER {
+            break;
+        }
+        before.push(line);
+    }
+    for line in lines.by_ref() {
+        if line == END_MARKER {
+            break;
+        }
+        block.push(line.to_owned());
+    }
+    let after: Vec<_> = lines.collect();
+
+    let old = block.clone();
+    block.retain(|line| line.split_whitespace().nth(1) != Some(host));
+    if let Some(address) = address {
+        block.push(format!("{address}\t{host}"));
+    }
+    if block == old {
+        return None;
+    }
+
+    let mut out = String::new();
+    for line in before {
+        out.push_str(line);
+        out.push('\n');
+    }
+    if !block.is_empty() {
+        out.push_str(BEGIN_MARKER);
+        out.push('\n');
+        for line in &block {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str(END_MARKER);
+        out.push('\n');
+    }
+    for line in after {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// Apply [`update_hosts`] to the hosts file, returning whether it changed
+///
+/// The file is rewritten in place rather than replaced, as it is often bind
+/// mounted, and locked against concurrent bcvk invocations.
+fn edit_hosts(host: &str, address: Option<IpAddr>) -> Result<bool> {
+    let content =
+        std::fs::read_to_string(HOSTS_FILE).with_context(|| format!("Reading {HOSTS_FILE}"))?;
+    if update_hosts(&content, host, address).is_none() {
+        return Ok(false);
+    }
+
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(HOSTS_FILE)
+        .with_context(|| format!("Opening {HOSTS_FILE} for writing"))?;
+    rustix::fs::flock(&f, FlockOperation::LockExclusive)
+        .with_context(|| format!("Locking {HOSTS_FILE}"))?;
+    let mut content = String::new();
+    f.read_to_string(&mut content)
+        .with_context(|| format!("Reading {HOSTS_FILE}"))?;
+    let Some(updated) = update_hosts(&content, host, address) else {
+        return Ok(false);
+    };
+    f.rewind()?;
+    f.set_len(0)?;
+    f.write_all(updated.as_bytes())
+        .with_context(|| format!("Writing {HOSTS_FILE}"))?;
+    Ok(true)
+}
+
+/// Fail early if names can't be registered, before creating a VM
+pub(crate) fn check_writable(domain_name: &str) -> Result<()> {
+    host_name(domain_name)?;
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
+    rustix::fs::access(HOSTS_FILE, Access::WRITE_OK).map_err(|e| {
+        eyre!(
+            "--register-dns needs write access to {HOSTS_FILE} ({e}); \
+             run as root or grant the current user write access"
+        )
+    })
+}
+
+/// Make `domain_name` resolve to `address` on the host
+///
+/// Returns the registered host name.
+pub(crate) fn register(domain_name: &str, address: IpAddr) -> Result<String> {
+    let host = host_name(domain_name)?;
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!("add {address} {host} to {HOSTS_FILE}"));
+        return Ok(host);
+    }
+    edit_hosts(&host, Some(address))?;
+    debug!("Registered {host} as {address}");
+    Ok(host)
+}
+
+/// Remove the host name of a domain which is being removed, if registered
+pub(crate) fn unregister(domain_name: &str) {
+    // Such a domain can't have been registered
+    let Ok(host) = host_name(domain_name) else {
+        return;
+    };
+    if crate::hostexec::dry_run() {
+        return;
+    }
+    match edit_hosts(&host, None) {
+        Ok(true) => debug!("Removed {host} from {HOSTS_FILE}"),
+        Ok(false) => {}
+        Err(e) => tracing::warn!("Failed to remove {host} from {HOSTS_FILE}: {e:#}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTS: &str = "127.0.0.1 localhost\n::1 localhost\n";
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(host_name("MyVM-1").unwrap(), "myvm-1.bcvk.local");
+        let long = "a".repeat(64);
+        for name in ["", "-vm", "vm-", "my_vm", "vm.example", long.as_str()] {
+            assert!(host_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_update_hosts() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let content = update_hosts(HOSTS, "a.bcvk.local", Some(localhost)).unwrap();
+        assert_eq!(
+            content,
+            format!("{HOSTS}{BEGIN_MARKER}\n127.0.0.1\ta.bcvk.local\n{END_MARKER}\n")
+        );
+        // Registering again changes nothing
+        assert_eq!(
+            update_hosts(&content, "a.bcvk.local", Some(localhost)),
+            None
+        );
+
+        // Lines after the block are kept, and a changed entry moves to its end
+        let content = format!("{content}# added later\n");
+        let content =
+            update_hosts(&content, "b.bcvk.local", Some("10.0.5.10".parse().unwrap())).unwrap();
+        let content =
+            update_hosts(&content, "a.bcvk.local", Some("10.0.5.11".parse().unwrap())).unwrap();
+        assert_eq!(
+            content,
+            format!(
+                "{HOSTS}{BEGIN_MARKER}\n10.0.5.10\tb.bcvk.local\n10.0.5.11\ta.bcvk.local\n\
+                 {END_MARKER}\n# added later\n"
+            )
+        );
+
+        let content = update_hosts(&content, "a.bcvk.local", None).unwrap();
+        assert_eq!(update_hosts(&content, "a.bcvk.local", None), None);
+        // The block is dropped with its last entry
+        let content = update_hosts(&content, "b.bcvk.local", None).unwrap();
+        assert_eq!(content, format!("{HOSTS}# added later\n"));
+    }
+}
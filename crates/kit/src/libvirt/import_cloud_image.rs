@@ -0,0 +1,287 @@
+//! libvirt import-cloud-image command - move a generic cloud image to bootc
+//!
+//! For users provisioning VMs from generic cloud images (e.g. Fedora Cloud
+//! or CentOS GenericCloud qcow2 files) who want to move to bootc without a
+//! fresh install. The image is downloaded or copied into the storage pool as
+//! `<domain>.cloud-image`, grown to the requested disk size, and booted
+//! with `libvirt run --disk-image`; the SSH key is injected via systemd
+//! credentials like for any other domain, so cloud-init is not needed.
+//!
+//! The guest is then moved to the container image over SSH: images which
+//! are already bootc systems run `bootc switch`, package-based ones run
+//! `bootc install to-existing-root` from the container image with podman.
+//! After a restart the domain runs the container image and is managed like
+//! one created by `libvirt run`. The imported image remains the backing
+//! file of the VM disk and is removed along with the domain.
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::cleanup::CleanupGuard;
+use crate::domain_list::DomainLister;
+use crate::libvirt::domain_metadata::DomainMetadata;
+use crate::libvirt::run::LibvirtRunOpts;
+
+/// Moves the guest to the container image given as the first argument
+const SWITCH_SCRIPT: &str = r#"set -eu
+image=$1
+if [ -e /run/ostree-booted ]; then
+    exec bootc switch "$image"
+fi
+command -v podman >/dev/null || { echo "podman is not installed in the cloud image" >&2; exit 1; }
+exec podman run --rm --privileged --pid=host \
+    --security-opt label=type:unconfined_t \
+    -v /:/target -v /dev:/dev -v /var/lib/containers:/var/lib/containers \
+    "$image" bootc install to-existing-root --acknowledge-destructive
+"#;
+
+/// Seconds to wait for the guest to shut down before restarting it
+const SHUTDOWN_TIMEOUT_SECS: u64 = 120;
+
+/// Options for importing a cloud image and switching it to a bootc image
+#[derive(Debug, Parser)]
+pub struct LibvirtImportCloudImageOpts {
+    /// URL (http or https) or path of the cloud image, in qcow2 or raw format
+    pub source: String,
+
+    /// Container image to switch the VM to
+    #[clap(long, value_name = "IMAGE", add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub switch_to: String,
+
+    /// Name for the VM (auto-generated from the container image if not specified)
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// Size the cloud image is grown to; it must fit the pulled container image and the new deployment
+    #[clap(long, default_value = "20G")]
+    pub disk_size: String,
+
+    /// Memory for the VM (e.g. 4G, 2048M)
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_MEMORY)]
+    pub memory: String,
+
+    /// Number of virtual CPUs for the VM
+    #[clap(long, default_value = "2")]
+    pub cpus: u32,
+
+    /// Libvirt storage pool for the disks of the VM; pools other than the default one must already exist
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+
+    /// User-defined labels for organizing VMs (comma not allowed in labels)
+    #[clap(long)]
+    pub label: Vec<String>,
+}
+
+/// Whether `source` is a URL rather than a path
+fn is_url(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+/// Path of the cloud image imported for the domain `name`, next to its disk
+pub(crate) fn imported_image_path(disk_path: &Utf8Path, name: &str) -> Utf8PathBuf {
+    disk_path.with_file_name(format!("{name}.cloud-image"))
+}
+
+/// Download `url` to `dest`
+fn download(url: &str, dest: &Utf8Path) -> Result<()> {
+    println!("Downloading {url}...");
+    let mut response = reqwest::blocking::get(url)
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to download {url}"))?;
+    let mut out = BufWriter::new(File::create(dest).with_context(|| format!("Creating {dest}"))?);
+    response
+        .copy_to(&mut out)
+        .with_context(|| format!("Failed to download {url}"))?;
+    out.into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Writing {dest}"))?;
+    Ok(())
+}
+
+/// Arguments of `libvirt run` booting the imported image
+fn run_args(opts: &LibvirtImportCloudImageOpts, name: &str, image: &Utf8Path) -> Vec<String> {
+    let mut args = vec![
+        "run".to_owned(),
+        "--name".to_owned(),
+        name.to_owned(),
+        "--disk-image".to_owned(),
+        image.to_string(),
+        "--memory".to_owned(),
+        opts.memory.clone(),
+        "--cpus".to_owned(),
+        opts.cpus.to_string(),
+        "--pool".to_owned(),
+        opts.pool.clone(),
+        "--wait".to_owned(),
+        "ssh".to_owned(),
+    ];
+    for label in &opts.label {
+        args.extend(["--label".to_owned(), label.clone()]);
+    }
+    args.push(opts.switch_to.clone());
+    args
+}
+
+fn ssh_opts(name: &str, command: &[&str]) -> super::ssh::LibvirtSshOpts {
+    super::ssh::LibvirtSshOpts {
+        domain_name: name.to_owned(),
+        user: Some("root".to_owned()),
+        command: command.iter().map(|s| s.to_string()).collect(),
+        no_strict: false,
+        timeout: 30,
+        log_level: "ERROR".to_string(),
+        extra_options: vec![],
+        suppress_output: false,
+        wait: None,
+    }
+}
+
+/// Drop the pinned SSH host keys from the persistent definition of a
+/// stopped domain, as the new root filesystem may have others
+fn forget_host_keys(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    let xml = super::start::inactive_domain_xml(global_opts, name)?;
+    let mut metadata =
+        DomainMetadata::from_xml(&xml)?.ok_or_else(|| eyre!("No bcvk metadata in domain XML"))?;
+    metadata.ssh_host_keys.clear();
+    super::ssh::store_metadata(global_opts, name, &metadata, "SSH host keys", false)
+}
+
+/// Execute the libvirt import-cloud-image command
+pub fn run(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    opts: LibvirtImportCloudImageOpts,
+) -> Result<()> {
+    let connect_uri = global_opts.connect.as_deref();
+    if connect_uri.is_some_and(super::ssh::is_remote_uri) {
+        return Err(eyre!(
+            "import-cloud-image is not supported with a remote hypervisor"
+        ));
+    }
+    let size = crate::utils::parse_size(&opts.disk_size)?;
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let existing_domains = lister
+        .list_all_domains()
+        .with_context(|| "Failed to list existing domains")?;
+    let name = match &opts.name {
+        Some(name) if existing_domains.contains(name) => {
+            return Err(eyre!("VM '{name}' already exists"));
+        }
+        Some(name) => name.clone(),
+        None => super::run::generate_unique_vm_name(&opts.switch_to, &existing_domains),
+    };
+
+    let pool_path = super::run::get_libvirt_storage_pool_path(connect_uri, &opts.pool)?;
+    let image_path = imported_image_path(&pool_path.join(format!("{name}.qcow2")), &name);
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!(
+            "import {} to {image_path}, boot it as '{name}' and switch it to {}",
+            opts.source, opts.switch_to
+        ));
+        return Ok(());
+    }
+
+    // Removed again unless the domain is created
+    let image_guard = CleanupGuard::remove_path(&image_path);
+    if is_url(&opts.source) {
+        download(&opts.source, &image_path)?;
+    } else {
+        println!("Copying {} to {image_path}...", opts.source);
+        std::fs::copy(&opts.source, &image_path)
+            .with_context(|| format!("Failed to copy {} to {image_path}", opts.source))?;
+    }
+    let info = crate::qemu_img::info(&image_path)?;
+    if info.virtual_size < size {
+        debug!("Growing {image_path} to {size} bytes");
+        let (dir, file_name) = crate::qemu_img::open_parent(&image_path)?;
+        crate::qemu_img::resize(&dir, file_name, size, false)
+            .with_context(|| format!("Failed to grow {image_path}"))?;
+    }
+
+    let run_opts = LibvirtRunOpts::try_parse_from(run_args(&opts, &name, &image_path))
+        .context("Invalid options for the VM")?;
+    super::run::run(global_opts, run_opts)?;
+    image_guard.disarm();
+
+    // Cloud images usually grow their root filesystem with cloud-init,
+    // which does nothing without a datasource
+    super::resize_disk::grow_fs(global_opts, &name)?;
+
+    println!("Switching VM '{name}' to {}...", opts.switch_to);
+    let mut command = vec!["sh", "-c", SWITCH_SCRIPT, "sh"];
+    command.push(&opts.switch_to);
+    super::ssh::run_ssh_impl(global_opts, ssh_opts(&name, &command))
+        .with_context(|| format!("Failed to switch VM '{name}' to {}", opts.switch_to))?;
+
+    super::update::shutdown_and_wait(
+        global_opts,
+        &lister,
+        &name,
+        std::time::Duration::from_secs(SHUTDOWN_TIMEOUT_SECS),
+    )?;
+    forget_host_keys(global_opts, &name)?;
+    super::update::start(global_opts, &name)?;
+    super::run::wait_for_ssh(global_opts, &name)?;
+
+    let status = super::ssh::run_ssh_output(
+        global_opts,
+        &ssh_opts(&name, &["bootc", "status", "--format=json"]),
+    )?;
+    let (image, digest) = super::update::booted_image(&status)
+        .with_context(|| format!("VM '{name}' did not boot {}", opts.switch_to))?;
+    let dom = lister.get_domain_xml(&name)?;
+    let mut metadata = DomainMetadata::from_dom(&dom)?
+        .ok_or_else(|| eyre!("Domain '{name}' has no bcvk metadata"))?;
+    metadata.source_image = Some(image.clone());
+    metadata.image_digest = Some(digest.clone());
+    super::ssh::store_metadata(global_opts, &name, &metadata, "image", true)?;
+    crate::events::record(crate::events::EventKind::VmUpdated {
+        name: name.clone(),
+        image: image.clone(),
+    });
+
+    println!("VM '{name}' now runs {image} ({digest})");
+    println!("\nUse 'bcvk libvirt ssh {name}' to connect");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_args() {
+        let opts = LibvirtImportCloudImageOpts::try_parse_from([
+            "import-cloud-image",
+            "https://example.com/cloud.qcow2",
+            "--switch-to",
+            "quay.io/fedora/fedora-bootc:42",
+            "--label",
+            "migrated",
+        ])
+        .unwrap();
+        assert!(is_url(&opts.source));
+        assert!(!is_url("./cloud.qcow2"));
+
+        let image = imported_image_path(Utf8Path::new("/var/lib/libvirt/images/vm.qcow2"), "vm");
+        assert_eq!(image, "/var/lib/libvirt/images/vm.cloud-image");
+        let run_opts = LibvirtRunOpts::try_parse_from(run_args(&opts, "vm", &image)).unwrap();
+        assert_eq!(run_opts.name.as_deref(), Some("vm"));
+        assert_eq!(run_opts.disk_image.as_deref(), Some(image.as_path()));
+        assert_eq!(
+            run_opts.image.as_deref(),
+            Some("quay.io/fedora/fedora-bootc:42")
+        );
+        assert_eq!(run_opts.label, ["migrated"]);
+    }
+}
@@ -3,15 +3,24 @@
 //! This module provides functionality to display detailed information about
 //! libvirt domains that were created from bootc container images.
 
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
+use serde::Serialize;
 
 use super::OutputFormat;
+use crate::domain_list::PodmanBootcDomain;
+use crate::qemu_img::{self, QemuImgInfo};
+
+/// Give up on backing chains deeper than this (they are likely cyclic)
+const MAX_CHAIN_DEPTH: usize = 16;
 
 /// Options for inspecting a libvirt domain
 #[derive(Debug, Parser)]
 pub struct LibvirtInspectOpts {
     /// Name of the domain to inspect
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
     pub name: String,
 
     /// Output format
@@ -19,6 +28,134 @@ pub struct LibvirtInspectOpts {
     pub format: OutputFormat,
 }
 
+/// One image in the backing chain of a VM disk
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct DiskChainLayer {
+    /// Path to the image
+    pub path: Utf8PathBuf,
+    /// Image format, if the image exists
+    pub format: Option<String>,
+    /// Virtual size in bytes, if the image exists
+    pub virtual_size: Option<u64>,
+    /// Space used on disk in bytes, if the image exists
+    pub actual_size: Option<u64>,
+    /// Whether this is a bcvk base disk
+    pub base_disk: bool,
+    /// Container image digest the disk was installed from, if recorded
+    pub image_digest: Option<String>,
+    /// Whether the image is referenced but no longer exists
+    pub missing: bool,
+}
+
+/// Domain information plus its resolved disk chain, for JSON output
+#[derive(Debug, Serialize)]
+struct InspectOutput<'a> {
+    #[serde(flatten)]
+    vm: &'a PodmanBootcDomain,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk_chain: Option<Vec<DiskChainLayer>>,
+}
+
+/// Walk the backing chain starting at `top`, using `info` to query each
+/// image. `info` returns `None` for an image which does not exist, which
+/// ends the chain.
+fn resolve_disk_chain_with(
+    top: &Utf8Path,
+    info: impl Fn(&Utf8Path) -> Result<Option<QemuImgInfo>>,
+) -> Result<Vec<DiskChainLayer>> {
+    let mut chain = Vec::new();
+    let mut next = Some(top.to_owned());
+    while let Some(path) = next.take() {
+        if chain.len() >= MAX_CHAIN_DEPTH {
+            return Err(eyre!(
+                "Backing chain of {top} is deeper than {MAX_CHAIN_DEPTH}"
+            ));
+        }
+        let base_disk = path
+            .file_name()
+            .is_some_and(super::base_disks::is_base_disk_name);
+        let Some(img) = info(&path)? else {
+            chain.push(DiskChainLayer {
+                path,
+                format: None,
+                virtual_size: None,
+                actual_size: None,
+                base_disk,
+                image_digest: None,
+                missing: true,
+            });
+            break;
+        };
+        let image_digest = crate::cache_metadata::DiskImageMetadata::read_image_digest_from_path(
+            path.as_std_path(),
+        )
+        .unwrap_or(None);
+        // Relative backing file names are relative to the overlay
+        next = img
+            .full_backing_filename
+            .or(img.backing_filename)
+            .map(|backing| match path.parent() {
+                Some(parent) => parent.join(backing),
+                None => backing.into(),
+            });
+        chain.push(DiskChainLayer {
+            path,
+            format: Some(img.format),
+            virtual_size: Some(img.virtual_size),
+            actual_size: img.actual_size,
+            base_disk,
+            image_digest,
+            missing: false,
+        });
+    }
+    Ok(chain)
+}
+
+/// Resolve the backing chain of a VM disk, from the disk itself down to
+/// the bottom-most image (normally a base disk).
+pub fn resolve_disk_chain(top: &Utf8Path) -> Result<Vec<DiskChainLayer>> {
+    resolve_disk_chain_with(top, |path| {
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        qemu_img::info(path).map(Some)
+    })
+}
+
+/// Print the disk chain as part of the YAML output
+fn print_disk_chain_yaml(chain: &[DiskChainLayer]) {
+    if let Some(base) = chain.iter().find(|l| l.base_disk) {
+        println!("base_disk: {}", base.path);
+        println!("base_disk_missing: {}", base.missing);
+        if let Some(ref digest) = base.image_digest {
+            println!("base_disk_image_digest: {}", digest);
+        }
+    }
+    println!("disk_chain:");
+    for layer in chain {
+        println!("  - path: {}", layer.path);
+        if layer.missing {
+            println!("    missing: true");
+            continue;
+        }
+        if let Some(ref format) = layer.format {
+            println!("    format: {}", format);
+        }
+        if let Some(size) = layer.virtual_size {
+            println!("    virtual_size: {}", size);
+        }
+        if let Some(size) = layer.actual_size {
+            println!("    actual_size: {}", size);
+        }
+        if layer.base_disk {
+            println!("    base_disk: true");
+        }
+        if let Some(ref digest) = layer.image_digest {
+            println!("    image_digest: {}", digest);
+        }
+    }
+}
+
 /// Execute the libvirt inspect command
 pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtInspectOpts) -> Result<()> {
     use crate::domain_list::DomainLister;
@@ -35,6 +172,20 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtInspectOpt
         .get_domain_info(&opts.name)
         .map_err(|_| color_eyre::eyre::eyre!("VM '{}' not found", opts.name))?;
 
+    // The disk may not be accessible from here, e.g. with a remote connection
+    let disk_chain = match vm.disk_path.as_deref() {
+        Some(disk_path) if matches!(opts.format, OutputFormat::Yaml | OutputFormat::Json) => {
+            match resolve_disk_chain(Utf8Path::new(disk_path)) {
+                Ok(chain) => Some(chain),
+                Err(e) => {
+                    eprintln!("Warning: Failed to resolve disk chain of {disk_path}: {e:#}");
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
     match opts.format {
         OutputFormat::Yaml => {
             println!("name: {}", vm.name);
@@ -51,11 +202,18 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtInspectOpt
             if let Some(ref disk_path) = vm.disk_path {
                 println!("disk_path: {}", disk_path);
             }
+            if let Some(ref chain) = disk_chain {
+                print_disk_chain_yaml(chain);
+            }
         }
         OutputFormat::Json => {
+            let output = InspectOutput {
+                vm: &vm,
+                disk_chain,
+            };
             println!(
                 "{}",
-                serde_json::to_string_pretty(&vm)
+                serde_json::to_string_pretty(&output)
                     .with_context(|| "Failed to serialize VM as JSON")?
             );
         }
@@ -84,3 +242,75 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtInspectOpt
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn info(format: &str, backing: Option<&str>) -> QemuImgInfo {
+        serde_json::from_value(serde_json::json!({
+            "virtual-size": 10737418240u64,
+            "filename": "unused",
+            "format": format,
+            "actual-size": 1048576,
+            "backing-filename": backing,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_disk_chain() {
+        let base = "/pool/bootc-base-0123456789abcdef.qcow2";
+        let cases: [(&str, Vec<(&str, Option<&str>)>, Vec<(&str, bool, bool)>); 3] = [
+            // A VM disk backed by an existing base disk
+            (
+                "/pool/vm.qcow2",
+                vec![
+                    ("/pool/vm.qcow2", Some("bootc-base-0123456789abcdef.qcow2")),
+                    (base, None),
+                ],
+                vec![("/pool/vm.qcow2", false, false), (base, true, false)],
+            ),
+            // The base disk was deleted
+            (
+                "/pool/vm.qcow2",
+                vec![("/pool/vm.qcow2", Some(base))],
+                vec![("/pool/vm.qcow2", false, false), (base, true, true)],
+            ),
+            // A standalone raw disk
+            (
+                "/images/disk.raw",
+                vec![("/images/disk.raw", None)],
+                vec![("/images/disk.raw", false, false)],
+            ),
+        ];
+        for (top, images, expected) in cases {
+            let images: HashMap<_, _> = images.into_iter().collect();
+            let chain = resolve_disk_chain_with(Utf8Path::new(top), |path| {
+                Ok(images
+                    .get(path.as_str())
+                    .map(|backing| info("qcow2", *backing)))
+            })
+            .unwrap();
+            let chain: Vec<_> = chain
+                .iter()
+                .map(|l| (l.path.as_str(), l.base_disk, l.missing))
+                .collect();
+            assert_eq!(chain, expected, "top: {top}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_disk_chain_cycle() {
+        let r = resolve_disk_chain_with(Utf8Path::new("/pool/a.qcow2"), |path| {
+            let backing = if path.as_str() == "/pool/a.qcow2" {
+                "b.qcow2"
+            } else {
+                "a.qcow2"
+            };
+            Ok(Some(info("qcow2", Some(backing))))
+        });
+        assert!(r.is_err());
+    }
+}
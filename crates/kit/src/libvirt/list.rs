@@ -2,17 +2,28 @@
 //!
 //! This module provides functionality to list libvirt domains that were
 //! created from bootc container images, showing their status and metadata.
+//!
+//! For each domain, the digest of the image its disk was installed from
+//! (`bootc:image-digest`) is compared with the current digest of its source
+//! image, to show whether an update is available.
+
+use std::collections::HashMap;
 
 use clap::Parser;
 use color_eyre::Result;
 use comfy_table::{presets::UTF8_FULL, Table};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 
 use super::OutputFormat;
+use crate::domain_list::PodmanBootcDomain;
+use crate::hostexec::HostCommand;
 
 /// Options for listing libvirt domains
 #[derive(Debug, Parser)]
 pub struct LibvirtListOpts {
     /// Domain name to query (returns only this domain)
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
     pub domain_name: Option<String>,
 
     /// Output format
@@ -26,6 +37,106 @@ pub struct LibvirtListOpts {
     /// Filter domains by label
     #[clap(long)]
     pub label: Option<String>,
+
+    /// Check the registry for updates instead of local container storage
+    #[clap(long)]
+    pub check_registry: bool,
+}
+
+/// Whether a newer image is available for a domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum UpdateStatus {
+    /// The domain runs the current digest of its image
+    UpToDate,
+    /// The image has a different digest than the domain was installed from
+    Available,
+    /// The digest of the domain or of its image is not known
+    Unknown,
+}
+
+impl UpdateStatus {
+    fn new(recorded: Option<&str>, current: Option<&str>) -> Self {
+        match (recorded, current) {
+            (Some(recorded), Some(current)) if recorded == current => Self::UpToDate,
+            (Some(_), Some(_)) => Self::Available,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::UpToDate => "up to date",
+            Self::Available => "available",
+            Self::Unknown => "-",
+        }
+    }
+}
+
+/// A domain as shown in JSON output
+#[derive(Debug, Serialize)]
+struct ListEntry<'a> {
+    #[serde(flatten)]
+    domain: &'a PodmanBootcDomain,
+    update: UpdateStatus,
+}
+
+/// The subset of `skopeo inspect` output we need
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RegistryInspect {
+    digest: String,
+}
+
+/// Looks up the current digests of images, once per image
+#[derive(Debug)]
+struct DigestResolver {
+    check_registry: bool,
+    cache: HashMap<String, Option<String>>,
+}
+
+impl DigestResolver {
+    fn new(check_registry: bool) -> Self {
+        Self {
+            check_registry,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, image: &str) -> Result<String> {
+        // Images built locally have no registry to check
+        if self.check_registry && !image.starts_with("localhost/") {
+            let r: RegistryInspect = HostCommand::new("skopeo")
+                .args(["inspect", "--no-tags", &format!("docker://{image}")])
+                .run_and_parse_json()?;
+            Ok(r.digest)
+        } else {
+            Ok(crate::images::inspect(image)?.digest.to_string())
+        }
+    }
+
+    /// The current digest of an image, if it can be determined
+    fn current_digest(&mut self, image: &str) -> Option<&str> {
+        if !self.cache.contains_key(image) {
+            let digest = match self.lookup(image) {
+                Ok(digest) => Some(digest),
+                Err(e) => {
+                    debug!("Failed to get digest of {image}: {e}");
+                    None
+                }
+            };
+            self.cache.insert(image.to_owned(), digest);
+        }
+        self.cache[image].as_deref()
+    }
+
+    fn update_status(&mut self, domain: &PodmanBootcDomain) -> UpdateStatus {
+        let current = match domain.image.as_deref() {
+            Some(image) => self.current_digest(image),
+            None => None,
+        };
+        UpdateStatus::new(domain.image_digest.as_deref(), current)
+    }
 }
 
 /// Execute the libvirt list command
@@ -67,6 +178,9 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtListOpts)
         domains.retain(|d| d.labels.contains(filter_label));
     }
 
+    let mut resolver = DigestResolver::new(opts.check_registry);
+    let updates: Vec<UpdateStatus> = domains.iter().map(|d| resolver.update_status(d)).collect();
+
     match opts.format {
         OutputFormat::Table => {
             if domains.is_empty() {
@@ -84,9 +198,9 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtListOpts)
 
             let mut table = Table::new();
             table.load_preset(UTF8_FULL);
-            table.set_header(vec!["NAME", "IMAGE", "STATUS", "MEMORY", "SSH"]);
+            table.set_header(vec!["NAME", "IMAGE", "STATUS", "MEMORY", "SSH", "UPDATE"]);
 
-            for domain in &domains {
+            for (domain, update) in domains.iter().zip(&updates) {
                 let image = match &domain.image {
                     Some(img) => {
                         if img.len() > 38 {
@@ -112,6 +226,7 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtListOpts)
                     &domain.status_string(),
                     &memory,
                     &ssh,
+                    update.as_str(),
                 ]);
             }
 
@@ -123,17 +238,22 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtListOpts)
             );
         }
         OutputFormat::Json => {
+            let entries: Vec<ListEntry> = domains
+                .iter()
+                .zip(updates)
+                .map(|(domain, update)| ListEntry { domain, update })
+                .collect();
             // If querying a specific domain, return object directly instead of array
-            if opts.domain_name.is_some() && !domains.is_empty() {
+            if opts.domain_name.is_some() && !entries.is_empty() {
                 println!(
                     "{}",
-                    serde_json::to_string_pretty(&domains[0])
+                    serde_json::to_string_pretty(&entries[0])
                         .with_context(|| "Failed to serialize domain as JSON")?
                 );
             } else {
                 println!(
                     "{}",
-                    serde_json::to_string_pretty(&domains)
+                    serde_json::to_string_pretty(&entries)
                         .with_context(|| "Failed to serialize domains as JSON")?
                 );
             }
@@ -151,3 +271,30 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtListOpts)
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_status() {
+        let cases = [
+            (Some("sha256:a"), Some("sha256:a"), UpdateStatus::UpToDate),
+            (Some("sha256:a"), Some("sha256:b"), UpdateStatus::Available),
+            (None, Some("sha256:b"), UpdateStatus::Unknown),
+            (Some("sha256:a"), None, UpdateStatus::Unknown),
+            (None, None, UpdateStatus::Unknown),
+        ];
+        for (recorded, current, expected) in cases {
+            assert_eq!(
+                UpdateStatus::new(recorded, current),
+                expected,
+                "{recorded:?} {current:?}"
+            );
+        }
+        assert_eq!(
+            serde_json::to_value(UpdateStatus::UpToDate).unwrap(),
+            "up-to-date"
+        );
+    }
+}
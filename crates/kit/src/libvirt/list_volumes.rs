@@ -3,18 +3,18 @@
 //! This module provides functionality to discover and list bootc volumes
 //! with their container image metadata and creation information.
 
+use crate::hostexec::HostCommand;
 use clap::Parser;
 use color_eyre::{eyre::eyre, Result};
 use comfy_table::{presets::UTF8_FULL, Table};
 use serde_json::{json, Value};
-use std::process::Command;
 use tracing::{debug, warn};
 
 /// Configuration options for listing bootc volumes
 #[derive(Debug, Parser)]
 pub struct LibvirtListVolumesOpts {
     /// Libvirt storage pool name to search
-    #[clap(long, default_value = "default")]
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
     pub pool: String,
 
     /// Output format (human-readable or JSON)
@@ -71,7 +71,7 @@ impl BootcVolume {
 
 impl LibvirtListVolumesOpts {
     /// Build a virsh command with optional connection URI
-    fn virsh_command(&self, global_opts: &crate::libvirt::LibvirtOptions) -> Command {
+    fn virsh_command(&self, global_opts: &crate::libvirt::LibvirtOptions) -> HostCommand {
         global_opts.virsh_command()
     }
 
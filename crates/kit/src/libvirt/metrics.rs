@@ -0,0 +1,446 @@
+//! libvirt metrics command - show resource usage of a bootc domain
+//!
+//! This samples `virsh domstats` for CPU time, memory, balloon and block/network
+//! I/O statistics. CPU usage is derived from the CPU time consumed between two
+//! samples, so even a single report takes one interval to gather.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use super::OutputFormat;
+
+/// `VIR_DOMAIN_RUNNING`
+const STATE_RUNNING: u64 = 1;
+/// `VIR_DOMAIN_PAUSED`
+const STATE_PAUSED: u64 = 3;
+
+/// `virsh domstats` with the statistics groups used here; the domains to
+/// sample are appended
+pub(crate) const DOMSTATS_ARGS: &[&str] = &[
+    "domstats",
+    "--state",
+    "--cpu-total",
+    "--balloon",
+    "--vcpu",
+    "--interface",
+    "--block",
+];
+
+/// Options for showing resource usage of a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtMetricsOpts {
+    /// Name of the domain
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub name: String,
+
+    /// Keep printing samples until interrupted
+    #[clap(long, short = 'w')]
+    pub watch: bool,
+
+    /// Seconds between samples; CPU usage is averaged over this interval
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    /// Output format; JSON prints one object per line for each sample
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+/// I/O counters for a block device
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BlockStats {
+    /// Target device name (e.g. vda)
+    pub name: String,
+    /// Bytes read
+    pub rd_bytes: u64,
+    /// Bytes written
+    pub wr_bytes: u64,
+    /// Read requests
+    pub rd_reqs: u64,
+    /// Write requests
+    pub wr_reqs: u64,
+}
+
+/// I/O counters for a network interface
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct NetStats {
+    /// Host-side interface name
+    pub name: String,
+    /// Bytes received
+    pub rx_bytes: u64,
+    /// Bytes transmitted
+    pub tx_bytes: u64,
+    /// Packets received
+    pub rx_pkts: u64,
+    /// Packets transmitted
+    pub tx_pkts: u64,
+}
+
+/// Memory balloon statistics
+///
+/// Everything except the current and maximum size is reported by the guest's
+/// balloon driver, and so may be missing.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BalloonStats {
+    /// Memory currently assigned to the domain
+    pub current_bytes: Option<u64>,
+    /// Maximum memory of the domain
+    pub maximum_bytes: Option<u64>,
+    /// Memory visible to the guest
+    pub available_bytes: Option<u64>,
+    /// Memory the guest could use without swapping
+    pub usable_bytes: Option<u64>,
+    /// Memory unused by the guest
+    pub unused_bytes: Option<u64>,
+}
+
+/// One sample of a domain's resource usage
+#[derive(Debug, Serialize)]
+pub struct DomainMetrics {
+    /// When the sample was taken
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Domain name
+    pub domain: String,
+    /// Total CPU time consumed by the domain in nanoseconds
+    pub cpu_time_ns: Option<u64>,
+    /// CPU usage since the previous sample, in percent of one host CPU
+    pub cpu_percent: Option<f64>,
+    /// Number of active vCPUs
+    pub vcpus: Option<u64>,
+    /// Resident memory of the QEMU process
+    pub memory_rss_bytes: Option<u64>,
+    /// Memory balloon statistics
+    pub balloon: BalloonStats,
+    /// Per block device statistics
+    pub block: Vec<BlockStats>,
+    /// Per network interface statistics
+    pub net: Vec<NetStats>,
+}
+
+/// Split `virsh domstats` output for several domains into the name and
+/// output of each
+pub(crate) fn split_domstats(output: &str) -> Vec<(&str, &str)> {
+    let mut sections = Vec::new();
+    let mut current: Option<(&str, usize)> = None;
+    let mut offset = 0;
+    for line in output.split_inclusive('\n') {
+        let name = line
+            .trim_end()
+            .strip_prefix("Domain: '")
+            .and_then(|l| l.strip_suffix('\''));
+        if let Some(name) = name {
+            if let Some((prev, start)) = current.take() {
+                sections.push((prev, &output[start..offset]));
+            }
+            current = Some((name, offset));
+        }
+        offset += line.len();
+    }
+    if let Some((name, start)) = current {
+        sections.push((name, &output[start..]));
+    }
+    sections
+}
+
+/// Parse the `key=value` lines of `virsh domstats` output for one domain
+fn parse_domstats(output: &str) -> BTreeMap<&str, &str> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .collect()
+}
+
+impl DomainMetrics {
+    /// Build a sample from `virsh domstats` output; CPU usage is left unset
+    pub(crate) fn from_domstats(domain: &str, output: &str) -> Result<Self> {
+        let stats = parse_domstats(output);
+        let get = |key: &str| stats.get(key).and_then(|v| v.parse::<u64>().ok());
+        // Memory statistics are in KiB
+        let kib = |key: &str| get(key).map(|v| v * 1024);
+        let name = |key: &str| stats.get(key).copied().unwrap_or_default().to_owned();
+
+        match get("state.state") {
+            Some(STATE_RUNNING | STATE_PAUSED) => {}
+            Some(_) => return Err(eyre!("Domain '{}' is not running", domain)),
+            None => return Err(eyre!("No statistics found for domain '{}'", domain)),
+        }
+
+        let block = (0..get("block.count").unwrap_or(0))
+            .map(|i| BlockStats {
+                name: name(&format!("block.{i}.name")),
+                rd_bytes: get(&format!("block.{i}.rd.bytes")).unwrap_or(0),
+                wr_bytes: get(&format!("block.{i}.wr.bytes")).unwrap_or(0),
+                rd_reqs: get(&format!("block.{i}.rd.reqs")).unwrap_or(0),
+                wr_reqs: get(&format!("block.{i}.wr.reqs")).unwrap_or(0),
+            })
+            .collect();
+        let net = (0..get("net.count").unwrap_or(0))
+            .map(|i| NetStats {
+                name: name(&format!("net.{i}.name")),
+                rx_bytes: get(&format!("net.{i}.rx.bytes")).unwrap_or(0),
+                tx_bytes: get(&format!("net.{i}.tx.bytes")).unwrap_or(0),
+                rx_pkts: get(&format!("net.{i}.rx.pkts")).unwrap_or(0),
+                tx_pkts: get(&format!("net.{i}.tx.pkts")).unwrap_or(0),
+            })
+            .collect();
+
+        Ok(Self {
+            timestamp: chrono::Utc::now(),
+            domain: domain.to_owned(),
+            cpu_time_ns: get("cpu.time"),
+            cpu_percent: None,
+            vcpus: get("vcpu.current"),
+            memory_rss_bytes: kib("balloon.rss"),
+            balloon: BalloonStats {
+                current_bytes: kib("balloon.current"),
+                maximum_bytes: kib("balloon.maximum"),
+                available_bytes: kib("balloon.available"),
+                usable_bytes: kib("balloon.usable"),
+                unused_bytes: kib("balloon.unused"),
+            },
+            block,
+            net,
+        })
+    }
+}
+
+/// CPU usage in percent of one host CPU, given CPU times in nanoseconds
+pub(crate) fn cpu_percent(prev: Option<u64>, cur: Option<u64>, elapsed: Duration) -> Option<f64> {
+    let used = cur?.checked_sub(prev?)?;
+    let elapsed = elapsed.as_nanos();
+    if elapsed == 0 {
+        return None;
+    }
+    Some(used as f64 * 100.0 / elapsed as f64)
+}
+
+fn sample(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<DomainMetrics> {
+    let output = global_opts
+        .virsh_command()
+        .args(DOMSTATS_ARGS)
+        .arg(name)
+        .output()
+        .with_context(|| "Failed to run virsh domstats")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "Failed to get statistics for domain '{}': {}",
+            name,
+            stderr.trim()
+        ));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| "Invalid UTF-8 in virsh domstats output")?;
+    DomainMetrics::from_domstats(name, &stdout)
+}
+
+fn table_header() -> String {
+    format!(
+        "{:<8} {:>6} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "TIME", "CPU%", "RSS", "MEMORY", "BLK-RD", "BLK-WR", "NET-RX", "NET-TX"
+    )
+}
+
+fn table_row(metrics: &DomainMetrics) -> String {
+    let bytes = |v: Option<u64>| v.map_or_else(|| "-".to_owned(), |v| HumanBytes(v).to_string());
+    let total = |v: u64| bytes(Some(v));
+    format!(
+        "{:<8} {:>6} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        metrics
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S")
+            .to_string(),
+        metrics
+            .cpu_percent
+            .map_or_else(|| "-".to_owned(), |p| format!("{p:.1}")),
+        bytes(metrics.memory_rss_bytes),
+        bytes(metrics.balloon.current_bytes),
+        total(metrics.block.iter().map(|b| b.rd_bytes).sum()),
+        total(metrics.block.iter().map(|b| b.wr_bytes).sum()),
+        total(metrics.net.iter().map(|n| n.rx_bytes).sum()),
+        total(metrics.net.iter().map(|n| n.tx_bytes).sum()),
+    )
+}
+
+/// Execute the libvirt metrics command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtMetricsOpts) -> Result<()> {
+    match opts.format {
+        OutputFormat::Table | OutputFormat::Json => {}
+        OutputFormat::Yaml => {
+            return Err(eyre!("YAML format is not supported for metrics command"))
+        }
+        OutputFormat::Xml => return Err(eyre!("XML format is not supported for metrics command")),
+    }
+
+    let interval = Duration::from_secs(opts.interval);
+    let mut prev = sample(global_opts, &opts.name)?;
+    let mut prev_time = Instant::now();
+    if matches!(opts.format, OutputFormat::Table) {
+        println!("{}", table_header());
+    }
+
+    loop {
+        std::thread::sleep(interval);
+        let mut cur = sample(global_opts, &opts.name)?;
+        let now = Instant::now();
+        cur.cpu_percent = cpu_percent(prev.cpu_time_ns, cur.cpu_time_ns, now - prev_time);
+
+        match opts.format {
+            OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string(&cur).with_context(|| "Failed to serialize metrics")?
+            ),
+            _ => println!("{}", table_row(&cur)),
+        }
+
+        if !opts.watch {
+            return Ok(());
+        }
+        prev = cur;
+        prev_time = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMSTATS: &str = "Domain: 'bootc-test'
+  state.state=1
+  state.reason=1
+  cpu.time=2500000000
+  cpu.user=1500000000
+  cpu.system=1000000000
+  balloon.current=4194304
+  balloon.maximum=4194304
+  balloon.unused=3145728
+  balloon.available=4020000
+  balloon.usable=3500000
+  balloon.rss=1048576
+  vcpu.current=2
+  vcpu.maximum=2
+  net.count=1
+  net.0.name=vnet3
+  net.0.rx.bytes=1000
+  net.0.rx.pkts=10
+  net.0.tx.bytes=2000
+  net.0.tx.pkts=20
+  block.count=2
+  block.0.name=vda
+  block.0.rd.reqs=100
+  block.0.rd.bytes=409600
+  block.0.wr.reqs=50
+  block.0.wr.bytes=204800
+  block.1.name=vdb
+  block.1.rd.bytes=4096
+  block.1.wr.bytes=8192
+
+";
+
+    #[test]
+    fn test_from_domstats() {
+        let m = DomainMetrics::from_domstats("bootc-test", DOMSTATS).unwrap();
+        assert_eq!(m.domain, "bootc-test");
+        assert_eq!(m.cpu_time_ns, Some(2_500_000_000));
+        assert_eq!(m.cpu_percent, None);
+        assert_eq!(m.vcpus, Some(2));
+        assert_eq!(m.memory_rss_bytes, Some(1024 * 1024 * 1024));
+        assert_eq!(
+            m.balloon,
+            BalloonStats {
+                current_bytes: Some(4 * 1024 * 1024 * 1024),
+                maximum_bytes: Some(4 * 1024 * 1024 * 1024),
+                available_bytes: Some(4020000 * 1024),
+                usable_bytes: Some(3500000 * 1024),
+                unused_bytes: Some(3 * 1024 * 1024 * 1024),
+            }
+        );
+        assert_eq!(
+            m.block,
+            [
+                BlockStats {
+                    name: "vda".into(),
+                    rd_bytes: 409600,
+                    wr_bytes: 204800,
+                    rd_reqs: 100,
+                    wr_reqs: 50,
+                },
+                BlockStats {
+                    name: "vdb".into(),
+                    rd_bytes: 4096,
+                    wr_bytes: 8192,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            m.net,
+            [NetStats {
+                name: "vnet3".into(),
+                rx_bytes: 1000,
+                tx_bytes: 2000,
+                rx_pkts: 10,
+                tx_pkts: 20,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_split_domstats() {
+        let output = format!("{DOMSTATS}Domain: 'other'\n  state.state=1\n\n");
+        let sections = split_domstats(&output);
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0], ("bootc-test", DOMSTATS));
+        assert_eq!(
+            sections[1],
+            ("other", "Domain: 'other'\n  state.state=1\n\n")
+        );
+        assert!(split_domstats("").is_empty());
+    }
+
+    #[test]
+    fn test_from_domstats_not_running() {
+        let output = "Domain: 'bootc-test'\n  state.state=5\n  state.reason=1\n";
+        assert!(DomainMetrics::from_domstats("bootc-test", output).is_err());
+        assert!(DomainMetrics::from_domstats("bootc-test", "").is_err());
+
+        // Paused domains still have statistics
+        let output = "Domain: 'bootc-test'\n  state.state=3\n";
+        let m = DomainMetrics::from_domstats("bootc-test", output).unwrap();
+        assert!(m.block.is_empty());
+        assert_eq!(m.memory_rss_bytes, None);
+    }
+
+    #[test]
+    fn test_cpu_percent() {
+        let second = Duration::from_secs(1);
+        let cases = [
+            (Some(0), Some(500_000_000), second, Some(50.0)),
+            (
+                Some(1_000_000_000),
+                Some(3_000_000_000),
+                second,
+                Some(200.0),
+            ),
+            (None, Some(1), second, None),
+            (Some(1), None, second, None),
+            // CPU time going backwards, e.g. after a restart
+            (Some(5), Some(1), second, None),
+            (Some(0), Some(1), Duration::ZERO, None),
+        ];
+        for (prev, cur, elapsed, expected) in cases {
+            assert_eq!(
+                cpu_percent(prev, cur, elapsed),
+                expected,
+                "{prev:?} {cur:?}"
+            );
+        }
+    }
+}
@@ -3,8 +3,10 @@
 //! This module provides a comprehensive libvirt integration with subcommands for:
 //! - `run`: Run a bootable container as a persistent VM
 //! - `list`: List bootc domains with metadata
+//! - `top`: Show the resource usage of all running bootc domains
 //! - `upload`: Upload bootc disk images to libvirt with metadata annotations
 //! - `list-volumes`: List available bootc volumes with metadata
+//! - `export`/`import`: Move a domain between hosts as a bundle
 
 use clap::Subcommand;
 
@@ -24,22 +26,36 @@ pub const LIBVIRT_DEFAULT_MEMORY: &str = "4G";
 /// Default disk size for libvirt base disks
 pub const LIBVIRT_DEFAULT_DISK_SIZE: &str = "20G";
 
+/// Storage pool for disk images, created on first use if missing
+pub const LIBVIRT_DEFAULT_POOL: &str = "default";
+
 pub mod base_disks;
 pub mod base_disks_cli;
-pub mod domain;
+pub mod bundle;
+pub mod dns;
+pub mod import_cloud_image;
+pub use bcvk_core::{domain, domain_metadata};
 pub mod inspect;
 pub mod list;
 pub mod list_volumes;
+pub mod metrics;
+pub mod port_forward;
 pub mod print_firmware;
+pub mod resize_disk;
 pub mod rm;
 pub mod rm_all;
 pub mod run;
+pub mod scp;
 pub mod secureboot;
 pub mod ssh;
+pub mod ssh_keys;
 pub mod start;
 pub mod status;
 pub mod stop;
+pub mod top;
+pub mod update;
 pub mod upload;
+pub mod volume;
 
 /// Global options for libvirt operations
 #[derive(Debug, Clone, Default)]
@@ -49,14 +65,32 @@ pub struct LibvirtOptions {
 }
 
 impl LibvirtOptions {
-    /// Create a virsh Command with the appropriate connection URI
-    pub fn virsh_command(&self) -> std::process::Command {
-        let mut cmd = std::process::Command::new("virsh");
+    /// Create a virsh command with the appropriate connection URI
+    pub fn virsh_command(&self) -> crate::hostexec::HostCommand {
+        let mut cmd = crate::hostexec::HostCommand::new("virsh");
         if let Some(ref uri) = self.connect {
             cmd.arg("-c").arg(uri);
         }
         cmd
     }
+
+    /// Tag `e` with [`crate::Failure::HypervisorUnavailable`] if libvirt
+    /// cannot be connected to, as checked with `virsh uri`
+    pub(crate) fn tag_unavailable(&self, e: color_eyre::Report) -> color_eyre::Report {
+        if crate::Failure::of(&e).is_some() {
+            return e;
+        }
+        let connected = self
+            .virsh_command()
+            .arg("uri")
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if connected {
+            e
+        } else {
+            e.wrap_err(crate::Failure::HypervisorUnavailable)
+        }
+    }
 }
 
 /// Convert a unit string to bytes multiplier
@@ -188,6 +222,13 @@ pub enum LibvirtSubcommands {
     /// SSH to libvirt domain with embedded SSH key
     Ssh(ssh::LibvirtSshOpts),
 
+    /// Copy files to or from a libvirt domain with embedded SSH key
+    Scp(scp::LibvirtScpOpts),
+
+    /// Forward local ports to a libvirt domain over SSH with embedded SSH key
+    #[clap(name = "port-forward")]
+    PortForward(port_forward::LibvirtPortForwardOpts),
+
     /// List bootc domains with metadata
     List(list::LibvirtListOpts),
 
@@ -195,12 +236,22 @@ pub enum LibvirtSubcommands {
     #[clap(name = "list-volumes")]
     ListVolumes(list_volumes::LibvirtListVolumesOpts),
 
-    /// Stop a running libvirt domain
+    /// Create, remove, attach and detach volumes in libvirt storage pools
+    Volume(volume::LibvirtVolumeOpts),
+
+    /// Stop running libvirt domains
     Stop(stop::LibvirtStopOpts),
 
     /// Start a stopped libvirt domain
     Start(start::LibvirtStartOpts),
 
+    /// Grow the disk of a domain, and optionally its root filesystem
+    #[clap(name = "resize-disk")]
+    ResizeDisk(resize_disk::LibvirtResizeDiskOpts),
+
+    /// Update a VM to a new image or digest
+    Update(update::LibvirtUpdateOpts),
+
     /// Remove a libvirt domain and its resources
     #[clap(name = "rm")]
     Remove(rm::LibvirtRmOpts),
@@ -209,9 +260,25 @@ pub enum LibvirtSubcommands {
     #[clap(name = "rm-all")]
     RemoveAll(rm_all::LibvirtRmAllOpts),
 
+    /// Export a stopped domain with its disks, NVRAM and TPM state into a bundle
+    Export(bundle::LibvirtExportOpts),
+
+    /// Recreate a domain from a bundle written by export
+    Import(bundle::LibvirtImportOpts),
+
+    /// Boot a generic cloud image and switch it to a bootc container image
+    #[clap(name = "import-cloud-image")]
+    ImportCloudImage(import_cloud_image::LibvirtImportCloudImageOpts),
+
     /// Show detailed information about a libvirt domain
     Inspect(inspect::LibvirtInspectOpts),
 
+    /// Show resource usage (CPU, memory, block and network I/O) of a libvirt domain
+    Metrics(metrics::LibvirtMetricsOpts),
+
+    /// Show a refreshing table of the resource usage of all running bootc domains
+    Top(top::LibvirtTopOpts),
+
     /// Show libvirt environment status and capabilities
     Status(status::LibvirtStatusOpts),
 
@@ -0,0 +1,190 @@
+//! libvirt port-forward command - reach services in a running bootc domain
+//!
+//! This runs `ssh -N -L` with the SSH key and port stored in the domain
+//! metadata, the same way as `bcvk libvirt ssh`, so that ports of an existing
+//! domain can be forwarded without recreating it with `libvirt run --port`.
+//! The connection is re-established whenever it drops.
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::process::{Command, Stdio};
+
+use camino::Utf8PathBuf;
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+
+/// A port forwarded from the host to the domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortForward {
+    /// Port on the loopback interface of the host
+    pub local: u16,
+    /// Port on the loopback interface of the domain
+    pub remote: u16,
+}
+
+impl std::str::FromStr for PortForward {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (local, remote) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Invalid port forward '{s}'. Expected LOCAL:REMOTE"))?;
+        let port = |p: &str| {
+            p.parse::<u16>()
+                .ok()
+                .filter(|p| *p != 0)
+                .ok_or_else(|| eyre!("Invalid port '{p}' in '{s}'"))
+        };
+        Ok(Self {
+            local: port(local)?,
+            remote: port(remote)?,
+        })
+    }
+}
+
+impl PortForward {
+    /// The `ssh -L` specification
+    fn ssh_spec(&self) -> String {
+        format!("127.0.0.1:{}:localhost:{}", self.local, self.remote)
+    }
+}
+
+/// Options for forwarding ports to a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtPortForwardOpts {
+    /// Name of the libvirt domain
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub domain_name: String,
+
+    /// Ports to forward, as LOCAL:REMOTE
+    #[clap(required = true, value_name = "LOCAL:REMOTE")]
+    pub forwards: Vec<PortForward>,
+
+    /// Keep forwarding in the background, logging to a file in the user's state directory
+    #[clap(long)]
+    pub daemonize: bool,
+
+    /// SSH username to use for connection (defaults to the one given with `libvirt run --ssh-user`, or root)
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Do not check the SSH host key of the domain against the one learned on the first connection
+    #[clap(long)]
+    pub no_strict: bool,
+
+    /// SSH connection timeout in seconds
+    #[clap(long, default_value = "30")]
+    pub timeout: u32,
+
+    /// Extra SSH options in key=value format
+    #[clap(long)]
+    pub extra_options: Vec<String>,
+}
+
+/// Log file of a forwarding to `domain` in the background
+fn log_path(domain: &str) -> Result<Utf8PathBuf> {
+    let state_dir = dirs::state_dir().ok_or_else(|| eyre!("No user state directory"))?;
+    let state_dir = Utf8PathBuf::try_from(state_dir)?;
+    Ok(state_dir
+        .join("bcvk")
+        .join(format!("port-forward-{domain}.log")))
+}
+
+/// Run this command again without `--daemonize`, detached from the terminal
+fn daemonize(domain: &str) -> Result<()> {
+    let log = log_path(domain)?;
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {parent}"))?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log)
+        .with_context(|| format!("Opening {log}"))?;
+
+    let exe = std::env::current_exe().context("Finding the bcvk executable")?;
+    let mut cmd = Command::new(exe);
+    cmd.args(std::env::args_os().skip(1).filter(|a| a != "--daemonize"))
+        .stdin(Stdio::null())
+        .stdout(file.try_clone()?)
+        .stderr(file);
+    // SAFETY: This API is safe to call in a forked child.
+    #[allow(unsafe_code)]
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(&mut cmd, || {
+            rustix::process::setsid().map(drop).map_err(Into::into)
+        });
+    }
+    let child = cmd.spawn().context("Starting port forwarding")?;
+    println!(
+        "Forwarding in the background (PID {}), logging to {log}",
+        child.id()
+    );
+    println!("Stop it with: kill {}", child.id());
+    Ok(())
+}
+
+/// Execute the libvirt port-forward command
+pub fn run(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    opts: LibvirtPortForwardOpts,
+) -> Result<()> {
+    // Checked here, as ssh would otherwise keep failing to reconnect
+    for forward in &opts.forwards {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, forward.local))
+            .with_context(|| format!("Local port {} is not available", forward.local))?;
+    }
+
+    let ssh_opts = super::ssh::LibvirtSshOpts {
+        domain_name: opts.domain_name,
+        user: opts.user,
+        command: vec![],
+        no_strict: opts.no_strict,
+        timeout: opts.timeout,
+        log_level: "ERROR".to_string(),
+        extra_options: opts.extra_options,
+        suppress_output: false,
+        wait: None,
+    };
+    if opts.daemonize {
+        // Fail in the foreground if the domain can't be reached at all
+        super::ssh::run_ssh_output(
+            global_opts,
+            &super::ssh::LibvirtSshOpts {
+                command: vec!["true".to_string()],
+                ..ssh_opts.clone()
+            },
+        )?;
+        return daemonize(&ssh_opts.domain_name);
+    }
+
+    let forwards: Vec<String> = opts.forwards.iter().map(PortForward::ssh_spec).collect();
+    for forward in &opts.forwards {
+        println!(
+            "Forwarding 127.0.0.1:{} to port {} of domain '{}'",
+            forward.local, forward.remote, ssh_opts.domain_name
+        );
+    }
+    super::ssh::run_port_forward(global_opts, &ssh_opts, &forwards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_forward() {
+        let cases = [
+            ("8080:80", Some((8080, 80))),
+            ("5432:5432", Some((5432, 5432))),
+            ("8080", None),
+            ("0:80", None),
+            ("8080:http", None),
+            ("70000:80", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = input.parse::<PortForward>().ok();
+            assert_eq!(parsed.map(|f| (f.local, f.remote)), expected, "{input}");
+        }
+    }
+}
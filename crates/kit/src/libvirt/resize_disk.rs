@@ -0,0 +1,180 @@
+//! libvirt resize-disk command - grow the disk of a bootc domain
+//!
+//! The disk of a running domain is resized by QEMU (`virsh blockresize`),
+//! so the guest sees the new size right away; that of a stopped domain with
+//! `qemu-img resize`. Either way only the VM disk, the top of its backing
+//! chain, changes: the base disk it was cloned from is shared with other
+//! VMs and stays as it is, with the new space being unallocated in the
+//! overlay.
+//!
+//! Growing the partition and filesystem is up to the guest; `--grow-fs`
+//! does it over SSH with `growpart` and the tool of the filesystem.
+
+use camino::Utf8Path;
+use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use tracing::debug;
+
+use crate::domain_list::DomainLister;
+use crate::libvirt::domain_metadata::DomainMetadata;
+
+/// Grows the partition and filesystem holding /var to fill the disk
+///
+/// On bootc systems /sysroot is mounted read-only, while /var is a writable
+/// mount of the same filesystem. growpart exits with 1 if the partition
+/// already fills the disk.
+const GROW_FS_SCRIPT: &str = r#"set -eu
+command -v growpart >/dev/null || { echo "growpart is not installed" >&2; exit 1; }
+src=$(findmnt -nvo SOURCE --target /var)
+fstype=$(findmnt -nvo FSTYPE --target /var)
+part=$(basename "$src")
+disk=/dev/$(lsblk -ndo PKNAME "$src")
+growpart "$disk" "$(cat /sys/class/block/$part/partition)" || [ $? -eq 1 ]
+case "$fstype" in
+    xfs) xfs_growfs /var ;;
+    btrfs) btrfs filesystem resize max /var ;;
+    ext4) resize2fs "$src" ;;
+    *) echo "Unsupported filesystem $fstype" >&2; exit 1 ;;
+esac
+df -h /var
+"#;
+
+/// Options for resizing the disk of a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtResizeDiskOpts {
+    /// Name of the domain whose disk to resize
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub name: String,
+
+    /// New size of the disk (e.g. 40G, 51200M); must be larger than the current size
+    pub size: String,
+
+    /// Grow the root partition and filesystem in the guest over SSH; the VM must be running
+    #[clap(long)]
+    pub grow_fs: bool,
+}
+
+/// Check that `new` grows a disk of `current` bytes
+///
+/// Returns whether the size changes.
+fn check_new_size(current: u64, new: u64) -> Result<bool> {
+    if new < current {
+        return Err(eyre!(
+            "The disk is {}, shrinking it to {} is not supported",
+            HumanBytes(current),
+            HumanBytes(new)
+        ));
+    }
+    Ok(new > current)
+}
+
+/// Grow the root partition and filesystem of a running domain to fill its disk
+pub(super) fn grow_fs(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    println!("Growing the root filesystem of VM '{name}'...");
+    let ssh_opts = super::ssh::LibvirtSshOpts {
+        domain_name: name.to_owned(),
+        user: Some("root".to_owned()),
+        command: vec!["sh".to_owned(), "-c".to_owned(), GROW_FS_SCRIPT.to_owned()],
+        no_strict: false,
+        timeout: 30,
+        log_level: "ERROR".to_string(),
+        extra_options: vec![],
+        suppress_output: false,
+        wait: None,
+    };
+    let output = super::ssh::run_ssh_output(global_opts, &ssh_opts)
+        .context("Failed to grow the root filesystem")?;
+    print!("{}", String::from_utf8_lossy(&output));
+    Ok(())
+}
+
+/// Execute the libvirt resize-disk command
+pub fn run(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    opts: LibvirtResizeDiskOpts,
+) -> Result<()> {
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let name = &opts.name;
+    let domain = lister
+        .get_domain_info(name)
+        .map_err(|_| eyre!("VM '{name}' not found"))?;
+    let dom = lister.get_domain_xml(name)?;
+    let mut metadata = DomainMetadata::from_dom(&dom)?
+        .ok_or_else(|| eyre!("VM '{name}' was not created by bcvk"))?;
+    if dom.find("transient").is_some() {
+        return Err(eyre!(
+            "VM '{name}' has a transient disk, whose changes are discarded when it stops"
+        ));
+    }
+    let running = domain.is_running();
+    if opts.grow_fs && !running {
+        return Err(eyre!(
+            "VM '{name}' must be running to grow its filesystem; start it first"
+        ));
+    }
+    let disk_path = domain
+        .disk_path
+        .as_deref()
+        .map(Utf8Path::new)
+        .ok_or_else(|| eyre!("VM '{name}' has no disk"))?;
+
+    let size = crate::utils::parse_size(&opts.size)?;
+    let current = crate::qemu_img::info(disk_path)?.virtual_size;
+    if !check_new_size(current, size)? {
+        println!(
+            "Disk {disk_path} of VM '{name}' is already {}",
+            HumanBytes(size)
+        );
+    } else {
+        if running {
+            debug!("Resizing {disk_path} of running VM '{name}' to {size} bytes");
+            super::run::run_virsh_cmd(
+                global_opts.connect.as_deref(),
+                &["blockresize", name, disk_path.as_str(), &format!("{size}B")],
+                &format!("Failed to resize disk {disk_path}"),
+            )?;
+        } else {
+            debug!("Resizing {disk_path} of stopped VM '{name}' to {size} bytes");
+            let (dir, file_name) = crate::qemu_img::open_parent(disk_path)?;
+            crate::qemu_img::resize(&dir, file_name, size, false)
+                .with_context(|| format!("Failed to resize disk {disk_path}"))?;
+        }
+        println!(
+            "Disk {disk_path} of VM '{name}' resized from {} to {}",
+            HumanBytes(current),
+            HumanBytes(size)
+        );
+    }
+
+    if opts.grow_fs {
+        if crate::hostexec::dry_run() {
+            crate::hostexec::dry_run_note(format_args!(
+                "grow the root filesystem of '{name}' over SSH"
+            ));
+        } else {
+            grow_fs(global_opts, name)?;
+        }
+    }
+
+    metadata.disk_size = Some(opts.size.clone());
+    super::ssh::store_metadata(global_opts, name, &metadata, "disk size", running)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_new_size() {
+        let gib = 1024 * 1024 * 1024;
+        assert!(check_new_size(20 * gib, 40 * gib).unwrap());
+        assert!(!check_new_size(20 * gib, 20 * gib).unwrap());
+        let err = check_new_size(20 * gib, 10 * gib).unwrap_err().to_string();
+        assert!(err.contains("shrinking"), "{err}");
+    }
+}
@@ -10,6 +10,7 @@ use color_eyre::Result;
 #[derive(Debug, Parser)]
 pub struct LibvirtRmOpts {
     /// Name of the domain to remove
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
     pub name: String,
 
     /// Force removal without confirmation (also stops running VMs)
@@ -31,6 +32,7 @@ fn remove_vm_impl(
     state: &str,
     domain_info: &crate::domain_list::PodmanBootcDomain,
     stop_if_running: bool,
+    keep_disk: bool,
 ) -> Result<()> {
     use color_eyre::eyre::Context;
 
@@ -60,17 +62,48 @@ fn remove_vm_impl(
     }
 
     // Remove disk manually if it exists (unmanaged storage)
-    if let Some(ref disk_path) = domain_info.disk_path {
-        if std::path::Path::new(disk_path).exists() {
+    if let Some(disk_path) = domain_info.disk_path.as_ref().filter(|_| !keep_disk) {
+        if crate::hostexec::dry_run() {
+            crate::hostexec::dry_run_note(format_args!("remove {disk_path}"));
+        } else if std::path::Path::new(disk_path).exists() {
             std::fs::remove_file(disk_path)
                 .with_context(|| format!("Failed to remove disk file: {}", disk_path))?;
         }
+
+        // The backing file of domains created by import-cloud-image
+        let imported = crate::libvirt::import_cloud_image::imported_image_path(
+            camino::Utf8Path::new(disk_path),
+            vm_name,
+        );
+        if imported.exists() && !crate::hostexec::dry_run() {
+            std::fs::remove_file(&imported)
+                .with_context(|| format!("Failed to remove imported cloud image: {imported}"))?;
+        }
     }
 
     // Remove libvirt domain with nvram and storage
+    let mut undefine = vec!["undefine", vm_name, "--nvram"];
+    let other_storage;
+    if keep_disk {
+        let lister = match global_opts.connect.as_ref() {
+            Some(uri) => crate::domain_list::DomainLister::with_connection(uri.clone()),
+            None => crate::domain_list::DomainLister::new(),
+        };
+        let dom = lister.get_domain_xml(vm_name)?;
+        other_storage = disk_sources(&dom)
+            .into_iter()
+            .filter(|source| Some(source) != domain_info.disk_path.as_ref())
+            .collect::<Vec<_>>()
+            .join(",");
+        if !other_storage.is_empty() {
+            undefine.extend(["--storage", other_storage.as_str()]);
+        }
+    } else {
+        undefine.push("--remove-all-storage");
+    }
     let output = global_opts
         .virsh_command()
-        .args(&["undefine", vm_name, "--nvram", "--remove-all-storage"])
+        .args(&undefine)
         .output()
         .with_context(|| "Failed to undefine libvirt domain")?;
 
@@ -82,9 +115,31 @@ fn remove_vm_impl(
         ));
     }
 
+    crate::libvirt::ssh_keys::remove(domain_info.ssh_private_key_file.as_deref());
+    crate::libvirt::dns::unregister(vm_name);
+
+    crate::events::record(crate::events::EventKind::DomainRemoved {
+        name: vm_name.to_string(),
+    });
+
     Ok(())
 }
 
+/// The files backing the disks of a domain
+fn disk_sources(dom: &crate::xml_utils::XmlNode) -> Vec<String> {
+    let Some(devices) = dom.find("devices") else {
+        return Vec::new();
+    };
+    devices
+        .children
+        .iter()
+        .filter(|n| {
+            n.name == "disk" && n.attributes.get("device").map(String::as_str) == Some("disk")
+        })
+        .filter_map(|disk| disk.find("source")?.attributes.get("file").cloned())
+        .collect()
+}
+
 /// Remove a VM without confirmation
 ///
 /// This is the core removal logic that can be reused by other commands.
@@ -93,6 +148,27 @@ pub fn remove_vm_forced(
     global_opts: &crate::libvirt::LibvirtOptions,
     vm_name: &str,
     stop_if_running: bool,
+) -> Result<()> {
+    remove_vm_by_name(global_opts, vm_name, stop_if_running, false)
+}
+
+/// Remove a VM without confirmation, but keep its disk
+///
+/// Used by `libvirt run --replace --keep-disk`. The disk, and for domains
+/// created by import-cloud-image the image backing it, stay in place for
+/// the new domain; additional disks are removed.
+pub fn remove_vm_keep_disk(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    vm_name: &str,
+) -> Result<()> {
+    remove_vm_by_name(global_opts, vm_name, true, true)
+}
+
+fn remove_vm_by_name(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    vm_name: &str,
+    stop_if_running: bool,
+    keep_disk: bool,
 ) -> Result<()> {
     use crate::domain_list::DomainLister;
     use color_eyre::eyre::Context;
@@ -113,7 +189,14 @@ pub fn remove_vm_forced(
         .get_domain_info(vm_name)
         .with_context(|| format!("Failed to get info for VM '{}'", vm_name))?;
 
-    remove_vm_impl(global_opts, vm_name, &state, &domain_info, stop_if_running)
+    remove_vm_impl(
+        global_opts,
+        vm_name,
+        &state,
+        &domain_info,
+        stop_if_running,
+        keep_disk,
+    )
 }
 
 /// Execute the libvirt rm command
@@ -177,8 +260,28 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmOpts) ->
         &state,
         &domain_info,
         opts.stop || opts.force,
+        false,
     )?;
 
     println!("VM '{}' removed successfully", opts.name);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_sources() {
+        let dom = crate::xml_utils::parse_xml_dom(
+            r#"<domain><devices>
+                <disk type="file" device="disk"><source file="/pool/vm.qcow2"/></disk>
+                <disk type="file" device="cdrom"><source file="/tmp/seed.iso"/></disk>
+                <disk type="file" device="disk"><source file="/pool/vm-disk1.raw"/></disk>
+                <interface type="user"/>
+            </devices></domain>"#,
+        )
+        .unwrap();
+        assert_eq!(disk_sources(&dom), ["/pool/vm.qcow2", "/pool/vm-disk1.raw"]);
+    }
+}
@@ -2,9 +2,32 @@
 //!
 //! This module provides functionality to remove multiple libvirt domains
 //! and their associated resources at once, with optional label filtering.
+//!
+//! With `--purge-orphans`, files bcvk created for domains that no longer
+//! exist are removed too: disks and disk snapshots backed by a base disk
+//! left in the storage pools, as well as domain XML files left in the
+//! temporary directory by interrupted runs. Other files named like those of
+//! bcvk domains, such as extra disks and secure boot variables, are only
+//! listed, as nothing shows that bcvk created them.
+
+use std::collections::{BTreeSet, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime};
 
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use color_eyre::Result;
+use indicatif::HumanBytes;
+
+use crate::domain_list::DomainLister;
+use crate::libvirt::domain_metadata::DomainMetadata;
+use crate::xml_utils::XmlNode;
+
+/// Prefix of the temporary domain XML files written when creating domains
+const TEMP_XML_PREFIX: &str = "bcvk-libvirt";
+
+/// Temporary files younger than this may belong to a running command
+const TEMP_MIN_AGE: Duration = Duration::from_secs(60 * 60);
 
 /// Options for removing multiple libvirt domains
 #[derive(Debug, Parser)]
@@ -20,11 +43,260 @@ pub struct LibvirtRmAllOpts {
     /// Filter domains by label (only remove domains with this label)
     #[clap(long)]
     pub label: Option<String>,
+
+    /// Also remove files bcvk created for domains that no longer exist, and report the space reclaimed
+    #[clap(long)]
+    pub purge_orphans: bool,
+}
+
+/// What the defined domains refer to, to tell orphaned files apart
+#[derive(Debug, Default)]
+struct DomainRefs {
+    /// Names of all defined domains, whether created by bcvk or not
+    names: HashSet<String>,
+    /// Files used by any domain, as disk or firmware variables
+    files: HashSet<Utf8PathBuf>,
+    /// Storage pools holding the disks of bcvk domains
+    pools: BTreeSet<String>,
+}
+
+impl DomainRefs {
+    fn collect(lister: &DomainLister) -> Result<Self> {
+        use color_eyre::eyre::Context;
+
+        let mut r = Self::default();
+        r.pools.insert(super::LIBVIRT_DEFAULT_POOL.to_owned());
+        for name in lister.list_all_domains()? {
+            // Unknown references could make files in use look orphaned
+            let dom = lister
+                .get_domain_xml(&name)
+                .context("Not looking for orphaned files")?;
+            r.add(name, &dom);
+        }
+        Ok(r)
+    }
+
+    fn add(&mut self, name: String, dom: &XmlNode) {
+        fn walk(node: &XmlNode, files: &mut HashSet<Utf8PathBuf>) {
+            let file = match node.name.as_str() {
+                "source" => node.attributes.get("file").map(String::as_str),
+                "nvram" | "loader" => Some(node.text_content().trim()),
+                _ => None,
+            };
+            files.extend(file.filter(|f| !f.is_empty()).map(Utf8PathBuf::from));
+            for child in &node.children {
+                walk(child, files);
+            }
+        }
+        walk(dom, &mut self.files);
+        match DomainMetadata::from_dom(dom) {
+            Ok(metadata) => self.pools.extend(metadata.and_then(|m| m.pool)),
+            Err(e) => tracing::warn!(
+                "Failed to parse the metadata of domain '{name}', not scanning its pool: {e:#}"
+            ),
+        }
+        self.names.insert(name);
+    }
+}
+
+/// Kinds of files bcvk creates for a domain in its storage pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PoolFileKind {
+    /// `NAME.qcow2`, the disk cloned from a base disk
+    Disk,
+    /// `NAME-diskN.FORMAT`, an additional disk
+    ExtraDisk,
+    /// `NAME_OVMF_VARS.fd`, the secure boot variables
+    SecureBootVars,
+    /// `NAME.pre-update-TIMESTAMP.qcow2`, the disk before `libvirt update`
+    Snapshot,
+}
+
+/// Match a file name in a storage pool against the names bcvk gives the
+/// files of a domain, returning their kind and the domain name
+fn parse_pool_file_name(file_name: &str) -> Option<(PoolFileKind, &str)> {
+    if super::base_disks::is_base_disk_name(file_name) {
+        return None;
+    }
+    let r = if let Some(name) = file_name.strip_suffix("_OVMF_VARS.fd") {
+        (PoolFileKind::SecureBootVars, name)
+    } else if let Some((name, rest)) = file_name.split_once(".pre-update-") {
+        let timestamp = rest.strip_suffix(".qcow2")?;
+        if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        (PoolFileKind::Snapshot, name)
+    } else {
+        let (stem, ext) = file_name.rsplit_once('.')?;
+        match stem.rsplit_once("-disk") {
+            Some((name, n))
+                if (ext == "qcow2" || ext == "raw")
+                    && !n.is_empty()
+                    && n.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                (PoolFileKind::ExtraDisk, name)
+            }
+            _ if ext == "qcow2" => (PoolFileKind::Disk, stem),
+            _ => return None,
+        }
+    };
+    (!r.1.is_empty()).then_some(r)
+}
+
+/// Whether the qcow2 image at `path` is backed by a bcvk base disk
+fn is_cloned_from_base(path: &Utf8Path) -> bool {
+    let Ok(info) = crate::qemu_img::info(path) else {
+        return false;
+    };
+    info.backing_filename
+        .as_deref()
+        .and_then(|f| Utf8Path::new(f).file_name())
+        .is_some_and(super::base_disks::is_base_disk_name)
+}
+
+/// A file bcvk created that is not used anymore
+#[derive(Debug)]
+struct Orphan {
+    /// The storage pool holding the file, or `None` for a temporary file
+    pool: Option<String>,
+    path: Utf8PathBuf,
+    /// Allocated size in bytes
+    size: u64,
+}
+
+/// The unused files found in storage pools and the temporary directory
+#[derive(Debug, Default)]
+struct Orphans {
+    /// Files bcvk created, which can be removed
+    removable: Vec<Orphan>,
+    /// Files named like those of bcvk domains, which nothing shows bcvk
+    /// created, so they are kept
+    unknown: Vec<Utf8PathBuf>,
+}
+
+impl Orphans {
+    fn print_unknown(&self) {
+        if self.unknown.is_empty() {
+            return;
+        }
+        println!("Files named like those of removed VMs, which may not be bcvk's:");
+        for path in &self.unknown {
+            println!("  - {path} (unknown, not removed)");
+        }
+    }
+}
+
+/// Find the orphaned files in the storage pools of `refs`, and the
+/// temporary domain XML files older than [`TEMP_MIN_AGE`]
+///
+/// Only disks and disk snapshots backed by a base disk are known to be
+/// bcvk's; the other files named like those of bcvk domains are returned
+/// as unknown. The storage pools are read directly, so they are skipped for
+/// a remote hypervisor.
+fn find_orphans(connect_uri: Option<&str>, refs: &DomainRefs) -> Result<Orphans> {
+    let mut orphans = Orphans::default();
+    let no_pools = BTreeSet::new();
+    let pools = if connect_uri.is_some_and(super::ssh::is_remote_uri) {
+        eprintln!(
+            "Warning: Not looking for orphaned files in the storage pools of a remote hypervisor"
+        );
+        &no_pools
+    } else {
+        &refs.pools
+    };
+    for pool in pools {
+        let pool_path = match super::run::get_libvirt_storage_pool_path(connect_uri, pool) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Warning: Skipping storage pool '{pool}': {e:#}");
+                continue;
+            }
+        };
+        let Ok(entries) = pool_path.read_dir_utf8() else {
+            eprintln!("Warning: Cannot read storage pool '{pool}' at {pool_path}");
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Some((kind, name)) = parse_pool_file_name(entry.file_name()) else {
+                continue;
+            };
+            let path = entry.path();
+            if refs.names.contains(name) || refs.files.contains(path) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let from_base = match kind {
+                PoolFileKind::Disk | PoolFileKind::Snapshot => is_cloned_from_base(path),
+                // Created empty, so there is nothing tying them to bcvk
+                PoolFileKind::ExtraDisk | PoolFileKind::SecureBootVars => false,
+            };
+            if from_base {
+                orphans.removable.push(Orphan {
+                    pool: Some(pool.clone()),
+                    path: path.to_owned(),
+                    size: metadata.blocks() * 512,
+                });
+            } else if kind != PoolFileKind::Disk {
+                // Unlike the other names, that of disks is not specific to bcvk
+                orphans.unknown.push(path.to_owned());
+            }
+        }
+    }
+
+    let tmp = Utf8PathBuf::try_from(std::env::temp_dir())?;
+    let now = SystemTime::now();
+    for entry in tmp.read_dir_utf8()?.flatten() {
+        if !entry.file_name().starts_with(TEMP_XML_PREFIX) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let old = metadata
+            .modified()
+            .ok()
+            .and_then(|t| now.duration_since(t).ok())
+            .is_some_and(|age| age > TEMP_MIN_AGE);
+        if metadata.is_file() && old {
+            orphans.removable.push(Orphan {
+                pool: None,
+                path: entry.path().to_owned(),
+                size: metadata.blocks() * 512,
+            });
+        }
+    }
+    Ok(orphans)
+}
+
+/// Remove an orphaned file, through libvirt if it is in a storage pool
+fn remove_orphan(connect_uri: Option<&str>, orphan: &Orphan) -> Result<()> {
+    use color_eyre::eyre::Context;
+
+    match &orphan.pool {
+        Some(pool) => {
+            let name = orphan.path.file_name().unwrap_or(orphan.path.as_str());
+            super::run::run_virsh_cmd(
+                connect_uri,
+                &["vol-delete", "--pool", pool, name],
+                &format!("Failed to delete volume '{name}'"),
+            )
+        }
+        None if crate::hostexec::dry_run() => {
+            crate::hostexec::dry_run_note(format_args!("remove {}", orphan.path));
+            Ok(())
+        }
+        None => std::fs::remove_file(&orphan.path)
+            .with_context(|| format!("Failed to remove {}", orphan.path)),
+    }
 }
 
 /// Execute the libvirt rm-all command
 pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts) -> Result<()> {
-    use crate::domain_list::DomainLister;
     use color_eyre::eyre::Context;
 
     let connect_uri = global_opts.connect.as_ref();
@@ -32,6 +304,11 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
         Some(uri) => DomainLister::with_connection(uri.clone()),
         None => DomainLister::new(),
     };
+    // Collected before removing domains, so their storage pools are scanned
+    let refs = opts
+        .purge_orphans
+        .then(|| DomainRefs::collect(&lister))
+        .transpose()?;
 
     // Get all bootc domains
     let mut domains = lister
@@ -49,16 +326,20 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
         } else {
             println!("No VMs found");
         }
-        return Ok(());
+        if !opts.purge_orphans {
+            return Ok(());
+        }
     }
 
     // Confirmation prompt
     if !opts.force {
-        println!(
-            "This will permanently delete {} VM{} and their data:",
-            domains.len(),
-            if domains.len() == 1 { "" } else { "s" }
-        );
+        if !domains.is_empty() {
+            println!(
+                "This will permanently delete {} VM{} and their data:",
+                domains.len(),
+                if domains.len() == 1 { "" } else { "s" }
+            );
+        }
         for domain in &domains {
             println!("  - {} ({})", domain.name, domain.status_string());
             if let Some(ref image) = domain.image {
@@ -71,6 +352,30 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
                 println!("    Labels: {}", domain.labels.join(", "));
             }
         }
+        if let Some(refs) = &refs {
+            let orphans = find_orphans(connect_uri.map(String::as_str), refs)?;
+            let removable = &orphans.removable;
+            if removable.is_empty() {
+                println!("No orphaned files found");
+            } else {
+                let total: u64 = removable.iter().map(|o| o.size).sum();
+                println!(
+                    "This will delete {} orphaned file{} ({}):",
+                    removable.len(),
+                    if removable.len() == 1 { "" } else { "s" },
+                    HumanBytes(total)
+                );
+                for orphan in removable {
+                    println!("  - {} ({})", orphan.path, HumanBytes(orphan.size));
+                }
+            }
+            orphans.print_unknown();
+            if !domains.is_empty() {
+                println!(
+                    "Unused files of the VMs listed above are also removed once they are gone."
+                );
+            }
+        }
         println!();
         println!("Are you sure? This cannot be undone. Use --force to skip this prompt.");
         return Ok(());
@@ -131,6 +436,8 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
             .with_context(|| format!("Failed to undefine domain '{}'", domain.name))?;
 
         if output.status.success() {
+            crate::libvirt::ssh_keys::remove(domain.ssh_private_key_file.as_deref());
+            crate::libvirt::dns::unregister(&domain.name);
             println!("  VM '{}' removed successfully", domain.name);
             removed_count += 1;
         } else {
@@ -143,6 +450,37 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
         }
     }
 
+    if let Some(refs) = refs {
+        let connect_uri = connect_uri.map(String::as_str);
+        let refs = DomainRefs {
+            pools: refs.pools,
+            ..DomainRefs::collect(&lister)?
+        };
+        let mut purged = 0;
+        let mut reclaimed = 0;
+        let orphans = find_orphans(connect_uri, &refs)?;
+        for orphan in &orphans.removable {
+            match remove_orphan(connect_uri, orphan) {
+                Ok(()) => {
+                    println!("Removed orphaned file {}", orphan.path);
+                    purged += 1;
+                    reclaimed += orphan.size;
+                }
+                Err(e) => {
+                    eprintln!("  {e:#}");
+                    error_count += 1;
+                }
+            }
+        }
+        println!(
+            "Reclaimed {} from {} orphaned file{}",
+            HumanBytes(reclaimed),
+            purged,
+            if purged == 1 { "" } else { "s" }
+        );
+        orphans.print_unknown();
+    }
+
     println!();
     println!(
         "Summary: {} VM{} removed, {} error{}",
@@ -154,7 +492,7 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
 
     if error_count > 0 {
         Err(color_eyre::eyre::eyre!(
-            "Failed to remove {} VM{}",
+            "Failed to remove {} item{}",
             error_count,
             if error_count == 1 { "" } else { "s" }
         ))
@@ -162,3 +500,60 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtRmAllOpts)
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pool_file_name() {
+        use PoolFileKind::*;
+        let cases = [
+            ("vm1.qcow2", Some((Disk, "vm1"))),
+            ("my-vm.qcow2", Some((Disk, "my-vm"))),
+            ("vm1-disk1.qcow2", Some((ExtraDisk, "vm1"))),
+            ("vm1-disk12.raw", Some((ExtraDisk, "vm1"))),
+            ("vm1_OVMF_VARS.fd", Some((SecureBootVars, "vm1"))),
+            ("vm1.pre-update-1760000000.qcow2", Some((Snapshot, "vm1"))),
+            ("vm1-diskx.qcow2", Some((Disk, "vm1-diskx"))),
+            ("vm1-disk1.img", None),
+            ("vm1.raw", None),
+            ("vm1.pre-update-.qcow2", None),
+            ("vm1.pre-update-abc.qcow2", None),
+            ("bootc-base-0123456789abcdef.qcow2", None),
+            (".qcow2", None),
+            ("_OVMF_VARS.fd", None),
+            ("README", None),
+        ];
+        for (file_name, expected) in cases {
+            assert_eq!(parse_pool_file_name(file_name), expected, "{file_name}");
+        }
+    }
+
+    #[test]
+    fn test_domain_refs() {
+        let xml = r#"<domain><name>vm1</name>
+            <metadata><bootc:container xmlns:bootc="https://github.com/containers/bootc">
+              <bootc:storage-pool>fast</bootc:storage-pool></bootc:container></metadata>
+            <os><loader>/usr/share/edk2/ovmf/OVMF_CODE.fd</loader>
+              <nvram>/var/lib/libvirt/images/vm1_OVMF_VARS.fd</nvram></os>
+            <devices><disk type="file"><source file="/fast/vm1.qcow2"/></disk>
+              <disk type="file"><source file="/fast/vm1-disk1.raw"/></disk>
+              <interface type="network"><source network="default"/></interface></devices>
+            </domain>"#;
+        let dom = crate::xml_utils::parse_xml_dom(xml).unwrap();
+        let mut refs = DomainRefs::default();
+        refs.add("vm1".to_owned(), &dom);
+        assert!(refs.names.contains("vm1"));
+        for f in [
+            "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+            "/var/lib/libvirt/images/vm1_OVMF_VARS.fd",
+            "/fast/vm1.qcow2",
+            "/fast/vm1-disk1.raw",
+        ] {
+            assert!(refs.files.contains(Utf8Path::new(f)), "{f}");
+        }
+        assert_eq!(refs.files.len(), 4);
+        assert_eq!(refs.pools, BTreeSet::from(["fast".to_owned()]));
+    }
+}
@@ -8,27 +8,35 @@ use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, ValueEnum};
 use color_eyre::eyre::{self, eyre};
 use color_eyre::{eyre::Context, Result};
+use serde::Serialize;
 use std::fs;
 use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
-use tracing::{debug, info};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::common_opts::MemoryOpts;
+use crate::cleanup::CleanupGuard;
+use crate::common_opts::{expand_karg_profiles, KargProfile, MemoryOpts, ResourceLimits, SwapOpts};
 use crate::domain_list::DomainLister;
-use crate::install_options::InstallOptions;
-use crate::libvirt::domain::VirtiofsFilesystem;
+use crate::host_devices::HostDevice;
+use crate::hostexec::HostCommand;
+use crate::image_pull::PullPolicy;
+use crate::install_options::{InstallOptions, SelinuxMode};
+use crate::libvirt::base_disks::CloneMode;
+use crate::libvirt::domain::{self, AdditionalDisk, Cdrom, NetworkInterface, VirtiofsFilesystem};
+use crate::preflight;
+use crate::qemu_img::ImageFormat;
+use crate::stage_progress::StageProgress;
 use crate::utils::parse_memory_to_mb;
 use crate::xml_utils;
 
-/// SSH wait timeout in seconds
-const SSH_WAIT_TIMEOUT_SECONDS: u64 = 180;
-
 /// Transport type for updating from host container storage
 const UPDATE_FROM_HOST_TRANSPORT: &str = "containers-storage";
 
 /// Create a virsh command with optional connection URI
-pub(super) fn virsh_command(connect_uri: Option<&str>) -> Result<std::process::Command> {
-    let mut cmd = std::process::Command::new("virsh");
+pub(super) fn virsh_command(connect_uri: Option<&str>) -> Result<HostCommand> {
+    let mut cmd = HostCommand::new("virsh");
     if let Some(uri) = connect_uri {
         cmd.arg("-c").arg(uri);
     }
@@ -90,6 +98,98 @@ pub enum FirmwareType {
     Bios,
 }
 
+/// What `libvirt run` waits for before returning
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "kebab-case")]
+pub enum WaitMode {
+    /// The domain accepts SSH connections (default)
+    #[default]
+    Ssh,
+    /// systemd in the domain finished booting
+    Boot,
+    /// Nothing, return once the domain is started
+    None,
+}
+
+impl std::fmt::Display for WaitMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Ssh => "ssh",
+            Self::Boot => "boot",
+            Self::None => "none",
+        };
+        f.write_str(s)
+    }
+}
+
+/// How `libvirt run` reports the created domain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "kebab-case")]
+pub enum RunOutput {
+    /// A human-readable summary (default)
+    #[default]
+    Text,
+    /// Only a JSON object describing the domain on stdout; everything else
+    /// goes to stderr
+    Json,
+}
+
+/// The domain created by `libvirt run`, as printed with `--output json`
+#[derive(Debug, Serialize)]
+struct DomainHandle {
+    name: String,
+    uuid: String,
+    /// Whether the domain was started
+    running: bool,
+    /// Forwarded to port 22 of the domain; reassigned on start if taken
+    ssh_port: u16,
+    disk: Utf8PathBuf,
+    additional_disks: Vec<Utf8PathBuf>,
+    /// Unknown for remote hypervisors, or if libvirt's PID file is not readable
+    qemu_pid: Option<u32>,
+}
+
+/// How the guest memory is backed on the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "kebab-case")]
+pub enum MemoryBacking {
+    /// Shared memory, as required by virtiofs (default)
+    #[default]
+    Shared,
+    /// Shared memory that is also locked into host RAM, so it is never swapped out
+    Locked,
+}
+
+impl From<FirmwareType> for domain::FirmwareType {
+    fn from(firmware: FirmwareType) -> Self {
+        match firmware {
+            FirmwareType::UefiSecure => Self::UefiSecure,
+            FirmwareType::UefiInsecure => Self::UefiInsecure,
+            FirmwareType::Bios => Self::Bios,
+        }
+    }
+}
+
+impl From<MemoryBacking> for domain::MemoryBacking {
+    fn from(backing: MemoryBacking) -> Self {
+        match backing {
+            MemoryBacking::Shared => Self::Shared,
+            MemoryBacking::Locked => Self::Locked,
+        }
+    }
+}
+
+/// Parse a huge page size (e.g. 2M, 1G) to KiB
+fn parse_hugepage_size(size: &str) -> Result<u64> {
+    let bytes = crate::utils::parse_size(size)?;
+    if bytes < 4096 || !bytes.is_power_of_two() {
+        return Err(eyre!(
+            "Invalid huge page size '{size}': expected a power of two like 2M or 1G"
+        ));
+    }
+    Ok(bytes / 1024)
+}
+
 /// Port mapping from host to VM
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PortMapping {
@@ -135,6 +235,108 @@ impl std::fmt::Display for PortMapping {
     }
 }
 
+/// Options of QEMU user-mode (slirp) networking, from `--network user:KEY=VALUE,...`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserNetwork {
+    /// Guest network, as ADDR/PREFIX
+    pub net: Option<(Ipv4Addr, u8)>,
+    /// Address of the host (gateway) as seen from the guest
+    pub host: Option<Ipv4Addr>,
+    /// Address of the built-in DNS server
+    pub dns: Option<Ipv4Addr>,
+    /// Hostname handed to the guest via DHCP
+    pub hostname: Option<String>,
+}
+
+impl UserNetwork {
+    /// Parse the value of `--network`; returns `None` for modes other than `user`
+    pub fn from_network(network: &str) -> Result<Option<Self>> {
+        match network.split_once(':') {
+            None if network == "user" => Ok(Some(Self::default())),
+            Some(("user", opts)) => opts.parse().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Options to append to `-netdev user,...`
+    pub fn netdev_options(&self) -> Vec<String> {
+        let mut r = Vec::new();
+        if let Some((addr, prefix)) = self.net {
+            r.push(format!("net={addr}/{prefix}"));
+        }
+        if let Some(host) = self.host {
+            r.push(format!("host={host}"));
+        }
+        if let Some(dns) = self.dns {
+            r.push(format!("dns={dns}"));
+        }
+        if let Some(hostname) = &self.hostname {
+            r.push(format!("hostname={hostname}"));
+        }
+        r
+    }
+}
+
+impl FromStr for UserNetwork {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_addr = |key: &str, value: &str| {
+            value
+                .parse::<Ipv4Addr>()
+                .map_err(|_| eyre!("Invalid {key} address '{value}'"))
+        };
+        let mut r = Self::default();
+        for opt in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or_else(|| eyre!("Invalid network option '{opt}'. Expected KEY=VALUE"))?;
+            match key {
+                "net" => {
+                    let (addr, prefix) = value.split_once('/').ok_or_else(|| {
+                        eyre!("Invalid net '{value}'. Expected ADDR/PREFIX, e.g. 10.0.5.0/24")
+                    })?;
+                    let prefix = prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|p| *p <= 32)
+                        .ok_or_else(|| eyre!("Invalid prefix length '{prefix}'"))?;
+                    r.net = Some((parse_addr(key, addr)?, prefix));
+                }
+                "host" => r.host = Some(parse_addr(key, value)?),
+                "dns" => r.dns = Some(parse_addr(key, value)?),
+                "hostname" => {
+                    if value.is_empty()
+                        || !value
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                    {
+                        return Err(eyre!("Invalid hostname '{value}'"));
+                    }
+                    r.hostname = Some(value.to_owned());
+                }
+                _ => {
+                    return Err(eyre!(
+                        "Unknown network option '{key}'. Supported: net, host, dns, hostname"
+                    ))
+                }
+            }
+        }
+        // QEMU requires both addresses to be inside the guest network
+        if let Some((net, prefix)) = r.net {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            for (key, addr) in [("host", r.host), ("dns", r.dns)] {
+                if let Some(addr) = addr {
+                    if u32::from(addr) & mask != u32::from(net) & mask {
+                        return Err(eyre!("The {key} address {addr} is not in {net}/{prefix}"));
+                    }
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
 /// Bind mount from host to VM
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BindMount {
@@ -201,20 +403,193 @@ impl BindMount {
     }
 }
 
+/// Extra blank disk to create and attach to the VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskSpec {
+    /// Virtual size in bytes
+    pub size: u64,
+    /// Disk image format
+    pub format: ImageFormat,
+    /// Serial number exposed to the guest
+    pub serial: Option<String>,
+    /// Host cache and I/O settings
+    pub io: crate::qemu::DiskIoConfig,
+}
+
+impl FromStr for DiskSpec {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut size = None;
+        let mut format = ImageFormat::Qcow2;
+        let mut serial = None;
+        let mut io = crate::qemu::DiskIoConfig::default();
+
+        for part in s.split(',') {
+            let (key, value) = part.split_once('=').ok_or_else(|| {
+                eyre!(
+                    "Invalid disk option '{}'. Expected format: size=SIZE[,format=qcow2|raw][,serial=SERIAL][,cache=MODE][,aio=MODE]",
+                    part
+                )
+            })?;
+            match key.trim() {
+                "size" => size = Some(crate::utils::parse_size(value)?),
+                "format" => {
+                    format = match value.trim() {
+                        "qcow2" => ImageFormat::Qcow2,
+                        "raw" => ImageFormat::Raw,
+                        o => return Err(eyre!("Unsupported disk format '{}'", o)),
+                    }
+                }
+                "serial" => serial = Some(value.trim().to_string()),
+                "cache" | "aio" => {
+                    let opt: crate::qemu::DiskIoConfig = part.trim().parse()?;
+                    io.cache = opt.cache.or(io.cache);
+                    io.aio = opt.aio.or(io.aio);
+                }
+                o => return Err(eyre!("Unknown disk option '{}'", o)),
+            }
+        }
+
+        let size = size.ok_or_else(|| eyre!("Disk specification '{}' is missing size=", s))?;
+        if size == 0 {
+            return Err(eyre!("Disk size must be greater than zero"));
+        }
+
+        Ok(DiskSpec {
+            size,
+            format,
+            serial,
+            io,
+        })
+    }
+}
+
+/// Volume mount from host to VM
+///
+/// The preferred form is `host_path:guest_path[:ro]`, which is mounted
+/// automatically in the guest via a systemd mount unit. The legacy
+/// `host_path:tag` form only exposes a raw virtiofs tag and is deprecated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VolumeMount {
+    /// Automatically mounted at a guest path
+    Mount { bind: BindMount, readonly: bool },
+    /// Raw virtiofs tag that must be mounted manually in the guest (deprecated)
+    Tag { host_path: String, tag: String },
+}
+
+impl FromStr for VolumeMount {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host_part, rest) = s.split_once(':').ok_or_else(|| {
+            color_eyre::eyre::eyre!(
+                "Invalid volume format '{}'. Expected format: host_path:guest_path[:ro]",
+                s
+            )
+        })?;
+
+        let host_path = host_part.trim();
+        let rest = rest.trim();
+
+        if host_path.is_empty() || rest.is_empty() {
+            return Err(color_eyre::eyre::eyre!(
+                "Invalid volume format '{}'. Both host path and guest path must be non-empty",
+                s
+            ));
+        }
+
+        // Anything not starting with a slash is a legacy virtiofs tag
+        if !rest.starts_with('/') {
+            return Ok(VolumeMount::Tag {
+                host_path: host_path.to_string(),
+                tag: rest.to_string(),
+            });
+        }
+
+        let (guest_path, readonly) = if let Some(p) = rest.strip_suffix(":ro") {
+            (p, true)
+        } else if let Some(p) = rest.strip_suffix(":rw") {
+            (p, false)
+        } else {
+            (rest, false)
+        };
+
+        Ok(VolumeMount::Mount {
+            bind: BindMount {
+                host_path: host_path.to_string(),
+                guest_path: guest_path.to_string(),
+            },
+            readonly,
+        })
+    }
+}
+
+impl VolumeMount {
+    /// Path on the host backing this volume
+    fn host_path(&self) -> &str {
+        match self {
+            VolumeMount::Mount { bind, .. } => &bind.host_path,
+            VolumeMount::Tag { host_path, .. } => host_path,
+        }
+    }
+
+    /// Validate that the volume paths are valid
+    fn validate(&self) -> Result<()> {
+        match self {
+            VolumeMount::Mount { bind, .. } => bind.validate(),
+            VolumeMount::Tag { host_path, .. } => {
+                let host_path_buf = std::path::Path::new(host_path);
+                if !host_path_buf.exists() {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Host path '{}' does not exist",
+                        host_path
+                    ));
+                }
+                if !host_path_buf.is_dir() {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Host path '{}' is not a directory",
+                        host_path
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Options for creating and running a bootable container VM
 #[derive(Debug, Parser)]
 pub struct LibvirtRunOpts {
     /// Container image to run as a bootable VM
-    pub image: String,
+    ///
+    /// With --disk-image this is optional and only recorded as the VM's source image.
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub image: Option<String>,
+
+    /// Boot an existing qcow2 or raw disk image instead of installing IMAGE
+    ///
+    /// The image is used as the read-only backing file of the VM's disk, so it
+    /// must not be modified while the VM exists.
+    #[clap(
+        long,
+        conflicts_with_all = ["disk_size", "filesystem", "root_size", "target_transport", "karg", "karg_profiles", "selinux", "composefs_backend", "update_from_host"]
+    )]
+    pub disk_image: Option<Utf8PathBuf>,
 
     /// Name for the VM (auto-generated if not specified)
     #[clap(long)]
     pub name: Option<String>,
 
-    /// Replace existing VM with same name (stop and remove if exists)
+    /// Replace existing VM with same name (shut down and remove if exists)
     #[clap(long, short = 'R')]
     pub replace: bool,
 
+    /// With --replace, boot the disk of the replaced VM instead of a new one
+    /// installed from IMAGE, keeping its state; only the VM configuration changes
+    #[clap(long, requires = "replace", conflicts_with_all = ["disk_image", "transient"])]
+    pub keep_disk: bool,
+
     #[clap(
         long,
         help = "Instance type (e.g., u1.nano, u1.small, u1.medium). Overrides cpus/memory if specified."
@@ -228,6 +603,36 @@ pub struct LibvirtRunOpts {
     #[clap(long, default_value = "2")]
     pub cpus: u32,
 
+    #[clap(flatten)]
+    pub resources: ResourceLimits,
+
+    #[clap(flatten)]
+    pub swap: SwapOpts,
+
+    /// Pull the image before installing it: always, if it is missing, or never
+    #[clap(long, value_enum, default_value_t = PullPolicy::Never)]
+    pub pull: PullPolicy,
+
+    /// Pass a host device through: a PCI device bound to vfio-pci, or a GPU
+    /// render node for virtio-gpu with OpenGL (can be specified multiple times)
+    #[clap(long = "device", value_name = "vfio:PCI_ADDRESS|/dev/dri[/renderDN]")]
+    pub devices: Vec<HostDevice>,
+
+    /// Back the guest memory with huge pages, of the given size (e.g. 2M, 1G) or
+    /// the host default; the host must have enough huge pages reserved
+    #[clap(
+        long,
+        value_name = "SIZE",
+        num_args = 0..=1,
+        require_equals = true,
+        value_parser = parse_hugepage_size
+    )]
+    pub hugepages: Option<Option<u64>>,
+
+    /// How the guest memory is backed on the host; locked memory is never swapped out
+    #[clap(long, value_name = "BACKING", default_value = "shared")]
+    pub memory_backing: MemoryBacking,
+
     /// Disk size for the VM (e.g. 20G, 10240M, or plain number for bytes)
     #[clap(long, default_value = "20G")]
     pub disk_size: String,
@@ -236,13 +641,49 @@ pub struct LibvirtRunOpts {
     #[clap(flatten)]
     pub install: InstallOptions,
 
+    /// Checks the image must pass before it is installed
+    #[clap(flatten)]
+    pub policy: crate::image_policy::ImagePolicyOpts,
+
+    /// Install the disk with the kernel arguments of a named profile (repeatable); --karg arguments come after them
+    #[clap(long = "karg-profile", value_enum, value_name = "PROFILE")]
+    pub karg_profiles: Vec<KargProfile>,
+
+    /// Relabel /etc and /var for SELinux on the first boot, e.g. when the disk was used with SELinux disabled
+    #[clap(long)]
+    pub selinux_relabel: bool,
+
+    /// Libvirt storage pool for the disks of the VM; pools other than the default one must already exist
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+
+    /// How the VM's disk is cloned from the cached base disk; reflink and full clones don't depend on the base disk
+    #[clap(long, value_enum, default_value_t = CloneMode::Backing, conflicts_with_all = ["disk_image", "keep_disk", "transient"])]
+    pub clone_mode: CloneMode,
+
+    /// Additional blank disk to attach (format: size=10G[,format=qcow2][,serial=data][,cache=none|writeback|unsafe][,aio=io_uring|threads])
+    #[clap(long = "disk", action = clap::ArgAction::Append, conflicts_with = "transient")]
+    pub disks: Vec<DiskSpec>,
+
+    /// ISO image to attach as a read-only cdrom, e.g. a driver disk or an installer to test (can be specified multiple times)
+    #[clap(long = "cdrom", value_name = "ISO")]
+    pub cdroms: Vec<Utf8PathBuf>,
+
+    /// Boot from the first --cdrom instead of the disk; the disk comes next in the boot order
+    #[clap(long, requires = "cdroms")]
+    pub boot_cdrom: bool,
+
+    /// Host cache and I/O settings of the root disk; QEMU's defaults apply to unset ones
+    #[clap(long, value_name = "cache=none|writeback|unsafe,aio=io_uring|threads")]
+    pub disk_io: Option<crate::qemu::DiskIoConfig>,
+
     /// Port mapping from host to VM (format: host_port:guest_port, e.g., 8080:80)
     #[clap(long = "port", short = 'p', action = clap::ArgAction::Append)]
     pub port_mappings: Vec<PortMapping>,
 
-    /// Volume mount from host to VM (raw virtiofs tag, for manual mounting)
+    /// Volume mount from host to VM (format: host_path:guest_path[:ro]; the host_path:tag form is deprecated)
     #[clap(long = "volume", short = 'v', action = clap::ArgAction::Append)]
-    pub raw_volumes: Vec<String>,
+    pub volumes: Vec<VolumeMount>,
 
     /// Bind mount from host to VM (format: host_path:guest_path)
     #[clap(long = "bind", action = clap::ArgAction::Append)]
@@ -252,24 +693,41 @@ pub struct LibvirtRunOpts {
     #[clap(long = "bind-ro", action = clap::ArgAction::Append)]
     pub bind_mounts_ro: Vec<BindMount>,
 
-    /// Network mode for the VM
+    /// Network mode for the VM; user-mode networking takes options as user:net=CIDR,host=IP,dns=IP,hostname=NAME
     #[clap(long, default_value = "user")]
     pub network: String,
 
-    /// Keep the VM running in background after creation
+    /// Make the VM resolvable on the host as NAME.bcvk.local, via a block of /etc/hosts managed by bcvk
     #[clap(long)]
+    pub register_dns: bool,
+
+    /// Return once the VM is created, leaving it running (the default unless --ssh is given)
+    #[clap(long, conflicts_with = "ssh")]
     pub detach: bool,
 
+    /// How to report the created VM; with json, the other output goes to stderr
+    #[clap(long, value_enum, default_value_t = RunOutput::Text, conflicts_with = "ssh")]
+    pub output: RunOutput,
+
     /// Automatically SSH into the VM after creation
     #[clap(long)]
     pub ssh: bool,
 
-    /// Wait for SSH to become available and verify connectivity (for testing)
-    #[clap(long, conflicts_with = "ssh")]
+    /// Also create this user for SSH, with sudo rights; `bcvk libvirt ssh` connects as it by default
+    #[clap(long, value_name = "NAME[:UID]")]
+    pub ssh_user: Option<crate::credentials::SshUser>,
+
+    /// What to wait for before returning; a line with the SSH port and the time it took is printed once ready
+    #[clap(long, value_enum, default_value_t = WaitMode::Ssh)]
+    pub wait: WaitMode,
+
+    /// Deprecated: waiting for SSH is the default, see --wait
+    #[clap(long, hide = true, conflicts_with = "wait")]
     pub ssh_wait: bool,
 
-    /// Mount host container storage (RO) at /run/host-container-storage
-    #[clap(long = "bind-storage-ro")]
+    /// Mount host container storage (RO) at /run/host-container-storage,
+    /// and use it as an additional image store in the VM
+    #[clap(long = "bind-storage-ro", visible_alias = "share-host-images")]
     pub bind_storage_ro: bool,
 
     /// Implies --bind-storage-ro, but also configure to update from the host
@@ -285,10 +743,27 @@ pub struct LibvirtRunOpts {
     #[clap(long)]
     pub disable_tpm: bool,
 
+    /// Guest real-time clock; clock=vm only advances it while the guest runs
+    #[clap(long, value_name = "base=utc|localtime,clock=host|vm")]
+    pub rtc: Option<crate::qemu::RtcConfig>,
+
+    /// Start the guest clock at this time (e.g. 2030-01-01T00:00:00Z or @SECONDS), advancing only while the guest runs
+    #[clap(
+        long,
+        value_name = "TIMESTAMP",
+        value_parser = crate::qemu::parse_rtc_timestamp,
+        conflicts_with = "rtc"
+    )]
+    pub freeze_time: Option<i64>,
+
     /// Directory containing secure boot keys (required for uefi-secure)
     #[clap(long)]
     pub secure_boot_keys: Option<Utf8PathBuf>,
 
+    /// Ignition config to provide to the guest via QEMU fw_cfg, for images provisioned with Ignition
+    #[clap(long, value_name = "FILE")]
+    pub ignition: Option<Utf8PathBuf>,
+
     /// User-defined labels for organizing VMs (comma not allowed in labels)
     #[clap(long)]
     pub label: Vec<String>,
@@ -297,6 +772,18 @@ pub struct LibvirtRunOpts {
     #[clap(long)]
     pub transient: bool,
 
+    /// Embed the SSH private key in the domain XML, where anyone with access to libvirt can read it, rather than in a file of the current user
+    #[clap(long)]
+    pub insecure_embed_key: bool,
+
+    /// Define the domain and create its disks, but don't start it; start it later with `bcvk libvirt start` or any libvirt tool
+    #[clap(long, conflicts_with_all = ["transient", "ssh", "wait", "ssh_wait"])]
+    pub no_start: bool,
+
+    /// Create the VM even if it requests more memory than the host has, and without warning about overcommitting it
+    #[clap(long)]
+    pub force: bool,
+
     /// Additional metadata key-value pairs (used internally, not exposed via CLI)
     #[clap(skip)]
     pub metadata: std::collections::HashMap<String, String>,
@@ -304,6 +791,14 @@ pub struct LibvirtRunOpts {
     /// Additional SMBIOS credentials to inject (used internally, not exposed via CLI)
     #[clap(skip)]
     pub extra_smbios_credentials: Vec<String>,
+
+    /// Network interfaces on libvirt networks to attach in addition to the user-mode network (used internally, not exposed via CLI)
+    #[clap(skip)]
+    pub interfaces: Vec<NetworkInterface>,
+
+    /// Address to register with --register-dns instead of the loopback address (used internally, not exposed via CLI)
+    #[clap(skip)]
+    pub dns_address: Option<IpAddr>,
 }
 
 impl LibvirtRunOpts {
@@ -325,7 +820,19 @@ impl LibvirtRunOpts {
         if let Some(itype) = self.itype {
             Ok(itype.memory_mb())
         } else {
-            parse_memory_to_mb(&self.memory.memory)
+            parse_memory_to_mb(self.memory.memory())
+        }
+    }
+
+    /// The container image, or the disk image when booting one directly
+    ///
+    /// Used to name the VM and recorded as its source image.
+    fn source_name(&self) -> &str {
+        match (&self.image, &self.disk_image) {
+            (Some(image), _) => image,
+            (None, Some(disk_image)) => disk_image.as_str(),
+            // Checked in run()
+            (None, None) => unreachable!("either an image or --disk-image is required"),
         }
     }
 
@@ -339,68 +846,86 @@ impl LibvirtRunOpts {
     }
 }
 
-/// Wait for SSH to become available on a libvirt domain
-///
-/// Polls SSH connectivity by attempting simple commands until successful or timeout.
-fn wait_for_ssh_ready(
-    global_opts: &crate::libvirt::LibvirtOptions,
-    domain_name: &str,
-    timeout_secs: u64,
-) -> Result<()> {
-    use std::time::Duration;
-
-    debug!(
-        "Waiting for SSH to become available on domain '{}' (timeout: {}s)",
-        domain_name, timeout_secs
-    );
+/// Execute the libvirt run command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpts) -> Result<()> {
+    // The progress and summary go to stderr, so that stdout only carries the JSON
+    let mut json_out = match opts.output {
+        RunOutput::Json => {
+            let stdout = std::io::stdout();
+            stdout.lock().flush()?;
+            let out = fs::File::from(rustix::io::dup(&stdout)?);
+            rustix::stdio::dup2_stdout(std::io::stderr())
+                .context("Redirecting stdout to stderr")?;
+            Some(out)
+        }
+        RunOutput::Text => None,
+    };
+    // Validate labels don't contain commas
+    opts.validate_labels()?;
+    // Checked before installing; applied when creating the domain
+    UserNetwork::from_network(&opts.network)
+        .with_context(|| format!("Invalid --network '{}'", opts.network))?;
+    if let Some(path) = opts.ignition.as_deref() {
+        crate::utils::read_ignition_config(path)?;
+    }
 
-    // Create progress bar
-    let pb = crate::boot_progress::create_boot_progress_bar();
-    pb.set_message("Waiting for SSH to become available...");
-
-    // Clone values for closure
-    let global_opts_clone = global_opts.clone();
-    let domain_name_clone = domain_name.to_string();
-
-    // Use shared polling function with libvirt-specific test
-    let (_elapsed, pb) = crate::utils::wait_for_readiness(
-        pb,
-        "Waiting for SSH",
-        || {
-            // Create a test SSH connection with short timeout
-            let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
-                domain_name: domain_name_clone.clone(),
-                user: "root".to_string(),
-                command: vec!["true".to_string()], // Simple command to test connectivity
-                strict_host_keys: false,
-                timeout: 5, // Short timeout for each attempt
-                log_level: "ERROR".to_string(),
-                extra_options: vec![],
-                suppress_output: true, // Suppress error messages during connectivity testing
-            };
+    // Not enforced by clap, so a configuration profile can provide the image
+    if opts.image.is_none() && opts.disk_image.is_none() {
+        return Err(eyre!(
+            "A container image is required unless --disk-image is given"
+        ));
+    }
 
-            // Try to connect
-            match crate::libvirt::ssh::run_ssh_impl(&global_opts_clone, ssh_opts) {
-                Ok(_) => Ok(true),
-                Err(_) => Ok(false),
-            }
-        },
-        Duration::from_secs(timeout_secs),
-        Duration::from_secs(2), // Poll every 2 seconds
-    )?;
+    // Only an installed image is checked, not what a disk image contains
+    if (opts.disk_image.is_some() || opts.keep_disk) && opts.policy.is_active() {
+        return Err(eyre!(
+            "--verify-signature and --require-label only apply when installing an image, not with --disk-image or --keep-disk"
+        ));
+    }
 
-    pb.finish_and_clear();
-    Ok(())
-}
+    // The disk image is referenced from the domain, so it must be absolute
+    if let Some(disk_image) = opts.disk_image.as_mut() {
+        *disk_image = disk_image
+            .canonicalize_utf8()
+            .with_context(|| format!("Failed to find disk image {}", disk_image))?;
+    }
+    for cdrom in opts.cdroms.iter_mut() {
+        *cdrom = cdrom
+            .canonicalize_utf8()
+            .with_context(|| format!("Failed to find cdrom image {}", cdrom))?;
+    }
 
-/// Execute the libvirt run command
-pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpts) -> Result<()> {
-    use crate::images;
+    opts.swap.size()?;
+    // libvirt grants the device nodes to QEMU itself
+    for device in opts.devices.iter_mut() {
+        device.check()?;
+    }
+    if opts
+        .devices
+        .iter()
+        .filter(|d| matches!(d, HostDevice::RenderNode(_)))
+        .count()
+        > 1
+    {
+        return Err(eyre!("Only one render node can be passed through"));
+    }
 
-    // Validate labels don't contain commas
-    opts.validate_labels()?;
+    for volume in &opts.volumes {
+        volume
+            .validate()
+            .with_context(|| format!("Failed to validate volume '{}'", volume.host_path()))?;
+        if let VolumeMount::Tag { host_path, tag } = volume {
+            eprintln!(
+                "Warning: '--volume {host_path}:{tag}' uses the deprecated tag-only form. \
+                 Use '--volume {host_path}:/guest/path' to mount automatically."
+            );
+        }
+    }
 
     let connect_uri = global_opts.connect.as_deref();
+    if !opts.force {
+        check_host_resources(connect_uri, &opts)?;
+    }
     let lister = match global_opts.connect.as_ref() {
         Some(uri) => DomainLister::with_connection(uri.clone()),
         None => DomainLister::new(),
@@ -410,18 +935,14 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpt
         .with_context(|| "Failed to list existing domains")?;
 
     // Generate or validate VM name
+    let mut kept_disk = None;
     let vm_name = match &opts.name {
         Some(name) => {
             if existing_domains.contains(name) {
                 if opts.replace {
-                    // Replace mode: remove the existing VM
                     println!("Replacing existing VM '{}'...", name);
-                    crate::libvirt::rm::remove_vm_forced(
-                        global_opts,
-                        name,
-                        true, // stop if running
-                    )
-                    .with_context(|| format!("Failed to remove existing VM '{}'", name))?;
+                    kept_disk = replace_domain(global_opts, &lister, name, &opts)
+                        .with_context(|| format!("Failed to remove existing VM '{}'", name))?;
                 } else {
                     return Err(color_eyre::eyre::eyre!(
                         "VM '{}' already exists. Use --replace to replace it.",
@@ -431,16 +952,487 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpt
             }
             name.clone()
         }
-        None => generate_unique_vm_name(&opts.image, &existing_domains),
+        None => generate_unique_vm_name(opts.source_name(), &existing_domains),
     };
+    if opts.register_dns {
+        if global_opts
+            .connect
+            .as_deref()
+            .is_some_and(crate::libvirt::ssh::is_remote_uri)
+        {
+            return Err(eyre!(
+                "--register-dns is not supported with a remote hypervisor"
+            ));
+        }
+        crate::libvirt::dns::check_writable(&vm_name)?;
+    }
+
+    let wait = if opts.ssh_wait {
+        WaitMode::Ssh
+    } else {
+        opts.wait
+    };
+    let wait = if opts.no_start { WaitMode::None } else { wait };
+    let kept = kept_disk.is_some();
+    let disk_stages = if opts.disk_image.is_some() || kept {
+        1
+    } else {
+        3
+    };
+    let mut stages = StageProgress::new(disk_stages + 1 + usize::from(wait != WaitMode::None));
+
+    let (disk_path, disk_format, image_digest) =
+        if let Some(disk_image) = opts.disk_image.as_deref() {
+            println!(
+                "Creating libvirt domain '{}' (disk image: {})",
+                vm_name, disk_image
+            );
+            stages.begin("Preparing disk image");
+            let (disk_path, disk_format) = prepare_disk_image(
+                disk_image,
+                &vm_name,
+                opts.transient,
+                connect_uri,
+                &opts.pool,
+            )?;
+            (disk_path, disk_format, None)
+        } else if let Some((disk_path, image_digest)) = kept_disk {
+            println!(
+                "Creating libvirt domain '{}' (kept disk: {})",
+                vm_name, disk_path
+            );
+            stages.skip("Preparing disk", "keeping the disk of the replaced VM");
+            // Disks created by bcvk are always qcow2
+            (disk_path, ImageFormat::Qcow2, image_digest)
+        } else {
+            let (disk_path, image_digest) =
+                prepare_installed_disk(&mut opts, &vm_name, connect_uri, &mut stages)?;
+            // Base disks and their clones are always qcow2
+            (disk_path, ImageFormat::Qcow2, Some(image_digest))
+        };
+
+    // Disks created for the VM are removed again unless the domain is created
+    let guard_disks = !crate::hostexec::dry_run();
+    let mut disk_guards = Vec::new();
+    if guard_disks && !kept && opts.disk_image.as_deref() != Some(disk_path.as_path()) {
+        disk_guards.push(CleanupGuard::remove_path(&disk_path));
+    }
+
+    let additional_disks = create_additional_disks(&vm_name, &opts.disks, connect_uri, &opts.pool)
+        .with_context(|| "Failed to create additional disks")?;
+    if guard_disks {
+        disk_guards.extend(additional_disks.iter().map(CleanupGuard::remove_path));
+    }
+
+    // Phase 3: Create libvirt domain
+    stages.begin(if opts.no_start {
+        "Defining libvirt domain"
+    } else {
+        "Creating and starting libvirt domain"
+    });
+
+    // Create the domain directly (simpler than using libvirt/create for files)
+    let ssh_port = create_libvirt_domain_from_disk(
+        &vm_name,
+        &disk_path,
+        disk_format,
+        &additional_disks,
+        image_digest.as_deref(),
+        &opts,
+        global_opts,
+    )
+    .with_context(|| "Failed to create libvirt domain")?;
+    stages.finish();
+    let started = Instant::now();
+    disk_guards.into_iter().for_each(CleanupGuard::disarm);
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
+
+    crate::events::record(crate::events::EventKind::VmCreated {
+        name: vm_name.clone(),
+        image: opts.source_name().to_owned(),
+    });
+
+    let resolved_memory = opts.resolved_memory_mb()?;
+    let resolved_cpus = opts.resolved_cpus()?;
+
+    if opts.no_start {
+        println!("VM '{}' defined, but not started", vm_name);
+    } else {
+        println!("VM '{}' created successfully!", vm_name);
+    }
+    if let Some(ref image) = opts.image {
+        println!("  Image: {}", image);
+    }
+    if let Some(ref disk_image) = opts.disk_image {
+        println!("  Disk image: {}", disk_image);
+    }
+    println!("  Disk: {}", disk_path);
+    for path in &additional_disks {
+        println!("  Additional disk: {}", path);
+    }
+    if let Some(ref itype) = opts.itype {
+        println!("  Instance Type: {}", itype);
+    }
+    println!("  Memory: {} MiB", resolved_memory);
+    println!("  CPUs: {}", resolved_cpus);
+
+    // Display volume mount information if any
+    if !opts.volumes.is_empty() {
+        println!("\nVolume mounts:");
+        for volume in opts.volumes.iter() {
+            match volume {
+                VolumeMount::Mount { bind, readonly } => {
+                    let access = if *readonly { ", read-only" } else { "" };
+                    println!(
+                        "  {} → {} (automatically mounted{})",
+                        bind.host_path, bind.guest_path, access
+                    );
+                }
+                VolumeMount::Tag { host_path, tag } => {
+                    println!(
+                        "  {} (tag: {}, mount with: mount -t virtiofs {} /your/mount/point)",
+                        host_path, tag, tag
+                    );
+                }
+            }
+        }
+    }
+
+    // Display port forwarding information if any
+    if !opts.port_mappings.is_empty() {
+        println!("\nPort forwarding:");
+        for mapping in opts.port_mappings.iter() {
+            println!(
+                "  localhost:{} -> VM:{}",
+                mapping.host_port, mapping.guest_port
+            );
+        }
+    }
+
+    // Display bind mount information
+    if !opts.bind_mounts.is_empty() {
+        println!("\nBind mounts (read-write):");
+        for bind_mount in opts.bind_mounts.iter() {
+            println!(
+                "  {} → {} (automatically mounted)",
+                bind_mount.host_path, bind_mount.guest_path
+            );
+        }
+    }
+
+    if !opts.bind_mounts_ro.is_empty() {
+        println!("\nBind mounts (read-only):");
+        for bind_mount in opts.bind_mounts_ro.iter() {
+            println!(
+                "  {} → {} (automatically mounted, read-only)",
+                bind_mount.host_path, bind_mount.guest_path
+            );
+        }
+    }
+
+    if opts.register_dns {
+        let address = opts.dns_address.unwrap_or(Ipv4Addr::LOCALHOST.into());
+        match crate::libvirt::dns::register(&vm_name, address) {
+            Ok(host) => println!("\nDNS name: {host} -> {address}"),
+            // The VM is usable without its name
+            Err(e) => eprintln!("Warning: Failed to register the DNS name of '{vm_name}': {e:#}"),
+        }
+    }
+
+    if opts.no_start {
+        println!("  SSH port: {ssh_port} (reassigned on start if taken)");
+        println!("\nUse 'bcvk libvirt start {}' to start it", vm_name);
+        if let Some(out) = json_out.as_mut() {
+            write_handle(
+                out,
+                connect_uri,
+                &vm_name,
+                false,
+                ssh_port,
+                &disk_path,
+                &additional_disks,
+            )?;
+        }
+        return Ok(());
+    }
+
+    if wait != WaitMode::None {
+        println!();
+        stages.begin(match wait {
+            WaitMode::Boot => "Waiting for the VM to boot",
+            _ => "Waiting for SSH",
+        });
+        wait_for_ssh(global_opts, &vm_name)?;
+        if wait == WaitMode::Boot {
+            wait_for_boot(global_opts, &vm_name)?;
+        }
+        stages.finish();
+        println!(
+            "\n{}",
+            readiness_line(&vm_name, wait, ssh_port, started.elapsed())
+        );
+    }
+    if let Some(out) = json_out.as_mut() {
+        write_handle(
+            out,
+            connect_uri,
+            &vm_name,
+            true,
+            ssh_port,
+            &disk_path,
+            &additional_disks,
+        )?;
+    }
+
+    if opts.ssh {
+        // Wait for SSH then enter interactive shell
+        let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
+            domain_name: vm_name,
+            user: None,
+            command: vec![],
+            suppress_output: false,
+            no_strict: false,
+            timeout: 30,
+            log_level: "ERROR".to_string(),
+            extra_options: vec![],
+            wait: Some(crate::libvirt::ssh::DEFAULT_WAIT_SECONDS),
+        };
+        crate::libvirt::ssh::run(global_opts, ssh_opts)
+    } else {
+        println!("\nUse 'bcvk libvirt ssh {}' to connect", vm_name);
+        Ok(())
+    }
+}
+
+/// How long `--replace` waits for the replaced domain to shut down before
+/// forcing it off
+const REPLACE_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Shut down and remove the domain `name` for `--replace`
+///
+/// With `--keep-disk`, the disk of the domain is kept and returned, along
+/// with the digest of the image it was installed from.
+fn replace_domain(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    name: &str,
+    opts: &LibvirtRunOpts,
+) -> Result<Option<(Utf8PathBuf, Option<String>)>> {
+    let domain = lister.get_domain_info(name)?;
+    // Checked before anything is stopped
+    let kept_disk = if opts.keep_disk {
+        Some(check_kept_disk(
+            lister,
+            name,
+            &domain,
+            opts.image.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
+    if domain.is_running() {
+        if let Err(e) = crate::libvirt::update::shutdown_and_wait(
+            global_opts,
+            lister,
+            name,
+            REPLACE_SHUTDOWN_TIMEOUT,
+        ) {
+            debug!("Shutting down '{name}': {e:#}");
+            eprintln!("Warning: VM '{name}' did not shut down gracefully, forcing it off");
+        }
+    }
+    if opts.keep_disk {
+        crate::libvirt::rm::remove_vm_keep_disk(global_opts, name)?;
+    } else {
+        crate::libvirt::rm::remove_vm_forced(global_opts, name, true)?;
+    }
+    Ok(kept_disk)
+}
+
+/// Check that the disk of `domain` can be kept for `--keep-disk`, returning
+/// it and the digest of the image it was installed from
+fn check_kept_disk(
+    lister: &DomainLister,
+    name: &str,
+    domain: &crate::domain_list::PodmanBootcDomain,
+    image: Option<&str>,
+) -> Result<(Utf8PathBuf, Option<String>)> {
+    if lister.get_domain_xml(name)?.find("transient").is_some() {
+        return Err(eyre!(
+            "VM '{name}' has a transient disk, which cannot be kept"
+        ));
+    }
+    let disk_path = domain
+        .disk_path
+        .as_deref()
+        .ok_or_else(|| eyre!("VM '{name}' has no disk to keep"))?;
+    if let (Some(image), Some(current)) = (image, domain.image.as_deref()) {
+        if image != current {
+            return Err(eyre!(
+                "VM '{name}' runs {current}, which --keep-disk keeps; use 'bcvk libvirt update' to change its image"
+            ));
+        }
+    }
+    Ok((disk_path.into(), domain.image_digest.clone()))
+}
+
+/// Line printed once the domain is ready, for scripts: `key=value` fields
+/// after a fixed `ready:` prefix
+fn readiness_line(name: &str, wait: WaitMode, ssh_port: u16, elapsed: Duration) -> String {
+    format!(
+        "ready: name={name} wait={wait} ssh-port={ssh_port} elapsed={:.1}s",
+        elapsed.as_secs_f64()
+    )
+}
+
+/// The PID of the QEMU process of the running domain `name`, from the PID
+/// file libvirt keeps for it
+fn qemu_pid(connect_uri: Option<&str>, name: &str) -> Option<u32> {
+    let uri = match connect_uri {
+        Some(uri) => uri.to_owned(),
+        None => {
+            let output = virsh_command(None).ok()?.arg("uri").output().ok()?;
+            String::from_utf8(output.stdout).ok()?.trim().to_owned()
+        }
+    };
+    if crate::libvirt::ssh::is_remote_uri(&uri) {
+        return None;
+    }
+    let run_dir = match crate::libvirt::status::ConnectionKind::from_uri(&uri) {
+        crate::libvirt::status::ConnectionKind::System => Utf8PathBuf::from("/run/libvirt/qemu"),
+        crate::libvirt::status::ConnectionKind::Session => {
+            Utf8PathBuf::try_from(dirs::runtime_dir()?)
+                .ok()?
+                .join("libvirt/qemu/run")
+        }
+    };
+    let pid = fs::read_to_string(run_dir.join(format!("{name}.pid"))).ok()?;
+    pid.trim().parse().ok()
+}
+
+/// Write the [`DomainHandle`] of the domain `name` as a line of JSON
+fn write_handle(
+    out: &mut fs::File,
+    connect_uri: Option<&str>,
+    name: &str,
+    running: bool,
+    ssh_port: u16,
+    disk: &Utf8Path,
+    additional_disks: &[Utf8PathBuf],
+) -> Result<()> {
+    let uuid = virsh_command(connect_uri)?
+        .args(["domuuid", name])
+        .output()
+        .context("Failed to run virsh domuuid")?;
+    if !uuid.status.success() {
+        return Err(eyre!(
+            "Failed to get the UUID of '{name}': {}",
+            String::from_utf8_lossy(&uuid.stderr).trim()
+        ));
+    }
+    let handle = DomainHandle {
+        name: name.to_owned(),
+        uuid: String::from_utf8_lossy(&uuid.stdout).trim().to_owned(),
+        running,
+        ssh_port,
+        disk: disk.to_owned(),
+        additional_disks: additional_disks.to_vec(),
+        qemu_pid: running.then(|| qemu_pid(connect_uri, name)).flatten(),
+    };
+    serde_json::to_writer(&mut *out, &handle)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// SSH options for running `command` in a domain as its default user, which
+/// may not be root
+fn probe_ssh_opts(name: &str, command: &[&str]) -> crate::libvirt::ssh::LibvirtSshOpts {
+    crate::libvirt::ssh::LibvirtSshOpts {
+        domain_name: name.to_owned(),
+        user: None,
+        command: command.iter().map(|s| s.to_string()).collect(),
+        suppress_output: true,
+        no_strict: false,
+        timeout: 30,
+        log_level: "ERROR".to_string(),
+        extra_options: vec![],
+        wait: None,
+    }
+}
+
+/// Wait until the domain accepts SSH connections
+pub(super) fn wait_for_ssh(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
+        wait: Some(crate::libvirt::ssh::DEFAULT_WAIT_SECONDS),
+        ..probe_ssh_opts(name, &["true"])
+    };
+    crate::libvirt::ssh::run(global_opts, ssh_opts)
+}
+
+/// Check the state reported by `systemctl is-system-running` once booted
+///
+/// A degraded system counts as booted, as a failed unit is often unrelated
+/// to what the VM is used for; it is reported though.
+fn check_system_state(name: &str, state: &str) -> Result<()> {
+    match state {
+        "running" => Ok(()),
+        "degraded" => {
+            eprintln!(
+                "Warning: VM '{name}' booted with failed units; see 'bcvk libvirt ssh {name} systemctl --failed'"
+            );
+            Ok(())
+        }
+        "" => Err(eyre!(
+            "VM '{name}' did not finish booting within {}s",
+            crate::libvirt::ssh::DEFAULT_WAIT_SECONDS
+        )),
+        state => Err(eyre!(
+            "VM '{name}' did not boot properly (system state: {state})"
+        )),
+    }
+}
+
+/// Wait until systemd in the domain finished booting
+fn wait_for_boot(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    let pb = crate::boot_progress::create_boot_progress_bar();
+    pb.set_message("Waiting for boot to finish");
+    // is-system-running fails unless the state is "running"; on timeout it
+    // prints nothing
+    let script = format!(
+        "timeout {} systemctl is-system-running --wait || true",
+        crate::libvirt::ssh::DEFAULT_WAIT_SECONDS
+    );
+    let output =
+        crate::libvirt::ssh::run_ssh_output(global_opts, &probe_ssh_opts(name, &[&script]));
+    pb.finish_and_clear();
+    let output = output.with_context(|| format!("Waiting for VM '{name}' to boot"))?;
+    check_system_state(name, String::from_utf8_lossy(&output).trim())
+}
 
+/// Install the container image to a base disk and create the VM disk from it
+///
+/// Returns the VM disk path and the image digest.
+fn prepare_installed_disk(
+    opts: &mut LibvirtRunOpts,
+    vm_name: &str,
+    connect_uri: Option<&str>,
+    stages: &mut StageProgress,
+) -> Result<(Utf8PathBuf, String)> {
+    let image = opts.source_name().to_owned();
     println!(
         "Creating libvirt domain '{}' (install source container image: {})",
-        vm_name, opts.image
+        vm_name, image
     );
 
+    crate::image_pull::ensure(&image, opts.pull)?;
+
     // Get the image digest for caching
-    let inspect = images::inspect(&opts.image)?;
+    stages.begin("Inspecting image");
+    let inspect = crate::images::inspect(&image)?;
+    opts.policy.check(&image, &inspect)?;
     let image_digest = inspect.digest.to_string();
     debug!("Image digest: {}", image_digest);
 
@@ -448,13 +1440,23 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpt
         opts.bind_storage_ro = true;
         opts.install.target_transport = Some(UPDATE_FROM_HOST_TRANSPORT.to_owned());
     }
+    // The permissive profile is the same as --selinux permissive, which is
+    // recorded in the metadata
+    opts.install.selinux =
+        SelinuxMode::with_karg_profiles(opts.install.selinux, &opts.karg_profiles)?;
+    opts.karg_profiles.retain(|p| *p != KargProfile::Permissive);
+    // The base disk is looked up by the expanded arguments, so that a
+    // profile and its arguments given with --karg share it
+    opts.install.karg = expand_karg_profiles(&opts.karg_profiles, &opts.install.karg);
 
     // Phase 1: Find or create a base disk image
+    stages.begin("Preparing base disk");
     let base_disk_path = crate::libvirt::base_disks::find_or_create_base_disk(
-        &opts.image,
+        &image,
         &image_digest,
         &opts.install,
         connect_uri,
+        &opts.pool,
     )
     .with_context(|| "Failed to find or create base disk")?;
 
@@ -462,107 +1464,179 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, mut opts: LibvirtRunOpt
 
     // Phase 2: Clone the base disk to create a VM-specific disk (or use base directly if transient)
     let disk_path = if opts.transient {
-        println!("Transient mode: using base disk directly with overlay");
+        stages.skip(
+            "Creating VM disk",
+            "transient VMs use the base disk with an overlay",
+        );
         base_disk_path
+    } else if crate::hostexec::dry_run() {
+        stages.begin("Creating VM disk");
+        let pool_path = get_libvirt_storage_pool_path(connect_uri, &opts.pool)?;
+        let disk_path = pool_path.join(format!("{vm_name}.qcow2"));
+        let mode = opts.clone_mode.resolve(&pool_path);
+        crate::hostexec::dry_run_note(format_args!(
+            "clone {base_disk_path} to {disk_path} ({mode})"
+        ));
+        disk_path
     } else {
-        let cloned_disk =
-            crate::libvirt::base_disks::clone_from_base(&base_disk_path, &vm_name, connect_uri)
-                .with_context(|| "Failed to clone VM disk from base")?;
+        stages.begin("Creating VM disk");
+        // Resolved here so the domain metadata records the actual mode
+        let pool_path = get_libvirt_storage_pool_path(connect_uri, &opts.pool)?;
+        opts.clone_mode = opts.clone_mode.resolve(&pool_path);
+        let cloned_disk = crate::libvirt::base_disks::clone_from_base(
+            &base_disk_path,
+            vm_name,
+            connect_uri,
+            &opts.pool,
+            opts.clone_mode,
+        )
+        .with_context(|| "Failed to clone VM disk from base")?;
         println!("Created VM disk: {}", cloned_disk);
         cloned_disk
     };
 
-    // Phase 3: Create libvirt domain
-    println!("Creating libvirt domain...");
-
-    // Create the domain directly (simpler than using libvirt/create for files)
-    create_libvirt_domain_from_disk(&vm_name, &disk_path, &image_digest, &opts, global_opts)
-        .with_context(|| "Failed to create libvirt domain")?;
+    Ok((disk_path, image_digest))
+}
 
-    // VM is now managed by libvirt, no need to track separately
+/// Determine the format of an existing disk image for `--disk-image`
+fn disk_image_format(info: &crate::qemu_img::QemuImgInfo) -> Result<ImageFormat> {
+    match info.format.as_str() {
+        "qcow2" => Ok(ImageFormat::Qcow2),
+        "raw" => Ok(ImageFormat::Raw),
+        o => Err(eyre!(
+            "Unsupported disk image format '{}' (expected qcow2 or raw)",
+            o
+        )),
+    }
+}
 
-    let resolved_memory = opts.resolved_memory_mb()?;
-    let resolved_cpus = opts.resolved_cpus()?;
+/// Create the VM disk for `--disk-image`, which must be an absolute path
+///
+/// The image itself is never written to: persistent VMs get a qcow2 overlay
+/// `{vm_name}.qcow2` in the storage pool `pool` backed by it (so that
+/// removing the VM doesn't remove the image), while transient VMs use it
+/// directly with a libvirt-managed transient overlay.
+///
+/// Returns the VM disk path and its format.
+fn prepare_disk_image(
+    disk_image: &Utf8Path,
+    vm_name: &str,
+    transient: bool,
+    connect_uri: Option<&str>,
+    pool: &str,
+) -> Result<(Utf8PathBuf, ImageFormat)> {
+    use crate::qemu_img;
+
+    if !disk_image.is_file() {
+        return Err(eyre!("Disk image {} is not a regular file", disk_image));
+    }
+    let format = disk_image_format(&qemu_img::info(disk_image)?)?;
+    debug!("Disk image {} has format {}", disk_image, format);
 
-    println!("VM '{}' created successfully!", vm_name);
-    println!("  Image: {}", opts.image);
-    println!("  Disk: {}", disk_path);
-    if let Some(ref itype) = opts.itype {
-        println!("  Instance Type: {}", itype);
+    if transient {
+        println!("Transient mode: using disk image directly with overlay");
+        return Ok((disk_image.to_owned(), format));
     }
-    println!("  Memory: {} MiB", resolved_memory);
-    println!("  CPUs: {}", resolved_cpus);
 
-    // Display volume mount information if any
-    if !opts.raw_volumes.is_empty() {
-        println!("\nRaw volume mounts (manual):");
-        for volume_str in opts.raw_volumes.iter() {
-            if let Ok((host_path, tag)) = parse_volume_mount(volume_str) {
-                println!(
-                    "  {} (tag: {}, mount with: mount -t virtiofs {} /your/mount/point)",
-                    host_path, tag, tag
-                );
-            }
-        }
+    let pool_path = get_libvirt_storage_pool_path(connect_uri, pool)?;
+    let vol_name = format!("{}.qcow2", vm_name);
+    let vm_disk_path = pool_path.join(&vol_name);
+    if vm_disk_path == disk_image {
+        return Err(eyre!(
+            "Disk image {} would be replaced by the disk for VM '{}'",
+            disk_image,
+            vm_name
+        ));
     }
 
-    // Display port forwarding information if any
-    if !opts.port_mappings.is_empty() {
-        println!("\nPort forwarding:");
-        for mapping in opts.port_mappings.iter() {
-            println!(
-                "  localhost:{} -> VM:{}",
-                mapping.host_port, mapping.guest_port
-            );
-        }
+    delete_stale_volume(connect_uri, pool, &vol_name)?;
+
+    let (pool_dir, _) = qemu_img::open_parent(&vm_disk_path)?;
+    qemu_img::create_with_backing(&pool_dir, &vol_name, disk_image.as_str(), format, None)
+        .with_context(|| format!("Failed to create VM disk backed by {}", disk_image))?;
+
+    // Make libvirt aware of the new volume so it's removed with the domain
+    match virsh_command(connect_uri)?
+        .args(["pool-refresh", pool])
+        .output()
+    {
+        Ok(output) if !output.status.success() => warn!(
+            "Failed to refresh libvirt storage pool {pool}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to run virsh pool-refresh: {e}"),
     }
 
-    // Display bind mount information
-    if !opts.bind_mounts.is_empty() {
-        println!("\nBind mounts (read-write):");
-        for bind_mount in opts.bind_mounts.iter() {
-            println!(
-                "  {} → {} (automatically mounted)",
-                bind_mount.host_path, bind_mount.guest_path
-            );
-        }
+    println!("Created VM disk: {}", vm_disk_path);
+    Ok((vm_disk_path, ImageFormat::Qcow2))
+}
+
+/// Remove any stale volume `vol_name` left behind by a previous VM of the same name
+///
+/// A missing volume is expected; failing to delete an existing one is an error.
+fn delete_stale_volume(connect_uri: Option<&str>, pool: &str, vol_name: &str) -> Result<()> {
+    let exists = virsh_command(connect_uri)?
+        .args(["vol-path", "--pool", pool, vol_name])
+        .output()
+        .context("Failed to run virsh vol-path")?
+        .status
+        .success();
+    if !exists {
+        debug!("No stale volume {vol_name} in pool {pool}");
+        return Ok(());
     }
+    virsh_command(connect_uri)?
+        .args(["vol-delete", "--pool", pool, vol_name])
+        .run()
+        .with_context(|| format!("Failed to delete stale volume {vol_name} in pool {pool}"))?;
+    debug!("Deleted stale volume {vol_name} in pool {pool}");
+    Ok(())
+}
 
-    if !opts.bind_mounts_ro.is_empty() {
-        println!("\nBind mounts (read-only):");
-        for bind_mount in opts.bind_mounts_ro.iter() {
-            println!(
-                "  {} → {} (automatically mounted, read-only)",
-                bind_mount.host_path, bind_mount.guest_path
-            );
-        }
+/// Create blank volumes in the storage pool `pool` for `--disk` options
+///
+/// Volumes are named `{vm_name}-disk{N}.{format}` and created through libvirt
+/// so that they are removed along with the domain's other storage.
+fn create_additional_disks(
+    vm_name: &str,
+    disks: &[DiskSpec],
+    connect_uri: Option<&str>,
+    pool: &str,
+) -> Result<Vec<Utf8PathBuf>> {
+    if disks.is_empty() {
+        return Ok(Vec::new());
     }
 
-    if opts.ssh_wait {
-        // Wait for SSH to be ready and verify connectivity
-        wait_for_ssh_ready(global_opts, &vm_name, SSH_WAIT_TIMEOUT_SECONDS)?;
-        println!("Ready; use bcvk libvirt ssh to connect");
-        Ok(())
-    } else if opts.ssh {
-        // Wait for SSH then enter interactive shell
-        wait_for_ssh_ready(global_opts, &vm_name, SSH_WAIT_TIMEOUT_SECONDS)?;
+    let pool_path = get_libvirt_storage_pool_path(connect_uri, pool)?;
+    let mut paths = Vec::new();
 
-        // Use the libvirt SSH functionality directly
-        let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
-            domain_name: vm_name,
-            user: "root".to_string(),
-            command: vec![],
-            suppress_output: false,
-            strict_host_keys: false,
-            timeout: 30,
-            log_level: "ERROR".to_string(),
-            extra_options: vec![],
-        };
-        crate::libvirt::ssh::run(global_opts, ssh_opts)
-    } else {
-        println!("\nUse 'bcvk libvirt ssh {}' to connect", vm_name);
-        Ok(())
+    for (idx, disk) in disks.iter().enumerate() {
+        let vol_name = format!("{}-disk{}.{}", vm_name, idx + 1, disk.format);
+
+        delete_stale_volume(connect_uri, pool, &vol_name)?;
+
+        debug!(
+            "Creating additional disk {} ({} bytes)",
+            vol_name, disk.size
+        );
+        run_virsh_cmd(
+            connect_uri,
+            &[
+                "vol-create-as",
+                pool,
+                &vol_name,
+                &disk.size.to_string(),
+                "--format",
+                disk.format.as_str(),
+            ],
+            &format!("Failed to create volume '{}'", vol_name),
+        )?;
+
+        paths.push(pool_path.join(vol_name));
     }
+
+    Ok(paths)
 }
 
 /// Determine the appropriate default storage pool path based on connection type
@@ -594,10 +1668,7 @@ fn ensure_default_pool(connect_uri: Option<&str>) -> Result<()> {
 
     if output.status.success() {
         // Pool exists, make sure it's active
-        let mut cmd = virsh_command(connect_uri)?;
-        cmd.args(&["pool-start", "default"]);
-        let _ = cmd.output(); // Ignore errors if already started
-        return Ok(());
+        return start_pool_if_inactive(connect_uri, "default");
     }
 
     // Pool doesn't exist, need to create it
@@ -683,13 +1754,101 @@ fn ensure_default_pool(connect_uri: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-/// Get the path of the default libvirt storage pool
-pub fn get_libvirt_storage_pool_path(connect_uri: Option<&str>) -> Result<Utf8PathBuf> {
+/// Check the memory, vCPUs and disks of the VM against the resources of the
+/// libvirt host and the free space in the storage pool
+fn check_host_resources(connect_uri: Option<&str>, opts: &LibvirtRunOpts) -> Result<()> {
+    let virsh_output = |subcommand: &str| -> Result<String> {
+        let output = virsh_command(connect_uri)?
+            .arg(subcommand)
+            .output()
+            .with_context(|| format!("Failed to run virsh {subcommand}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(eyre!("virsh {subcommand} failed: {stderr}"));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    let mut host = preflight::HostResources::from_virsh(
+        &virsh_output("nodeinfo")?,
+        &virsh_output("nodememstats")?,
+    )?;
+    // Huge pages are reserved up front, so they are not available memory
+    if opts.hugepages.is_some() {
+        host.memory_available_mb = host.memory_total_mb;
+    }
+    ensure_pool(connect_uri, &opts.pool)?;
+    let pool = run_virsh_xml(connect_uri, &["pool-dumpxml", &opts.pool])
+        .with_context(|| format!("Failed to get storage pool '{}' info", opts.pool))?;
+    host.disk_available_bytes = pool
+        .find("available")
+        .and_then(|n| n.text_content().trim().parse().ok());
+
+    // A disk image or kept disk already exists
+    let root_disk = if opts.disk_image.is_some() || opts.keep_disk {
+        0
+    } else {
+        crate::utils::parse_size(&opts.disk_size)?
+    };
+    let vm = preflight::VmResources {
+        memory_mb: opts.resolved_memory_mb()?.into(),
+        vcpus: opts.resolved_cpus()?,
+        disk_bytes: root_disk + opts.disks.iter().map(|d| d.size).sum::<u64>(),
+    };
+    preflight::check(&vm, &host)
+}
+
+/// Ensure the libvirt storage pool `pool` exists and is active
+///
+/// Only the default pool is created if it is missing.
+fn ensure_pool(connect_uri: Option<&str>, pool: &str) -> Result<()> {
+    if pool == super::LIBVIRT_DEFAULT_POOL {
+        return ensure_default_pool(connect_uri);
+    }
+
+    let output = virsh_command(connect_uri)?
+        .args(["pool-info", pool])
+        .output()
+        .with_context(|| format!("Failed to check for storage pool '{pool}'"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Storage pool '{pool}' does not exist. Create it with: virsh pool-define-as {pool} dir --target <path>"
+        ));
+    }
+    start_pool_if_inactive(connect_uri, pool)
+}
+
+/// Start the storage pool `pool` unless it is already active
+fn start_pool_if_inactive(connect_uri: Option<&str>, pool: &str) -> Result<()> {
+    let output = virsh_command(connect_uri)?
+        .args(["pool-list", "--inactive", "--name"])
+        .output()
+        .context("Failed to run virsh pool-list")?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to list inactive storage pools: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    if !String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|name| name.trim() == pool)
+    {
+        return Ok(());
+    }
+    info!("Starting storage pool '{pool}'");
+    virsh_command(connect_uri)?
+        .args(["pool-start", pool])
+        .run()
+        .with_context(|| format!("Failed to start storage pool '{pool}'"))
+}
+
+/// Get the path of the libvirt storage pool `pool`
+pub fn get_libvirt_storage_pool_path(connect_uri: Option<&str>, pool: &str) -> Result<Utf8PathBuf> {
     // Ensure pool exists before querying
-    ensure_default_pool(connect_uri)?;
+    ensure_pool(connect_uri, pool)?;
 
-    let dom = run_virsh_xml(connect_uri, &["pool-dumpxml", "default"])
-        .context("Failed to get default storage pool info")?;
+    let dom = run_virsh_xml(connect_uri, &["pool-dumpxml", pool])
+        .with_context(|| format!("Failed to get storage pool '{pool}' info"))?;
 
     if let Some(path_node) = dom.find("path") {
         let path_str = path_node.text_content().trim();
@@ -704,20 +1863,9 @@ pub fn get_libvirt_storage_pool_path(connect_uri: Option<&str>) -> Result<Utf8Pa
 }
 
 /// Generate a unique VM name from an image name
-fn generate_unique_vm_name(image: &str, existing_domains: &[String]) -> String {
-    // Extract image name from full image path
-    let base_name = if let Some(last_slash) = image.rfind('/') {
-        &image[last_slash + 1..]
-    } else {
-        image
-    };
-
-    // Remove tag if present
-    let base_name = if let Some(colon) = base_name.find(':') {
-        &base_name[..colon]
-    } else {
-        base_name
-    };
+pub(super) fn generate_unique_vm_name(image: &str, existing_domains: &[String]) -> String {
+    // The image name, without registry, tag or digest
+    let base_name = crate::images::short_name(image);
 
     // Sanitize name (replace invalid characters with hyphens)
     let sanitized: String = base_name
@@ -743,10 +1891,13 @@ fn generate_unique_vm_name(image: &str, existing_domains: &[String]) -> String {
     candidate
 }
 
-/// List all volumes in the default storage pool
-pub fn list_storage_pool_volumes(connect_uri: Option<&str>) -> Result<Vec<Utf8PathBuf>> {
+/// List all volumes in the storage pool `pool`
+pub fn list_storage_pool_volumes(
+    connect_uri: Option<&str>,
+    pool: &str,
+) -> Result<Vec<Utf8PathBuf>> {
     // Get the storage pool path from XML
-    let pool_path = get_libvirt_storage_pool_path(connect_uri)?;
+    let pool_path = get_libvirt_storage_pool_path(connect_uri, pool)?;
 
     debug!("Scanning storage pool directory: {:?}", pool_path);
 
@@ -769,7 +1920,7 @@ pub fn list_storage_pool_volumes(connect_uri: Option<&str>) -> Result<Vec<Utf8Pa
 }
 
 /// Find an available SSH port for port forwarding using random allocation
-fn find_available_ssh_port() -> u16 {
+pub(crate) fn find_available_ssh_port() -> u16 {
     use rand::Rng;
 
     // Try random ports in the range 2222-3000 to avoid conflicts in concurrent scenarios
@@ -795,44 +1946,6 @@ fn find_available_ssh_port() -> u16 {
     PORT_RANGE_START // Ultimate fallback
 }
 
-/// Parse a volume mount string in the format "host_path:tag"
-fn parse_volume_mount(volume_str: &str) -> Result<(String, String)> {
-    let (host_part, tag_part) = volume_str.split_once(':').ok_or_else(|| {
-        color_eyre::eyre::eyre!(
-            "Invalid volume format '{}'. Expected format: host_path:tag",
-            volume_str
-        )
-    })?;
-
-    let host_path = host_part.trim();
-    let tag = tag_part.trim();
-
-    if host_path.is_empty() || tag.is_empty() {
-        return Err(color_eyre::eyre::eyre!(
-            "Invalid volume format '{}'. Both host path and tag must be non-empty",
-            volume_str
-        ));
-    }
-
-    // Validate that the host path exists
-    let host_path_buf = std::path::Path::new(host_path);
-    if !host_path_buf.exists() {
-        return Err(color_eyre::eyre::eyre!(
-            "Host path '{}' does not exist",
-            host_path
-        ));
-    }
-
-    if !host_path_buf.is_dir() {
-        return Err(color_eyre::eyre::eyre!(
-            "Host path '{}' is not a directory",
-            host_path
-        ));
-    }
-
-    Ok((host_path.to_string(), tag.to_string()))
-}
-
 /// Process bind mounts and add them to the domain builder
 ///
 /// This helper processes a slice of bind mounts, generates virtiofs filesystems,
@@ -928,39 +2041,105 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_volume_mount_valid() {
-        let result = parse_volume_mount("/tmp:mytag");
-        assert!(result.is_ok());
-        let (host, tag) = result.unwrap();
-        assert_eq!(host, "/tmp");
-        assert_eq!(tag, "mytag");
-    }
+    fn test_parse_volume_mount() {
+        let mount = |host: &str, guest: &str, readonly| VolumeMount::Mount {
+            bind: BindMount {
+                host_path: host.to_string(),
+                guest_path: guest.to_string(),
+            },
+            readonly,
+        };
+        let cases = [
+            ("/tmp:/mnt/data", mount("/tmp", "/mnt/data", false)),
+            ("/tmp:/mnt/data:ro", mount("/tmp", "/mnt/data", true)),
+            ("/tmp:/mnt/data:rw", mount("/tmp", "/mnt/data", false)),
+            (
+                "/tmp:mytag",
+                VolumeMount::Tag {
+                    host_path: "/tmp".to_string(),
+                    tag: "mytag".to_string(),
+                },
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<VolumeMount>().unwrap(), expected, "{input}");
+        }
 
-    #[test]
-    fn test_parse_volume_mount_invalid_format() {
-        let result = parse_volume_mount("/tmp");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Expected format: host_path:tag"));
+        let errors = [
+            ("/tmp", "Expected format: host_path:guest_path[:ro]"),
+            (":mytag", "must be non-empty"),
+            ("/tmp:", "must be non-empty"),
+        ];
+        for (input, msg) in errors {
+            let err = input.parse::<VolumeMount>().unwrap_err().to_string();
+            assert!(err.contains(msg), "{input}: {err}");
+        }
     }
 
     #[test]
-    fn test_parse_volume_mount_empty_parts() {
-        let result = parse_volume_mount(":mytag");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("Both host path and tag must be non-empty"));
+    fn test_validate_volume_mount() {
+        assert!("/tmp:/mnt/data"
+            .parse::<VolumeMount>()
+            .unwrap()
+            .validate()
+            .is_ok());
+        for input in [
+            "/nonexistent/path/that/does/not/exist:mytag",
+            "/nonexistent/path/that/does/not/exist:/mnt/data",
+        ] {
+            let err = input
+                .parse::<VolumeMount>()
+                .unwrap()
+                .validate()
+                .unwrap_err();
+            assert!(err.to_string().contains("does not exist"), "{input}");
+        }
     }
 
     #[test]
-    fn test_parse_volume_mount_nonexistent_host() {
-        let result = parse_volume_mount("/nonexistent/path/that/does/not/exist:mytag");
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("does not exist"));
+    fn test_parse_disk_spec() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let cases = [
+            ("size=10G", 10 * GIB, ImageFormat::Qcow2, None),
+            ("size=1G,format=raw", GIB, ImageFormat::Raw, None),
+            (
+                "size=5G,format=qcow2,serial=data",
+                5 * GIB,
+                ImageFormat::Qcow2,
+                Some("data"),
+            ),
+            (
+                "serial=var,size=2G",
+                2 * GIB,
+                ImageFormat::Qcow2,
+                Some("var"),
+            ),
+        ];
+        for (input, size, format, serial) in cases {
+            let expected = DiskSpec {
+                size,
+                format,
+                serial: serial.map(ToOwned::to_owned),
+                io: Default::default(),
+            };
+            assert_eq!(input.parse::<DiskSpec>().unwrap(), expected, "{input}");
+        }
+
+        let spec: DiskSpec = "size=1G,cache=none,aio=io_uring".parse().unwrap();
+        assert_eq!(spec.io.to_string(), "cache=none,aio=io_uring");
+
+        let errors = [
+            ("format=raw", "missing size="),
+            ("size=0", "greater than zero"),
+            ("size=1G,format=vmdk", "Unsupported disk format"),
+            ("size=1G,bus=scsi", "Unknown disk option"),
+            ("size=1G,cache=directsync", "Invalid disk cache mode"),
+            ("10G", "Expected format: size=SIZE"),
+        ];
+        for (input, msg) in errors {
+            let err = input.parse::<DiskSpec>().unwrap_err().to_string();
+            assert!(err.contains(msg), "{input}: {err}");
+        }
     }
 
     #[test]
@@ -1016,17 +2195,97 @@ mod tests {
         let result = "70000:80".parse::<PortMapping>();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_hugepage_size() {
+        assert_eq!(parse_hugepage_size("2M").unwrap(), 2048);
+        assert_eq!(parse_hugepage_size("1G").unwrap(), 1048576);
+        for invalid in ["", "3M", "1K", "huge"] {
+            assert!(parse_hugepage_size(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_user_network() {
+        let cases = [
+            ("user", Some(vec![])),
+            ("user:", Some(vec![])),
+            ("bridge=virbr0", None),
+            (
+                "user:net=10.0.5.0/24,host=10.0.5.2,dns=10.0.5.3,hostname=myvm",
+                Some(vec![
+                    "net=10.0.5.0/24",
+                    "host=10.0.5.2",
+                    "dns=10.0.5.3",
+                    "hostname=myvm",
+                ]),
+            ),
+            (
+                "user:hostname=vm1.example.com",
+                Some(vec!["hostname=vm1.example.com"]),
+            ),
+            ("user:dns=10.0.2.4", Some(vec!["dns=10.0.2.4"])),
+        ];
+        for (input, expected) in cases {
+            let network = UserNetwork::from_network(input).unwrap();
+            assert_eq!(
+                network.map(|n| n.netdev_options()),
+                expected.map(|v| v.into_iter().map(String::from).collect::<Vec<_>>()),
+                "{input}"
+            );
+        }
+
+        let errors = [
+            ("user:net=10.0.5.0", "Expected ADDR/PREFIX"),
+            ("user:net=10.0.5.0/33", "Invalid prefix length"),
+            ("user:host=10.0.5", "Invalid host address"),
+            ("user:dns=::1", "Invalid dns address"),
+            ("user:hostname=my_vm", "Invalid hostname"),
+            ("user:hostname=", "Invalid hostname"),
+            ("user:restrict=on", "Unknown network option"),
+            ("user:net", "Expected KEY=VALUE"),
+            ("user:net=10.0.5.0/24,host=10.0.6.2", "not in 10.0.5.0/24"),
+            ("user:net=10.0.5.0/24,dns=192.168.1.1", "not in 10.0.5.0/24"),
+        ];
+        for (input, msg) in errors {
+            let err = UserNetwork::from_network(input).unwrap_err().to_string();
+            assert!(err.contains(msg), "{input}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_readiness_line() {
+        assert_eq!(
+            readiness_line("vm1", WaitMode::Boot, 2222, Duration::from_millis(12345)),
+            "ready: name=vm1 wait=boot ssh-port=2222 elapsed=12.3s"
+        );
+    }
+
+    #[test]
+    fn test_check_system_state() {
+        assert!(check_system_state("vm1", "running").is_ok());
+        assert!(check_system_state("vm1", "degraded").is_ok());
+        let err = check_system_state("vm1", "maintenance").unwrap_err();
+        assert!(err.to_string().contains("maintenance"), "{err}");
+        let err = check_system_state("vm1", "").unwrap_err();
+        assert!(err.to_string().contains("did not finish booting"), "{err}");
+    }
 }
 
 /// Create a libvirt domain directly from a disk image file
+///
+/// Returns the host port forwarded to SSH in the domain.
 fn create_libvirt_domain_from_disk(
     domain_name: &str,
     disk_path: &Utf8Path,
-    image_digest: &str,
+    disk_format: ImageFormat,
+    additional_disk_paths: &[Utf8PathBuf],
+    image_digest: Option<&str>,
     opts: &LibvirtRunOpts,
     global_opts: &crate::libvirt::LibvirtOptions,
-) -> Result<()> {
+) -> Result<u16> {
     use crate::libvirt::domain::DomainBuilder;
+    use crate::libvirt::domain_metadata::DomainMetadata;
     use crate::ssh::generate_ssh_keypair;
 
     // Generate SSH keypair for the domain
@@ -1058,15 +2317,11 @@ fn create_libvirt_domain_from_disk(
     let public_key_content = std::fs::read_to_string(&keypair.public_key_path)
         .map_err(|e| color_eyre::eyre::eyre!("Failed to read generated public key: {}", e))?;
 
-    let private_key_base64 = base64::Engine::encode(
-        &base64::engine::general_purpose::STANDARD,
-        private_key_content.as_bytes(),
-    );
-    debug!("Generated ephemeral SSH keypair (will be stored in domain XML)");
+    debug!("Generated ephemeral SSH keypair");
 
-    // Generate SMBIOS credential for SSH key injection and systemd environment configuration
-    // Combine SSH key setup and storage opts for systemd contexts
-    let mut tmpfiles_content = crate::credentials::key_to_root_tmpfiles_d(&public_key_content);
+    // Generate SMBIOS credentials for SSH key injection
+    let ssh_creds =
+        crate::credentials::smbios_creds_for_ssh(&public_key_content, opts.ssh_user.as_ref())?;
 
     let memory = opts.resolved_memory_mb()?;
     let cpus = opts.resolved_cpus()?;
@@ -1078,7 +2333,7 @@ fn create_libvirt_domain_from_disk(
         eyre::ensure!(opts.firmware == FirmwareType::UefiSecure);
 
         // Place the OVMF vars file in the libvirt storage pool so it's lifecycled with the VM
-        let pool_path = get_libvirt_storage_pool_path(global_opts.connect.as_deref())
+        let pool_path = get_libvirt_storage_pool_path(global_opts.connect.as_deref(), &opts.pool)
             .context("Failed to get libvirt storage pool path for secure boot vars")?;
         let vars_output_path = pool_path.join(format!("{}_OVMF_VARS.fd", domain_name));
 
@@ -1090,47 +2345,111 @@ fn create_libvirt_domain_from_disk(
         None
     };
 
-    // Build domain XML using the existing DomainBuilder with bootc metadata and SSH keys
+    // The bcvk metadata, including the SSH key; the secure boot and host
+    // storage settings are added below
+    let mut metadata = DomainMetadata {
+        source_image: Some(opts.source_name().to_owned()),
+        image_digest: image_digest.map(ToOwned::to_owned),
+        pinned_digest: opts
+            .image
+            .as_deref()
+            .and_then(crate::images::pinned_digest)
+            .map(ToOwned::to_owned),
+        memory_mb: Some(memory),
+        vcpus: Some(cpus),
+        network: Some(opts.network.clone()),
+        ssh_generated: true,
+        ssh_private_key: Some(private_key_content),
+        ssh_port: Some(ssh_port),
+        ssh_user: opts.ssh_user.as_ref().map(|user| user.name.clone()),
+        instance_type: opts.itype.map(|itype| itype.to_string()),
+        labels: opts.label.clone(),
+        pool: Some(opts.pool.clone()),
+        extra: opts
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.trim_start_matches("bootc:").to_owned(), v.clone()))
+            .collect(),
+        ..DomainMetadata::new()
+    };
+    if let Some(disk_image) = opts.disk_image.as_deref() {
+        metadata.disk_image = Some(disk_image.to_string());
+    } else {
+        metadata.disk_size = Some(opts.disk_size.clone());
+        metadata.filesystem = Some(
+            opts.install
+                .filesystem
+                .clone()
+                .unwrap_or_else(|| "ext4".to_string()),
+        );
+        metadata.kargs = opts.install.karg.clone();
+        metadata.selinux = opts.install.selinux.map(|m| m.to_string());
+        if !opts.transient && !opts.keep_disk {
+            metadata.clone_mode = Some(opts.clone_mode.to_string());
+        }
+    }
+
+    // Build domain XML using the existing DomainBuilder
     let mut domain_builder = DomainBuilder::new()
         .with_name(domain_name)
         .with_memory(memory.into())
         .with_vcpus(cpus)
         .with_disk(disk_path.as_str())
+        .with_disk_format(disk_format.as_str())
+        .with_disk_io(opts.disk_io.unwrap_or_default())
         .with_transient_disk(opts.transient)
         .with_network("none") // Use QEMU args for SSH networking instead
-        .with_firmware(opts.firmware)
-        .with_tpm(!opts.disable_tpm)
-        .with_metadata("bootc:source-image", &opts.image)
-        .with_metadata("bootc:memory-mb", &memory.to_string())
-        .with_metadata("bootc:vcpus", &cpus.to_string())
-        .with_metadata("bootc:disk-size-gb", &opts.disk_size.to_string())
-        .with_metadata(
-            "bootc:filesystem",
-            opts.install
-                .filesystem
-                .as_ref()
-                .unwrap_or(&"ext4".to_string()),
-        )
-        .with_metadata("bootc:network", &opts.network)
-        .with_metadata("bootc:ssh-generated", "true")
-        .with_metadata("bootc:ssh-private-key-base64", &private_key_base64)
-        .with_metadata("bootc:ssh-port", &ssh_port.to_string())
-        .with_metadata("bootc:image-digest", image_digest);
+        .with_firmware(opts.firmware.into())
+        .with_tpm(!opts.disable_tpm);
+
+    if let Some(rtc) = opts
+        .freeze_time
+        .map(crate::qemu::RtcConfig::frozen_at)
+        .or(opts.rtc)
+    {
+        domain_builder = domain_builder.with_rtc(rtc);
+    }
+
+    if let Some(percent) = opts.resources.cpu_quota {
+        domain_builder = domain_builder.with_cpu_quota(percent);
+    }
+    if let Some(weight) = opts.resources.io_weight {
+        domain_builder = domain_builder.with_io_weight(weight);
+    }
+    if let Some(page_size_kib) = opts.hugepages {
+        domain_builder = domain_builder.with_hugepages(page_size_kib);
+    }
+    domain_builder = domain_builder.with_memory_backing(opts.memory_backing.into());
+
+    for (path, spec) in additional_disk_paths.iter().zip(&opts.disks) {
+        domain_builder = domain_builder.with_additional_disk(AdditionalDisk {
+            path: path.to_string(),
+            format: spec.format.to_string(),
+            serial: spec.serial.clone(),
+            io: spec.io,
+        });
+    }
 
-    // Add instance type metadata if specified
-    if let Some(itype) = opts.itype {
-        domain_builder = domain_builder.with_metadata("bootc:instance-type", &itype.to_string());
+    for (idx, path) in opts.cdroms.iter().enumerate() {
+        domain_builder = domain_builder.with_cdrom(Cdrom {
+            path: path.to_string(),
+            boot: opts.boot_cdrom && idx == 0,
+        });
     }
 
-    // Add labels if specified
-    if !opts.label.is_empty() {
-        let labels = opts.label.join(",");
-        domain_builder = domain_builder.with_metadata("bootc:label", &labels);
+    for interface in &opts.interfaces {
+        domain_builder = domain_builder.with_interface(interface.clone());
+    }
+    for device in &opts.devices {
+        domain_builder = domain_builder.with_host_device(device.clone());
     }
 
-    // Add any additional metadata from caller
-    for (key, value) in &opts.metadata {
-        domain_builder = domain_builder.with_metadata(key, value);
+    // The config is embedded in the domain XML, so that it is also
+    // available to remote hypervisors and survives redefinitions
+    if let Some(path) = opts.ignition.as_deref() {
+        let config = crate::utils::read_ignition_config(path)?;
+        domain_builder =
+            domain_builder.with_fw_cfg_entry(crate::qemu::IGNITION_FW_CFG_NAME, &config);
     }
 
     // Add secure boot configuration if enabled
@@ -1149,30 +2468,35 @@ fn create_libvirt_domain_from_disk(
             .with_nvram_template(sb_vars_path.as_str(), &sb_config.vars_format);
 
         // Add secure boot keys path to metadata for reference
-        domain_builder =
-            domain_builder.with_metadata("bootc:secure-boot-keys", sb_config.key_dir.as_str());
+        metadata.secure_boot_keys = Some(sb_config.key_dir.to_string());
     }
 
-    // Add user-specified raw volume mounts (manual virtiofs tags)
-    if !opts.raw_volumes.is_empty() {
-        debug!("Processing {} raw volume mount(s)", opts.raw_volumes.len());
-
-        for volume_str in opts.raw_volumes.iter() {
-            let (host_path, tag) = parse_volume_mount(volume_str)
-                .with_context(|| format!("Failed to parse volume mount '{}'", volume_str))?;
-
-            debug!(
-                "Adding raw volume mount: {} (host) with tag '{}'",
-                host_path, tag
-            );
+    // Split volumes into automatically mounted ones and legacy raw tags
+    let mut volume_mounts = Vec::new();
+    let mut volume_mounts_ro = Vec::new();
+    for volume in opts.volumes.iter() {
+        match volume {
+            VolumeMount::Mount { bind, readonly } => {
+                if *readonly {
+                    volume_mounts_ro.push(bind.clone());
+                } else {
+                    volume_mounts.push(bind.clone());
+                }
+            }
+            VolumeMount::Tag { host_path, tag } => {
+                debug!(
+                    "Adding raw volume mount: {} (host) with tag '{}'",
+                    host_path, tag
+                );
 
-            let virtiofs_fs = VirtiofsFilesystem {
-                source_dir: host_path.clone(),
-                tag: tag.clone(),
-                readonly: false,
-            };
+                let virtiofs_fs = VirtiofsFilesystem {
+                    source_dir: host_path.clone(),
+                    tag: tag.clone(),
+                    readonly: false,
+                };
 
-            domain_builder = domain_builder.with_virtiofs_filesystem(virtiofs_fs);
+                domain_builder = domain_builder.with_virtiofs_filesystem(virtiofs_fs);
+            }
         }
     }
 
@@ -1199,6 +2523,25 @@ fn create_libvirt_domain_from_disk(
         &mut mount_unit_names,
     )?;
 
+    // Process volumes using the same mount unit machinery as bind mounts
+    domain_builder = process_bind_mounts(
+        &volume_mounts,
+        "bcvk-volume-",
+        false,
+        domain_builder,
+        &mut smbios_creds,
+        &mut mount_unit_names,
+    )?;
+
+    domain_builder = process_bind_mounts(
+        &volume_mounts_ro,
+        "bcvk-volume-ro-",
+        true,
+        domain_builder,
+        &mut smbios_creds,
+        &mut mount_unit_names,
+    )?;
+
     // Add container storage mount if requested
     if opts.bind_storage_ro {
         // Check libvirt version compatibility for readonly virtiofs
@@ -1220,10 +2563,9 @@ fn create_libvirt_domain_from_disk(
             readonly: true,
         };
 
-        domain_builder = domain_builder
-            .with_virtiofs_filesystem(virtiofs_fs)
-            .with_metadata("bootc:bind-storage-ro", "true")
-            .with_metadata("bootc:storage-path", storage_path.as_str());
+        domain_builder = domain_builder.with_virtiofs_filesystem(virtiofs_fs);
+        metadata.bind_storage_ro = true;
+        metadata.storage_path = Some(storage_path.to_string());
 
         // Generate mount unit for automatic mounting at /run/host-container-storage
         let guest_mount_path = "/run/host-container-storage";
@@ -1236,14 +2578,24 @@ fn create_libvirt_domain_from_disk(
         smbios_creds.push(mount_cred);
         mount_unit_names.push(unit_name);
 
-        // Add tmpfiles.d lines and systemd service credentials for STORAGE_OPTS
-        tmpfiles_content.push_str(&crate::credentials::storage_opts_tmpfiles_d_lines());
+        // Use it as an additional image store via STORAGE_OPTS
         smbios_creds.extend(
-            crate::credentials::smbios_creds_for_storage_opts()
+            crate::credentials::smbios_creds_for_storage_opts(guest_mount_path)
                 .context("Failed to generate storage opts credentials")?,
         );
     }
 
+    if opts.selinux_relabel {
+        smbios_creds.extend(crate::credentials::smbios_creds_for_selinux_relabel());
+    }
+
+    if let Some(size) = opts.swap.size()? {
+        smbios_creds.extend(crate::credentials::smbios_creds_for_swap(
+            size,
+            opts.swap.swap_backend,
+        ));
+    }
+
     // Create a single dropin for local-fs.target that wants all mount units
     // This must be done AFTER all mount units have been added (including bind-storage-ro)
     if !mount_unit_names.is_empty() {
@@ -1258,18 +2610,8 @@ fn create_libvirt_domain_from_disk(
 
     let mut qemu_args = Vec::new();
 
-    // Build QEMU args with all SMBIOS credentials
-    {
-        let encoded = data_encoding::BASE64.encode(tmpfiles_content.as_bytes());
-        let smbios_cred = format!("io.systemd.credential.binary:tmpfiles.extra={encoded}");
-        qemu_args.extend([
-            "-smbios".to_string(),
-            format!("type=11,value={}", smbios_cred),
-        ]);
-    }
-
-    // Add all SMBIOS credentials (mount units, storage opts, etc.)
-    for cred in smbios_creds {
+    // Add all SMBIOS credentials (SSH access, mount units, storage opts, etc.)
+    for cred in ssh_creds.into_iter().chain(smbios_creds) {
         qemu_args.push("-smbios".to_string());
         qemu_args.push(format!("type=11,value={}", cred));
     }
@@ -1281,6 +2623,7 @@ fn create_libvirt_domain_from_disk(
     }
 
     // Build netdev user mode networking with port forwarding
+    let user_network = UserNetwork::from_network(&opts.network)?.unwrap_or_default();
     let mut hostfwd_args = vec![format!("tcp::{}-:22", ssh_port)];
 
     // Add user-specified port mappings
@@ -1293,9 +2636,10 @@ fn create_libvirt_domain_from_disk(
 
     let netdev_config = format!(
         "user,id=ssh0,{}",
-        hostfwd_args
-            .iter()
-            .map(|fwd| format!("hostfwd={}", fwd))
+        user_network
+            .netdev_options()
+            .into_iter()
+            .chain(hostfwd_args.iter().map(|fwd| format!("hostfwd={}", fwd)))
             .collect::<Vec<_>>()
             .join(",")
     );
@@ -1305,7 +2649,22 @@ fn create_libvirt_domain_from_disk(
     qemu_args.push("-device".to_string());
     qemu_args.push("virtio-net-pci,netdev=ssh0,addr=0x3".to_string());
 
+    // Keep the SSH key out of the domain XML, which other libvirt users can read
+    let mut key_guard = None;
+    if opts.insecure_embed_key {
+        metadata.ssh_key_embedded = true;
+    } else if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!(
+            "write the SSH key of {domain_name} to a file in the user state directory"
+        ));
+        metadata.ssh_private_key = None;
+    } else {
+        key_guard = super::ssh_keys::externalize(&mut metadata, domain_name)?
+            .map(CleanupGuard::remove_path);
+    }
+
     let domain_xml = domain_builder
+        .with_domain_metadata(&metadata)
         .with_qemu_args(qemu_args)
         .build_xml()
         .with_context(|| "Failed to build domain XML")?;
@@ -1338,12 +2697,34 @@ fn create_libvirt_domain_from_disk(
             &["define", &xml_path],
             "Failed to define libvirt domain",
         )?;
+        if opts.no_start {
+            key_guard.into_iter().for_each(CleanupGuard::disarm);
+            return Ok(ssh_port);
+        }
+        // Don't leave a defined but never started domain behind
+        let undefine_guard = (!crate::hostexec::dry_run()).then(|| {
+            let connect_uri = connect_uri.map(ToOwned::to_owned);
+            let domain_name = domain_name.to_owned();
+            CleanupGuard::new(format!("domain {domain_name}"), move || {
+                if let Err(e) = run_virsh_cmd(
+                    connect_uri.as_deref(),
+                    &["undefine", "--nvram", &domain_name],
+                    "Failed to undefine libvirt domain",
+                ) {
+                    warn!("{e}");
+                }
+            })
+        });
         run_virsh_cmd(
             connect_uri,
             &["start", domain_name],
             "Failed to start libvirt domain",
         )?;
+        if let Some(guard) = undefine_guard {
+            guard.disarm();
+        }
     }
+    key_guard.into_iter().for_each(CleanupGuard::disarm);
 
-    Ok(())
+    Ok(ssh_port)
 }
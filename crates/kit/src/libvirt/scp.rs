@@ -0,0 +1,79 @@
+//! libvirt scp command - copy files to and from a bootc domain
+//!
+//! This uses the SSH key and port stored in the domain metadata, the same
+//! way as `bcvk libvirt ssh`.
+
+use clap::Parser;
+use color_eyre::{eyre::eyre, Result};
+
+use crate::ephemeral_cp::CopyLocation;
+
+/// Options for copying files to or from a libvirt domain
+#[derive(Debug, Parser)]
+pub struct LibvirtScpOpts {
+    /// Source: a local path, or DOMAIN:PATH for a path in the VM
+    pub source: CopyLocation,
+
+    /// Destination: a local path, or DOMAIN:PATH for a path in the VM
+    pub dest: CopyLocation,
+
+    /// Copy directories recursively
+    #[clap(long, short = 'r')]
+    pub recursive: bool,
+
+    /// Preserve modification times and modes
+    #[clap(long, short = 'p')]
+    pub preserve: bool,
+
+    /// SSH username to use for connection (defaults to the one given with `libvirt run --ssh-user`, or root)
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Do not check the SSH host key of the domain against the one learned on the first connection
+    #[clap(long)]
+    pub no_strict: bool,
+
+    /// SSH connection timeout in seconds
+    #[clap(long, default_value = "30")]
+    pub timeout: u32,
+
+    /// Extra SSH options in key=value format
+    #[clap(long)]
+    pub extra_options: Vec<String>,
+}
+
+/// Execute the libvirt scp command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtScpOpts) -> Result<()> {
+    let domain_name = match (&opts.source, &opts.dest) {
+        (CopyLocation::Vm { vm, .. }, CopyLocation::Host(_))
+        | (CopyLocation::Host(_), CopyLocation::Vm { vm, .. }) => vm.clone(),
+        (CopyLocation::Host(_), CopyLocation::Host(_)) => {
+            return Err(eyre!(
+                "One of source or destination must be a path in a domain (DOMAIN:PATH)"
+            ))
+        }
+        (CopyLocation::Vm { .. }, CopyLocation::Vm { .. }) => {
+            return Err(eyre!("Copying directly between domains is not supported"))
+        }
+    };
+
+    let ssh_opts = super::ssh::LibvirtSshOpts {
+        domain_name,
+        user: opts.user,
+        command: vec![],
+        no_strict: opts.no_strict,
+        timeout: opts.timeout,
+        log_level: "ERROR".to_string(),
+        extra_options: opts.extra_options,
+        suppress_output: false,
+        wait: None,
+    };
+    let mut flags = Vec::new();
+    if opts.recursive {
+        flags.push("-r");
+    }
+    if opts.preserve {
+        flags.push("-p");
+    }
+    super::ssh::run_scp(global_opts, &ssh_opts, &flags, &opts.source, &opts.dest)
+}
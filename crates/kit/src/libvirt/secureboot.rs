@@ -11,6 +11,8 @@ use serde::{Deserialize, Serialize};
 use std::fs;
 use std::ops::ControlFlow;
 
+pub use crate::qemu::FirmwareInfo;
+
 /// System-wide QEMU firmware descriptor search directories
 const QEMU_FIRMWARE_DIRS: &[&str] = &["/etc/qemu/firmware", "/usr/share/qemu/firmware"];
 
@@ -92,6 +94,9 @@ impl FirmwareDescriptor {
     /// QEMU firmware feature: enrolled keys
     pub(crate) const FEATURE_ENROLLED_KEYS: &'static str = "enrolled-keys";
 
+    /// QEMU firmware feature: requires a machine with SMM emulation
+    pub(crate) const FEATURE_REQUIRES_SMM: &'static str = "requires-smm";
+
     /// Check if this firmware supports secure boot
     pub(crate) fn supports_secure_boot(&self) -> bool {
         self.features
@@ -104,25 +109,18 @@ impl FirmwareDescriptor {
             .contains(&Self::FEATURE_ENROLLED_KEYS.to_string())
     }
 
+    /// Check if this firmware requires SMM emulation
+    pub(crate) fn requires_smm(&self) -> bool {
+        self.features
+            .contains(&Self::FEATURE_REQUIRES_SMM.to_string())
+    }
+
     /// Check if this firmware supports the given architecture
     pub(crate) fn supports_architecture(&self, arch: &str) -> bool {
         self.targets.iter().any(|t| t.architecture == arch)
     }
 }
 
-/// UEFI firmware paths and formats from QEMU firmware interop descriptors
-#[derive(Debug, Clone)]
-pub struct FirmwareInfo {
-    /// Path to OVMF_CODE firmware file
-    pub code_path: Utf8PathBuf,
-    /// Format of the OVMF_CODE file (raw, qcow2)
-    pub code_format: String,
-    /// Path to OVMF_VARS template file
-    pub vars_path: Utf8PathBuf,
-    /// Format of the OVMF_VARS file (raw, qcow2)
-    pub vars_format: String,
-}
-
 /// Secure Boot key configuration
 #[derive(Debug, Clone)]
 pub struct SecureBootConfig {
@@ -154,10 +152,7 @@ impl SecureBootKeys {
     /// Load existing secure boot keys from a directory
     pub fn load(key_dir: &Utf8Path) -> Result<Self> {
         // Check if directory exists
-        let mut test_dir = std::process::Command::new("test");
-        test_dir.args(["-d", key_dir.as_str()]);
-
-        if !test_dir.status()?.success() {
+        if !key_dir.is_dir() {
             return Err(eyre!(
                 "Secure boot key directory not found: {}. Please generate keys externally.",
                 key_dir
@@ -167,19 +162,13 @@ impl SecureBootKeys {
         let guid_file = key_dir.join("GUID.txt");
 
         // Read GUID file
-        let mut cat_guid = std::process::Command::new("cat");
-        cat_guid.arg(guid_file.as_str());
-        let guid_output = cat_guid.output()?;
-
-        if !guid_output.status.success() {
-            return Err(eyre!(
-                "Failed to read GUID from {}. Ensure keys are properly generated.",
-                guid_file
-            ));
-        }
-
-        let guid = String::from_utf8(guid_output.stdout)
-            .map_err(|_| eyre!("Invalid UTF-8 in GUID file"))?
+        let guid = fs::read_to_string(&guid_file)
+            .map_err(|e| {
+                eyre!(
+                    "Failed to read GUID from {}: {e}. Ensure keys are properly generated.",
+                    guid_file
+                )
+            })?
             .trim()
             .to_string();
 
@@ -198,10 +187,7 @@ impl SecureBootKeys {
         ];
 
         for (path, name) in &required_files {
-            let mut test_file = std::process::Command::new("test");
-            test_file.args(["-f", path.as_str()]);
-
-            if !test_file.status()?.success() {
+            if !path.is_file() {
                 return Err(eyre!(
                     "Required secure boot file {} not found in {}",
                     name,
@@ -221,16 +207,12 @@ pub fn customize_ovmf_vars(
     output_path: &Utf8Path,
 ) -> Result<()> {
     // Check if virt-fw-vars is available
-    let mut check = std::process::Command::new("which");
-    check.arg("virt-fw-vars");
-    let check_output = check.output()?;
-
-    if !check_output.status.success() {
+    if which::which("virt-fw-vars").is_err() {
         return Err(eyre!("virt-fw-vars tool not found"));
     }
 
     // Use virt-fw-vars to inject keys into OVMF_VARS
-    let mut cmd = std::process::Command::new("virt-fw-vars");
+    let mut cmd = crate::hostexec::HostCommand::new("virt-fw-vars");
     cmd.args([
         "--input",
         ovmf_vars_path.as_str(),
@@ -272,7 +254,7 @@ pub fn setup_secure_boot(
     let keys = SecureBootKeys::load(key_dir)?;
 
     // Find the system firmware (includes format info)
-    let firmware_info = find_firmware_from_descriptors(true)?;
+    let firmware_info = find_firmware_from_descriptors(true, true)?;
 
     // Check if custom vars template already exists at the output path
     if !vars_output_path.exists() {
@@ -379,7 +361,11 @@ pub(crate) fn get_qemu_architecture() -> &'static str {
 /// - Searches in $XDG_CONFIG_HOME/qemu/firmware, /etc/qemu/firmware, /usr/share/qemu/firmware
 /// - Filters by architecture and secure boot support
 /// - Skips firmware with enrolled keys (known to cause issues)
-fn find_firmware_from_descriptors(require_secure_boot: bool) -> Result<FirmwareInfo> {
+/// - Skips firmware requiring SMM emulation unless `allow_smm` is set
+fn find_firmware_from_descriptors(
+    require_secure_boot: bool,
+    allow_smm: bool,
+) -> Result<FirmwareInfo> {
     let descriptors = list_firmware_descriptors()?;
     let arch = get_qemu_architecture();
 
@@ -414,6 +400,11 @@ fn find_firmware_from_descriptors(require_secure_boot: bool) -> Result<FirmwareI
             continue;
         }
 
+        if !allow_smm && descriptor.requires_smm() {
+            tracing::debug!("Skipping {}, firmware requires SMM", descriptor_path);
+            continue;
+        }
+
         // Skip memory-mapped firmware (we need separate code and vars files)
         if descriptor.mapping.device == FirmwareMapping::DEVICE_TYPE_MEMORY {
             tracing::debug!(
@@ -456,7 +447,7 @@ fn find_firmware_from_descriptors(require_secure_boot: bool) -> Result<FirmwareI
 
 /// Find the system OVMF_VARS.fd file using QEMU firmware interop JSON descriptors
 pub(crate) fn find_ovmf_vars() -> Result<Utf8PathBuf> {
-    let firmware_info = find_firmware_from_descriptors(false)?;
+    let firmware_info = find_firmware_from_descriptors(false, true)?;
 
     if !firmware_info.vars_path.exists() {
         return Err(eyre!(
@@ -476,7 +467,7 @@ pub(crate) fn find_ovmf_vars() -> Result<Utf8PathBuf> {
 ///
 /// Returns full firmware info including paths and formats for both CODE and VARS
 pub fn find_secure_boot_firmware() -> Result<FirmwareInfo> {
-    let firmware_info = find_firmware_from_descriptors(true)?;
+    let firmware_info = find_firmware_from_descriptors(true, true)?;
 
     if !firmware_info.code_path.exists() {
         return Err(eyre!(
@@ -495,6 +486,32 @@ pub fn find_secure_boot_firmware() -> Result<FirmwareInfo> {
     Ok(firmware_info)
 }
 
+/// Find UEFI firmware which QEMU can run without SMM emulation
+///
+/// Used to boot disk images directly with QEMU, where we don't configure
+/// the machine for SMM as libvirt does.
+pub(crate) fn find_uefi_firmware() -> Result<FirmwareInfo> {
+    let firmware_info = find_firmware_from_descriptors(false, false)?;
+
+    for path in [&firmware_info.code_path, &firmware_info.vars_path] {
+        if !path.exists() {
+            return Err(eyre!(
+                "Firmware descriptor returned non-existent path: {}. Please verify your QEMU firmware installation.",
+                path
+            ));
+        }
+    }
+
+    tracing::debug!(
+        "Found UEFI firmware: code={} ({}), vars={} ({})",
+        firmware_info.code_path,
+        firmware_info.code_format,
+        firmware_info.vars_path,
+        firmware_info.vars_format
+    );
+    Ok(firmware_info)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -3,13 +3,24 @@
 //! This module provides functionality to start stopped libvirt domains
 //! that were created from bootc container images.
 
+use std::io::Write;
+
 use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
+use tracing::debug;
+
+use crate::libvirt::domain_metadata::{self, DomainMetadata};
+use crate::xml_utils::{self, XmlNode};
+
+/// Guest port of the SSH forward set up by `bcvk libvirt run`
+const GUEST_SSH_PORT: u16 = 22;
 
 /// Options for starting a libvirt domain
 #[derive(Debug, Parser)]
 pub struct LibvirtStartOpts {
     /// Name of the domain to start
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
     pub name: String,
 
     /// Automatically SSH into the domain after starting
@@ -20,7 +31,6 @@ pub struct LibvirtStartOpts {
 /// Execute the libvirt start command
 pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtStartOpts) -> Result<()> {
     use crate::domain_list::DomainLister;
-    use color_eyre::eyre::Context;
 
     let connect_uri = global_opts.connect.as_ref();
     let lister = match connect_uri {
@@ -31,7 +41,7 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtStartOpts)
     // Check if domain exists and get its state
     let state = lister
         .get_domain_state(&opts.name)
-        .map_err(|_| color_eyre::eyre::eyre!("VM '{}' not found", opts.name))?;
+        .map_err(|_| eyre!("VM '{}' not found", opts.name))?;
 
     if state == "running" {
         println!("VM '{}' is already running", opts.name);
@@ -39,19 +49,23 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtStartOpts)
             println!("🔗 Connecting to running VM...");
             let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
                 domain_name: opts.name,
-                user: "root".to_string(),
+                user: None,
                 command: vec![],
-                strict_host_keys: false,
+                no_strict: false,
                 timeout: 30,
                 log_level: "ERROR".to_string(),
                 extra_options: vec![],
                 suppress_output: false,
+                wait: Some(crate::libvirt::ssh::DEFAULT_WAIT_SECONDS),
             };
             return crate::libvirt::ssh::run(global_opts, ssh_opts);
         }
         return Ok(());
     }
 
+    migrate_metadata(global_opts, &opts.name)?;
+    ensure_forwarded_ports_available(global_opts, &opts.name)?;
+
     println!("Starting VM '{}'...", opts.name);
 
     // Use virsh to start the domain
@@ -63,29 +77,279 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtStartOpts)
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(color_eyre::eyre::eyre!(
-            "Failed to start VM '{}': {}",
-            opts.name,
-            stderr
-        ));
+        return Err(eyre!("Failed to start VM '{}': {}", opts.name, stderr));
     }
 
     println!("VM '{}' started successfully", opts.name);
 
     if opts.ssh {
-        // Use the libvirt SSH functionality directly
+        // The guest needs to boot before it accepts connections
         let ssh_opts = crate::libvirt::ssh::LibvirtSshOpts {
             domain_name: opts.name,
-            user: "root".to_string(),
+            user: None,
             command: vec![],
-            strict_host_keys: false,
+            no_strict: false,
             timeout: 30,
             log_level: "ERROR".to_string(),
             extra_options: vec![],
             suppress_output: false,
+            wait: Some(crate::libvirt::ssh::DEFAULT_WAIT_SECONDS),
         };
         crate::libvirt::ssh::run(global_opts, ssh_opts)
     } else {
         Ok(())
     }
 }
+
+/// A host to guest TCP port forward of a domain's user mode network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PortForward {
+    host_port: u16,
+    guest_port: u16,
+}
+
+/// Parse the `hostfwd=tcp::HOST-:GUEST` forwards from the domain's
+/// `qemu:commandline` arguments
+fn parse_port_forwards(dom: &XmlNode) -> Vec<PortForward> {
+    let Some(cmdline) = dom.find("qemu:commandline") else {
+        return Vec::new();
+    };
+    cmdline
+        .children
+        .iter()
+        .filter_map(|arg| arg.attributes.get("value"))
+        .flat_map(|value| value.split(','))
+        .filter_map(|opt| {
+            let (host, guest) = opt.strip_prefix("hostfwd=tcp::")?.split_once("-:")?;
+            Some(PortForward {
+                host_port: host.parse().ok()?,
+                guest_port: guest.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Change the SSH port of a domain in its parsed XML, both in the bcvk
+/// metadata and the port forward
+pub(crate) fn replace_ssh_port(dom: &mut XmlNode, old: u16, new: u16) -> Result<()> {
+    let mut metadata = DomainMetadata::from_dom(dom)?
+        .filter(|m| m.ssh_port == Some(old))
+        .ok_or_else(|| eyre!("Failed to find SSH port {old} in domain metadata"))?;
+    metadata.ssh_port = Some(new);
+    metadata.replace_in_dom(dom)?;
+
+    let from = format!("hostfwd=tcp::{old}-:{GUEST_SSH_PORT}");
+    let to = format!("hostfwd=tcp::{new}-:{GUEST_SSH_PORT}");
+    let mut found = false;
+    if let Some(cmdline) = dom.find_mut("qemu:commandline") {
+        for value in cmdline
+            .children
+            .iter_mut()
+            .filter_map(|arg| arg.attributes.get_mut("value"))
+        {
+            if value.split(',').any(|opt| opt == from) {
+                *value = value
+                    .split(',')
+                    .map(|opt| if opt == from { to.as_str() } else { opt })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                found = true;
+            }
+        }
+    }
+    if !found {
+        return Err(eyre!("Failed to find '{from}' in domain XML"));
+    }
+    Ok(())
+}
+
+/// Get the persistent definition of a domain
+pub(crate) fn inactive_domain_xml(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    name: &str,
+) -> Result<String> {
+    let output = global_opts
+        .virsh_command()
+        .args(["dumpxml", "--inactive", name])
+        .output()
+        .with_context(|| "Failed to run virsh dumpxml")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("Failed to get XML for VM '{}': {}", name, stderr));
+    }
+    String::from_utf8(output.stdout).with_context(|| "Invalid UTF-8 in domain XML")
+}
+
+/// Replace the persistent definition of a domain
+pub(crate) fn define_domain_xml(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    xml: &str,
+) -> Result<()> {
+    let mut tmpf = tempfile::NamedTempFile::with_prefix("bcvk-libvirt")?;
+    tmpf.write_all(xml.as_bytes())
+        .with_context(|| "Failed to write domain XML")?;
+    let xml_path = tmpf
+        .path()
+        .to_str()
+        .ok_or_else(|| eyre!("Invalid UTF-8 in tempfile"))?;
+    super::run::run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["define", xml_path],
+        "Failed to update libvirt domain",
+    )
+}
+
+/// Upgrade the bcvk metadata of a domain created by an older version of bcvk
+/// to the current schema, in its persistent definition
+fn migrate_metadata(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    let xml = inactive_domain_xml(global_opts, name)?;
+    let Some(xml) = domain_metadata::migrate(&xml)
+        .with_context(|| format!("Failed to upgrade the metadata of VM '{name}'"))?
+    else {
+        return Ok(());
+    };
+    define_domain_xml(global_opts, &xml)?;
+    debug!(
+        "Upgraded metadata of VM '{name}' to schema version {}",
+        domain_metadata::SCHEMA_VERSION
+    );
+    Ok(())
+}
+
+fn port_in_use(port: u16) -> bool {
+    std::net::TcpListener::bind(("127.0.0.1", port)).is_err()
+}
+
+/// Check that the host ports forwarded to a stopped domain are still free
+///
+/// Ports are allocated when the domain is created, but e.g. after a host
+/// reboot another process may have taken one, and QEMU would then fail to
+/// set up the forward. A taken SSH port is replaced by a newly allocated one
+/// in the domain definition; ports from `--port` were chosen by the user, so
+/// for those we fail instead.
+fn ensure_forwarded_ports_available(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    name: &str,
+) -> Result<()> {
+    let connect_uri = global_opts.connect.as_deref();
+    if connect_uri.is_some_and(crate::libvirt::ssh::is_remote_uri) {
+        // The forwards listen on the remote hypervisor, which we can't check
+        debug!("Skipping port check for domain '{name}' on a remote hypervisor");
+        return Ok(());
+    }
+
+    let xml = inactive_domain_xml(global_opts, name)?;
+    let mut dom = xml_utils::parse_xml_dom(&xml)?;
+
+    let ssh_port = DomainMetadata::from_dom(&dom)?.and_then(|m| m.ssh_port);
+
+    let mut reassign_ssh_port = None;
+    for fwd in parse_port_forwards(&dom) {
+        if !port_in_use(fwd.host_port) {
+            continue;
+        }
+        if Some(fwd.host_port) == ssh_port && fwd.guest_port == GUEST_SSH_PORT {
+            reassign_ssh_port = Some(fwd.host_port);
+        } else {
+            return Err(eyre!(
+                "Cannot start VM '{}': host port {} (forwarded to guest port {}) is in use by another process",
+                name,
+                fwd.host_port,
+                fwd.guest_port
+            ));
+        }
+    }
+
+    let Some(old_port) = reassign_ssh_port else {
+        return Ok(());
+    };
+    let new_port = super::run::find_available_ssh_port();
+    if port_in_use(new_port) {
+        return Err(eyre!(
+            "SSH port {} of VM '{}' is in use by another process, and no free port was found",
+            old_port,
+            name
+        ));
+    }
+    replace_ssh_port(&mut dom, old_port, new_port)?;
+    define_domain_xml(global_opts, &dom.to_xml()?)?;
+
+    println!(
+        "SSH port {} of VM '{}' is in use by another process; reassigned to {}",
+        old_port, name, new_port
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN_XML: &str = r#"<domain type="kvm" xmlns:qemu="http://libvirt.org/schemas/domain/qemu/1.0">
+  <name>test</name>
+  <metadata>
+    <bootc:container xmlns:bootc="https://github.com/containers/bootc">
+      <bootc:ssh-port>2345</bootc:ssh-port>
+    </bootc:container>
+  </metadata>
+  <qemu:commandline>
+    <qemu:arg value="-netdev"/>
+    <qemu:arg value="user,id=ssh0,hostfwd=tcp::2345-:22,hostfwd=tcp::8080-:80"/>
+    <qemu:arg value="-device"/>
+    <qemu:arg value="virtio-net-pci,netdev=ssh0,addr=0x3"/>
+  </qemu:commandline>
+</domain>"#;
+
+    #[test]
+    fn test_parse_port_forwards() {
+        let dom = xml_utils::parse_xml_dom(DOMAIN_XML).unwrap();
+        assert_eq!(
+            parse_port_forwards(&dom),
+            [
+                PortForward {
+                    host_port: 2345,
+                    guest_port: 22
+                },
+                PortForward {
+                    host_port: 8080,
+                    guest_port: 80
+                },
+            ]
+        );
+
+        let dom = xml_utils::parse_xml_dom("<domain><name>test</name></domain>").unwrap();
+        assert!(parse_port_forwards(&dom).is_empty());
+    }
+
+    #[test]
+    fn test_replace_ssh_port() {
+        let mut dom = xml_utils::parse_xml_dom(DOMAIN_XML).unwrap();
+        replace_ssh_port(&mut dom, 2345, 2400).unwrap();
+        assert_eq!(
+            dom.find_with_namespace("ssh-port").unwrap().text_content(),
+            "2400"
+        );
+        assert_eq!(
+            parse_port_forwards(&dom)[0],
+            PortForward {
+                host_port: 2400,
+                guest_port: 22
+            }
+        );
+        // The user's port mapping is untouched
+        assert_eq!(
+            parse_port_forwards(&dom)[1],
+            PortForward {
+                host_port: 8080,
+                guest_port: 80
+            }
+        );
+
+        // Metadata and forward must both be present
+        let mut dom = xml_utils::parse_xml_dom(DOMAIN_XML).unwrap();
+        assert!(replace_ssh_port(&mut dom, 2222, 2400).is_err());
+        let mut dom =
+            xml_utils::parse_xml_dom(&DOMAIN_XML.replace("2345-:22", "2346-:22")).unwrap();
+        assert!(replace_ssh_port(&mut dom, 2345, 2400).is_err());
+    }
+}
@@ -1,19 +1,28 @@
 //! libvirt status command - show libvirt environment information
 //!
-//! This module provides a status command that outputs JSON metadata about
-//! the libvirt environment, including version information and domain count.
+//! This module provides a status command that outputs metadata about
+//! the libvirt environment, including version information and domain count,
+//! along with a set of environment checks. Each check is reported as
+//! pass/warn/fail together with a suggested fix, and the results are
+//! summarized in a capability matrix for scripts.
 
+use camino::Utf8PathBuf;
 use clap::Parser;
 use color_eyre::{eyre::Context, Result};
+use comfy_table::{presets::UTF8_FULL, Table};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
 
+use super::LIBVIRT_DEFAULT_POOL;
 use crate::domain_list::DomainLister;
+use crate::hostexec::HostCommand;
+
+/// Warn when the storage pool has less free space than one default-sized disk
+const MIN_POOL_FREE_BYTES: u64 = 20 * 1024 * 1024 * 1024;
 
 /// Options for the libvirt status command
 #[derive(Debug, Parser)]
 pub struct LibvirtStatusOpts {
-    /// Output format (yaml or json)
+    /// Output format
     #[clap(long, default_value = "yaml", value_enum)]
     pub format: OutputFormat,
 }
@@ -25,6 +34,108 @@ pub enum OutputFormat {
     Yaml,
     /// JSON format (machine-readable)
     Json,
+    /// Table of checks with suggested fixes
+    Table,
+}
+
+/// Outcome of a single environment check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckResult {
+    /// Everything is in order
+    Pass,
+    /// Usable, but some functionality is degraded
+    Warn,
+    /// bcvk libvirt commands are expected to fail
+    Fail,
+}
+
+impl CheckResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CheckResult::Pass => "pass",
+            CheckResult::Warn => "warn",
+            CheckResult::Fail => "fail",
+        }
+    }
+}
+
+/// A single environment check and how to remediate it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusCheck {
+    /// Short identifier of the check
+    pub name: String,
+    /// Outcome of the check
+    pub result: CheckResult,
+    /// What was found
+    pub detail: String,
+    /// Suggested fix, if the check did not pass
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remediation: Option<String>,
+}
+
+/// Machine-readable summary of which features are usable
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The storage pool exists and is active
+    pub storage_pool: bool,
+    /// UEFI firmware with secure boot support was found
+    pub secure_boot: bool,
+    /// virtiofsd is installed, required for --volume and --bind-storage-ro
+    pub virtiofs: bool,
+    /// Both libvirt and virtiofsd support readonly virtiofs mounts
+    pub readonly_virtiofs: bool,
+    /// Connected to a per-user session daemon instead of the system one
+    pub session: bool,
+}
+
+/// Connection type of the libvirt URI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionKind {
+    /// qemu:///system or a remote system daemon
+    System,
+    /// qemu:///session
+    Session,
+}
+
+impl ConnectionKind {
+    pub(crate) fn from_uri(uri: &str) -> Self {
+        if uri.trim_end().ends_with("/session") {
+            ConnectionKind::Session
+        } else {
+            ConnectionKind::System
+        }
+    }
+}
+
+/// Storage pool information from `virsh pool-dumpxml`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolInfo {
+    /// Pool target path
+    pub path: Option<Utf8PathBuf>,
+    /// Free space in bytes
+    pub available_bytes: Option<u64>,
+}
+
+/// Firmware descriptors found on the host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FirmwareStatus {
+    /// Path to the non secure boot OVMF_VARS
+    pub vars_path: Option<String>,
+    /// Path to the secure boot capable OVMF_CODE
+    pub secure_boot_code_path: Option<String>,
+}
+
+/// virtiofsd installation information
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VirtiofsdStatus {
+    /// Path to the binary
+    pub path: Option<String>,
+    /// Version reported by `virtiofsd --version`
+    pub version: Option<String>,
+    /// Whether `--readonly` is supported
+    pub supports_readonly: bool,
 }
 
 /// libvirt version information
@@ -43,6 +154,13 @@ pub struct LibvirtStatus {
     pub supports_readonly_virtiofs: bool,
     pub domain_count: usize,
     pub running_domain_count: usize,
+    pub uri: Option<String>,
+    pub connection: Option<ConnectionKind>,
+    pub pool: Option<PoolInfo>,
+    pub firmware: FirmwareStatus,
+    pub virtiofsd: VirtiofsdStatus,
+    pub capabilities: Capabilities,
+    pub checks: Vec<StatusCheck>,
 }
 
 /// Parse a version string like "6.2.0" into LibvirtVersion struct
@@ -98,7 +216,7 @@ fn parse_libvirt_version_from_output(version_output: &str) -> Option<LibvirtVers
 
 /// Parse libvirt version from virsh version output
 pub fn parse_libvirt_version() -> Result<Option<LibvirtVersion>> {
-    let output = Command::new("virsh")
+    let output = HostCommand::new("virsh")
         .args(&["version"])
         .output()
         .with_context(|| "Failed to check libvirt version")?;
@@ -124,14 +242,296 @@ pub fn supports_readonly_virtiofs(version: &Option<LibvirtVersion>) -> bool {
     }
 }
 
+/// Parse the storage pool path and free space from `virsh pool-dumpxml` output
+fn parse_pool_xml(xml: &str) -> Result<PoolInfo> {
+    let dom = crate::xml_utils::parse_xml_dom(xml).context("Failed to parse pool XML")?;
+    let path = dom
+        .find("path")
+        .map(|n| n.text_content().trim().to_string())
+        .filter(|p| !p.is_empty())
+        .map(Utf8PathBuf::from);
+    let available_bytes = dom.find("available").and_then(|n| {
+        let value = n.text_content().trim().parse::<u128>().ok()?;
+        let unit = n.attributes.get("unit").map(|s| s.as_str()).unwrap_or("B");
+        u64::try_from(value * super::unit_to_bytes(unit)?).ok()
+    });
+    Ok(PoolInfo {
+        path,
+        available_bytes,
+    })
+}
+
+/// Extract the version from `virtiofsd --version` output, e.g. "virtiofsd backend 1.10.1"
+fn parse_virtiofsd_version(output: &str) -> Option<String> {
+    output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .last()
+        .filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|v| v.to_string())
+}
+
+/// Query the URI virsh connects to
+pub(crate) fn query_uri(global_opts: &super::LibvirtOptions) -> Option<String> {
+    let output = match global_opts.virsh_command().arg("uri").output() {
+        Ok(o) if o.status.success() => o,
+        Ok(o) => {
+            tracing::debug!("virsh uri failed: {}", String::from_utf8_lossy(&o.stderr));
+            return None;
+        }
+        Err(e) => {
+            tracing::debug!("Failed to run virsh uri: {e}");
+            return None;
+        }
+    };
+    let uri = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!uri.is_empty()).then_some(uri)
+}
+
+/// Query the default storage pool; `None` if it does not exist or is inactive
+fn query_pool(global_opts: &super::LibvirtOptions) -> Result<Option<PoolInfo>> {
+    let output = global_opts
+        .virsh_command()
+        .args(&["pool-dumpxml", LIBVIRT_DEFAULT_POOL])
+        .output()
+        .with_context(|| "Failed to query storage pool")?;
+    if !output.status.success() {
+        tracing::debug!(
+            "pool-dumpxml {LIBVIRT_DEFAULT_POOL} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Ok(None);
+    }
+    let xml = std::str::from_utf8(&output.stdout).context("Invalid UTF-8 in pool XML")?;
+    let active = global_opts
+        .virsh_command()
+        .args(&["pool-info", LIBVIRT_DEFAULT_POOL])
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).contains("running"))
+        .unwrap_or(false);
+    if !active {
+        return Ok(None);
+    }
+    parse_pool_xml(xml).map(Some)
+}
+
+/// Look up firmware descriptors
+fn query_firmware() -> FirmwareStatus {
+    let vars_path = super::secureboot::find_ovmf_vars()
+        .inspect_err(|e| tracing::debug!("Failed to find OVMF_VARS: {e}"))
+        .ok()
+        .map(|p| p.to_string());
+    let secure_boot_code_path = super::secureboot::find_secure_boot_firmware()
+        .inspect_err(|e| tracing::debug!("Failed to find secure boot firmware: {e}"))
+        .ok()
+        .map(|fw| fw.code_path.to_string());
+    FirmwareStatus {
+        vars_path,
+        secure_boot_code_path,
+    }
+}
+
+/// Find virtiofsd and query its version and readonly support
+fn query_virtiofsd() -> VirtiofsdStatus {
+    let Some(path) = crate::qemu::find_virtiofsd() else {
+        return VirtiofsdStatus::default();
+    };
+    let version = HostCommand::new(path)
+        .arg("--version")
+        .output()
+        .inspect_err(|e| tracing::debug!("Failed to run {path} --version: {e}"))
+        .ok()
+        .and_then(|o| parse_virtiofsd_version(&String::from_utf8_lossy(&o.stdout)));
+    let supports_readonly = HostCommand::new(path)
+        .arg("--help")
+        .output()
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout).contains("--readonly")
+                || String::from_utf8_lossy(&o.stderr).contains("--readonly")
+        })
+        .unwrap_or(false);
+    VirtiofsdStatus {
+        path: Some(path.to_string()),
+        version,
+        supports_readonly,
+    }
+}
+
+/// Evaluate the gathered information into checks with suggested fixes
+fn evaluate_checks(status: &LibvirtStatus) -> Vec<StatusCheck> {
+    let check = |name: &str, result, detail: String, remediation: Option<&str>| StatusCheck {
+        name: name.to_string(),
+        result,
+        detail,
+        remediation: remediation.map(|s| s.to_string()),
+    };
+    let mut checks = Vec::new();
+
+    checks.push(match &status.version {
+        Some(v) => check("libvirt", CheckResult::Pass, format!("libvirt {}", v.full_version), None),
+        None => check(
+            "libvirt",
+            CheckResult::Fail,
+            "Unable to query libvirt version".to_string(),
+            Some("Install libvirt and start the daemon: systemctl enable --now libvirtd.socket (or virtqemud.socket)"),
+        ),
+    });
+
+    checks.push(match (&status.uri, status.connection) {
+        (Some(uri), Some(ConnectionKind::Session)) => check(
+            "connection",
+            CheckResult::Warn,
+            format!("{uri}: per-user session; only user-mode networking is available"),
+            Some("Use --connect qemu:///system for bridged networks and system-wide storage"),
+        ),
+        (Some(uri), _) => check(
+            "connection",
+            CheckResult::Pass,
+            format!("{uri}: system daemon; requires root or membership in the libvirt group"),
+            None,
+        ),
+        (None, _) => check(
+            "connection",
+            CheckResult::Fail,
+            "Unable to connect to libvirt".to_string(),
+            Some("Check the --connect URI and that your user may access the libvirt socket"),
+        ),
+    });
+
+    checks.push(match &status.pool {
+        None => check(
+            "storage-pool",
+            CheckResult::Warn,
+            format!("Storage pool '{LIBVIRT_DEFAULT_POOL}' is missing or inactive"),
+            Some("It is created on first use by 'bcvk libvirt run', or run: virsh pool-start default"),
+        ),
+        Some(PoolInfo {
+            path,
+            available_bytes: Some(avail),
+        }) if *avail < MIN_POOL_FREE_BYTES => check(
+            "storage-pool",
+            CheckResult::Warn,
+            format!(
+                "{}: only {} free",
+                path.as_ref().map(|p| p.as_str()).unwrap_or(LIBVIRT_DEFAULT_POOL),
+                indicatif::BinaryBytes(*avail)
+            ),
+            Some("Free up space, e.g. with 'bcvk libvirt base-disks prune'"),
+        ),
+        Some(pool) => check(
+            "storage-pool",
+            CheckResult::Pass,
+            format!(
+                "{}: {} free",
+                pool.path.as_ref().map(|p| p.as_str()).unwrap_or(LIBVIRT_DEFAULT_POOL),
+                pool.available_bytes
+                    .map(|b| indicatif::BinaryBytes(b).to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            ),
+            None,
+        ),
+    });
+
+    checks.push(
+        match (
+            &status.firmware.vars_path,
+            &status.firmware.secure_boot_code_path,
+        ) {
+            (_, Some(code)) => check(
+                "firmware",
+                CheckResult::Pass,
+                format!("Secure boot capable firmware: {code}"),
+                None,
+            ),
+            (Some(vars), None) => check(
+                "firmware",
+                CheckResult::Warn,
+                format!("UEFI firmware found ({vars}) but no secure boot variant"),
+                Some("Install the edk2-ovmf (or ovmf) package to use --secure-boot-keys"),
+            ),
+            (None, None) => check(
+                "firmware",
+                CheckResult::Fail,
+                "No UEFI firmware descriptors found".to_string(),
+                Some("Install the edk2-ovmf (or ovmf) package"),
+            ),
+        },
+    );
+
+    checks.push(match &status.virtiofsd.path {
+        Some(path) => check(
+            "virtiofsd",
+            CheckResult::Pass,
+            format!(
+                "{path} ({})",
+                status
+                    .virtiofsd
+                    .version
+                    .as_deref()
+                    .unwrap_or("unknown version")
+            ),
+            None,
+        ),
+        None => check(
+            "virtiofsd",
+            CheckResult::Warn,
+            "virtiofsd not found; --volume and --bind-storage-ro are unavailable".to_string(),
+            Some("Install the virtiofsd package"),
+        ),
+    });
+
+    checks.push(if status.capabilities.readonly_virtiofs {
+        check(
+            "readonly-virtiofs",
+            CheckResult::Pass,
+            "Readonly virtiofs mounts are supported".to_string(),
+            None,
+        )
+    } else {
+        check(
+            "readonly-virtiofs",
+            CheckResult::Warn,
+            "Readonly virtiofs mounts are unsupported; host mounts are writable".to_string(),
+            Some("Upgrade to libvirt 11.0 or newer and a virtiofsd that supports --readonly"),
+        )
+    });
+
+    checks
+}
+
+/// Render the checks as a table
+fn render_table(status: &LibvirtStatus) -> Table {
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL);
+    table.set_header(vec!["CHECK", "RESULT", "DETAIL", "SUGGESTED FIX"]);
+    for check in &status.checks {
+        table.add_row(vec![
+            check.name.as_str(),
+            check.result.as_str(),
+            check.detail.as_str(),
+            check.remediation.as_deref().unwrap_or(""),
+        ]);
+    }
+    table
+}
+
 /// Execute the libvirt status command
-pub fn run(opts: LibvirtStatusOpts) -> Result<()> {
+pub fn run(global_opts: &super::LibvirtOptions, opts: LibvirtStatusOpts) -> Result<()> {
     // Get libvirt version
     let version = parse_libvirt_version()?;
+    let uri = query_uri(global_opts);
+    let connection = uri.as_deref().map(ConnectionKind::from_uri);
+    let pool = query_pool(global_opts)?;
+    let firmware = query_firmware();
+    let virtiofsd = query_virtiofsd();
     let supports_readonly = supports_readonly_virtiofs(&version);
 
     // Get domain count
-    let lister = DomainLister::new();
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
     let all_domains = lister
         .list_all_domains()
         .with_context(|| "Failed to list domains")?;
@@ -146,12 +546,28 @@ pub fn run(opts: LibvirtStatusOpts) -> Result<()> {
         }
     }
 
-    let status = LibvirtStatus {
+    let capabilities = Capabilities {
+        storage_pool: pool.is_some(),
+        secure_boot: firmware.secure_boot_code_path.is_some(),
+        virtiofs: virtiofsd.path.is_some(),
+        readonly_virtiofs: supports_readonly && virtiofsd.supports_readonly,
+        session: connection == Some(ConnectionKind::Session),
+    };
+
+    let mut status = LibvirtStatus {
         version,
         supports_readonly_virtiofs: supports_readonly,
         domain_count: all_domains.len(),
         running_domain_count: running_count,
+        uri,
+        connection,
+        pool,
+        firmware,
+        virtiofsd,
+        capabilities,
+        checks: Vec::new(),
     };
+    status.checks = evaluate_checks(&status);
 
     // Output in requested format
     match opts.format {
@@ -169,6 +585,15 @@ pub fn run(opts: LibvirtStatusOpts) -> Result<()> {
                     .with_context(|| "Failed to serialize status as JSON")?
             );
         }
+        OutputFormat::Table => {
+            println!("{}", render_table(&status));
+            println!(
+                "\n{} domain{} ({} running)",
+                status.domain_count,
+                if status.domain_count == 1 { "" } else { "s" },
+                status.running_domain_count
+            );
+        }
     }
 
     Ok(())
@@ -275,4 +700,107 @@ mod tests {
         });
         assert!(supports_readonly_virtiofs(&version));
     }
+
+    fn status_for_test() -> LibvirtStatus {
+        LibvirtStatus {
+            version: parse_version_string("11.0.0"),
+            supports_readonly_virtiofs: true,
+            domain_count: 0,
+            running_domain_count: 0,
+            uri: Some("qemu:///session".to_string()),
+            connection: Some(ConnectionKind::Session),
+            pool: Some(PoolInfo {
+                path: Some("/var/lib/libvirt/images".into()),
+                available_bytes: Some(1024),
+            }),
+            firmware: FirmwareStatus {
+                vars_path: Some("/usr/share/edk2/ovmf/OVMF_VARS.fd".to_string()),
+                secure_boot_code_path: None,
+            },
+            virtiofsd: VirtiofsdStatus::default(),
+            capabilities: Capabilities {
+                storage_pool: true,
+                secure_boot: false,
+                virtiofs: false,
+                readonly_virtiofs: false,
+                session: true,
+            },
+            checks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_checks() {
+        let status = status_for_test();
+        let results: Vec<_> = evaluate_checks(&status)
+            .into_iter()
+            .map(|c| (c.name, c.result, c.remediation.is_some()))
+            .collect();
+        let expected = [
+            ("libvirt", CheckResult::Pass, false),
+            ("connection", CheckResult::Warn, true),
+            ("storage-pool", CheckResult::Warn, true),
+            ("firmware", CheckResult::Warn, true),
+            ("virtiofsd", CheckResult::Warn, true),
+            ("readonly-virtiofs", CheckResult::Warn, true),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(n, r, f)| (n.to_string(), r, f))
+            .collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_parse_pool_xml() {
+        let xml = r#"<pool type='dir'>
+  <name>default</name>
+  <capacity unit='bytes'>107374182400</capacity>
+  <allocation unit='bytes'>10737418240</allocation>
+  <available unit='bytes'>96636764160</available>
+  <target>
+    <path>/var/lib/libvirt/images</path>
+  </target>
+</pool>"#;
+        let info = parse_pool_xml(xml).unwrap();
+        assert_eq!(
+            info.path.as_deref().map(|p| p.as_str()),
+            Some("/var/lib/libvirt/images")
+        );
+        assert_eq!(info.available_bytes, Some(96636764160));
+
+        let info = parse_pool_xml("<pool><available unit='GiB'>2</available></pool>").unwrap();
+        assert_eq!(info.path, None);
+        assert_eq!(info.available_bytes, Some(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_parse_virtiofsd_version_and_uri() {
+        let cases = [
+            ("virtiofsd backend 1.10.1\n", Some("1.10.1")),
+            ("virtiofsd 1.13.0\nmore\n", Some("1.13.0")),
+            ("usage: virtiofsd\n", None),
+            ("", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_virtiofsd_version(input).as_deref(),
+                expected,
+                "{input}"
+            );
+        }
+
+        assert_eq!(
+            ConnectionKind::from_uri("qemu:///session\n"),
+            ConnectionKind::Session
+        );
+        assert_eq!(
+            ConnectionKind::from_uri("qemu:///system"),
+            ConnectionKind::System
+        );
+        assert_eq!(
+            ConnectionKind::from_uri("qemu+ssh://host/system"),
+            ConnectionKind::System
+        );
+    }
 }
@@ -1,70 +1,235 @@
-//! libvirt stop command - stop a running bootc domain
+//! libvirt stop command - stop running bootc domains
 //!
 //! This module provides functionality to stop running libvirt domains
-//! that were created from bootc container images.
+//! that were created from bootc container images, either by name or all
+//! at once with optional label filtering. Domains are shut down gracefully
+//! and forcefully stopped if they do not power off within the timeout.
+
+use std::time::{Duration, Instant};
 
 use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
 
-/// Options for stopping a libvirt domain
+use crate::domain_list::{DomainLister, PodmanBootcDomain};
+
+/// Interval between checks whether the domains have shut down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options for stopping libvirt domains
 #[derive(Debug, Parser)]
 pub struct LibvirtStopOpts {
     /// Name of the domain to stop
-    pub name: String,
+    #[clap(
+        required_unless_present = "all",
+        conflicts_with = "all",
+        add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names)
+    )]
+    pub name: Option<String>,
+
+    /// Stop all running bcvk domains
+    #[clap(long)]
+    pub all: bool,
+
+    /// With --all, only stop domains with this label (e.g. ci-run=1234); may be repeated, in which case domains must have all of them
+    #[clap(long, requires = "all")]
+    pub label: Vec<String>,
 
     /// Force stop the domain
     #[clap(long, short = 'f')]
     pub force: bool,
 
-    /// Timeout in seconds for graceful shutdown
+    /// Timeout in seconds for graceful shutdown, after which the domain is forcefully stopped
     #[clap(long, default_value = "60")]
     pub timeout: u32,
 }
 
+/// Whether `domain` has all of `labels`
+fn has_labels(domain: &PodmanBootcDomain, labels: &[String]) -> bool {
+    labels.iter().all(|l| domain.labels.contains(l))
+}
+
 /// Execute the libvirt stop command
 pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtStopOpts) -> Result<()> {
-    use crate::domain_list::DomainLister;
-    use color_eyre::eyre::Context;
-
     let connect_uri = global_opts.connect.as_ref();
     let lister = match connect_uri {
         Some(uri) => DomainLister::with_connection(uri.clone()),
         None => DomainLister::new(),
     };
 
-    // Check if domain exists and get its state
-    let state = lister
-        .get_domain_state(&opts.name)
-        .map_err(|_| color_eyre::eyre::eyre!("VM '{}' not found", opts.name))?;
+    let names = if let Some(name) = opts.name.as_deref() {
+        // Check if domain exists and get its state
+        let state = lister
+            .get_domain_state(name)
+            .map_err(|_| eyre!("VM '{}' not found", name))?;
 
-    if state != "running" {
-        println!("VM '{}' is already stopped (state: {})", opts.name, state);
-        return Ok(());
-    }
-
-    println!("🛑 Stopping VM '{}'...", opts.name);
-
-    // Use virsh to stop the domain
-    let mut cmd = global_opts.virsh_command();
-    if opts.force {
-        cmd.args(&["destroy", &opts.name]);
+        if state != "running" {
+            println!("VM '{}' is already stopped (state: {})", name, state);
+            return Ok(());
+        }
+        vec![name.to_owned()]
     } else {
-        cmd.args(&["shutdown", &opts.name]);
-    }
+        let mut domains = lister
+            .list_running_bootc_domains()
+            .with_context(|| "Failed to list bootc domains from libvirt")?;
+        domains.retain(|d| has_labels(d, &opts.label));
+        if domains.is_empty() {
+            if opts.label.is_empty() {
+                println!("No running VMs found");
+            } else {
+                println!(
+                    "No running VMs found with label '{}'",
+                    opts.label.join(", ")
+                );
+            }
+            return Ok(());
+        }
+        domains.into_iter().map(|d| d.name).collect()
+    };
+
+    let timeout = Duration::from_secs(opts.timeout.into());
+    stop_domains(global_opts, &lister, &names, opts.force, timeout)
+}
 
-    let output = cmd
+/// Run `virsh <action> <name>`
+fn virsh(global_opts: &crate::libvirt::LibvirtOptions, action: &str, name: &str) -> Result<()> {
+    let output = global_opts
+        .virsh_command()
+        .args(&[action, name])
         .output()
         .with_context(|| "Failed to run virsh command")?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(color_eyre::eyre::eyre!(
-            "Failed to stop VM '{}': {}",
-            opts.name,
-            stderr
+        return Err(eyre!("Failed to stop VM '{}': {}", name, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// Stop the running domains `names`
+///
+/// All domains are asked to shut down at once, and the ones which are still
+/// running after `timeout` are destroyed. Domains which failed to stop are
+/// reported at the end.
+fn stop_domains(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    names: &[String],
+    force: bool,
+    timeout: Duration,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut pending = Vec::new();
+    for name in names {
+        println!("🛑 Stopping VM '{}'...", name);
+        let action = if force { "destroy" } else { "shutdown" };
+        match virsh(global_opts, action, name) {
+            Ok(()) if force => println!("VM '{}' stopped successfully", name),
+            Ok(()) => pending.push(name.as_str()),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if !pending.is_empty() && !crate::hostexec::dry_run() {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Transient domains disappear when they shut down
+            pending.retain(|name| match lister.get_domain_state(name) {
+                Ok(state) if state == "running" => true,
+                _ => {
+                    println!("VM '{}' stopped successfully", name);
+                    false
+                }
+            });
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        for name in pending {
+            println!(
+                "VM '{}' did not shut down within {}s, forcing it off",
+                name,
+                timeout.as_secs()
+            );
+            match virsh(global_opts, "destroy", name) {
+                Ok(()) => println!("VM '{}' stopped successfully", name),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+
+    match errors.len() {
+        0 => Ok(()),
+        1 if names.len() == 1 => Err(errors.remove(0)),
+        n => {
+            for e in &errors {
+                eprintln!("{e}");
+            }
+            Err(eyre!("Failed to stop {} of {} VMs", n, names.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostexec::{with_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_has_labels() {
+        let domain = PodmanBootcDomain {
+            name: "test-vm".into(),
+            state: "running".into(),
+            image: None,
+            image_digest: None,
+            created: None,
+            memory_mb: None,
+            vcpus: None,
+            disk_path: None,
+            labels: vec!["ci-run=1234".into(), "bcvk-integration".into()],
+            ssh_port: None,
+            has_ssh_key: false,
+            ssh_private_key: None,
+            ssh_private_key_file: None,
+        };
+        assert!(has_labels(&domain, &[]));
+        assert!(has_labels(&domain, &["ci-run=1234".into()]));
+        assert!(has_labels(
+            &domain,
+            &["bcvk-integration".into(), "ci-run=1234".into()]
+        ));
+        assert!(!has_labels(&domain, &["ci-run=5678".into()]));
+        assert!(!has_labels(
+            &domain,
+            &["ci-run=1234".into(), "other".into()]
         ));
     }
 
-    println!("VM '{}' stopped successfully", opts.name);
-    Ok(())
+    #[test]
+    fn test_stop_domains() {
+        let global_opts = crate::libvirt::LibvirtOptions { connect: None };
+        let lister = DomainLister::new();
+        let names = ["a".to_owned(), "b".to_owned()];
+        let run = |force| {
+            let recorder = Arc::new(RecordingExecutor::default());
+            with_executor(recorder.clone(), || {
+                stop_domains(&global_opts, &lister, &names, force, Duration::ZERO)
+            })
+            .unwrap();
+            recorder
+                .commands()
+                .into_iter()
+                .map(|c| c[1..].join(" "))
+                .collect::<Vec<_>>()
+        };
+
+        // All domains are shut down before waiting for any of them
+        assert_eq!(
+            run(false),
+            ["shutdown a", "shutdown b", "domstate a", "domstate b"]
+        );
+        assert_eq!(run(true), ["destroy a", "destroy b"]);
+    }
 }
@@ -0,0 +1,298 @@
+//! libvirt top command - refreshing resource usage of all bootc domains
+//!
+//! Every interval, `virsh domstats` is run once for all running domains, and
+//! the bootc domains among them are shown in a table similar to `podman
+//! stats`. CPU usage and block and network I/O rates are computed from the
+//! previous sample of each domain, so they appear after one interval.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+
+use super::metrics::{cpu_percent, split_domstats, DomainMetrics, DOMSTATS_ARGS};
+use crate::domain_list::DomainLister;
+
+/// Clears the terminal and moves the cursor to the top left
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Options for showing the resource usage of all bootc domains
+#[derive(Debug, Parser)]
+pub struct LibvirtTopOpts {
+    /// Seconds between refreshes; rates are averaged over this interval
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    /// Print a single table after one interval and exit
+    #[clap(long)]
+    pub no_stream: bool,
+
+    /// Column to sort the domains by, largest first
+    #[clap(long, value_enum, default_value_t = SortKey::Cpu)]
+    pub sort: SortKey,
+}
+
+/// Columns the table can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// CPU usage
+    Cpu,
+    /// Resident memory of the QEMU process
+    Memory,
+    /// Block I/O rate, reads and writes
+    Disk,
+    /// Network I/O rate, received and transmitted
+    Net,
+    /// Domain name, in alphabetical order
+    Name,
+}
+
+/// Resource usage of one domain since its previous sample
+#[derive(Debug, Default, PartialEq)]
+struct Row {
+    name: String,
+    cpu_percent: Option<f64>,
+    rss_bytes: Option<u64>,
+    memory_bytes: Option<u64>,
+    /// Bytes per second read from and written to block devices, and
+    /// received and transmitted on network interfaces
+    rates: Option<[f64; 4]>,
+}
+
+/// Cumulative block read, block write, network rx and network tx bytes
+fn io_totals(m: &DomainMetrics) -> [u64; 4] {
+    [
+        m.block.iter().map(|b| b.rd_bytes).sum(),
+        m.block.iter().map(|b| b.wr_bytes).sum(),
+        m.net.iter().map(|n| n.rx_bytes).sum(),
+        m.net.iter().map(|n| n.tx_bytes).sum(),
+    ]
+}
+
+impl Row {
+    fn new(cur: &DomainMetrics, prev: Option<&DomainMetrics>, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        // Counters going backwards mean the domain was restarted
+        let rates = prev.filter(|_| secs > 0.0).and_then(|prev| {
+            let (prev, cur) = (io_totals(prev), io_totals(cur));
+            let mut rates = [0.0; 4];
+            for (rate, (p, c)) in rates.iter_mut().zip(prev.into_iter().zip(cur)) {
+                *rate = c.checked_sub(p)? as f64 / secs;
+            }
+            Some(rates)
+        });
+        Self {
+            name: cur.domain.clone(),
+            cpu_percent: cpu_percent(prev.and_then(|p| p.cpu_time_ns), cur.cpu_time_ns, elapsed),
+            rss_bytes: cur.memory_rss_bytes,
+            memory_bytes: cur.balloon.current_bytes,
+            rates,
+        }
+    }
+
+    /// Value to sort by, unknown values last
+    fn sort_value(&self, key: SortKey) -> f64 {
+        let rates = |i: usize| self.rates.map(|r| r[i] + r[i + 1]);
+        match key {
+            SortKey::Cpu => self.cpu_percent,
+            SortKey::Memory => self.rss_bytes.map(|v| v as f64),
+            SortKey::Disk => rates(0),
+            SortKey::Net => rates(2),
+            SortKey::Name => None,
+        }
+        .unwrap_or(-1.0)
+    }
+}
+
+fn sort_rows(rows: &mut [Row], key: SortKey) {
+    rows.sort_by(|a, b| {
+        b.sort_value(key)
+            .total_cmp(&a.sort_value(key))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+fn render(rows: &[Row]) -> String {
+    let width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0).max(4);
+    let bytes = |v: Option<u64>| v.map_or_else(|| "-".to_owned(), |v| HumanBytes(v).to_string());
+    let rate = |r: Option<f64>| {
+        r.map_or_else(|| "-".to_owned(), |r| format!("{}/s", HumanBytes(r as u64)))
+    };
+    let mut out = format!(
+        "{:<width$} {:>6} {:>11} {:>11} {:>12} {:>12} {:>12} {:>12}\n",
+        "NAME", "CPU%", "RSS", "MEMORY", "BLK-RD", "BLK-WR", "NET-RX", "NET-TX"
+    );
+    for row in rows {
+        let r = |i: usize| rate(row.rates.map(|r| r[i]));
+        out.push_str(&format!(
+            "{:<width$} {:>6} {:>11} {:>11} {:>12} {:>12} {:>12} {:>12}\n",
+            row.name,
+            row.cpu_percent
+                .map_or_else(|| "-".to_owned(), |p| format!("{p:.1}")),
+            bytes(row.rss_bytes),
+            bytes(row.memory_bytes),
+            r(0),
+            r(1),
+            r(2),
+            r(3),
+        ));
+    }
+    out
+}
+
+/// Sample all running bootc domains
+///
+/// Whether a domain is a bootc domain is looked up once and remembered in
+/// `bootc`, so that each refresh only runs `virsh domstats`.
+fn sample_all(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    bootc: &mut HashMap<String, bool>,
+) -> Result<BTreeMap<String, DomainMetrics>> {
+    let output = global_opts
+        .virsh_command()
+        .args(DOMSTATS_ARGS)
+        .arg("--list-running")
+        .output()
+        .with_context(|| "Failed to run virsh domstats")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("Failed to get domain statistics: {}", stderr.trim()));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| "Invalid UTF-8 in virsh domstats output")?;
+
+    let mut samples = BTreeMap::new();
+    for (name, section) in split_domstats(&stdout) {
+        let is_bootc = *bootc.entry(name.to_owned()).or_insert_with(|| {
+            lister
+                .get_domain_xml(name)
+                .is_ok_and(|dom| lister.is_podman_bootc_domain(name, &dom))
+        });
+        if !is_bootc {
+            continue;
+        }
+        // The domain may have stopped in the meantime
+        if let Ok(m) = DomainMetrics::from_domstats(name, section) {
+            samples.insert(name.to_owned(), m);
+        }
+    }
+    Ok(samples)
+}
+
+/// Execute the libvirt top command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtTopOpts) -> Result<()> {
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let interval = Duration::from_secs(opts.interval);
+    let refresh = std::io::stdout().is_terminal() && !opts.no_stream;
+    let mut bootc = HashMap::new();
+    let mut prev = sample_all(global_opts, &lister, &mut bootc)?;
+    let mut prev_time = Instant::now();
+
+    loop {
+        std::thread::sleep(interval);
+        let cur = sample_all(global_opts, &lister, &mut bootc)?;
+        let now = Instant::now();
+        let mut rows: Vec<Row> = cur
+            .values()
+            .map(|m| Row::new(m, prev.get(&m.domain), now - prev_time))
+            .collect();
+        sort_rows(&mut rows, opts.sort);
+
+        let mut stdout = std::io::stdout().lock();
+        if refresh {
+            write!(stdout, "{CLEAR_SCREEN}")?;
+        }
+        writeln!(
+            stdout,
+            "{} - {} running bootc domain(s), refreshing every {}s\n",
+            chrono::Local::now().format("%H:%M:%S"),
+            rows.len(),
+            opts.interval
+        )?;
+        write!(stdout, "{}", render(&rows))?;
+        if opts.no_stream {
+            return Ok(());
+        }
+        if !refresh {
+            writeln!(stdout)?;
+        }
+        stdout.flush()?;
+        prev = cur;
+        prev_time = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libvirt::metrics::{BlockStats, NetStats};
+
+    fn metrics(name: &str, cpu_time_ns: u64, io: u64) -> DomainMetrics {
+        DomainMetrics {
+            timestamp: chrono::Utc::now(),
+            domain: name.to_owned(),
+            cpu_time_ns: Some(cpu_time_ns),
+            cpu_percent: None,
+            vcpus: Some(2),
+            memory_rss_bytes: Some(io),
+            balloon: Default::default(),
+            block: vec![BlockStats {
+                name: "vda".into(),
+                rd_bytes: io,
+                wr_bytes: 2 * io,
+                ..Default::default()
+            }],
+            net: vec![NetStats {
+                name: "vnet0".into(),
+                rx_bytes: 3 * io,
+                tx_bytes: 4 * io,
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_row() {
+        let second = Duration::from_secs(1);
+        let prev = metrics("a", 0, 1000);
+        let cur = metrics("a", 1_500_000_000, 3000);
+        let row = Row::new(&cur, Some(&prev), 2 * second);
+        assert_eq!(row.cpu_percent, Some(75.0));
+        assert_eq!(row.rates, Some([1000.0, 2000.0, 3000.0, 4000.0]));
+
+        // No previous sample, or counters reset by a restart
+        assert_eq!(Row::new(&cur, None, second).rates, None);
+        assert_eq!(Row::new(&prev, Some(&cur), second).rates, None);
+    }
+
+    #[test]
+    fn test_sort_rows() {
+        let row = |name: &str, cpu: Option<f64>, rss: u64| Row {
+            name: name.into(),
+            cpu_percent: cpu,
+            rss_bytes: Some(rss),
+            ..Default::default()
+        };
+        let mut rows = vec![
+            row("b", Some(5.0), 300),
+            row("c", None, 200),
+            row("a", Some(90.0), 100),
+            row("d", Some(5.0), 400),
+        ];
+        let names = |rows: &[Row]| rows.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+        sort_rows(&mut rows, SortKey::Cpu);
+        assert_eq!(names(&rows), ["a", "b", "d", "c"]);
+        sort_rows(&mut rows, SortKey::Memory);
+        assert_eq!(names(&rows), ["d", "b", "c", "a"]);
+        sort_rows(&mut rows, SortKey::Name);
+        assert_eq!(names(&rows), ["a", "b", "c", "d"]);
+    }
+}
@@ -0,0 +1,457 @@
+//! libvirt update command - move a bootc domain to a newer image
+//!
+//! There are two ways to update a VM:
+//!
+//! - In place (the default): `bootc upgrade`, or `bootc switch` with
+//!   `--image`, runs in the guest over SSH, which pulls the image from its
+//!   registry. If this staged a new deployment, the domain is restarted into
+//!   it. bootc keeps the previous deployment for `bootc rollback`.
+//! - `--rebuild`: a base disk is installed from the image in local container
+//!   storage, and the VM disk is replaced by a fresh overlay on top of it,
+//!   discarding all changes made in the VM. The previous VM disk is kept as
+//!   `<domain>.pre-update-<timestamp>.qcow2` in the storage pool, unless
+//!   `--no-snapshot` is given.
+//!
+//! Either way the `bootc:source-image` and `bootc:image-digest` metadata of
+//! the domain are updated.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use camino::Utf8Path;
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::domain_list::{DomainLister, PodmanBootcDomain};
+use crate::install_options::InstallOptions;
+use crate::libvirt::base_disks::CloneMode;
+use crate::libvirt::domain_metadata::{self, DomainMetadata};
+
+/// How often to check whether the domain has shut down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options for updating a libvirt domain to a new image
+#[derive(Debug, Parser)]
+pub struct LibvirtUpdateOpts {
+    /// Name of the domain to update
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub name: String,
+
+    /// Container image to switch to (defaults to the image the VM runs)
+    #[clap(long, add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
+    pub image: Option<String>,
+
+    /// Replace the VM disk with a fresh install of the image from local container storage,
+    /// instead of updating in the guest
+    #[clap(long)]
+    pub rebuild: bool,
+
+    /// Don't keep the previous VM disk when rebuilding
+    #[clap(long, requires = "rebuild")]
+    pub no_snapshot: bool,
+
+    /// Seconds to wait for the VM to shut down before restarting it
+    #[clap(long, default_value = "60")]
+    pub timeout: u64,
+}
+
+/// The subset of `bootc status --format=json` we need
+#[derive(Debug, Deserialize)]
+struct BootcHost {
+    status: BootcHostStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct BootcHostStatus {
+    staged: Option<BootEntry>,
+    booted: Option<BootEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BootEntry {
+    image: Option<ImageStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageStatus {
+    image: ImageReference,
+    image_digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageReference {
+    image: String,
+}
+
+/// Parse `bootc status --format=json`, returning the image reference and
+/// digest of the staged deployment, if any
+fn staged_image(status: &[u8]) -> Result<Option<(String, String)>> {
+    let host: BootcHost = serde_json::from_slice(status).context("Parsing bootc status")?;
+    Ok(host
+        .status
+        .staged
+        .and_then(|e| e.image)
+        .map(|i| (i.image.image, i.image_digest)))
+}
+
+/// Parse `bootc status --format=json`, returning the image reference and
+/// digest of the booted deployment
+pub(super) fn booted_image(status: &[u8]) -> Result<(String, String)> {
+    let host: BootcHost = serde_json::from_slice(status).context("Parsing bootc status")?;
+    host.status
+        .booted
+        .and_then(|e| e.image)
+        .map(|i| (i.image.image, i.image_digest))
+        .ok_or_else(|| eyre!("No container image is booted"))
+}
+
+/// Set the source image and digest in the bcvk metadata of domain XML,
+/// upgrading the metadata to the current schema
+///
+/// With `new_disk`, the pinned SSH host keys are dropped, as the guest
+/// generates new ones on the first boot of the new disk.
+fn set_image_metadata(xml: &str, image: &str, digest: &str, new_disk: bool) -> Result<String> {
+    let mut metadata =
+        DomainMetadata::from_xml(xml)?.ok_or_else(|| eyre!("No bcvk metadata in domain XML"))?;
+    metadata.schema_version = domain_metadata::SCHEMA_VERSION;
+    metadata.source_image = Some(image.to_owned());
+    metadata.image_digest = Some(digest.to_owned());
+    metadata.pinned_digest = crate::images::pinned_digest(image).map(ToOwned::to_owned);
+    if new_disk {
+        metadata.ssh_host_keys.clear();
+    }
+    metadata.replace_in_xml(xml)
+}
+
+fn ssh_opts(name: &str, command: &[&str]) -> super::ssh::LibvirtSshOpts {
+    super::ssh::LibvirtSshOpts {
+        domain_name: name.to_owned(),
+        user: Some("root".to_owned()),
+        command: command.iter().map(|s| s.to_string()).collect(),
+        no_strict: false,
+        timeout: 30,
+        log_level: "ERROR".to_string(),
+        extra_options: vec![],
+        suppress_output: false,
+        wait: None,
+    }
+}
+
+/// Update the image metadata in the persistent definition of the domain
+fn define_with_image(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    name: &str,
+    image: &str,
+    digest: &str,
+    new_disk: bool,
+) -> Result<()> {
+    let xml = super::start::inactive_domain_xml(global_opts, name)?;
+    let xml = set_image_metadata(&xml, image, digest, new_disk)?;
+    super::start::define_domain_xml(global_opts, &xml)
+}
+
+/// Shut the domain down gracefully and wait until it is off
+pub(super) fn shutdown_and_wait(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    name: &str,
+    timeout: Duration,
+) -> Result<()> {
+    println!("Shutting down VM '{name}'...");
+    super::run::run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["shutdown", name],
+        "Failed to shut down VM",
+    )?;
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
+    let deadline = Instant::now() + timeout;
+    while lister.get_domain_state(name)? == "running" {
+        if Instant::now() > deadline {
+            return Err(eyre!(
+                "VM '{name}' did not shut down within {}s; stop it with 'bcvk libvirt stop --force {name}' and retry",
+                timeout.as_secs()
+            ));
+        }
+        std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+pub(super) fn start(global_opts: &crate::libvirt::LibvirtOptions, name: &str) -> Result<()> {
+    super::run::run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["start", name],
+        "Failed to start VM",
+    )?;
+    println!("VM '{name}' started");
+    Ok(())
+}
+
+/// Update in the guest with `bootc upgrade` or `bootc switch`
+fn update_in_guest(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    opts: &LibvirtUpdateOpts,
+    domain: &PodmanBootcDomain,
+    image: &str,
+) -> Result<()> {
+    let name = &domain.name;
+    if !domain.is_running() {
+        return Err(eyre!(
+            "VM '{name}' must be running to update it in place; start it, or use --rebuild"
+        ));
+    }
+
+    let command = if opts.image.is_some() {
+        vec!["bootc", "switch", image]
+    } else {
+        vec!["bootc", "upgrade"]
+    };
+    println!("Running '{}' in VM '{name}'...", command.join(" "));
+    super::ssh::run_ssh_impl(global_opts, ssh_opts(name, &command))?;
+
+    let status = super::ssh::run_ssh_output(
+        global_opts,
+        &ssh_opts(name, &["bootc", "status", "--format=json"]),
+    )?;
+    let Some((staged_image, staged_digest)) = staged_image(&status)? else {
+        println!("VM '{name}' is already up to date");
+        return Ok(());
+    };
+    debug!("Staged {staged_image}@{staged_digest}");
+
+    define_with_image(global_opts, name, &staged_image, &staged_digest, false)?;
+    // A full restart, unlike a reboot, makes the updated definition current
+    shutdown_and_wait(global_opts, lister, name, Duration::from_secs(opts.timeout))?;
+    start(global_opts, name)?;
+    crate::events::record(crate::events::EventKind::VmUpdated {
+        name: name.clone(),
+        image: staged_image,
+    });
+    Ok(())
+}
+
+/// Rebuild the VM disk from a new base disk
+fn rebuild(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    lister: &DomainLister,
+    opts: &LibvirtUpdateOpts,
+    domain: &PodmanBootcDomain,
+    image: &str,
+) -> Result<()> {
+    let name = &domain.name;
+    let connect_uri = global_opts.connect.as_deref();
+    let dom = lister.get_domain_xml(name)?;
+    let metadata = DomainMetadata::from_dom(&dom)?.unwrap_or_default();
+    if metadata.disk_image.is_some() {
+        return Err(eyre!(
+            "VM '{name}' was created from a disk image and can't be rebuilt from a container image"
+        ));
+    }
+    if dom.find("transient").is_some() {
+        return Err(eyre!(
+            "VM '{name}' has a transient disk; recreate it to use a new image"
+        ));
+    }
+    let disk_path = domain
+        .disk_path
+        .as_deref()
+        .map(Utf8Path::new)
+        .ok_or_else(|| eyre!("VM '{name}' has no disk"))?;
+    if disk_path.file_name() != Some(format!("{name}.qcow2").as_str()) {
+        return Err(eyre!(
+            "Disk {disk_path} of VM '{name}' was not created by bcvk and can't be rebuilt"
+        ));
+    }
+
+    let digest = crate::images::inspect(image)?.digest.to_string();
+    if domain.image.as_deref() == Some(image)
+        && metadata.image_digest.as_deref() == Some(digest.as_str())
+    {
+        println!("VM '{name}' is already up to date ({digest})");
+        return Ok(());
+    }
+
+    // Domains created before the clone mode was recorded have a backing file
+    let clone_mode = metadata
+        .clone_mode
+        .as_deref()
+        .map(|m| CloneMode::from_str(m, false).map_err(|e| eyre!("Invalid clone mode '{m}': {e}")))
+        .transpose()?
+        .unwrap_or(CloneMode::Backing);
+    // The new disk goes to the pool the VM was created in
+    let pool = metadata
+        .pool
+        .unwrap_or_else(|| super::LIBVIRT_DEFAULT_POOL.to_owned());
+    // The base disk is looked up by its install options; only the
+    // filesystem, kernel arguments and SELinux mode are recorded for the VM
+    let install = InstallOptions {
+        filesystem: metadata.filesystem,
+        karg: metadata.kargs,
+        selinux: metadata.selinux.as_deref().map(str::parse).transpose()?,
+        ..Default::default()
+    };
+    let base_disk =
+        super::base_disks::find_or_create_base_disk(image, &digest, &install, connect_uri, &pool)
+            .with_context(|| "Failed to find or create base disk")?;
+    println!("Using base disk image: {base_disk}");
+
+    let was_running = domain.is_running();
+    if was_running {
+        shutdown_and_wait(global_opts, lister, name, Duration::from_secs(opts.timeout))?;
+    }
+
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!(
+            "replace {disk_path} with a clone of {base_disk} ({clone_mode})"
+        ));
+    } else {
+        if !opts.no_snapshot {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .context("Invalid system time")?
+                .as_secs();
+            let snapshot = disk_path.with_file_name(format!("{name}.pre-update-{timestamp}.qcow2"));
+            std::fs::rename(disk_path, &snapshot)
+                .with_context(|| format!("Failed to move {disk_path} to {snapshot}"))?;
+            // Make libvirt track the snapshot instead of the moved volume
+            super::run::run_virsh_cmd(
+                connect_uri,
+                &["pool-refresh", &pool],
+                "Failed to refresh libvirt storage pool",
+            )?;
+            println!("Previous disk kept as {snapshot}");
+        }
+        super::base_disks::clone_from_base(&base_disk, name, connect_uri, &pool, clone_mode)
+            .with_context(|| "Failed to clone VM disk from base")?;
+    }
+
+    define_with_image(global_opts, name, image, &digest, true)?;
+    if was_running {
+        start(global_opts, name)?;
+    }
+    crate::events::record(crate::events::EventKind::VmUpdated {
+        name: name.clone(),
+        image: image.to_owned(),
+    });
+    println!("VM '{name}' updated to {image} ({digest})");
+    Ok(())
+}
+
+/// Execute the libvirt update command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtUpdateOpts) -> Result<()> {
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let domain = lister
+        .get_domain_info(&opts.name)
+        .map_err(|_| eyre!("VM '{}' not found", opts.name))?;
+    let image = match (&opts.image, &domain.image) {
+        (Some(image), _) | (None, Some(image)) => image.clone(),
+        (None, None) => {
+            return Err(eyre!(
+                "VM '{}' has no source image in its metadata; specify one with --image",
+                opts.name
+            ))
+        }
+    };
+
+    if opts.rebuild {
+        rebuild(global_opts, &lister, &opts, &domain, &image)
+    } else {
+        update_in_guest(global_opts, &lister, &opts, &domain, &image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN_XML: &str = r#"<domain type="kvm">
+  <name>test</name>
+  <metadata>
+    <bootc:container xmlns:bootc="https://github.com/containers/bootc">
+      <bootc:source-image>quay.io/fedora/fedora-bootc:41</bootc:source-image>
+      <bootc:ssh-port>2222</bootc:ssh-port>
+    </bootc:container>
+  </metadata>
+</domain>"#;
+
+    #[test]
+    fn test_set_image_metadata() -> Result<()> {
+        let xml = set_image_metadata(
+            DOMAIN_XML,
+            "quay.io/fedora/fedora-bootc:42",
+            "sha256:abcd",
+            false,
+        )?;
+        let metadata = DomainMetadata::from_xml(&xml)?.unwrap();
+        assert_eq!(
+            metadata.source_image.as_deref(),
+            Some("quay.io/fedora/fedora-bootc:42")
+        );
+        assert_eq!(metadata.image_digest.as_deref(), Some("sha256:abcd"));
+        assert_eq!(metadata.ssh_port, Some(2222));
+        assert!(!metadata.needs_migration());
+        assert!(xml.starts_with("<domain type=\"kvm\">\n  <name>test</name>"));
+
+        assert!(set_image_metadata("<domain/>", "x", "y", false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_and_booted_image() -> Result<()> {
+        let status = br#"{
+            "apiVersion": "org.containers.bootc/v1",
+            "kind": "BootcHost",
+            "spec": {"image": {"image": "quay.io/example/os:latest", "transport": "registry"}},
+            "status": {
+                "staged": {
+                    "image": {
+                        "image": {"image": "quay.io/example/os:latest", "transport": "registry"},
+                        "version": "42.20250101.0",
+                        "timestamp": null,
+                        "imageDigest": "sha256:1234"
+                    },
+                    "pinned": false
+                },
+                "booted": {
+                    "image": {
+                        "image": {"image": "quay.io/example/os:latest", "transport": "registry"},
+                        "version": "42.20241201.0",
+                        "timestamp": null,
+                        "imageDigest": "sha256:5678"
+                    },
+                    "pinned": false
+                },
+                "rollback": null
+            }
+        }"#;
+        assert_eq!(
+            staged_image(status)?,
+            Some((
+                "quay.io/example/os:latest".to_string(),
+                "sha256:1234".to_string()
+            ))
+        );
+
+        assert_eq!(
+            booted_image(status)?,
+            (
+                "quay.io/example/os:latest".to_string(),
+                "sha256:5678".to_string()
+            )
+        );
+
+        let status = br#"{"status": {"staged": null, "booted": {"image": null}}}"#;
+        assert_eq!(staged_image(status)?, None);
+        assert!(booted_image(status).is_err());
+        Ok(())
+    }
+}
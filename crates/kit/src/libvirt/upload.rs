@@ -2,22 +2,27 @@
 //!
 //! This module provides functionality to upload disk images created by to-disk
 //! to libvirt storage pools, maintaining container image metadata as libvirt annotations.
+//!
+//! The disk is installed in the format of the volume directly, so no
+//! converted copy is needed locally, and streamed to the pool with
+//! `virsh vol-upload`, which also works for pools on remote hosts.
 
 use crate::common_opts::MemoryOpts;
+use crate::hostexec::HostCommand;
 use crate::install_options::InstallOptions;
-use crate::to_disk::{run as to_disk, ToDiskAdditionalOpts, ToDiskOpts};
+use crate::to_disk::{run as to_disk, Format, ToDiskAdditionalOpts, ToDiskOpts};
 use crate::{images, utils};
 use camino::Utf8PathBuf;
 use clap::Parser;
 use color_eyre::{eyre::eyre, Result};
 use std::path::Path;
-use std::process::Command;
 use tracing::debug;
 
 /// Configuration options for uploading a bootc disk image to libvirt
 #[derive(Debug, Parser, Clone)]
 pub struct LibvirtUploadOpts {
     /// Container image to install and upload
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
     pub source_image: String,
 
     /// Name for the libvirt volume (defaults to sanitized image name)
@@ -25,13 +30,21 @@ pub struct LibvirtUploadOpts {
     pub volume_name: Option<String>,
 
     /// Libvirt storage pool name
-    #[clap(long, default_value = "default")]
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
     pub pool: String,
 
     /// Size of the disk image (e.g., '20G', '10240M'). If not specified, uses the actual size of the created disk.
     #[clap(long)]
     pub disk_size: Option<String>,
 
+    /// Format of the volume; the disk is installed in it directly rather than converted
+    #[clap(long, value_enum, default_value_t = Format::Raw)]
+    pub format: Format,
+
+    /// Upload only the allocated data of the disk, keeping the volume sparse
+    #[clap(long)]
+    pub sparse: bool,
+
     /// Installation options (filesystem, root-size, storage-path)
     #[clap(flatten)]
     pub install: InstallOptions,
@@ -46,7 +59,7 @@ pub struct LibvirtUploadOpts {
 
 impl LibvirtUploadOpts {
     /// Build a virsh command with optional connection URI  
-    fn virsh_command(&self, global_opts: &crate::libvirt::LibvirtOptions) -> Command {
+    fn virsh_command(&self, global_opts: &crate::libvirt::LibvirtOptions) -> HostCommand {
         global_opts.virsh_command()
     }
 
@@ -56,14 +69,15 @@ impl LibvirtUploadOpts {
             return name.clone();
         }
 
-        // Sanitize the image name for use as a volume name
-        let image_name = self.source_image.clone();
+        // Sanitize the image name for use as a volume name; a tag is kept,
+        // a digest is not
+        let image_name = crate::images::without_digest(&self.source_image);
 
         // Remove registry prefix if present
         let name = image_name
             .split('/')
             .last()
-            .unwrap_or(&image_name)
+            .unwrap_or(image_name)
             .replace(':', "-")
             .replace('/', "-")
             .replace('.', "-");
@@ -128,7 +142,7 @@ impl LibvirtUploadOpts {
         self.check_pool_exists(global_opts)?;
 
         let volume_name = self.get_cached_volume_name(image_digest);
-        let volume_path = format!("{}.raw", volume_name);
+        let volume_path = format!("{}.{}", volume_name, self.format);
 
         // Delete existing volume if it exists
         let _ = self
@@ -145,7 +159,7 @@ impl LibvirtUploadOpts {
                 &volume_path,
                 &disk_size_bytes.to_string(),
                 "--format",
-                "raw",
+                self.format.as_str(),
             ])
             .output()?;
 
@@ -156,16 +170,18 @@ impl LibvirtUploadOpts {
 
         // Upload the disk image to the volume
         debug!("Uploading disk image to volume '{}'", volume_path);
-        let output = self
-            .virsh_command(global_opts)
-            .args(&[
-                "vol-upload",
-                &volume_path,
-                disk_path.to_str().unwrap(),
-                "--pool",
-                &self.pool,
-            ])
-            .output()?;
+        let mut cmd = self.virsh_command(global_opts);
+        cmd.args(&[
+            "vol-upload",
+            &volume_path,
+            disk_path.to_str().unwrap(),
+            "--pool",
+            &self.pool,
+        ]);
+        if self.sparse {
+            cmd.arg("--sparse");
+        }
+        let output = cmd.output()?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
@@ -212,6 +228,7 @@ pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtUploadOpts
         install: opts.install.clone(),
         additional: ToDiskAdditionalOpts {
             disk_size: Some(disk_size.to_string()),
+            format: opts.format.clone(),
             common: crate::run_ephemeral::CommonVmOpts {
                 memory: opts.memory.clone(),
                 vcpus: opts.vcpus,
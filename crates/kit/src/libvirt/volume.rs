@@ -0,0 +1,403 @@
+//! libvirt volume command - manage volumes in libvirt storage pools
+//!
+//! `bcvk libvirt list-volumes` only lists volumes; these subcommands create
+//! and remove them and attach them to or detach them from domains. The pool
+//! is refreshed first, so that files added or removed behind libvirt's back
+//! are taken into account.
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+use super::run::{run_virsh_cmd, run_virsh_xml};
+use crate::domain_list::DomainLister;
+use crate::to_disk::Format;
+use crate::xml_utils::XmlNode;
+
+/// Options for the volume command
+#[derive(Debug, Parser)]
+pub struct LibvirtVolumeOpts {
+    #[command(subcommand)]
+    pub command: VolumeSubcommand,
+}
+
+/// Volume subcommands
+#[derive(Debug, Subcommand)]
+pub enum VolumeSubcommand {
+    /// Create an empty volume
+    Create(VolumeCreateOpts),
+    /// Remove a volume
+    Rm(VolumeRmOpts),
+    /// Attach a volume to a domain as a virtio disk
+    Attach(VolumeAttachOpts),
+    /// Detach a volume from a domain
+    Detach(VolumeDetachOpts),
+}
+
+/// Options for volume create
+#[derive(Debug, Parser)]
+pub struct VolumeCreateOpts {
+    /// Name of the volume
+    pub name: String,
+
+    /// Size of the volume (e.g. 10G, 5120M, or plain number for bytes)
+    #[clap(long)]
+    pub size: String,
+
+    /// Volume format
+    #[clap(long, default_value_t = Format::Qcow2)]
+    pub format: Format,
+
+    /// Libvirt storage pool to create the volume in
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+}
+
+/// Options for volume rm
+#[derive(Debug, Parser)]
+pub struct VolumeRmOpts {
+    /// Name of the volume
+    pub name: String,
+
+    /// Remove the volume even if domains use it
+    #[clap(long, short = 'f')]
+    pub force: bool,
+
+    /// Libvirt storage pool of the volume
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+}
+
+/// Options for volume attach
+#[derive(Debug, Parser)]
+pub struct VolumeAttachOpts {
+    /// Name of the domain
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub domain: String,
+
+    /// Name of the volume
+    pub name: String,
+
+    /// Target device in the guest (e.g. vdc); defaults to the first free vdX
+    #[clap(long)]
+    pub target: Option<String>,
+
+    /// Libvirt storage pool of the volume
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+}
+
+/// Options for volume detach
+#[derive(Debug, Parser)]
+pub struct VolumeDetachOpts {
+    /// Name of the domain
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub domain: String,
+
+    /// Name of the volume
+    pub name: String,
+
+    /// Libvirt storage pool of the volume
+    #[clap(long, default_value = super::LIBVIRT_DEFAULT_POOL)]
+    pub pool: String,
+}
+
+/// A disk of a domain
+#[derive(Debug, PartialEq, Eq)]
+struct DomainDisk {
+    /// Host path of the disk, for file disks
+    source: Option<String>,
+    /// Device name in the guest
+    target: Option<String>,
+}
+
+/// Collect the disks in the domain XML `node`
+fn domain_disks(node: &XmlNode, disks: &mut Vec<DomainDisk>) {
+    if node.name == "disk" {
+        disks.push(DomainDisk {
+            source: node
+                .find("source")
+                .and_then(|s| s.attributes.get("file"))
+                .cloned(),
+            target: node
+                .find("target")
+                .and_then(|t| t.attributes.get("dev"))
+                .cloned(),
+        });
+        return;
+    }
+    for child in &node.children {
+        domain_disks(child, disks);
+    }
+}
+
+/// The first virtio device name (vdb, vdc, ...) not in `used`
+fn next_free_target(used: &[&str]) -> Result<String> {
+    ('b'..='z')
+        .map(|c| format!("vd{c}"))
+        .find(|dev| !used.contains(&dev.as_str()))
+        .ok_or_else(|| eyre!("No free virtio disk target; use --target"))
+}
+
+/// Refresh `pool` so that libvirt sees volumes changed outside of it
+fn refresh_pool(global_opts: &crate::libvirt::LibvirtOptions, pool: &str) -> Result<()> {
+    run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["pool-refresh", pool],
+        &format!("Failed to refresh storage pool '{pool}'"),
+    )
+}
+
+/// Get the host path of volume `name` in `pool`
+fn volume_path(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    pool: &str,
+    name: &str,
+) -> Result<String> {
+    let output = global_opts
+        .virsh_command()
+        .args(&["vol-path", name, "--pool", pool])
+        .output()
+        .with_context(|| "Failed to run virsh vol-path")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "Volume '{name}' not found in pool '{pool}': {}",
+            stderr.trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Get the format (e.g. qcow2) of volume `name` in `pool`
+fn volume_format(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    pool: &str,
+    name: &str,
+) -> Result<String> {
+    let dom = run_virsh_xml(
+        global_opts.connect.as_deref(),
+        &["vol-dumpxml", name, "--pool", pool],
+    )
+    .with_context(|| format!("Failed to get XML for volume '{name}'"))?;
+    let format = dom
+        .find("format")
+        .and_then(|f| f.attributes.get("type"))
+        .cloned()
+        .unwrap_or_else(|| {
+            debug!("Volume '{name}' has no format, assuming raw");
+            "raw".to_owned()
+        });
+    Ok(format)
+}
+
+/// Get the disks of `domain`, as currently running if it is running
+fn get_domain_disks(
+    global_opts: &crate::libvirt::LibvirtOptions,
+    domain: &str,
+) -> Result<Vec<DomainDisk>> {
+    let dom = run_virsh_xml(global_opts.connect.as_deref(), &["dumpxml", domain])
+        .with_context(|| format!("Failed to get XML for domain '{domain}'"))?;
+    let mut disks = Vec::new();
+    domain_disks(&dom, &mut disks);
+    Ok(disks)
+}
+
+/// Names of the domains using the disk at `path`
+fn domains_using(global_opts: &crate::libvirt::LibvirtOptions, path: &str) -> Result<Vec<String>> {
+    let lister = match global_opts.connect.as_ref() {
+        Some(uri) => DomainLister::with_connection(uri.clone()),
+        None => DomainLister::new(),
+    };
+    let mut users = Vec::new();
+    for domain in lister.list_all_domains()? {
+        let disks = get_domain_disks(global_opts, &domain)?;
+        if disks.iter().any(|d| d.source.as_deref() == Some(path)) {
+            users.push(domain);
+        }
+    }
+    Ok(users)
+}
+
+fn create(global_opts: &crate::libvirt::LibvirtOptions, opts: VolumeCreateOpts) -> Result<()> {
+    let size = crate::utils::parse_size(&opts.size)?;
+    refresh_pool(global_opts, &opts.pool)?;
+    run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &[
+            "vol-create-as",
+            &opts.pool,
+            &opts.name,
+            &size.to_string(),
+            "--format",
+            opts.format.as_str(),
+        ],
+        &format!("Failed to create volume '{}'", opts.name),
+    )?;
+    println!(
+        "Created {} volume '{}' in pool '{}'",
+        opts.format, opts.name, opts.pool
+    );
+    Ok(())
+}
+
+fn rm(global_opts: &crate::libvirt::LibvirtOptions, opts: VolumeRmOpts) -> Result<()> {
+    refresh_pool(global_opts, &opts.pool)?;
+    let path = volume_path(global_opts, &opts.pool, &opts.name)?;
+    if !opts.force {
+        let users = domains_using(global_opts, &path)?;
+        if !users.is_empty() {
+            return Err(eyre!(
+                "Volume '{}' is used by {}; detach it first or use --force",
+                opts.name,
+                users.join(", ")
+            ));
+        }
+    }
+    run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["vol-delete", &opts.name, "--pool", &opts.pool],
+        &format!("Failed to remove volume '{}'", opts.name),
+    )?;
+    println!("Removed volume '{}' from pool '{}'", opts.name, opts.pool);
+    Ok(())
+}
+
+fn attach(global_opts: &crate::libvirt::LibvirtOptions, opts: VolumeAttachOpts) -> Result<()> {
+    refresh_pool(global_opts, &opts.pool)?;
+    let path = volume_path(global_opts, &opts.pool, &opts.name)?;
+    let format = volume_format(global_opts, &opts.pool, &opts.name)?;
+
+    let disks = get_domain_disks(global_opts, &opts.domain)?;
+    if disks
+        .iter()
+        .any(|d| d.source.as_deref() == Some(path.as_str()))
+    {
+        return Err(eyre!(
+            "Volume '{}' is already attached to '{}'",
+            opts.name,
+            opts.domain
+        ));
+    }
+    let target = match opts.target {
+        Some(target) => target,
+        None => {
+            let used: Vec<&str> = disks.iter().filter_map(|d| d.target.as_deref()).collect();
+            next_free_target(&used)?
+        }
+    };
+
+    // --persistent also changes the running domain if it is running
+    run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &[
+            "attach-disk",
+            &opts.domain,
+            &path,
+            &target,
+            "--driver",
+            "qemu",
+            "--subdriver",
+            &format,
+            "--targetbus",
+            "virtio",
+            "--persistent",
+        ],
+        &format!(
+            "Failed to attach volume '{}' to '{}'",
+            opts.name, opts.domain
+        ),
+    )?;
+    println!(
+        "Attached volume '{}' to '{}' as {target}",
+        opts.name, opts.domain
+    );
+    Ok(())
+}
+
+fn detach(global_opts: &crate::libvirt::LibvirtOptions, opts: VolumeDetachOpts) -> Result<()> {
+    refresh_pool(global_opts, &opts.pool)?;
+    let path = volume_path(global_opts, &opts.pool, &opts.name)?;
+    let disks = get_domain_disks(global_opts, &opts.domain)?;
+    let target = disks
+        .iter()
+        .find(|d| d.source.as_deref() == Some(path.as_str()))
+        .and_then(|d| d.target.clone())
+        .ok_or_else(|| {
+            eyre!(
+                "Volume '{}' is not attached to '{}'",
+                opts.name,
+                opts.domain
+            )
+        })?;
+    run_virsh_cmd(
+        global_opts.connect.as_deref(),
+        &["detach-disk", &opts.domain, &target, "--persistent"],
+        &format!(
+            "Failed to detach volume '{}' from '{}'",
+            opts.name, opts.domain
+        ),
+    )?;
+    println!("Detached volume '{}' from '{}'", opts.name, opts.domain);
+    Ok(())
+}
+
+/// Execute the volume command
+pub fn run(global_opts: &crate::libvirt::LibvirtOptions, opts: LibvirtVolumeOpts) -> Result<()> {
+    match opts.command {
+        VolumeSubcommand::Create(opts) => create(global_opts, opts),
+        VolumeSubcommand::Rm(opts) => rm(global_opts, opts),
+        VolumeSubcommand::Attach(opts) => attach(global_opts, opts),
+        VolumeSubcommand::Detach(opts) => detach(global_opts, opts),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_utils::parse_xml_dom;
+
+    #[test]
+    fn test_domain_disks() {
+        let dom = parse_xml_dom(
+            r#"<domain><devices>
+                <disk type="file" device="disk">
+                    <driver name="qemu" type="qcow2"/>
+                    <source file="/var/lib/libvirt/images/vm.qcow2"/>
+                    <target dev="vda" bus="virtio"/>
+                </disk>
+                <disk type="file" device="cdrom">
+                    <target dev="sda" bus="sata"/>
+                </disk>
+                <interface type="network"><target dev="vnet0"/></interface>
+            </devices></domain>"#,
+        )
+        .unwrap();
+        let mut disks = Vec::new();
+        domain_disks(&dom, &mut disks);
+        assert_eq!(
+            disks,
+            [
+                DomainDisk {
+                    source: Some("/var/lib/libvirt/images/vm.qcow2".into()),
+                    target: Some("vda".into()),
+                },
+                DomainDisk {
+                    source: None,
+                    target: Some("sda".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_free_target() {
+        assert_eq!(next_free_target(&[]).unwrap(), "vdb");
+        assert_eq!(next_free_target(&["vda", "vdb", "sda"]).unwrap(), "vdc");
+        assert_eq!(next_free_target(&["vda", "vdc"]).unwrap(), "vdb");
+        let all: Vec<String> = ('b'..='z').map(|c| format!("vd{c}")).collect();
+        let all: Vec<&str> = all.iter().map(|s| s.as_str()).collect();
+        assert!(next_free_target(&all).is_err());
+    }
+}
@@ -4,6 +4,7 @@
 //! to libvirt storage pools, maintaining container image metadata as libvirt annotations.
 
 use crate::common_opts::MemoryOpts;
+use crate::hostexec::HostCommand;
 use crate::install_options::InstallOptions;
 use crate::to_disk::{run as to_disk, ToDiskAdditionalOpts, ToDiskOpts};
 use crate::xml_utils::{self, XmlWriter};
@@ -12,8 +13,7 @@ use camino::Utf8Path;
 use clap::Parser;
 use color_eyre::{eyre::eyre, Result};
 use std::path::Path;
-use std::process::Command;
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// Configuration options for uploading a bootc disk image to libvirt
 #[derive(Debug, Parser)]
@@ -26,7 +26,7 @@ pub struct LibvirtUploadDiskOpts {
     pub volume_name: Option<String>,
 
     /// Libvirt storage pool name
-    #[clap(long, default_value = "default")]
+    #[clap(long, default_value = crate::libvirt::LIBVIRT_DEFAULT_POOL)]
     pub pool: String,
 
     /// Size of the disk image (e.g., '20G', '10240M'). If not specified, uses the actual size of the created disk.
@@ -60,14 +60,15 @@ impl LibvirtUploadDiskOpts {
             return name.clone();
         }
 
-        // Sanitize the image name for use as a volume name
-        let image_name = self.source_image.clone();
+        // Sanitize the image name for use as a volume name; a tag is kept,
+        // a digest is not
+        let image_name = crate::images::without_digest(&self.source_image);
 
         // Remove registry prefix if present
         let name = image_name
             .split('/')
             .last()
-            .unwrap_or(&image_name)
+            .unwrap_or(image_name)
             .replace(':', "-")
             .replace('/', "-")
             .replace('.', "-");
@@ -77,7 +78,7 @@ impl LibvirtUploadDiskOpts {
 
     /// Check if libvirt storage pool exists
     fn check_pool_exists(&self) -> Result<()> {
-        let output = Command::new("virsh")
+        let output = HostCommand::new("virsh")
             .args(&["pool-info", &self.pool])
             .output()?;
 
@@ -102,12 +103,20 @@ impl LibvirtUploadDiskOpts {
         let volume_path = format!("{}.raw", volume_name);
 
         // Delete existing volume if it exists
-        let _ = Command::new("virsh")
+        match HostCommand::new("virsh")
             .args(&["vol-delete", &volume_path, "--pool", &self.pool])
-            .output();
+            .output()
+        {
+            Ok(output) if !output.status.success() => debug!(
+                "Not deleting volume {volume_path}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to run virsh vol-delete: {e}"),
+        }
 
         // Use the provided disk size
-        let output = Command::new("virsh")
+        let output = HostCommand::new("virsh")
             .args(&[
                 "vol-create-as",
                 &self.pool,
@@ -125,7 +134,7 @@ impl LibvirtUploadDiskOpts {
 
         // Upload the disk image to the volume
         debug!("Uploading disk image to volume '{}'", volume_path);
-        let output = Command::new("virsh")
+        let output = HostCommand::new("virsh")
             .args(&[
                 "vol-upload",
                 &volume_path,
@@ -173,7 +182,7 @@ impl LibvirtUploadDiskOpts {
         std::fs::write(&temp_metadata, metadata_xml)?;
 
         // Set the metadata on the volume
-        let _output = Command::new("virsh")
+        let _output = HostCommand::new("virsh")
             .args(&[
                 "vol-desc",
                 &volume_path,
@@ -188,7 +197,7 @@ impl LibvirtUploadDiskOpts {
         // This is more reliable than vol-desc which might not support metadata
 
         // Get current volume XML
-        let output = Command::new("virsh")
+        let output = HostCommand::new("virsh")
             .args(&["vol-dumpxml", &volume_path, "--pool", &self.pool])
             .output()?;
 
@@ -0,0 +1,262 @@
+//! Log output of bcvk
+//!
+//! By default events are written to stderr in a compact, human-readable
+//! format, filtered by `RUST_LOG` (default `info`). For CI and for the
+//! container entrypoint, whose output ends up in the logs of the container,
+//! `--log-format json` writes one JSON object per event instead, and the
+//! log can additionally be written to a file, the systemd journal (with the
+//! default `journald` feature) or (with the `otlp` feature) an
+//! OpenTelemetry collector.
+//!
+//! [`install_default`] sets up the default output before the command line is
+//! parsed, and [`install`] replaces it with the selected one afterwards.
+//!
+//! All events are emitted within a `bcvk` span carrying an invocation ID,
+//! which ephemeral containers inherit via [`INVOCATION_ID_ENV`], so that
+//! the logs of the host and of the container entrypoint can be correlated.
+
+use std::fs::OpenOptions;
+use std::sync::{Mutex, OnceLock};
+
+use camino::Utf8PathBuf;
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing_error::ErrorLayer;
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// Environment variable with the invocation ID to use, set for containers
+pub(crate) const INVOCATION_ID_ENV: &str = "BCVK_INVOCATION_ID";
+
+/// Environment variable with the log format, set for containers
+pub(crate) const LOG_FORMAT_ENV: &str = "BCVK_LOG_FORMAT";
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Handle to replace the output layers installed by [`install_default`]
+static OUTPUT: OnceLock<reload::Handle<Vec<BoxedLayer>, Registry>> = OnceLock::new();
+
+/// Format of the log output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Compact,
+    /// One JSON object per line, with a timestamp and the fields of the
+    /// enclosing spans
+    Json,
+}
+
+impl LogFormat {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Compact => "compact",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Options for the log output, global to all commands
+#[derive(Debug, Args)]
+pub(crate) struct LogOpts {
+    /// Format of the log output on stderr and in --log-file
+    #[clap(long, global = true, value_enum, env = LOG_FORMAT_ENV, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Also append the log to this file
+    #[clap(long, global = true, value_name = "PATH")]
+    pub log_file: Option<Utf8PathBuf>,
+
+    /// Also send the log to the systemd journal
+    #[cfg(feature = "journald")]
+    #[clap(long, global = true)]
+    pub log_journald: bool,
+
+    /// Export spans and events to this OpenTelemetry collector, using OTLP
+    /// over HTTP (e.g. http://localhost:4318/v1/traces)
+    #[cfg(feature = "otlp")]
+    #[clap(long, global = true, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+}
+
+/// The ID of this invocation, inherited from [`INVOCATION_ID_ENV`] if set
+pub(crate) fn invocation_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        std::env::var(INVOCATION_ID_ENV)
+            .ok()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+    })
+}
+
+/// The log format selected by [`install`]
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Environment for containers running bcvk, so that their log output uses
+/// the same format and invocation ID
+pub(crate) fn container_env() -> [String; 2] {
+    let format = FORMAT.get().copied().unwrap_or_default();
+    [
+        format!("{LOG_FORMAT_ENV}={}", format.as_str()),
+        format!("{INVOCATION_ID_ENV}={}", invocation_id()),
+    ]
+}
+
+/// The span all events of this invocation are emitted in
+pub(crate) fn root_span() -> tracing::Span {
+    tracing::info_span!("bcvk", invocation = invocation_id())
+}
+
+/// A layer writing events in `format` to `writer`
+fn fmt_layer<W>(format: LogFormat, writer: W, terminal: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        // Timestamps are added by whatever collects stderr, but not to files
+        LogFormat::Compact if terminal => fmt::layer()
+            .event_format(fmt::format().without_time().with_target(false).compact())
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Compact => fmt::layer()
+            .event_format(fmt::format().with_target(false).compact())
+            .with_ansi(false)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+#[cfg(feature = "otlp")]
+static TRACER_PROVIDER: OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> = OnceLock::new();
+
+/// A layer exporting spans and events to the OTLP collector at `endpoint`
+#[cfg(feature = "otlp")]
+fn otlp_layer(endpoint: &str) -> Result<BoxedLayer> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig as _;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .with_context(|| format!("Setting up OTLP exporter for {endpoint}"))?;
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name("bcvk")
+        .build();
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer("bcvk");
+    if TRACER_PROVIDER.set(provider).is_err() {
+        tracing::warn!("OTLP exporter already set up, not exporting to {endpoint}");
+    }
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+/// Install the default log output (compact, to stderr), so that events
+/// emitted before [`install`] are not lost
+pub(crate) fn install_default() {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new("info"))
+        .unwrap();
+    let (output, handle) =
+        reload::Layer::new(vec![fmt_layer(LogFormat::default(), std::io::stderr, true)]);
+    tracing_subscriber::registry()
+        .with(output)
+        .with(ErrorLayer::default())
+        .with(filter)
+        .init();
+    if OUTPUT.set(handle).is_err() {
+        tracing::warn!("Default log output installed twice");
+    }
+}
+
+/// Replace the default log output with the one selected by `opts`
+pub(crate) fn install(opts: &LogOpts) -> Result<()> {
+    if let Err(format) = FORMAT.set(opts.log_format) {
+        tracing::warn!("Log format already set, ignoring {}", format.as_str());
+    }
+
+    let mut layers: Vec<BoxedLayer> = vec![fmt_layer(opts.log_format, std::io::stderr, true)];
+    if let Some(path) = opts.log_file.as_deref() {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening log file {path}"))?;
+        layers.push(fmt_layer(opts.log_format, Mutex::new(file), false));
+    }
+    #[cfg(feature = "journald")]
+    if opts.log_journald {
+        let layer = tracing_journald::layer()
+            .context("Connecting to the systemd journal")?
+            .with_syslog_identifier("bcvk".to_owned());
+        layers.push(layer.boxed());
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = opts.otlp_endpoint.as_deref() {
+        layers.push(otlp_layer(endpoint)?);
+    }
+
+    OUTPUT
+        .get()
+        .ok_or_else(|| eyre!("Default log output not installed"))?
+        .reload(layers)
+        .context("Replacing the default log output")
+}
+
+/// Flush log output which is sent in batches; call before exiting
+pub(crate) fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("Failed to export traces: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Debug, Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        log: LogOpts,
+    }
+
+    #[test]
+    fn test_log_opts() {
+        let cases: &[(&[&str], LogFormat, Option<&str>)] = &[
+            (&[], LogFormat::Compact, None),
+            (&["--log-format", "json"], LogFormat::Json, None),
+            (
+                &["--log-file", "/tmp/bcvk.log"],
+                LogFormat::Compact,
+                Some("/tmp/bcvk.log"),
+            ),
+        ];
+        for (args, format, file) in cases {
+            let cli = TestCli::try_parse_from(std::iter::once("bcvk").chain(args.iter().copied()))
+                .unwrap();
+            assert_eq!(cli.log.log_format, *format, "{args:?}");
+            assert_eq!(cli.log.log_file.as_deref().map(|p| p.as_str()), *file);
+            assert_eq!(
+                LogFormat::from_str(format.as_str(), false).unwrap(),
+                *format
+            );
+        }
+        assert!(TestCli::try_parse_from(["bcvk", "--log-format", "xml"]).is_err());
+    }
+}
@@ -1,39 +1,81 @@
 //! Bootc Virtualization Kit (bcvk) - A toolkit for bootc containers and local virtualization
 
 use cap_std_ext::cap_std::fs::Dir;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use color_eyre::{eyre::Context as _, Report, Result};
 
-mod arch;
 mod boot_progress;
 mod cache_metadata;
+mod checkpoint;
+mod cleanup;
 mod cli_json;
 mod common_opts;
+mod completion;
+mod compose;
+mod config;
 mod container_entrypoint;
-mod credentials;
 mod domain_list;
+mod envdetect;
 mod ephemeral;
+mod ephemeral_boot_disk;
+mod ephemeral_commit;
+mod ephemeral_cp;
+mod ephemeral_list;
+mod error_hints;
+mod events;
+mod image_policy;
+mod image_pull;
 mod images;
+mod images_diff;
+mod images_inspect;
+mod images_verify;
 mod install_options;
 mod instancetypes;
+mod kernel_cache;
 mod libvirt;
 mod libvirt_upload_disk;
+mod logging;
 #[allow(dead_code)]
 mod podman;
-mod qemu;
-mod qemu_img;
+mod preflight;
+mod project;
+mod qemu_watchdog;
 mod run_ephemeral;
 mod run_ephemeral_ssh;
 mod ssh;
+mod stage_progress;
 mod status_monitor;
 mod supervisor_status;
 pub(crate) mod systemd;
 mod to_disk;
 mod utils;
-mod xml_utils;
+mod vsock_exec;
 
-/// Default state directory for bcvk container data
-pub const CONTAINER_STATEDIR: &str = "/var/lib/bcvk";
+// The parts of bcvk usable as a library
+use bcvk_core::{
+    credentials, host_devices, hostexec, qemu, qemu_img, qmp, xml_utils, CONTAINER_STATEDIR,
+};
+
+pub(crate) use bcvk_core::failure::Failure;
+
+/// The nonzero exit status of a command bcvk ran for the user, e.g. in a
+/// VM, which bcvk exits with without reporting an error
+///
+/// This is returned as an error, so that destructors and [`cleanup`] guards
+/// run on the way back to `main`, unlike with `std::process::exit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("command exited with status {0}")]
+pub(crate) struct ExitStatus(pub(crate) i32);
+
+impl ExitStatus {
+    /// Succeed for status 0, else fail with the status
+    pub(crate) fn check(code: i32) -> Result<()> {
+        match code {
+            0 => Ok(()),
+            code => Err(Report::new(Self(code))),
+        }
+    }
+}
 
 /// A comprehensive toolkit for bootc containers and local virtualization.
 ///
@@ -44,6 +86,26 @@ pub const CONTAINER_STATEDIR: &str = "/var/lib/bcvk";
 #[derive(Parser)]
 #[command(version)]
 struct Cli {
+    /// Print commands which would change host state (virsh, qemu-img,
+    /// podman) instead of running them
+    ///
+    /// Subcommands with a --dry-run of their own (to-disk, libvirt
+    /// base-disks prune and gc) take it instead.
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Use the defaults of this profile from the configuration file
+    /// (~/.config/bcvk/config.toml); may also be set via BCVK_PROFILE
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Print the full error report, with span traces, when a command fails
+    #[clap(long, global = true)]
+    verbose: bool,
+
+    #[command(flatten)]
+    log: logging::LogOpts,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -98,6 +160,36 @@ enum Commands {
         command: libvirt::LibvirtSubcommands,
     },
 
+    /// Manage groups of libvirt VMs declared in a compose file
+    Compose {
+        /// Compose file declaring the VMs
+        #[clap(short = 'f', long = "file", global = true, default_value = compose::DEFAULT_FILE)]
+        file: camino::Utf8PathBuf,
+
+        /// Hypervisor connection URI (e.g., qemu:///system, qemu+ssh://host/system)
+        #[clap(short = 'c', long = "connect", global = true)]
+        connect: Option<String>,
+
+        #[command(subcommand)]
+        command: compose::ComposeCommands,
+    },
+
+    /// Work on a project directory in a libvirt VM
+    Project {
+        /// Hypervisor connection URI (e.g., qemu:///system, qemu+ssh://host/system)
+        #[clap(short = 'c', long = "connect", global = true)]
+        connect: Option<String>,
+
+        #[command(subcommand)]
+        command: project::ProjectCommands,
+    },
+
+    /// Show the log of operations performed by bcvk
+    Events(events::EventsOpts),
+
+    /// Generate a shell completion script
+    Completion(completion::CompletionOpts),
+
     /// Upload bootc disk images to libvirt (deprecated)
     #[clap(name = "libvirt-upload-disk", hide = true)]
     LibvirtUploadDisk(libvirt_upload_disk::LibvirtUploadDiskOpts),
@@ -115,31 +207,17 @@ enum Commands {
     Internals(InternalsOpts),
 }
 
-/// Install and configure the tracing/logging system.
-///
-/// Sets up structured logging with environment-based filtering,
-/// error layer integration, and console output formatting.
-/// Logs are filtered by RUST_LOG environment variable, defaulting to 'info'.
-fn install_tracing() {
-    use tracing_error::ErrorLayer;
-    use tracing_subscriber::fmt;
-    use tracing_subscriber::prelude::*;
-    use tracing_subscriber::EnvFilter;
-
-    let format = fmt::format().without_time().with_target(false).compact();
-
-    let fmt_layer = fmt::layer()
-        .event_format(format)
-        .with_writer(std::io::stderr);
-    let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
-
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt_layer)
-        .with(ErrorLayer::default())
-        .init();
+/// Parse the command line, using the defaults of the selected profile
+/// of the configuration file (see [`config`]).
+fn parse_cli() -> Result<Cli, Report> {
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let mut cmd = Cli::command();
+    let explicit = config::profile_arg(cmd.clone(), &args);
+    if let Some(profile) = config::load_profile(explicit)? {
+        cmd = profile.apply(cmd);
+    }
+    let matches = cmd.get_matches_from(args);
+    Ok(Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
 }
 
 /// Main entry point for the bcvk CLI application.
@@ -148,16 +226,99 @@ fn install_tracing() {
 /// bcvk operations including VM management, SSH access, and
 /// container image handling.
 fn main() -> Result<(), Report> {
-    install_tracing();
+    clap_complete::CompleteEnv::with_factory(Cli::command)
+        .var(completion::COMPLETE_ENV)
+        .complete();
     color_eyre::install()?;
+    // Reconfigured once the command line (e.g. --log-format) is parsed
+    logging::install_default();
 
-    let cli = Cli::parse();
+    let cli = parse_cli()?;
+    logging::install(&cli.log)?;
+    let _span = logging::root_span().entered();
+    if let Some(profile) = cli.profile.as_deref() {
+        tracing::debug!("Using profile {profile}");
+    }
+    hostexec::set_dry_run(cli.dry_run);
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .context("Init tokio runtime")?;
+    // The container entrypoint handles signals itself
+    let entrypoint = matches!(cli.command, Commands::ContainerEntrypoint(_));
+    if !entrypoint {
+        cleanup::install_signal_handler(&rt)?;
+    }
 
-    match cli.command {
+    if let Err(e) = run_command(cli.command, &rt) {
+        if let Some(&ExitStatus(code)) = e.downcast_ref::<ExitStatus>() {
+            logging::shutdown();
+            std::process::exit(code);
+        }
+        // The output of the container entrypoint ends up in logs, keep it complete
+        if entrypoint {
+            logging::shutdown();
+            return Err(e);
+        }
+        if cli.verbose {
+            eprintln!("Error: {e:?}");
+        } else {
+            error_hints::print(&e);
+        }
+        logging::shutdown();
+        std::process::exit(Failure::of(&e).map_or(1, Failure::exit_code));
+    }
+    tracing::debug!("exiting");
+    // Ensure we don't block on any spawned tasks
+    rt.shutdown_background();
+    logging::shutdown();
+    std::process::exit(0)
+}
+
+/// Run the given libvirt command
+fn run_libvirt(
+    options: &libvirt::LibvirtOptions,
+    command: libvirt::LibvirtSubcommands,
+) -> Result<()> {
+    match command {
+        libvirt::LibvirtSubcommands::Run(opts) => libvirt::run::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Ssh(opts) => libvirt::ssh::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Scp(opts) => libvirt::scp::run(options, opts)?,
+        libvirt::LibvirtSubcommands::PortForward(opts) => {
+            libvirt::port_forward::run(options, opts)?
+        }
+        libvirt::LibvirtSubcommands::List(opts) => libvirt::list::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Metrics(opts) => libvirt::metrics::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Top(opts) => libvirt::top::run(options, opts)?,
+        libvirt::LibvirtSubcommands::ListVolumes(opts) => {
+            libvirt::list_volumes::run(options, opts)?
+        }
+        libvirt::LibvirtSubcommands::Volume(opts) => libvirt::volume::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Stop(opts) => libvirt::stop::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Start(opts) => libvirt::start::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Update(opts) => libvirt::update::run(options, opts)?,
+        libvirt::LibvirtSubcommands::ResizeDisk(opts) => libvirt::resize_disk::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Remove(opts) => libvirt::rm::run(options, opts)?,
+        libvirt::LibvirtSubcommands::RemoveAll(opts) => libvirt::rm_all::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Inspect(opts) => libvirt::inspect::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Export(opts) => libvirt::bundle::run_export(options, opts)?,
+        libvirt::LibvirtSubcommands::Import(opts) => libvirt::bundle::run_import(options, opts)?,
+        libvirt::LibvirtSubcommands::ImportCloudImage(opts) => {
+            libvirt::import_cloud_image::run(options, opts)?
+        }
+        libvirt::LibvirtSubcommands::Upload(opts) => libvirt::upload::run(options, opts)?,
+        libvirt::LibvirtSubcommands::Status(opts) => libvirt::status::run(options, opts)?,
+        libvirt::LibvirtSubcommands::BaseDisks(opts) => {
+            libvirt::base_disks_cli::run(options, opts)?
+        }
+        libvirt::LibvirtSubcommands::PrintFirmware(opts) => libvirt::print_firmware::run(opts)?,
+    }
+    Ok(())
+}
+
+/// Run the given command
+fn run_command(command: Commands, rt: &tokio::runtime::Runtime) -> Result<()> {
+    match command {
         Commands::Images(opts) => opts.run()?,
         Commands::Ephemeral(cmd) => cmd.run()?,
         Commands::ToDisk(opts) => {
@@ -165,31 +326,21 @@ fn main() -> Result<(), Report> {
         }
         Commands::Libvirt { connect, command } => {
             let options = libvirt::LibvirtOptions { connect };
-            match command {
-                libvirt::LibvirtSubcommands::Run(opts) => libvirt::run::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::Ssh(opts) => libvirt::ssh::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::List(opts) => libvirt::list::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::ListVolumes(opts) => {
-                    libvirt::list_volumes::run(&options, opts)?
-                }
-                libvirt::LibvirtSubcommands::Stop(opts) => libvirt::stop::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::Start(opts) => libvirt::start::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::Remove(opts) => libvirt::rm::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::RemoveAll(opts) => {
-                    libvirt::rm_all::run(&options, opts)?
-                }
-                libvirt::LibvirtSubcommands::Inspect(opts) => {
-                    libvirt::inspect::run(&options, opts)?
-                }
-                libvirt::LibvirtSubcommands::Upload(opts) => libvirt::upload::run(&options, opts)?,
-                libvirt::LibvirtSubcommands::Status(opts) => libvirt::status::run(opts)?,
-                libvirt::LibvirtSubcommands::BaseDisks(opts) => {
-                    libvirt::base_disks_cli::run(&options, opts)?
-                }
-                libvirt::LibvirtSubcommands::PrintFirmware(opts) => {
-                    libvirt::print_firmware::run(opts)?
-                }
-            }
+            run_libvirt(&options, command).map_err(|e| options.tag_unavailable(e))?
+        }
+        Commands::Events(opts) => events::run(opts)?,
+        Commands::Completion(opts) => completion::run(opts)?,
+        Commands::Compose {
+            file,
+            connect,
+            command,
+        } => {
+            let options = libvirt::LibvirtOptions { connect };
+            compose::run(&file, &options, command).map_err(|e| options.tag_unavailable(e))?;
+        }
+        Commands::Project { connect, command } => {
+            let options = libvirt::LibvirtOptions { connect };
+            project::run(&options, command).map_err(|e| options.tag_unavailable(e))?;
         }
         Commands::LibvirtUploadDisk(opts) => {
             eprintln!(
@@ -226,8 +377,25 @@ fn main() -> Result<(), Report> {
             }
         },
     }
-    tracing::debug!("exiting");
-    // Ensure we don't block on any spawned tasks
-    rt.shutdown_background();
-    std::process::exit(0)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_global() {
+        let cli = Cli::try_parse_from(["bcvk", "libvirt", "list", "--dry-run"]).unwrap();
+        assert!(cli.dry_run);
+        let cli = Cli::try_parse_from(["bcvk", "--dry-run", "libvirt", "list"]).unwrap();
+        assert!(cli.dry_run);
+
+        // to-disk reports whether the disk would be regenerated instead
+        let cli = Cli::try_parse_from(["bcvk", "to-disk", "--dry-run", "img", "disk.img"]).unwrap();
+        let Commands::ToDisk(opts) = cli.command else {
+            panic!("expected to-disk");
+        };
+        assert!(opts.additional.dry_run);
+    }
 }
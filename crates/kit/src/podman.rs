@@ -1,15 +1,18 @@
-use std::process::Command;
+use std::collections::HashMap;
 
-use bootc_utils::CommandRunExt;
 use color_eyre::{eyre::eyre, Result};
 use serde::Deserialize;
 
+use crate::hostexec::HostCommand;
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Store {
-    #[allow(dead_code)]
     pub graph_driver_name: String,
     pub graph_root: String,
+    /// Storage options by name, e.g. `overlay.mount_program`
+    #[serde(default)]
+    pub graph_options: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,7 +28,7 @@ pub struct ImageInspect {
 }
 
 pub fn get_system_info() -> Result<PodmanSystemInfo> {
-    Command::new("podman")
+    HostCommand::new("podman")
         .arg("system")
         .arg("info")
         .arg("--format=json")
@@ -35,7 +38,7 @@ pub fn get_system_info() -> Result<PodmanSystemInfo> {
 
 /// Get the size of a container image in bytes
 pub fn get_image_size(image: &str) -> Result<u64> {
-    let inspect_result: Vec<ImageInspect> = Command::new("podman")
+    let inspect_result: Vec<ImageInspect> = crate::images::podman()
         .arg("inspect")
         .arg("--format=json")
         .arg("--type=image")
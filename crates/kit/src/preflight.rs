@@ -0,0 +1,246 @@
+//! Checking the size of a VM against the resources of the host
+//!
+//! A VM with more memory than the host has at all fails late and obscurely
+//! inside QEMU or libvirt. Overcommitting the host otherwise is common (the
+//! guest rarely uses all of its memory, and disks are sparse), but may run
+//! the host out of memory or disk space later on, so [`check`] warns about
+//! more memory than is available, more vCPUs than CPUs, and disks larger
+//! than the free space of their storage pool. It runs before anything is
+//! created, and `--force` skips it.
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use tracing::warn;
+
+/// Where the local host's memory is described
+const PROC_MEMINFO: &str = "/proc/meminfo";
+
+/// What a VM will use of the host
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct VmResources {
+    /// Guest memory in megabytes
+    pub memory_mb: u64,
+    /// Number of vCPUs
+    pub vcpus: u32,
+    /// Largest size the disks created for the VM can grow to, in bytes
+    pub disk_bytes: u64,
+}
+
+/// What the host has to offer
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HostResources {
+    /// Number of CPUs
+    pub cpus: u32,
+    /// Physical memory in megabytes
+    pub memory_total_mb: u64,
+    /// Memory available without swapping, in megabytes
+    pub memory_available_mb: u64,
+    /// Free space in the storage the disks are created in, in bytes, if known
+    pub disk_available_bytes: Option<u64>,
+}
+
+/// A `Key: VALUE kB` field of /proc/meminfo, in megabytes
+fn meminfo_field(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        if k.trim() != key {
+            return None;
+        }
+        let kib: u64 = v.trim().strip_suffix("kB")?.trim().parse().ok()?;
+        Some(kib / 1024)
+    })
+}
+
+/// A `Key: VALUE` field of virsh output, e.g. of `virsh nodeinfo`
+fn virsh_field<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+/// A `Key: VALUE KiB` field of virsh output, in megabytes
+fn virsh_kib_field(output: &str, key: &str) -> Option<u64> {
+    let kib: u64 = virsh_field(output, key)?
+        .strip_suffix("KiB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib / 1024)
+}
+
+impl HostResources {
+    /// The resources of the local host, without a storage pool
+    pub(crate) fn local() -> Result<Self> {
+        let meminfo = std::fs::read_to_string(PROC_MEMINFO)
+            .with_context(|| format!("Reading {PROC_MEMINFO}"))?;
+        Self::from_meminfo(&meminfo, crate::qemu::default_vcpus())
+    }
+
+    /// The resources described by /proc/meminfo, with `cpus` CPUs
+    fn from_meminfo(meminfo: &str, cpus: u32) -> Result<Self> {
+        let field =
+            |key| meminfo_field(meminfo, key).ok_or_else(|| eyre!("No {key} in {PROC_MEMINFO}"));
+        Ok(Self {
+            cpus,
+            memory_total_mb: field("MemTotal")?,
+            memory_available_mb: field("MemAvailable")?,
+            disk_available_bytes: None,
+        })
+    }
+
+    /// The resources of a libvirt host, from the output of `virsh nodeinfo`
+    /// and `virsh nodememstats`
+    pub(crate) fn from_virsh(nodeinfo: &str, nodememstats: &str) -> Result<Self> {
+        let cpus = virsh_field(nodeinfo, "CPU(s)")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| eyre!("No CPU count in virsh nodeinfo"))?;
+        let memory_total_mb = virsh_kib_field(nodeinfo, "Memory size")
+            .ok_or_else(|| eyre!("No memory size in virsh nodeinfo"))?;
+        // Page cache and buffers can be reclaimed for the VM
+        let memory_available_mb = ["free", "buffers", "cached"]
+            .into_iter()
+            .map(|key| virsh_kib_field(nodememstats, key))
+            .sum::<Option<u64>>()
+            .ok_or_else(|| eyre!("No free memory in virsh nodememstats"))?;
+        Ok(Self {
+            cpus,
+            memory_total_mb,
+            memory_available_mb,
+            disk_available_bytes: None,
+        })
+    }
+}
+
+/// Check that the host can run a VM using `vm`, failing if it has not
+/// enough memory at all, and warning about the resources it would overcommit
+pub(crate) fn check(vm: &VmResources, host: &HostResources) -> Result<()> {
+    let (fatal, warnings) = assess(vm, host);
+    for warning in warnings {
+        warn!("The VM overcommits the host: {warning}");
+    }
+    match fatal {
+        Some(problem) => Err(eyre!(
+            "The host lacks the resources for this VM: {problem}. Request less, or use --force to create it anyway"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// What the host lacks to run `vm`: a problem preventing it from running
+/// at all, and the resources it would overcommit
+fn assess(vm: &VmResources, host: &HostResources) -> (Option<String>, Vec<String>) {
+    let mb = |mb: u64| HumanBytes(mb * 1024 * 1024);
+    let mut fatal = None;
+    let mut warnings = Vec::new();
+    if vm.memory_mb > host.memory_total_mb {
+        fatal = Some(format!(
+            "{} of memory requested, but the host only has {}",
+            mb(vm.memory_mb),
+            mb(host.memory_total_mb)
+        ));
+    } else if vm.memory_mb > host.memory_available_mb {
+        warnings.push(format!(
+            "{} of memory requested, but only {} of the host's {} are available",
+            mb(vm.memory_mb),
+            mb(host.memory_available_mb),
+            mb(host.memory_total_mb)
+        ));
+    }
+    if vm.vcpus > host.cpus {
+        warnings.push(format!(
+            "{} vCPUs requested, but the host only has {} CPUs",
+            vm.vcpus, host.cpus
+        ));
+    }
+    if let Some(available) = host.disk_available_bytes {
+        if vm.disk_bytes > available {
+            warnings.push(format!(
+                "the disks can grow to {}, but only {} are free in the storage pool",
+                HumanBytes(vm.disk_bytes),
+                HumanBytes(available)
+            ));
+        }
+    }
+    (fatal, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_resources_from_meminfo() {
+        let meminfo = "MemTotal:       16303744 kB\nMemFree:         1269436 kB\nMemAvailable:    8388608 kB\nBuffers:          289032 kB\n";
+        let host = HostResources::from_meminfo(meminfo, 8).unwrap();
+        assert_eq!(host.cpus, 8);
+        assert_eq!(host.memory_total_mb, 15921);
+        assert_eq!(host.memory_available_mb, 8192);
+        assert!(HostResources::from_meminfo("MemTotal: 1024 kB\n", 8).is_err());
+    }
+
+    #[test]
+    fn test_host_resources_from_virsh() {
+        let nodeinfo = "CPU model:           x86_64\nCPU(s):              16\nCPU frequency:       3600 MHz\nMemory size:         32768000 KiB\n";
+        let nodememstats = "total  :             32768000 KiB\nfree   :              2097152 KiB\nbuffers:                 1024 KiB\ncached :              4193280 KiB\n";
+        let host = HostResources::from_virsh(nodeinfo, nodememstats).unwrap();
+        assert_eq!(host.cpus, 16);
+        assert_eq!(host.memory_total_mb, 32000);
+        assert_eq!(host.memory_available_mb, 2048 + 1 + 4095);
+        assert!(HostResources::from_virsh(nodeinfo, "total  : 1 KiB\n").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let host = HostResources {
+            cpus: 8,
+            memory_total_mb: 16384,
+            memory_available_mb: 6144,
+            disk_available_bytes: Some(30 << 30),
+        };
+        let vm = |memory_mb, vcpus, disk_gib: u64| VmResources {
+            memory_mb,
+            vcpus,
+            disk_bytes: disk_gib << 30,
+        };
+        // The fatal problem and the warnings
+        let cases = [
+            (vm(4096, 2, 20), None, None),
+            (vm(6144, 8, 30), None, None),
+            (vm(65536, 2, 20), Some("the host only has 16.00 GiB"), None),
+            (
+                vm(8192, 2, 20),
+                None,
+                Some("only 6.00 GiB of the host's 16.00 GiB"),
+            ),
+            (vm(4096, 16, 20), None, Some("16 vCPUs requested")),
+            (vm(4096, 2, 50), None, Some("only 30.00 GiB are free")),
+        ];
+        for (vm, fatal, warning) in cases {
+            let (f, warnings) = assess(&vm, &host);
+            match fatal {
+                None => {
+                    assert_eq!(f, None);
+                    check(&vm, &host).unwrap();
+                }
+                Some(msg) => {
+                    assert!(f.unwrap().contains(msg));
+                    let err = check(&vm, &host).unwrap_err().to_string();
+                    assert!(err.contains(msg), "{err}");
+                    assert!(err.contains("--force"), "{err}");
+                }
+            }
+            match warning {
+                None => assert!(warnings.is_empty(), "{warnings:?}"),
+                Some(msg) => assert!(warnings.iter().any(|w| w.contains(msg)), "{warnings:?}"),
+            }
+        }
+        // Without a storage pool, disks are not checked
+        let host = HostResources {
+            disk_available_bytes: None,
+            ..host
+        };
+        assert_eq!(assess(&vm(4096, 2, 500), &host), (None, vec![]));
+    }
+}
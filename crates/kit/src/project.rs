@@ -0,0 +1,196 @@
+//! Syncing a project directory into a VM
+//!
+//! `bcvk project sync` pushes a directory on the host into a libvirt VM
+//! with rsync over the domain's SSH connection. This is an alternative to
+//! virtiofs mounts (`libvirt run --volume`) where those are not available,
+//! e.g. with a remote hypervisor. Files ignored by `.gitignore` files, which
+//! rsync reads itself, and the `.git` directory are not copied.
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::libvirt::ssh::{LibvirtSshOpts, RsyncTransport};
+use crate::libvirt::LibvirtOptions;
+
+/// Path of the project in the VM unless given otherwise
+pub const DEFAULT_DEST: &str = "/srv/project";
+
+/// How long to wait for more changes before syncing in `--watch` mode
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Project subcommands
+#[derive(Debug, Subcommand)]
+pub enum ProjectCommands {
+    /// Copy a project directory into a VM with rsync over SSH
+    Sync(ProjectSyncOpts),
+}
+
+/// Options for syncing a project directory into a VM
+#[derive(Debug, Parser)]
+pub struct ProjectSyncOpts {
+    /// Name of the libvirt domain to sync to
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::domain_names))]
+    pub domain_name: String,
+
+    /// Project directory on the host
+    #[clap(long, default_value = ".")]
+    pub dir: Utf8PathBuf,
+
+    /// Path of the project in the VM
+    #[clap(long, default_value = DEFAULT_DEST)]
+    pub dest: String,
+
+    /// Keep running, and sync again whenever files in the directory change
+    #[clap(long)]
+    pub watch: bool,
+
+    /// Keep files in the VM which don't exist in the directory
+    #[clap(long)]
+    pub no_delete: bool,
+
+    /// SSH username to use for connection (defaults to the one given with `libvirt run --ssh-user`, or root)
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Do not check the SSH host key of the domain against the one learned on the first connection
+    #[clap(long)]
+    pub no_strict: bool,
+}
+
+/// The rsync options for syncing to `dest`
+fn rsync_flags(dest: &str, delete: bool) -> Result<Vec<String>> {
+    let mut flags = vec![
+        "--archive".to_owned(),
+        "--compress".to_owned(),
+        "--human-readable".to_owned(),
+        "--exclude=/.git/".to_owned(),
+        // Exclude what the .gitignore file of each directory matches
+        "--filter=:- .gitignore".to_owned(),
+    ];
+    if delete {
+        flags.push("--delete".to_owned());
+    }
+    // Create the destination first; rsync only creates its last component
+    flags.push(format!(
+        "--rsync-path=mkdir -p {} && rsync",
+        shlex::try_quote(dest).map_err(|e| eyre!("Invalid destination {dest}: {e}"))?
+    ));
+    Ok(flags)
+}
+
+/// Whether a change to `path` may need syncing; changes inside `.git` never do
+fn is_relevant(dir: &Path, path: &Path) -> bool {
+    !path.strip_prefix(dir).is_ok_and(|p| p.starts_with(".git"))
+}
+
+/// Sync once, then again after each batch of changes
+fn watch(dir: &Utf8Path, sync: impl Fn() -> Result<()>) -> Result<()> {
+    let dir = dir
+        .canonicalize_utf8()
+        .with_context(|| format!("Resolving {dir}"))?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |res| {
+            let _ = tx.send(res);
+        },
+        Config::default(),
+    )?;
+    watcher
+        .watch(dir.as_std_path(), RecursiveMode::Recursive)
+        .with_context(|| format!("Watching {dir}"))?;
+
+    sync()?;
+    println!("Watching {dir} for changes, press Ctrl-C to stop");
+    loop {
+        let event: notify::Event = rx.recv()??;
+        if !event
+            .paths
+            .iter()
+            .any(|p| is_relevant(dir.as_std_path(), p))
+        {
+            continue;
+        }
+        // Editors and builds change several files at once
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+        // The VM may be rebooting; keep watching
+        if let Err(e) = sync() {
+            eprintln!("Sync failed: {e:#}");
+        }
+    }
+}
+
+/// Execute the project sync command
+fn sync(global_opts: &LibvirtOptions, opts: ProjectSyncOpts) -> Result<()> {
+    if !opts.dir.is_dir() {
+        return Err(eyre!("{} is not a directory", opts.dir));
+    }
+    let ssh_opts = LibvirtSshOpts {
+        domain_name: opts.domain_name.clone(),
+        user: opts.user.clone(),
+        command: vec![],
+        no_strict: opts.no_strict,
+        timeout: 30,
+        log_level: "ERROR".to_string(),
+        extra_options: vec![],
+        suppress_output: false,
+        wait: None,
+    };
+    let transport = RsyncTransport::new(global_opts, &ssh_opts)?;
+    let flags = rsync_flags(&opts.dest, !opts.no_delete)?;
+    // With trailing slashes, rsync copies the contents of the directory
+    let source = format!("{}/", opts.dir.as_str().trim_end_matches('/'));
+    let dest = format!("{}/", opts.dest.trim_end_matches('/'));
+    let sync_once = || -> Result<()> {
+        transport.push(&flags, &source, &dest)?;
+        println!("Synced {} to {}:{}", opts.dir, opts.domain_name, opts.dest);
+        Ok(())
+    };
+    if opts.watch {
+        watch(&opts.dir, sync_once)
+    } else {
+        sync_once()
+    }
+}
+
+/// Execute a project subcommand
+pub fn run(global_opts: &LibvirtOptions, command: ProjectCommands) -> Result<()> {
+    match command {
+        ProjectCommands::Sync(opts) => sync(global_opts, opts),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rsync_flags() {
+        let flags = rsync_flags("/srv/my project", true).unwrap();
+        assert!(flags.iter().any(|f| f == "--filter=:- .gitignore"));
+        assert!(flags.iter().any(|f| f == "--delete"));
+        assert!(flags
+            .iter()
+            .any(|f| f == "--rsync-path=mkdir -p '/srv/my project' && rsync"));
+
+        let flags = rsync_flags(DEFAULT_DEST, false).unwrap();
+        assert!(!flags.iter().any(|f| f == "--delete"));
+    }
+
+    #[test]
+    fn test_is_relevant() {
+        let dir = Path::new("/home/user/proj");
+        assert!(is_relevant(dir, Path::new("/home/user/proj/src/main.rs")));
+        assert!(is_relevant(
+            dir,
+            Path::new("/home/user/proj/.github/ci.yml")
+        ));
+        assert!(!is_relevant(dir, Path::new("/home/user/proj/.git/index")));
+    }
+}
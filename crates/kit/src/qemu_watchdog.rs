@@ -0,0 +1,241 @@
+//! Watchdog for the QEMU process supervised by the container entrypoint
+//!
+//! When QEMU fails, its exit status and the last lines of the guest console
+//! are recorded in the supervisor status (see [`crate::supervisor_status`])
+//! and logged, so that they end up in `podman logs`.
+//!
+//! With `bcvk ephemeral run --restart-policy on-failure`, podman restarts
+//! the container (and so the VM, from scratch) when the entrypoint fails.
+//! A guest which panicked or stopped on an emulation error doesn't make
+//! QEMU exit though, so in that case the run state of the VM is polled over
+//! QMP and QEMU terminated once the guest is dead.
+//!
+//! Detached containers also get a podman health check running [`health`],
+//! which marks them unhealthy while the VM is in such a state.
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use camino::Utf8Path;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use crate::qmp::QmpClient;
+use crate::supervisor_status::{QemuExit, SupervisorStatus};
+
+/// How often the watchdog queries the run state of the VM
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for the connection to QMP, which serves one client at
+/// a time, in the health check
+const HEALTH_QMP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the last console output to be written to the log
+const CONSOLE_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of console lines recorded when QEMU fails
+const CONSOLE_TAIL_LINES: usize = 20;
+
+/// QEMU run states in which the guest will not make progress by itself
+const FAILED_STATES: &[&str] = &["guest-panicked", "internal-error", "io-error"];
+
+/// When podman restarts the container of an ephemeral VM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartPolicy {
+    /// Never
+    #[default]
+    No,
+    /// When the VM failed, at most the given number of times
+    OnFailure(Option<u32>),
+}
+
+impl std::str::FromStr for RestartPolicy {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(Self::No),
+            None if s == "on-failure" => Ok(Self::OnFailure(None)),
+            Some(("on-failure", n)) => n
+                .parse()
+                .map(|n| Self::OnFailure(Some(n)))
+                .map_err(|_| eyre!("Invalid number of restarts '{n}'")),
+            _ => Err(eyre!(
+                "Invalid restart policy '{s}'. Expected 'no' or 'on-failure[:N]'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::No => write!(f, "no"),
+            Self::OnFailure(None) => write!(f, "on-failure"),
+            Self::OnFailure(Some(n)) => write!(f, "on-failure:{n}"),
+        }
+    }
+}
+
+/// Whether the guest is dead in the QEMU run state `status`
+fn is_failed_state(status: &str) -> bool {
+    FAILED_STATES.contains(&status)
+}
+
+/// Start polling the run state of the VM, terminating QEMU once the guest
+/// is dead
+///
+/// Returns why QEMU was terminated, which is set before it is. Errors
+/// talking to QMP are ignored, as another client may be using it.
+pub(crate) fn spawn(qmp_socket: &Utf8Path) -> Arc<OnceLock<String>> {
+    let qmp_socket = qmp_socket.to_owned();
+    let verdict = Arc::new(OnceLock::new());
+    let result = Arc::clone(&verdict);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let mut qmp = match QmpClient::connect(&qmp_socket) {
+            Ok(qmp) => qmp,
+            Err(e) => {
+                debug!("Watchdog: {e:#}");
+                continue;
+            }
+        };
+        match qmp.query_status() {
+            Ok(status) if is_failed_state(&status.status) => {
+                let reason = format!("the VM is in state {}", status.status);
+                error!("Watchdog: {reason}, terminating QEMU");
+                let _ = result.set(reason);
+                if let Err(e) = qmp.quit() {
+                    warn!("Watchdog: terminating QEMU: {e:#}");
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Watchdog: {e:#}"),
+        }
+    });
+    verdict
+}
+
+/// The last `n` lines of `input`
+fn tail_lines(input: impl BufRead, n: usize) -> Vec<String> {
+    let mut lines = VecDeque::with_capacity(n);
+    for line in input.lines().map_while(|l| l.ok()) {
+        if lines.len() == n {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+    lines.into()
+}
+
+/// Record how QEMU exited, and log it along with the end of the console log
+///
+/// `watchdog` is why the watchdog terminated QEMU, if it did; `console_log`
+/// the log file of the guest console and the thread copying the console to
+/// it, which finishes once QEMU exited.
+pub(crate) fn qemu_exit(
+    status: ExitStatus,
+    watchdog: Option<String>,
+    console_log: Option<(&Utf8Path, JoinHandle<()>)>,
+) -> QemuExit {
+    let console_tail = console_log
+        .map(|(path, copier)| {
+            let deadline = Instant::now() + CONSOLE_FLUSH_TIMEOUT;
+            while !copier.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            match std::fs::File::open(path) {
+                Ok(f) => tail_lines(std::io::BufReader::new(f), CONSOLE_TAIL_LINES),
+                Err(e) => {
+                    warn!("Reading {path}: {e}");
+                    Vec::new()
+                }
+            }
+        })
+        .unwrap_or_default();
+
+    match &watchdog {
+        Some(reason) => error!("QEMU was terminated as {reason}"),
+        None => error!("QEMU failed: {status}"),
+    }
+    if !console_tail.is_empty() {
+        error!("Last lines of the guest console:");
+        for line in &console_tail {
+            error!("console: {line}");
+        }
+    }
+    QemuExit {
+        code: status.code(),
+        signal: status.signal(),
+        watchdog,
+        console_tail,
+    }
+}
+
+/// Check the health of the VM, for the podman health check
+///
+/// The VM is unhealthy once QEMU failed, and while it is in a state it
+/// will not recover from by itself.
+pub fn health() -> Result<()> {
+    let status = SupervisorStatus::read_from_file(crate::run_ephemeral::SUPERVISOR_STATUS)
+        .context("Reading the supervisor status")?;
+    if let Some(exit) = status.qemu_exit {
+        return Err(match exit.watchdog {
+            Some(reason) => eyre!("QEMU was terminated as {reason}"),
+            None => eyre!("QEMU failed"),
+        });
+    }
+    if !status.running {
+        return Err(eyre!("The VM is not running"));
+    }
+    let socket = Utf8Path::new(crate::run_ephemeral::QMP_SOCKET);
+    // QEMU has not been started yet
+    if !socket.exists() {
+        return Ok(());
+    }
+    let vm = QmpClient::connect_with_timeout(socket, HEALTH_QMP_TIMEOUT)?.query_status()?;
+    if is_failed_state(&vm.status) {
+        return Err(eyre!("The VM is in state {}", vm.status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_restart_policy() {
+        let cases = [
+            ("no", Some(RestartPolicy::No)),
+            ("on-failure", Some(RestartPolicy::OnFailure(None))),
+            ("on-failure:3", Some(RestartPolicy::OnFailure(Some(3)))),
+            ("on-failure:", None),
+            ("on-failure:x", None),
+            ("always", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = input.parse::<RestartPolicy>().ok();
+            assert_eq!(parsed, expected, "input: {input}");
+            if let Some(policy) = parsed {
+                assert_eq!(policy.to_string(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tail_lines() {
+        let input = "a\nb\nc\nd\n";
+        assert_eq!(tail_lines(input.as_bytes(), 2), ["c", "d"]);
+        assert_eq!(tail_lines(input.as_bytes(), 10), ["a", "b", "c", "d"]);
+        assert!(tail_lines(&b""[..], 2).is_empty());
+    }
+}
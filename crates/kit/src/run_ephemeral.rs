@@ -88,15 +88,18 @@
 //! - Ensures perfect fidelity of user options across process boundaries
 
 use std::fs::File;
-use std::io::{BufWriter, Seek, Write};
-use std::os::unix::process::CommandExt;
+use std::io::{BufRead, BufWriter, Seek, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use bootc_utils::CommandRunExt;
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use color_eyre::eyre::{eyre, Context};
-use color_eyre::Result;
+use color_eyre::{Report, Result};
 use rustix::path::Arg;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
@@ -104,18 +107,20 @@ use tracing::{debug, warn};
 
 const ENTRYPOINT: &str = "/var/lib/bcvk/entrypoint";
 
-/// Get default vCPU count (number of available processors, or 2 as fallback)
-pub fn default_vcpus() -> u32 {
-    std::thread::available_parallelism()
-        .map(|n| n.get() as u32)
-        .unwrap_or(2)
-}
+/// How often podman runs the health check of detached containers
+const HEALTH_INTERVAL: &str = "30s";
 
-use crate::qemu;
+use crate::credentials::SwapBackend;
+use crate::host_devices::HostDevice;
+use crate::hostexec::HostCommand;
+use crate::image_pull::PullPolicy;
+use crate::qemu::{self, default_vcpus};
 use crate::{
     boot_progress,
-    common_opts::MemoryOpts,
+    common_opts::{expand_karg_profiles, KargProfile, MemoryOpts, ResourceLimits, SwapOpts},
+    envdetect::{self, AccelMode, UnprivilegedMode, TCG_DEFAULT_MEMORY},
     podman,
+    qemu_watchdog::{self, RestartPolicy},
     supervisor_status::{StatusWriter, SupervisorState, SupervisorStatus},
     systemd, utils, CONTAINER_STATEDIR,
 };
@@ -175,9 +180,46 @@ pub struct CommonVmOpts {
     #[clap(flatten)]
     pub memory: MemoryOpts,
 
+    #[clap(
+        long,
+        value_name = "SIZE",
+        help = "Let the VM grow up to this much memory with `bcvk ephemeral set-memory`; it starts with --memory"
+    )]
+    pub memory_max: Option<String>,
+
     #[clap(long, help = "Number of vCPUs (overridden by --itype if specified)")]
     pub vcpus: Option<u32>,
 
+    #[clap(
+        long,
+        value_name = "MODEL",
+        help = "CPU model to present to the guest, optionally with flags (e.g. max, Skylake-Server,-hle); default: host"
+    )]
+    pub cpu: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "sockets=N,cores=N,threads=N",
+        help = "vCPU topology; the vCPU count defaults to the product, and must match --vcpus or --itype if given"
+    )]
+    pub smp: Option<qemu::SmpTopology>,
+
+    #[clap(
+        long,
+        value_name = "base=utc|localtime,clock=host|vm",
+        help = "Guest real-time clock; clock=vm only advances it while the guest runs"
+    )]
+    pub rtc: Option<qemu::RtcConfig>,
+
+    #[clap(
+        long,
+        value_name = "TIMESTAMP",
+        value_parser = qemu::parse_rtc_timestamp,
+        conflicts_with = "rtc",
+        help = "Start the guest clock at this time (e.g. 2030-01-01T00:00:00Z or @SECONDS), advancing only while the guest runs"
+    )]
+    pub freeze_time: Option<i64>,
+
     #[clap(long, help = "Enable console output to terminal for debugging")]
     pub console: bool,
 
@@ -206,6 +248,38 @@ pub struct CommonVmOpts {
         help = "Generate SSH keypair and inject via systemd credentials"
     )]
     pub ssh_keygen: bool,
+
+    #[clap(
+        long,
+        value_name = "NAME[:UID]",
+        help = "Also create this user for SSH, with sudo rights, and connect as it by default (requires --ssh-keygen)"
+    )]
+    pub ssh_user: Option<crate::credentials::SshUser>,
+
+    #[clap(
+        long,
+        help = "Attach a TPM 2.0 device emulated by swtpm (requires swtpm on the host)"
+    )]
+    pub tpm: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = AccelMode::Auto,
+        help = "Accelerator; auto falls back to TCG software emulation, with less default memory, if /dev/kvm is not accessible"
+    )]
+    pub accel: AccelMode,
+
+    #[clap(
+        long,
+        value_name = "default|microvm",
+        default_value = "default",
+        help = "QEMU machine type; microvm boots faster but has no PCI, falling back to the default where it is unavailable (e.g. with --tpm or --device)"
+    )]
+    pub machine: qemu::MachineType,
+
+    #[clap(flatten)]
+    pub resources: ResourceLimits,
 }
 
 impl CommonVmOpts {
@@ -214,24 +288,186 @@ impl CommonVmOpts {
         if let Some(itype) = self.itype {
             Ok(itype.memory_mb())
         } else {
-            crate::utils::parse_memory_to_mb(&self.memory.memory)
+            crate::utils::parse_memory_to_mb(self.memory.memory())
         }
     }
 
-    /// Get vCPU count, using instancetype if specified
+    /// The guest real-time clock, if configured
+    pub fn rtc(&self) -> Option<qemu::RtcConfig> {
+        self.freeze_time
+            .map(qemu::RtcConfig::frozen_at)
+            .or(self.rtc)
+    }
+
+    /// Parse the maximum memory to MB, if memory ballooning is enabled
+    pub fn memory_max_mb(&self) -> color_eyre::Result<Option<u32>> {
+        self.memory_max
+            .as_deref()
+            .map(crate::utils::parse_memory_to_mb)
+            .transpose()
+    }
+
+    /// Get vCPU count, using instancetype or the topology if specified
     pub fn vcpus(&self) -> color_eyre::Result<u32> {
-        if let Some(itype) = self.itype {
-            Ok(itype.vcpus())
+        let vcpus = if let Some(itype) = self.itype {
+            itype.vcpus()
+        } else if let Some(vcpus) = self.vcpus {
+            vcpus
+        } else if let Some(smp) = self.smp {
+            smp.vcpus()
         } else {
-            Ok(self.vcpus.unwrap_or_else(default_vcpus))
+            default_vcpus()
+        };
+        if let Some(smp) = self.smp.filter(|smp| smp.vcpus() != vcpus) {
+            return Err(eyre!(
+                "--smp {smp} has {} vCPUs, but {vcpus} are configured",
+                smp.vcpus()
+            ));
+        }
+        Ok(vcpus)
+    }
+}
+
+/// Container path where the host directory for a disk-backed overlay is mounted
+pub(crate) const OVERLAY_BACKING_DIR: &str = "/run/overlay-backing";
+
+/// Symlink to the overlay disk of the running VM, if disk-backed
+pub(crate) const OVERLAY_DISK_LINK: &str = "/run/overlay-disk";
+
+/// QMP socket of the running VM
+pub(crate) const QMP_SOCKET: &str = "/run/qmp.sock";
+
+/// Status of the VM written by the supervisor, see [`crate::supervisor_status`]
+pub(crate) const SUPERVISOR_STATUS: &str = "/run/supervisor-status.json";
+
+/// Timestamped output of the guest console, unless it is interactive (`--console`)
+pub(crate) const CONSOLE_LOG: &str = "/run/console.log";
+
+/// Mount point of the Ignition config given with `--ignition` in the container
+const IGNITION_CONFIG: &str = "/run/ignition.ign";
+
+/// Environment variable with additional QEMU arguments, split like a shell would
+const QEMU_ARGS_ENV: &str = "BCVK_QEMU_ARGS";
+
+/// State directory of the emulated TPM; this only lives as long as the container
+const SWTPM_STATE_DIR: &str = "/run/swtpm";
+
+/// Container label recording the memory of the VM, in megabytes
+pub(crate) const MEMORY_LABEL: &str = "bcvk.memory-mb";
+
+/// Container path where the disk image for `bcvk ephemeral boot-disk` is mounted
+const BOOT_DISK_PATH: &str = "/run/boot-disk/disk";
+
+/// Copy of the UEFI variables template used when booting a disk image
+const BOOT_DISK_VARS: &str = "/run/qemu/efivars.fd";
+
+/// Guest mount point of the host container storage (`hoststorage`)
+const HOST_STORAGE_GUEST_PATH: &str = "/run/virtiofs-mnt-hoststorage";
+
+/// Serial (and thus `/dev/disk/by-id/virtio-<serial>`) of the overlay disk
+const OVERLAY_DISK_SERIAL: &str = "bcvk-overlay";
+
+/// Size of a disk-backed overlay when `--overlay-size` is not given.
+/// The file is sparse, so this is only an upper bound.
+const DEFAULT_OVERLAY_DISK_SIZE: u64 = 20 * 1024 * 1024 * 1024;
+
+/// Where the writable overlay on top of the (read-only) root filesystem lives
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverlayBacking {
+    /// A tmpfs inside the VM, i.e. in guest memory (`systemd.volatile=overlay`)
+    #[default]
+    Tmpfs,
+    /// An ext4 formatted disk image created in the given host directory
+    Disk(Utf8PathBuf),
+}
+
+impl std::str::FromStr for OverlayBacking {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "tmpfs" => Ok(Self::Tmpfs),
+            Some(("disk", path)) if !path.is_empty() => Ok(Self::Disk(path.into())),
+            _ => Err(eyre!(
+                "Invalid overlay backing '{s}'. Expected 'tmpfs' or 'disk:<path>'"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OverlayBacking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tmpfs => write!(f, "tmpfs"),
+            Self::Disk(path) => write!(f, "disk:{path}"),
+        }
+    }
+}
+
+/// Options for the transient root filesystem overlay of an ephemeral VM.
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OverlayOpts {
+    #[clap(
+        long,
+        value_name = "SIZE",
+        help = "Size of the writable root overlay (e.g. 10G); disk images default to 20G (sparse)"
+    )]
+    pub overlay_size: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "tmpfs|disk:PATH",
+        default_value = "tmpfs",
+        help = "Back the root overlay by guest memory, or by a disk image created in the host directory PATH"
+    )]
+    pub overlay_backing: OverlayBacking,
+
+    #[clap(
+        long,
+        help = "Keep the overlay disk image after the VM exits (requires --overlay-backing=disk:PATH)"
+    )]
+    pub keep_overlay: bool,
+}
+
+impl OverlayOpts {
+    /// Parse the overlay size to bytes, if one was given
+    fn size(&self) -> Result<Option<u64>> {
+        self.overlay_size
+            .as_deref()
+            .map(utils::parse_size)
+            .transpose()
+            .context("Parsing --overlay-size")
+    }
+
+    /// Validate the options on the host, making a disk backing path absolute.
+    fn validate(&mut self) -> Result<()> {
+        self.size()?;
+        match &mut self.overlay_backing {
+            OverlayBacking::Tmpfs if self.keep_overlay => Err(eyre!(
+                "--keep-overlay requires --overlay-backing=disk:<path>"
+            )),
+            OverlayBacking::Tmpfs => Ok(()),
+            OverlayBacking::Disk(path) => {
+                let abs = path
+                    .canonicalize_utf8()
+                    .with_context(|| format!("Overlay backing directory {path}"))?;
+                if !abs.is_dir() {
+                    return Err(eyre!("Overlay backing path {abs} is not a directory"));
+                }
+                *path = abs;
+                Ok(())
+            }
         }
     }
 }
 
 /// Ephemeral VM options: container-style flags, host bind mounts, systemd injection.
-#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RunEphemeralOpts {
-    #[clap(help = "Container image to run as ephemeral VM")]
+    #[clap(
+        help = "Container image to run as ephemeral VM",
+        add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names)
+    )]
     pub image: String,
 
     #[clap(flatten)]
@@ -271,9 +507,41 @@ pub struct RunEphemeralOpts {
     )]
     pub bind_storage_ro: bool,
 
-    #[clap(long, help = "Allocate a swap device of the provided size")]
+    #[clap(
+        long,
+        help = "Mount host container storage (RO) at /run/virtiofs-mnt-hoststorage and use it as an additional image store"
+    )]
+    #[serde(default)]
+    pub share_host_images: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "swap",
+        help = "Allocate a swap device of the provided size"
+    )]
     pub add_swap: Option<String>,
 
+    #[clap(flatten)]
+    #[serde(default)]
+    pub swap: SwapOpts,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PullPolicy::Never,
+        help = "Pull the image before running it: always, if it is missing, or never"
+    )]
+    #[serde(default)]
+    pub pull: PullPolicy,
+
+    #[clap(
+        long = "device",
+        value_name = "vfio:PCI_ADDRESS|/dev/dri[/renderDN]",
+        help = "Pass a host device through: a PCI device bound to vfio-pci, or a GPU render node for virtio-gpu with OpenGL and Vulkan (can be specified multiple times)"
+    )]
+    #[serde(default)]
+    pub devices: Vec<HostDevice>,
+
     #[clap(
         long = "mount-disk-file",
         value_name = "FILE[:NAME]",
@@ -281,9 +549,137 @@ pub struct RunEphemeralOpts {
     )]
     pub mount_disk_files: Vec<String>,
 
+    #[clap(
+        long,
+        value_name = "cache=none|writeback|unsafe,aio=io_uring|threads",
+        help = "Host cache and I/O settings of the --mount-disk-file disks; QEMU's defaults apply to unset ones"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_io: Option<qemu::DiskIoConfig>,
+
     #[clap(long = "karg", help = "Additional kernel command line arguments")]
     pub kernel_args: Vec<String>,
 
+    #[clap(
+        long = "karg-profile",
+        value_enum,
+        value_name = "PROFILE",
+        help = "Add the kernel arguments of a named profile (repeatable); --karg arguments come after them"
+    )]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub karg_profiles: Vec<KargProfile>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with = "console",
+        help = "Write the timestamped guest console output to this host file, which is kept after the VM exits"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub console_log: Option<Utf8PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Provide an Ignition config to the guest via QEMU fw_cfg, for images provisioned with Ignition"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignition: Option<Utf8PathBuf>,
+
+    #[clap(
+        long,
+        help = "Run an agent in the guest executing commands sent over vsock, for `bcvk ephemeral exec --via vsock`; it only accepts connections with a secret generated for the VM"
+    )]
+    #[serde(default)]
+    pub vsock_exec: bool,
+
+    #[clap(
+        long,
+        value_name = "no|on-failure[:N]",
+        default_value = "no",
+        conflicts_with_all = ["rm", "execute"],
+        help = "Restart the container when the VM fails (at most N times), as podman's --restart; a guest which panicked or stopped on an error is terminated for this"
+    )]
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser = utils::parse_duration,
+        help = "Terminate the VM and its container after this time (e.g. 90m, 2h; plain numbers are seconds), even if bcvk is killed"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+
+    /// When the VM is terminated, in seconds since the epoch
+    /// Not a CLI option - set from --timeout when the container is created,
+    /// so that the time left doesn't start over when it is restarted
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Run the VM even if it requests more memory than the host has, and without warning about overcommitting it"
+    )]
+    #[serde(default)]
+    pub force: bool,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = UnprivilegedMode::Auto,
+        help = "Run the VM container without its own PID namespace and /proc, and with fuse-overlayfs storage, for unprivileged containers such as CI jobs; auto does so when /proc is masked"
+    )]
+    #[serde(default)]
+    pub unprivileged: UnprivilegedMode,
+
+    #[clap(
+        long = "qemu-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Append a raw argument to the QEMU command line (repeatable); see also BCVK_QEMU_ARGS"
+    )]
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+
+    #[clap(flatten)]
+    #[serde(default)]
+    pub overlay: OverlayOpts,
+
+    #[clap(
+        long,
+        help = "Extract the kernel and initramfs on every boot instead of caching them per image"
+    )]
+    #[serde(default)]
+    pub no_kernel_cache: bool,
+
+    /// Key of the kernel cache entry for the image
+    /// Not a CLI option - set from the image ID unless --no-kernel-cache is given,
+    /// if the caller did not already do so
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_cache_key: Option<String>,
+
+    /// Host path of a checkpoint to restore instead of booting
+    /// Not a CLI option - set by `bcvk ephemeral restore`
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restore_from: Option<Utf8PathBuf>,
+
+    /// Host path of a disk image to boot instead of the image's root filesystem
+    /// Not a CLI option - set by `bcvk ephemeral boot-disk`
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_disk: Option<Utf8PathBuf>,
+
+    /// Boot the disk with emulated AHCI and e1000e devices instead of virtio
+    /// Not a CLI option - set by `bcvk ephemeral boot-disk --emulated-devices`
+    #[clap(skip)]
+    #[serde(default)]
+    pub emulated_devices: bool,
+
     /// Host DNS servers (read on host, configured via podman --dns flags)
     /// Not a CLI option - populated automatically from host's /etc/resolv.conf
     #[clap(skip)]
@@ -291,6 +687,46 @@ pub struct RunEphemeralOpts {
     pub host_dns_servers: Option<Vec<String>>,
 }
 
+/// Copy the guest console output from `input` to `output` line by line,
+/// prefixing each line with the time returned by `now`
+fn copy_console_log(
+    input: impl BufRead,
+    mut output: impl Write,
+    now: impl Fn() -> String,
+) -> std::io::Result<()> {
+    for line in input.split(b'\n') {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        write!(output, "{} ", now())?;
+        output.write_all(line)?;
+        output.write_all(b"\n")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Terminate QEMU once `deadline` (seconds since the epoch) has passed; the
+/// returned flag is set when it did
+///
+/// This runs in the container entrypoint, so that the VM is torn down even
+/// if the bcvk process which created it is gone.
+fn spawn_deadline_timer(qemu_pid: u32, deadline: u64) -> Arc<AtomicBool> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&expired);
+    std::thread::spawn(move || {
+        let deadline = SystemTime::UNIX_EPOCH + Duration::from_secs(deadline);
+        if let Ok(left) = deadline.duration_since(SystemTime::now()) {
+            std::thread::sleep(left);
+        }
+        warn!("The VM reached its --timeout, terminating it");
+        flag.store(true, Ordering::SeqCst);
+        if let Some(pid) = rustix::process::Pid::from_raw(qemu_pid as i32) {
+            let _ = rustix::process::kill_process(pid, rustix::process::Signal::TERM);
+        }
+    });
+    expired
+}
+
 /// Parse DNS servers from resolv.conf format content
 fn parse_resolv_conf(content: &str) -> Vec<String> {
     let mut dns_servers = Vec::new();
@@ -364,6 +800,20 @@ fn read_host_dns_servers() -> Option<Vec<String>> {
     None
 }
 
+impl RunEphemeralOpts {
+    /// Prepend the QEMU arguments from `BCVK_QEMU_ARGS`, so `--qemu-arg` can override them
+    ///
+    /// Only the `ephemeral run` and `run-ssh` commands honor the variable.
+    pub(crate) fn add_qemu_args_from_env(&mut self) -> Result<()> {
+        if let Ok(env_args) = std::env::var(QEMU_ARGS_ENV) {
+            let env_args = shlex::split(&env_args)
+                .ok_or_else(|| eyre!("Failed to parse {QEMU_ARGS_ENV}: {env_args}"))?;
+            self.qemu_args.splice(0..0, env_args);
+        }
+        Ok(())
+    }
+}
+
 /// Launch privileged container with QEMU+KVM for ephemeral VM, spawning as subprocess.
 /// Returns the container ID instead of executing the command.
 pub fn run_detached(opts: RunEphemeralOpts) -> Result<String> {
@@ -372,7 +822,7 @@ pub fn run_detached(opts: RunEphemeralOpts) -> Result<String> {
     // Leak the tempdir to keep it alive for the entire container lifetime
     std::mem::forget(temp_dir);
 
-    debug!("Podman command: {:?}", cmd);
+    debug!("Podman command: {cmd}");
     let output = cmd.output().context("Failed to execute podman command")?;
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
@@ -386,18 +836,118 @@ pub fn run_detached(opts: RunEphemeralOpts) -> Result<String> {
 
 /// Launch privileged container with QEMU+KVM for ephemeral VM.
 pub fn run(opts: RunEphemeralOpts) -> Result<()> {
+    if crate::images::needs_import(&opts.image) {
+        return run_imported(opts);
+    }
     let (mut cmd, _temp_dir) = prepare_run_command_with_temp(opts)?;
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(&cmd);
+        return Ok(());
+    }
     // Keep _temp_dir alive until exec replaces our process
     // At this point our process is replaced by `podman`, we are just a wrapper for creating
     // a container image and nothing else lives past that event.
-    return Err(cmd.exec()).context("execve");
+    return Err(cmd.as_std_mut().exec()).context("execve");
+}
+
+/// Run an image from an OCI archive or directory, imported into a temporary
+/// image store which has to outlive the container; so unlike [`run`], podman
+/// runs as a child process rather than replacing this one.
+fn run_imported(mut opts: RunEphemeralOpts) -> Result<()> {
+    if opts.podman.detach {
+        return Err(eyre!(
+            "Images from OCI archives or directories cannot be run detached, as their temporary image store is removed when bcvk exits"
+        ));
+    }
+    // Ctrl-C in the terminal also stops podman; the store is removed then too
+    let imported = crate::images::import(&opts.image)?;
+    opts.image = imported.id().to_owned();
+    let (mut cmd, _temp_dir) = prepare_run_command_with_temp(opts)?;
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(&cmd);
+        return Ok(());
+    }
+    let status = cmd
+        .as_std_mut()
+        .status()
+        .context("Failed to execute podman command")?;
+    // Exit like podman would have, after removing the image store
+    crate::ExitStatus::check(
+        status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()),
+    )
 }
 
 fn prepare_run_command_with_temp(
-    opts: RunEphemeralOpts,
-) -> Result<(std::process::Command, tempfile::TempDir)> {
+    mut opts: RunEphemeralOpts,
+) -> Result<(HostCommand, tempfile::TempDir)> {
     debug!("Running QEMU inside hybrid container for {}", opts.image);
 
+    opts.overlay.validate()?;
+    opts.swap.size()?;
+    if let Some(timeout) = opts.timeout.filter(|_| opts.deadline.is_none()) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context("Invalid system time")?;
+        opts.deadline = Some((now + timeout).as_secs());
+    }
+    crate::image_pull::ensure(&opts.image, opts.pull)?;
+    for device in opts.devices.iter_mut() {
+        device.check()?;
+        device.check_access()?;
+    }
+    if opts
+        .devices
+        .iter()
+        .any(|d| matches!(d, HostDevice::Vfio(_)))
+    {
+        crate::host_devices::check_memlock(opts.common.memory_mb()?.into())?;
+    }
+
+    crate::qemu::validate_extra_args(&opts.qemu_args)?;
+    // Catch an --smp topology not matching the vCPU count before starting the container
+    opts.common.vcpus()?;
+    if opts.common.ssh_user.is_some() && !opts.common.ssh_keygen {
+        return Err(eyre!("--ssh-user requires --ssh-keygen"));
+    }
+    // Decide on the accelerator here, where /dev/kvm can still be left out of the container
+    let requested_accel = opts.common.accel;
+    opts.common.accel = envdetect::resolve_accel(requested_accel)?;
+    if requested_accel == AccelMode::Auto
+        && opts.common.accel == AccelMode::Tcg
+        && opts.common.itype.is_none()
+        && opts.common.memory.memory.is_none()
+    {
+        debug!("Using {TCG_DEFAULT_MEMORY} of memory for TCG");
+        opts.common.memory.memory = Some(TCG_DEFAULT_MEMORY.to_owned());
+    }
+    if !opts.force {
+        // With a balloon, the guest can grow to the maximum
+        let memory_mb = match opts.common.memory_max_mb()? {
+            Some(max) => max,
+            None => opts.common.memory_mb()?,
+        };
+        let vm = crate::preflight::VmResources {
+            memory_mb: memory_mb.into(),
+            vcpus: opts.common.vcpus()?,
+            ..Default::default()
+        };
+        crate::preflight::check(&vm, &crate::preflight::HostResources::local()?)?;
+    }
+    // Unprivileged containers (e.g. CI jobs) mask /proc and cannot hold native overlay mounts
+    let unprivileged_caps = envdetect::HostCapabilities::detect_unprivileged(opts.unprivileged);
+    if let Some(caps) = &unprivileged_caps {
+        let blockers = caps.blockers();
+        if !blockers.is_empty() {
+            return Err(eyre!(
+                "Cannot run the VM container unprivileged: {}; see bcvk ephemeral check-host",
+                blockers.join("; ")
+            ));
+        }
+        debug!("Running the VM container in unprivileged mode");
+    }
+
     let script = include_str!("../scripts/entrypoint.sh");
 
     let td = tempfile::tempdir()?;
@@ -424,7 +974,7 @@ fn prepare_run_command_with_temp(
     let mut host_mounts = Vec::new();
 
     // Add container storage mount if requested
-    if opts.bind_storage_ro {
+    if opts.bind_storage_ro || opts.share_host_images {
         let storage_path = utils::detect_container_storage_path().context(
             "Failed to detect container storage path. Use --ro-bind to specify manually.",
         )?;
@@ -470,7 +1020,10 @@ fn prepare_run_command_with_temp(
     }
 
     // Run the container with the setup script
-    let mut cmd = Command::new("podman");
+    let mut cmd = crate::images::podman();
+    if let Some(option) = unprivileged_caps.as_ref().and_then(|c| c.storage_option()) {
+        cmd.arg(option);
+    }
     cmd.arg("run");
     // We don't do pulling because then we'd have to propagate all the authfile
     // and status output for that in the general case.
@@ -480,6 +1033,19 @@ fn prepare_run_command_with_temp(
     for label in opts.podman.label.iter() {
         cmd.arg(format!("--label={label}"));
     }
+    // Shown by `bcvk ephemeral list`
+    cmd.arg(format!(
+        "--label={MEMORY_LABEL}={}",
+        opts.common.memory_mb()?
+    ));
+    // Read back by `bcvk ephemeral ssh` to pick the default user
+    if let Some(user) = &opts.common.ssh_user {
+        cmd.arg(format!(
+            "--label={}={}",
+            crate::ssh::SSH_USER_LABEL,
+            user.name
+        ));
+    }
 
     // We always want this to be a tmpfs on general principle
     // to match the running system. But also, apparently creating
@@ -507,10 +1073,23 @@ fn prepare_run_command_with_temp(
     }
     if opts.podman.detach {
         cmd.arg("-d");
+        // Detached containers are long-running, let podman report failed VMs
+        cmd.arg(format!(r#"--health-cmd=["{ENTRYPOINT}", "health"]"#));
+        cmd.arg(format!("--health-interval={HEALTH_INTERVAL}"));
+    }
+    if opts.restart_policy != RestartPolicy::No {
+        cmd.arg(format!("--restart={}", opts.restart_policy));
+    }
+    for env in crate::logging::container_env() {
+        cmd.arg(format!("--env={env}"));
     }
     for env in opts.podman.env.iter() {
         cmd.arg(format!("--env={env}"));
     }
+    if unprivileged_caps.is_some() {
+        cmd.arg(format!("--env={}=1", envdetect::UNPRIVILEGED_ENV));
+    }
+    cmd.args(opts.common.resources.podman_args());
 
     let vhost_dev = Utf8Path::new(qemu::VHOST_VSOCK)
         .try_exists()?
@@ -533,9 +1112,32 @@ fn prepare_run_command_with_temp(
         // Ensure we can create large files on the host and not in the overlay
         "-v",
         "/var/tmp:/var/tmp",
-        "--device=/dev/kvm",
     ]);
+    if opts.common.accel.accel() == qemu::Accel::Kvm {
+        cmd.arg("--device=/dev/kvm");
+    }
     cmd.args(vhost_dev);
+    for device in &opts.devices {
+        for node in device.device_nodes()? {
+            cmd.arg(format!("--device={node}"));
+        }
+    }
+    if opts
+        .devices
+        .iter()
+        .any(|d| matches!(d, HostDevice::Vfio(_)))
+    {
+        // VFIO pins all guest memory
+        cmd.arg("--ulimit=memlock=-1:-1");
+    }
+    if opts
+        .devices
+        .iter()
+        .any(|d| matches!(d, HostDevice::RenderNode(_)))
+    {
+        // Render nodes are usually only accessible to the render group
+        cmd.arg("--group-add=keep-groups");
+    }
     cmd.args([
         "-v",
         // The core way things work here is we run the host as a nested container
@@ -573,6 +1175,69 @@ fn prepare_run_command_with_temp(
         cmd.args(["-v", &format!("{}:{}:rw", disk_file, container_disk_path)]);
     }
 
+    // Mount the host directory which will hold a disk-backed overlay
+    if let OverlayBacking::Disk(ref dir) = opts.overlay.overlay_backing {
+        cmd.args(["-v", &format!("{dir}:{OVERLAY_BACKING_DIR}")]);
+    }
+
+    // Mount the checkpoint to restore from
+    if let Some(ref checkpoint) = opts.restore_from {
+        cmd.args([
+            "-v",
+            &format!(
+                "{checkpoint}:{}:ro",
+                crate::checkpoint::CONTAINER_CHECKPOINT_DIR
+            ),
+        ]);
+    }
+
+    // Mount the disk image to boot; QEMU never writes to it
+    if let Some(ref disk) = opts.boot_disk {
+        cmd.args(["-v", &format!("{disk}:{BOOT_DISK_PATH}:ro")]);
+    }
+
+    // Mount the cache of extracted kernels; a disk image brings its own kernel
+    if !opts.no_kernel_cache && opts.boot_disk.is_none() {
+        let cache_dir = crate::kernel_cache::host_cache_dir()?;
+        if !crate::hostexec::dry_run() {
+            std::fs::create_dir_all(&cache_dir).with_context(|| format!("Creating {cache_dir}"))?;
+            crate::kernel_cache::prune(&cache_dir).context("Pruning kernel cache")?;
+        }
+        if opts.kernel_cache_key.is_none() {
+            let image_id = crate::images::inspect(&opts.image)?.id;
+            opts.kernel_cache_key = Some(crate::kernel_cache::cache_key(&image_id));
+        }
+        cmd.args([
+            "-v",
+            &format!(
+                "{cache_dir}:{}",
+                crate::kernel_cache::CONTAINER_KERNEL_CACHE_DIR
+            ),
+        ]);
+    }
+
+    // Mount the host file receiving the console log over the one in the container
+    if let Some(ref path) = opts.console_log {
+        let path = if crate::hostexec::dry_run() {
+            path.clone()
+        } else {
+            File::create(path).with_context(|| format!("Creating {path}"))?;
+            path.canonicalize_utf8()
+                .with_context(|| format!("Resolving {path}"))?
+        };
+        cmd.args(["-v", &format!("{path}:{CONSOLE_LOG}")]);
+    }
+
+    // Mount the Ignition config, checking it first as errors in it only
+    // show up on the guest console
+    if let Some(ref path) = opts.ignition {
+        crate::utils::read_ignition_config(path)?;
+        let path = path
+            .canonicalize_utf8()
+            .with_context(|| format!("Resolving {path}"))?;
+        cmd.args(["-v", &format!("{path}:{IGNITION_CONFIG}:ro")]);
+    }
+
     // Mount systemd units directory if specified
     if let Some(ref units_dir) = opts.systemd_units_dir {
         cmd.args(["-v", &format!("{}:/run/systemd-units:ro", units_dir)]);
@@ -638,7 +1303,7 @@ fn prepare_run_command_with_temp(
 pub(crate) fn process_disk_files(
     disk_specs: &[String],
     image: &str,
-) -> Result<Vec<(Utf8PathBuf, String, crate::to_disk::Format)>> {
+) -> Result<Vec<(Utf8PathBuf, String, crate::qemu_img::ImageFormat)>> {
     use std::fs::File;
 
     let mut processed_disks = Vec::new();
@@ -647,35 +1312,34 @@ pub(crate) fn process_disk_files(
         return Ok(processed_disks);
     }
 
-    // Get image size for auto-sizing new disk files (2x the image size)
-    let image_size = podman::get_image_size(image)?;
-    // Use minimum 4GB or 2x image size, whichever is larger
-    let disk_size = std::cmp::max(image_size * 2, 4u64 * 1024 * 1024 * 1024);
+    // Size of new disk files: 2x the image size, with a minimum of 4GB; the
+    // image is only inspected if a disk file has to be created
+    let mut disk_size = None;
 
     for disk_spec in disk_specs {
         let (disk_file, disk_name, format) = if let Some((file, rest)) = disk_spec.split_once(':') {
             if let Some((name, format_str)) = rest.split_once(':') {
                 let format = match format_str {
-                    "raw" => crate::to_disk::Format::Raw,
-                    "qcow2" => crate::to_disk::Format::Qcow2,
+                    "raw" => crate::qemu_img::ImageFormat::Raw,
+                    "qcow2" => crate::qemu_img::ImageFormat::Qcow2,
                     _ => return Err(eyre!("Unsupported disk format: {}", format_str)),
                 };
                 (file.to_string(), name.to_string(), format)
             } else {
                 // Auto-detect format from file extension if not explicitly provided
                 let format = if file.ends_with(".qcow2") {
-                    crate::to_disk::Format::Qcow2
+                    crate::qemu_img::ImageFormat::Qcow2
                 } else {
-                    crate::to_disk::Format::Raw
+                    crate::qemu_img::ImageFormat::Raw
                 };
                 (file.to_string(), rest.to_string(), format)
             }
         } else {
             // Auto-detect format from file extension if not explicitly provided
             let format = if disk_spec.ends_with(".qcow2") {
-                crate::to_disk::Format::Qcow2
+                crate::qemu_img::ImageFormat::Qcow2
             } else {
-                crate::to_disk::Format::Raw
+                crate::qemu_img::ImageFormat::Raw
             };
             (disk_spec.clone(), "output".to_string(), format)
         };
@@ -695,6 +1359,13 @@ pub(crate) fn process_disk_files(
                 ));
             }
         } else {
+            let disk_size = match disk_size {
+                Some(size) => size,
+                None => {
+                    let image_size = podman::get_image_size(image)?;
+                    *disk_size.insert(std::cmp::max(image_size * 2, 4u64 * 1024 * 1024 * 1024))
+                }
+            };
             // Create sparse disk image file
             debug!(
                 "Creating new disk file {} (size: {} bytes)",
@@ -831,53 +1502,36 @@ fn check_required_container_binaries() -> Result<()> {
     Ok(())
 }
 
-/// VM execution inside container: extracts kernel/initramfs, starts virtiofsd processes,
-/// generates systemd mount units, sets up command execution, launches QEMU.
-pub(crate) async fn run_impl(opts: RunEphemeralOpts) -> Result<()> {
-    use crate::qemu;
-    use std::fs;
-
-    debug!("Running QEMU implementation inside container");
-
-    // Check for required binaries in the target container image early
-    check_required_container_binaries()?;
-
-    // Initialize status writer for supervisor monitoring
-    let status_writer = StatusWriter::new("/run/supervisor-status.json");
-    status_writer.update_state(SupervisorState::WaitingForSystemd)?;
-
-    // Check systemd version from the container image
-    let systemd_version = {
-        Some(std::env::var("SYSTEMD_VERSION")?)
-            .filter(|v| !v.is_empty())
-            .as_deref()
-            .map(systemd::SystemdVersion::from_version_output)
-            .transpose()?
-    };
-    debug!("Container image systemd version: {systemd_version:?}");
-
-    // Check if we need to handle cloud-init
-    let cloudinit = {
-        Command::new("systemctl")
-            .args([
-                "--root=/run/source-image",
-                "is-enabled",
-                "cloud-init.target",
-            ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?
-            .success()
-    };
-    tracing::debug!("Target image has cloud-init: {cloudinit}");
-
-    // Verify KVM access
-    if !Utf8Path::new("/dev/kvm").exists() || !fs::File::open("/dev/kvm").is_ok() {
-        return Err(eyre!("KVM device not accessible"));
-    }
+/// Extract the kernel (.linux section) and initramfs (.initrd section) from a UKI.
+fn extract_uki(uki_path: &Utf8Path, kernel: &Utf8Path, initramfs: &Utf8Path) -> Result<()> {
+    debug!("Extracting kernel and initramfs from UKI: {:?}", uki_path);
+
+    Command::new("objcopy")
+        .args([
+            "--dump-section",
+            &format!(".linux={}", kernel),
+            uki_path.as_str(),
+        ])
+        .run()
+        .map_err(|e| eyre!("Failed to extract kernel from UKI: {e}"))?;
+    debug!("Extracted kernel from UKI to {}", kernel);
+
+    Command::new("objcopy")
+        .args([
+            "--dump-section",
+            &format!(".initrd={}", initramfs),
+            uki_path.as_str(),
+        ])
+        .run()
+        .map_err(|e| eyre!("Failed to extract initramfs from UKI: {e}"))?;
+    debug!("Extracted initramfs from UKI to {}", initramfs);
+    Ok(())
+}
 
-    // Create QEMU mount points
-    fs::create_dir_all("/run/qemu")?;
+/// Find the kernel and initramfs of the source image, extracting them from
+/// a UKI if needed, and make them available at `/run/qemu/{kernel,initramfs}`.
+fn mount_kernel_and_initramfs(kernel_cache_key: Option<&str>) -> Result<()> {
+    use std::fs;
 
     // Find kernel and initramfs in /usr/lib/modules/
     let modules_dir = Utf8Path::new("/run/source-image/usr/lib/modules");
@@ -917,31 +1571,33 @@ pub(crate) async fn run_impl(opts: RunEphemeralOpts) -> Result<()> {
     let initramfs_mount = "/run/qemu/initramfs";
 
     // Extract from UKI if found, otherwise use traditional kernel
-    if let Some(uki_path) = uki_file {
-        debug!("Extracting kernel and initramfs from UKI: {:?}", uki_path);
-
-        // Extract .linux section (kernel) from UKI
-        Command::new("objcopy")
-            .args([
-                "--dump-section",
-                &format!(".linux={}", kernel_mount),
-                uki_path.as_str(),
-            ])
-            .run()
-            .map_err(|e| eyre!("Failed to extract kernel from UKI: {e}"))?;
-        debug!("Extracted kernel from UKI to {}", kernel_mount);
-
-        // Extract .initrd section (initramfs) from UKI
-        Command::new("objcopy")
-            .args([
-                "--dump-section",
-                &format!(".initrd={}", initramfs_mount),
-                uki_path.as_str(),
-            ])
-            .run()
-            .map_err(|e| eyre!("Failed to extract initramfs from UKI: {e}"))?;
-        debug!("Extracted initramfs from UKI to {}", initramfs_mount);
-    } else {
+    match (uki_file, kernel_cache_key) {
+        (Some(uki_path), Some(key)) => {
+            // Reuse a previous extraction for this image, bind mounted below
+            let entry = crate::kernel_cache::get_or_insert(
+                Utf8Path::new(crate::kernel_cache::CONTAINER_KERNEL_CACHE_DIR),
+                key,
+                |dir| {
+                    extract_uki(
+                        &uki_path,
+                        &dir.join(crate::kernel_cache::KERNEL),
+                        &dir.join(crate::kernel_cache::INITRAMFS),
+                    )
+                },
+            )?;
+            vmlinuz_path = Some(entry.join(crate::kernel_cache::KERNEL));
+            initramfs_path = Some(entry.join(crate::kernel_cache::INITRAMFS));
+        }
+        (Some(uki_path), None) => {
+            extract_uki(
+                &uki_path,
+                Utf8Path::new(kernel_mount),
+                Utf8Path::new(initramfs_mount),
+            )?;
+        }
+        (None, _) => {}
+    }
+    if !Utf8Path::new(kernel_mount).exists() {
         let vmlinuz_path = vmlinuz_path
             .ok_or_else(|| eyre!("No kernel found in /run/source-image/usr/lib/modules"))?;
         let initramfs_path = initramfs_path
@@ -968,6 +1624,65 @@ pub(crate) async fn run_impl(opts: RunEphemeralOpts) -> Result<()> {
             .map_err(|e| eyre!("Failed to bind mount initramfs: {e}"))?;
     }
 
+    Ok(())
+}
+
+/// VM execution inside container: extracts kernel/initramfs, starts virtiofsd processes,
+/// generates systemd mount units, sets up command execution, launches QEMU.
+pub(crate) async fn run_impl(opts: RunEphemeralOpts) -> Result<()> {
+    use crate::qemu;
+    use std::fs;
+
+    debug!("Running QEMU implementation inside container");
+
+    // Check for required binaries in the target container image early
+    check_required_container_binaries()?;
+
+    // Initialize status writer for supervisor monitoring
+    let status_writer = StatusWriter::new(SUPERVISOR_STATUS);
+    status_writer.update_state(SupervisorState::WaitingForSystemd)?;
+
+    // Check systemd version from the container image
+    let systemd_version = {
+        Some(std::env::var("SYSTEMD_VERSION")?)
+            .filter(|v| !v.is_empty())
+            .as_deref()
+            .map(systemd::SystemdVersion::from_version_output)
+            .transpose()?
+    };
+    debug!("Container image systemd version: {systemd_version:?}");
+
+    // Check if we need to handle cloud-init
+    let cloudinit = {
+        Command::new("systemctl")
+            .args([
+                "--root=/run/source-image",
+                "is-enabled",
+                "cloud-init.target",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?
+            .success()
+    };
+    tracing::debug!("Target image has cloud-init: {cloudinit}");
+
+    // Verify KVM access, unless emulating
+    let accel = opts.common.accel.accel();
+    if accel == qemu::Accel::Kvm
+        && (!Utf8Path::new("/dev/kvm").exists() || !fs::File::open("/dev/kvm").is_ok())
+    {
+        return Err(Report::new(crate::Failure::HypervisorUnavailable)
+            .wrap_err("KVM device not accessible"));
+    }
+
+    // Create QEMU mount points
+    fs::create_dir_all("/run/qemu")?;
+
+    if opts.boot_disk.is_none() {
+        mount_kernel_and_initramfs(opts.kernel_cache_key.as_deref())?;
+    }
+
     // Process host mounts and prepare virtiofsd instances for each using async manager
     let mut additional_mounts = Vec::new();
     // Collect mount unit credentials to inject via SMBIOS instead of writing to filesystem
@@ -1038,6 +1753,21 @@ pub(crate) async fn run_impl(opts: RunEphemeralOpts) -> Result<()> {
         }
     }
 
+    // Pull images from the host container storage mounted above
+    if opts.share_host_images {
+        mount_unit_smbios_creds.extend(crate::credentials::smbios_creds_for_storage_opts(
+            HOST_STORAGE_GUEST_PATH,
+        )?);
+        debug!("Generated SMBIOS credentials for STORAGE_OPTS");
+    }
+
+    if opts.vsock_exec {
+        mount_unit_smbios_creds.extend(crate::credentials::smbios_creds_for_vsock_exec(
+            crate::vsock_exec::VSOCK_EXEC_PORT,
+        ));
+        debug!("Generated SMBIOS credentials for the vsock exec agent");
+    }
+
     // If we have mount units, create a single dropin for local-fs.target
     if !mount_unit_names.is_empty() {
         let wants_list = mount_unit_names.join(" ");
@@ -1160,27 +1890,102 @@ StandardOutput=file:/dev/virtio-ports/executestatus
 
     std::fs::create_dir_all(CONTAINER_STATEDIR)?;
 
-    // Configure qemu for direct kernel boot
-    debug!("Configuring QEMU for direct kernel boot");
-    let mut qemu_config = crate::qemu::QemuConfig::new_direct_boot(
-        opts.common.memory_mb()?,
-        opts.common.vcpus()?,
-        "/run/qemu/kernel".to_string(),
-        "/run/qemu/initramfs".to_string(),
-        main_virtiofsd_config.socket_path.clone(),
-    );
+    let mut qemu_config = if opts.boot_disk.is_some() {
+        // Boot the disk image with UEFI firmware, with its own bootloader and kernel
+        debug!("Configuring QEMU for firmware boot of {BOOT_DISK_PATH}");
+        let disk = Utf8Path::new(BOOT_DISK_PATH);
+        let disk_format = crate::qemu_img::info(disk)?.format;
+        let firmware = crate::libvirt::secureboot::find_uefi_firmware()?;
+        fs::copy(&firmware.vars_path, BOOT_DISK_VARS)
+            .with_context(|| format!("Copying {}", firmware.vars_path))?;
+        // The template is usually read-only, but the guest writes to the copy
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(BOOT_DISK_VARS, fs::Permissions::from_mode(0o644))?;
+        }
+        let mut config = crate::qemu::QemuConfig::new_firmware_boot(
+            opts.common.memory_mb()?,
+            opts.common.vcpus()?,
+            &firmware,
+            BOOT_DISK_VARS.into(),
+            disk.to_owned(),
+            disk_format,
+        );
+        config.set_emulated_devices(opts.emulated_devices);
+        config
+    } else {
+        // Configure qemu for direct kernel boot
+        debug!("Configuring QEMU for direct kernel boot");
+        crate::qemu::QemuConfig::new_direct_boot(
+            opts.common.memory_mb()?,
+            opts.common.vcpus()?,
+            "/run/qemu/kernel".to_string(),
+            "/run/qemu/initramfs".to_string(),
+            main_virtiofsd_config.socket_path.clone(),
+        )
+    };
+    qemu_config.set_accel(accel);
+    qemu_config.set_machine(opts.common.machine);
+    if let Some(rtc) = opts.common.rtc() {
+        qemu_config.set_rtc(rtc);
+    }
+    if let Some(cpu) = &opts.common.cpu {
+        qemu_config.set_cpu_model(cpu.clone());
+    }
+    if let Some(smp) = opts.common.smp {
+        qemu_config.set_smp_topology(smp);
+    }
+    let memory_max_mb = opts.common.memory_max_mb()?;
+    if let Some(max) = memory_max_mb {
+        qemu_config.enable_balloon(max);
+    }
 
     // Check for BCVK_DEBUG=disable-vsock to force disabling vsock for testing
     let vsock_force_disabled = std::env::var("BCVK_DEBUG").as_deref() == Ok("disable-vsock");
     let vsock_enabled = !vsock_force_disabled && qemu_config.enable_vsock().is_ok();
+    if opts.vsock_exec && !vsock_enabled {
+        return Err(eyre!(
+            "--vsock-exec requires vsock, but {} is not available",
+            qemu::VHOST_VSOCK
+        ));
+    }
+    if opts.vsock_exec {
+        let dir = Utf8Path::new(crate::vsock_exec::VSOCK_SECRET_DIR);
+        fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?;
+        qemu_config.add_secret_credential(
+            crate::credentials::VSOCK_EXEC_SECRET_CREDENTIAL,
+            &crate::vsock_exec::generate_secret(),
+            dir,
+        )?;
+    }
+
+    let restore_dir = opts
+        .restore_from
+        .is_some()
+        .then(|| Utf8Path::new(crate::checkpoint::CONTAINER_CHECKPOINT_DIR));
+
+    // The guest of a restored VM already has the saved public key
+    if let Some(dir) = restore_dir.filter(|_| opts.common.ssh_keygen) {
+        let statedir = Utf8Path::new(CONTAINER_STATEDIR);
+        for f in crate::checkpoint::SSH_KEY_FILES {
+            std::fs::copy(dir.join(f), statedir.join(f))
+                .with_context(|| format!("Restoring SSH key {f}"))?;
+        }
+    }
 
     // Handle SSH key generation and credential injection
     if opts.common.ssh_keygen {
-        let key_pair = crate::ssh::generate_default_keypair()?;
-        // Create credential and add to kernel args
-        let pubkey = std::fs::read_to_string(key_pair.public_key_path.as_path())?;
-        let credential = crate::credentials::smbios_cred_for_root_ssh(&pubkey)?;
-        qemu_config.add_smbios_credential(credential);
+        let pubkey_path = if restore_dir.is_some() {
+            Utf8Path::new(CONTAINER_STATEDIR).join("ssh.pub")
+        } else {
+            crate::ssh::generate_default_keypair()?.public_key_path
+        };
+        let pubkey = std::fs::read_to_string(&pubkey_path)?;
+        let credentials =
+            crate::credentials::smbios_creds_for_ssh(&pubkey, opts.common.ssh_user.as_ref())?;
+        for credential in credentials {
+            qemu_config.add_smbios_credential(credential);
+        }
     }
 
     // Build kernel command line for direct boot
@@ -1188,10 +1993,8 @@ StandardOutput=file:/dev/virtio-ports/executestatus
         // At the core we boot from the mounted container's root,
         "rootfstype=virtiofs",
         "root=rootfs",
-        // But read-only, with an overlayfs in the VM backed
-        // by tmpfs
+        // But read-only, with a writable overlay set up below
         "rootflags=ro",
-        "systemd.volatile=overlay",
         // This avoids having journald interact with the rootfs
         // at all, which lessens the I/O traffic for virtiofs
         "systemd.journald.storage=volatile",
@@ -1202,9 +2005,8 @@ StandardOutput=file:/dev/virtio-ports/executestatus
     .map(ToOwned::to_owned)
     .collect::<Vec<_>>();
 
-    if opts.common.console {
-        kernel_cmdline.push("console=hvc0".to_string());
-    }
+    // With --console this is connected to the terminal, and otherwise logged
+    kernel_cmdline.push("console=hvc0".to_string());
     if cloudinit {
         // We don't provide any cloud-init datasource right now,
         // though in the future it would make sense to do so,
@@ -1212,13 +2014,141 @@ StandardOutput=file:/dev/virtio-ports/executestatus
         kernel_cmdline.push("ds=iid-datasource-none".to_string());
     }
 
-    kernel_cmdline.extend(opts.kernel_args.clone());
+    // The writable overlay on top of the root filesystem
+    let mut tmp_overlay = None;
+    match &opts.overlay.overlay_backing {
+        OverlayBacking::Tmpfs => {
+            // An overlayfs in the VM backed by tmpfs
+            kernel_cmdline.push("systemd.volatile=overlay".to_string());
+            if let Some(size) = opts.overlay.size()? {
+                // systemd-volatile-root doesn't support configuring the size,
+                // so grow (or shrink) the tmpfs once we're in the real root.
+                let svc = format!(
+                    r#"[Unit]
+Description=Resize bcvk root overlay
+DefaultDependencies=no
+Before=sysinit.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/usr/bin/mount -o remount,size={size} /run/systemd/overlay-sysroot
+"#
+                );
+                let encoded_svc = data_encoding::BASE64.encode(svc.as_bytes());
+                mount_unit_smbios_creds.push(format!(
+                    "io.systemd.credential.binary:systemd.extra-unit.bcvk-overlay-size.service={encoded_svc}"
+                ));
+                let dropin = "[Unit]\nWants=bcvk-overlay-size.service\n";
+                let encoded_dropin = data_encoding::BASE64.encode(dropin.as_bytes());
+                mount_unit_smbios_creds.push(format!(
+                    "io.systemd.credential.binary:systemd.unit-dropin.sysinit.target~bcvk-overlay-size={encoded_dropin}"
+                ));
+                debug!("Generated SMBIOS credential to resize tmpfs overlay to {size}");
+            }
+        }
+        OverlayBacking::Disk(host_dir) => {
+            let size = opts.overlay.size()?.unwrap_or(DEFAULT_OVERLAY_DISK_SIZE);
+            let tmpf = tempfile::Builder::new()
+                .prefix("bcvk-overlay-")
+                .suffix(".img")
+                .tempfile_in(OVERLAY_BACKING_DIR)
+                .context("Creating overlay disk")?;
+            tmpf.as_file()
+                .set_len(size)
+                .context("Allocating overlay disk")?;
+            let path: Utf8PathBuf = tmpf.path().to_owned().try_into()?;
+            if let Some(dir) = restore_dir {
+                // Work on a copy, so the checkpoint can be restored again
+                crate::checkpoint::copy_sparse(
+                    dir.join(crate::checkpoint::OVERLAY_FILE).as_std_path(),
+                    path.as_std_path(),
+                )
+                .context("Restoring overlay disk")?;
+                debug!("Restored overlay disk {path}");
+            } else {
+                debug!("Allocated overlay disk {path} ({size} bytes)");
+                Command::new("mkfs.ext4")
+                    .args(["-q", "-F", "-L", "bcvk-overlay", path.as_str()])
+                    .run()
+                    .map_err(|e| eyre!("Formatting overlay disk: {e}"))?;
+            }
+            // For `bcvk ephemeral checkpoint`
+            std::os::unix::fs::symlink(&path, OVERLAY_DISK_LINK).context("Linking overlay disk")?;
+
+            qemu_config.add_virtio_blk_device_with_format(
+                path.to_string(),
+                OVERLAY_DISK_SERIAL.into(),
+                crate::qemu_img::ImageFormat::Raw,
+            );
+
+            // The equivalent of systemd.volatile=overlay, but with the upper
+            // directory on the overlay disk. This runs in the initramfs.
+            let device_unit = r#"dev-disk-by\x2did-virtio\x2dbcvk\x2doverlay.device"#;
+            let svc = format!(
+                r#"[Unit]
+Description=Set up bcvk disk-backed root overlay
+DefaultDependencies=no
+ConditionPathExists=/etc/initrd-release
+Requires={device_unit}
+After={device_unit} sysroot.mount
+Before=initrd-root-fs.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/usr/bin/mkdir -p /run/bcvk-overlay
+ExecStart=/usr/bin/mount /dev/disk/by-id/virtio-{OVERLAY_DISK_SERIAL} /run/bcvk-overlay
+ExecStart=/usr/bin/mkdir -p /run/bcvk-overlay/upper /run/bcvk-overlay/work
+ExecStart=/usr/bin/mount -t overlay overlay -o lowerdir=/sysroot,upperdir=/run/bcvk-overlay/upper,workdir=/run/bcvk-overlay/work /sysroot
+"#
+            );
+            let encoded_svc = data_encoding::BASE64.encode(svc.as_bytes());
+            mount_unit_smbios_creds.push(format!(
+                "io.systemd.credential.binary:systemd.extra-unit.bcvk-overlay.service={encoded_svc}"
+            ));
+            let dropin = "[Unit]\nWants=bcvk-overlay.service\n";
+            let encoded_dropin = data_encoding::BASE64.encode(dropin.as_bytes());
+            mount_unit_smbios_creds.push(format!(
+                "io.systemd.credential.binary:systemd.unit-dropin.initrd-root-fs.target~bcvk-overlay={encoded_dropin}"
+            ));
+            debug!("Generated SMBIOS credentials for disk-backed overlay");
+
+            let tmp_path = tmpf.into_temp_path();
+            if opts.overlay.keep_overlay {
+                // Keep it up front, so it survives the VM failing too
+                tmp_path.keep().context("Keeping overlay disk")?;
+                let name = path.file_name().unwrap_or_default();
+                eprintln!("Overlay disk will be kept at {host_dir}/{name}");
+            } else {
+                tmp_overlay = Some(tmp_path);
+            }
+        }
+    }
+
+    kernel_cmdline.extend(expand_karg_profiles(&opts.karg_profiles, &opts.kernel_args));
     qemu_config.set_kernel_cmdline(kernel_cmdline);
 
     // TODO allocate unlinked unnamed file and pass via fd
     let mut tmp_swapfile = None;
-    if let Some(size) = opts.add_swap {
-        let size = utils::parse_size(&size)?;
+    // The root filesystem is virtiofs, which can't hold a swap file, so a
+    // file-backed --swap is a disk backed by a file on the host instead
+    let swap_disk = match (opts.swap.size()?, opts.swap.swap_backend) {
+        (Some(size), SwapBackend::Zram) => {
+            mount_unit_smbios_creds.extend(crate::credentials::smbios_creds_for_swap(
+                size,
+                SwapBackend::Zram,
+            ));
+            None
+        }
+        (Some(size), SwapBackend::File) => Some(size),
+        (None, _) => opts
+            .add_swap
+            .as_deref()
+            .map(utils::parse_size)
+            .transpose()?,
+    };
+    if let Some(size) = swap_disk {
         debug!("Allocating swap: {size}");
         let mut tmpf = tempfile::NamedTempFile::new_in("/var/tmp")?;
         tmpf.as_file_mut()
@@ -1235,7 +2165,7 @@ StandardOutput=file:/dev/virtio-ports/executestatus
         qemu_config.add_virtio_blk_device_with_format(
             path.to_owned().into(),
             "swap".into(),
-            crate::to_disk::Format::Raw,
+            crate::qemu_img::ImageFormat::Raw,
         );
 
         // Create swap unit via SMBIOS credential
@@ -1275,23 +2205,23 @@ Options=
             if parts.len() >= 2 {
                 let format = if parts.len() == 3 {
                     match parts[2] {
-                        "qcow2" => crate::to_disk::Format::Qcow2,
-                        "raw" => crate::to_disk::Format::Raw,
+                        "qcow2" => crate::qemu_img::ImageFormat::Qcow2,
+                        "raw" => crate::qemu_img::ImageFormat::Raw,
                         _ => {
                             // Auto-detect from file extension as fallback
                             if parts[0].ends_with(".qcow2") {
-                                crate::to_disk::Format::Qcow2
+                                crate::qemu_img::ImageFormat::Qcow2
                             } else {
-                                crate::to_disk::Format::Raw
+                                crate::qemu_img::ImageFormat::Raw
                             }
                         }
                     }
                 } else {
                     // Auto-detect format from file extension
                     if parts[0].ends_with(".qcow2") {
-                        crate::to_disk::Format::Qcow2
+                        crate::qemu_img::ImageFormat::Qcow2
                     } else {
-                        crate::to_disk::Format::Raw
+                        crate::qemu_img::ImageFormat::Raw
                     }
                 };
 
@@ -1307,21 +2237,45 @@ Options=
                     ));
                 }
 
+                let io = opts.disk_io.unwrap_or_default();
                 debug!(
-                    "Adding virtio-blk device: file={}, serial={}, format={:?}",
-                    disk_file, serial, format
+                    "Adding virtio-blk device: file={}, serial={}, format={:?}, io={}",
+                    disk_file, serial, format, io
                 );
 
                 virtio_blk_devices.push(crate::qemu::VirtioBlkDevice {
                     disk_file,
                     serial,
                     format,
+                    io,
                 });
             }
         }
     }
 
     qemu_config.set_console(opts.common.console);
+    let mut console_copier = None;
+    if !opts.common.console {
+        let console_pipe = qemu_config.add_console_log_pipe()?;
+        let console_log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(CONSOLE_LOG)
+            .with_context(|| format!("Opening {CONSOLE_LOG}"))?;
+        console_copier = Some(std::thread::spawn(move || {
+            let input = std::io::BufReader::new(File::from(console_pipe));
+            let now = || chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            if let Err(e) = copy_console_log(input, console_log, now) {
+                warn!("Copying console output to {CONSOLE_LOG}: {e}");
+            }
+        }));
+        debug!("Logging the guest console to {CONSOLE_LOG}");
+    }
+
+    if opts.ignition.is_some() {
+        qemu_config.add_fw_cfg_file(crate::qemu::IGNITION_FW_CFG_NAME, IGNITION_CONFIG);
+        debug!("Providing the Ignition config via fw_cfg");
+    }
 
     // Add virtio-serial device for journal streaming
     qemu_config.add_virtio_serial_out("org.bcvk.journal", "/run/journal.log".to_string(), false);
@@ -1346,8 +2300,24 @@ Options=
         // TODO: Add proper SMBIOS credential injection if needed
     }
 
+    qemu_config.enable_qmp(QMP_SOCKET.into());
+    if opts.common.tpm {
+        qemu_config.enable_tpm(SWTPM_STATE_DIR.into());
+        debug!("Enabled emulated TPM 2.0");
+    }
+    for device in &opts.devices {
+        qemu_config.add_host_device(device.clone());
+    }
+    qemu_config.add_extra_args(opts.qemu_args.iter().cloned());
+    if let Some(dir) = restore_dir {
+        qemu_config.set_incoming_migration(dir.join(crate::checkpoint::STATE_FILE));
+        debug!("Restoring VM state from checkpoint");
+    }
+
     // Set main virtiofs configuration for root filesystem (will be spawned by QEMU)
-    qemu_config.set_main_virtiofs(main_virtiofsd_config.clone());
+    if opts.boot_disk.is_none() {
+        qemu_config.set_main_virtiofs(main_virtiofsd_config.clone());
+    }
 
     // Add additional virtiofs configurations (will be spawned by QEMU)
     for (virtiofs_config, tag) in additional_mounts {
@@ -1364,14 +2334,10 @@ Options=
 
     // Add virtio-blk devices
     for blk_device in virtio_blk_devices {
-        qemu_config.add_virtio_blk_device_with_format(
-            blk_device.disk_file,
-            blk_device.serial,
-            blk_device.format,
-        );
+        qemu_config.add_virtio_blk_device(blk_device);
     }
 
-    let status_writer_clone = StatusWriter::new("/run/supervisor-status.json");
+    let status_writer_clone = StatusWriter::new(SUPERVISOR_STATUS);
 
     // Only enable systemd notification debugging if the systemd version supports it
     // and the host has vsock enabled
@@ -1419,6 +2385,33 @@ Options=
         }
     };
 
+    // For `bcvk ephemeral exec --via vsock`
+    if opts.vsock_exec {
+        let cid = qemu
+            .guest_cid()
+            .ok_or_else(|| eyre!("No vsock CID could be allocated for --vsock-exec"))?;
+        std::fs::write(crate::vsock_exec::VSOCK_CID_FILE, cid.to_string())
+            .context("Writing vsock CID")?;
+    }
+
+    let deadline_timer = opts
+        .deadline
+        .map(|deadline| spawn_deadline_timer(qemu.qemu_process.id(), deadline));
+    let timed_out = || {
+        deadline_timer
+            .as_ref()
+            .is_some_and(|t| t.load(Ordering::SeqCst))
+    };
+
+    // Start with --memory; a restored VM keeps the balloon target it had
+    if memory_max_mb.is_some() && restore_dir.is_none() {
+        let memory_mb = opts.common.memory_mb()?;
+        qemu.qmp()?
+            .balloon(memory_mb)
+            .context("Setting initial memory balloon target")?;
+        debug!("Set initial balloon target to {memory_mb}MB");
+    }
+
     // Handle execute command output streaming if needed
     if let Some((exec_pipefd, status_pipefd)) = exec_pipes {
         tracing::debug!("Starting execute output streaming with pipes");
@@ -1443,6 +2436,9 @@ Options=
         // Discard errors from qemu and the output copier
         tracing::debug!("qemu exit status: {qemu:?}");
         tracing::debug!("output copy: {output_copier:?}");
+        if timed_out() {
+            return Err(eyre!("The command did not finish before the --timeout"));
+        }
 
         // Parse exit code from systemd service status
         let exit_code = parse_service_exit_code(&status)?;
@@ -1453,15 +2449,31 @@ Options=
             ));
         }
     } else {
+        // Terminate QEMU if the guest dies, so that the container is restarted
+        let watchdog = (opts.restart_policy != RestartPolicy::No)
+            .then(|| qemu_watchdog::spawn(Utf8Path::new(QMP_SOCKET)));
+
         // Wait for QEMU to complete
         tracing::debug!("Waiting for qemu exit");
         let exit_status = qemu.wait().await?;
-        if !exit_status.success() {
-            return Err(eyre!("QEMU exited with non-zero status: {}", exit_status));
+        let terminated = watchdog.and_then(|w| w.get().cloned());
+        if timed_out() {
+            // Not a failure, so that the container is not restarted
+            debug!("QEMU was terminated on --timeout: {exit_status}");
+        } else if !exit_status.success() || terminated.is_some() {
+            let console_log = console_copier.map(|c| (Utf8Path::new(CONSOLE_LOG), c));
+            let exit = qemu_watchdog::qemu_exit(exit_status, terminated, console_log);
+            let err = match exit.watchdog {
+                Some(ref reason) => eyre!("QEMU was terminated as {reason}"),
+                None => eyre!("QEMU exited with non-zero status: {}", exit_status),
+            };
+            status_writer.failed(exit)?;
+            return Err(err);
         }
     }
 
     drop(tmp_swapfile);
+    drop(tmp_overlay);
 
     debug!("QEMU completed successfully");
     status_writer.finish()?;
@@ -1473,6 +2485,51 @@ Options=
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_overlay_backing() {
+        let cases = [
+            ("tmpfs", Some(OverlayBacking::Tmpfs)),
+            (
+                "disk:/var/tmp",
+                Some(OverlayBacking::Disk("/var/tmp".into())),
+            ),
+            ("disk:", None),
+            ("disk", None),
+            ("tmpfs:/foo", None),
+            ("ramfs", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = input.parse::<OverlayBacking>().ok();
+            assert_eq!(parsed, expected, "input: {input}");
+            if let Some(backing) = parsed {
+                assert_eq!(backing.to_string(), input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vcpus_with_topology() {
+        let opts = |vcpus: Option<u32>, smp: &str| CommonVmOpts {
+            vcpus,
+            smp: Some(smp.parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(opts(None, "sockets=2,cores=3").vcpus().unwrap(), 6);
+        assert_eq!(opts(Some(4), "cores=2,threads=2").vcpus().unwrap(), 4);
+        assert!(opts(Some(4), "cores=2").vcpus().is_err());
+    }
+
+    #[test]
+    fn test_copy_console_log() {
+        let input = b"[    0.000000] Linux version 6.12\r\nWelcome\r\nlogin: ";
+        let mut output = Vec::new();
+        copy_console_log(&input[..], &mut output, || "T".to_owned()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "T [    0.000000] Linux version 6.12\nT Welcome\nT login: \n"
+        );
+    }
+
     #[test]
     fn test_parse_resolv_conf() {
         let cases = vec![
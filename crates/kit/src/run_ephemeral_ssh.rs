@@ -1,11 +1,14 @@
 use color_eyre::eyre::{eyre, Context as _};
-use color_eyre::Result;
+use color_eyre::{Report, Result};
 use indicatif::ProgressBar;
 use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+use crate::cleanup::CleanupGuard;
+use crate::hostexec::HostCommand;
 use crate::run_ephemeral::{run_detached, RunEphemeralOpts};
 use crate::ssh;
 use crate::supervisor_status::{SupervisorState, SupervisorStatus};
@@ -96,28 +99,26 @@ fn show_container_logs(container_name: &str) {
 
 /// RAII guard for ephemeral container cleanup
 /// Ensures container is removed when dropped, even on error paths
-struct ContainerCleanup {
-    container_id: String,
+/// and when interrupted
+pub(crate) struct ContainerCleanup {
+    _guard: CleanupGuard,
 }
 
 impl ContainerCleanup {
-    fn new(container_id: String) -> Self {
-        Self { container_id }
-    }
-}
-
-impl Drop for ContainerCleanup {
-    fn drop(&mut self) {
-        debug!("Cleaning up ephemeral container {}", self.container_id);
-        let result = Command::new("podman")
-            .args(["rm", "-f", &self.container_id])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
-
-        if let Err(e) = result {
-            tracing::warn!("Failed to remove container {}: {}", self.container_id, e);
-        }
+    pub(crate) fn new(container_id: String) -> Self {
+        let description = format!("ephemeral container {container_id}");
+        let _guard = CleanupGuard::new(description, move || {
+            let result = HostCommand::new("podman")
+                .args(["rm", "-f", &container_id])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .output();
+
+            if let Err(e) = result {
+                tracing::warn!("Failed to remove container {}: {}", container_id, e);
+            }
+        });
+        Self { _guard }
     }
 }
 
@@ -145,18 +146,19 @@ fn is_container_running(container_name: &str) -> Result<bool> {
     Ok(state.trim() == "running")
 }
 
-/// Wait for VM SSH availability using the supervisor status file
+/// Targets which systemd only reaches if the boot failed
+const FAILED_BOOT_TARGETS: &[&str] = &["emergency.target", "rescue.target"];
+
+/// Follow the supervisor status file of the VM until `done` returns true
 ///
-/// Monitors /run/supervisor-status.json inside the container for SSH.
-/// Returns Ok(true) when systemd indicates ssh is probably ready.
+/// Monitors /run/supervisor-status.json inside the container.
 /// Returns Ok(false) if we don't support systemd status notifications.
-pub fn wait_for_vm_ssh(
+fn wait_for_vm_status(
     container_name: &str,
-    timeout: Option<Duration>,
+    timeout: Duration,
     progress: ProgressBar,
+    mut done: impl FnMut(&SupervisorStatus) -> Result<bool>,
 ) -> Result<(bool, ProgressBar)> {
-    let timeout = timeout.unwrap_or(SSH_TIMEOUT);
-
     debug!(
         "Waiting for VM readiness via supervisor status file (timeout: {}s)...",
         timeout.as_secs()
@@ -194,21 +196,51 @@ pub fn wait_for_vm_ssh(
         }
     };
 
+    // Read JSON lines from the monitor in a thread, so that the timeout can
+    // be enforced while the VM is stuck
     let stdout = child.stdout.take().unwrap();
-    let reader = std::io::BufReader::new(stdout);
-
-    // Read JSON lines from the monitor
-    for line in std::io::BufRead::lines(reader) {
-        let line = line.context("Reading monitor output")?;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let reader = std::io::BufReader::new(stdout);
+        for line in std::io::BufRead::lines(reader) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let line = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => line.context("Reading monitor output")?,
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = child.kill();
+                progress.finish_and_clear();
+                show_container_logs(container_name);
+                return Err(Report::new(crate::Failure::SshTimeout).wrap_err(format!(
+                    "Timed out after {}s waiting for the VM",
+                    timeout.as_secs()
+                )));
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
 
         let status: SupervisorStatus = serde_json::from_str(&line)
             .with_context(|| format!("Failed to parse monitor output as JSON: {}", line))?;
         debug!("Status update: {:?}", status.state);
 
-        if status.ssh_access {
-            // End the monitor
-            let _ = child.kill();
-            return Ok((true, progress));
+        match done(&status) {
+            Ok(true) => {
+                // End the monitor
+                let _ = child.kill();
+                return Ok((true, progress));
+            }
+            Ok(false) => {}
+            Err(e) => {
+                let _ = child.kill();
+                progress.finish_and_clear();
+                return Err(e);
+            }
         }
 
         if let Some(state) = status.state {
@@ -228,6 +260,7 @@ pub fn wait_for_vm_ssh(
             }
         } else {
             debug!("Target does not support systemd readiness");
+            let _ = child.kill();
             return Ok((false, progress));
         }
     }
@@ -239,6 +272,53 @@ pub fn wait_for_vm_ssh(
     Err(eyre!("Monitor process exited unexpectedly: {status:?}"))
 }
 
+/// Wait for VM SSH availability using the supervisor status file
+///
+/// Returns Ok(true) when systemd indicates ssh is probably ready.
+/// Returns Ok(false) if we don't support systemd status notifications.
+pub fn wait_for_vm_ssh(
+    container_name: &str,
+    timeout: Option<Duration>,
+    progress: ProgressBar,
+) -> Result<(bool, ProgressBar)> {
+    let timeout = timeout.unwrap_or(SSH_TIMEOUT);
+    wait_for_vm_status(container_name, timeout, progress, |status| {
+        Ok(status.ssh_access)
+    })
+}
+
+/// Wait until systemd in the VM reports that it finished booting
+///
+/// This relies on the notifications systemd sends over vsock, and fails if
+/// they are not available, or if systemd reaches the emergency or rescue
+/// target instead.
+pub fn wait_for_vm_boot(
+    container_name: &str,
+    timeout: Option<Duration>,
+    progress: ProgressBar,
+) -> Result<(Duration, ProgressBar)> {
+    let timeout = timeout.unwrap_or(SSH_TIMEOUT);
+    let start = Instant::now();
+    let (supported, progress) = wait_for_vm_status(container_name, timeout, progress, |status| {
+        match &status.state {
+            Some(SupervisorState::Ready) => Ok(true),
+            Some(SupervisorState::ReachedTarget(target))
+                if FAILED_BOOT_TARGETS.contains(&target.as_str()) =>
+            {
+                Err(eyre!("The VM booted into {target}"))
+            }
+            _ => Ok(false),
+        }
+    })?;
+    if !supported {
+        progress.finish_and_clear();
+        return Err(eyre!(
+            "The VM does not report its boot progress; this requires vsock and systemd 254 or newer"
+        ));
+    }
+    Ok((start.elapsed(), progress))
+}
+
 /// Wait for SSH to be ready by polling SSH connection attempts
 ///
 /// Attempts to connect to the VM via SSH until successful or timeout.
@@ -250,7 +330,30 @@ pub fn wait_for_ssh_ready(
 ) -> Result<(std::time::Duration, ProgressBar)> {
     let timeout = timeout.unwrap_or(SSH_TIMEOUT);
     let (_, progress) = wait_for_vm_ssh(container_name, Some(timeout), progress)?;
+    poll_ssh(container_name, timeout, progress)
+}
+
+/// Like [`wait_for_ssh_ready`], but start polling SSH as soon as QEMU runs
+/// rather than when the guest reports SSH access, so that the connection
+/// attempts overlap with the boot of the VM
+pub fn wait_for_ssh_after_spawn(
+    container_name: &str,
+    timeout: Option<Duration>,
+    progress: ProgressBar,
+) -> Result<(std::time::Duration, ProgressBar)> {
+    let timeout = timeout.unwrap_or(SSH_TIMEOUT);
+    let (_, progress) = wait_for_vm_status(container_name, timeout, progress, |status| {
+        Ok(status.running)
+    })?;
+    poll_ssh(container_name, timeout, progress)
+}
 
+/// Poll SSH connectivity to the VM until it succeeds or `timeout` passes
+fn poll_ssh(
+    container_name: &str,
+    timeout: Duration,
+    progress: ProgressBar,
+) -> Result<(std::time::Duration, ProgressBar)> {
     debug!("Polling SSH connectivity...");
 
     // Use SSH options optimized for connectivity testing
@@ -288,6 +391,9 @@ pub fn run_ephemeral_ssh(opts: RunEphemeralSshOpts) -> Result<()> {
 
     debug!("Starting ephemeral VM...");
     let container_id = run_detached(ephemeral_opts)?;
+    if crate::hostexec::dry_run() {
+        return Ok(());
+    }
     debug!("Ephemeral VM started with container ID: {}", container_id);
 
     // Create cleanup guard to ensure container removal on any exit path
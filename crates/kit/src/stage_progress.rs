@@ -0,0 +1,67 @@
+//! Progress through the stages of a multi-step operation
+//!
+//! Long-running commands such as `bcvk libvirt run` consist of several
+//! steps, some of which (like installing a base disk) take minutes and
+//! print output of their own. Each stage is announced with its number and
+//! the total, e.g. `[2/5] Preparing base disk`, and slow stages report how
+//! long they took, so it is visible where the time goes.
+
+use std::time::{Duration, Instant};
+
+use indicatif::HumanDuration;
+
+/// Stages shorter than this are not reported as done separately
+const REPORT_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Tracks and prints the current stage of an operation
+#[derive(Debug)]
+pub(crate) struct StageProgress {
+    total: usize,
+    current: usize,
+    stage: Option<(String, Instant)>,
+}
+
+impl StageProgress {
+    /// Create a tracker for an operation with `total` stages
+    pub(crate) fn new(total: usize) -> Self {
+        Self {
+            total,
+            current: 0,
+            stage: None,
+        }
+    }
+
+    fn prefix(&self) -> String {
+        format!("[{}/{}]", self.current, self.total)
+    }
+
+    /// Finish the current stage, if any, and start the next one
+    pub(crate) fn begin(&mut self, name: impl Into<String>) {
+        self.finish();
+        self.current += 1;
+        let name = name.into();
+        println!("{} {name}...", self.prefix());
+        self.stage = Some((name, Instant::now()));
+    }
+
+    /// Skip the next stage, saying why
+    pub(crate) fn skip(&mut self, name: &str, reason: &str) {
+        self.finish();
+        self.current += 1;
+        println!("{} {name}: skipped, {reason}", self.prefix());
+    }
+
+    /// Finish the current stage, reporting how long it took if that was a while
+    pub(crate) fn finish(&mut self) {
+        if let Some((name, started)) = self.stage.take() {
+            let elapsed = started.elapsed();
+            if elapsed >= REPORT_THRESHOLD {
+                println!(
+                    "{} {name}: done in {}",
+                    self.prefix(),
+                    HumanDuration(elapsed)
+                );
+            }
+        }
+    }
+}
@@ -14,6 +14,25 @@ pub struct SupervisorStatus {
     pub ssh_access: bool,
     /// True if qemu is running
     pub running: bool,
+    /// How qemu exited, if it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qemu_exit: Option<QemuExit>,
+}
+
+/// How qemu exited, recorded by the supervisor when it failed
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct QemuExit {
+    /// Exit code of qemu, unless it was killed by a signal
+    pub code: Option<i32>,
+    /// Signal qemu was killed by
+    pub signal: Option<i32>,
+    /// Why the watchdog terminated qemu, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<String>,
+    /// The last lines of the guest console
+    #[serde(default)]
+    pub console_tail: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -83,4 +102,13 @@ impl StatusWriter {
             ..Default::default()
         })
     }
+
+    /// Record that qemu failed
+    pub fn failed(self, exit: QemuExit) -> Result<()> {
+        self.update(SupervisorStatus {
+            running: false,
+            qemu_exit: Some(exit),
+            ..Default::default()
+        })
+    }
 }
@@ -9,13 +9,16 @@
 //! The bootc installation process follows these key steps:
 //!
 //! 1. **Image Preparation**: Validates the source container image and prepares the
-//!    target disk file, creating it with appropriate sizing if it doesn't exist
+//!    target disk file, creating it with appropriate sizing if it doesn't exist;
+//!    the image is inspected once, and the disk is created while the host
+//!    container storage is located
 //!
 //! 2. **Storage Configuration**: Sets up container storage access within the
 //!    installation VM by mounting the host's container storage as read-only
 //!
 //! 3. **Ephemeral VM Launch**: Creates a temporary VM using the bootc image itself
-//!    as the installation environment, with the target disk attached via virtio-blk
+//!    as the installation environment, with the target disk attached via virtio-blk;
+//!    SSH is polled as soon as the VM reports that its SSH server is up
 //!
 //! 4. **Bootc Installation**: Executes `bootc install to-disk` within the VM,
 //!    installing the container image to the attached disk with the specified
@@ -62,6 +65,13 @@
 //! - **Debug Support**: Provides comprehensive logging and debug output for
 //!   troubleshooting installation issues
 //!
+//! # Streaming to stdout
+//!
+//! With `-` as the target, the image is installed into a temporary sparse
+//! file in `/var/tmp` and then written to stdout, so it can be piped to a
+//! compressor or another host without keeping a copy around. While
+//! installing, stdout is redirected to stderr so only the image ends up on it.
+//!
 //! # Usage Examples
 //!
 //! ```bash
@@ -71,23 +81,72 @@
 //! # Custom filesystem and size
 //! bcvk to-disk --filesystem xfs --root-size 20G \
 //!     quay.io/centos-bootc/centos-bootc:stream10 output.img
+//!
+//! # Stream the image to another host
+//! bcvk to-disk quay.io/centos-bootc/centos-bootc:stream10 - | zstd | ssh host 'zstd -d | dd of=/dev/sda'
 //! ```
 
-use std::io::IsTerminal;
+use std::io::{IsTerminal, Write};
 
 use crate::cache_metadata::DiskImageMetadata;
-use crate::install_options::InstallOptions;
+use crate::cleanup::CleanupGuard;
+use crate::image_pull::PullPolicy;
+use crate::install_options::{EncryptRoot, InstallOptions, INSTALL_CONFIG_PATH};
 use crate::run_ephemeral::{run_detached, CommonVmOpts, RunEphemeralOpts};
-use crate::run_ephemeral_ssh::wait_for_ssh_ready;
+use crate::run_ephemeral_ssh::{
+    wait_for_ssh_after_spawn, wait_for_ssh_ready, wait_for_vm_boot, ContainerCleanup,
+};
 use crate::{images, ssh, utils};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, ValueEnum};
 use color_eyre::eyre::{eyre, Context};
-use color_eyre::Result;
+use color_eyre::{Report, Result};
 use indicatif::HumanDuration;
 use indoc::indoc;
+use serde::Deserialize;
 use tracing::debug;
 
+/// Suffix of the lock file next to the target disk, see [`lock_target`]
+const LOCK_SUFFIX: &str = ".lock";
+
+/// Target disk argument writing the image to stdout instead of a file
+const STDOUT_TARGET: &str = "-";
+
+/// Directory the image is installed in before it is written to stdout; not
+/// /tmp, which is often memory backed
+const STDOUT_STAGING_DIR: &str = "/var/tmp";
+
+/// Shell script setting up the storage of the installer VM: a tmpfs for
+/// `/var/tmp` and container storage sized by `{TMPFS_SIZE}`, and the host
+/// container storage in `$AIS`
+const STORAGE_SETUP_SCRIPT: &str = indoc! {r#"
+    echo "Setting up temporary filesystems..."
+    # Mount /var/tmp as a large tmpfs, then symlink /var/lib/containers to it
+    # to consolidate temporary storage in one location
+    mount -t tmpfs -o {TMPFS_SIZE} tmpfs /var/tmp
+    mkdir -p /var/tmp/containers
+    rm /var/lib/containers -rf
+    ln -sr /var/tmp/containers /var/lib/containers
+
+    # Ensure virtiofs mount is available (fallback for older systemd without SMBIOS support)
+    AIS=/run/virtiofs-mnt-hoststorage/
+    if ! mountpoint -q ${AIS} &>/dev/null; then
+        echo "virtiofs mount not found at ${AIS}, mounting manually..."
+        mkdir -p ${AIS}
+        mount -t virtiofs mount_hoststorage ${AIS} -o ro
+    fi"#};
+
+/// Path in the installer VM the `--install-config` file is written to
+const INSTALL_CONFIG_VM_PATH: &str = "/run/bcvk-install-config.toml";
+
+/// Path in the installer VM the passphrase of `--encrypt-root` is copied to,
+/// readable only by root and removed once enrolled
+const PASSPHRASE_VM_PATH: &str = "/run/bcvk-root-passphrase";
+
+/// Transport of the image the installed system is upgraded from, as in
+/// `bootc install --target-transport`
+const DEFAULT_TARGET_TRANSPORT: &str = "registry";
+
 /// Supported disk image formats
 #[derive(Debug, Clone, ValueEnum, PartialEq, Default)]
 pub enum Format {
@@ -125,6 +184,13 @@ pub struct ToDiskAdditionalOpts {
     #[clap(long, default_value_t = Format::Raw)]
     pub format: Format,
 
+    /// Host cache and I/O settings of the target disk while installing;
+    /// defaults to cache=unsafe for a newly created image file, as an
+    /// interrupted installation is discarded anyway and the image is synced
+    /// once QEMU exits
+    #[clap(long, value_name = "cache=none|writeback|unsafe,aio=io_uring|threads")]
+    pub disk_io: Option<crate::qemu::DiskIoConfig>,
+
     /// Common VM configuration options
     #[clap(flatten)]
     pub common: CommonVmOpts,
@@ -142,6 +208,34 @@ pub struct ToDiskAdditionalOpts {
     /// Check if the disk would be regenerated without actually creating it
     #[clap(long)]
     pub dry_run: bool,
+
+    /// If the target disk was installed from an older digest of the image
+    /// with otherwise identical options, deploy the new image into it instead
+    /// of installing from scratch; unchanged layers and files are reused from
+    /// its ostree repository
+    #[clap(long)]
+    pub chunked_copy: bool,
+
+    /// After installing, boot the disk in an ephemeral VM and fail unless
+    /// systemd finishes booting
+    #[clap(long)]
+    pub verify_boot: bool,
+
+    /// Shell command run via SSH in the VM booted by --verify-boot; the
+    /// verification fails if it exits with a non-zero status
+    #[clap(long, value_name = "COMMAND", requires = "verify_boot")]
+    pub verify_command: Option<String>,
+
+    /// If another `bcvk to-disk` is writing to the same target, wait for it
+    /// to finish instead of failing
+    #[clap(long)]
+    pub wait_lock: bool,
+    #[clap(flatten)]
+    pub policy: crate::image_policy::ImagePolicyOpts,
+
+    /// Pull the image before installing it: always, if it is missing, or never
+    #[clap(long, value_enum, default_value_t = PullPolicy::Never)]
+    pub pull: PullPolicy,
 }
 
 /// Configuration options for installing a bootc container image to disk
@@ -150,9 +244,10 @@ pub struct ToDiskAdditionalOpts {
 #[derive(Debug, Parser)]
 pub struct ToDiskOpts {
     /// Container image to install
+    #[clap(add = clap_complete::engine::ArgValueCandidates::new(crate::completion::image_names))]
     pub source_image: String,
 
-    /// Target disk/device path
+    /// Target disk/device path, or - to write the image to stdout
     pub target_disk: Utf8PathBuf,
 
     /// Installation options (filesystem, root-size, storage-path)
@@ -172,18 +267,6 @@ impl ToDiskOpts {
         &self.source_image
     }
 
-    /// Resolve and validate the container storage path
-    ///
-    /// Uses explicit storage_path if specified, otherwise auto-detects container storage.
-    fn get_storage_path(&self) -> Result<Utf8PathBuf> {
-        if let Some(ref path) = self.install.storage_path {
-            utils::validate_container_storage_path(path)?;
-            Ok(path.clone())
-        } else {
-            utils::detect_container_storage_path()
-        }
-    }
-
     /// Generate the complete bootc installation command arguments for SSH execution
     fn generate_bootc_install_command(&self, disk_size: u64) -> Result<Vec<String>> {
         let source_imgref = format!("containers-storage:{}", self.source_image);
@@ -222,6 +305,29 @@ impl ToDiskOpts {
             .map(|v| format!("--env=RUST_LOG={v}"))
             .unwrap_or_default();
 
+        // Checked before the VM boots; see enroll_passphrase
+        if let Some(EncryptRoot::Passphrase(path)) = &self.install.encrypt_root {
+            let passphrase = std::fs::read_to_string(path)
+                .with_context(|| format!("Reading passphrase from {path}"))?;
+            if passphrase.trim_end_matches('\n').is_empty() {
+                return Err(eyre!("Passphrase file {path} is empty"));
+            }
+        }
+
+        // The install configuration is written to a file in the VM, which
+        // is mounted into the installation container
+        let (install_config_setup, install_config_mount) = match &self.install.install_config {
+            Some(config) => {
+                let contents = shlex::try_quote(&config.contents)
+                    .map_err(|e| eyre!("Failed to quote {}: {}", config.path, e))?;
+                (
+                    format!("printf '%s' {contents} > {INSTALL_CONFIG_VM_PATH}"),
+                    format!("-v {INSTALL_CONFIG_VM_PATH}:{INSTALL_CONFIG_PATH}:ro"),
+                )
+            }
+            None => Default::default(),
+        };
+
         // Size /var/tmp tmpfs to match swap size (disk_size)
         // This avoids duplicating size calculation logic
         let tmpfs_size_str = format!("size={}k", disk_size / 1024);
@@ -233,21 +339,8 @@ impl ToDiskOpts {
         let script = indoc! {r#"
             set -euo pipefail
 
-            echo "Setting up temporary filesystems..."
-            # Mount /var/tmp as a large tmpfs, then symlink /var/lib/containers to it
-            # to consolidate temporary storage in one location
-            mount -t tmpfs -o {TMPFS_SIZE} tmpfs /var/tmp
-            mkdir -p /var/tmp/containers
-            rm /var/lib/containers -rf
-            ln -sr /var/tmp/containers /var/lib/containers
-
-            # Ensure virtiofs mount is available (fallback for older systemd without SMBIOS support)
-            AIS=/run/virtiofs-mnt-hoststorage/
-            if ! mountpoint -q ${AIS} &>/dev/null; then
-                echo "virtiofs mount not found at ${AIS}, mounting manually..."
-                mkdir -p ${AIS}
-                mount -t virtiofs mount_hoststorage ${AIS} -o ro
-            fi
+            {STORAGE_SETUP}
+            {INSTALL_CONFIG_SETUP}
 
             echo "Starting bootc installation..."
             echo "Source image: {SOURCE_IMGREF}"
@@ -270,6 +363,7 @@ impl ToDiskOpts {
                 -v /var/lib/containers:/var/lib/containers -v /var/tmp:/var/tmp -v /dev:/dev -v "${AIS}:${AIS}" \
                 --security-opt label=type:unconfined_t \
                 --env=STORAGE_OPTS \
+                {INSTALL_CONFIG_MOUNT} \
                 {INSTALL_LOG} \
                 {SOURCE_IMGREF} \
                 bootc install to-disk \
@@ -308,6 +402,7 @@ EOF
                     -v /var/lib/containers:/var/lib/containers -v /var/tmp:/var/tmp -v /dev:/dev -v "${AIS}:${AIS}" \
                     --security-opt label=type:unconfined_t \
                     --env=STORAGE_OPTS \
+                    {INSTALL_CONFIG_MOUNT} \
                     {INSTALL_LOG} \
                     containers-storage:{SOURCE_IMAGE} \
                     bootc install to-disk \
@@ -326,20 +421,89 @@ EOF
 
             echo "Installation completed successfully!"
         "#}
+        .replace("{STORAGE_SETUP}", STORAGE_SETUP_SCRIPT)
         .replace("{TMPFS_SIZE}", &tmpfs_size_quoted)
         .replace("{SOURCE_IMGREF}", &quoted_source_imgref)
         .replace("{SOURCE_IMAGE}", &quoted_source_image)
+        .replace("{INSTALL_CONFIG_SETUP}", &install_config_setup)
+        .replace("{INSTALL_CONFIG_MOUNT}", &install_config_mount)
         .replace("{INSTALL_LOG}", &install_log)
         .replace("{BOOTC_ARGS}", &bootc_args);
 
         Ok(vec!["/bin/bash".to_string(), "-c".to_string(), script])
     }
 
+    /// Generate the command deploying the source image into the existing
+    /// installation on the target disk, see `--chunked-copy`
+    ///
+    /// The root and boot partitions are passed as arguments, see
+    /// [`TargetPartitions::args`].
+    fn generate_bootc_update_command(
+        &self,
+        source_ref: &str,
+        disk_size: u64,
+    ) -> Result<Vec<String>> {
+        let quote = |v: &str| -> Result<String> {
+            Ok(shlex::try_quote(v)
+                .map_err(|e| eyre!("Failed to quote '{v}': {e}"))?
+                .to_string())
+        };
+        let transport = self
+            .install
+            .target_transport
+            .as_deref()
+            .unwrap_or(DEFAULT_TARGET_TRANSPORT);
+        let source_imgref = format!(
+            "ostree-unverified-image:containers-storage:{}",
+            self.source_image
+        );
+        let target_imgref = format!("ostree-unverified-image:{transport}:{source_ref}");
+
+        let script = indoc! {r#"
+            set -euo pipefail
+
+            {STORAGE_SETUP}
+            export STORAGE_OPTS=additionalimagestore=${AIS}
+
+            echo "Mounting the existing installation..."
+            ROOT_DEV=$1
+            BOOT_DEV=${2:-}
+            TARGET=/run/target
+            mkdir -p ${TARGET}
+            mount "${ROOT_DEV}" ${TARGET}
+            if test -n "${BOOT_DEV}"; then
+                mount "${BOOT_DEV}" ${TARGET}/boot
+            fi
+            STATEROOT=$(ls ${TARGET}/ostree/deploy | head -n 1)
+
+            echo "Deploying {SOURCE_IMGREF}..."
+            # Without --karg, ostree carries over the kernel arguments of the
+            # existing (merge) deployment
+            ostree container image deploy --sysroot ${TARGET} --stateroot "${STATEROOT}" \
+                --imgref {SOURCE_IMGREF} --target-imgref {TARGET_IMGREF}
+            # Unchanged objects are shared with the new deployment
+            ostree admin undeploy --sysroot ${TARGET} 1
+
+            umount -R ${TARGET}
+            echo "Update completed successfully!"
+        "#}
+        .replace("{STORAGE_SETUP}", STORAGE_SETUP_SCRIPT)
+        .replace(
+            "{TMPFS_SIZE}",
+            &quote(&format!("size={}k", disk_size / 1024))?,
+        )
+        .replace("{SOURCE_IMGREF}", &quote(&source_imgref)?)
+        .replace("{TARGET_IMGREF}", &quote(&target_imgref)?);
+
+        Ok(vec!["/bin/bash".to_string(), "-c".to_string(), script])
+    }
+
     /// Calculate the optimal target disk size based on the source image or explicit size
     ///
     /// Returns explicit disk_size if provided (parsed from human-readable format),
-    /// otherwise 2x the image size with a 4GB minimum.
-    fn calculate_disk_size(&self) -> Result<u64> {
+    /// otherwise 2x the image size with a 4GB minimum. The image is inspected
+    /// unless its size is passed as `image_size`.
+    fn calculate_disk_size(&self, image_size: Option<u64>) -> Result<u64> {
         if let Some(ref size_str) = self.additional.disk_size {
             let parsed = utils::parse_size(size_str)?;
             debug!("Using explicit disk size: {} -> {} bytes", size_str, parsed);
@@ -347,7 +511,10 @@ EOF
         }
 
         // Get the image size and multiply by 2 for installation space
-        let image_size = images::get_image_size(&self.source_image)?;
+        let image_size = match image_size {
+            Some(size) => size,
+            None => images::get_image_size(&self.source_image)?,
+        };
         debug!("Image size for {}: {} bytes", self.source_image, image_size);
 
         // Minimum 4GB, otherwise 2x the image size
@@ -368,8 +535,55 @@ EOF
 ///
 /// Main entry point for the bootc installation process. See module-level documentation
 /// for details on the installation workflow and architecture.
-pub fn run(opts: ToDiskOpts) -> Result<()> {
+pub fn run(mut opts: ToDiskOpts) -> Result<()> {
+    if opts.target_disk == STDOUT_TARGET {
+        return run_to_stdout(opts);
+    }
+    let started = std::time::Instant::now();
+    // Images from OCI archives and directories are imported into a temporary
+    // image store, which the installer VM uses instead of the host container
+    // storage. From here on the image is referenced by ID, and by the original
+    // reference in messages and metadata.
+    let source_ref = opts.source_image.clone();
+    crate::image_pull::ensure(&opts.source_image, opts.additional.pull)?;
+    let imported = if images::needs_import(&opts.source_image) {
+        Some(images::import(&opts.source_image)?)
+    } else {
+        None
+    };
+    if let Some(imported) = &imported {
+        opts.source_image = imported.id().to_owned();
+    }
+    if opts.additional.chunked_copy
+        && (opts.install.encrypt_root.is_some() || opts.install.composefs_backend)
+    {
+        return Err(eyre!(
+            "--chunked-copy is not supported with --encrypt-root or --composefs-backend"
+        ));
+    }
+    // The root is sealed to the TPM of the installer VM, or needs a passphrase
+    if opts.additional.verify_boot && opts.install.encrypt_root.is_some() {
+        return Err(eyre!("--verify-boot is not supported with --encrypt-root"));
+    }
+
+    // Reused below, so that the image is inspected only once
+    let mut image_info = None;
+    // Checked first, so that not even a cached disk of a refused image is used
+    if opts.additional.policy.is_active() {
+        let inspect = images::inspect(&opts.source_image)?;
+        opts.additional.policy.check(&source_ref, &inspect)?;
+        image_info = Some(inspect);
+    }
+
+    // Held until the installation is done
+    let _lock = if crate::hostexec::dry_run() {
+        None
+    } else {
+        Some(lock_target(&opts.target_disk, opts.additional.wait_lock)?)
+    };
+
     // Phase 0: Check for existing cached disk image
+    let mut update_existing = false;
     let would_reuse = if opts.target_disk.exists() {
         debug!(
             "Target disk {} already exists, checking cache metadata",
@@ -377,14 +591,18 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
         );
 
         // Get the image digest for comparison
-        let inspect = images::inspect(&opts.source_image)?;
+        let inspect = match image_info.take() {
+            Some(inspect) => inspect,
+            None => images::inspect(&opts.source_image)?,
+        };
         let image_digest = inspect.digest.to_string();
+        image_info = Some(inspect);
 
         // Check if cached disk matches our requirements
         match crate::cache_metadata::check_cached_disk(
             opts.target_disk.as_std_path(),
             &image_digest,
-            &opts.source_image,
+            &source_ref,
             &opts.install,
         )? {
             Ok(()) => {
@@ -399,8 +617,31 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
                 return Ok(());
             }
             Err(e) => {
-                debug!("Existing disk does not match requirements, recreating: {e}");
-                if !opts.additional.dry_run {
+                debug!("Existing disk does not match requirements: {e}");
+                let previous_digest = if opts.additional.chunked_copy {
+                    crate::cache_metadata::check_previous_install(
+                        opts.target_disk.as_std_path(),
+                        &source_ref,
+                        &opts.install,
+                    )?
+                } else {
+                    None
+                };
+                if let Some(previous_digest) = previous_digest {
+                    if opts.additional.dry_run {
+                        println!("would-update");
+                        return Ok(());
+                    }
+                    println!(
+                        "Updating existing disk image at {} from digest {previous_digest} to {image_digest}",
+                        opts.target_disk
+                    );
+                    update_existing = true;
+                } else if opts.additional.dry_run {
+                    // Only reporting, see below
+                } else if crate::hostexec::dry_run() {
+                    crate::hostexec::dry_run_note(format_args!("remove {}", opts.target_disk));
+                } else {
                     // Remove the existing disk so we can recreate it
                     std::fs::remove_file(&opts.target_disk).with_context(|| {
                         format!("Failed to remove existing disk {}", opts.target_disk)
@@ -424,8 +665,33 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
     }
 
     // Phase 1: Validation and preparation
-    // Resolve container storage path (auto-detect or validate specified path)
-    let storage_path = opts.get_storage_path()?;
+    // The image size is needed unless the disk size is given
+    if image_info.is_none() && opts.additional.disk_size.is_none() {
+        image_info = Some(images::inspect(&opts.source_image)?);
+    }
+    let disk_size = opts.calculate_disk_size(image_info.as_ref().map(|i| i.size))?;
+
+    // Only a disk created here is worthless until the installation completes
+    let creates_file = !update_existing && !is_block_device(&opts.target_disk);
+    // A new disk is removed again unless the installation completes; never
+    // a block device node
+    let mut disk_guard = (creates_file && !crate::hostexec::dry_run())
+        .then(|| CleanupGuard::remove_path(&opts.target_disk));
+
+    // Resolving the container storage path (which runs podman) and creating
+    // the target disk (which may run qemu-img) are independent, so they run
+    // concurrently
+    let storage_path = std::thread::scope(|s| {
+        let disk = (!update_existing).then(|| {
+            s.spawn(|| create_disk(&opts.target_disk, &opts.additional.format, disk_size))
+        });
+        let storage_path = resolve_storage_path(opts.install.storage_path.clone());
+        if let Some(disk) = disk {
+            disk.join()
+                .map_err(|_| eyre!("Creating the target disk panicked"))??;
+        }
+        storage_path
+    })?;
 
     // Debug logging for installation configuration
     if opts.additional.common.debug {
@@ -437,53 +703,22 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
         }
     }
 
-    let disk_size = opts.calculate_disk_size()?;
-
-    // Create disk image based on format
-    match opts.additional.format {
-        Format::Raw => {
-            // Create sparse file - only allocates space as data is written
-            let file = std::fs::File::create(&opts.target_disk)
-                .with_context(|| format!("Opening {}", opts.target_disk))?;
-            file.set_len(disk_size)?;
-            // TODO pass to qemu via fdset
-            drop(file);
-        }
-        Format::Qcow2 => {
-            // Use qemu-img to create qcow2 format
-            debug!("Creating qcow2 with size {} bytes", disk_size);
-            let size_arg = disk_size.to_string();
-            let output = std::process::Command::new("qemu-img")
-                .args([
-                    "create",
-                    "-f",
-                    "qcow2",
-                    opts.target_disk.as_str(),
-                    &size_arg,
-                ])
-                .output()
-                .with_context(|| {
-                    format!("Failed to run qemu-img create for {}", opts.target_disk)
-                })?;
-
-            if !output.status.success() {
-                return Err(color_eyre::eyre::eyre!(
-                    "qemu-img create failed: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                ));
-            }
-            debug!("qemu-img create completed successfully");
-        }
-    }
-
     // Phase 3: Installation command generation
     // Generate complete script including storage setup and bootc install
-    let bootc_install_command = opts.generate_bootc_install_command(disk_size)?;
+    let mut bootc_install_command = if update_existing {
+        opts.generate_bootc_update_command(&source_ref, disk_size)?
+    } else {
+        opts.generate_bootc_install_command(disk_size)?
+    };
 
     // Phase 4: Ephemeral VM configuration
     let mut common_opts = opts.additional.common.clone();
     // Enable SSH key generation for SSH-based installation
     common_opts.ssh_keygen = true;
+    // bootc enrolls the LUKS key into the TPM
+    if opts.install.encrypt_root.is_some() {
+        common_opts.tpm = true;
+    }
 
     let tty = std::io::stdout().is_terminal();
 
@@ -493,50 +728,80 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
     // - Attach target disk via virtio-blk
     // - Disable networking (using local storage only)
     let ephemeral_opts = RunEphemeralOpts {
-        host_dns_servers: None,
         image: opts.get_installer_image().to_string(),
         common: common_opts,
         podman: crate::run_ephemeral::CommonPodmanOptions {
             rm: true,     // Clean up container after installation
             detach: true, // Run in detached mode for SSH approach
             tty,
-            label: opts.additional.label,
+            label: opts.additional.label.clone(),
             ..Default::default()
         },
         // Workaround for https://github.com/containers/container-libs/issues/144#issuecomment-3300424410
         // Basically containers-libs allocates a tempfile for a whole serialization of a layer as a tarball
         // when fetching, so we need enough memory to do so.
         add_swap: Some(format!("{disk_size}")),
-        bind_mounts: Vec::new(),    // No additional bind mounts needed
-        ro_bind_mounts: Vec::new(), // No additional ro bind mounts needed
-        systemd_units_dir: None,    // No custom systemd units
-        bind_storage_ro: true,      // Mount host container storage read-only
+        // Mount the image store read-only where the host container storage would be
+        ro_bind_mounts: imported
+            .iter()
+            .map(|i| format!("{}:hoststorage", i.store()))
+            .collect(),
+        bind_storage_ro: imported.is_none(), // Mount host container storage read-only
         mount_disk_files: vec![format!(
             "{}:output:{}",
             opts.target_disk,
             opts.additional.format.as_str()
         )], // Attach target disk
-        kernel_args: Default::default(),
-        debug_entrypoint: None,
+        disk_io: opts
+            .additional
+            .disk_io
+            .or_else(|| creates_file.then(crate::qemu::DiskIoConfig::throwaway)),
+        force: true,
+        kernel_cache_key: image_info
+            .as_ref()
+            .map(|i| crate::kernel_cache::cache_key(&i.id)),
+        ..Default::default()
     };
 
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!(
+            "install {} to {} using an ephemeral VM",
+            source_ref, opts.target_disk
+        ));
+        return Ok(());
+    }
+
     // Phase 5: SSH-based VM configuration and execution
     // Launch VM in detached mode with SSH enabled
     debug!("Starting ephemeral VM with SSH...");
     let container_id = run_detached(ephemeral_opts)?;
     debug!("Ephemeral VM started with container ID: {}", container_id);
+    let container_guard = {
+        let container_id = container_id.clone();
+        CleanupGuard::new(format!("installer container {container_id}"), move || {
+            // The image may be in the store of an imported image
+            if let Err(e) = images::podman().args(["rm", "-f", &container_id]).output() {
+                debug!("Failed to remove ephemeral container: {e}");
+            }
+        })
+    };
 
     // Use the SSH approach for better TTY forwarding and output buffering
     let result = (|| -> Result<()> {
-        // Wait for SSH to be ready
+        // Poll SSH while the installer VM boots
         let progress_bar = crate::boot_progress::create_boot_progress_bar();
-        let (duration, progress_bar) = wait_for_ssh_ready(&container_id, None, progress_bar)?;
+        let (duration, progress_bar) = wait_for_ssh_after_spawn(&container_id, None, progress_bar)?;
         progress_bar.finish_and_clear();
         println!(
             "Connected ({} elapsed), beginning installation...",
             HumanDuration(duration)
         );
 
+        if update_existing {
+            let partitions = TargetPartitions::query(&container_id)?;
+            bootc_install_command.extend(partitions.args());
+        }
+
         // Connect via SSH and execute the installation command
         debug!(
             "Executing installation via SSH: {:?}",
@@ -548,48 +813,471 @@ pub fn run(opts: ToDiskOpts) -> Result<()> {
         };
         let status = ssh::connect(&container_id, bootc_install_command, &ssh_options)?;
         if !status.success() {
-            return Err(eyre!(
+            return Err(Report::new(crate::Failure::InstallFailed).wrap_err(format!(
                 "SSH installation command failed with exit code: {:?}",
                 status.code()
-            ));
+            )));
+        }
+
+        if let Some(EncryptRoot::Passphrase(path)) = &opts.install.encrypt_root {
+            send_passphrase(&container_id, path)?;
+            enroll_passphrase(&container_id)?;
         }
 
         Ok(())
     })();
 
-    // Cleanup: stop and remove the container
+    // Cleanup: shut down the VM cleanly so the target disk is consistent,
+    // then remove the container
     debug!("Cleaning up ephemeral container...");
-    let _ = std::process::Command::new("podman")
-        .args(["rm", "-f", &container_id])
-        .output();
+    if let Err(e) =
+        crate::ephemeral::stop_container(&container_id, crate::ephemeral::DEFAULT_SHUTDOWN_TIMEOUT)
+    {
+        debug!("Failed to stop ephemeral container: {e}");
+    }
+    drop(container_guard);
 
     // Handle the result - remove disk file on failure
+    let result = result.and_then(|()| sync_target(&opts.target_disk));
     match result {
         Ok(()) => {
-            // Write metadata to the disk image for caching
-            // Extract values before they're potentially moved
-            let write_result = write_disk_metadata(
-                &opts.source_image,
-                &opts.target_disk,
-                &opts.install,
-                &opts.additional.format,
-            );
-            if let Err(e) = write_result {
-                debug!("Failed to write metadata to disk image: {}", e);
-                // Don't fail the operation just because metadata couldn't be written
+            if opts.additional.verify_boot {
+                // A disk which fails to boot is kept for inspection; it has
+                // no metadata yet, so later runs do not reuse it
+                if let Some(disk_guard) = disk_guard.take() {
+                    disk_guard.disarm();
+                }
+                verify_boot(&opts).with_context(|| {
+                    format!(
+                        "Boot verification of {} failed; inspect it with 'bcvk ephemeral boot-disk'",
+                        opts.target_disk
+                    )
+                })?;
             }
-            Ok(())
+            finish_disk(&opts, &source_ref, disk_guard, started.elapsed())
         }
-        Err(e) => {
-            let _ = std::fs::remove_file(&opts.target_disk);
-            Err(e)
+        // The previous deployment stays bootable if updating fails
+        Err(e) if update_existing => Err(e.wrap_err(format!(
+            "Failed to update {}; retry without --chunked-copy to reinstall",
+            opts.target_disk
+        ))),
+        // The disk is removed by its guard
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy the passphrase for the encrypted root from `path` into the
+/// installer VM, via the stdin of SSH so that it appears on no command line
+fn send_passphrase(container_id: &str, path: &Utf8Path) -> Result<()> {
+    let passphrase =
+        std::fs::read(path).with_context(|| format!("Reading passphrase from {path}"))?;
+    let args = [
+        "sh".to_owned(),
+        "-c".to_owned(),
+        format!("umask 077 && cat > {PASSPHRASE_VM_PATH}"),
+    ];
+    let options = ssh::SshConnectionOptions {
+        allocate_tty: false,
+        forward_stdin: true,
+        ..ssh::SshConnectionOptions::default()
+    };
+    let mut child = ssh::ssh_command(container_id, &args, &options)?
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to run SSH")?;
+    // Dropping stdin ends the input of cat
+    child
+        .stdin
+        .take()
+        .expect("piped stdin")
+        .write_all(&passphrase)
+        .context("Sending the passphrase")?;
+    let status = child.wait().context("Waiting for SSH")?;
+    if !status.success() {
+        return Err(eyre!(
+            "Copying the passphrase into the installer VM failed: {status}"
+        ));
+    }
+    Ok(())
+}
+
+/// Replace the binding of the encrypted root to the (throwaway) TPM of the
+/// installer VM, which bootc sealed the key to, with the passphrase copied
+/// in by [`send_passphrase`]
+fn enroll_passphrase(container_id: &str) -> Result<()> {
+    let devices = BlockDevice::query(container_id)?;
+    let luks_dev = devices
+        .iter()
+        .find_map(|d| d.find(&|d| d.fstype.as_deref() == Some("crypto_LUKS")))
+        .ok_or_else(|| eyre!("No LUKS device found on the target disk"))?;
+    // The passphrase is only read in the VM, so that it never appears on a
+    // command line
+    let script = indoc! {r#"
+        set -euo pipefail
+        echo "Enrolling passphrase for the encrypted root..."
+        NEWPASSWORD="$(cat {PASSPHRASE_FILE})" systemd-cryptenroll --unlock-tpm2-device=auto \
+            --password --wipe-slot=tpm2 "$1"
+        rm -f {PASSPHRASE_FILE}
+    "#}
+    .replace("{PASSPHRASE_FILE}", PASSPHRASE_VM_PATH);
+    let args = vec![
+        "/bin/bash".to_owned(),
+        "-c".to_owned(),
+        script,
+        "bcvk".to_owned(),
+        luks_dev.name.clone(),
+    ];
+    let status = ssh::connect(container_id, args, &ssh::SshConnectionOptions::default())?;
+    if !status.success() {
+        return Err(Report::new(crate::Failure::InstallFailed)
+            .wrap_err(format!("Enrolling the passphrase failed: {status}")));
+    }
+    Ok(())
+}
+
+/// A block device on the target disk, as listed by `lsblk --json`
+#[derive(Debug, Deserialize)]
+struct BlockDevice {
+    /// Path of the device node
+    name: String,
+    /// Filesystem label
+    label: Option<String>,
+    /// Filesystem type, e.g. `crypto_LUKS`
+    fstype: Option<String>,
+    /// Partitions and other devices stacked on this one
+    #[serde(default)]
+    children: Vec<BlockDevice>,
+}
+
+/// Output of `lsblk --json`
+#[derive(Debug, Deserialize)]
+struct Lsblk {
+    blockdevices: Vec<BlockDevice>,
+}
+
+impl BlockDevice {
+    /// List the target disk and the devices on it in the installer VM
+    fn query(container_id: &str) -> Result<Vec<BlockDevice>> {
+        let args = [
+            "sh".to_owned(),
+            "-c".to_owned(),
+            "udevadm settle && lsblk --json --paths -o NAME,LABEL,FSTYPE /dev/disk/by-id/virtio-output"
+                .to_owned(),
+        ];
+        let output = ssh::ssh_command(container_id, &args, &ssh::SshConnectionOptions::default())?
+            .output()
+            .context("Failed to run SSH")?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "Listing the partitions of the target disk failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
         }
+        Self::parse(&output.stdout)
+    }
+
+    /// Parse the output of `lsblk --json`
+    fn parse(buf: &[u8]) -> Result<Vec<BlockDevice>> {
+        let lsblk: Lsblk = serde_json::from_slice(buf).context("Parsing lsblk output")?;
+        Ok(lsblk.blockdevices)
     }
+
+    /// Find this device or one stacked on it matching `f`
+    fn find(&self, f: &dyn Fn(&BlockDevice) -> bool) -> Option<&BlockDevice> {
+        if f(self) {
+            return Some(self);
+        }
+        self.children.iter().find_map(|c| c.find(f))
+    }
+}
+
+/// Partitions of an existing installation on the target disk
+#[derive(Debug, PartialEq, Eq)]
+struct TargetPartitions {
+    /// Device with the `root` label
+    root: String,
+    /// Device with the `boot` label, if /boot is a separate partition
+    boot: Option<String>,
+}
+
+impl TargetPartitions {
+    /// Find the partitions of the installation in the installer VM
+    fn query(container_id: &str) -> Result<Self> {
+        Self::from_devices(&BlockDevice::query(container_id)?)
+    }
+
+    /// Find the partitions by their labels, as set by bootc
+    fn from_devices(devices: &[BlockDevice]) -> Result<Self> {
+        let by_label = |label: &str| {
+            devices
+                .iter()
+                .find_map(|d| d.find(&|d| d.label.as_deref() == Some(label)))
+                .map(|d| d.name.clone())
+        };
+        let root =
+            by_label("root").ok_or_else(|| eyre!("No root filesystem found on the target disk"))?;
+        Ok(Self {
+            root,
+            boot: by_label("boot"),
+        })
+    }
+
+    /// Arguments of the script from [`ToDiskOpts::generate_bootc_update_command`]
+    fn args(self) -> Vec<String> {
+        // $0 of `bash -c`
+        std::iter::once("bcvk".to_owned())
+            .chain(std::iter::once(self.root))
+            .chain(self.boot)
+            .collect()
+    }
+}
+
+/// Install to a temporary file, then write the image to stdout
+///
+/// Anything else bcvk and the processes it runs print goes to stderr
+/// meanwhile, so that stdout only carries the image.
+fn run_to_stdout(mut opts: ToDiskOpts) -> Result<()> {
+    if opts.additional.chunked_copy || opts.additional.dry_run {
+        return Err(eyre!(
+            "--chunked-copy and --dry-run need an existing target disk, not stdout"
+        ));
+    }
+    let stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        return Err(eyre!(
+            "Refusing to write a disk image to a terminal; redirect or pipe stdout"
+        ));
+    }
+    stdout.lock().flush()?;
+    let mut image_out = std::fs::File::from(rustix::io::dup(&stdout)?);
+    rustix::stdio::dup2_stdout(std::io::stderr()).context("Redirecting stdout to stderr")?;
+
+    let staging = tempfile::Builder::new()
+        .prefix("bcvk-to-disk")
+        .tempdir_in(STDOUT_STAGING_DIR)
+        .with_context(|| format!("Creating temporary directory in {STDOUT_STAGING_DIR}"))?;
+    let staging_dir = Utf8Path::from_path(staging.path())
+        .ok_or_else(|| eyre!("Invalid UTF-8 in {:?}", staging.path()))?;
+    // The disk is as large as the image; don't leave it behind on SIGINT/SIGTERM
+    let _staging_guard = CleanupGuard::remove_path(staging_dir);
+    let disk = staging_dir.join(format!("disk.{}", opts.additional.format));
+    opts.target_disk = disk.clone();
+    run(opts)?;
+
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!("write {disk} to stdout"));
+        return Ok(());
+    }
+    eprintln!("Writing the disk image to stdout...");
+    let mut f = std::fs::File::open(&disk).with_context(|| format!("Opening {disk}"))?;
+    std::io::copy(&mut f, &mut image_out).context("Writing the disk image to stdout")?;
+    Ok(())
+}
+
+/// Resolve and validate the container storage path
+///
+/// Uses the explicit path if specified, otherwise auto-detects container storage.
+fn resolve_storage_path(explicit: Option<Utf8PathBuf>) -> Result<Utf8PathBuf> {
+    if let Some(path) = explicit {
+        utils::validate_container_storage_path(&path)?;
+        Ok(path)
+    } else {
+        utils::detect_container_storage_path()
+    }
+}
+
+/// Path of the lock file of `target`: a hidden file next to it, as the
+/// target itself is removed and recreated
+fn lock_path(target: &Utf8Path) -> Result<Utf8PathBuf> {
+    let name = target
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid target disk path {target}"))?;
+    Ok(target.with_file_name(format!(".{name}{LOCK_SUFFIX}")))
+}
+
+/// Lock `target` against concurrent `bcvk to-disk` runs, waiting for the
+/// lock if `wait` is set and failing otherwise. Block devices are locked
+/// directly. The lock is held until the returned file is closed.
+fn lock_target(target: &Utf8Path, wait: bool) -> Result<std::fs::File> {
+    use rustix::fs::{flock, FlockOperation};
+
+    let is_device = is_block_device(target);
+    let path = if is_device {
+        target.to_owned()
+    } else {
+        lock_path(target)?
+    };
+    let mut lock = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!is_device)
+        .create(!is_device)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Opening {path}"))?;
+
+    match flock(&lock, FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => {}
+        Err(rustix::io::Errno::WOULDBLOCK) => {
+            // The holder writes its PID into lock files, but not devices
+            let holder = (!is_device)
+                .then(|| std::fs::read_to_string(&path).ok())
+                .flatten()
+                .and_then(|s| s.trim().parse::<u32>().ok())
+                .map(|pid| format!(" by PID {pid}"))
+                .unwrap_or_default();
+            if !wait {
+                return Err(eyre!(
+                    "{target} is in use{holder} by another bcvk to-disk; use --wait-lock to wait for it"
+                ));
+            }
+            eprintln!("Waiting for {target}, which is in use{holder}...");
+            flock(&lock, FlockOperation::LockExclusive)
+                .with_context(|| format!("Locking {path}"))?;
+        }
+        Err(e) => return Err(e).with_context(|| format!("Locking {path}")),
+    }
+    if !is_device {
+        lock.set_len(0)?;
+        write!(lock, "{}", std::process::id())?;
+    }
+    Ok(lock)
+}
+
+/// Whether `target` is an existing block device
+fn is_block_device(target: &Utf8Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(target).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+/// Flush the target disk to stable storage, as QEMU may have written it
+/// with cache=unsafe
+fn sync_target(target: &Utf8Path) -> Result<()> {
+    std::fs::File::open(target)
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("Syncing {target}"))
+}
+
+/// Create the empty target disk image
+fn create_disk(target_disk: &Utf8Path, format: &Format, disk_size: u64) -> Result<()> {
+    match format {
+        Format::Raw if crate::hostexec::dry_run() => {
+            crate::hostexec::dry_run_note(format_args!(
+                "create {disk_size} byte raw disk {target_disk}"
+            ));
+        }
+        Format::Raw => {
+            // Create sparse file - only allocates space as data is written
+            let file = std::fs::File::create(target_disk)
+                .with_context(|| format!("Opening {target_disk}"))?;
+            file.set_len(disk_size)?;
+            // TODO pass to qemu via fdset
+            drop(file);
+        }
+        Format::Qcow2 => {
+            // Use qemu-img to create qcow2 format
+            debug!("Creating qcow2 with size {} bytes", disk_size);
+            let (dir, name) = crate::qemu_img::open_parent(target_disk)?;
+            crate::qemu_img::create(&dir, name, crate::qemu_img::ImageFormat::Qcow2, disk_size)?;
+            debug!("qemu-img create completed successfully");
+        }
+    }
+    Ok(())
+}
+
+/// Record a successfully built disk: write its cache metadata and keep it
+fn finish_disk(
+    opts: &ToDiskOpts,
+    source_ref: &str,
+    disk_guard: Option<CleanupGuard>,
+    elapsed: std::time::Duration,
+) -> Result<()> {
+    // Write metadata to the disk image for caching
+    let write_result = write_disk_metadata(
+        &opts.source_image,
+        source_ref,
+        &opts.target_disk,
+        &opts.install,
+        &opts.additional.format,
+    );
+    if let Err(e) = write_result {
+        debug!("Failed to write metadata to disk image: {}", e);
+        // Don't fail the operation just because metadata couldn't be written
+    }
+    crate::events::record(crate::events::EventKind::DiskBuilt {
+        path: opts.target_disk.to_string(),
+        image: source_ref.to_owned(),
+        seconds: Some(elapsed.as_secs()),
+    });
+    if let Some(disk_guard) = disk_guard {
+        disk_guard.disarm();
+    }
+    Ok(())
+}
+
+/// Boot the installed disk in an ephemeral VM and wait until systemd has
+/// finished booting, then run `--verify-command` in it
+fn verify_boot(opts: &ToDiskOpts) -> Result<()> {
+    let disk = opts
+        .target_disk
+        .canonicalize_utf8()
+        .with_context(|| format!("Disk image {}", opts.target_disk))?;
+    let mut common = opts.additional.common.clone();
+    common.ssh_keygen = true;
+    let ephemeral_opts = RunEphemeralOpts {
+        // Provides the userspace QEMU runs in
+        image: opts.get_installer_image().to_string(),
+        common,
+        podman: crate::run_ephemeral::CommonPodmanOptions {
+            rm: true,
+            detach: true,
+            label: opts.additional.label.clone(),
+            ..Default::default()
+        },
+        force: true,
+        boot_disk: Some(disk),
+        ..Default::default()
+    };
+
+    println!("Verifying that {} boots...", opts.target_disk);
+    let container_id = run_detached(ephemeral_opts)?;
+    // The disk is booted with snapshot=on, so the VM can just be killed
+    let _cleanup = ContainerCleanup::new(container_id.clone());
+
+    let progress_bar = crate::boot_progress::create_boot_progress_bar();
+    let (duration, progress_bar) = wait_for_vm_boot(&container_id, None, progress_bar)?;
+    progress_bar.finish_and_clear();
+    println!("Booted successfully ({} elapsed)", HumanDuration(duration));
+
+    if let Some(command) = &opts.additional.verify_command {
+        let progress_bar = crate::boot_progress::create_boot_progress_bar();
+        let (_, progress_bar) = wait_for_ssh_ready(&container_id, None, progress_bar)?;
+        progress_bar.finish_and_clear();
+        debug!("Running verification command: {command}");
+        let args = vec!["/bin/bash".to_string(), "-c".to_string(), command.clone()];
+        let ssh_options = ssh::SshConnectionOptions {
+            allocate_tty: false,
+            ..ssh::SshConnectionOptions::default()
+        };
+        let status = ssh::connect(&container_id, args, &ssh_options)?;
+        if !status.success() {
+            return Err(eyre!(
+                "Verification command failed with exit code: {:?}",
+                status.code()
+            ));
+        }
+        println!("Verification command succeeded");
+    }
+    Ok(())
 }
 
 /// Write metadata to disk image for caching purposes
+///
+/// `source_image` is inspected for the digest, `source_ref` is recorded as
+/// the image the disk was built from.
 fn write_disk_metadata(
     source_image: &str,
+    source_ref: &str,
     target_disk: &Utf8PathBuf,
     install_options: &InstallOptions,
     format: &Format,
@@ -602,7 +1290,7 @@ fn write_disk_metadata(
     let digest = inspect.digest.to_string();
 
     // Prepare metadata using the new helper method
-    let metadata = DiskImageMetadata::from(install_options, &digest, source_image);
+    let metadata = DiskImageMetadata::from(install_options, &digest, source_ref);
 
     // Write metadata using rustix fsetxattr
     let file = std::fs::OpenOptions::new()
@@ -644,7 +1332,7 @@ mod tests {
             },
         };
 
-        let size = opts.calculate_disk_size()?;
+        let size = opts.calculate_disk_size(None)?;
         // Should be 10GB as specified
         assert_eq!(size, 10 * 1024 * 1024 * 1024);
 
@@ -662,9 +1350,164 @@ mod tests {
             },
         };
 
-        let size2 = opts2.calculate_disk_size()?;
+        let size2 = opts2.calculate_disk_size(None)?;
         assert_eq!(size2, 5120 * 1024 * 1024);
 
         Ok(())
     }
+
+    #[test]
+    fn test_lock_target() -> Result<()> {
+        assert_eq!(
+            lock_path(Utf8Path::new("/var/tmp/disk.img"))?,
+            Utf8Path::new("/var/tmp/.disk.img.lock")
+        );
+
+        let td = tempfile::tempdir()?;
+        let target = Utf8Path::from_path(td.path()).unwrap().join("disk.img");
+        let lock = lock_target(&target, false)?;
+        let err = lock_target(&target, false).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("in use by PID {}", std::process::id())),
+            "{err}"
+        );
+        drop(lock);
+        lock_target(&target, false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_command() -> Result<()> {
+        let mut opts = ToDiskOpts {
+            source_image: "sha256:1234".to_string(),
+            target_disk: "/tmp/test.img".into(),
+            install: Default::default(),
+            additional: Default::default(),
+        };
+        let script = opts
+            .generate_bootc_update_command("quay.io/example/os:latest", 1 << 30)?
+            .pop()
+            .unwrap();
+        assert!(script.contains("mount -t tmpfs -o 'size=1048576k' tmpfs /var/tmp"));
+        assert!(script.contains(
+            "--imgref ostree-unverified-image:containers-storage:sha256:1234 --target-imgref ostree-unverified-image:registry:quay.io/example/os:latest"
+        ));
+        assert!(!script.contains("bootc install"));
+
+        opts.install.target_transport = Some("containers-storage".to_string());
+        let script = opts
+            .generate_bootc_update_command("localhost/os", 1 << 30)?
+            .pop()
+            .unwrap();
+        assert!(script
+            .contains("--target-imgref ostree-unverified-image:containers-storage:localhost/os"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_command_install_config() -> Result<()> {
+        let mut opts = ToDiskOpts {
+            source_image: "test:latest".to_string(),
+            target_disk: "/tmp/test.img".into(),
+            install: Default::default(),
+            additional: Default::default(),
+        };
+        let script = opts.generate_bootc_install_command(1 << 30)?.pop().unwrap();
+        assert!(!script.contains(INSTALL_CONFIG_PATH));
+
+        opts.install.install_config = Some(crate::install_options::InstallConfig {
+            path: "install.toml".into(),
+            contents: "[install]\nkargs = [\"console=ttyS0\"]\n".into(),
+        });
+        let script = opts.generate_bootc_install_command(1 << 30)?.pop().unwrap();
+        assert!(script.contains("kargs = [\"console=ttyS0\"]\n' > /run/bcvk-install-config.toml"));
+        // Both the installation and its retry use the configuration
+        assert_eq!(
+            script
+                .matches("-v /run/bcvk-install-config.toml:/usr/lib/bootc/install/99-bcvk.toml:ro")
+                .count(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_command_encrypt_root() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let pwfile = Utf8PathBuf::try_from(td.path().join("pw"))?;
+        std::fs::write(&pwfile, "hunter 2\n")?;
+
+        let opts = ToDiskOpts {
+            source_image: "test:latest".to_string(),
+            target_disk: "/tmp/test.img".into(),
+            install: InstallOptions {
+                encrypt_root: Some(EncryptRoot::Passphrase(pwfile.clone())),
+                ..Default::default()
+            },
+            additional: Default::default(),
+        };
+        let script = opts.generate_bootc_install_command(1 << 30)?.pop().unwrap();
+        assert!(script.contains("--block-setup tpm2-luks"));
+        // Enrolled separately, see enroll_passphrase
+        assert!(!script.contains("systemd-cryptenroll"));
+        assert!(!script.contains("hunter"));
+
+        std::fs::write(&pwfile, "\n")?;
+        assert!(opts.generate_bootc_install_command(1 << 30).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_partitions() -> Result<()> {
+        let lsblk = br#"{
+           "blockdevices": [
+              {"name": "/dev/vdb", "label": null, "fstype": null,
+                 "children": [
+                    {"name": "/dev/vdb1", "label": null, "fstype": null},
+                    {"name": "/dev/vdb2", "label": "EFI-SYSTEM", "fstype": "vfat"},
+                    {"name": "/dev/vdb3", "label": "boot", "fstype": "ext4"},
+                    {"name": "/dev/vdb4", "label": "root", "fstype": "xfs"}
+                 ]
+              }
+           ]
+        }"#;
+        let partitions = TargetPartitions::from_devices(&BlockDevice::parse(lsblk)?)?;
+        assert_eq!(
+            partitions,
+            TargetPartitions {
+                root: "/dev/vdb4".to_owned(),
+                boot: Some("/dev/vdb3".to_owned()),
+            }
+        );
+        assert_eq!(partitions.args(), ["bcvk", "/dev/vdb4", "/dev/vdb3"]);
+
+        let lsblk =
+            br#"{"blockdevices": [{"name": "/dev/vdb", "label": "root", "fstype": "xfs"}]}"#;
+        let partitions = TargetPartitions::from_devices(&BlockDevice::parse(lsblk)?)?;
+        assert_eq!(partitions.args(), ["bcvk", "/dev/vdb"]);
+
+        // The root is found under the LUKS device, as is the LUKS device itself
+        let lsblk = br#"{
+           "blockdevices": [
+              {"name": "/dev/vdb", "label": null, "fstype": null,
+                 "children": [
+                    {"name": "/dev/vdb3", "label": null, "fstype": "crypto_LUKS",
+                       "children": [
+                          {"name": "/dev/mapper/root", "label": "root", "fstype": "xfs"}
+                       ]
+                    }
+                 ]
+              }
+           ]
+        }"#;
+        let devices = BlockDevice::parse(lsblk)?;
+        let luks = devices[0].find(&|d| d.fstype.as_deref() == Some("crypto_LUKS"));
+        assert_eq!(luks.map(|d| d.name.as_str()), Some("/dev/vdb3"));
+
+        let lsblk = br#"{"blockdevices": [{"name": "/dev/vdb", "label": null, "fstype": null}]}"#;
+        assert!(TargetPartitions::from_devices(&BlockDevice::parse(lsblk)?).is_err());
+        assert!(BlockDevice::parse(b"NAME LABEL").is_err());
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::eyre::{eyre, Context};
-use color_eyre::Result;
+use color_eyre::{Report, Result};
 use indicatif::ProgressBar;
 use std::io::{Seek as _, Write as _};
 use std::os::fd::OwnedFd;
@@ -68,11 +68,11 @@ where
         std::thread::sleep(poll_interval);
     }
 
-    Err(eyre!(
+    Err(Report::new(crate::Failure::SshTimeout).wrap_err(format!(
         "Timeout waiting for readiness after {}s ({} attempts)",
         timeout.as_secs(),
         attempt
-    ))
+    )))
 }
 
 /// Creates a sealed memory file descriptor for secure data transfer.
@@ -99,9 +99,7 @@ pub(crate) fn impl_sealed_memfd(description: &str, content: &[u8]) -> Result<Own
 
 /// Detect the container storage path using podman system info
 pub(crate) fn detect_container_storage_path() -> Result<Utf8PathBuf> {
-    use std::process::Command;
-
-    let output = Command::new("podman")
+    let output = crate::hostexec::HostCommand::new("podman")
         .args(["system", "info", "--format", "json"])
         .output()
         .context(
@@ -167,6 +165,20 @@ pub(crate) fn validate_container_storage_path(path: &Utf8Path) -> Result<()> {
     Ok(())
 }
 
+/// Read an Ignition config, checking that it is one: Ignition only
+/// reports errors on the guest console, and fails the boot
+pub(crate) fn read_ignition_config(path: &Utf8Path) -> Result<String> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+    let config: serde_json::Value = serde_json::from_str(&content)
+        .with_context(|| format!("Parsing Ignition config {path}"))?;
+    if config.get("ignition").is_none() {
+        return Err(eyre!(
+            "{path} is not an Ignition config (no \"ignition\" section); Butane configs need to be converted with butane first"
+        ));
+    }
+    Ok(content)
+}
+
 /// Parse size string (e.g., "10G", "5120M", "1T") to bytes
 pub(crate) fn parse_size(size_str: &str) -> Result<u64> {
     let size_str = size_str.trim().to_uppercase();
@@ -206,6 +218,35 @@ pub(crate) fn parse_size(size_str: &str) -> Result<u64> {
     Ok(number * multiplier)
 }
 
+/// Parse a duration string (e.g. "30d", "12h", "90m", "45s", "2w")
+///
+/// A plain number is interpreted as seconds.
+pub(crate) fn parse_duration(duration_str: &str) -> Result<std::time::Duration> {
+    let duration_str = duration_str.trim();
+
+    if duration_str.is_empty() {
+        return Err(eyre!("Empty duration string"));
+    }
+
+    let (number_str, multiplier) = match duration_str.char_indices().last() {
+        Some((idx, 'w')) => (&duration_str[..idx], 7 * 24 * 60 * 60),
+        Some((idx, 'd')) => (&duration_str[..idx], 24 * 60 * 60),
+        Some((idx, 'h')) => (&duration_str[..idx], 60 * 60),
+        Some((idx, 'm')) => (&duration_str[..idx], 60),
+        Some((idx, 's')) => (&duration_str[..idx], 1),
+        _ => (duration_str, 1),
+    };
+
+    let number: u64 = number_str
+        .parse()
+        .map_err(|_| eyre!("Invalid number in duration: {}", duration_str))?;
+
+    let secs = number
+        .checked_mul(multiplier)
+        .ok_or_else(|| eyre!("Duration too large: {}", duration_str))?;
+    Ok(std::time::Duration::from_secs(secs))
+}
+
 /// Parse a memory string (like "2G", "1024M", "512") to megabytes
 pub(crate) fn parse_memory_to_mb(memory_str: &str) -> Result<u32> {
     let memory_str = memory_str.trim();
@@ -248,3 +289,49 @@ pub(crate) fn parse_memory_to_mb(memory_str: &str) -> Result<u32> {
 
     Ok(total_mb as u32)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_read_ignition_config() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+
+        let path = dir.join("config.ign");
+        let config = r#"{"ignition": {"version": "3.4.0"}}"#;
+        std::fs::write(&path, config)?;
+        assert_eq!(read_ignition_config(&path)?, config);
+
+        std::fs::write(&path, "variant: fcos\nversion: 1.5.0\n")?;
+        assert!(read_ignition_config(&path).is_err());
+        std::fs::write(&path, r#"{"passwd": {}}"#)?;
+        assert!(read_ignition_config(&path).is_err());
+        assert!(read_ignition_config(&dir.join("missing.ign")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let cases = [
+            ("45", Some(45)),
+            ("45s", Some(45)),
+            ("90m", Some(90 * 60)),
+            ("12h", Some(12 * 60 * 60)),
+            ("30d", Some(30 * 24 * 60 * 60)),
+            ("2w", Some(2 * 7 * 24 * 60 * 60)),
+            (" 1d ", Some(24 * 60 * 60)),
+            ("", None),
+            ("d", None),
+            ("1.5d", None),
+            ("10y", None),
+            ("99999999999999999d", None),
+        ];
+        for (input, expected) in cases {
+            let r = parse_duration(input).ok();
+            assert_eq!(r, expected.map(Duration::from_secs), "{input:?}");
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Running commands in ephemeral VMs over vsock, without SSH
+//!
+//! `bcvk ephemeral run --vsock-exec` injects a small agent into the guest
+//! via systemd credentials: a socket unit listening on [`VSOCK_EXEC_PORT`]
+//! which runs a shell for each connection (see
+//! [`crate::credentials::smbios_creds_for_vsock_exec`]). This works with
+//! images without sshd, e.g. hardened appliance images.
+//!
+//! The vsock CID of the guest is only known in the container running QEMU,
+//! so `bcvk ephemeral exec --via vsock` connects from there, with `podman
+//! exec` running the entrypoint's `vsock-exec` command. The protocol is:
+//!
+//! - the client sends the secret of the VM, a random nonce and the base64
+//!   encoded shell command, each on a line, followed by the stdin of the
+//!   command;
+//! - the agent sends the stdout and stderr of the command, followed by a
+//!   line break and a line with the nonce and the exit status.
+//!
+//! The secret is generated for each VM when it starts, and passed to the
+//! guest as a credential (see [`generate_secret`]), so that only processes
+//! able to read it from the container can run commands in the guest, not
+//! any host process able to connect to the guest's vsock CID.
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::process::Command;
+
+use clap::{Parser, ValueEnum};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use tracing::{debug, warn};
+use vsock::{VsockAddr, VsockStream};
+
+/// vsock port the agent listens on in the guest
+pub(crate) const VSOCK_EXEC_PORT: u32 = 7022;
+
+/// File in the container holding the vsock CID of a guest running the agent
+pub(crate) const VSOCK_CID_FILE: &str = "/run/vsock-exec-cid";
+
+/// Directory in the container holding the secret of the agent, in a file
+/// named after [`crate::credentials::VSOCK_EXEC_SECRET_CREDENTIAL`]
+pub(crate) const VSOCK_SECRET_DIR: &str = "/run/vsock-exec";
+
+/// A new random secret for the agent of a VM
+pub(crate) fn generate_secret() -> String {
+    let bytes: [u8; 32] = rand::random();
+    data_encoding::HEXLOWER.encode(&bytes)
+}
+
+/// How to reach the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExecVia {
+    /// SSH, as `bcvk ephemeral ssh`
+    Ssh,
+    /// The vsock agent of a VM started with `--vsock-exec`
+    Vsock,
+}
+
+/// Options for running a command in an ephemeral VM
+#[derive(Debug, Parser)]
+pub struct ExecOpts {
+    /// Name or ID of the container running the VM, or an unambiguous prefix
+    /// of its name as shown by `bcvk ephemeral list`
+    pub container_name: String,
+
+    /// How to reach the guest; vsock needs a VM started with --vsock-exec,
+    /// and merges stderr into stdout
+    #[clap(long, value_enum, default_value_t = ExecVia::Ssh)]
+    pub via: ExecVia,
+
+    /// Command to run, and its arguments
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// Options for `vsock-exec` in the container
+#[derive(Debug, Parser)]
+pub struct ContainerVsockExecOpts {
+    /// Command to run, and its arguments
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// The request sent to the agent, up to the stdin of the command
+fn request(secret: &str, nonce: &str, command: &[String]) -> Result<String> {
+    let command = shlex::try_join(command.iter().map(String::as_str))
+        .map_err(|e| eyre!("Invalid command: {e}"))?;
+    let encoded = data_encoding::BASE64.encode(command.as_bytes());
+    Ok(format!("{secret}\n{nonce}\n{encoded}\n"))
+}
+
+/// Copies the output of the agent to `out`, holding back what may be the
+/// trailer with the exit status
+struct OutputFilter<W> {
+    out: W,
+    trailer_prefix: Vec<u8>,
+    /// The longest trailer is the prefix, 10 digits and a line break
+    max_trailer: usize,
+    held: Vec<u8>,
+}
+
+impl<W: Write> OutputFilter<W> {
+    fn new(nonce: &str, out: W) -> Self {
+        let trailer_prefix = format!("\n{nonce} ").into_bytes();
+        Self {
+            out,
+            max_trailer: trailer_prefix.len() + 11,
+            trailer_prefix,
+            held: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.held.extend_from_slice(data);
+        if let Some(n) = self.held.len().checked_sub(self.max_trailer) {
+            self.out.write_all(&self.held[..n])?;
+            self.out.flush()?;
+            self.held.drain(..n);
+        }
+        Ok(())
+    }
+
+    /// Write the output before the trailer, and return the exit status
+    fn finish(mut self) -> Result<i32> {
+        let trailer = self
+            .held
+            .windows(self.trailer_prefix.len())
+            .rposition(|w| w == self.trailer_prefix.as_slice())
+            .ok_or_else(|| eyre!("The connection to the vsock agent was closed early"))?;
+        let status = std::str::from_utf8(&self.held[trailer + self.trailer_prefix.len()..])
+            .ok()
+            .and_then(|s| s.strip_suffix('\n'))
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| eyre!("Invalid exit status from the vsock agent"))?;
+        self.out.write_all(&self.held[..trailer])?;
+        self.out.flush()?;
+        Ok(status)
+    }
+}
+
+/// Run `command` via the agent of the guest with `cid`, forwarding stdio
+///
+/// Returns the exit status of the command.
+fn exec(cid: u32, secret: &str, command: &[String]) -> Result<i32> {
+    let nonce = uuid::Uuid::new_v4().simple().to_string();
+    let mut stream = VsockStream::connect(&VsockAddr::new(cid, VSOCK_EXEC_PORT))
+        .with_context(|| format!("Connecting to the vsock agent (CID {cid})"))?;
+    stream.write_all(request(secret, &nonce, command)?.as_bytes())?;
+
+    // The command may exit without reading all of stdin, so this is not
+    // waited for
+    let mut input = stream.try_clone()?;
+    std::thread::spawn(move || {
+        match std::io::copy(&mut std::io::stdin().lock(), &mut input) {
+            Ok(_) => {}
+            // The command exited without reading all of stdin
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                debug!("Stopped forwarding stdin: {e}")
+            }
+            Err(e) => warn!("Forwarding stdin to the vsock agent failed: {e}"),
+        }
+        if let Err(e) = input.shutdown(Shutdown::Write) {
+            debug!("Closing stdin of the vsock agent: {e}");
+        }
+    });
+
+    let mut filter = OutputFilter::new(&nonce, std::io::stdout().lock());
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = stream
+            .read(&mut buf)
+            .context("Reading from the vsock agent")?;
+        if n == 0 {
+            break;
+        }
+        filter.write(&buf[..n])?;
+    }
+    filter.finish()
+}
+
+/// Execute `vsock-exec` in the container, failing with the command's
+/// status if it is not 0
+pub fn run_in_container(opts: ContainerVsockExecOpts) -> Result<()> {
+    let cid = std::fs::read_to_string(VSOCK_CID_FILE)
+        .map_err(|_| eyre!("The VM was not started with --vsock-exec"))?;
+    let cid = cid
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid CID in {VSOCK_CID_FILE}"))?;
+    let secret_path = format!(
+        "{VSOCK_SECRET_DIR}/{}",
+        crate::credentials::VSOCK_EXEC_SECRET_CREDENTIAL
+    );
+    let secret = std::fs::read_to_string(&secret_path)
+        .with_context(|| format!("Reading the secret of the vsock agent from {secret_path}"))?;
+    debug!("Running {:?} via vsock CID {cid}", opts.command);
+    crate::ExitStatus::check(exec(cid, &secret, &opts.command)?)
+}
+
+/// Execute the ephemeral exec command, failing with the command's status
+/// if it is not 0
+pub fn run(opts: ExecOpts) -> Result<()> {
+    let container = crate::ephemeral_list::resolve_container(&opts.container_name)?;
+    // The command may change anything in the VM
+    if crate::hostexec::dry_run() {
+        crate::hostexec::dry_run_note(format_args!("run {:?} in {container}", opts.command));
+        return Ok(());
+    }
+    let status = match opts.via {
+        ExecVia::Ssh => {
+            let options = crate::ssh::SshConnectionOptions {
+                allocate_tty: false,
+                forward_stdin: true,
+                ..Default::default()
+            };
+            crate::ssh::ssh_command(&container, &opts.command, &options)?.status()
+        }
+        ExecVia::Vsock => Command::new("podman")
+            .args(["exec", "-i", container.as_str(), "/var/lib/bcvk/entrypoint"])
+            .arg("vsock-exec")
+            .arg("--")
+            .args(&opts.command)
+            .status(),
+    }
+    .map_err(|e| eyre!("Failed to run podman exec: {e}"))?;
+    crate::ExitStatus::check(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let command = ["echo".to_owned(), "a b".to_owned()];
+        let request = request("s3cret", "n0nce", &command).unwrap();
+        let lines: Vec<_> = request.strip_suffix('\n').unwrap().split('\n').collect();
+        let [secret, nonce, encoded] = lines[..] else {
+            panic!("Unexpected request {request:?}");
+        };
+        assert_eq!(secret, "s3cret");
+        assert_eq!(nonce, "n0nce");
+        let decoded = data_encoding::BASE64.decode(encoded.as_bytes()).unwrap();
+        assert_eq!(decoded, b"echo 'a b'");
+    }
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 64);
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[test]
+    fn test_output_filter() {
+        let run = |chunks: &[&[u8]]| {
+            let mut out = Vec::new();
+            let mut filter = OutputFilter::new("abc", &mut out);
+            for chunk in chunks {
+                filter.write(chunk).unwrap();
+            }
+            let status = filter.finish();
+            status.map(|s| (s, String::from_utf8(out).unwrap()))
+        };
+        assert_eq!(
+            run(&[b"hello\n", b"world\nabc 0\n", b"\nabc 3\n"]).unwrap(),
+            (3, "hello\nworld\nabc 0\n".to_owned())
+        );
+        let long = "x".repeat(100);
+        assert_eq!(
+            run(&[long.as_bytes(), b"\nab", b"c 127\n"]).unwrap(),
+            (127, long)
+        );
+        assert_eq!(run(&[b"\nabc 1\n"]).unwrap(), (1, String::new()));
+        assert!(run(&[b"partial output"]).is_err());
+    }
+}